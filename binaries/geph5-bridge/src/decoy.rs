@@ -0,0 +1,61 @@
+use std::{
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+
+use futures_util::{AsyncReadExt as _, AsyncWriteExt as _};
+use geph5_misc_rpc::bridge::ObfsProtocol;
+use sillad::Pipe;
+use sillad_sosistab3::{Cookie, CLIENT_HANDSHAKE_LEN};
+use smol::future::FutureExt as _;
+use smol_timeout2::TimeoutExt;
+
+/// The innocuous website, such as `example.com:443`, that connections which don't open with a valid handshake are proxied to unchanged, so that censors probing us see a web server rather than a connection that dies. Without one, every connection goes on to the exit like before.
+pub static DECOY_SITE: LazyLock<Option<String>> =
+    LazyLock::new(|| std::env::var("GEPH5_BRIDGE_DECOY").ok());
+
+/// How long a client has to send its handshake, after which it goes to the decoy site, like a probe that waits for the server to speak first.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The cookie of the sosistab3 layer that the protocol puts directly on the wire, if it puts one there. Only connections with such a layer can be checked without terminating them.
+pub fn wire_cookie(protocol: &ObfsProtocol) -> Option<Cookie> {
+    match protocol {
+        ObfsProtocol::Sosistab3(cookie) => Some(Cookie::new(cookie)),
+        ObfsProtocol::Sosistab3New(cookie, lower) => match lower.as_ref() {
+            ObfsProtocol::None => Some(Cookie::new(cookie)),
+            lower => wire_cookie(lower),
+        },
+        ObfsProtocol::ConnTest(lower) => wire_cookie(lower),
+        _ => None,
+    }
+}
+
+/// Reads what a connection opens with, and checks whether it is a client handshake made with the cookie. Returns what was read either way, so that it can be passed on.
+pub async fn screen(conn: &mut impl Pipe, cookie: Cookie) -> (Vec<u8>, bool) {
+    let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+    let mut opening = [0u8; CLIENT_HANDSHAKE_LEN];
+    let mut filled = 0;
+    while filled < CLIENT_HANDSHAKE_LEN {
+        match conn
+            .read(&mut opening[filled..])
+            .timeout(deadline.saturating_duration_since(Instant::now()))
+            .await
+        {
+            Some(Ok(n)) if n > 0 => filled += n,
+            _ => return (opening[..filled].to_vec(), false),
+        }
+    }
+    (opening.to_vec(), cookie.is_client_handshake(&opening))
+}
+
+/// Proxies a connection that failed screening to the decoy site, starting with what it already sent.
+pub async fn proxy_to_decoy(decoy: &str, conn: impl Pipe, opening: Vec<u8>) -> anyhow::Result<()> {
+    let decoy_conn = smol::net::TcpStream::connect(decoy).await?;
+    let (conn_read, mut conn_write) = conn.split();
+    let (decoy_read, mut decoy_write) = decoy_conn.split();
+    decoy_write.write_all(&opening).await?;
+    futures_util::io::copy(conn_read, &mut decoy_write)
+        .race(futures_util::io::copy(decoy_read, &mut conn_write))
+        .await?;
+    Ok(())
+}
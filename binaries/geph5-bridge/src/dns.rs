@@ -0,0 +1,21 @@
+use std::sync::LazyLock;
+
+use sillad_dns::listener::DnsListener;
+use sillad_kcp::KcpParams;
+
+use crate::listen_forward::forward_locally;
+
+/// The domain that this bridge is the authoritative DNS server for, if it runs a DNS tunnel. The domain's NS record must point at this bridge.
+pub static DNS_TUNNEL_DOMAIN: LazyLock<Option<String>> =
+    LazyLock::new(|| std::env::var("GEPH5_BRIDGE_DNS_DOMAIN").ok());
+
+/// Answers DNS tunnel queries on port 53, handing each session to the local port that the client asks for.
+pub async fn dns_loop(domain: &str) -> anyhow::Result<()> {
+    let mut listener =
+        DnsListener::bind("0.0.0.0:53".parse().unwrap(), domain, KcpParams::default()).await?;
+    tracing::info!(domain, "DNS tunnel server started");
+    loop {
+        let (client_conn, port) = listener.accept().await?;
+        forward_locally(client_conn, port);
+    }
+}
@@ -0,0 +1,14 @@
+use sillad_icmp::listener::IcmpListener;
+use sillad_kcp::KcpParams;
+
+use crate::listen_forward::forward_locally;
+
+/// Answers the experimental ICMP tunnel, handing each session to the local port that the client asks for.
+pub async fn icmp_loop(max_pps: u32) -> anyhow::Result<()> {
+    let mut listener = IcmpListener::bind(KcpParams::default(), max_pps)?;
+    tracing::info!(max_pps, "ICMP tunnel responder started");
+    loop {
+        let (client_conn, port) = listener.accept().await?;
+        forward_locally(client_conn, port);
+    }
+}
@@ -1,32 +1,54 @@
 use std::{
-    net::{IpAddr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
     str::FromStr,
     sync::{
         atomic::{AtomicUsize, Ordering},
-        Arc, LazyLock,
+        Arc, LazyLock, Mutex,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
 use async_channel::{Receiver, Sender};
 use async_io_bufpool::pooled_read;
 use async_trait::async_trait;
+use dashmap::DashSet;
 
 use futures_util::{AsyncRead, AsyncReadExt as _, AsyncWrite};
-use geph5_misc_rpc::bridge::{B2eMetadata, BridgeControlProtocol, BridgeControlService};
+use geph5_broker_protocol::PortHopSchedule;
+use geph5_misc_rpc::{
+    b2e_auth::b2e_auth_bridge,
+    bridge::{
+        bandwidth_proof, B2eCredential, B2eMetadata, B2eStreamMetadata, BridgeControlProtocol,
+        BridgeControlService, BridgeListener, PtListener,
+    },
+};
 use moka::future::Cache;
 use once_cell::sync::Lazy;
 use picomux::{PicoMux, Stream};
 use rand::Rng;
-use sillad::{dialer::Dialer, listener::Listener, tcp::TcpListener, Pipe};
+use sillad::{
+    dialer::Dialer,
+    listener::Listener,
+    tcp::{TcpDialer, TcpListener},
+    Pipe,
+};
+use sillad_quic::dialer::QuicDialer;
 use smol::future::FutureExt as _;
 use smol::io::AsyncWriteExt;
 use smol_timeout2::TimeoutExt;
 use stdcode::StdcodeSerializeExt;
-use tap::Tap;
 
-use crate::asn_count::{self, incr_bytes_asn};
+use crate::{
+    asn_count::{self, incr_bytes_asn},
+    decoy::{proxy_to_decoy, screen, wire_cookie, DECOY_SITE},
+    dns::DNS_TUNNEL_DOMAIN,
+    listeners::{pt_serve, quic_forward_loop, BRIDGE_LISTENERS},
+    port_hop::{port_hop_loop, PORT_HOP_SCHEDULE},
+    portmap::{keep_mapped, map_port},
+    ratelimit::{client_limiter, wait_for, Limiter},
+    usage::incr_bytes_exit,
+};
 
 pub async fn listen_forward_loop(my_ip: IpAddr, listener: impl Listener) -> anyhow::Result<()> {
     let state = State { my_ip };
@@ -34,38 +56,137 @@ pub async fn listen_forward_loop(my_ip: IpAddr, listener: impl Listener) -> anyh
     Ok(())
 }
 
+/// The ports of the listeners that tcp_forward has handed out, which are the only local ports that tunnels over ICMP or DNS may reach.
+pub static FORWARDED_PORTS: LazyLock<DashSet<u16>> = LazyLock::new(DashSet::new);
+
+/// The longest bandwidth challenge we answer, in bytes before hex encoding.
+const MAX_BANDWIDTH_PROOF_LEN: usize = 16_000_000;
+
 #[allow(clippy::type_complexity)]
 struct State {
     // b2e_dest => (metadata, task)
     my_ip: IpAddr,
 }
 
-#[async_trait]
-impl BridgeControlProtocol for State {
-    async fn tcp_forward(&self, b2e_dest: SocketAddr, metadata: B2eMetadata) -> SocketAddr {
+impl State {
+    /// Forwards a port to the exit, returning the address that clients reach it at and the local port number.
+    async fn forward(&self, b2e_dest: SocketAddr, metadata: B2eMetadata) -> (SocketAddr, u16) {
+        #[allow(clippy::type_complexity)]
         static MAPPING: LazyLock<
-            Cache<(SocketAddr, B2eMetadata), (SocketAddr, Arc<smol::Task<anyhow::Result<()>>>)>,
+            Cache<
+                (SocketAddr, B2eMetadata),
+                (SocketAddr, u16, Arc<smol::Task<anyhow::Result<()>>>),
+            >,
         > = LazyLock::new(|| {
             Cache::builder()
                 .time_to_idle(Duration::from_secs(3600))
                 .build()
         });
 
-        MAPPING
+        let (addr, port, _) = MAPPING
             .get_with((b2e_dest, metadata.clone()), async {
                 let listener = random_tcp_listener().await;
-                let addr = listener
-                    .local_addr()
-                    .await
-                    .tap_mut(|s| s.set_ip(self.my_ip));
-                let task = smolscale::spawn(handle_one_listener(listener, b2e_dest, metadata));
-                (addr, Arc::new(task))
+                let port = listener.local_addr().await.port();
+                FORWARDED_PORTS.insert(port);
+                // behind NAT, clients reach the port through whichever one the router maps to it
+                let addr = SocketAddr::new(self.my_ip, map_port(port).await);
+                let task = smolscale::spawn(
+                    handle_one_listener(listener, b2e_dest, metadata.clone())
+                        .race(quic_forward_loop(port))
+                        .race(port_hop_loop(port, b2e_dest, metadata))
+                        .race(keep_mapped(port)),
+                );
+                (addr, port, Arc::new(task))
             })
-            .await
-            .0
+            .await;
+        (addr, port)
     }
 }
 
+#[async_trait]
+impl BridgeControlProtocol for State {
+    async fn tcp_forward(&self, b2e_dest: SocketAddr, metadata: B2eMetadata) -> SocketAddr {
+        self.forward(b2e_dest, metadata).await.0
+    }
+
+    async fn pt_forward(
+        &self,
+        b2e_dest: SocketAddr,
+        metadata: B2eMetadata,
+        transport: String,
+    ) -> Option<PtListener> {
+        static PT_MAPPING: LazyLock<Cache<(u16, String), (PtListener, Arc<smol::Task<()>>)>> =
+            LazyLock::new(|| {
+                Cache::builder()
+                    .time_to_idle(Duration::from_secs(3600))
+                    .build()
+            });
+
+        // keyed by the forwarded port, so that a forward that was dropped and made again gets a transport of its own
+        let (_, port) = self.forward(b2e_dest, metadata).await;
+        let res = PT_MAPPING
+            .try_get_with((port, transport.clone()), async {
+                let (listener, task) = pt_serve(self.my_ip, &transport, port).await?;
+                anyhow::Ok((listener, Arc::new(task)))
+            })
+            .await;
+        match res {
+            Ok((listener, _)) => Some(listener),
+            Err(err) => {
+                tracing::warn!(
+                    transport = display(&transport),
+                    err = debug(err),
+                    "cannot run pluggable transport"
+                );
+                None
+            }
+        }
+    }
+
+    async fn dns_tunnel_domain(&self) -> Option<String> {
+        DNS_TUNNEL_DOMAIN.clone()
+    }
+
+    async fn listeners(&self) -> Vec<BridgeListener> {
+        BRIDGE_LISTENERS.clone()
+    }
+
+    async fn port_hop_schedule(&self) -> Option<PortHopSchedule> {
+        PORT_HOP_SCHEDULE.clone()
+    }
+
+    async fn bandwidth_proof(&self, nonce: String, len: u32) -> String {
+        // anyone can reach the control port, so don't let them make us send arbitrarily much
+        bandwidth_proof(&nonce, (len as usize).min(MAX_BANDWIDTH_PROOF_LEN))
+    }
+
+    async fn set_b2e_credential(&self, credential: B2eCredential) {
+        *B2E_CREDENTIAL.lock().unwrap() = Some(credential);
+    }
+}
+
+/// Hands a session that came over ICMP or DNS to one of our forwarding listeners, so that it goes on to the exit like any connection from outside.
+pub fn forward_locally(client_conn: impl Pipe, port: u16) {
+    if !FORWARDED_PORTS.contains(&port) {
+        tracing::debug!(port, "tunnel asked for a port we do not forward");
+        return;
+    }
+    smolscale::spawn(async move {
+        let forward_conn = TcpDialer {
+            dest_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port),
+        }
+        .dial()
+        .await?;
+        let (client_read, mut client_write) = client_conn.split();
+        let (forward_read, mut forward_write) = forward_conn.split();
+        futures_util::io::copy(client_read, &mut forward_write)
+            .race(futures_util::io::copy(forward_read, &mut client_write))
+            .await?;
+        anyhow::Ok(())
+    })
+    .detach();
+}
+
 async fn random_tcp_listener() -> TcpListener {
     loop {
         let rando = rand::thread_rng().gen_range(2048u16..65535);
@@ -79,7 +200,7 @@ async fn random_tcp_listener() -> TcpListener {
     }
 }
 
-async fn handle_one_listener(
+pub async fn handle_one_listener(
     mut listener: impl Listener,
     b2e_dest: SocketAddr,
     metadata: B2eMetadata,
@@ -87,7 +208,7 @@ async fn handle_one_listener(
     static COUNT: AtomicUsize = AtomicUsize::new(0);
 
     loop {
-        let client_conn = listener.accept().await?;
+        let mut client_conn = listener.accept().await?;
         let count = COUNT.fetch_add(1, Ordering::Relaxed);
 
         let remote_ip = SocketAddr::from_str(client_conn.remote_addr().unwrap())
@@ -111,21 +232,39 @@ async fn handle_one_listener(
                     "closing a connection"
                 );
             });
-            let exit_conn = dial_pooled(b2e_dest, &metadata.stdcode())
+            // with a decoy site, whatever doesn't open with a valid handshake goes there instead of to the exit
+            let mut opening = vec![];
+            if let (Some(decoy), Some(cookie)) =
+                (DECOY_SITE.as_deref(), wire_cookie(&metadata.protocol))
+            {
+                let genuine;
+                (opening, genuine) = screen(&mut client_conn, cookie).await;
+                if !genuine {
+                    tracing::debug!(asn = remote_asn, "sending a probe to the decoy site");
+                    return proxy_to_decoy(decoy, client_conn, opening).await;
+                }
+            }
+            let exit_conn = dial_pooled(b2e_dest, &metadata, client_id(remote_ip))
                 .await
                 .inspect_err(|e| tracing::warn!("cannot dial pooled: {:?}", e))?;
             let (client_read, client_write) = client_conn.split();
-            let (exit_read, exit_write) = exit_conn.split();
+            let (exit_read, mut exit_write) = exit_conn.split();
+            exit_write.write_all(&opening).await?;
+            let limiter = client_limiter(remote_ip).await;
             io_copy_with_timeout(
                 exit_read,
                 client_write,
                 remote_asn,
+                b2e_dest,
+                limiter.as_deref(),
                 Duration::from_secs(1800),
             )
             .race(io_copy_with_timeout(
                 client_read,
                 exit_write,
                 remote_asn,
+                b2e_dest,
+                limiter.as_deref(),
                 Duration::from_secs(1800),
             ))
             .await?;
@@ -135,11 +274,13 @@ async fn handle_one_listener(
     }
 }
 
-/// Copies data between a reader and a writer with a timeout.
+/// Copies data between a reader and a writer with a timeout, counting the bytes towards the client's ASN and the exit, and keeping to the bridge's rate limits.
 pub async fn io_copy_with_timeout<R, W>(
     mut reader: R,
     mut writer: W,
     asn: u32,
+    exit: SocketAddr,
+    limiter: Option<&Limiter>,
     timeout: Duration,
 ) -> std::io::Result<()>
 where
@@ -152,9 +293,11 @@ where
                 if buf.is_empty() {
                     return Ok(());
                 }
+                wait_for(limiter, buf.len()).await;
                 writer.write_all(&buf).await?;
 
                 incr_bytes_asn(asn, buf.len() as u64);
+                incr_bytes_exit(exit, buf.len() as u64);
             }
             Some(Err(err)) => return Err(err),
             None => return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "timeout")),
@@ -162,7 +305,71 @@ where
     }
 }
 
-async fn dial_pooled(b2e_dest: SocketAddr, metadata: &[u8]) -> anyhow::Result<picomux::Stream> {
+/// The number that the exit knows a client of ours by, which is the same for every connection from the client's address but can't be turned back into the address.
+fn client_id(ip: IpAddr) -> u64 {
+    static SALT: LazyLock<[u8; 32]> = LazyLock::new(rand::random);
+    let hash = blake3::keyed_hash(&SALT, ip.to_string().as_bytes());
+    u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap())
+}
+
+/// The credential the broker gave us for authenticating our connections to exits, if it gave us one.
+static B2E_CREDENTIAL: Mutex<Option<B2eCredential>> = Mutex::new(None);
+
+/// How long we stick to unauthenticated connections to an exit after authenticating to it failed, which is what happens with exits that predate authentication.
+const AUTH_RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// The congestion controller to reach exits over QUIC with, if we reach them over QUIC at all. On lossy international paths, QUIC with a controller like BBR keeps up its throughput where TCP backs off, and every client sharing the connection would feel that.
+static B2E_QUIC: LazyLock<Option<sillad_quic::Congestion>> = LazyLock::new(|| {
+    std::env::var("GEPH5_BRIDGE_B2E_QUIC")
+        .ok()
+        .map(|congestion| {
+            congestion
+                .parse()
+                .expect("GEPH5_BRIDGE_B2E_QUIC must name a congestion controller")
+        })
+});
+
+/// How long we stick to TCP for an exit after reaching it over QUIC failed, which is what happens with exits that don't listen for bridges over QUIC.
+const QUIC_RETRY_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Dials a connection to the exit for the pool, over QUIC if we are set up for that and it didn't fail lately, and over TCP otherwise.
+async fn dial_exit(
+    dest: SocketAddr,
+    quic_failed_at: &Mutex<Option<Instant>>,
+) -> std::io::Result<Box<dyn Pipe>> {
+    let quic_congestion = B2E_QUIC.filter(|_| {
+        quic_failed_at
+            .lock()
+            .unwrap()
+            .map_or(true, |at| at.elapsed() > QUIC_RETRY_INTERVAL)
+    });
+    if let Some(congestion) = quic_congestion {
+        let dialer = QuicDialer {
+            dest_addr: dest,
+            server_name: None,
+            congestion,
+            obfs: None,
+        };
+        match dialer.dial().timeout(Duration::from_secs(10)).await {
+            Some(Ok(pipe)) => return Ok(Box::new(pipe)),
+            res => {
+                tracing::warn!(
+                    dest = display(dest),
+                    res = debug(res.map(|r| r.map(|_| ()))),
+                    "cannot reach the exit over QUIC, so using TCP for now"
+                );
+                *quic_failed_at.lock().unwrap() = Some(Instant::now());
+            }
+        }
+    }
+    Ok(Box::new(TcpDialer { dest_addr: dest }.dial().await?))
+}
+
+async fn dial_pooled(
+    b2e_dest: SocketAddr,
+    metadata: &B2eMetadata,
+    client_id: u64,
+) -> anyhow::Result<picomux::Stream> {
     static POOLS: Lazy<Cache<SocketAddr, Arc<SinglePool>>> = Lazy::new(|| {
         Cache::builder()
             .time_to_idle(Duration::from_secs(3600 * 2))
@@ -180,7 +387,7 @@ async fn dial_pooled(b2e_dest: SocketAddr, metadata: &[u8]) -> anyhow::Result<pi
         .await
         .map_err(|e| anyhow::anyhow!(e))?;
     let stream = pool
-        .connect(metadata)
+        .connect(metadata.clone(), client_id)
         .timeout(Duration::from_secs(1))
         .await
         .context("timeout connecting through pool")?
@@ -188,8 +395,9 @@ async fn dial_pooled(b2e_dest: SocketAddr, metadata: &[u8]) -> anyhow::Result<pi
     Ok(stream)
 }
 
+/// Persistent connections to one exit, each multiplexing the streams of many clients, which are authenticated whenever the broker gave us a credential and the exit understands it.
 struct SinglePool {
-    send: Sender<(Vec<u8>, oneshot::Sender<Stream>)>,
+    send: Sender<(B2eMetadata, u64, oneshot::Sender<Stream>)>,
     live_count: Arc<AtomicUsize>,
     _tasks: Vec<smol::Task<()>>,
 }
@@ -198,14 +406,42 @@ impl SinglePool {
     pub async fn create(dest: SocketAddr) -> anyhow::Result<Self> {
         let (send, recv) = async_channel::bounded(100);
         let live_count = Arc::new(AtomicUsize::new(0));
+        let auth_failed_at: Arc<Mutex<Option<Instant>>> = Arc::new(Mutex::new(None));
+        let quic_failed_at: Arc<Mutex<Option<Instant>>> = Arc::new(Mutex::new(None));
         let mut tasks = vec![];
         for _ in 0..32 {
             let recv = recv.clone();
             let live_count = live_count.clone();
+            let auth_failed_at = auth_failed_at.clone();
+            let quic_failed_at = quic_failed_at.clone();
             let task = smolscale::spawn(async move {
                 loop {
-                    let conn = sillad::tcp::TcpDialer { dest_addr: dest }.dial().await;
-                    if let Ok(conn) = conn {
+                    let conn = dial_exit(dest, &quic_failed_at).await;
+                    if let Ok(mut conn) = conn {
+                        let credential = B2E_CREDENTIAL.lock().unwrap().clone().filter(|_| {
+                            auth_failed_at
+                                .lock()
+                                .unwrap()
+                                .map_or(true, |at| at.elapsed() > AUTH_RETRY_INTERVAL)
+                        });
+                        let authenticated = match credential {
+                            Some(credential) => match b2e_auth_bridge(&mut conn, &credential)
+                                .timeout(Duration::from_secs(10))
+                                .await
+                            {
+                                Some(Ok(())) => true,
+                                res => {
+                                    tracing::warn!(
+                                        dest = display(dest),
+                                        res = debug(res),
+                                        "cannot authenticate to the exit, so connecting without authentication for now"
+                                    );
+                                    *auth_failed_at.lock().unwrap() = Some(Instant::now());
+                                    continue;
+                                }
+                            },
+                            None => false,
+                        };
                         let (read, write) = conn.split();
                         let mux = PicoMux::new(read, write);
                         let recv = recv.clone();
@@ -213,7 +449,7 @@ impl SinglePool {
                         scopeguard::defer!({
                             live_count.fetch_sub(1, Ordering::Relaxed);
                         });
-                        if let Err(err) = remote_once(recv.clone(), &mux).await {
+                        if let Err(err) = remote_once(recv.clone(), &mux, authenticated).await {
                             tracing::error!(dest = display(dest), "remote_once error: {}", err);
                         }
                     }
@@ -229,13 +465,13 @@ impl SinglePool {
         })
     }
 
-    pub async fn connect(&self, metadata: &[u8]) -> anyhow::Result<Stream> {
+    pub async fn connect(&self, metadata: B2eMetadata, client_id: u64) -> anyhow::Result<Stream> {
         if self.live_count.load(Ordering::Relaxed) == 0 {
             anyhow::bail!("no live workers")
         }
         let (back, front) = oneshot::channel();
         self.send
-            .send((metadata.to_vec(), back))
+            .send((metadata, client_id, back))
             .await
             .ok()
             .context("oh no underlying streams are dead")?;
@@ -244,11 +480,22 @@ impl SinglePool {
 }
 
 async fn remote_once(
-    req: Receiver<(Vec<u8>, oneshot::Sender<Stream>)>,
+    req: Receiver<(B2eMetadata, u64, oneshot::Sender<Stream>)>,
     mux: &PicoMux,
+    authenticated: bool,
 ) -> anyhow::Result<()> {
     loop {
-        let (metadata, back) = req.recv().await?;
+        let (metadata, client_id, back) = req.recv().await?;
+        // only exits that we authenticated to know to expect the client
+        let metadata = if authenticated {
+            B2eStreamMetadata {
+                metadata,
+                client_id,
+            }
+            .stdcode()
+        } else {
+            metadata.stdcode()
+        };
         let stream = mux.open(&metadata).await?;
         back.send(stream).ok();
     }
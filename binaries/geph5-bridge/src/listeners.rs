@@ -0,0 +1,120 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::LazyLock,
+};
+
+use anyhow::Context;
+use geph5_misc_rpc::bridge::{BridgeListener, PtListener};
+use sillad::listener::Listener;
+use sillad_pt::server::PtServer;
+use sillad_quic::{listener::QuicListener, Congestion};
+
+use crate::{
+    listen_forward::forward_locally,
+    portmap::{keep_mapped, map_port},
+};
+
+/// The listeners that this bridge serves, from a comma-separated list of `sosistab3`, `quic`, `websocket:<host><path>`, and `pt:<transport>`, such as `sosistab3,websocket:example.com/ws,quic,pt:obfs4`. Without one, we serve just what the broker asks for, like bridges that predate multiple listeners.
+pub static BRIDGE_LISTENERS: LazyLock<Vec<BridgeListener>> = LazyLock::new(|| {
+    std::env::var("GEPH5_BRIDGE_LISTENERS")
+        .map(|listeners| {
+            listeners
+                .split(',')
+                .map(|listener| parse_listener(listener.trim()))
+                .collect::<anyhow::Result<_>>()
+                .expect(
+                    "GEPH5_BRIDGE_LISTENERS must list sosistab3, quic, websocket, or pt listeners",
+                )
+        })
+        .unwrap_or_default()
+});
+
+fn parse_listener(listener: &str) -> anyhow::Result<BridgeListener> {
+    match listener.split_once(':') {
+        None if listener == "sosistab3" => Ok(BridgeListener::Sosistab3),
+        None if listener == "quic" => Ok(BridgeListener::Quic),
+        Some(("websocket", rest)) => {
+            let (host, path) = rest
+                .find('/')
+                .map(|idx| rest.split_at(idx))
+                .context("websocket listener needs a path")?;
+            Ok(BridgeListener::Websocket(host.into(), path.into()))
+        }
+        Some(("pt", transport)) if !transport.is_empty() => {
+            Ok(BridgeListener::PluggableTransport(transport.into()))
+        }
+        _ => anyhow::bail!("unknown listener {listener}"),
+    }
+}
+
+/// Answers QUIC on the UDP port with the given number, handing each connection to the forwarding listener on the TCP port with the same number. Does nothing unless we serve QUIC.
+pub async fn quic_forward_loop(port: u16) -> anyhow::Result<()> {
+    if !BRIDGE_LISTENERS.contains(&BridgeListener::Quic) {
+        return smol::future::pending().await;
+    }
+    let mut listener = match QuicListener::bind(
+        SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port),
+        Congestion::default(),
+    )
+    .await
+    {
+        Ok(listener) => listener,
+        Err(err) => {
+            // the TCP listener still works, so only the QUIC route to this port fails
+            tracing::warn!(port, err = debug(err), "cannot listen for QUIC");
+            return smol::future::pending().await;
+        }
+    };
+    loop {
+        let client_conn = listener.accept().await?;
+        forward_locally(client_conn, port);
+    }
+}
+
+/// The executable, with its arguments separated by spaces, that runs the pluggable transports among our listeners, such as `/usr/bin/lyrebird`.
+static PT_COMMAND: LazyLock<Option<Vec<String>>> = LazyLock::new(|| {
+    std::env::var("GEPH5_BRIDGE_PT_COMMAND")
+        .ok()
+        .map(|command| command.split_whitespace().map(String::from).collect())
+});
+
+/// Runs a pluggable transport in front of the forwarded TCP port with the given number, returning what clients need to connect through it, and a task that keeps it running until dropped. Connections through the transport reach the port from the bridge itself, just like the tunnels over ICMP and DNS.
+pub async fn pt_serve(
+    my_ip: IpAddr,
+    transport: &str,
+    port: u16,
+) -> anyhow::Result<(PtListener, smol::Task<()>)> {
+    anyhow::ensure!(
+        BRIDGE_LISTENERS.contains(&BridgeListener::PluggableTransport(transport.into())),
+        "we do not serve the pluggable transport {transport}"
+    );
+    let command = PT_COMMAND
+        .as_ref()
+        .context("no GEPH5_BRIDGE_PT_COMMAND to run pluggable transports with")?;
+    let server = PtServer::launch(
+        command,
+        transport,
+        SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+        SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port),
+        &std::env::temp_dir().join("geph5-bridge-pt"),
+    )
+    .await?;
+    let pt_port = server.addr().port();
+    let listener = PtListener {
+        transport: transport.into(),
+        args: server.args().iter().cloned().collect(),
+        addr: SocketAddr::new(my_ip, map_port(pt_port).await),
+    };
+    let task = smolscale::spawn(async move {
+        let _server = server;
+        if let Err(err) = keep_mapped(pt_port).await {
+            tracing::warn!(
+                pt_port,
+                err = debug(err),
+                "stopped mapping the transport's port"
+            );
+        }
+        smol::future::pending().await
+    });
+    Ok((listener, task))
+}
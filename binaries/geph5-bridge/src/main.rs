@@ -1,18 +1,27 @@
 mod asn_count;
+mod decoy;
+mod dns;
+mod icmp;
 mod influxdb;
 mod listen_forward;
+mod listeners;
+mod port_hop;
+mod portmap;
+mod ratelimit;
+mod usage;
 
 use std::{
     net::{IpAddr, SocketAddr},
     str::FromStr,
-    sync::Arc,
+    sync::{Arc, Mutex},
     thread::available_parallelism,
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
 
 use anyhow::Context as _;
-use geph5_broker_protocol::{BridgeDescriptor, Mac};
+use geph5_broker_protocol::{BridgeDescriptor, BridgeUsage, BrokerClient, Mac};
 use listen_forward::listen_forward_loop;
+use nanorpc::RpcTransport;
 use rand::Rng;
 use sillad::{
     dialer::DialerExt,
@@ -29,7 +38,7 @@ static ALLOC: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 
 fn main() {
     if std::env::var("GEPH5_BRIDGE_POOL")
-        .unwrap()
+        .unwrap_or_default()
         .contains("yaofan")
     {
         smolscale::permanently_single_threaded();
@@ -84,7 +93,7 @@ fn main() {
         .unwrap();
 
         let port = rand::thread_rng().gen_range(1024..10000);
-        let control_listen = SocketAddr::new(my_ip, port);
+        let control_listen = SocketAddr::new(my_ip, portmap::map_port(port).await);
         let control_cookie = format!("bridge-cookie-{}", rand::random::<u128>());
 
         let upload_loop = broker_loop(control_listen, control_cookie.clone());
@@ -102,62 +111,219 @@ fn main() {
                 smol::Timer::after(Duration::from_secs(1)).await;
             }
         };
-        upload_loop.race(listen_loop).await
+        // the experimental ICMP tunnel needs a raw socket, so it only runs when asked for
+        let icmp_loop = async {
+            if let Ok(max_pps) = std::env::var("GEPH5_BRIDGE_ICMP_PPS") {
+                let max_pps = max_pps
+                    .parse()
+                    .expect("GEPH5_BRIDGE_ICMP_PPS must be a number");
+                if let Err(err) = icmp::icmp_loop(max_pps).await {
+                    tracing::error!(err = %err, "error in icmp_loop");
+                }
+            }
+            smol::future::pending().await
+        };
+        let dns_loop = async {
+            if let Some(domain) = dns::DNS_TUNNEL_DOMAIN.as_deref() {
+                if let Err(err) = dns::dns_loop(domain).await {
+                    tracing::error!(err = %err, "error in dns_loop");
+                }
+            }
+            smol::future::pending().await
+        };
+        let portmap_loop = async {
+            if let Err(err) = portmap::keep_mapped(port).await {
+                tracing::error!(err = %err, "error in keep_mapped");
+            }
+            smol::future::pending().await
+        };
+        upload_loop
+            .race(listen_loop)
+            .race(icmp_loop)
+            .race(dns_loop)
+            .race(portmap_loop)
+            .await
+    })
+}
+
+/// How the bridge authenticates itself to the broker, and which pool it is in.
+#[derive(Clone)]
+struct BridgeCredentials {
+    auth_token: String,
+    pool: String,
+    /// When the token stops being accepted, for volunteer bridges, whose tokens only last until they register again.
+    expiry: Option<u64>,
+}
+
+/// Registers as a volunteer bridge, which has the broker time a bandwidth challenge sent from our control port.
+async fn register_volunteer<T: RpcTransport<Error = anyhow::Error>>(
+    broker_rpc: &BrokerClient<T>,
+    control_listen: SocketAddr,
+    control_cookie: &str,
+) -> anyhow::Result<BridgeCredentials> {
+    let registration = broker_rpc
+        .register_volunteer_bridge(control_listen, control_cookie.to_string())
+        .timeout(Duration::from_secs(120))
+        .await
+        .context("volunteer registration timed out")??
+        .map_err(|e| anyhow::anyhow!(e))?;
+    tracing::info!(
+        pool = registration.pool,
+        expiry = registration.expiry,
+        "registered as a volunteer bridge"
+    );
+    Ok(BridgeCredentials {
+        auth_token: registration.token,
+        pool: registration.pool,
+        expiry: Some(registration.expiry),
     })
 }
 
 async fn broker_loop(control_listen: SocketAddr, control_cookie: String) {
-    let auth_token = std::env::var("GEPH5_BRIDGE_TOKEN").unwrap();
-    let pool = std::env::var("GEPH5_BRIDGE_POOL").unwrap();
     let broker_addr: SocketAddr = std::env::var("GEPH5_BROKER_ADDR").unwrap().parse().unwrap();
+    let broker_rpc = Arc::new(BrokerClient(nanorpc_sillad::DialerTransport(
+        TcpDialer {
+            dest_addr: broker_addr,
+        }
+        .timeout(Duration::from_secs(1)),
+    )));
+
+    // bridges at home only work if the router forwards their ports, so check that the broker gets through before anything else
+    match broker_rpc
+        .probe_bridge(control_listen, control_cookie.clone())
+        .timeout(Duration::from_secs(30))
+        .await
+    {
+        Some(Ok(Ok(()))) => tracing::info!(
+            control_listen = display(control_listen),
+            "the broker can reach us"
+        ),
+        res => tracing::warn!(
+            control_listen = display(control_listen),
+            res = debug(res),
+            "the broker cannot reach us, so clients likely cannot either; are our ports forwarded?"
+        ),
+    }
+
+    // bridges run by the operators share a token, while anyone else can volunteer a bridge, which gets a token of its own
+    let credentials = match std::env::var("GEPH5_BRIDGE_TOKEN") {
+        Ok(auth_token) => BridgeCredentials {
+            auth_token,
+            pool: std::env::var("GEPH5_BRIDGE_POOL").unwrap(),
+            expiry: None,
+        },
+        Err(_) => loop {
+            match register_volunteer(&broker_rpc, control_listen, &control_cookie).await {
+                Ok(credentials) => break credentials,
+                Err(err) => {
+                    tracing::warn!(err = %err, "could not register as a volunteer bridge");
+                    smol::Timer::after(Duration::from_secs(60)).await;
+                }
+            }
+        },
+    };
     tracing::info!(
-        auth_token,
+        auth_token = credentials.auth_token,
         broker_addr = display(broker_addr),
         "starting upload loop"
     );
+    let credentials = Mutex::new(credentials);
 
-    let bridge_key = format!("bridges.{pool}");
+    let report_loop = async {
+        loop {
+            smol::Timer::after(Duration::from_secs(300)).await;
+            let BridgeCredentials {
+                auth_token, pool, ..
+            } = credentials.lock().unwrap().clone();
+            let bytes_per_exit = usage::take_exit_usage();
+            let total: u64 = bytes_per_exit.values().sum();
+            let res = async {
+                broker_rpc
+                    .report_bridge_usage(Mac::new(
+                        BridgeUsage {
+                            control_listen,
+                            pool: pool.clone(),
+                            bytes_per_exit: bytes_per_exit.clone(),
+                            limits: *ratelimit::BRIDGE_LIMITS,
+                            timestamp: SystemTime::now()
+                                .duration_since(SystemTime::UNIX_EPOCH)
+                                .unwrap()
+                                .as_secs(),
+                        },
+                        blake3::hash(auth_token.as_bytes()).as_bytes(),
+                    ))
+                    .timeout(Duration::from_secs(10))
+                    .await
+                    .context("report bridge usage timed out")??
+                    .map_err(|e| anyhow::anyhow!(e))?;
+                anyhow::Ok(())
+            };
+            match res.await {
+                Ok(()) => tracing::debug!(total, "reported bridge usage"),
+                Err(err) => {
+                    tracing::warn!(err = %err, total, "error reporting bridge usage");
+                    usage::restore_exit_usage(bytes_per_exit);
+                }
+            }
+        }
+    };
 
-    let broker_rpc = Arc::new(geph5_broker_protocol::BrokerClient(
-        nanorpc_sillad::DialerTransport(
-            TcpDialer {
-                dest_addr: broker_addr,
+    let upload_loop = async {
+        let mut last_renewal = Instant::now();
+        loop {
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            let expiry = credentials.lock().unwrap().expiry;
+            // register again a day ahead, so that a broker that is down for a while doesn't leave us without a token
+            if expiry.is_some_and(|expiry| expiry < now + 86400)
+                && last_renewal.elapsed() > Duration::from_secs(600)
+            {
+                last_renewal = Instant::now();
+                match register_volunteer(&broker_rpc, control_listen, &control_cookie).await {
+                    Ok(renewed) => *credentials.lock().unwrap() = renewed,
+                    Err(err) => {
+                        tracing::warn!(err = %err, "could not renew the volunteer registration")
+                    }
+                }
             }
-            .timeout(Duration::from_secs(1)),
-        ),
-    ));
+            let BridgeCredentials {
+                auth_token, pool, ..
+            } = credentials.lock().unwrap().clone();
+            tracing::info!(
+                auth_token,
+                broker_addr = display(broker_addr),
+                "uploading..."
+            );
 
-    loop {
-        tracing::info!(
-            auth_token,
-            broker_addr = display(broker_addr),
-            "uploading..."
-        );
-
-        let res = async {
-            broker_rpc
-                .insert_bridge(Mac::new(
-                    BridgeDescriptor {
-                        control_listen,
-                        control_cookie: control_cookie.clone(),
-                        pool: pool.clone(),
-                        expiry: SystemTime::now()
-                            .duration_since(SystemTime::UNIX_EPOCH)
-                            .unwrap()
-                            .as_secs()
-                            + 120,
-                    },
-                    blake3::hash(auth_token.as_bytes()).as_bytes(),
-                ))
-                .timeout(Duration::from_secs(2))
-                .await
-                .context("insert bridge timed out")??
-                .map_err(|e| anyhow::anyhow!(e))?;
-            anyhow::Ok(())
-        };
-        if let Err(err) = res.await {
-            tracing::error!(err = %err, "error in upload_loop");
+            let res = async {
+                broker_rpc
+                    .insert_bridge(Mac::new(
+                        BridgeDescriptor {
+                            control_listen,
+                            control_cookie: control_cookie.clone(),
+                            pool: pool.clone(),
+                            expiry: SystemTime::now()
+                                .duration_since(SystemTime::UNIX_EPOCH)
+                                .unwrap()
+                                .as_secs()
+                                + 120,
+                        },
+                        blake3::hash(auth_token.as_bytes()).as_bytes(),
+                    ))
+                    .timeout(Duration::from_secs(2))
+                    .await
+                    .context("insert bridge timed out")??
+                    .map_err(|e| anyhow::anyhow!(e))?;
+                anyhow::Ok(())
+            };
+            if let Err(err) = res.await {
+                tracing::error!(err = %err, "error in upload_loop");
+            }
+            smol::Timer::after(Duration::from_secs(10)).await;
         }
-        smol::Timer::after(Duration::from_secs(10)).await;
-    }
+    };
+
+    upload_loop.race(report_loop).await
 }
@@ -0,0 +1,77 @@
+use std::{
+    collections::BTreeMap,
+    net::{Ipv4Addr, SocketAddr},
+    sync::LazyLock,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use geph5_broker_protocol::PortHopSchedule;
+use geph5_misc_rpc::bridge::B2eMetadata;
+use sillad::tcp::TcpListener;
+
+use crate::listen_forward::handle_one_listener;
+
+/// The schedule that this bridge hops ports on, if it does, from a range of ports and how many seconds each hop lasts, such as `20000-40000/300`. The seed is fresh whenever the bridge starts, and reaches clients through the broker.
+pub static PORT_HOP_SCHEDULE: LazyLock<Option<PortHopSchedule>> = LazyLock::new(|| {
+    let spec = std::env::var("GEPH5_BRIDGE_PORT_HOP").ok()?;
+    Some(parse_schedule(&spec).expect("GEPH5_BRIDGE_PORT_HOP must look like 20000-40000/300"))
+});
+
+fn parse_schedule(spec: &str) -> anyhow::Result<PortHopSchedule> {
+    let (range, period_secs) = spec.split_once('/').context("no hop length")?;
+    let (min_port, max_port) = range.split_once('-').context("no port range")?;
+    let schedule = PortHopSchedule {
+        seed: format!("{:032x}", rand::random::<u128>()),
+        period_secs: period_secs.parse()?,
+        min_port: min_port.parse()?,
+        max_port: max_port.parse()?,
+    };
+    anyhow::ensure!(
+        schedule.period_secs > 0 && schedule.min_port <= schedule.max_port,
+        "empty port range or zero hop length"
+    );
+    Ok(schedule)
+}
+
+/// Listens for the forwarded port on the ports that the schedule has for the periods just before, during, and just after now, so that clients whose clocks are somewhat off still get through. Connections outlive the period they were made in. Does nothing unless we hop ports.
+pub async fn port_hop_loop(
+    forwarded: u16,
+    b2e_dest: SocketAddr,
+    metadata: B2eMetadata,
+) -> anyhow::Result<()> {
+    let Some(schedule) = PORT_HOP_SCHEDULE.as_ref() else {
+        return smol::future::pending().await;
+    };
+    // dropping a task closes its listener
+    let mut listeners: BTreeMap<u64, smol::Task<anyhow::Result<()>>> = BTreeMap::new();
+    loop {
+        let period = schedule.current_period();
+        listeners.retain(|&listening, _| listening + 1 >= period);
+        for hop in period.saturating_sub(1)..=period + 1 {
+            if listeners.contains_key(&hop) {
+                continue;
+            }
+            let port = schedule.port(forwarded, hop);
+            match TcpListener::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port)).await {
+                Ok(listener) => {
+                    listeners.insert(
+                        hop,
+                        smolscale::spawn(handle_one_listener(listener, b2e_dest, metadata.clone())),
+                    );
+                }
+                Err(err) => {
+                    tracing::debug!(forwarded, port, err = debug(err), "cannot hop to a port")
+                }
+            }
+        }
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        smol::Timer::after(Duration::from_secs(
+            schedule.period_secs - now % schedule.period_secs,
+        ))
+        .await;
+    }
+}
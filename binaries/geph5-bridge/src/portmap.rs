@@ -0,0 +1,198 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddrV4},
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use anyhow::Context;
+use smol::net::UdpSocket;
+use smol_timeout2::TimeoutExt;
+
+/// Whether we ask the router in front of us to forward our ports, which volunteer bridges do unless told not to, since they mostly run at home behind NAT. Setting `GEPH5_BRIDGE_PORTMAP` to 1 or 0 turns it on or off for any bridge.
+pub static PORT_MAPPING: LazyLock<bool> =
+    LazyLock::new(|| match std::env::var("GEPH5_BRIDGE_PORTMAP") {
+        Ok(setting) => setting != "0",
+        Err(_) => std::env::var("GEPH5_BRIDGE_TOKEN").is_err(),
+    });
+
+/// How long we ask mappings to last. We renew them well before then.
+const LEASE_SECS: u32 = 3600;
+
+const WAN_IP_CONNECTION: &str = "urn:schemas-upnp-org:service:WANIPConnection:1";
+
+/// How the router takes mapping requests, once we have found out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Method {
+    Unknown,
+    NatPmp,
+    Upnp,
+    Neither,
+}
+
+static METHOD: Mutex<Method> = Mutex::new(Method::Unknown);
+
+/// Asks the router to forward the TCP port to us, returning the port that it is reachable on from outside. When port mapping is off or fails, returns the port unchanged, leaving it to whoever runs the bridge to forward it.
+pub async fn map_port(port: u16) -> u16 {
+    if !*PORT_MAPPING {
+        return port;
+    }
+    let Some(gateway) = default_gateway() else {
+        tracing::warn!("no default gateway to ask for port mappings");
+        return port;
+    };
+    let method = *METHOD.lock().unwrap();
+    let res = match method {
+        Method::Unknown => find_method(gateway, port).await,
+        Method::NatPmp => natpmp_map(gateway, port).await,
+        Method::Upnp => upnp_map(gateway, port).await,
+        Method::Neither => return port,
+    };
+    match res {
+        Ok(external) => {
+            tracing::debug!(port, external, method = debug(method), "mapped a port");
+            external
+        }
+        Err(err) => {
+            tracing::warn!(port, err = %err, "could not map a port");
+            port
+        }
+    }
+}
+
+/// Keeps the port mapped for as long as this runs, renewing the mapping well before it lapses. Does nothing unless port mapping is on.
+pub async fn keep_mapped(port: u16) -> anyhow::Result<()> {
+    if !*PORT_MAPPING {
+        return smol::future::pending().await;
+    }
+    loop {
+        smol::Timer::after(Duration::from_secs(LEASE_SECS as u64 / 3)).await;
+        map_port(port).await;
+    }
+}
+
+/// Tries NAT-PMP and then UPnP, remembering whichever works, or that neither does, so that later mappings don't wait on a protocol that the router ignores.
+async fn find_method(gateway: Ipv4Addr, port: u16) -> anyhow::Result<u16> {
+    if let Ok(external) = natpmp_map(gateway, port).await {
+        *METHOD.lock().unwrap() = Method::NatPmp;
+        return Ok(external);
+    }
+    match upnp_map(gateway, port).await {
+        Ok(external) => {
+            *METHOD.lock().unwrap() = Method::Upnp;
+            Ok(external)
+        }
+        Err(err) => {
+            *METHOD.lock().unwrap() = Method::Neither;
+            tracing::warn!("the router takes neither NAT-PMP nor UPnP requests, so ports must be forwarded by hand");
+            Err(err)
+        }
+    }
+}
+
+/// The IPv4 default gateway, from the kernel's routing table.
+fn default_gateway() -> Option<Ipv4Addr> {
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if *fields.get(1)? != "00000000" {
+            return None;
+        }
+        // the kernel writes addresses in host byte order
+        let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+        Some(Ipv4Addr::from(gateway.to_le_bytes()))
+    })
+}
+
+/// Maps the port through NAT-PMP (RFC 6886), asking for the same port outside, and retrying with a doubling timeout.
+async fn natpmp_map(gateway: Ipv4Addr, port: u16) -> anyhow::Result<u16> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect(SocketAddrV4::new(gateway, 5351)).await?;
+    let mut request = [0u8; 12];
+    request[1] = 2; // map a TCP port
+    request[4..6].copy_from_slice(&port.to_be_bytes());
+    request[6..8].copy_from_slice(&port.to_be_bytes());
+    request[8..12].copy_from_slice(&LEASE_SECS.to_be_bytes());
+    let mut wait = Duration::from_millis(250);
+    for _ in 0..4 {
+        socket.send(&request).await?;
+        let mut response = [0u8; 16];
+        if let Some(n) = socket.recv(&mut response).timeout(wait).await {
+            anyhow::ensure!(
+                n? == response.len() && response[1] == 128 + 2,
+                "malformed NAT-PMP response"
+            );
+            let result = u16::from_be_bytes([response[2], response[3]]);
+            anyhow::ensure!(result == 0, "NAT-PMP result code {result}");
+            return Ok(u16::from_be_bytes([response[10], response[11]]));
+        }
+        wait *= 2;
+    }
+    anyhow::bail!("no NAT-PMP response")
+}
+
+/// Maps the port through the UPnP internet gateway device that answers our SSDP search, asking for the same port outside.
+async fn upnp_map(gateway: Ipv4Addr, port: u16) -> anyhow::Result<u16> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: {WAN_IP_CONNECTION}\r\n\r\n"
+    );
+    socket
+        .send_to(search.as_bytes(), "239.255.255.250:1900")
+        .await?;
+    let mut buf = [0u8; 2048];
+    let location = loop {
+        let (n, _) = socket
+            .recv_from(&mut buf)
+            .timeout(Duration::from_secs(3))
+            .await
+            .context("no UPnP gateway answered")??;
+        let response = String::from_utf8_lossy(&buf[..n]);
+        let location = response.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("location")
+                .then(|| value.trim().to_string())
+        });
+        if let Some(location) = location {
+            break reqwest::Url::parse(&location)?;
+        }
+    };
+
+    // a full XML parser is overkill for finding one element
+    let description = reqwest::get(location.clone()).await?.text().await?;
+    let control_path = description
+        .split_once(WAN_IP_CONNECTION)
+        .and_then(|(_, service)| service.split_once("<controlURL>"))
+        .and_then(|(_, rest)| rest.split_once("</controlURL>"))
+        .context("gateway has no WAN IP connection to map ports on")?
+        .0;
+    let control_url = location.join(control_path.trim())?;
+
+    let body = format!(
+        r#"<?xml version="1.0"?><s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/"><s:Body><u:AddPortMapping xmlns:u="{WAN_IP_CONNECTION}"><NewRemoteHost></NewRemoteHost><NewExternalPort>{port}</NewExternalPort><NewProtocol>TCP</NewProtocol><NewInternalPort>{port}</NewInternalPort><NewInternalClient>{}</NewInternalClient><NewEnabled>1</NewEnabled><NewPortMappingDescription>geph5-bridge</NewPortMappingDescription><NewLeaseDuration>{LEASE_SECS}</NewLeaseDuration></u:AddPortMapping></s:Body></s:Envelope>"#,
+        local_ip_towards(gateway)?
+    );
+    let response = reqwest::Client::new()
+        .post(control_url)
+        .header("Content-Type", "text/xml; charset=\"utf-8\"")
+        .header(
+            "SOAPAction",
+            format!("\"{WAN_IP_CONNECTION}#AddPortMapping\""),
+        )
+        .body(body)
+        .send()
+        .await?;
+    anyhow::ensure!(
+        response.status().is_success(),
+        "gateway refused the mapping with {}",
+        response.status()
+    );
+    Ok(port)
+}
+
+/// Our address on the network that the gateway is on, which is where mapped ports must lead.
+fn local_ip_towards(gateway: Ipv4Addr) -> anyhow::Result<IpAddr> {
+    // connecting a UDP socket sends nothing, but picks the local address
+    let probe = std::net::UdpSocket::bind("0.0.0.0:0")?;
+    probe.connect((gateway, 9))?;
+    Ok(probe.local_addr()?.ip())
+}
@@ -0,0 +1,93 @@
+//! Limits on how fast the bridge relays, so that whoever runs it, usually a volunteer on a home connection, can cap how much of their bandwidth it takes. There is a limit for each client address, so that one heavy user can't crowd out the rest, and one for everything together. Both count bytes in both directions, and are off unless set. Sessions that come in through the DNS or ICMP tunnels reach us from the loopback address, so they share a single client limit.
+
+use std::{
+    net::IpAddr,
+    num::NonZeroU32,
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+
+use geph5_broker_protocol::BridgeLimits;
+use governor::{DefaultDirectRateLimiter, Quota};
+use moka::future::Cache;
+
+/// The limits we were configured with, in kilobytes per second.
+pub static BRIDGE_LIMITS: LazyLock<BridgeLimits> = LazyLock::new(|| BridgeLimits {
+    client_kbps: kbps_from_env("GEPH5_BRIDGE_CLIENT_KBPS"),
+    total_kbps: kbps_from_env("GEPH5_BRIDGE_TOTAL_KBPS"),
+});
+
+fn kbps_from_env(var: &str) -> u32 {
+    std::env::var(var)
+        .map(|kbps| {
+            kbps.parse()
+                .unwrap_or_else(|_| panic!("{var} must be a number"))
+        })
+        .unwrap_or(0)
+}
+
+/// A limit on how many bytes a second go through.
+pub struct Limiter {
+    inner: DefaultDirectRateLimiter,
+    /// The most bytes the limiter lets through at once, a second's worth.
+    burst: usize,
+}
+
+impl Limiter {
+    fn new(kbps: u32) -> Option<Self> {
+        let bytes_per_sec = NonZeroU32::new(kbps.saturating_mul(1000))?;
+        Some(Self {
+            inner: governor::RateLimiter::direct(Quota::per_second(bytes_per_sec)),
+            burst: bytes_per_sec.get() as usize,
+        })
+    }
+
+    /// Waits until the given number of bytes can be let through.
+    async fn wait(&self, bytes: usize) {
+        // reads bigger than the burst wait in pieces, since they would never fit at once
+        let mut remaining = bytes;
+        while remaining > 0 {
+            let n = remaining.min(self.burst);
+            remaining -= n;
+            let mut delay: f32 = 0.005;
+            while self
+                .inner
+                .check_n(NonZeroU32::new(n as u32).unwrap())
+                .unwrap()
+                .is_err()
+            {
+                smol::Timer::after(Duration::from_secs_f32(delay)).await;
+                delay = (delay + rand::random::<f32>() * 0.05).min(1.0);
+            }
+        }
+    }
+}
+
+static TOTAL_LIMITER: LazyLock<Option<Limiter>> =
+    LazyLock::new(|| Limiter::new(BRIDGE_LIMITS.total_kbps));
+
+static CLIENT_LIMITERS: LazyLock<Cache<IpAddr, Arc<Limiter>>> = LazyLock::new(|| {
+    Cache::builder()
+        .time_to_idle(Duration::from_secs(3600))
+        .build()
+});
+
+/// The limiter shared by every connection from the given client address, if there is a limit for each client.
+pub async fn client_limiter(ip: IpAddr) -> Option<Arc<Limiter>> {
+    let kbps = BRIDGE_LIMITS.client_kbps;
+    if kbps == 0 {
+        return None;
+    }
+    Some(
+        CLIENT_LIMITERS
+            .get_with(ip, async { Arc::new(Limiter::new(kbps).unwrap()) })
+            .await,
+    )
+}
+
+/// Waits until the given number of bytes can be relayed under the client's limit, if it has one, and the limit for everything together.
+pub async fn wait_for(client: Option<&Limiter>, bytes: usize) {
+    for limiter in [client, TOTAL_LIMITER.as_ref()].into_iter().flatten() {
+        limiter.wait(bytes).await;
+    }
+}
@@ -0,0 +1,28 @@
+use std::{collections::BTreeMap, net::SocketAddr, sync::LazyLock};
+
+use dashmap::DashMap;
+
+// Bytes relayed to and from each exit since the last report to the broker
+static EXIT_BYTE_COUNTS: LazyLock<DashMap<SocketAddr, u64>> = LazyLock::new(DashMap::new);
+
+/// Counts bytes relayed to or from the exit at the given b2e address.
+pub fn incr_bytes_exit(exit: SocketAddr, bytes: u64) {
+    *EXIT_BYTE_COUNTS.entry(exit).or_insert(0) += bytes;
+}
+
+/// Takes the counts since the last time, starting over from zero.
+pub fn take_exit_usage() -> BTreeMap<SocketAddr, u64> {
+    let exits: Vec<SocketAddr> = EXIT_BYTE_COUNTS.iter().map(|entry| *entry.key()).collect();
+    exits
+        .into_iter()
+        .filter_map(|exit| EXIT_BYTE_COUNTS.remove(&exit))
+        .filter(|(_, bytes)| *bytes > 0)
+        .collect()
+}
+
+/// Puts back counts that could not be reported, so that they go out with the next report.
+pub fn restore_exit_usage(usage: BTreeMap<SocketAddr, u64>) {
+    for (exit, bytes) in usage {
+        incr_bytes_exit(exit, bytes);
+    }
+}
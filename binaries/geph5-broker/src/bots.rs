@@ -0,0 +1,243 @@
+use std::{sync::LazyLock, time::Duration};
+
+use anyhow::Context;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    distribution::{bridge_bundle, bundle_message, IdentityLimiter},
+    CONFIG_FILE,
+};
+
+/// Configuration for handing out bridges through chat bots, which work as long as the chat service itself is reachable.
+#[derive(Deserialize, Clone, Debug)]
+pub struct BotConfig {
+    #[serde(default)]
+    pub telegram: Option<TelegramConfig>,
+    #[serde(default)]
+    pub matrix: Option<MatrixConfig>,
+    #[serde(default = "default_per_user_per_day")]
+    pub per_user_per_day: u32,
+    /// Kept small, since chat messages are limited in length.
+    #[serde(default = "default_bridges_per_reply")]
+    pub bridges_per_reply: usize,
+    /// Connectivity hints sent along with every answer, such as other ways of getting bridges.
+    #[serde(default)]
+    pub hints: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct TelegramConfig {
+    pub bot_token: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct MatrixConfig {
+    pub homeserver: String,
+    pub access_token: String,
+    /// Our own user ID, so that we don't answer ourselves.
+    pub user_id: String,
+}
+
+fn default_per_user_per_day() -> u32 {
+    3
+}
+
+fn default_bridges_per_reply() -> usize {
+    1
+}
+
+/// How long we wait for new messages in one long poll.
+const POLL_TIMEOUT: Duration = Duration::from_secs(50);
+
+/// Runs every configured bot until the broker exits. Each bot restarts by itself after errors.
+pub async fn bots_loop() {
+    let Some(cfg) = &CONFIG_FILE.wait().bots else {
+        return;
+    };
+    let telegram = async {
+        if let Some(telegram) = &cfg.telegram {
+            retry_forever("telegram", || telegram_loop(cfg, telegram)).await
+        }
+    };
+    let matrix = async {
+        if let Some(matrix) = &cfg.matrix {
+            retry_forever("matrix", || matrix_loop(cfg, matrix)).await
+        }
+    };
+    futures_util::join!(telegram, matrix);
+}
+
+async fn retry_forever<F: std::future::Future<Output = anyhow::Result<()>>>(
+    name: &str,
+    f: impl Fn() -> F,
+) {
+    loop {
+        if let Err(err) = f().await {
+            tracing::warn!(bot = name, err = debug(err), "bot stopped, restarting");
+        }
+        tokio::time::sleep(Duration::from_secs(10)).await;
+    }
+}
+
+/// Answers someone who asked a bot for bridges, or says why not.
+async fn answer(cfg: &BotConfig, identity: &str) -> String {
+    static PER_USER: LazyLock<IdentityLimiter> = LazyLock::new(|| {
+        IdentityLimiter::new(
+            CONFIG_FILE
+                .wait()
+                .bots
+                .as_ref()
+                .map(|cfg| cfg.per_user_per_day)
+                .unwrap_or_default(),
+            Duration::from_secs(86400),
+        )
+    });
+
+    if !PER_USER.allow(identity).await {
+        return "You have asked for bridges too often today. Please try again tomorrow.".into();
+    }
+    match bridge_bundle(identity, cfg.bridges_per_reply).await {
+        Ok(bundle) => bundle_message(&bundle, cfg.hints.as_deref()),
+        Err(err) => {
+            tracing::warn!(err = debug(err), "could not bundle bridges for a bot");
+            "Sorry, we could not find bridges for you right now. Please try again later.".into()
+        }
+    }
+}
+
+/// Answers `/start` and `/bridges` in private chats, through the long-polling Bot API.
+async fn telegram_loop(cfg: &BotConfig, telegram: &TelegramConfig) -> anyhow::Result<()> {
+    let client = reqwest::Client::new();
+    let api = format!("https://api.telegram.org/bot{}", telegram.bot_token);
+    let mut offset = 0i64;
+    loop {
+        let resp: Value = client
+            .get(format!("{api}/getUpdates"))
+            .query(&[
+                ("timeout", POLL_TIMEOUT.as_secs().to_string()),
+                ("offset", offset.to_string()),
+            ])
+            .timeout(POLL_TIMEOUT + Duration::from_secs(10))
+            .send()
+            .await?
+            .json()
+            .await?;
+        let updates = resp["result"]
+            .as_array()
+            .context("Telegram gave no updates")?;
+        for update in updates {
+            offset = offset.max(update["update_id"].as_i64().unwrap_or_default() + 1);
+            let message = &update["message"];
+            let text = message["text"].as_str().unwrap_or_default();
+            if message["chat"]["type"] != "private"
+                || !(text.starts_with("/start") || text.starts_with("/bridges"))
+            {
+                continue;
+            }
+            let (Some(chat_id), Some(user_id)) = (
+                message["chat"]["id"].as_i64(),
+                message["from"]["id"].as_i64(),
+            ) else {
+                continue;
+            };
+            let reply = answer(cfg, &format!("telegram:{user_id}")).await;
+            client
+                .post(format!("{api}/sendMessage"))
+                .json(&json!({ "chat_id": chat_id, "text": reply }))
+                .send()
+                .await?
+                .error_for_status()?;
+        }
+    }
+}
+
+/// Joins the rooms it is invited to and answers `!bridges`, through the client-server sync API.
+async fn matrix_loop(cfg: &BotConfig, matrix: &MatrixConfig) -> anyhow::Result<()> {
+    let client = reqwest::Client::new();
+    let endpoint = |segments: &[&str]| -> anyhow::Result<reqwest::Url> {
+        let mut url = reqwest::Url::parse(&matrix.homeserver)?;
+        url.path_segments_mut()
+            .ok()
+            .context("homeserver cannot be a base URL")?
+            .pop_if_empty()
+            .extend(["_matrix", "client", "v3"])
+            .extend(segments);
+        Ok(url)
+    };
+    let mut since: Option<String> = None;
+    loop {
+        let mut query = vec![("timeout", POLL_TIMEOUT.as_millis().to_string())];
+        if let Some(since) = &since {
+            query.push(("since", since.clone()));
+        }
+        let resp: Value = client
+            .get(endpoint(&["sync"])?)
+            .bearer_auth(&matrix.access_token)
+            .query(&query)
+            .timeout(POLL_TIMEOUT + Duration::from_secs(10))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        // the first sync brings up old messages, which were answered before
+        let first_sync = since.is_none();
+        since = Some(
+            resp["next_batch"]
+                .as_str()
+                .context("Matrix gave no next batch")?
+                .to_string(),
+        );
+
+        if let Some(invites) = resp["rooms"]["invite"].as_object() {
+            for room_id in invites.keys() {
+                client
+                    .post(endpoint(&["rooms", room_id, "join"])?)
+                    .bearer_auth(&matrix.access_token)
+                    .json(&json!({}))
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+        }
+        if first_sync {
+            continue;
+        }
+        let Some(joined) = resp["rooms"]["join"].as_object() else {
+            continue;
+        };
+        for (room_id, room) in joined {
+            let Some(events) = room["timeline"]["events"].as_array() else {
+                continue;
+            };
+            for event in events {
+                let sender = event["sender"].as_str().unwrap_or_default();
+                if event["type"] != "m.room.message"
+                    || sender == matrix.user_id
+                    || !event["content"]["body"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .starts_with("!bridges")
+                {
+                    continue;
+                }
+                let reply = answer(cfg, &format!("matrix:{sender}")).await;
+                let txn_id = format!("{:x}", rand::random::<u64>());
+                client
+                    .put(endpoint(&[
+                        "rooms",
+                        room_id,
+                        "send",
+                        "m.room.message",
+                        &txn_id,
+                    ])?)
+                    .bearer_auth(&matrix.access_token)
+                    .json(&json!({ "msgtype": "m.text", "body": reply }))
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+        }
+    }
+}
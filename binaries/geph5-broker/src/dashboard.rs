@@ -0,0 +1,245 @@
+use std::{
+    collections::BTreeMap,
+    ops::Deref,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use async_io::Timer;
+use axum::{
+    extract::Query,
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{database::POSTGRES, CONFIG_FILE};
+
+/// Configuration for the aggregate statistics served to operators' dashboards.
+#[derive(Deserialize, Clone, Debug)]
+pub struct DashboardConfig {
+    /// The bearer token that requests for statistics must carry.
+    pub token: String,
+    /// How often the number of exits and bridges is recorded.
+    #[serde(default = "default_snapshot_interval")]
+    pub snapshot_interval_secs: u64,
+    /// How long recorded statistics are kept.
+    #[serde(default = "default_retention_days")]
+    pub retention_days: u32,
+}
+
+fn default_snapshot_interval() -> u64 {
+    300
+}
+
+fn default_retention_days() -> u32 {
+    90
+}
+
+/// How often every instance adds the counts it gathered to the daily totals.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Counts gathered since the last flush, by metric and key.
+static PENDING_COUNTS: LazyLock<Mutex<BTreeMap<(&'static str, String), i64>>> =
+    LazyLock::new(Default::default);
+
+/// Creates the rollup tables if they do not exist yet. Daily totals hold counts, such as tokens issued, while snapshots hold how many of something there were at a point in time, such as bridges.
+pub async fn init_dashboard_tables() -> anyhow::Result<()> {
+    sqlx::query(
+        r"CREATE TABLE IF NOT EXISTS stats_daily (
+            day DATE NOT NULL,
+            metric TEXT NOT NULL,
+            key TEXT NOT NULL,
+            value BIGINT NOT NULL,
+            PRIMARY KEY (day, metric, key)
+        )",
+    )
+    .execute(POSTGRES.deref())
+    .await?;
+    sqlx::query(
+        r"CREATE TABLE IF NOT EXISTS stats_snapshots (
+            taken BIGINT NOT NULL,
+            metric TEXT NOT NULL,
+            key TEXT NOT NULL,
+            value BIGINT NOT NULL,
+            PRIMARY KEY (taken, metric, key)
+        )",
+    )
+    .execute(POSTGRES.deref())
+    .await?;
+    Ok(())
+}
+
+/// Counts one occurrence of something towards today's total, such as a token issued at some account level.
+pub fn count(metric: &'static str, key: &str) {
+    if CONFIG_FILE.wait().dashboard.is_none() {
+        return;
+    }
+    *PENDING_COUNTS
+        .lock()
+        .unwrap()
+        .entry((metric, key.to_string()))
+        .or_default() += 1;
+}
+
+/// This loop adds the counts this instance gathered to the daily totals. Every instance runs it, since each only knows about the requests it served.
+pub async fn stats_flush_loop() -> anyhow::Result<()> {
+    if CONFIG_FILE.wait().dashboard.is_none() {
+        return smol::future::pending().await;
+    }
+    loop {
+        Timer::after(FLUSH_INTERVAL).await;
+        let counts = std::mem::take(&mut *PENDING_COUNTS.lock().unwrap());
+        if counts.is_empty() {
+            continue;
+        }
+        if let Err(err) = flush_counts(&counts).await {
+            // keep the counts for the next try
+            let mut pending = PENDING_COUNTS.lock().unwrap();
+            for (key, value) in counts {
+                *pending.entry(key).or_default() += value;
+            }
+            return Err(err);
+        }
+    }
+}
+
+async fn flush_counts(counts: &BTreeMap<(&'static str, String), i64>) -> anyhow::Result<()> {
+    let mut txn = POSTGRES.begin().await?;
+    for ((metric, key), value) in counts {
+        sqlx::query(
+            r"INSERT INTO stats_daily (day, metric, key, value)
+            VALUES ((now() at time zone 'utc')::date, $1, $2, $3)
+            ON CONFLICT (day, metric, key) DO UPDATE
+            SET value = stats_daily.value + EXCLUDED.value",
+        )
+        .bind(*metric)
+        .bind(key)
+        .bind(*value)
+        .execute(&mut *txn)
+        .await?;
+    }
+    txn.commit().await?;
+    Ok(())
+}
+
+/// This loop records how many exits and bridges are up, and forgets statistics older than the retention period.
+#[tracing::instrument]
+pub async fn stats_snapshot_loop() -> anyhow::Result<()> {
+    let Some(cfg) = &CONFIG_FILE.wait().dashboard else {
+        return smol::future::pending().await;
+    };
+    tracing::info!("starting the stats snapshot loop");
+    loop {
+        let mut txn = POSTGRES.begin().await?;
+        sqlx::query(
+            r"INSERT INTO stats_snapshots (taken, metric, key, value)
+            SELECT extract(epoch from now())::bigint, 'active_exits', '', count(*)
+            FROM exits_new WHERE expiry > extract(epoch from now())",
+        )
+        .execute(&mut *txn)
+        .await?;
+        sqlx::query(
+            r"INSERT INTO stats_snapshots (taken, metric, key, value)
+            SELECT extract(epoch from now())::bigint, 'bridges', pool, count(*)
+            FROM bridges_new WHERE expiry > extract(epoch from now())
+            GROUP BY pool",
+        )
+        .execute(&mut *txn)
+        .await?;
+        sqlx::query(
+            "DELETE FROM stats_snapshots WHERE taken < extract(epoch from now()) - $1 * 86400",
+        )
+        .bind(cfg.retention_days as i64)
+        .execute(&mut *txn)
+        .await?;
+        sqlx::query(
+            "DELETE FROM stats_daily WHERE day < (now() at time zone 'utc')::date - $1::integer",
+        )
+        .bind(cfg.retention_days as i32)
+        .execute(&mut *txn)
+        .await?;
+        txn.commit().await?;
+        tracing::debug!("recorded a stats snapshot");
+        Timer::after(Duration::from_secs(cfg.snapshot_interval_secs)).await;
+    }
+}
+
+#[derive(Deserialize)]
+pub struct StatsQuery {
+    /// How many days back to go.
+    #[serde(default = "default_days")]
+    days: u32,
+}
+
+fn default_days() -> u32 {
+    30
+}
+
+/// Serves the daily totals and snapshots of the last few days, to requests carrying the configured bearer token.
+pub async fn stats(headers: HeaderMap, Query(query): Query<StatsQuery>) -> Response {
+    let Some(cfg) = &CONFIG_FILE.wait().dashboard else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let authorized = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| token == cfg.token);
+    if !authorized {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let days = query.days.clamp(1, cfg.retention_days.max(1));
+    match fetch_stats(days).await {
+        Ok(stats) => Json(stats).into_response(),
+        Err(err) => {
+            tracing::warn!(err = debug(err), "could not fetch stats");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+async fn fetch_stats(days: u32) -> anyhow::Result<serde_json::Value> {
+    let daily: Vec<(String, String, String, i64)> = sqlx::query_as(
+        r"SELECT to_char(day, 'YYYY-MM-DD'), metric, key, value FROM stats_daily
+        WHERE day > (now() at time zone 'utc')::date - $1::integer
+        ORDER BY day, metric, key",
+    )
+    .bind(days as i32)
+    .fetch_all(POSTGRES.deref())
+    .await?;
+    let snapshots: Vec<(i64, String, String, i64)> = sqlx::query_as(
+        r"SELECT taken, metric, key, value FROM stats_snapshots
+        WHERE taken > extract(epoch from now()) - $1 * 86400
+        ORDER BY taken, metric, key",
+    )
+    .bind(days as i64)
+    .fetch_all(POSTGRES.deref())
+    .await?;
+
+    // daily totals go by day, then metric, then key
+    let mut by_day: BTreeMap<String, BTreeMap<String, BTreeMap<String, i64>>> = BTreeMap::new();
+    for (day, metric, key, value) in daily {
+        by_day
+            .entry(day)
+            .or_default()
+            .entry(metric)
+            .or_default()
+            .insert(key, value);
+    }
+    let mut by_time: BTreeMap<i64, BTreeMap<String, BTreeMap<String, i64>>> = BTreeMap::new();
+    for (taken, metric, key, value) in snapshots {
+        by_time
+            .entry(taken)
+            .or_default()
+            .entry(metric)
+            .or_default()
+            .insert(key, value);
+    }
+    Ok(json!({
+        "daily": by_day,
+        "snapshots": by_time,
+    }))
+}
@@ -1,7 +1,10 @@
-use std::{ops::Deref, str::FromStr, sync::LazyLock, time::Duration};
+use std::{net::SocketAddr, ops::Deref, str::FromStr, sync::LazyLock, time::Duration};
 
 use async_io::Timer;
-use geph5_broker_protocol::BridgeDescriptor;
+use geph5_broker_protocol::{
+    Announcement, AnnouncementKind, BridgeDescriptor, BridgeUsage, BridgeUsageSummary,
+    DirectUdpListener, ExitFeatures, ExitLoad,
+};
 use moka::future::Cache;
 
 use rand::Rng;
@@ -12,7 +15,7 @@ use sqlx::{
     PgPool,
 };
 
-use crate::CONFIG_FILE;
+use crate::{partition::BridgeQuery, reachability::UNREACHABLE_THRESHOLD, CONFIG_FILE};
 
 pub static POSTGRES: LazyLock<PgPool> = LazyLock::new(|| {
     smolscale::block_on(
@@ -46,10 +49,52 @@ pub async fn database_gc_loop() -> anyhow::Result<()> {
             .execute(POSTGRES.deref())
             .await?;
         tracing::debug!(rows_affected = res.rows_affected(), "cleaned up exits");
+        let res =
+            sqlx::query("delete from exit_loads where updated < extract(epoch from now()) - $1")
+                .bind(EXIT_LOAD_TTL_SECS)
+                .execute(POSTGRES.deref())
+                .await?;
+        tracing::debug!(rows_affected = res.rows_affected(), "cleaned up exit loads");
+        let res =
+            sqlx::query("delete from exit_features where updated < extract(epoch from now()) - $1")
+                .bind(EXIT_LOAD_TTL_SECS)
+                .execute(POSTGRES.deref())
+                .await?;
+        tracing::debug!(
+            rows_affected = res.rows_affected(),
+            "cleaned up exit features"
+        );
         let res = sqlx::query("delete from bridges_new where expiry < extract(epoch from now())")
             .execute(POSTGRES.deref())
             .await?;
         tracing::debug!(rows_affected = res.rows_affected(), "cleaned up bridges");
+        let res = sqlx::query(
+            "delete from bridge_usage where hour < extract(epoch from now())::bigint / 3600 - $1",
+        )
+        .bind(BRIDGE_USAGE_RETENTION_HOURS)
+        .execute(POSTGRES.deref())
+        .await?;
+        tracing::debug!(
+            rows_affected = res.rows_affected(),
+            "cleaned up bridge usage"
+        );
+        let res =
+            sqlx::query("delete from volunteer_bridges where expiry < extract(epoch from now())")
+                .execute(POSTGRES.deref())
+                .await?;
+        tracing::debug!(
+            rows_affected = res.rows_affected(),
+            "cleaned up volunteer bridges"
+        );
+        let res = sqlx::query(
+            "delete from bridge_limits where updated < extract(epoch from now()) - 86400",
+        )
+        .execute(POSTGRES.deref())
+        .await?;
+        tracing::debug!(
+            rows_affected = res.rows_affected(),
+            "cleaned up bridge limits"
+        );
     }
 }
 
@@ -89,8 +134,273 @@ pub async fn insert_exit(exit: &ExitRow) -> anyhow::Result<()> {
     Ok(())
 }
 
-pub async fn query_bridges(key: &str) -> anyhow::Result<Vec<(BridgeDescriptor, u32, bool)>> {
-    static CACHE: LazyLock<Cache<String, Vec<(BridgeDescriptor, u32, bool)>>> =
+/// How long a load report counts for. Exits report every few seconds, so older reports come from exits that stopped reporting.
+pub const EXIT_LOAD_TTL_SECS: i64 = 30;
+
+/// Creates the table of exit load reports, which exit lists depend on, if it does not exist yet.
+pub async fn init_exit_load_table() -> anyhow::Result<()> {
+    sqlx::query(
+        r"CREATE TABLE IF NOT EXISTS exit_loads (
+            pubkey BYTEA PRIMARY KEY,
+            kbps REAL NOT NULL,
+            kbps_capacity REAL NOT NULL,
+            sessions INTEGER NOT NULL,
+            streams INTEGER NOT NULL,
+            stream_capacity INTEGER NOT NULL,
+            cpu REAL NOT NULL,
+            remaining_capacity REAL NOT NULL,
+            updated BIGINT NOT NULL
+        )",
+    )
+    .execute(POSTGRES.deref())
+    .await?;
+    Ok(())
+}
+
+pub async fn insert_exit_load(pubkey: [u8; 32], load: &ExitLoad) -> anyhow::Result<()> {
+    sqlx::query(
+        r"INSERT INTO exit_loads (pubkey, kbps, kbps_capacity, sessions, streams, stream_capacity, cpu, remaining_capacity, updated)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, extract(epoch from now())::bigint)
+        ON CONFLICT (pubkey) DO UPDATE
+        SET kbps = EXCLUDED.kbps,
+            kbps_capacity = EXCLUDED.kbps_capacity,
+            sessions = EXCLUDED.sessions,
+            streams = EXCLUDED.streams,
+            stream_capacity = EXCLUDED.stream_capacity,
+            cpu = EXCLUDED.cpu,
+            remaining_capacity = EXCLUDED.remaining_capacity,
+            updated = EXCLUDED.updated
+        ",
+    )
+    .bind(pubkey)
+    .bind(load.kbps)
+    .bind(load.kbps_capacity)
+    .bind(load.sessions.min(i32::MAX as u32) as i32)
+    .bind(load.streams.min(i32::MAX as u32) as i32)
+    .bind(load.stream_capacity.min(i32::MAX as u32) as i32)
+    .bind(load.cpu)
+    .bind(load.remaining_capacity())
+    .execute(POSTGRES.deref())
+    .await?;
+    Ok(())
+}
+
+/// Creates the table of what exits reported they can do, if it does not exist yet. Reports expire like load reports do.
+pub async fn init_exit_features_table() -> anyhow::Result<()> {
+    sqlx::query(
+        r"CREATE TABLE IF NOT EXISTS exit_features (
+            pubkey BYTEA PRIMARY KEY,
+            ipv6 BOOLEAN NOT NULL,
+            obfs_udp_listen TEXT,
+            obfs_udp_cookie TEXT,
+            kcp_listen TEXT,
+            kcp_cookie TEXT,
+            updated BIGINT NOT NULL
+        )",
+    )
+    .execute(POSTGRES.deref())
+    .await?;
+    Ok(())
+}
+
+pub async fn insert_exit_features(pubkey: [u8; 32], features: &ExitFeatures) -> anyhow::Result<()> {
+    sqlx::query(
+        r"INSERT INTO exit_features (pubkey, ipv6, obfs_udp_listen, obfs_udp_cookie, kcp_listen, kcp_cookie, updated)
+        VALUES ($1, $2, $3, $4, $5, $6, extract(epoch from now())::bigint)
+        ON CONFLICT (pubkey) DO UPDATE
+        SET ipv6 = EXCLUDED.ipv6,
+            obfs_udp_listen = EXCLUDED.obfs_udp_listen,
+            obfs_udp_cookie = EXCLUDED.obfs_udp_cookie,
+            kcp_listen = EXCLUDED.kcp_listen,
+            kcp_cookie = EXCLUDED.kcp_cookie,
+            updated = EXCLUDED.updated
+        ",
+    )
+    .bind(pubkey)
+    .bind(features.ipv6)
+    .bind(features.obfs_udp.as_ref().map(|l| l.addr.to_string()))
+    .bind(features.obfs_udp.as_ref().map(|l| l.cookie.clone()))
+    .bind(features.kcp.as_ref().map(|l| l.addr.to_string()))
+    .bind(features.kcp.as_ref().map(|l| l.cookie.clone()))
+    .execute(POSTGRES.deref())
+    .await?;
+    Ok(())
+}
+
+/// What the exit with the given b2e address last reported it can do, if it reported recently.
+pub async fn query_exit_features(exit_b2e: SocketAddr) -> anyhow::Result<Option<ExitFeatures>> {
+    static CACHE: LazyLock<Cache<SocketAddr, Option<ExitFeatures>>> = LazyLock::new(|| {
+        Cache::builder()
+            .time_to_live(Duration::from_secs(60))
+            .build()
+    });
+    CACHE
+        .try_get_with(exit_b2e, async {
+            #[allow(clippy::type_complexity)]
+            let row: Option<(
+                bool,
+                Option<String>,
+                Option<String>,
+                Option<String>,
+                Option<String>,
+                i64,
+            )> = sqlx::query_as(
+                r"select f.ipv6, f.obfs_udp_listen, f.obfs_udp_cookie, f.kcp_listen, f.kcp_cookie, f.updated
+                from exit_features f
+                join exits_new e on e.pubkey = f.pubkey
+                where e.b2e_listen = $1 and f.updated > extract(epoch from now()) - $2",
+            )
+            .bind(exit_b2e.to_string())
+            .bind(EXIT_LOAD_TTL_SECS)
+            .fetch_optional(POSTGRES.deref())
+            .await?;
+            anyhow::Ok(row.map(
+                |(ipv6, obfs_udp_listen, obfs_udp_cookie, kcp_listen, kcp_cookie, updated)| {
+                    ExitFeatures {
+                        ipv6,
+                        obfs_udp: direct_udp_listener(obfs_udp_listen, obfs_udp_cookie),
+                        kcp: direct_udp_listener(kcp_listen, kcp_cookie),
+                        timestamp: updated as _,
+                    }
+                },
+            ))
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!(e))
+}
+
+fn direct_udp_listener(
+    listen: Option<String>,
+    cookie: Option<String>,
+) -> Option<DirectUdpListener> {
+    Some(DirectUdpListener {
+        addr: listen?.parse().ok()?,
+        cookie: cookie?,
+    })
+}
+
+/// The public keys of the exits that reported they can reach IPv6 destinations.
+pub async fn query_ipv6_exits() -> anyhow::Result<Vec<[u8; 32]>> {
+    static CACHE: LazyLock<Cache<(), Vec<[u8; 32]>>> = LazyLock::new(|| {
+        Cache::builder()
+            .time_to_live(Duration::from_secs(60))
+            .build()
+    });
+    CACHE
+        .try_get_with((), async {
+            let rows: Vec<(Vec<u8>,)> = sqlx::query_as(
+                "select pubkey from exit_features where ipv6 and updated > extract(epoch from now()) - $1",
+            )
+            .bind(EXIT_LOAD_TTL_SECS)
+            .fetch_all(POSTGRES.deref())
+            .await?;
+            anyhow::Ok(
+                rows.into_iter()
+                    .filter_map(|(pubkey,)| pubkey.try_into().ok())
+                    .collect(),
+            )
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!(e))
+}
+
+/// How many hours of bridge usage we keep.
+const BRIDGE_USAGE_RETENTION_HOURS: i64 = 24 * 7;
+
+/// Creates the tables of how many bytes bridges relayed to each exit, bucketed by hour since the Unix epoch, and of the rate limits they last reported, if they do not exist yet.
+pub async fn init_bridge_usage_table() -> anyhow::Result<()> {
+    sqlx::query(
+        r"CREATE TABLE IF NOT EXISTS bridge_usage (
+            listen TEXT NOT NULL,
+            pool TEXT NOT NULL,
+            exit TEXT NOT NULL,
+            hour BIGINT NOT NULL,
+            bytes BIGINT NOT NULL,
+            PRIMARY KEY (listen, exit, hour)
+        )",
+    )
+    .execute(POSTGRES.deref())
+    .await?;
+    sqlx::query(
+        r"CREATE TABLE IF NOT EXISTS bridge_limits (
+            listen TEXT PRIMARY KEY,
+            client_kbps INTEGER NOT NULL,
+            total_kbps INTEGER NOT NULL,
+            updated BIGINT NOT NULL
+        )",
+    )
+    .execute(POSTGRES.deref())
+    .await?;
+    Ok(())
+}
+
+/// Adds what a bridge reported to the usage of the current hour, and remembers the limits it reported.
+pub async fn insert_bridge_usage(usage: &BridgeUsage) -> anyhow::Result<()> {
+    let mut txn = POSTGRES.begin().await?;
+    for (exit, bytes) in usage.bytes_per_exit.iter() {
+        sqlx::query(
+            r"INSERT INTO bridge_usage (listen, pool, exit, hour, bytes)
+            VALUES ($1, $2, $3, extract(epoch from now())::bigint / 3600, $4)
+            ON CONFLICT (listen, exit, hour) DO UPDATE
+            SET pool = EXCLUDED.pool,
+                bytes = bridge_usage.bytes + EXCLUDED.bytes
+            ",
+        )
+        .bind(usage.control_listen.to_string())
+        .bind(&usage.pool)
+        .bind(exit.to_string())
+        .bind(*bytes as i64)
+        .execute(&mut *txn)
+        .await?;
+    }
+    sqlx::query(
+        r"INSERT INTO bridge_limits (listen, client_kbps, total_kbps, updated)
+        VALUES ($1, $2, $3, extract(epoch from now()))
+        ON CONFLICT (listen) DO UPDATE
+        SET client_kbps = EXCLUDED.client_kbps,
+            total_kbps = EXCLUDED.total_kbps,
+            updated = EXCLUDED.updated",
+    )
+    .bind(usage.control_listen.to_string())
+    .bind(usage.limits.client_kbps as i32)
+    .bind(usage.limits.total_kbps as i32)
+    .execute(&mut *txn)
+    .await?;
+    txn.commit().await?;
+    Ok(())
+}
+
+/// How much each bridge of the pool relayed within the last day, busiest first.
+pub async fn query_bridge_usage(pool: &str) -> anyhow::Result<Vec<BridgeUsageSummary>> {
+    let rows: Vec<(String, i64, i64)> = sqlx::query_as(
+        r"SELECT listen,
+            COALESCE(SUM(bytes) FILTER (WHERE hour >= extract(epoch from now())::bigint / 3600 - 1), 0)::bigint,
+            COALESCE(SUM(bytes), 0)::bigint AS day
+        FROM bridge_usage
+        WHERE pool = $1 AND hour >= extract(epoch from now())::bigint / 3600 - 24
+        GROUP BY listen
+        ORDER BY day DESC",
+    )
+    .bind(pool)
+    .fetch_all(POSTGRES.deref())
+    .await?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|(listen, last_hour, last_day)| {
+            Some(BridgeUsageSummary {
+                control_listen: listen.parse().ok()?,
+                bytes_last_hour: last_hour as u64,
+                bytes_last_day: last_day as u64,
+            })
+        })
+        .collect())
+}
+
+/// Picks one bridge from every pool, the one nearest to the requester's partition, skipping bridges that probes found to be down everywhere, or unreachable from the requester's country when we know it, and bridges that are saturating their own rate limit.
+pub async fn query_bridges(
+    query: &BridgeQuery,
+) -> anyhow::Result<Vec<(BridgeDescriptor, u32, bool)>> {
+    static CACHE: LazyLock<Cache<BridgeQuery, Vec<(BridgeDescriptor, u32, bool)>>> =
         LazyLock::new(|| {
             Cache::builder()
                 .time_to_live(Duration::from_secs(300))
@@ -104,7 +414,7 @@ pub async fn query_bridges(key: &str) -> anyhow::Result<Vec<(BridgeDescriptor, u
     // );
 
     CACHE
-        .try_get_with(key.to_string(), async {
+        .try_get_with(query.clone(), async {
             let raw: Vec<(String, String, String, i64, i32, bool)> = sqlx::query_as(
                 r"
 WITH selected_bridges AS (
@@ -118,8 +428,30 @@ WITH selected_bridges AS (
     FROM bridges_new bn
     LEFT JOIN bridge_group_delays bgd 
            ON bn.pool = bgd.pool
+    -- a country's vantage points only count a bridge as unreachable if none of them reach it
+    WHERE bn.listen NOT IN (
+        SELECT bp.listen
+          FROM bridge_probes bp
+         WHERE bp.country = '' OR bp.country = $2
+         GROUP BY bp.listen, bp.country
+        HAVING MIN(bp.consecutive_failures) >= $3
+    )
+    -- bridges that spent the last hour near their own bandwidth cap get no new clients until they have room again
+      AND bn.listen NOT IN (
+        SELECT bl.listen
+          FROM bridge_limits bl
+          JOIN bridge_usage bu ON bu.listen = bl.listen
+         WHERE bl.total_kbps > 0
+           AND bu.hour = extract(epoch from now())::bigint / 3600 - 1
+         GROUP BY bl.listen, bl.total_kbps
+        HAVING SUM(bu.bytes) >= bl.total_kbps::bigint * 1000 * 3600 * 9 / 10
+    )
+      AND (bn.pool = ANY($7)) = $8
+      AND NOT (bn.pool = ANY($9))
     ORDER BY 
         bn.pool,
+        -- how far the bridge's partition is past the requester's, going around the ring
+        (((('x' || SUBSTR(ENCODE(DIGEST(bn.listen || $4, 'sha256'), 'hex'), 1, 8))::bit(32)::bigint % $5) - $6 + $5) % $5),
         ENCODE(DIGEST(bn.listen || $1, 'sha256'), 'hex')
 ),
 updated AS (
@@ -141,7 +473,15 @@ JOIN updated u ON u.listen = sb.listen;
 
         ",
             )
-            .bind(key)
+            .bind(&query.key)
+            .bind(query.country.as_deref().unwrap_or_default())
+            .bind(UNREACHABLE_THRESHOLD)
+            .bind(&query.salt)
+            .bind(query.partitions as i64)
+            .bind(query.partition as i64)
+            .bind(&query.reserve_pools)
+            .bind(query.reserve)
+            .bind(&query.excluded_pools)
             .fetch_all(POSTGRES.deref())
             .await?;
             anyhow::Ok(
@@ -164,3 +504,62 @@ JOIN updated u ON u.listen = sb.listen;
         .await
         .map_err(|e| anyhow::anyhow!(e))
 }
+
+/// Creates the table of announcements, which operators fill in by hand, if it does not exist yet.
+pub async fn init_announcements_table() -> anyhow::Result<()> {
+    sqlx::query(
+        r"CREATE TABLE IF NOT EXISTS announcements (
+            id TEXT PRIMARY KEY,
+            kind TEXT NOT NULL,
+            title TEXT NOT NULL,
+            contents TEXT NOT NULL,
+            regions TEXT[] NOT NULL DEFAULT '{}',
+            created_unix BIGINT NOT NULL,
+            expiry_unix BIGINT NOT NULL
+        )",
+    )
+    .execute(POSTGRES.deref())
+    .await?;
+    Ok(())
+}
+
+/// Queries all the announcements that have not yet expired.
+pub async fn query_announcements() -> anyhow::Result<Vec<Announcement>> {
+    static CACHE: LazyLock<Cache<(), Vec<Announcement>>> = LazyLock::new(|| {
+        Cache::builder()
+            .time_to_live(Duration::from_secs(60))
+            .build()
+    });
+
+    CACHE
+        .try_get_with((), async {
+            let raw: Vec<(String, String, String, String, Vec<String>, i64, i64)> = sqlx::query_as(
+                r"SELECT id, kind, title, contents, regions, created_unix, expiry_unix
+                    FROM announcements
+                    WHERE expiry_unix > extract(epoch from now())
+                    ORDER BY created_unix DESC",
+            )
+            .fetch_all(POSTGRES.deref())
+            .await?;
+            anyhow::Ok(
+                raw.into_iter()
+                    .map(|row| Announcement {
+                        id: row.0,
+                        kind: match row.1.as_str() {
+                            "maintenance" => AnnouncementKind::Maintenance,
+                            "update_required" => AnnouncementKind::UpdateRequired,
+                            "regional_advice" => AnnouncementKind::RegionalAdvice,
+                            _ => AnnouncementKind::Info,
+                        },
+                        title: row.2,
+                        contents: row.3,
+                        regions: row.4,
+                        created_unix: row.5 as _,
+                        expiry_unix: row.6 as _,
+                    })
+                    .collect(),
+            )
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!(e))
+}
@@ -0,0 +1,124 @@
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ed25519_dalek::VerifyingKey;
+use futures_util::future::join_all;
+use geph5_broker_protocol::{
+    encode_bridge_bundle, AccountLevel, BridgeBundle, BundledRoute, ExitDescriptor, Signed,
+    DOMAIN_BRIDGE_BUNDLE,
+};
+use moka::future::Cache;
+
+use crate::{
+    database::query_bridges, partition::BridgeQuery, routes::bridge_to_leaf_route,
+    rpc_impl::free_exits, MASTER_SECRET,
+};
+
+/// How long the routes in a bundle keep working. Bridges forget a forwarding an hour after it was last handed out, and the routes we build are cached for up to ten minutes before that.
+pub const BUNDLE_LIFETIME: Duration = Duration::from_secs(2700);
+
+/// Limits how often each identity, such as a connect token or an email address, may be handed bridges.
+pub struct IdentityLimiter {
+    counts: Cache<String, Arc<AtomicU32>>,
+    max: u32,
+}
+
+impl IdentityLimiter {
+    /// Allows up to `max` requests per identity within every `period`, counted from the first.
+    pub fn new(max: u32, period: Duration) -> Self {
+        Self {
+            counts: Cache::builder().time_to_live(period).build(),
+            max,
+        }
+    }
+
+    /// Counts a request, returning whether it is allowed.
+    pub async fn allow(&self, identity: &str) -> bool {
+        self.counts
+            .get_with(identity.to_string(), async { Arc::new(AtomicU32::new(0)) })
+            .await
+            .fetch_add(1, Ordering::Relaxed)
+            < self.max
+    }
+}
+
+/// Builds a signed bundle of bridge routes for someone identified by something other than a connect token, such as an email address. Identities are placed into bridge partitions just like tokens, as free users with no known country, so that harvesting identities can't enumerate every bridge either.
+pub async fn bridge_bundle(identity: &str, count: usize) -> anyhow::Result<Signed<BridgeBundle>> {
+    let exits = free_exits().await.map_err(|e| anyhow::anyhow!(e))?;
+    anyhow::ensure!(!exits.is_empty(), "no free exits to bundle routes to");
+    let (exit_pubkey, exit) = pick_exit(identity, &exits);
+
+    let bridges =
+        query_bridges(&BridgeQuery::new(identity, None, AccountLevel::Free, false)).await?;
+    let routes = join_all(
+        bridges
+            .into_iter()
+            .filter(|(_, _, is_plus)| !is_plus)
+            .take(count)
+            .map(|(desc, delay_ms, _)| bridge_to_leaf_route(desc, delay_ms, exit.b2e_listen)),
+    )
+    .await
+    .into_iter()
+    .filter_map(|route| {
+        route
+            .inspect_err(|err| tracing::warn!(err = debug(err), "could not bundle a bridge"))
+            .ok()
+    })
+    .map(|route| BundledRoute {
+        exit_pubkey,
+        exit: exit.clone(),
+        route,
+    })
+    .collect::<Vec<_>>();
+    anyhow::ensure!(!routes.is_empty(), "no bridges to bundle");
+
+    let expiry = (SystemTime::now() + BUNDLE_LIFETIME)
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    Ok(Signed::new(
+        BridgeBundle { routes, expiry },
+        DOMAIN_BRIDGE_BUNDLE,
+        &MASTER_SECRET,
+    ))
+}
+
+/// Picks an exit for an identity by weighted rendezvous hashing, so that the same identity keeps getting the same exit while exits with more capacity left get proportionally more identities.
+fn pick_exit(
+    identity: &str,
+    exits: &[(VerifyingKey, ExitDescriptor)],
+) -> (VerifyingKey, ExitDescriptor) {
+    exits
+        .iter()
+        .map(|(pubkey, exit)| {
+            let hash = blake3::keyed_hash(pubkey.as_bytes(), identity.as_bytes());
+            let uniform = (u64::from_be_bytes(hash.as_bytes()[..8].try_into().unwrap()) as f64
+                + 1.0)
+                / (u64::MAX as f64 + 2.0);
+            let weight = (1.0 - exit.load as f64).max(0.01);
+            (-uniform.ln() / weight, (*pubkey, exit.clone()))
+        })
+        .min_by(|a, b| a.0.total_cmp(&b.0))
+        .unwrap()
+        .1
+}
+
+/// The message that side channels answer with: how to use the bundle, the bundle itself, and optionally some hints on getting connected.
+pub fn bundle_message(bundle: &Signed<BridgeBundle>, hints: Option<&str>) -> String {
+    let mut message = format!(
+        "Here are some bridges for Geph. Copy everything below, including the lines with dashes, and paste it into Geph within the next {} minutes.\n\n{}\n",
+        BUNDLE_LIFETIME.as_secs() / 60,
+        encode_bridge_bundle(bundle)
+    );
+    if let Some(hints) = hints {
+        message.push('\n');
+        message.push_str(hints);
+        message.push('\n');
+    }
+    message
+}
@@ -0,0 +1,134 @@
+use std::{sync::LazyLock, time::Duration};
+
+use axum::{extract::Path, http::StatusCode, Form};
+use serde::Deserialize;
+
+use crate::{
+    distribution::{bridge_bundle, bundle_message, IdentityLimiter},
+    CONFIG_FILE,
+};
+
+/// Configuration for handing out bridges over email, for those who can't reach the broker at all. Mail to our address is forwarded to a webhook by Mailgun, and answers go out through Mailgun too.
+#[derive(Deserialize, Clone, Debug)]
+pub struct EmailConfig {
+    /// The secret in the path of the webhook, so that only Mailgun can post to it.
+    pub webhook_secret: String,
+    pub mailgun_domain: String,
+    pub mailgun_api_key: String,
+    /// The address we answer from.
+    pub from: String,
+    /// Only senders at these domains are answered, since addresses elsewhere are too cheap to come by in bulk.
+    #[serde(default = "default_allowed_domains")]
+    pub allowed_domains: Vec<String>,
+    #[serde(default = "default_per_address_per_day")]
+    pub per_address_per_day: u32,
+    #[serde(default = "default_bridges_per_reply")]
+    pub bridges_per_reply: usize,
+}
+
+fn default_allowed_domains() -> Vec<String> {
+    vec!["gmail.com".into(), "riseup.net".into()]
+}
+
+fn default_per_address_per_day() -> u32 {
+    3
+}
+
+fn default_bridges_per_reply() -> usize {
+    3
+}
+
+/// The fields we use out of what Mailgun posts for an incoming email.
+#[derive(Deserialize)]
+pub struct InboundEmail {
+    sender: String,
+}
+
+/// The webhook that incoming emails are posted to. Every email from an allowed domain gets a few bridges back, up to a number of times a day per sender.
+pub async fn inbound_email(
+    Path(secret): Path<String>,
+    Form(email): Form<InboundEmail>,
+) -> StatusCode {
+    let Some(cfg) = &CONFIG_FILE.wait().email else {
+        return StatusCode::NOT_FOUND;
+    };
+    if secret != cfg.webhook_secret {
+        return StatusCode::FORBIDDEN;
+    }
+    // Mailgun retries on errors, and we would rather drop an email than answer it twice
+    tokio::spawn(async move {
+        if let Err(err) = answer_email(cfg, &email.sender).await {
+            tracing::warn!(err = debug(err), "could not answer an email");
+        }
+    });
+    StatusCode::OK
+}
+
+async fn answer_email(cfg: &EmailConfig, sender: &str) -> anyhow::Result<()> {
+    static PER_ADDRESS: LazyLock<IdentityLimiter> = LazyLock::new(|| {
+        IdentityLimiter::new(
+            CONFIG_FILE
+                .wait()
+                .email
+                .as_ref()
+                .map(|cfg| cfg.per_address_per_day)
+                .unwrap_or_default(),
+            Duration::from_secs(86400),
+        )
+    });
+
+    let Some(address) = normalize_address(sender) else {
+        anyhow::bail!("malformed sender address")
+    };
+    let domain = address.rsplit('@').next().unwrap_or_default();
+    if !cfg.allowed_domains.iter().any(|allowed| allowed == domain) {
+        tracing::debug!(domain, "ignoring an email from a domain we do not answer");
+        return Ok(());
+    }
+    if !PER_ADDRESS.allow(&address).await {
+        tracing::debug!("ignoring an email from an address that asked too often");
+        return Ok(());
+    }
+
+    let bundle = bridge_bundle(&format!("email:{address}"), cfg.bridges_per_reply).await?;
+    let text = bundle_message(&bundle, None);
+    let resp = reqwest::Client::new()
+        .post(format!(
+            "https://api.mailgun.net/v3/{}/messages",
+            cfg.mailgun_domain
+        ))
+        .basic_auth("api", Some(&cfg.mailgun_api_key))
+        .form(&[
+            ("from", cfg.from.as_str()),
+            ("to", address.as_str()),
+            ("subject", "Your Geph bridges"),
+            ("text", text.as_str()),
+        ])
+        .send()
+        .await?;
+    anyhow::ensure!(
+        resp.status().is_success(),
+        "Mailgun answered {}",
+        resp.status()
+    );
+    tracing::debug!(
+        routes = bundle.inner.routes.len(),
+        "answered an email with bridges"
+    );
+    Ok(())
+}
+
+/// Brings an address into the form that rate limits apply to, so that the same mailbox can't ask again under a different spelling. Tags after a plus are dropped everywhere, and so are the dots Gmail ignores.
+fn normalize_address(address: &str) -> Option<String> {
+    let address = address.trim().to_lowercase();
+    let (local, domain) = address.rsplit_once('@')?;
+    let local = local.split('+').next()?;
+    let (local, domain) = match domain {
+        "gmail.com" | "googlemail.com" => (local.replace('.', ""), "gmail.com"),
+        domain => (local.to_string(), domain),
+    };
+    if local.is_empty() || domain.is_empty() {
+        return None;
+    }
+    Some(format!("{local}@{domain}"))
+}
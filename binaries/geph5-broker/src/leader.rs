@@ -0,0 +1,107 @@
+use std::{
+    collections::BTreeSet,
+    future::Future,
+    ops::Deref,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use async_io::Timer;
+use axum::{http::StatusCode, Json};
+use serde_json::{json, Value};
+use smol::future::FutureExt as _;
+use smol_timeout2::TimeoutExt;
+use sqlx::Connection;
+
+use crate::database::POSTGRES;
+
+/// How often we try to take over a job that another instance is running.
+const TAKEOVER_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often the leader checks that its connection, and with it the lock, is still alive.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The background jobs this instance currently leads.
+static LEADING: LazyLock<Mutex<BTreeSet<&'static str>>> = LazyLock::new(Default::default);
+
+/// Runs a background job on only one of the broker instances sharing the database. Leadership is a Postgres advisory lock held on a connection of its own, so it passes to another instance as soon as the leader's connection drops, whether it crashed or lost the network. Until this instance wins the lock, this waits; once it loses it, the job is stopped and this returns an error, so that respawning it waits for leadership again.
+pub async fn run_as_leader<F: Future<Output = anyhow::Result<()>>>(
+    job: &'static str,
+    f: impl FnOnce() -> F,
+) -> anyhow::Result<()> {
+    let lock_key = i64::from_be_bytes(
+        blake3::hash(format!("geph5-broker/{job}").as_bytes()).as_bytes()[..8]
+            .try_into()
+            .unwrap(),
+    );
+    let mut conn = loop {
+        let mut conn = POSTGRES.acquire().await?.detach();
+        let (acquired,): (bool,) = sqlx::query_as("SELECT pg_try_advisory_lock($1)")
+            .bind(lock_key)
+            .fetch_one(&mut conn)
+            .await?;
+        if acquired {
+            break conn;
+        }
+        drop(conn);
+        Timer::after(TAKEOVER_INTERVAL).await;
+    };
+
+    tracing::info!(job, "became the leader for a background job");
+    let _leading = Leading::new(job);
+
+    let keepalive = async {
+        loop {
+            Timer::after(KEEPALIVE_INTERVAL).await;
+            if !matches!(conn.ping().timeout(KEEPALIVE_INTERVAL).await, Some(Ok(()))) {
+                anyhow::bail!("lost the connection holding leadership of {job}")
+            }
+        }
+    };
+    f().race(keepalive).await
+}
+
+/// Marks a job as led by this instance for as long as it lives.
+struct Leading(&'static str);
+
+impl Leading {
+    fn new(job: &'static str) -> Self {
+        LEADING.lock().unwrap().insert(job);
+        Self(job)
+    }
+}
+
+impl Drop for Leading {
+    fn drop(&mut self) {
+        LEADING.lock().unwrap().remove(self.0);
+    }
+}
+
+/// Answers as long as the process is up.
+pub async fn live() -> StatusCode {
+    StatusCode::OK
+}
+
+/// Answers whether this instance can serve requests, which needs the database, along with the background jobs it leads.
+pub async fn ready() -> (StatusCode, Json<Value>) {
+    let database_ok = matches!(
+        sqlx::query("SELECT 1")
+            .execute(POSTGRES.deref())
+            .timeout(Duration::from_secs(5))
+            .await,
+        Some(Ok(_))
+    );
+    let leading: Vec<&str> = LEADING.lock().unwrap().iter().copied().collect();
+    let status = if database_ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(json!({
+            "database": database_ok,
+            "leading": leading,
+        })),
+    )
+}
@@ -1,32 +1,62 @@
 use anyhow::Context;
-use axum::{routing::post, Json, Router};
+use axum::{
+    http::HeaderMap,
+    routing::{get, post},
+    Json, Router,
+};
+use bots::BotConfig;
 use clap::Parser;
+use dashboard::{stats_flush_loop, stats_snapshot_loop, DashboardConfig};
 use database::database_gc_loop;
 use ed25519_dalek::SigningKey;
+use email::EmailConfig;
 
 use free_voucher::create_one_day_vouchers_for_all_users;
+use leader::run_as_leader;
+use mirror::MirrorConfig;
 use nano_influxdb::InfluxDbEndpoint;
 use nanorpc::{JrpcRequest, JrpcResponse, RpcService};
 use once_cell::sync::{Lazy, OnceCell};
-
+use partition::PartitionConfig;
+use reachability::{
+    bridge_probe_loop, default_probe_interval, default_vantage_points, init_probe_table,
+    requester_country, VantagePoint,
+};
+
+use routes::MeekConfig;
+use routes_challenge::ChallengeConfig;
 use rpc_impl::WrappedBrokerService;
 use self_stat::self_stat_loop;
 use serde::Deserialize;
 use smolscale::immortal::{Immortal, RespawnStrategy};
-use std::{fmt::Debug, fs, net::SocketAddr, path::PathBuf, sync::LazyLock};
+use std::{fmt::Debug, fs, net::SocketAddr, path::PathBuf, sync::LazyLock, time::Duration};
 use tikv_jemallocator::Jemalloc;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+use trust::{init_trust_tables, trust_strike_loop, TrustConfig};
+use volunteer::{init_volunteer_table, VolunteerConfig};
 
 mod auth;
+mod bots;
+mod dashboard;
 mod database;
+mod distribution;
+mod email;
 
 mod free_voucher;
+mod leader;
+mod mirror;
 mod news;
+mod partition;
 mod payments;
+mod protocol_stats;
 mod puzzle;
+mod reachability;
 mod routes;
+mod routes_challenge;
 mod rpc_impl;
 mod self_stat;
+mod trust;
+mod volunteer;
 
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
@@ -114,6 +144,83 @@ struct ConfigFile {
     /// Optional InfluxDB configuration for metrics
     #[serde(default)]
     influxdb: Option<InfluxDbEndpoint>,
+
+    /// Where bridges are probed from, to tell which ones are down or blocked where
+    #[serde(default = "default_vantage_points")]
+    vantage_points: Vec<VantagePoint>,
+
+    #[serde(default = "default_probe_interval")]
+    probe_interval_secs: u64,
+
+    /// How bridges are split up among requesters
+    #[serde(default)]
+    bridge_partitioning: PartitionConfig,
+
+    /// When bridge-list requests must solve puzzles
+    #[serde(default)]
+    routes_challenge: ChallengeConfig,
+
+    /// Handing out bridges over email, if at all
+    #[serde(default)]
+    email: Option<EmailConfig>,
+
+    /// Handing out bridges through chat bots, if at all
+    #[serde(default)]
+    bots: Option<BotConfig>,
+
+    /// Serving signed lists for CDN caches and static mirrors, if at all
+    #[serde(default)]
+    mirror: Option<MirrorConfig>,
+
+    /// Handing out bridges to trust groups that grow by invites, if at all
+    #[serde(default)]
+    trust: Option<TrustConfig>,
+
+    /// Letting volunteers run bridges that register themselves, if at all
+    #[serde(default)]
+    volunteer_bridges: Option<VolunteerConfig>,
+
+    /// Serving aggregate statistics to operators' dashboards, if at all
+    #[serde(default)]
+    dashboard: Option<DashboardConfig>,
+
+    /// Domain-fronting meek routes through a CDN, if at all
+    #[serde(default)]
+    meek: Option<MeekConfig>,
+
+    /// The server names that QUIC routes send in their handshakes, one picked at random per route. Without any, they send no name
+    #[serde(default)]
+    quic_sni_domains: Vec<String>,
+
+    /// Granting Plus users reverse port forwarding at exits, if at all
+    #[serde(default)]
+    reverse_forward: Option<ReverseForwardConfig>,
+}
+
+/// How much reverse port forwarding Plus users are granted at exits.
+#[derive(Deserialize)]
+struct ReverseForwardConfig {
+    /// How many ports each grant lets a user forward at once, at each exit
+    #[serde(default = "default_reverse_forward_max_ports")]
+    max_ports: u16,
+    /// How long grants last, after which exits end the forwards and clients ask for new grants
+    #[serde(default = "default_reverse_forward_lifetime")]
+    grant_lifetime_secs: u64,
+    /// How many grants each user may be issued per hour
+    #[serde(default = "default_reverse_forward_grants_per_hour")]
+    grants_per_hour: u32,
+}
+
+fn default_reverse_forward_max_ports() -> u16 {
+    4
+}
+
+fn default_reverse_forward_lifetime() -> u64 {
+    86400
+}
+
+fn default_reverse_forward_grants_per_hour() -> u32 {
+    60
 }
 
 fn default_puzzle_difficulty() -> u16 {
@@ -168,26 +275,79 @@ async fn main() -> anyhow::Result<()> {
     Lazy::force(&PLUS_MIZARU_SK);
     Lazy::force(&FREE_MIZARU_SK);
     LazyLock::force(&database::POSTGRES);
-
-    let _gc_loop = Immortal::respawn(RespawnStrategy::Immediate, database_gc_loop);
+    init_probe_table().await?;
+    database::init_exit_load_table().await?;
+    database::init_exit_features_table().await?;
+    database::init_announcements_table().await?;
+    database::init_bridge_usage_table().await?;
+    init_trust_tables().await?;
+    init_volunteer_table().await?;
+    protocol_stats::init_protocol_stats_table().await?;
+    dashboard::init_dashboard_tables().await?;
+
+    // background jobs that touch shared state run on only one of the instances sharing the database
+    let _gc_loop = Immortal::respawn(RespawnStrategy::Immediate, || {
+        run_as_leader("gc", database_gc_loop)
+    });
     let _self_stat_loop = Immortal::respawn(RespawnStrategy::Immediate, self_stat_loop);
+    let _probe_loop = Immortal::respawn(RespawnStrategy::Immediate, || {
+        run_as_leader("probe", bridge_probe_loop)
+    });
+    let _strike_loop = Immortal::respawn(RespawnStrategy::Immediate, || {
+        run_as_leader("trust-strike", trust_strike_loop)
+    });
+    let _snapshot_loop = Immortal::respawn(RespawnStrategy::Immediate, || {
+        run_as_leader("stats-snapshot", stats_snapshot_loop)
+    });
+    // every instance flushes the counts of the requests it served itself
+    let _flush_loop = Immortal::respawn(RespawnStrategy::Immediate, stats_flush_loop);
     let _tcp_loop = Immortal::respawn(RespawnStrategy::Immediate, || async {
         nanorpc_sillad::rpc_serve(
             sillad::tcp::TcpListener::bind(CONFIG_FILE.wait().tcp_listen).await?,
-            WrappedBrokerService::new(),
+            WrappedBrokerService::new(None),
         )
         .await?;
         anyhow::Ok(())
     });
 
+    // bots long-poll their chat services, which only allow one poller at a time
+    if CONFIG_FILE.wait().bots.is_some() {
+        tokio::spawn(async {
+            loop {
+                let res = run_as_leader("bots", || async {
+                    bots::bots_loop().await;
+                    anyhow::Ok(())
+                })
+                .await;
+                if let Err(err) = res {
+                    tracing::warn!(err = debug(err), "bots stopped");
+                }
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        });
+    }
+
     let listener = tokio::net::TcpListener::bind(CONFIG_FILE.wait().listen).await?;
-    let app = Router::new().route("/", post(rpc));
+    let app = Router::new()
+        .route("/", post(rpc))
+        .route("/email/:secret", post(email::inbound_email))
+        .route("/mirror/exits/:level", get(mirror::mirrored_exits))
+        .route("/mirror/routes/:exit", get(mirror::mirrored_routes))
+        .route("/health/live", get(leader::live))
+        .route("/health/ready", get(leader::ready))
+        .route("/stats", get(dashboard::stats));
     axum::serve(listener, app).await?;
     Ok(())
 }
 
-async fn rpc(Json(payload): Json<JrpcRequest>) -> Json<JrpcResponse> {
-    Json(WrappedBrokerService::new().respond_raw(payload).await)
+async fn rpc(headers: HeaderMap, Json(payload): Json<JrpcRequest>) -> Json<JrpcResponse> {
+    let country = requester_country(&headers);
+    dashboard::count("requests", country.as_deref().unwrap_or("unknown"));
+    Json(
+        WrappedBrokerService::new(country)
+            .respond_raw(payload)
+            .await,
+    )
 }
 
 fn log_error(e: &impl Debug) {
@@ -0,0 +1,176 @@
+use std::{
+    ops::Deref,
+    sync::{Arc, LazyLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::Path,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures_util::future::join_all;
+use geph5_broker_protocol::{
+    AccountLevel, Mirrored, RouteDescriptor, Signed, DOMAIN_MIRRORED_EXITS, DOMAIN_MIRRORED_ROUTES,
+};
+use moka::future::Cache;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    database::query_bridges, distribution::BUNDLE_LIFETIME, partition::BridgeQuery,
+    routes::bridge_to_leaf_route, rpc_impl::exit_list, CONFIG_FILE, MASTER_SECRET,
+};
+
+/// Configuration for serving signed lists of exits and bridge routes that CDN caches and static mirrors can hold on to, for clients that can reach a mirror but not the broker. Mirrors can't tamper with the lists, since clients check them against the broker's master key.
+#[derive(Deserialize, Clone, Debug)]
+pub struct MirrorConfig {
+    /// Pools whose bridges are published through mirrors. Anyone can read them there, so these should be pools that are fine to be public, such as bridges behind CDNs.
+    #[serde(default)]
+    pub pools: Vec<String>,
+    /// How long the same list is served to everyone, and caches may keep it.
+    #[serde(default = "default_cache_secs")]
+    pub cache_secs: u64,
+}
+
+fn default_cache_secs() -> u64 {
+    600
+}
+
+/// How long mirrored exit lists stay valid. Exits rarely move, so this can be long.
+const EXITS_LIFETIME: Duration = Duration::from_secs(3600);
+
+/// A signed list, serialized once and served to everyone until the cache lets go of it.
+#[derive(Clone)]
+struct CachedBody {
+    generated: u64,
+    body: Option<Arc<String>>,
+}
+
+static BODY_CACHE: LazyLock<Cache<String, CachedBody>> = LazyLock::new(|| {
+    Cache::builder()
+        .time_to_live(Duration::from_secs(
+            CONFIG_FILE
+                .wait()
+                .mirror
+                .as_ref()
+                .map(|cfg| cfg.cache_secs)
+                .unwrap_or_else(default_cache_secs),
+        ))
+        .build()
+});
+
+/// Serves the signed list of exits for an account level, `free` or `plus`.
+pub async fn mirrored_exits(Path(level): Path<String>) -> Response {
+    let level = match level.as_str() {
+        "free" => AccountLevel::Free,
+        "plus" => AccountLevel::Plus,
+        _ => return StatusCode::NOT_FOUND.into_response(),
+    };
+    serve_cached(format!("exits/{level:?}"), async move {
+        let exits = exit_list(level).await.map_err(|e| anyhow::anyhow!(e))?;
+        anyhow::Ok(sign(exits, EXITS_LIFETIME, DOMAIN_MIRRORED_EXITS))
+    })
+    .await
+}
+
+/// Serves signed bridge routes, through the bridges of the mirrored pools, to the exit with the given hex-encoded public key.
+pub async fn mirrored_routes(Path(exit): Path<String>) -> Response {
+    let Some(cfg) = &CONFIG_FILE.wait().mirror else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if exit.len() != 64 || !exit.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    serve_cached(format!("routes/{}", exit.to_lowercase()), async move {
+        let exits = exit_list(AccountLevel::Plus)
+            .await
+            .map_err(|e| anyhow::anyhow!(e))?;
+        let Some((_, exit)) = exits
+            .all_exits
+            .iter()
+            .find(|(pk, _)| hex::encode(pk.as_bytes()).eq_ignore_ascii_case(&exit))
+        else {
+            return anyhow::Ok(None);
+        };
+
+        let mut query = BridgeQuery::new("mirror", None, AccountLevel::Free, true);
+        query.reserve_pools = cfg.pools.clone();
+        let bridges = query_bridges(&query).await?;
+        let routes = join_all(
+            bridges
+                .into_iter()
+                .map(|(desc, delay_ms, _)| bridge_to_leaf_route(desc, delay_ms, exit.b2e_listen)),
+        )
+        .await
+        .into_iter()
+        .filter_map(|route| {
+            route
+                .inspect_err(|err| tracing::warn!(err = debug(err), "could not mirror a bridge"))
+                .ok()
+        })
+        .collect();
+        anyhow::Ok(sign(
+            RouteDescriptor::Race(routes),
+            BUNDLE_LIFETIME,
+            DOMAIN_MIRRORED_ROUTES,
+        ))
+    })
+    .await
+}
+
+fn sign<T: Serialize>(inner: T, lifetime: Duration, domain: &str) -> Option<String> {
+    let expiry = (SystemTime::now() + lifetime)
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    serde_json::to_string(&Signed::new(
+        Mirrored { inner, expiry },
+        domain,
+        MASTER_SECRET.deref(),
+    ))
+    .ok()
+}
+
+/// Answers with the cached body for the key, generating it if needed. Bodies that don't exist are cached too, as plain 404s. Responses say how much longer the body stays in our cache, so that CDNs in front of us expire it at the same time we do.
+async fn serve_cached(
+    key: String,
+    generate: impl std::future::Future<Output = anyhow::Result<Option<String>>>,
+) -> Response {
+    if CONFIG_FILE.wait().mirror.is_none() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let cached = BODY_CACHE
+        .try_get_with(key, async {
+            anyhow::Ok(CachedBody {
+                generated: now(),
+                body: generate.await?.map(Arc::new),
+            })
+        })
+        .await;
+    match cached {
+        Ok(CachedBody { body: None, .. }) => StatusCode::NOT_FOUND.into_response(),
+        Ok(cached) => {
+            let cache_secs = CONFIG_FILE.wait().mirror.as_ref().unwrap().cache_secs;
+            let max_age = (cached.generated + cache_secs).saturating_sub(now());
+            (
+                [
+                    (header::CONTENT_TYPE, "application/json".to_string()),
+                    (header::CACHE_CONTROL, format!("public, max-age={max_age}")),
+                ],
+                cached.body.as_deref().cloned().unwrap_or_default(),
+            )
+                .into_response()
+        }
+        Err(err) => {
+            tracing::warn!(err = debug(err), "could not generate a mirrored list");
+            StatusCode::SERVICE_UNAVAILABLE.into_response()
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
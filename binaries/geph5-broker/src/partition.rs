@@ -0,0 +1,109 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use geph5_broker_protocol::AccountLevel;
+use serde::Deserialize;
+
+use crate::CONFIG_FILE;
+
+/// How bridges are split up among requesters. Every bridge and every requester falls into one of a number of partitions, and requesters are handed the bridges in their own partition first. Requesters of the same country and account level fall into only a few partitions, picked by their token, so that harvesting tokens from one place can't enumerate every bridge.
+///
+/// Since connect tokens are blindly signed, the account level is the only thing we know about the account behind a request.
+#[derive(Deserialize, Clone, Debug)]
+pub struct PartitionConfig {
+    #[serde(default = "default_partitions")]
+    pub partitions: u32,
+    /// How many partitions requesters of the same country and account level are spread over.
+    #[serde(default = "default_buckets_per_group")]
+    pub buckets_per_group: u32,
+    /// How often all partitions are reshuffled, so that a blocked partition does not stay blocked for good.
+    #[serde(default = "default_rotation_secs")]
+    pub rotation_secs: u64,
+    /// Pools held back from everyone, and only handed out to requesters whose other bridges are all unreachable.
+    #[serde(default)]
+    pub reserve_pools: Vec<String>,
+}
+
+impl Default for PartitionConfig {
+    fn default() -> Self {
+        Self {
+            partitions: default_partitions(),
+            buckets_per_group: default_buckets_per_group(),
+            rotation_secs: default_rotation_secs(),
+            reserve_pools: vec![],
+        }
+    }
+}
+
+fn default_partitions() -> u32 {
+    32
+}
+
+fn default_buckets_per_group() -> u32 {
+    4
+}
+
+fn default_rotation_secs() -> u64 {
+    86400 * 7
+}
+
+/// Everything that decides which bridges a bridge query returns.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BridgeQuery {
+    /// Picks among the bridges of a pool that are equally close to the requester's partition.
+    pub key: String,
+    pub country: Option<String>,
+    /// Mixed into the partition of every bridge, changing with every rotation.
+    pub salt: String,
+    pub partitions: u32,
+    pub partition: u32,
+    pub reserve_pools: Vec<String>,
+    /// Whether to query the reserve pools rather than the others.
+    pub reserve: bool,
+    /// Pools that are never returned, such as the ones set aside for trust groups.
+    pub excluded_pools: Vec<String>,
+}
+
+impl BridgeQuery {
+    /// Places a requester into its partition.
+    pub fn new(key: &str, country: Option<&str>, level: AccountLevel, reserve: bool) -> Self {
+        let cfg = &CONFIG_FILE.wait().bridge_partitioning;
+        let partitions = cfg.partitions.max(1);
+        let epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            / cfg.rotation_secs.max(1);
+        let bucket = hash_u32(key) % cfg.buckets_per_group.max(1);
+        let level = match level {
+            AccountLevel::Free => "free",
+            AccountLevel::Plus => "plus",
+        };
+        let partition = hash_u32(&format!(
+            "{}/{level}/{epoch}/{bucket}",
+            country.unwrap_or_default()
+        )) % partitions;
+        Self {
+            key: key.to_string(),
+            country: country.map(str::to_string),
+            salt: epoch.to_string(),
+            partitions,
+            partition,
+            reserve_pools: cfg.reserve_pools.clone(),
+            reserve,
+            excluded_pools: CONFIG_FILE
+                .wait()
+                .trust
+                .as_ref()
+                .map(|trust| trust.pools.clone())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+pub fn hash_u32(s: &str) -> u32 {
+    u32::from_be_bytes(
+        blake3::hash(s.as_bytes()).as_bytes()[..4]
+            .try_into()
+            .unwrap(),
+    )
+}
@@ -0,0 +1,134 @@
+use std::{
+    ops::Deref,
+    sync::LazyLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use geph5_broker_protocol::{debiased_success_rate, ProtocolOutcomes};
+use moka::future::Cache;
+
+use crate::database::POSTGRES;
+
+/// How quickly old reports fade, so that hints follow a country's blocking as it changes.
+const HALF_LIFE_SECS: f64 = 86400.0;
+
+/// How many reports a transport needs in a country before it is ranked there. Below that, a handful of clients could steer everyone else, and rankings could say too much about the few who reported.
+const MIN_REPORTS: f64 = 100.0;
+
+/// How many outcomes of one transport a single report may count, so that one client can't drown out the rest.
+const MAX_OUTCOMES_PER_REPORT: u32 = 50;
+
+/// How many transports a single report may cover.
+const MAX_PROTOCOLS_PER_REPORT: usize = 16;
+
+/// Creates the table of per-country transport outcomes if it does not exist yet. Only decaying totals are kept, never individual reports.
+pub async fn init_protocol_stats_table() -> anyhow::Result<()> {
+    sqlx::query(
+        r"CREATE TABLE IF NOT EXISTS protocol_stats (
+            country TEXT NOT NULL,
+            protocol TEXT NOT NULL,
+            successes DOUBLE PRECISION NOT NULL,
+            failures DOUBLE PRECISION NOT NULL,
+            last_update BIGINT NOT NULL,
+            PRIMARY KEY (country, protocol)
+        )",
+    )
+    .execute(POSTGRES.deref())
+    .await?;
+    Ok(())
+}
+
+/// Adds a client's randomized outcomes to the totals of its country, or of no country in particular if we don't know it.
+pub async fn record_outcomes(
+    country: Option<&str>,
+    outcomes: &[ProtocolOutcomes],
+) -> anyhow::Result<()> {
+    let now = now();
+    let mut txn = POSTGRES.begin().await?;
+    for outcome in outcomes.iter().take(MAX_PROTOCOLS_PER_REPORT) {
+        if !valid_protocol(&outcome.protocol) {
+            continue;
+        }
+        sqlx::query(
+            r"INSERT INTO protocol_stats (country, protocol, successes, failures, last_update)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (country, protocol) DO UPDATE
+            SET successes = protocol_stats.successes / power(2, ($5 - protocol_stats.last_update)::double precision / $6) + EXCLUDED.successes,
+                failures = protocol_stats.failures / power(2, ($5 - protocol_stats.last_update)::double precision / $6) + EXCLUDED.failures,
+                last_update = EXCLUDED.last_update",
+        )
+        .bind(country.unwrap_or_default())
+        .bind(&outcome.protocol)
+        .bind(outcome.successes.min(MAX_OUTCOMES_PER_REPORT) as f64)
+        .bind(outcome.failures.min(MAX_OUTCOMES_PER_REPORT) as f64)
+        .bind(now)
+        .bind(HALF_LIFE_SECS)
+        .execute(&mut *txn)
+        .await?;
+    }
+    txn.commit().await?;
+    Ok(())
+}
+
+/// Transports ordered from most to least likely to work in the country, going by everyone's reports where the country has too few of its own.
+pub async fn protocol_hints(country: Option<&str>) -> anyhow::Result<Vec<String>> {
+    static CACHE: LazyLock<Cache<String, Vec<String>>> = LazyLock::new(|| {
+        Cache::builder()
+            .time_to_live(Duration::from_secs(600))
+            .build()
+    });
+
+    let key = country.unwrap_or_default().to_string();
+    CACHE
+        .try_get_with(key, async {
+            if let Some(country) = country {
+                let hints = rank_protocols(Some(country)).await?;
+                if !hints.is_empty() {
+                    return anyhow::Ok(hints);
+                }
+            }
+            rank_protocols(None).await
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!(e))
+}
+
+async fn rank_protocols(country: Option<&str>) -> anyhow::Result<Vec<String>> {
+    let totals: Vec<(String, f64, f64)> = sqlx::query_as(
+        r"SELECT protocol,
+            sum(successes / power(2, ($2 - last_update)::double precision / $3)),
+            sum(failures / power(2, ($2 - last_update)::double precision / $3))
+        FROM protocol_stats
+        WHERE $1::text IS NULL OR country = $1
+        GROUP BY protocol",
+    )
+    .bind(country)
+    .bind(now())
+    .bind(HALF_LIFE_SECS)
+    .fetch_all(POSTGRES.deref())
+    .await?;
+    let mut ranked: Vec<(String, f64)> = totals
+        .into_iter()
+        .filter(|(_, successes, failures)| successes + failures >= MIN_REPORTS)
+        .filter_map(|(protocol, successes, failures)| {
+            Some((protocol, debiased_success_rate(successes, failures)?))
+        })
+        .collect();
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+    Ok(ranked.into_iter().map(|(protocol, _)| protocol).collect())
+}
+
+fn valid_protocol(protocol: &str) -> bool {
+    !protocol.is_empty()
+        && protocol.len() <= 32
+        && protocol
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
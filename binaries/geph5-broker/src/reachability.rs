@@ -0,0 +1,186 @@
+use std::{ops::Deref, time::Duration};
+
+use async_io::Timer;
+use futures_util::{stream, StreamExt};
+use rand::Rng;
+use serde::Deserialize;
+use sillad::{dialer::Dialer, tcp::TcpDialer};
+use sillad_proxy::{ProxyDialer, UpstreamProxy};
+use sillad_sosistab3::{dialer::SosistabDialer, Cookie};
+use smol_timeout2::TimeoutExt;
+
+use crate::{database::POSTGRES, CONFIG_FILE};
+
+/// How many probes in a row must fail from a vantage point before a bridge counts as unreachable from there.
+pub const UNREACHABLE_THRESHOLD: i32 = 3;
+
+/// How long one probe may take.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many probes run at once.
+const PROBE_PARALLELISM: usize = 64;
+
+/// How long we keep results for vantage points and bridges that are no longer probed.
+const RESULT_TTL_SECS: i64 = 3600;
+
+/// A place from which the broker probes bridges. Vantage points without a proxy probe straight from the broker. Ones without a country tell whether a bridge is up at all, while ones with a country tell whether it is blocked there.
+#[derive(Deserialize, Clone, Debug)]
+pub struct VantagePoint {
+    pub name: String,
+    #[serde(default)]
+    pub country: Option<String>,
+    #[serde(default)]
+    pub proxy: Option<UpstreamProxy>,
+}
+
+pub fn default_vantage_points() -> Vec<VantagePoint> {
+    vec![VantagePoint {
+        name: "broker".into(),
+        country: None,
+        proxy: None,
+    }]
+}
+
+pub fn default_probe_interval() -> u64 {
+    120
+}
+
+/// Creates the table of probe results, which bridge queries depend on, if it does not exist yet.
+pub async fn init_probe_table() -> anyhow::Result<()> {
+    sqlx::query(
+        r"CREATE TABLE IF NOT EXISTS bridge_probes (
+            listen TEXT NOT NULL,
+            vantage TEXT NOT NULL,
+            country TEXT NOT NULL,
+            consecutive_failures INTEGER NOT NULL,
+            last_success BIGINT,
+            last_probe BIGINT NOT NULL,
+            PRIMARY KEY (listen, vantage)
+        )",
+    )
+    .execute(POSTGRES.deref())
+    .await?;
+    Ok(())
+}
+
+/// This loop probes every registered bridge from every vantage point, recording which bridges can be reached from where, so that bridges that are down or blocked stop being handed out.
+#[tracing::instrument]
+pub async fn bridge_probe_loop() -> anyhow::Result<()> {
+    tracing::info!("starting the bridge probe loop");
+    let cfg = CONFIG_FILE.wait();
+    loop {
+        let bridges: Vec<(String, String)> =
+            sqlx::query_as("SELECT listen, cookie FROM bridges_new")
+                .fetch_all(POSTGRES.deref())
+                .await?;
+        tracing::debug!(
+            bridges = bridges.len(),
+            vantages = cfg.vantage_points.len(),
+            "probing bridges"
+        );
+
+        let probes = bridges.iter().flat_map(|(listen, cookie)| {
+            cfg.vantage_points.iter().map(move |vantage| async move {
+                let reachable = probe_bridge(vantage, listen, cookie).await;
+                if !reachable {
+                    tracing::debug!(listen, vantage = vantage.name, "bridge probe failed");
+                }
+                record_probe(vantage, listen, reachable).await
+            })
+        });
+        let failed_writes = stream::iter(probes)
+            .buffer_unordered(PROBE_PARALLELISM)
+            .filter(|res| std::future::ready(res.is_err()))
+            .count()
+            .await;
+        if failed_writes > 0 {
+            tracing::warn!(failed_writes, "could not record some bridge probes");
+        }
+
+        let res = sqlx::query(
+            "DELETE FROM bridge_probes WHERE last_probe < extract(epoch from now()) - $1",
+        )
+        .bind(RESULT_TTL_SECS)
+        .execute(POSTGRES.deref())
+        .await?;
+        tracing::debug!(
+            rows_affected = res.rows_affected(),
+            "cleaned up bridge probes"
+        );
+
+        // jitter, so that many brokers do not probe in lockstep
+        let interval = cfg.probe_interval_secs as f64;
+        Timer::after(Duration::from_secs_f64(
+            rand::thread_rng().gen_range(interval..interval * 1.5),
+        ))
+        .await;
+    }
+}
+
+/// Checks whether a bridge's control port completes an obfuscated handshake when reached from the given vantage point. This catches bridges that are blocked by protocol as well as by address.
+async fn probe_bridge(vantage: &VantagePoint, listen: &str, cookie: &str) -> bool {
+    let Ok(dest_addr) = listen.parse() else {
+        return false;
+    };
+    let cookie = Cookie::new(cookie);
+    let res = match &vantage.proxy {
+        Some(proxy) => SosistabDialer {
+            inner: ProxyDialer {
+                proxy: proxy.clone(),
+                dest_addr,
+            },
+            cookie,
+        }
+        .dial()
+        .timeout(PROBE_TIMEOUT)
+        .await
+        .map(|res| res.map(drop)),
+        None => SosistabDialer {
+            inner: TcpDialer { dest_addr },
+            cookie,
+        }
+        .dial()
+        .timeout(PROBE_TIMEOUT)
+        .await
+        .map(|res| res.map(drop)),
+    };
+    matches!(res, Some(Ok(())))
+}
+
+async fn record_probe(vantage: &VantagePoint, listen: &str, reachable: bool) -> anyhow::Result<()> {
+    sqlx::query(
+        r"INSERT INTO bridge_probes (listen, vantage, country, consecutive_failures, last_success, last_probe)
+        VALUES ($1, $2, $3, CASE WHEN $4 THEN 0 ELSE 1 END,
+                CASE WHEN $4 THEN extract(epoch from now())::bigint ELSE NULL END,
+                extract(epoch from now())::bigint)
+        ON CONFLICT (listen, vantage) DO UPDATE
+        SET country = EXCLUDED.country,
+            consecutive_failures = CASE WHEN $4 THEN 0 ELSE bridge_probes.consecutive_failures + 1 END,
+            last_success = COALESCE(EXCLUDED.last_success, bridge_probes.last_success),
+            last_probe = EXCLUDED.last_probe
+        ",
+    )
+    .bind(listen)
+    .bind(&vantage.name)
+    .bind(vantage.country.as_deref().unwrap_or_default().to_uppercase())
+    .bind(reachable)
+    .execute(POSTGRES.deref())
+    .await?;
+    Ok(())
+}
+
+/// Headers that CDNs in front of the broker use to pass on the country of the requester.
+const COUNTRY_HEADERS: [&str; 2] = ["cf-ipcountry", "cloudfront-viewer-country"];
+
+/// Finds the requester's country, as an uppercase ISO code, from the headers of an HTTP request that reached us through a CDN.
+pub fn requester_country(headers: &axum::http::HeaderMap) -> Option<String> {
+    COUNTRY_HEADERS.iter().find_map(|name| {
+        let country = headers.get(*name)?.to_str().ok()?.trim().to_uppercase();
+        // Cloudflare uses XX and T1 for unknown locations and Tor
+        (country.len() == 2
+            && country.bytes().all(|b| b.is_ascii_uppercase())
+            && country != "XX"
+            && country != "T1")
+            .then_some(country)
+    })
+}
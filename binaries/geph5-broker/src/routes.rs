@@ -1,12 +1,15 @@
 use anyhow::Context;
 use futures_util::{FutureExt as _, TryFutureExt};
-use geph5_broker_protocol::{BridgeDescriptor, RouteDescriptor};
-use geph5_misc_rpc::bridge::{B2eMetadata, BridgeControlClient, ObfsProtocol};
+use geph5_broker_protocol::{BridgeDescriptor, PortHopSchedule, RouteDescriptor};
+use geph5_misc_rpc::bridge::{
+    B2eCredential, B2eMetadata, BridgeControlClient, BridgeListener, ObfsProtocol,
+};
 
 use moka::future::Cache;
 use nanorpc_sillad::DialerTransport;
 
-use rand::RngCore;
+use rand::{seq::SliceRandom, RngCore};
+use serde::Deserialize;
 use sillad::tcp::TcpDialer;
 use sillad_sosistab3::{dialer::SosistabDialer, Cookie};
 use smol_timeout2::TimeoutExt;
@@ -16,6 +19,34 @@ use std::{
     time::{Duration, SystemTime},
 };
 
+use crate::{database::query_exit_features, CONFIG_FILE};
+
+/// The kinds of routes that bridge_to_leaf_route builds, named as clients name them in dial telemetry.
+pub const ROUTE_TRANSPORTS: &[&str] = &[
+    "tcp",
+    "port_hop",
+    "sosistab3",
+    "tls",
+    "websocket",
+    "meek",
+    "quic",
+    "icmp",
+    "dns",
+    "obfs_udp",
+    "kcp",
+];
+
+/// Configuration for meek routes, which reach bridges through a CDN that the censor would rather not block, by showing the CDN's own domain in TLS and naming the bridge only in the encrypted Host header.
+#[derive(Deserialize, Clone, Debug)]
+pub struct MeekConfig {
+    /// The domain that clients show in TLS, which the CDN must serve.
+    pub front_domain: String,
+    /// Where clients reach the CDN.
+    pub cdn_addr: SocketAddr,
+    /// The domain under which the CDN reaches bridges. The Host header of the route through port `p` at `a.b.c.d` is `a-b-c-d-p.<host_suffix>`, which the CDN must send on to that address and port over TLS, such as with a worker that parses the name.
+    pub host_suffix: String,
+}
+
 pub async fn bridge_to_leaf_route(
     bridge: BridgeDescriptor,
     delay_ms: u32,
@@ -37,6 +68,7 @@ pub async fn bridge_to_leaf_route(
         .get_with(
             (bridge.clone(), exit_b2e),
             async {
+                give_b2e_credential(&bridge).await;
                 // let obfs_route = bridge_to_leaf_route_inner(
                 //     bridge.clone(),
                 //     exit_b2e,
@@ -82,21 +114,114 @@ pub async fn bridge_to_leaf_route(
                         lower: plain_route.into(),
                     })
                 } else {
-                    let plain_route = bridge_to_leaf_route_inner(
-                        bridge.clone(),
-                        exit_b2e,
-                        ObfsProtocol::ConnTest(
-                            ObfsProtocol::Sosistab3New(gencookie(), ObfsProtocol::None.into())
-                                .into(),
-                        ),
-                    )
-                    .await?;
+                    // bridges that serve several listeners get a route for each, raced against each other in place of the usual sosistab3 one
+                    let listeners = bridge_listeners(&bridge).await;
+                    let plain_route = if listeners.is_empty() {
+                        bridge_to_leaf_route_inner(
+                            bridge.clone(),
+                            exit_b2e,
+                            ObfsProtocol::ConnTest(
+                                ObfsProtocol::Sosistab3New(gencookie(), ObfsProtocol::None.into())
+                                    .into(),
+                            ),
+                        )
+                        .await?
+                    } else {
+                        let mut routes = vec![];
+                        // a listener the bridge cannot serve right now, like a pluggable transport that does not start, costs only its own route
+                        let mut last_err = None;
+                        for listener in listeners {
+                            match listener_route(&bridge, exit_b2e, listener.clone()).await {
+                                Ok(route) => routes.push(route),
+                                Err(err) => {
+                                    tracing::warn!(
+                                        err = debug(&err),
+                                        listener = debug(listener),
+                                        "skipping a listener of the bridge"
+                                    );
+                                    last_err = Some(err);
+                                }
+                            }
+                        }
+                        if let (true, Some(err)) = (routes.is_empty(), last_err) {
+                            return Err(err);
+                        }
+                        RouteDescriptor::Race(routes)
+                    };
                     let legacy_route =
                         bridge_to_leaf_route_inner(bridge.clone(), exit_b2e, ObfsProtocol::None)
                             .await?;
+                    // the last resort, for when every long-lived connection gets cut. Bridges that predate meek cannot take it, and only lose this route
+                    let meek_route = match CONFIG_FILE.wait().meek.as_ref() {
+                        Some(meek) => meek_route(&bridge, exit_b2e, meek)
+                            .await
+                            .inspect_err(|err| {
+                                tracing::debug!(
+                                    err = debug(err),
+                                    bridge = debug(bridge.control_listen),
+                                    "skipping the meek route"
+                                )
+                            })
+                            .ok(),
+                        None => None,
+                    };
+                    let mut ladder = vec![plain_route];
+                    // bridges that hop ports get a route that follows them around, for when the port above is blocked
+                    if let Some(schedule) = bridge_port_hop_schedule(&bridge).await {
+                        let hop_route = bridge_to_leaf_route_inner(
+                            bridge.clone(),
+                            exit_b2e,
+                            ObfsProtocol::ConnTest(
+                                ObfsProtocol::Sosistab3New(gencookie(), ObfsProtocol::None.into())
+                                    .into(),
+                            ),
+                        )
+                        .await?;
+                        ladder.push(replace_tcp(hop_route, &|addr| RouteDescriptor::PortHop {
+                            addr,
+                            schedule: schedule.clone(),
+                        }));
+                    }
+                    ladder.push(legacy_route);
+                    ladder.extend(meek_route);
+                    // bridges in such pools answer the experimental ICMP tunnel, which clients only use if they opt in
+                    if bridge.pool.contains("icmp") {
+                        let icmp_route = bridge_to_leaf_route_inner(
+                            bridge.clone(),
+                            exit_b2e,
+                            ObfsProtocol::ConnTest(
+                                ObfsProtocol::Sosistab3New(gencookie(), ObfsProtocol::None.into())
+                                    .into(),
+                            ),
+                        )
+                        .await?;
+                        ladder.push(replace_tcp(icmp_route, &|addr| match addr {
+                            SocketAddr::V4(addr) => RouteDescriptor::Icmp {
+                                addr: *addr.ip(),
+                                port: addr.port(),
+                            },
+                            addr => RouteDescriptor::Tcp(addr),
+                        }));
+                    }
+                    // bridges that run a DNS tunnel get it as the very lowest rung, for networks where only DNS gets through
+                    if let Some(domain) = bridge_dns_domain(&bridge).await {
+                        let dns_route = bridge_to_leaf_route_inner(
+                            bridge.clone(),
+                            exit_b2e,
+                            ObfsProtocol::ConnTest(
+                                ObfsProtocol::Sosistab3New(gencookie(), ObfsProtocol::None.into())
+                                    .into(),
+                            ),
+                        )
+                        .await?;
+                        ladder.push(replace_tcp(dns_route, &|addr| RouteDescriptor::Dns {
+                            domain: domain.clone(),
+                            port: addr.port(),
+                        }));
+                    }
                     anyhow::Ok(RouteDescriptor::Delay {
                         milliseconds: delay_ms,
-                        lower: RouteDescriptor::Fallback(vec![plain_route, legacy_route]).into(),
+                        lower: RouteDescriptor::Fallback(ladder).into(),
                     })
                 }
             }
@@ -117,6 +242,104 @@ pub async fn bridge_to_leaf_route(
         .map_err(|e| anyhow::anyhow!(e))
 }
 
+/// Routes straight to the exit, over the UDP listeners it reported, for clients on networks where UDP gets through. They are raced against the bridge routes, so clients that cannot reach them lose nothing.
+pub async fn exit_direct_routes(exit_b2e: SocketAddr) -> Vec<RouteDescriptor> {
+    let features = match query_exit_features(exit_b2e).await {
+        Ok(features) => features,
+        Err(err) => {
+            tracing::warn!(
+                err = debug(err),
+                exit = debug(exit_b2e),
+                "could not query exit features"
+            );
+            None
+        }
+    };
+    let Some(features) = features else {
+        return vec![];
+    };
+    let mut routes = vec![];
+    if let Some(listener) = features.obfs_udp {
+        routes.push(RouteDescriptor::ConnTest {
+            ping_count: 1,
+            lower: RouteDescriptor::ObfsUdp {
+                addr: listener.addr,
+                cookie: listener.cookie,
+                fec_data_shards: 0,
+                fec_parity_shards: 0,
+                congestion: String::new(),
+            }
+            .into(),
+        });
+    }
+    if let Some(listener) = features.kcp {
+        routes.push(RouteDescriptor::ConnTest {
+            ping_count: 1,
+            lower: RouteDescriptor::Kcp {
+                addr: listener.addr,
+                cookie: listener.cookie,
+            }
+            .into(),
+        });
+    }
+    routes
+}
+
+/// Builds the meek route to the exit through the bridge, fronted by the CDN.
+async fn meek_route(
+    bridge: &BridgeDescriptor,
+    exit_b2e: SocketAddr,
+    meek: &MeekConfig,
+) -> anyhow::Result<RouteDescriptor> {
+    // the CDN speaks TLS to the bridge, which the exit takes off again, while the Host header only matters to the CDN
+    let route = bridge_to_leaf_route_inner(
+        bridge.clone(),
+        exit_b2e,
+        ObfsProtocol::ConnTest(
+            ObfsProtocol::Meek(
+                meek.host_suffix.clone(),
+                "/meek".into(),
+                ObfsProtocol::PlainTls(ObfsProtocol::None.into()).into(),
+            )
+            .into(),
+        ),
+    )
+    .await?;
+    let RouteDescriptor::ConnTest { ping_count, lower } = route else {
+        anyhow::bail!("meek route is not a conn_test one");
+    };
+    let RouteDescriptor::Meek { path, lower, .. } = *lower else {
+        anyhow::bail!("meek route has no meek in it");
+    };
+    let RouteDescriptor::PlainTls { lower, .. } = *lower else {
+        anyhow::bail!("meek route has no TLS in it");
+    };
+    let RouteDescriptor::Tcp(bridge_addr) = *lower else {
+        anyhow::bail!("meek route does not end in TCP");
+    };
+    let host = format!(
+        "{}-{}.{}",
+        bridge_addr.ip().to_string().replace(['.', ':'], "-"),
+        bridge_addr.port(),
+        meek.host_suffix
+    );
+    Ok(RouteDescriptor::ConnTest {
+        ping_count,
+        lower: RouteDescriptor::Meek {
+            host,
+            path,
+            lower: RouteDescriptor::PlainTls {
+                sni_domain: Some(meek.front_domain.clone()),
+                fingerprint: None,
+                ech_config_list: None,
+                lower: RouteDescriptor::Tcp(meek.cdn_addr).into(),
+            }
+            .into(),
+        }
+        .into(),
+    })
+}
+
 fn gencookie() -> String {
     let mut b = [0u8; 16];
     rand::thread_rng().fill_bytes(&mut b);
@@ -128,18 +351,7 @@ async fn bridge_to_leaf_route_inner(
     exit_b2e: SocketAddr,
     protocol: ObfsProtocol,
 ) -> anyhow::Result<RouteDescriptor> {
-    let cookie = Cookie::new(&bridge.control_cookie);
-
-    let control_dialer = SosistabDialer {
-        inner: TcpDialer {
-            dest_addr: bridge.control_listen,
-        },
-        cookie,
-    };
-
-    let control_client = BridgeControlClient(DialerTransport(control_dialer));
-
-    let sosistab_addr = control_client
+    let sosistab_addr = control_client(&bridge)
         .tcp_forward(
             exit_b2e,
             B2eMetadata {
@@ -155,6 +367,152 @@ async fn bridge_to_leaf_route_inner(
     anyhow::Ok(final_route)
 }
 
+/// Builds the route through one of the listeners that a bridge serves.
+async fn listener_route(
+    bridge: &BridgeDescriptor,
+    exit_b2e: SocketAddr,
+    listener: BridgeListener,
+) -> anyhow::Result<RouteDescriptor> {
+    match listener {
+        BridgeListener::Sosistab3 => {
+            bridge_to_leaf_route_inner(
+                bridge.clone(),
+                exit_b2e,
+                ObfsProtocol::ConnTest(
+                    ObfsProtocol::Sosistab3New(gencookie(), ObfsProtocol::None.into()).into(),
+                ),
+            )
+            .await
+        }
+        BridgeListener::Websocket(host, path) => {
+            bridge_to_leaf_route_inner(
+                bridge.clone(),
+                exit_b2e,
+                ObfsProtocol::ConnTest(
+                    ObfsProtocol::Websocket(host, path, ObfsProtocol::None.into()).into(),
+                ),
+            )
+            .await
+        }
+        // QUIC is already encrypted, so the bridge hands it straight to the forwarded port with the same number
+        BridgeListener::Quic => {
+            let route = bridge_to_leaf_route_inner(
+                bridge.clone(),
+                exit_b2e,
+                ObfsProtocol::ConnTest(ObfsProtocol::None.into()),
+            )
+            .await?;
+            let sni_domain = CONFIG_FILE
+                .wait()
+                .quic_sni_domains
+                .choose(&mut rand::thread_rng())
+                .cloned();
+            Ok(replace_tcp(route, &|addr| RouteDescriptor::Quic {
+                addr,
+                congestion: String::new(),
+                sni_domain: sni_domain.clone(),
+            }))
+        }
+        // the transport does its own obfuscation, so what it carries to the forwarded port goes to the exit as is
+        BridgeListener::PluggableTransport(transport) => {
+            let pt = control_client(bridge)
+                .pt_forward(
+                    exit_b2e,
+                    B2eMetadata {
+                        protocol: ObfsProtocol::ConnTest(ObfsProtocol::None.into()),
+                        expiry: SystemTime::now() + Duration::from_secs(86400),
+                    },
+                    transport,
+                )
+                .timeout(Duration::from_secs(15))
+                .await
+                .context("timeout when pt_forward")??
+                .context("bridge cannot run the pluggable transport")?;
+            Ok(RouteDescriptor::ConnTest {
+                ping_count: 1,
+                lower: RouteDescriptor::PluggableTransport {
+                    transport: pt.transport,
+                    args: pt.args,
+                    addr: pt.addr,
+                }
+                .into(),
+            })
+        }
+    }
+}
+
+/// Gives the bridge the credential that it authenticates its connections to exits with. Bridges that predate authenticated b2e connections don't take it, and carry on without.
+async fn give_b2e_credential(bridge: &BridgeDescriptor) {
+    let credential = B2eCredential::issue(
+        &CONFIG_FILE.wait().exit_token,
+        bridge.control_listen.to_string(),
+    );
+    let _ = control_client(bridge)
+        .set_b2e_credential(credential)
+        .timeout(Duration::from_secs(4))
+        .await;
+}
+
+/// Asks the bridge which listeners it serves. Bridges that predate multiple listeners serve none in particular.
+async fn bridge_listeners(bridge: &BridgeDescriptor) -> Vec<BridgeListener> {
+    control_client(bridge)
+        .listeners()
+        .timeout(Duration::from_secs(4))
+        .await
+        .and_then(|res| res.ok())
+        .unwrap_or_default()
+}
+
+/// Asks the bridge which schedule it hops ports on. Bridges that don't hop ports, including ones that predate port hopping, have none.
+async fn bridge_port_hop_schedule(bridge: &BridgeDescriptor) -> Option<PortHopSchedule> {
+    control_client(bridge)
+        .port_hop_schedule()
+        .timeout(Duration::from_secs(4))
+        .await?
+        .ok()
+        .flatten()
+}
+
+/// Swaps the TCP connection at the bottom of a route for another way of reaching the same port on the bridge, such as one of its tunnels.
+fn replace_tcp(
+    route: RouteDescriptor,
+    replace: &impl Fn(SocketAddr) -> RouteDescriptor,
+) -> RouteDescriptor {
+    match route {
+        RouteDescriptor::Tcp(addr) => replace(addr),
+        RouteDescriptor::ConnTest { ping_count, lower } => RouteDescriptor::ConnTest {
+            ping_count,
+            lower: replace_tcp(*lower, replace).into(),
+        },
+        RouteDescriptor::Sosistab3 { cookie, lower } => RouteDescriptor::Sosistab3 {
+            cookie,
+            lower: replace_tcp(*lower, replace).into(),
+        },
+        route => route,
+    }
+}
+
+/// Asks the bridge which domain its DNS tunnel answers for. Bridges without a DNS tunnel, including ones that predate them, have none.
+async fn bridge_dns_domain(bridge: &BridgeDescriptor) -> Option<String> {
+    control_client(bridge)
+        .dns_tunnel_domain()
+        .timeout(Duration::from_secs(4))
+        .await?
+        .ok()
+        .flatten()
+}
+
+pub fn control_client(
+    bridge: &BridgeDescriptor,
+) -> BridgeControlClient<DialerTransport<SosistabDialer<TcpDialer>>> {
+    BridgeControlClient(DialerTransport(SosistabDialer {
+        inner: TcpDialer {
+            dest_addr: bridge.control_listen,
+        },
+        cookie: Cookie::new(&bridge.control_cookie),
+    }))
+}
+
 fn protocol_to_descriptor(protocol: ObfsProtocol, addr: SocketAddr) -> RouteDescriptor {
     match protocol {
         ObfsProtocol::Sosistab3(cookie) => RouteDescriptor::Sosistab3 {
@@ -168,11 +526,23 @@ fn protocol_to_descriptor(protocol: ObfsProtocol, addr: SocketAddr) -> RouteDesc
         },
         ObfsProtocol::PlainTls(obfs_protocol) => RouteDescriptor::PlainTls {
             sni_domain: Some("labooyah-squish.be".into()),
+            fingerprint: None,
+            ech_config_list: None,
             lower: protocol_to_descriptor(*obfs_protocol, addr).into(),
         },
         ObfsProtocol::Sosistab3New(cookie, obfs_protocol) => RouteDescriptor::Sosistab3 {
             cookie,
             lower: protocol_to_descriptor(*obfs_protocol, addr).into(),
         },
+        ObfsProtocol::Websocket(host, path, obfs_protocol) => RouteDescriptor::Websocket {
+            host,
+            path,
+            lower: protocol_to_descriptor(*obfs_protocol, addr).into(),
+        },
+        ObfsProtocol::Meek(host, path, obfs_protocol) => RouteDescriptor::Meek {
+            host,
+            path,
+            lower: protocol_to_descriptor(*obfs_protocol, addr).into(),
+        },
     }
 }
@@ -0,0 +1,169 @@
+use std::{
+    sync::{LazyLock, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use geph5_broker_protocol::PuzzleSolution;
+use mizaru2::ClientToken;
+use serde::Deserialize;
+
+use crate::{distribution::IdentityLimiter, CONFIG_FILE, MASTER_SECRET};
+
+/// How long a puzzle stays valid, in seconds. Puzzles from the previous window are accepted too.
+const PUZZLE_WINDOW_SECS: u64 = 600;
+
+/// When and how hard bridge-list requests are challenged. Below the threshold, no puzzles are handed out at all. Above it, the puzzle gets one step harder every time the rate doubles.
+#[derive(Deserialize, Clone, Debug)]
+pub struct ChallengeConfig {
+    #[serde(default = "default_threshold_per_min")]
+    pub threshold_per_min: u32,
+    #[serde(default = "default_base_difficulty")]
+    pub base_difficulty: u16,
+    #[serde(default = "default_max_difficulty")]
+    pub max_difficulty: u16,
+    /// How many times a minute one connect token may ask for bridges, challenged or not.
+    #[serde(default = "default_per_token_per_min")]
+    pub per_token_per_min: u32,
+}
+
+impl Default for ChallengeConfig {
+    fn default() -> Self {
+        Self {
+            threshold_per_min: default_threshold_per_min(),
+            base_difficulty: default_base_difficulty(),
+            max_difficulty: default_max_difficulty(),
+            per_token_per_min: default_per_token_per_min(),
+        }
+    }
+}
+
+fn default_threshold_per_min() -> u32 {
+    600
+}
+
+fn default_base_difficulty() -> u16 {
+    16
+}
+
+fn default_max_difficulty() -> u16 {
+    22
+}
+
+fn default_per_token_per_min() -> u32 {
+    10
+}
+
+/// Decides whether a bridge-list request may go ahead. Returns the puzzle and difficulty the requester must solve first, if any, and an error if the token is asking too often.
+pub async fn challenge_routes_request(
+    token: &ClientToken,
+    solution: Option<&PuzzleSolution>,
+) -> anyhow::Result<Option<(String, u16)>> {
+    static PER_TOKEN: LazyLock<IdentityLimiter> = LazyLock::new(|| {
+        IdentityLimiter::new(
+            CONFIG_FILE.wait().routes_challenge.per_token_per_min,
+            Duration::from_secs(60),
+        )
+    });
+
+    let cfg = &CONFIG_FILE.wait().routes_challenge;
+    if !PER_TOKEN.allow(&format!("{:?}", token)).await {
+        anyhow::bail!("too many bridge requests for this token")
+    }
+
+    let rate = RATE.lock().unwrap().record();
+    let difficulty = adaptive_difficulty(rate, cfg);
+    if difficulty == 0 {
+        return Ok(None);
+    }
+    tracing::debug!(rate, difficulty, "challenging a bridge-list request");
+
+    if let Some(solution) = solution {
+        match verify_solution(token, solution) {
+            Ok(()) => return Ok(None),
+            Err(err) => tracing::debug!(err = debug(err), "rejected a puzzle solution"),
+        }
+    }
+    let window = now() / PUZZLE_WINDOW_SECS;
+    Ok(Some((routes_puzzle(token, window, difficulty), difficulty)))
+}
+
+fn adaptive_difficulty(rate: u64, cfg: &ChallengeConfig) -> u16 {
+    let threshold = cfg.threshold_per_min.max(1) as u64;
+    if rate <= threshold {
+        return 0;
+    }
+    let doublings = (rate / threshold).ilog2() as u16;
+    (cfg.base_difficulty + doublings).min(cfg.max_difficulty)
+}
+
+/// Puzzles are bound to the token and carry their window and difficulty, authenticated with a key derived from the master secret, so that the broker needs to remember nothing about the puzzles it handed out.
+fn routes_puzzle(token: &ClientToken, window: u64, difficulty: u16) -> String {
+    let key = blake3::derive_key("geph5 routes puzzle", MASTER_SECRET.as_bytes());
+    let mac = blake3::keyed_hash(
+        &key,
+        format!("{:?}/{window}/{difficulty}", token).as_bytes(),
+    );
+    format!("routes-{window}-{difficulty}-{}", mac.to_hex())
+}
+
+fn verify_solution(token: &ClientToken, solution: &PuzzleSolution) -> anyhow::Result<()> {
+    let mut parts = solution.puzzle.split('-');
+    let (Some("routes"), Some(window), Some(difficulty)) =
+        (parts.next(), parts.next(), parts.next())
+    else {
+        anyhow::bail!("malformed puzzle")
+    };
+    let window: u64 = window.parse()?;
+    let difficulty: u16 = difficulty.parse()?;
+    if routes_puzzle(token, window, difficulty) != solution.puzzle {
+        anyhow::bail!("puzzle was not issued for this token")
+    }
+    if window + 1 < now() / PUZZLE_WINDOW_SECS {
+        anyhow::bail!("puzzle expired")
+    }
+    // once the rate drops back under the threshold, easier puzzles than the ones we hand out now are still fine, but trivial ones never are
+    if difficulty < CONFIG_FILE.wait().routes_challenge.base_difficulty {
+        anyhow::bail!("puzzle too easy")
+    }
+    geph5_broker_protocol::puzzle::verify_puzzle_solution(
+        &solution.puzzle,
+        difficulty,
+        &solution.solution,
+    )
+}
+
+static RATE: LazyLock<Mutex<RateMeter>> = LazyLock::new(Default::default);
+
+/// Estimates the number of requests in the last minute, by weighing the previous minute's count by how much of it still falls in that span.
+#[derive(Default)]
+struct RateMeter {
+    minute: u64,
+    current: u64,
+    previous: u64,
+}
+
+impl RateMeter {
+    fn record(&mut self) -> u64 {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let minute = now.as_secs() / 60;
+        if minute != self.minute {
+            self.previous = if minute == self.minute + 1 {
+                self.current
+            } else {
+                0
+            };
+            self.current = 0;
+            self.minute = minute;
+        }
+        self.current += 1;
+        let elapsed = (now.as_secs_f64() / 60.0).fract();
+        self.current + (self.previous as f64 * (1.0 - elapsed)) as u64
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
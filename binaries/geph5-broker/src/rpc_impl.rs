@@ -5,9 +5,15 @@ use cadence::{StatsdClient, UdpMetricSink};
 use ed25519_dalek::VerifyingKey;
 use futures_util::{future::join_all, TryFutureExt};
 use geph5_broker_protocol::{
-    AccountLevel, AuthError, AvailabilityData, BridgeDescriptor, BrokerProtocol, BrokerService,
-    Credential, ExitDescriptor, ExitList, GenericError, Mac, NewsItem, RouteDescriptor, Signed,
-    UserInfo, VoucherInfo, DOMAIN_EXIT_DESCRIPTOR,
+    AccountLevel, AnnouncementList, AuthError, AvailabilityData, BridgeDescriptor, BridgeUsage,
+    BridgeUsageQuery, BridgeUsageSummary, BrokerProtocol, BrokerService, Capabilities, Credential,
+    ExitDescriptor, ExitFeatures, ExitList, ExitLoad, GenericError, Mac, NewsItem,
+    ProtocolOutcomes, PuzzleSolution, ReverseForwardGrant, RouteDescriptor, RoutesOrChallenge,
+    Signed, TrustInfo, UserInfo, VolunteerRegistration, VoucherInfo, BROKER_PROTOCOL_VERSION,
+    DOMAIN_ANNOUNCEMENT, DOMAIN_EXIT_DESCRIPTOR, DOMAIN_EXIT_FEATURES, DOMAIN_EXIT_LOAD,
+    DOMAIN_REVERSE_FORWARD, FEATURE_EXIT_LOAD, FEATURE_IPV6_EXITS, FEATURE_MIRRORS,
+    FEATURE_PROTOCOL_HINTS, FEATURE_REVERSE_FORWARD, FEATURE_ROUTES_CHALLENGE,
+    FEATURE_TRUST_GROUPS,
 };
 use influxdb_line_protocol::LineProtocolBuilder;
 use isocountry::CountryCode;
@@ -19,7 +25,7 @@ use rand::Rng;
 use std::{
     net::SocketAddr,
     ops::Deref,
-    sync::Arc,
+    sync::{Arc, LazyLock},
     time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
@@ -36,16 +42,28 @@ use crate::{
 };
 use crate::{
     auth::{new_auth_token, valid_auth_token},
-    database::{insert_exit, query_bridges, ExitRow, POSTGRES},
-    routes::bridge_to_leaf_route,
+    dashboard,
+    database::{
+        insert_bridge_usage, insert_exit, insert_exit_features, insert_exit_load,
+        query_announcements, query_bridge_usage, query_bridges, query_ipv6_exits, ExitRow,
+        EXIT_LOAD_TTL_SECS, POSTGRES,
+    },
+    distribution::IdentityLimiter,
+    partition::BridgeQuery,
+    protocol_stats::{protocol_hints, record_outcomes},
+    routes::{bridge_to_leaf_route, exit_direct_routes, ROUTE_TRANSPORTS},
+    routes_challenge::challenge_routes_request,
+    trust::{group_routes, issue_invite, redeem_invite, trust_info},
+    volunteer::{probe_bridge, register_volunteer, verify_bridge_mac},
     CONFIG_FILE, FREE_MIZARU_SK, MASTER_SECRET, PLUS_MIZARU_SK,
 };
 
 pub struct WrappedBrokerService(BrokerService<BrokerImpl>);
 
 impl WrappedBrokerService {
-    pub fn new() -> Self {
-        Self(BrokerService(BrokerImpl {}))
+    /// Creates a service for requests from the given country, if we know it.
+    pub fn new(requester_country: Option<String>) -> Self {
+        Self(BrokerService(BrokerImpl { requester_country }))
     }
 }
 
@@ -78,9 +96,71 @@ impl RpcService for WrappedBrokerService {
     }
 }
 
-struct BrokerImpl {}
+struct BrokerImpl {
+    requester_country: Option<String>,
+}
 
 impl BrokerImpl {
+    /// Picks the bridges for a requester whose token was already checked, and builds routes through them to the given exit.
+    async fn serve_routes(
+        &self,
+        token: ClientToken,
+        account_level: AccountLevel,
+        exit: SocketAddr,
+    ) -> anyhow::Result<RouteDescriptor> {
+        let key = format!("{:?}", token);
+        let query_usable = |reserve| {
+            let query = BridgeQuery::new(
+                &key,
+                self.requester_country.as_deref(),
+                account_level,
+                reserve,
+            );
+            async move {
+                let raw_descriptors = query_bridges(&query).await?;
+                anyhow::Ok(if account_level == AccountLevel::Free {
+                    raw_descriptors
+                        .into_iter()
+                        .filter(|(_, _, is_plus)| !is_plus)
+                        .collect::<Vec<_>>()
+                } else {
+                    raw_descriptors
+                })
+            }
+        };
+        let mut raw_descriptors = query_usable(false).await?;
+        // reserve pools are only for those who would otherwise get nothing
+        if raw_descriptors.is_empty() {
+            raw_descriptors = query_usable(true).await?;
+        }
+
+        let mut routes = vec![];
+        for route in (join_all(
+            raw_descriptors
+                .into_iter()
+                .map(|(desc, delay_ms, _is_plus)| {
+                    let bridge = desc.control_listen;
+                    bridge_to_leaf_route(desc, delay_ms, exit).inspect_err(move |err| {
+                        tracing::warn!(
+                            err = debug(err),
+                            bridge = debug(bridge),
+                            exit = debug(exit),
+                            "failed to call bridge_to_leaf_route"
+                        )
+                    })
+                }),
+        )
+        .await)
+            .into_iter()
+            .flatten()
+        {
+            routes.push(route)
+        }
+        routes.extend(exit_direct_routes(exit).await);
+
+        Ok(RouteDescriptor::Race(routes))
+    }
+
     async fn get_all_exits(&self) -> Result<ExitList, GenericError> {
         static EXIT_CACHE: Lazy<Cache<(), ExitList>> = Lazy::new(|| {
             Cache::builder()
@@ -90,26 +170,33 @@ impl BrokerImpl {
 
         let exit_list = EXIT_CACHE
             .try_get_with((), async {
-                let exits: Vec<(VerifyingKey, ExitDescriptor)> =
-                    sqlx::query_as("select * from exits_new")
-                        .fetch_all(POSTGRES.deref())
-                        .await?
-                        .into_iter()
-                        .map(|row: ExitRow| {
-                            (
-                                VerifyingKey::from_bytes(&row.pubkey).unwrap(),
-                                ExitDescriptor {
-                                    c2e_listen: row.c2e_listen.parse().unwrap(),
-                                    b2e_listen: row.b2e_listen.parse().unwrap(),
-                                    country: CountryCode::for_alpha2_caseless(&row.country)
-                                        .unwrap(),
-                                    city: row.city,
-                                    load: row.load,
-                                    expiry: row.expiry as _,
-                                },
-                            )
-                        })
-                        .collect();
+                // a recent load report can only make an exit look busier, so that clients weighing exits by load spread out over the ones with room to spare
+                let exits: Vec<(VerifyingKey, ExitDescriptor)> = sqlx::query_as(
+                    r"select e.pubkey, e.c2e_listen, e.b2e_listen, e.country, e.city,
+                        greatest(e.load, coalesce(1 - l.remaining_capacity, 0))::real as load,
+                        e.expiry
+                    from exits_new e
+                    left join exit_loads l
+                        on l.pubkey = e.pubkey and l.updated > extract(epoch from now()) - $1",
+                )
+                .bind(EXIT_LOAD_TTL_SECS)
+                .fetch_all(POSTGRES.deref())
+                .await?
+                .into_iter()
+                .map(|row: ExitRow| {
+                    (
+                        VerifyingKey::from_bytes(&row.pubkey).unwrap(),
+                        ExitDescriptor {
+                            c2e_listen: row.c2e_listen.parse().unwrap(),
+                            b2e_listen: row.b2e_listen.parse().unwrap(),
+                            country: CountryCode::for_alpha2_caseless(&row.country).unwrap(),
+                            city: row.city,
+                            load: row.load,
+                            expiry: row.expiry as _,
+                        },
+                    )
+                })
+                .collect();
                 let exit_list = ExitList {
                     all_exits: exits,
                     city_names: serde_yaml::from_str(include_str!("city_names.yaml")).unwrap(),
@@ -122,6 +209,24 @@ impl BrokerImpl {
     }
 }
 
+/// All exits that users of the given level may use, for frontends other than the RPC.
+pub async fn exit_list(level: AccountLevel) -> Result<ExitList, GenericError> {
+    let mut exit_list = BrokerImpl {
+        requester_country: None,
+    }
+    .get_all_exits()
+    .await?;
+    if level == AccountLevel::Free {
+        exit_list.all_exits.retain(|(_, e)| !is_plus_exit(e));
+    }
+    Ok(exit_list)
+}
+
+/// All exits that free users may use, for frontends other than the RPC.
+pub async fn free_exits() -> Result<Vec<(VerifyingKey, ExitDescriptor)>, GenericError> {
+    Ok(exit_list(AccountLevel::Free).await?.all_exits)
+}
+
 fn is_plus_exit(exit: &ExitDescriptor) -> bool {
     !matches!(
         exit.country,
@@ -135,6 +240,31 @@ fn is_plus_exit(exit: &ExitDescriptor) -> bool {
 
 #[async_trait]
 impl BrokerProtocol for BrokerImpl {
+    async fn get_capabilities(&self) -> Capabilities {
+        let cfg = CONFIG_FILE.wait();
+        let mut features = vec![FEATURE_ROUTES_CHALLENGE, FEATURE_EXIT_LOAD];
+        if cfg.mirror.is_some() {
+            features.push(FEATURE_MIRRORS);
+        }
+        if cfg.trust.is_some() {
+            features.push(FEATURE_TRUST_GROUPS);
+        }
+        features.push(FEATURE_PROTOCOL_HINTS);
+        features.push(FEATURE_IPV6_EXITS);
+        if cfg.reverse_forward.is_some() {
+            features.push(FEATURE_REVERSE_FORWARD);
+        }
+        Capabilities {
+            protocol_versions: [BROKER_PROTOCOL_VERSION].into(),
+            transports: ROUTE_TRANSPORTS.iter().map(|s| s.to_string()).collect(),
+            auth_schemes: ["secret", "legacy_username_password"]
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            features: features.into_iter().map(String::from).collect(),
+        }
+    }
+
     async fn get_mizaru_subkey(&self, level: AccountLevel, epoch: u16) -> Bytes {
         match level {
             AccountLevel::Free => &FREE_MIZARU_SK,
@@ -190,6 +320,13 @@ impl BrokerProtocol for BrokerImpl {
         }
         .blind_sign(epoch, &blind_token);
         tracing::debug!(elapsed = debug(start.elapsed()), "blind signing done");
+        dashboard::count(
+            "tokens_issued",
+            match level {
+                AccountLevel::Free => "free",
+                AccountLevel::Plus => "plus",
+            },
+        );
         Ok(signed)
     }
 
@@ -235,54 +372,31 @@ impl BrokerProtocol for BrokerImpl {
         sig: UnblindedSignature,
         exit: SocketAddr,
     ) -> Result<RouteDescriptor, GenericError> {
-        // authenticate the token
-        let account_level = if PLUS_MIZARU_SK
-            .to_public_key()
-            .blind_verify(token, &sig)
-            .is_ok()
-        {
-            AccountLevel::Plus
-        } else {
-            FREE_MIZARU_SK.to_public_key().blind_verify(token, &sig)?;
-
-            AccountLevel::Free
-        };
-
-        let raw_descriptors = query_bridges(&format!("{:?}", token)).await?;
-
-        let raw_descriptors = if account_level == AccountLevel::Free {
-            raw_descriptors
-                .into_iter()
-                .filter(|(_, _, is_plus)| !is_plus)
-                .collect()
-        } else {
-            raw_descriptors
-        };
+        let account_level = verify_connect_token(token, &sig)?;
+        if challenge_routes_request(&token, None).await?.is_some() {
+            return Err(GenericError(
+                "bridge requests must solve a puzzle right now, which needs a newer client".into(),
+            ));
+        }
+        Ok(self.serve_routes(token, account_level, exit).await?)
+    }
 
-        let mut routes = vec![];
-        for route in (join_all(
-            raw_descriptors
-                .into_iter()
-                .map(|(desc, delay_ms, _is_plus)| {
-                    let bridge = desc.control_listen;
-                    bridge_to_leaf_route(desc, delay_ms, exit).inspect_err(move |err| {
-                        tracing::warn!(
-                            err = debug(err),
-                            bridge = debug(bridge),
-                            exit = debug(exit),
-                            "failed to call bridge_to_leaf_route"
-                        )
-                    })
-                }),
-        )
-        .await)
-            .into_iter()
-            .flatten()
+    async fn get_routes_challenged(
+        &self,
+        token: ClientToken,
+        sig: UnblindedSignature,
+        exit: SocketAddr,
+        solution: Option<PuzzleSolution>,
+    ) -> Result<RoutesOrChallenge, GenericError> {
+        let account_level = verify_connect_token(token, &sig)?;
+        if let Some((puzzle, difficulty)) =
+            challenge_routes_request(&token, solution.as_ref()).await?
         {
-            routes.push(route)
+            return Ok(RoutesOrChallenge::Challenge { puzzle, difficulty });
         }
-
-        Ok(RouteDescriptor::Race(routes))
+        Ok(RoutesOrChallenge::Routes(
+            self.serve_routes(token, account_level, exit).await?,
+        ))
     }
 
     async fn insert_exit(
@@ -318,9 +432,118 @@ impl BrokerProtocol for BrokerImpl {
         Ok(())
     }
 
+    async fn report_exit_load(&self, report: Mac<Signed<ExitLoad>>) -> Result<(), GenericError> {
+        let report =
+            report.verify(blake3::hash(CONFIG_FILE.wait().exit_token.as_bytes()).as_bytes())?;
+        let pubkey = report.pubkey;
+        let report = report.verify(DOMAIN_EXIT_LOAD, |_| true)?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        if report.timestamp.abs_diff(now) > EXIT_LOAD_TTL_SECS as u64 {
+            return Err(GenericError(
+                "Exit load report is too old or from the future".to_string(),
+            ));
+        }
+        tracing::trace!(
+            remaining_capacity = report.remaining_capacity(),
+            sessions = report.sessions,
+            "exit reported its load"
+        );
+        insert_exit_load(pubkey.to_bytes(), &report).await?;
+        Ok(())
+    }
+
+    async fn report_exit_features(
+        &self,
+        report: Mac<Signed<ExitFeatures>>,
+    ) -> Result<(), GenericError> {
+        let report =
+            report.verify(blake3::hash(CONFIG_FILE.wait().exit_token.as_bytes()).as_bytes())?;
+        let pubkey = report.pubkey;
+        let report = report.verify(DOMAIN_EXIT_FEATURES, |_| true)?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        if report.timestamp.abs_diff(now) > EXIT_LOAD_TTL_SECS as u64 {
+            return Err(GenericError(
+                "Exit features report is too old or from the future".to_string(),
+            ));
+        }
+        insert_exit_features(pubkey.to_bytes(), &report).await?;
+        Ok(())
+    }
+
+    async fn get_ipv6_exits(&self) -> Result<Vec<VerifyingKey>, GenericError> {
+        Ok(query_ipv6_exits()
+            .await?
+            .into_iter()
+            .filter_map(|pubkey| VerifyingKey::from_bytes(&pubkey).ok())
+            .collect())
+    }
+
+    async fn get_reverse_forward_grant(
+        &self,
+        auth_token: String,
+    ) -> Result<Signed<ReverseForwardGrant>, GenericError> {
+        let Some(cfg) = &CONFIG_FILE.wait().reverse_forward else {
+            return Err(GenericError(
+                "reverse port forwarding is not granted by this broker".into(),
+            ));
+        };
+        static PER_USER: LazyLock<IdentityLimiter> = LazyLock::new(|| {
+            IdentityLimiter::new(
+                CONFIG_FILE
+                    .wait()
+                    .reverse_forward
+                    .as_ref()
+                    .map(|cfg| cfg.grants_per_hour)
+                    .unwrap_or_default(),
+                Duration::from_secs(3600),
+            )
+        });
+
+        let user_id = match valid_auth_token(auth_token).await? {
+            Some((user_id, AccountLevel::Plus)) => user_id,
+            Some(_) => {
+                return Err(GenericError(
+                    "only Plus users may use reverse port forwarding".into(),
+                ))
+            }
+            None => return Err(GenericError("invalid auth token".into())),
+        };
+        if !PER_USER.allow(&user_id.to_string()).await {
+            return Err(GenericError(
+                "too many reverse port forwarding grants for this user".into(),
+            ));
+        }
+        let key = blake3::derive_key("geph5 reverse forward holder", MASTER_SECRET.as_bytes());
+        let holder = *blake3::keyed_hash(&key, &user_id.to_be_bytes()).as_bytes();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        Ok(Signed::new(
+            ReverseForwardGrant {
+                max_ports: cfg.max_ports,
+                expiry: now + cfg.grant_lifetime_secs,
+                holder,
+            },
+            DOMAIN_REVERSE_FORWARD,
+            MASTER_SECRET.deref(),
+        ))
+    }
+
     async fn insert_bridge(&self, descriptor: Mac<BridgeDescriptor>) -> Result<(), GenericError> {
-        let descriptor = descriptor
-            .verify(blake3::hash(CONFIG_FILE.wait().bridge_token.as_bytes()).as_bytes())?;
+        let (listen, pool) = (
+            descriptor.inner.control_listen,
+            descriptor.inner.pool.clone(),
+        );
+        let descriptor = verify_bridge_mac(descriptor, listen, &pool).await?;
         tracing::debug!("inserting bridge from pool {}", descriptor.pool);
         sqlx::query(
             r#"
@@ -339,6 +562,65 @@ impl BrokerProtocol for BrokerImpl {
         Ok(())
     }
 
+    async fn register_volunteer_bridge(
+        &self,
+        control_listen: SocketAddr,
+        control_cookie: String,
+    ) -> Result<VolunteerRegistration, GenericError> {
+        Ok(register_volunteer(control_listen, control_cookie).await?)
+    }
+
+    async fn probe_bridge(
+        &self,
+        control_listen: SocketAddr,
+        control_cookie: String,
+    ) -> Result<(), GenericError> {
+        Ok(probe_bridge(control_listen, control_cookie).await?)
+    }
+
+    async fn report_bridge_usage(&self, usage: Mac<BridgeUsage>) -> Result<(), GenericError> {
+        let (listen, pool) = (usage.inner.control_listen, usage.inner.pool.clone());
+        let usage = verify_bridge_mac(usage, listen, &pool).await?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        if usage.timestamp.abs_diff(now) > EXIT_LOAD_TTL_SECS as u64 {
+            return Err(GenericError(
+                "Bridge usage report is too old or from the future".to_string(),
+            ));
+        }
+        insert_bridge_usage(&usage).await?;
+        Ok(())
+    }
+
+    async fn get_bridge_usage(
+        &self,
+        query: Mac<BridgeUsageQuery>,
+    ) -> Result<Vec<BridgeUsageSummary>, GenericError> {
+        let pool = query.inner.pool.clone();
+        let query = match query.inner.control_listen {
+            Some(listen) => verify_bridge_mac(query, listen, &pool).await?,
+            None => {
+                query.verify(blake3::hash(CONFIG_FILE.wait().bridge_token.as_bytes()).as_bytes())?
+            }
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        if query.timestamp.abs_diff(now) > EXIT_LOAD_TTL_SECS as u64 {
+            return Err(GenericError(
+                "Bridge usage query is too old or from the future".to_string(),
+            ));
+        }
+        let mut summaries = query_bridge_usage(&query.pool).await?;
+        if let Some(listen) = query.control_listen {
+            summaries.retain(|summary| summary.control_listen == listen);
+        }
+        Ok(summaries)
+    }
+
     async fn incr_stat(&self, stat: String, value: i32) {
         if let Some(client) = STATSD_CLIENT.as_ref() {
             client.count(&stat, value).unwrap();
@@ -382,6 +664,36 @@ impl BrokerProtocol for BrokerImpl {
         .detach();
     }
 
+    async fn report_protocol_outcomes(
+        &self,
+        token: ClientToken,
+        sig: UnblindedSignature,
+        outcomes: Vec<ProtocolOutcomes>,
+    ) -> Result<(), GenericError> {
+        // clients report hourly, so more than this is someone trying to skew the hints
+        static PER_TOKEN: LazyLock<IdentityLimiter> =
+            LazyLock::new(|| IdentityLimiter::new(3, Duration::from_secs(3600)));
+
+        verify_connect_token(token, &sig)?;
+        if !PER_TOKEN.allow(&format!("{:?}", token)).await {
+            return Err(GenericError(
+                "too many protocol reports for this token".into(),
+            ));
+        }
+        let country = self.requester_country.clone();
+        smolscale::spawn(
+            async move { record_outcomes(country.as_deref(), &outcomes).await }.inspect_err(|e| {
+                tracing::warn!(err = debug(e), "recording protocol outcomes failed")
+            }),
+        )
+        .detach();
+        Ok(())
+    }
+
+    async fn get_protocol_hints(&self) -> Result<Vec<String>, GenericError> {
+        Ok(protocol_hints(self.requester_country.as_deref()).await?)
+    }
+
     async fn get_puzzle(&self) -> (String, u16) {
         (new_puzzle().await, CONFIG_FILE.wait().puzzle_difficulty)
     }
@@ -408,6 +720,17 @@ impl BrokerProtocol for BrokerImpl {
         recv.await.unwrap().map_err(|e: anyhow::Error| e.into())
     }
 
+    async fn get_announcements(&self) -> Result<Signed<AnnouncementList>, GenericError> {
+        Ok(Signed::new(
+            AnnouncementList {
+                announcements: query_announcements().await?,
+                requester_country: self.requester_country.clone(),
+            },
+            DOMAIN_ANNOUNCEMENT,
+            MASTER_SECRET.deref(),
+        ))
+    }
+
     async fn raw_price_points(&self) -> Result<Vec<(u32, u32)>, GenericError> {
         Ok(vec![(30, 500), (90, 1500), (365, 5475), (730, 10342)])
     }
@@ -509,6 +832,30 @@ impl BrokerProtocol for BrokerImpl {
         Ok(days)
     }
 
+    async fn get_trust_info(&self, secret: String) -> Result<Option<TrustInfo>, GenericError> {
+        let user_id = validate_credential(Credential::Secret(secret)).await?;
+        Ok(trust_info(user_id).await?)
+    }
+
+    async fn issue_invite(&self, secret: String) -> Result<String, GenericError> {
+        let user_id = validate_credential(Credential::Secret(secret)).await?;
+        Ok(issue_invite(user_id).await?)
+    }
+
+    async fn redeem_invite(&self, secret: String, code: String) -> Result<TrustInfo, GenericError> {
+        let user_id = validate_credential(Credential::Secret(secret)).await?;
+        Ok(redeem_invite(user_id, &code).await?)
+    }
+
+    async fn get_group_routes(
+        &self,
+        secret: String,
+        exit_b2e: SocketAddr,
+    ) -> Result<RouteDescriptor, GenericError> {
+        let user_id = validate_credential(Credential::Secret(secret)).await?;
+        Ok(group_routes(user_id, self.requester_country.as_deref(), exit_b2e).await?)
+    }
+
     async fn upload_debug_pack(
         &self,
         email: Option<String>,
@@ -530,6 +877,23 @@ impl BrokerProtocol for BrokerImpl {
     }
 }
 
+/// Checks a connect token against both the Plus and the Free keys, returning the level it is good for.
+fn verify_connect_token(
+    token: ClientToken,
+    sig: &UnblindedSignature,
+) -> Result<AccountLevel, GenericError> {
+    if PLUS_MIZARU_SK
+        .to_public_key()
+        .blind_verify(token, sig)
+        .is_ok()
+    {
+        Ok(AccountLevel::Plus)
+    } else {
+        FREE_MIZARU_SK.to_public_key().blind_verify(token, sig)?;
+        Ok(AccountLevel::Free)
+    }
+}
+
 pub static STATSD_CLIENT: Lazy<Option<StatsdClient>> = Lazy::new(|| {
     if let Some(statsd_addr) = CONFIG_FILE.wait().statsd_addr {
         let socket = std::net::UdpSocket::bind("0.0.0.0:0").unwrap();
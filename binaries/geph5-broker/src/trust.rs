@@ -0,0 +1,473 @@
+use std::{
+    net::SocketAddr,
+    ops::Deref,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use async_io::Timer;
+use futures_util::future::join_all;
+use geph5_broker_protocol::{AccountLevel, RouteDescriptor, TrustInfo};
+use serde::Deserialize;
+
+use crate::{
+    auth::get_subscription_expiry,
+    database::{query_bridges, POSTGRES},
+    partition::{hash_u32, BridgeQuery},
+    reachability::UNREACHABLE_THRESHOLD,
+    routes::bridge_to_leaf_route,
+    CONFIG_FILE,
+};
+
+/// How long an invite can be redeemed after it was issued.
+const INVITE_LIFETIME_SECS: i64 = 86400 * 7;
+
+/// How often we look for trust groups whose bridges were blocked.
+const STRIKE_CHECK_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Configuration for invite-based bridge distribution. Accounts gain a level of trust for every period they go without their group's bridges getting blocked, and accounts trusted enough may invite others into their group, one level below themselves. Every group gets bridges of its own out of dedicated pools, so when one of them is blocked, the whole group loses its trust and moves on to fresh bridges, and groups that keep getting their bridges blocked lose them for good.
+#[derive(Deserialize, Clone, Debug)]
+pub struct TrustConfig {
+    /// Pools whose bridges only go to trust groups.
+    pub pools: Vec<String>,
+    #[serde(default = "default_max_trust")]
+    pub max_trust: u32,
+    /// The trust level at which accounts may issue invites.
+    #[serde(default = "default_invite_trust")]
+    pub invite_trust: u32,
+    #[serde(default = "default_promotion_secs")]
+    pub promotion_secs: u64,
+    #[serde(default = "default_invites_per_period")]
+    pub invites_per_period: u32,
+    #[serde(default = "default_invite_period_secs")]
+    pub invite_period_secs: u64,
+    #[serde(default = "default_max_group_size")]
+    pub max_group_size: u32,
+    /// How many times a group's bridges may be blocked before it gets no more.
+    #[serde(default = "default_max_strikes")]
+    pub max_strikes: u32,
+    /// Whether paying accounts start groups of their own without an invite. Payments are costly to fake in bulk, which makes such accounts good roots.
+    #[serde(default = "default_seed_plus")]
+    pub seed_plus: bool,
+}
+
+fn default_max_trust() -> u32 {
+    6
+}
+
+fn default_invite_trust() -> u32 {
+    3
+}
+
+fn default_promotion_secs() -> u64 {
+    86400 * 14
+}
+
+fn default_invites_per_period() -> u32 {
+    2
+}
+
+fn default_invite_period_secs() -> u64 {
+    86400 * 30
+}
+
+fn default_max_group_size() -> u32 {
+    8
+}
+
+fn default_max_strikes() -> u32 {
+    3
+}
+
+fn default_seed_plus() -> bool {
+    true
+}
+
+/// Creates the tables of trust groups, their members, invites, and the bridges handed to each group, if they do not exist yet.
+pub async fn init_trust_tables() -> anyhow::Result<()> {
+    for statement in [
+        r"CREATE TABLE IF NOT EXISTS trust_groups (
+            group_id BIGSERIAL PRIMARY KEY,
+            strikes INTEGER NOT NULL DEFAULT 0,
+            epoch INTEGER NOT NULL DEFAULT 0,
+            created BIGINT NOT NULL
+        )",
+        r"CREATE TABLE IF NOT EXISTS trust_members (
+            user_id INTEGER PRIMARY KEY,
+            group_id BIGINT NOT NULL,
+            trust INTEGER NOT NULL,
+            trust_since BIGINT NOT NULL,
+            inviter INTEGER,
+            joined BIGINT NOT NULL
+        )",
+        r"CREATE TABLE IF NOT EXISTS trust_invites (
+            code TEXT PRIMARY KEY,
+            inviter INTEGER NOT NULL,
+            created BIGINT NOT NULL,
+            redeemed_by INTEGER
+        )",
+        r"CREATE TABLE IF NOT EXISTS trust_group_bridges (
+            group_id BIGINT NOT NULL,
+            listen TEXT NOT NULL,
+            assigned BIGINT NOT NULL,
+            PRIMARY KEY (group_id, listen)
+        )",
+    ] {
+        sqlx::query(statement).execute(POSTGRES.deref()).await?;
+    }
+    Ok(())
+}
+
+fn trust_config() -> anyhow::Result<&'static TrustConfig> {
+    CONFIG_FILE
+        .wait()
+        .trust
+        .as_ref()
+        .context("invite-based bridge distribution is not enabled")
+}
+
+/// A trust level as stored, before counting the periods gone by since.
+struct Membership {
+    group_id: i64,
+    trust: i32,
+    trust_since: i64,
+}
+
+impl Membership {
+    fn effective_trust(&self, cfg: &TrustConfig) -> u32 {
+        let gained = (now() as i64 - self.trust_since).max(0) as u64 / cfg.promotion_secs.max(1);
+        (self.trust.max(0) as u64 + gained).min(cfg.max_trust as u64) as u32
+    }
+
+    fn next_promotion(&self, cfg: &TrustConfig) -> Option<u64> {
+        let trust = self.effective_trust(cfg);
+        (trust < cfg.max_trust).then(|| {
+            let gained = trust as u64 - self.trust.max(0) as u64;
+            self.trust_since.max(0) as u64 + (gained + 1) * cfg.promotion_secs.max(1)
+        })
+    }
+}
+
+async fn membership(user_id: i32) -> anyhow::Result<Option<Membership>> {
+    let row: Option<(i64, i32, i64)> =
+        sqlx::query_as("SELECT group_id, trust, trust_since FROM trust_members WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_optional(POSTGRES.deref())
+            .await?;
+    Ok(row.map(|(group_id, trust, trust_since)| Membership {
+        group_id,
+        trust,
+        trust_since,
+    }))
+}
+
+/// Looks up the account's membership, making a paying account the root of a new group if it has none.
+async fn membership_or_seed(cfg: &TrustConfig, user_id: i32) -> anyhow::Result<Option<Membership>> {
+    if let Some(member) = membership(user_id).await? {
+        return Ok(Some(member));
+    }
+    if !cfg.seed_plus || get_subscription_expiry(user_id).await?.is_none() {
+        return Ok(None);
+    }
+    let mut txn = POSTGRES.begin().await?;
+    let (group_id,): (i64,) = sqlx::query_as(
+        "INSERT INTO trust_groups (created) VALUES (extract(epoch from now())::bigint) RETURNING group_id",
+    )
+    .fetch_one(&mut *txn)
+    .await?;
+    let res = sqlx::query(
+        r"INSERT INTO trust_members (user_id, group_id, trust, trust_since, inviter, joined)
+        VALUES ($1, $2, 0, extract(epoch from now())::bigint, NULL, extract(epoch from now())::bigint)
+        ON CONFLICT (user_id) DO NOTHING",
+    )
+    .bind(user_id)
+    .bind(group_id)
+    .execute(&mut *txn)
+    .await?;
+    // someone else seeded us at the same time, so the group we just made stays unused
+    if res.rows_affected() == 0 {
+        txn.rollback().await?;
+    } else {
+        txn.commit().await?;
+        tracing::debug!(user_id, group_id, "seeded a trust group");
+    }
+    membership(user_id).await
+}
+
+/// Where the account stands, or nothing if it isn't in any group.
+pub async fn trust_info(user_id: i32) -> anyhow::Result<Option<TrustInfo>> {
+    let cfg = trust_config()?;
+    let Some(member) = membership_or_seed(cfg, user_id).await? else {
+        return Ok(None);
+    };
+    let (strikes, group_size): (i32, i64) = sqlx::query_as(
+        r"SELECT g.strikes, (SELECT COUNT(*) FROM trust_members m WHERE m.group_id = g.group_id)
+        FROM trust_groups g WHERE g.group_id = $1",
+    )
+    .bind(member.group_id)
+    .fetch_one(POSTGRES.deref())
+    .await?;
+    let (issued,): (i64,) = sqlx::query_as(
+        "SELECT COUNT(*) FROM trust_invites WHERE inviter = $1 AND created > extract(epoch from now()) - $2",
+    )
+    .bind(user_id)
+    .bind(cfg.invite_period_secs as i64)
+    .fetch_one(POSTGRES.deref())
+    .await?;
+
+    let trust = member.effective_trust(cfg);
+    let invites_left = if trust >= cfg.invite_trust && (strikes as u32) < cfg.max_strikes {
+        cfg.invites_per_period.saturating_sub(issued as u32)
+    } else {
+        0
+    };
+    Ok(Some(TrustInfo {
+        trust,
+        max_trust: cfg.max_trust,
+        invite_trust: cfg.invite_trust,
+        invites_left,
+        next_promotion: member.next_promotion(cfg),
+        group_size: group_size as u32,
+        strikes: strikes as u32,
+    }))
+}
+
+/// Issues an invite code into the account's group.
+pub async fn issue_invite(user_id: i32) -> anyhow::Result<String> {
+    let cfg = trust_config()?;
+    let info = trust_info(user_id)
+        .await?
+        .context("account is not in a trust group")?;
+    anyhow::ensure!(
+        info.trust >= cfg.invite_trust,
+        "account needs trust level {} to invite others",
+        cfg.invite_trust
+    );
+    anyhow::ensure!(info.invites_left > 0, "no invites left for now");
+
+    // locking the member keeps concurrent requests from issuing more invites than are left, since the count is checked again under the lock
+    let mut txn = POSTGRES.begin().await?;
+    let (group_id,): (i64,) =
+        sqlx::query_as("SELECT group_id FROM trust_members WHERE user_id = $1 FOR UPDATE")
+            .bind(user_id)
+            .fetch_one(&mut *txn)
+            .await?;
+    let (strikes, group_size, issued): (i32, i64, i64) = sqlx::query_as(
+        r"SELECT g.strikes,
+            (SELECT COUNT(*) FROM trust_members m WHERE m.group_id = g.group_id),
+            (SELECT COUNT(*) FROM trust_invites i WHERE i.inviter = $2 AND i.created > extract(epoch from now()) - $3)
+        FROM trust_groups g WHERE g.group_id = $1",
+    )
+    .bind(group_id)
+    .bind(user_id)
+    .bind(cfg.invite_period_secs as i64)
+    .fetch_one(&mut *txn)
+    .await?;
+    anyhow::ensure!((strikes as u32) < cfg.max_strikes, "trust group was closed");
+    anyhow::ensure!(
+        (issued as u32) < cfg.invites_per_period,
+        "no invites left for now"
+    );
+    anyhow::ensure!(
+        (group_size as u32) < cfg.max_group_size,
+        "trust group is full"
+    );
+
+    let code = hex::encode(rand::random::<[u8; 16]>());
+    sqlx::query(
+        "INSERT INTO trust_invites (code, inviter, created) VALUES ($1, $2, extract(epoch from now())::bigint)",
+    )
+    .bind(&code)
+    .bind(user_id)
+    .execute(&mut *txn)
+    .await?;
+    txn.commit().await?;
+    Ok(code)
+}
+
+/// Brings the account into the group of whoever issued the invite, one trust level below them.
+pub async fn redeem_invite(user_id: i32, code: &str) -> anyhow::Result<TrustInfo> {
+    let cfg = trust_config()?;
+    anyhow::ensure!(
+        membership(user_id).await?.is_none(),
+        "account is already in a trust group"
+    );
+
+    let mut txn = POSTGRES.begin().await?;
+    let invite: Option<(i32, i64, Option<i32>)> = sqlx::query_as(
+        "SELECT inviter, created, redeemed_by FROM trust_invites WHERE code = $1 FOR UPDATE",
+    )
+    .bind(code.trim().to_lowercase())
+    .fetch_optional(&mut *txn)
+    .await?;
+    let Some((inviter_id, created, None)) = invite else {
+        anyhow::bail!("no such invite, or it was already used")
+    };
+    anyhow::ensure!(
+        created + INVITE_LIFETIME_SECS > now() as i64,
+        "invite expired"
+    );
+    let inviter = membership(inviter_id)
+        .await?
+        .context("whoever issued the invite left their trust group")?;
+
+    // locking the group keeps concurrent redemptions from overfilling it
+    let (strikes, group_size): (i32, i64) = sqlx::query_as(
+        r"SELECT g.strikes, (SELECT COUNT(*) FROM trust_members m WHERE m.group_id = g.group_id)
+        FROM trust_groups g WHERE g.group_id = $1 FOR UPDATE",
+    )
+    .bind(inviter.group_id)
+    .fetch_one(&mut *txn)
+    .await?;
+    anyhow::ensure!((strikes as u32) < cfg.max_strikes, "trust group was closed");
+    anyhow::ensure!(
+        (group_size as u32) < cfg.max_group_size,
+        "trust group is full"
+    );
+
+    sqlx::query(
+        r"INSERT INTO trust_members (user_id, group_id, trust, trust_since, inviter, joined)
+        VALUES ($1, $2, $3, extract(epoch from now())::bigint, $4, extract(epoch from now())::bigint)",
+    )
+    .bind(user_id)
+    .bind(inviter.group_id)
+    .bind(inviter.effective_trust(cfg).saturating_sub(1) as i32)
+    .bind(inviter_id)
+    .execute(&mut *txn)
+    .await?;
+    sqlx::query("UPDATE trust_invites SET redeemed_by = $1 WHERE code = $2")
+        .bind(user_id)
+        .bind(code.trim().to_lowercase())
+        .execute(&mut *txn)
+        .await?;
+    txn.commit().await?;
+
+    trust_info(user_id)
+        .await?
+        .context("account vanished from its trust group")
+}
+
+/// Bridge routes to the exit, out of the bridges set aside for the account's group. Every bridge handed out is remembered, so that the group can be held to account once it is blocked.
+pub async fn group_routes(
+    user_id: i32,
+    country: Option<&str>,
+    exit_b2e: SocketAddr,
+) -> anyhow::Result<RouteDescriptor> {
+    let cfg = trust_config()?;
+    let member = membership_or_seed(cfg, user_id)
+        .await?
+        .context("account is not in a trust group")?;
+    let (strikes, epoch): (i32, i32) =
+        sqlx::query_as("SELECT strikes, epoch FROM trust_groups WHERE group_id = $1")
+            .bind(member.group_id)
+            .fetch_one(POSTGRES.deref())
+            .await?;
+    anyhow::ensure!(
+        (strikes as u32) < cfg.max_strikes,
+        "trust group had its bridges blocked too often"
+    );
+
+    // every group falls into a partition of its own choosing, rather than sharing a few with everyone from its country
+    let key = format!("trust/{}/{epoch}", member.group_id);
+    let mut query = BridgeQuery::new(&key, country, AccountLevel::Free, true);
+    query.partition = hash_u32(&key) % query.partitions;
+    query.reserve_pools = cfg.pools.clone();
+    query.excluded_pools = vec![];
+    let bridges = query_bridges(&query).await?;
+
+    for (bridge, _, _) in bridges.iter() {
+        sqlx::query(
+            r"INSERT INTO trust_group_bridges (group_id, listen, assigned)
+            VALUES ($1, $2, extract(epoch from now())::bigint)
+            ON CONFLICT (group_id, listen) DO NOTHING",
+        )
+        .bind(member.group_id)
+        .bind(bridge.control_listen.to_string())
+        .execute(POSTGRES.deref())
+        .await?;
+    }
+
+    let routes = join_all(
+        bridges
+            .into_iter()
+            .map(|(desc, delay_ms, _)| bridge_to_leaf_route(desc, delay_ms, exit_b2e)),
+    )
+    .await
+    .into_iter()
+    .filter_map(|route| {
+        route
+            .inspect_err(|err| tracing::warn!(err = debug(err), "could not route a group bridge"))
+            .ok()
+    })
+    .collect();
+    Ok(RouteDescriptor::Race(routes))
+}
+
+/// This loop strikes trust groups whose bridges were found blocked in some country while still reachable from uncensored vantage points. Their members lose their trust, and the group moves on to fresh bridges.
+#[tracing::instrument]
+pub async fn trust_strike_loop() -> anyhow::Result<()> {
+    if CONFIG_FILE.wait().trust.is_none() {
+        return std::future::pending().await;
+    }
+    tracing::info!("starting the trust strike loop");
+    loop {
+        Timer::after(STRIKE_CHECK_INTERVAL).await;
+        let blocked: Vec<(i64,)> = sqlx::query_as(
+            r"SELECT DISTINCT tgb.group_id
+            FROM trust_group_bridges tgb
+            WHERE EXISTS (
+                SELECT 1 FROM bridge_probes bp
+                 WHERE bp.listen = tgb.listen AND bp.country <> ''
+                 GROUP BY bp.country
+                HAVING MIN(bp.consecutive_failures) >= $1
+            )
+              AND EXISTS (
+                SELECT 1 FROM bridge_probes bp
+                 WHERE bp.listen = tgb.listen AND bp.country = '' AND bp.consecutive_failures < $1
+            )",
+        )
+        .bind(UNREACHABLE_THRESHOLD)
+        .fetch_all(POSTGRES.deref())
+        .await?;
+
+        for (group_id,) in blocked {
+            let mut txn = POSTGRES.begin().await?;
+            sqlx::query(
+                "UPDATE trust_groups SET strikes = strikes + 1, epoch = epoch + 1 WHERE group_id = $1",
+            )
+            .bind(group_id)
+            .execute(&mut *txn)
+            .await?;
+            sqlx::query(
+                "UPDATE trust_members SET trust = 0, trust_since = extract(epoch from now())::bigint WHERE group_id = $1",
+            )
+            .bind(group_id)
+            .execute(&mut *txn)
+            .await?;
+            sqlx::query("DELETE FROM trust_group_bridges WHERE group_id = $1")
+                .bind(group_id)
+                .execute(&mut *txn)
+                .await?;
+            txn.commit().await?;
+            tracing::info!(
+                group_id,
+                "bridges of a trust group were blocked, striking it"
+            );
+        }
+
+        let res = sqlx::query(
+            "DELETE FROM trust_invites WHERE redeemed_by IS NULL AND created < extract(epoch from now()) - $1",
+        )
+        .bind(INVITE_LIFETIME_SECS)
+        .execute(POSTGRES.deref())
+        .await?;
+        tracing::debug!(rows_affected = res.rows_affected(), "cleaned up invites");
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
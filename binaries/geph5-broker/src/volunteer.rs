@@ -0,0 +1,200 @@
+use std::{
+    net::SocketAddr,
+    ops::Deref,
+    sync::LazyLock,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use geph5_broker_protocol::{BridgeDescriptor, Mac, VolunteerRegistration};
+use geph5_misc_rpc::bridge::{bandwidth_proof, BridgeControlClient};
+use moka::future::Cache;
+use nanorpc_sillad::DialerTransport;
+use serde::{Deserialize, Serialize};
+use sillad::tcp::TcpDialer;
+use sillad_sosistab3::dialer::SosistabDialer;
+use smol_timeout2::TimeoutExt;
+
+use crate::{database::POSTGRES, routes::control_client, CONFIG_FILE};
+
+/// Configuration for bridges run by volunteers, which register themselves by proving they have enough bandwidth, and then get a token of their own instead of the one shared by the operators' bridges.
+#[derive(Deserialize, Clone, Debug)]
+pub struct VolunteerConfig {
+    /// The pool that volunteer bridges go into.
+    pub pool: String,
+    /// How fast, in kilobytes per second, a volunteer bridge must send us its answer to the bandwidth challenge.
+    #[serde(default = "default_min_kbps")]
+    pub min_kbps: u32,
+    /// How many bytes the bandwidth challenge asks for, which the answer doubles by being hex-encoded.
+    #[serde(default = "default_proof_bytes")]
+    pub proof_bytes: u32,
+    /// How long a registration lasts before the bridge has to prove its bandwidth again.
+    #[serde(default = "default_lifetime_days")]
+    pub lifetime_days: u64,
+}
+
+fn default_min_kbps() -> u32 {
+    1000
+}
+
+fn default_proof_bytes() -> u32 {
+    2_000_000
+}
+
+fn default_lifetime_days() -> u64 {
+    7
+}
+
+/// Recently looked-up registrations, so that bridges reporting in don't each hit the database.
+static VOLUNTEER_TOKENS: LazyLock<Cache<SocketAddr, Option<(String, String)>>> =
+    LazyLock::new(|| {
+        Cache::builder()
+            .time_to_live(Duration::from_secs(60))
+            .build()
+    });
+
+/// Creates the table of registered volunteer bridges, if it does not exist yet.
+pub async fn init_volunteer_table() -> anyhow::Result<()> {
+    sqlx::query(
+        r"CREATE TABLE IF NOT EXISTS volunteer_bridges (
+            listen TEXT PRIMARY KEY,
+            token TEXT NOT NULL,
+            pool TEXT NOT NULL,
+            kbps INTEGER NOT NULL,
+            expiry BIGINT NOT NULL
+        )",
+    )
+    .execute(POSTGRES.deref())
+    .await?;
+    Ok(())
+}
+
+/// Registers the volunteer bridge with the given control port, once it has answered a bandwidth challenge fast enough.
+pub async fn register_volunteer(
+    control_listen: SocketAddr,
+    control_cookie: String,
+) -> anyhow::Result<VolunteerRegistration> {
+    let cfg = CONFIG_FILE
+        .wait()
+        .volunteer_bridges
+        .as_ref()
+        .context("volunteer bridges are not accepted")?;
+    let kbps = measure_bandwidth(control_listen, control_cookie, cfg.proof_bytes).await?;
+    anyhow::ensure!(
+        kbps >= cfg.min_kbps,
+        "bridge sent at {kbps} kB/s, but {} kB/s is needed",
+        cfg.min_kbps
+    );
+    let token = hex::encode(rand::random::<[u8; 32]>());
+    let expiry =
+        SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() + cfg.lifetime_days * 86400;
+    sqlx::query(
+        r"INSERT INTO volunteer_bridges (listen, token, pool, kbps, expiry)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (listen) DO UPDATE
+        SET token = $2, pool = $3, kbps = $4, expiry = $5",
+    )
+    .bind(control_listen.to_string())
+    .bind(&token)
+    .bind(&cfg.pool)
+    .bind(kbps as i32)
+    .bind(expiry as i64)
+    .execute(POSTGRES.deref())
+    .await?;
+    VOLUNTEER_TOKENS.invalidate(&control_listen).await;
+    tracing::info!(
+        control_listen = display(control_listen),
+        kbps,
+        "registered a volunteer bridge"
+    );
+    Ok(VolunteerRegistration {
+        pool: cfg.pool.clone(),
+        token,
+        expiry,
+    })
+}
+
+/// Times how fast the bridge answers a bandwidth challenge, in kilobytes per second.
+async fn measure_bandwidth(
+    control_listen: SocketAddr,
+    control_cookie: String,
+    proof_bytes: u32,
+) -> anyhow::Result<u32> {
+    let nonce = hex::encode(rand::random::<[u8; 16]>());
+    let client = unlisted_control_client(control_listen, control_cookie);
+    let start = Instant::now();
+    let proof = client
+        .bandwidth_proof(nonce.clone(), proof_bytes)
+        .timeout(Duration::from_secs(60))
+        .await
+        .context("timed out waiting for the bandwidth proof")??;
+    let elapsed = start.elapsed();
+    anyhow::ensure!(
+        proof == bandwidth_proof(&nonce, proof_bytes as usize),
+        "wrong bandwidth proof"
+    );
+    Ok((proof.len() as f64 / 1000.0 / elapsed.as_secs_f64()) as u32)
+}
+
+/// Checks that a bridge's control port can be reached and answers with its cookie.
+pub async fn probe_bridge(
+    control_listen: SocketAddr,
+    control_cookie: String,
+) -> anyhow::Result<()> {
+    unlisted_control_client(control_listen, control_cookie)
+        .dns_tunnel_domain()
+        .timeout(Duration::from_secs(10))
+        .await
+        .context("timed out reaching the bridge")?
+        .context("cannot reach the bridge")?;
+    Ok(())
+}
+
+/// A client for the control port of a bridge that isn't in the database yet.
+fn unlisted_control_client(
+    control_listen: SocketAddr,
+    control_cookie: String,
+) -> BridgeControlClient<DialerTransport<SosistabDialer<TcpDialer>>> {
+    control_client(&BridgeDescriptor {
+        control_listen,
+        control_cookie,
+        pool: String::new(),
+        expiry: 0,
+    })
+}
+
+/// Checks a document from a bridge, which is MACed either with the token shared by the operators' bridges, or with the token of the volunteer bridge at the given address, which must then also be in the pool it was assigned.
+pub async fn verify_bridge_mac<T: Serialize + Clone>(
+    mac: Mac<T>,
+    control_listen: SocketAddr,
+    pool: &str,
+) -> anyhow::Result<T> {
+    let shared_key = blake3::hash(CONFIG_FILE.wait().bridge_token.as_bytes());
+    if let Ok(inner) = mac.clone().verify(shared_key.as_bytes()) {
+        return Ok(inner);
+    }
+    let (token, assigned_pool) = volunteer_token(control_listen)
+        .await?
+        .context("invalid MAC from an unregistered bridge")?;
+    anyhow::ensure!(
+        assigned_pool == pool,
+        "volunteer bridge claims pool {pool}, but was assigned {assigned_pool}"
+    );
+    Ok(mac.verify(blake3::hash(token.as_bytes()).as_bytes())?)
+}
+
+/// The token and pool of the volunteer bridge at the given address, if its registration is current.
+async fn volunteer_token(control_listen: SocketAddr) -> anyhow::Result<Option<(String, String)>> {
+    VOLUNTEER_TOKENS
+        .try_get_with(control_listen, async {
+            let row: Option<(String, String)> = sqlx::query_as(
+                "SELECT token, pool FROM volunteer_bridges WHERE listen = $1 AND expiry > extract(epoch from now())",
+            )
+            .bind(control_listen.to_string())
+            .fetch_optional(POSTGRES.deref())
+            .await?;
+            anyhow::Ok(row)
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!(e))
+}
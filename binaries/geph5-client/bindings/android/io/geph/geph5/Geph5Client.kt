@@ -0,0 +1,51 @@
+package io.geph.geph5
+
+/**
+ * Runs the Geph5 client inside the app, through the JNI bindings in libgeph5_client.so.
+ *
+ * Configs and connection info are JSON, in the same format as the client's control protocol.
+ * Functions returning Int give 0 on success and a negative GEPH5_ERR_* code from geph5_client.h
+ * on failure. Calls that wait on the network block, so make them off the main thread.
+ */
+object Geph5Client {
+    init {
+        System.loadLibrary("geph5_client")
+    }
+
+    /** Told about connection info changes, on a thread belonging to the client. */
+    fun interface EventListener {
+        fun onConnInfo(connInfoJson: String)
+    }
+
+    /**
+     * Starts the client. For VPN mode, pass the TUN device from VpnService.Builder.establish(),
+     * detached with ParcelFileDescriptor.detachFd(), and exclude the app itself from the VPN with
+     * addDisallowedApplication. Otherwise, pass -1.
+     */
+    @JvmStatic
+    external fun start(configJson: String, vpnFd: Int): Int
+
+    /** Stops the client, tearing down VPN mode. */
+    @JvmStatic
+    external fun stop(): Int
+
+    /** Applies a changed config. Settings that cannot change at runtime wait until restart. */
+    @JvmStatic
+    external fun setConfig(configJson: String): Int
+
+    /** The connection info, or null if the client is not running. */
+    @JvmStatic
+    external fun connInfo(): String?
+
+    /** A numeric stat, like "total_rx_bytes", or NaN if the client is not running. */
+    @JvmStatic
+    external fun statNum(stat: String): Double
+
+    /** Makes a JSON-RPC call to the control protocol, returning the response or null. */
+    @JvmStatic
+    external fun rpc(requestJson: String): String?
+
+    /** Sets the listener for connection info changes, or removes it if null. */
+    @JvmStatic
+    external fun setEventListener(listener: EventListener?): Int
+}
@@ -6,8 +6,109 @@
 #ifndef GEPH5_CLIENT_H
 #define GEPH5_CLIENT_H
 
+#include <stddef.h>
 #include <stdint.h>
 
+/*
+ * Stable interface. Strings are NUL-terminated UTF-8, configs and connection
+ * info are JSON in the control protocol's format, and every function returns
+ * one of the negative GEPH5_ERR_* codes on failure. Functions that wait on the
+ * network block the calling thread.
+ */
+
+#define GEPH5_OK 0
+#define GEPH5_ERR_NOT_RUNNING -1
+#define GEPH5_ERR_ALREADY_RUNNING -2
+#define GEPH5_ERR_INVALID_ARGUMENT -3
+#define GEPH5_ERR_BUFFER_TOO_SMALL -4
+#define GEPH5_ERR_NO_SUCH_STREAM -5
+#define GEPH5_ERR_IO -6
+
+/**
+ * @brief Called with the connection info, as JSON, whenever it changes
+ *
+ * Runs on a thread belonging to the client. The string is only valid during the call.
+ */
+typedef void (*geph5_event_callback)(const char* conn_info_json, void* userdata);
+
+/**
+ * @brief Starts the client
+ *
+ * @param config_json JSON string containing the client configuration
+ * @return int GEPH5_OK, or GEPH5_ERR_INVALID_ARGUMENT or GEPH5_ERR_ALREADY_RUNNING
+ */
+int geph5_start(const char* config_json);
+
+/**
+ * @brief Stops the client, closing its streams and tearing down VPN mode
+ *
+ * @return int GEPH5_OK, or GEPH5_ERR_NOT_RUNNING
+ */
+int geph5_stop(void);
+
+/**
+ * @brief Applies a changed configuration to the running client
+ *
+ * Settings that cannot change at runtime keep their old values until restart.
+ *
+ * @param config_json JSON string containing the client configuration
+ * @return int GEPH5_OK, or an error code
+ */
+int geph5_set_config(const char* config_json);
+
+/**
+ * @brief Writes the connection info, as JSON, into a buffer
+ *
+ * @param out_buf Buffer to store the NUL-terminated JSON
+ * @param out_buflen Length of the output buffer
+ * @return int Length of the JSON without the terminator, or an error code
+ */
+int geph5_get_conn_info(char* out_buf, size_t out_buflen);
+
+/**
+ * @brief Sets the callback told about connection info changes
+ *
+ * Once the client starts, the callback gets the initial connection info, then every change.
+ *
+ * @param callback The callback, or NULL to remove it
+ * @param userdata Passed to the callback as is; must be usable from any thread
+ * @return int GEPH5_OK
+ */
+int geph5_set_event_callback(geph5_event_callback callback, void* userdata);
+
+/**
+ * @brief Opens a TCP stream through the tunnel, blocking until it connects
+ *
+ * @param dest Destination as "host:port"
+ * @return int64_t A positive stream handle, or an error code
+ */
+int64_t geph5_open_stream(const char* dest);
+
+/**
+ * @brief Reads from a stream, blocking until data is available
+ *
+ * @return int64_t Number of bytes read, 0 once the stream is closed, or an error code
+ */
+int64_t geph5_stream_read(int64_t handle, uint8_t* buf, size_t buflen);
+
+/**
+ * @brief Writes a whole buffer to a stream
+ *
+ * @return int64_t Number of bytes written, or an error code
+ */
+int64_t geph5_stream_write(int64_t handle, const uint8_t* buf, size_t len);
+
+/**
+ * @brief Closes a stream, invalidating its handle
+ *
+ * @return int GEPH5_OK, or GEPH5_ERR_NO_SUCH_STREAM
+ */
+int geph5_stream_close(int64_t handle);
+
+/*
+ * Original interface, kept for existing embedders.
+ */
+
 /**
  * @brief Starts the Geph5 client with the provided configuration
  * 
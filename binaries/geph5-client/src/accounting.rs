@@ -0,0 +1,80 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use anyctx::AnyCtx;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{client::CtxField, Config};
+
+/// Upper bound on how many distinct hosts we keep counters for. Traffic to hosts beyond this is lumped together.
+const MAX_HOSTS: usize = 10000;
+
+/// The name under which traffic to hosts beyond [MAX_HOSTS] is counted.
+const OVERFLOW_HOST: &str = "(other)";
+
+/// How much traffic went through the tunnel to one host.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DomainUsage {
+    pub host: String,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+#[derive(Default)]
+pub struct DomainCounter {
+    rx_bytes: AtomicU64,
+    tx_bytes: AtomicU64,
+}
+
+impl DomainCounter {
+    pub fn incr_rx(&self, n: usize) {
+        self.rx_bytes.fetch_add(n as _, Ordering::Relaxed);
+    }
+
+    pub fn incr_tx(&self, n: usize) {
+        self.tx_bytes.fetch_add(n as _, Ordering::Relaxed);
+    }
+}
+
+static DOMAIN_COUNTERS: CtxField<DashMap<String, Arc<DomainCounter>>> = |_| DashMap::new();
+
+/// Gets the counter for the host part of a "host:port" destination, unless accounting is turned off.
+pub fn domain_counter(ctx: &AnyCtx<Config>, dest: &str) -> Option<Arc<DomainCounter>> {
+    if ctx.init().disable_domain_accounting {
+        return None;
+    }
+    let host = dest
+        .rsplit_once(':')
+        .map(|(host, _)| host)
+        .unwrap_or(dest)
+        .to_lowercase();
+    let counters = ctx.get(DOMAIN_COUNTERS);
+    if let Some(counter) = counters.get(&host) {
+        return Some(counter.clone());
+    }
+    let host = if counters.len() >= MAX_HOSTS {
+        OVERFLOW_HOST.to_string()
+    } else {
+        host
+    };
+    Some(counters.entry(host).or_default().clone())
+}
+
+/// The hosts with the most total traffic, in descending order.
+pub fn top_domains(ctx: &AnyCtx<Config>, limit: usize) -> Vec<DomainUsage> {
+    let mut usage: Vec<DomainUsage> = ctx
+        .get(DOMAIN_COUNTERS)
+        .iter()
+        .map(|entry| DomainUsage {
+            host: entry.key().clone(),
+            rx_bytes: entry.rx_bytes.load(Ordering::Relaxed),
+            tx_bytes: entry.tx_bytes.load(Ordering::Relaxed),
+        })
+        .collect();
+    usage.sort_by_key(|usage| std::cmp::Reverse(usage.rx_bytes + usage.tx_bytes));
+    usage.truncate(limit);
+    usage
+}
@@ -0,0 +1,186 @@
+//! JNI bindings for the Android app, matching the natives declared in `bindings/android/io/geph/geph5/Geph5Client.kt`.
+//!
+//! These sit on top of the C interface, so the client they start is the same one it reaches. In VPN mode, the app hands over the TUN device that `VpnService.Builder.establish()` gives it, detached from its `ParcelFileDescriptor`, and should exclude itself from the VPN with `addDisallowedApplication`, so that the tunnel's own connections do not loop back into it.
+
+use std::{
+    ffi::{c_char, c_void, CStr},
+    sync::OnceLock,
+};
+
+use jni::{
+    objects::{GlobalRef, JClass, JObject, JString, JValue},
+    sys::{jdouble, jint, jstring},
+    JNIEnv, JavaVM,
+};
+use nanorpc::{JrpcRequest, RpcTransport};
+use parking_lot::Mutex;
+
+use crate::{
+    ffi::{self, GEPH5_ERR_INVALID_ARGUMENT, GEPH5_ERR_NOT_RUNNING, GEPH5_OK},
+    Config,
+};
+
+static JAVA_VM: OnceLock<JavaVM> = OnceLock::new();
+
+/// The `Geph5Client.EventListener` told about connection info changes.
+static LISTENER: Mutex<Option<GlobalRef>> = Mutex::new(None);
+
+/// Starts the client with a config, as JSON, and the file descriptor of the TUN device for VPN mode, or -1 to run without one.
+#[no_mangle]
+pub extern "system" fn Java_io_geph_geph5_Geph5Client_start<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    config_json: JString<'local>,
+    vpn_fd: jint,
+) -> jint {
+    let Some(mut config) = parse_config(&mut env, &config_json) else {
+        return GEPH5_ERR_INVALID_ARGUMENT;
+    };
+    if vpn_fd >= 0 {
+        config.vpn_fd = Some(vpn_fd);
+    }
+    ffi::start(config)
+}
+
+/// Stops the client, tearing down VPN mode.
+#[no_mangle]
+pub extern "system" fn Java_io_geph_geph5_Geph5Client_stop<'local>(
+    _env: JNIEnv<'local>,
+    _class: JClass<'local>,
+) -> jint {
+    ffi::geph5_stop()
+}
+
+/// Applies a changed config, as JSON, to the running client.
+#[no_mangle]
+pub extern "system" fn Java_io_geph_geph5_Geph5Client_setConfig<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    config_json: JString<'local>,
+) -> jint {
+    let Some(config) = parse_config(&mut env, &config_json) else {
+        return GEPH5_ERR_INVALID_ARGUMENT;
+    };
+    let Some(client) = ffi::running_client() else {
+        return GEPH5_ERR_NOT_RUNNING;
+    };
+    client.reload_config(config);
+    GEPH5_OK
+}
+
+/// Gets the connection info as JSON, or null if the client is not running.
+#[no_mangle]
+pub extern "system" fn Java_io_geph_geph5_Geph5Client_connInfo<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+) -> jstring {
+    let info = ffi::running_client()
+        .and_then(|client| smolscale::block_on(client.control_client().conn_info()).ok());
+    match info {
+        Some(info) => to_jstring(&mut env, &serde_json::to_string(&info).unwrap()),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Gets a numeric stat, like "total_rx_bytes", or NaN if the client is not running.
+#[no_mangle]
+pub extern "system" fn Java_io_geph_geph5_Geph5Client_statNum<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    stat: JString<'local>,
+) -> jdouble {
+    let Ok(stat) = env.get_string(&stat).map(String::from) else {
+        return f64::NAN;
+    };
+    ffi::running_client()
+        .and_then(|client| smolscale::block_on(client.control_client().stat_num(stat)).ok())
+        .unwrap_or(f64::NAN)
+}
+
+/// Makes a JSON-RPC call to the control protocol, for everything without its own binding, like stat histories. Returns the JSON-RPC response, or null if the client is not running or the request is not valid.
+#[no_mangle]
+pub extern "system" fn Java_io_geph_geph5_Geph5Client_rpc<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    request_json: JString<'local>,
+) -> jstring {
+    let response = (|| {
+        let request: String = env.get_string(&request_json).ok()?.into();
+        let request: JrpcRequest = serde_json::from_str(&request).ok()?;
+        let client = ffi::running_client()?;
+        let response = smolscale::block_on(client.control_client().0.call_raw(request)).ok()?;
+        serde_json::to_string(&response).ok()
+    })();
+    match response {
+        Some(response) => to_jstring(&mut env, &response),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Sets the listener told about connection info changes, replacing any earlier one, or removes it if the listener is null.
+#[no_mangle]
+pub extern "system" fn Java_io_geph_geph5_Geph5Client_setEventListener<'local>(
+    env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    listener: JObject<'local>,
+) -> jint {
+    if listener.is_null() {
+        *LISTENER.lock() = None;
+        return ffi::geph5_set_event_callback(None, std::ptr::null_mut());
+    }
+    let (Ok(vm), Ok(listener)) = (env.get_java_vm(), env.new_global_ref(&listener)) else {
+        return GEPH5_ERR_INVALID_ARGUMENT;
+    };
+    let _ = JAVA_VM.set(vm);
+    *LISTENER.lock() = Some(listener);
+    ffi::geph5_set_event_callback(Some(on_conn_info), std::ptr::null_mut())
+}
+
+unsafe extern "C" fn on_conn_info(conn_info_json: *const c_char, _userdata: *mut c_void) {
+    // cloned out, so that the listener can replace itself
+    let Some(listener) = LISTENER.lock().clone() else {
+        return;
+    };
+    let Some(vm) = JAVA_VM.get() else {
+        return;
+    };
+    // the client's threads live as long as the process, so they stay attached
+    let Ok(mut env) = vm.attach_current_thread_as_daemon() else {
+        tracing::warn!("could not attach to the JVM to deliver an event");
+        return;
+    };
+    let json = CStr::from_ptr(conn_info_json).to_string_lossy();
+    // the thread never returns to Java, so local references have to be freed here, or they pile up until the table overflows
+    let delivered = env.with_local_frame(4, |env| -> jni::errors::Result<()> {
+        let json = env.new_string(&*json)?;
+        env.call_method(
+            &listener,
+            "onConnInfo",
+            "(Ljava/lang/String;)V",
+            &[JValue::Object(&json)],
+        )?;
+        Ok(())
+    });
+    if delivered.is_err() {
+        let _ = env.exception_describe();
+        let _ = env.exception_clear();
+        tracing::warn!("could not deliver an event to the listener");
+    }
+}
+
+fn parse_config(env: &mut JNIEnv, config_json: &JString) -> Option<Config> {
+    let config_json: String = env.get_string(config_json).ok()?.into();
+    match serde_json::from_str(&config_json) {
+        Ok(config) => Some(config),
+        Err(err) => {
+            tracing::warn!(err = debug(err), "Android app passed an invalid config");
+            None
+        }
+    }
+}
+
+fn to_jstring(env: &mut JNIEnv, s: &str) -> jstring {
+    env.new_string(s)
+        .map(JString::into_raw)
+        .unwrap_or(std::ptr::null_mut())
+}
@@ -0,0 +1,111 @@
+use std::{
+    collections::BTreeSet,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyctx::AnyCtx;
+use anyhow::Context;
+use geph5_broker_protocol::{Announcement, AnnouncementList, DOMAIN_ANNOUNCEMENT};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    broker::broker_client,
+    database::{db_read, db_write},
+    Config,
+};
+
+/// How often we check the broker for new announcements.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1800);
+
+/// How soon we try again after failing to reach the broker.
+const RETRY_INTERVAL: Duration = Duration::from_secs(300);
+
+/// An announcement, along with whether the user has read it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AnnouncementInfo {
+    #[serde(flatten)]
+    pub announcement: Announcement,
+    pub read: bool,
+}
+
+/// Periodically fetches the signed announcements from the broker and stores them, so that they can be shown even when we cannot reach the broker.
+pub async fn announcements_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    if ctx.init().broker.is_none() {
+        return smol::future::pending().await;
+    }
+    loop {
+        match fetch_announcements(ctx).await {
+            Ok(count) => {
+                tracing::debug!(count, "refreshed announcements");
+                smol::Timer::after(REFRESH_INTERVAL).await;
+            }
+            Err(err) => {
+                tracing::warn!(err = debug(err), "failed to refresh announcements");
+                smol::Timer::after(RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+async fn fetch_announcements(ctx: &AnyCtx<Config>) -> anyhow::Result<usize> {
+    let signed = broker_client(ctx)?
+        .get_announcements()
+        .await?
+        .map_err(|e| anyhow::anyhow!("broker refused to serve announcements: {e}"))?;
+    let list = signed
+        .verify(DOMAIN_ANNOUNCEMENT, |their_pk| {
+            if let Some(broker_pk) = &ctx.init().broker_keys {
+                hex::encode(their_pk.as_bytes()) == broker_pk.master
+            } else {
+                true
+            }
+        })
+        .context("could not verify announcements")?;
+    db_write(ctx, "announcements", &serde_json::to_vec(&list)?).await?;
+    // forget the read state of announcements that are gone, so that it does not grow forever
+    let mut read = read_ids(ctx).await?;
+    read.retain(|id| list.announcements.iter().any(|a| &a.id == id));
+    db_write(ctx, "announcements_read", &serde_json::to_vec(&read)?).await?;
+    Ok(list.announcements.len())
+}
+
+async fn read_ids(ctx: &AnyCtx<Config>) -> anyhow::Result<BTreeSet<String>> {
+    Ok(match db_read(ctx, "announcements_read").await? {
+        Some(raw) => serde_json::from_slice(&raw)?,
+        None => BTreeSet::new(),
+    })
+}
+
+/// Lists the stored announcements that have not expired and are for the country the broker saw us in, newest first.
+pub async fn list_announcements(ctx: &AnyCtx<Config>) -> anyhow::Result<Vec<AnnouncementInfo>> {
+    let list: Option<AnnouncementList> = match db_read(ctx, "announcements").await? {
+        // a list stored in an older format does not parse, and is replaced on the next refresh
+        Some(raw) => serde_json::from_slice(&raw).ok(),
+        None => None,
+    };
+    let Some(list) = list else {
+        return Ok(vec![]);
+    };
+    let read = read_ids(ctx).await?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let country = list.requester_country.as_deref();
+    let mut out: Vec<AnnouncementInfo> = list
+        .announcements
+        .into_iter()
+        .filter(|a| a.expiry_unix > now && a.relevant_to(country))
+        .map(|announcement| AnnouncementInfo {
+            read: read.contains(&announcement.id),
+            announcement,
+        })
+        .collect();
+    out.sort_by_key(|a| std::cmp::Reverse(a.announcement.created_unix));
+    Ok(out)
+}
+
+/// Marks an announcement as read.
+pub async fn mark_announcement_read(ctx: &AnyCtx<Config>, id: String) -> anyhow::Result<()> {
+    let mut read = read_ids(ctx).await?;
+    read.insert(id);
+    db_write(ctx, "announcements_read", &serde_json::to_vec(&read)?).await?;
+    Ok(())
+}
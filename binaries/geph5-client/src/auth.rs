@@ -10,31 +10,46 @@ use anyctx::AnyCtx;
 use anyhow::Context as _;
 use blind_rsa_signatures as brs;
 use futures_intrusive::sync::ManualResetEvent;
-use geph5_broker_protocol::{AccountLevel, AuthError};
-use mizaru2::{ClientToken, UnblindedSignature};
+use geph5_broker_protocol::{auth_scheme, AccountLevel, AuthError};
+use geph5_misc_rpc::exit::ConnectCredential;
+use mizaru2::ClientToken;
 use rand::Rng;
 use smol_timeout2::TimeoutExt;
 use stdcode::StdcodeSerializeExt;
 
 use crate::{
-    broker::broker_client,
+    broker::{broker_capabilities, broker_client},
     client::Config,
     database::{db_read, db_read_or_wait, db_remove, db_write},
+    events::{push_event, ConnEvent},
 };
 
 static ACCOUNT_STATUS_CHECKED: LazyLock<ManualResetEvent> =
     LazyLock::new(|| ManualResetEvent::new(false));
 
-pub async fn get_connect_token(
-    ctx: &AnyCtx<Config>,
-) -> anyhow::Result<(AccountLevel, ClientToken, UnblindedSignature)> {
-    tracing::debug!("waiting for connection token");
+/// The kinds of blindly signed tokens we keep for every epoch. Exits and the broker each get their own, so that the broker, which sees where our bridge requests come from, can't recognize our sessions at exits.
+const CONN_TOKEN: &str = "conn_token";
+const BROKER_TOKEN: &str = "broker_token";
+
+/// Gets the credential we present to exits.
+pub async fn get_connect_token(ctx: &AnyCtx<Config>) -> anyhow::Result<ConnectCredential> {
+    get_token(ctx, CONN_TOKEN).await
+}
+
+/// Gets the credential we present to the broker when asking for bridges.
+pub async fn get_broker_token(ctx: &AnyCtx<Config>) -> anyhow::Result<ConnectCredential> {
+    get_token(ctx, BROKER_TOKEN).await
+}
+
+async fn get_token(ctx: &AnyCtx<Config>, kind: &str) -> anyhow::Result<ConnectCredential> {
+    tracing::debug!(kind, "waiting for connection token");
     let start = Instant::now();
     ACCOUNT_STATUS_CHECKED.wait().await;
     tracing::debug!(elapsed = debug(start.elapsed()), "account status checked");
     let epoch = mizaru2::current_epoch();
-    let res = get_conn_token_inner(ctx, epoch, true).await?;
+    let res = get_conn_token_inner(ctx, kind, epoch, true).await?;
     tracing::debug!(
+        kind,
         elapsed = debug(start.elapsed()),
         "connection token obtained"
     );
@@ -43,18 +58,19 @@ pub async fn get_connect_token(
 
 async fn get_conn_token_inner(
     ctx: &AnyCtx<Config>,
+    kind: &str,
     epoch: u16,
     wait: bool,
-) -> anyhow::Result<(AccountLevel, ClientToken, UnblindedSignature)> {
+) -> anyhow::Result<ConnectCredential> {
     if !wait {
         Ok(stdcode::deserialize(
-            &db_read(ctx, &format!("conn_token_{epoch}"))
+            &db_read(ctx, &format!("{kind}_{epoch}"))
                 .await?
                 .context("absent right now")?,
         )?)
     } else {
         Ok(stdcode::deserialize(
-            &db_read_or_wait(ctx, &format!("conn_token_{epoch}")).await?,
+            &db_read_or_wait(ctx, &format!("{kind}_{epoch}")).await?,
         )?)
     }
 }
@@ -64,8 +80,15 @@ pub async fn get_auth_token(ctx: &AnyCtx<Config>) -> anyhow::Result<String> {
         Ok(String::from_utf8_lossy(&token).to_string())
     } else {
         tracing::debug!("obtaining auth token");
+        let credentials = &ctx.init().credentials;
+        if !broker_capabilities(ctx).await.accepts(credentials) {
+            anyhow::bail!(
+                "the broker does not accept {} credentials",
+                auth_scheme(credentials)
+            )
+        }
         let auth_token = broker_client(ctx)?
-            .get_auth_token(ctx.init().credentials.clone())
+            .get_auth_token(credentials.clone())
             .await??;
         db_write(ctx, "auth_token", auth_token.as_bytes()).await?;
         Ok(auth_token)
@@ -80,7 +103,13 @@ pub async fn auth_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
     let auth_token = get_auth_token(ctx).await?;
     loop {
         if let Err(err) = refresh_conn_token(ctx, &auth_token).await {
-            tracing::warn!(err = debug(err), "failed to refresh conn token");
+            tracing::warn!(err = debug(&err), "failed to refresh conn token");
+            push_event(
+                ctx,
+                ConnEvent::AuthError {
+                    reason: format!("{err:#}"),
+                },
+            );
             smol::Timer::after(Duration::from_secs(10)).await;
         } else {
             let sleep_secs = rand::thread_rng().gen_range(400..800);
@@ -102,29 +131,32 @@ async fn refresh_conn_token(ctx: &AnyCtx<Config>, auth_token: &str) -> anyhow::R
             .plus_expires_unix
             .unwrap_or_default();
 
-        let currently_plus =
-            if let Ok(inner) = get_conn_token_inner(ctx, mizaru2::current_epoch(), false).await {
-                inner.0 == AccountLevel::Plus
-            } else {
-                false
-            };
+        let currently_plus = if let Ok(inner) =
+            get_conn_token_inner(ctx, CONN_TOKEN, mizaru2::current_epoch(), false).await
+        {
+            inner.level == AccountLevel::Plus
+        } else {
+            false
+        };
 
         if plus_expiry > 0 && !currently_plus {
             tracing::debug!("we gained a plus! gonna clean up the conn token cache here");
-            db_remove(ctx, &format!("conn_token_{}", epoch)).await?;
-            db_remove(ctx, &format!("conn_token_{}", epoch + 1)).await?;
+            for kind in [CONN_TOKEN, BROKER_TOKEN] {
+                db_remove(ctx, &format!("{kind}_{}", epoch)).await?;
+                db_remove(ctx, &format!("{kind}_{}", epoch + 1)).await?;
+            }
         }
 
         ACCOUNT_STATUS_CHECKED.set();
 
-        for epoch in [epoch, epoch + 1] {
-            if db_read(ctx, &format!("conn_token_{epoch}"))
-                .await?
-                .is_none()
-            {
+        for (kind, epoch) in [CONN_TOKEN, BROKER_TOKEN]
+            .into_iter()
+            .flat_map(|kind| [(kind, epoch), (kind, epoch + 1)])
+        {
+            if db_read(ctx, &format!("{kind}_{epoch}")).await?.is_none() {
                 let token = ClientToken::random();
                 for level in [AccountLevel::Plus, AccountLevel::Free] {
-                    tracing::debug!(epoch, level = debug(level), "refreshing conn token");
+                    tracing::debug!(kind, epoch, level = debug(level), "refreshing conn token");
                     let subkey = broker_client
                         .get_mizaru_subkey(level, epoch)
                         .await
@@ -168,8 +200,13 @@ async fn refresh_conn_token(ctx: &AnyCtx<Config>, auth_token: &str) -> anyhow::R
                                 .context("cannot unblind response")?;
                             db_write(
                                 ctx,
-                                &format!("conn_token_{epoch}"),
-                                &(level, token, u_sig).stdcode(),
+                                &format!("{kind}_{epoch}"),
+                                &ConnectCredential {
+                                    level,
+                                    token,
+                                    sig: u_sig,
+                                }
+                                .stdcode(),
                             )
                             .await?;
                             break;
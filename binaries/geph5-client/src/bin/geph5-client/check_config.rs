@@ -0,0 +1,102 @@
+use std::path::Path;
+
+use geph5_client::{Config, ConfigProblem, Severity};
+
+use crate::read_config;
+
+/// Checks a config file, along with every profile in it, printing each problem found with the line it is on. Fails if any of them would keep the client from working.
+pub fn check_config(path: &Path, profile: Option<&str>, sets: &[String]) -> anyhow::Result<()> {
+    let text = std::fs::read_to_string(path)?;
+    let value: serde_json::Value = match serde_yaml::from_str(&text) {
+        Ok(value) => value,
+        Err(err) => {
+            let line = err.location().map(|location| location.line());
+            print_problem(path, line, Severity::Error, None, &err.to_string());
+            anyhow::bail!("the config is not valid YAML");
+        }
+    };
+    if let Err(err) = serde_path_to_error::deserialize::<_, Config>(value) {
+        let field = err.path().to_string();
+        let setting = field.split(['.', '[']).next().unwrap_or_default();
+        print_problem(
+            path,
+            setting_line(&text, setting),
+            Severity::Error,
+            None,
+            &format!("{field}: {}", err.inner()),
+        );
+        anyhow::bail!("the config could not be parsed");
+    }
+
+    let config = read_config(path, profile, sets)?;
+    let mut problems: Vec<(Option<String>, ConfigProblem)> =
+        config.check().into_iter().map(|p| (None, p)).collect();
+    for name in config.profiles.keys() {
+        // profiles that cannot be applied at all were already reported above
+        let Ok(effective) = config.with_profile(Some(name)) else {
+            continue;
+        };
+        for problem in effective.check() {
+            let seen = problems.iter().any(|(_, other)| {
+                other.setting == problem.setting && other.message == problem.message
+            });
+            if !seen {
+                problems.push((Some(name.clone()), problem));
+            }
+        }
+    }
+
+    for (profile, problem) in problems.iter() {
+        let line = if profile.is_none() {
+            setting_line(&text, &problem.setting)
+        } else {
+            None
+        };
+        print_problem(
+            path,
+            line,
+            problem.severity,
+            profile.as_deref(),
+            &format!("{}: {}", problem.setting, problem.message),
+        );
+    }
+    let errors = problems
+        .iter()
+        .filter(|(_, problem)| problem.severity == Severity::Error)
+        .count();
+    anyhow::ensure!(errors == 0, "found {errors} errors in the config");
+    println!("{} is OK, with {} warnings", path.display(), problems.len());
+    Ok(())
+}
+
+fn print_problem(
+    path: &Path,
+    line: Option<usize>,
+    severity: Severity,
+    profile: Option<&str>,
+    message: &str,
+) {
+    let location = match line {
+        Some(line) => format!("{}:{line}", path.display()),
+        None => path.display().to_string(),
+    };
+    let severity = match severity {
+        Severity::Error => "error",
+        Severity::Warning => "warning",
+    };
+    match profile {
+        Some(profile) => eprintln!("{location}: {severity} in profile {profile:?}: {message}"),
+        None => eprintln!("{location}: {severity}: {message}"),
+    }
+}
+
+/// Finds the line, counting from 1, where a top-level setting is in the config file.
+fn setting_line(text: &str, setting: &str) -> Option<usize> {
+    if setting.is_empty() {
+        return None;
+    }
+    let key = format!("{setting}:");
+    text.lines()
+        .position(|line| line.starts_with(&key))
+        .map(|index| index + 1)
+}
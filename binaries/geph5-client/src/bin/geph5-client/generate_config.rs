@@ -0,0 +1,208 @@
+use std::{
+    io::{stderr, stdin, Write},
+    net::SocketAddr,
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::Context;
+use geph5_client::{BrokerKeys, BrokerSource, Client, Config, ExitConstraint, Severity};
+use isocountry::CountryCode;
+use serde_json::json;
+
+/// Options for a generated config. Anything not given is prompted for, unless running non-interactively, in which case the defaults are used.
+#[derive(clap::Args)]
+pub struct GenerateArgs {
+    /// The account secret. Leave it out to be offered to register a new account
+    #[arg(long)]
+    secret: Option<String>,
+
+    /// Which exit to use: "auto", a two-letter country code, or an exit's hostname
+    #[arg(long)]
+    exit: Option<String>,
+
+    /// Where to listen for SOCKS5 proxy connections
+    #[arg(long)]
+    socks5_listen: Option<SocketAddr>,
+
+    /// Where to listen for HTTP proxy connections
+    #[arg(long)]
+    http_proxy_listen: Option<SocketAddr>,
+
+    /// Whether to tunnel all traffic in VPN mode, which needs root
+    #[arg(long)]
+    vpn: Option<bool>,
+
+    /// Use the defaults for anything not given instead of prompting
+    #[arg(long)]
+    non_interactive: bool,
+
+    /// Overwrite the config file if it already exists
+    #[arg(long)]
+    force: bool,
+}
+
+const DEFAULT_SOCKS5_LISTEN: &str = "127.0.0.1:9909";
+const DEFAULT_HTTP_PROXY_LISTEN: &str = "127.0.0.1:9910";
+const DEFAULT_CONTROL_LISTEN: &str = "127.0.0.1:12222";
+
+/// Writes a starter config to the given path, asking for whatever the arguments leave out.
+pub fn generate_config(path: &Path, args: GenerateArgs) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        args.force || !path.exists(),
+        "{} already exists; pass --force to overwrite it",
+        path.display()
+    );
+    let interactive = !args.non_interactive;
+    let ask = |question: &str, default: &str| -> anyhow::Result<String> {
+        if !interactive {
+            return Ok(default.to_string());
+        }
+        prompt(question, default)
+    };
+
+    let exit = match args.exit {
+        Some(exit) => exit,
+        None => ask(
+            "Which exit? \"auto\", a country code like \"CA\", or an exit hostname",
+            "auto",
+        )?,
+    };
+    let exit_constraint = parse_exit(&exit)?;
+    let socks5_listen: SocketAddr = match args.socks5_listen {
+        Some(listen) => listen,
+        None => ask("SOCKS5 proxy listen address", DEFAULT_SOCKS5_LISTEN)?
+            .parse()
+            .context("not a valid address")?,
+    };
+    let http_proxy_listen: SocketAddr = match args.http_proxy_listen {
+        Some(listen) => listen,
+        None => ask("HTTP proxy listen address", DEFAULT_HTTP_PROXY_LISTEN)?
+            .parse()
+            .context("not a valid address")?,
+    };
+    let vpn = match args.vpn {
+        Some(vpn) => vpn,
+        None => parse_yes_no(&ask(
+            "Tunnel all traffic in VPN mode? This needs root (y/n)",
+            "n",
+        )?)?,
+    };
+
+    let broker = BrokerSource::Race(vec![
+        BrokerSource::Fronted {
+            front: "https://www.cdn77.com/".into(),
+            host: "1826209743.rsc.cdn77.org".into(),
+        },
+        BrokerSource::Fronted {
+            front: "https://vuejs.org/".into(),
+            host: "svitania-naidallszei-2.netlify.app".into(),
+        },
+    ]);
+    let broker_keys = BrokerKeys {
+        master: "88c1d2d4197bed815b01a22cadfc6c35aa246dddb553682037a118aebfaa3954".into(),
+        mizaru_free: "0558216cbab7a9c46f298f4c26e171add9af87d0694988b8a8fe52ee932aa754".into(),
+        mizaru_plus: "cf6f58868c6d9459b3a63bc2bd86165631b3e916bad7f62b578cd9614e0bcb3b".into(),
+    };
+    let mut config = json!({
+        "socks5_listen": socks5_listen,
+        "http_proxy_listen": http_proxy_listen,
+        "control_listen": DEFAULT_CONTROL_LISTEN,
+        "exit_constraint": exit_constraint,
+        "vpn": vpn,
+        "broker": broker,
+        "broker_keys": broker_keys,
+    });
+
+    let secret = match args.secret {
+        Some(secret) => secret,
+        None => {
+            let secret = ask(
+                "Account secret, or leave it empty to register a new account",
+                "",
+            )?;
+            if secret.is_empty() {
+                let config: Config = serde_json::from_value(config.clone())?;
+                register(config)?
+            } else {
+                secret
+            }
+        }
+    };
+    config["credentials"] = json!({ "secret": secret });
+
+    let parsed: Config =
+        serde_json::from_value(config.clone()).context("generated an invalid config")?;
+    for problem in parsed.check() {
+        let severity = match problem.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        eprintln!("{severity}: {}: {}", problem.setting, problem.message);
+    }
+    std::fs::write(path, serde_yaml::to_string(&config)?)?;
+    eprintln!("config written to {}", path.display());
+    Ok(())
+}
+
+fn prompt(question: &str, default: &str) -> anyhow::Result<String> {
+    if default.is_empty() {
+        eprint!("{question}: ");
+    } else {
+        eprint!("{question} [{default}]: ");
+    }
+    stderr().flush()?;
+    let mut line = String::new();
+    stdin().read_line(&mut line)?;
+    let line = line.trim();
+    Ok(if line.is_empty() {
+        default.to_string()
+    } else {
+        line.to_string()
+    })
+}
+
+fn parse_yes_no(answer: &str) -> anyhow::Result<bool> {
+    match answer.to_lowercase().as_str() {
+        "y" | "yes" | "true" => Ok(true),
+        "n" | "no" | "false" => Ok(false),
+        other => anyhow::bail!("expected yes or no, got {other:?}"),
+    }
+}
+
+fn parse_exit(exit: &str) -> anyhow::Result<ExitConstraint> {
+    if exit.eq_ignore_ascii_case("auto") {
+        Ok(ExitConstraint::Auto)
+    } else if exit.len() == 2 {
+        let country = CountryCode::for_alpha2_caseless(exit)
+            .map_err(|_| anyhow::anyhow!("{exit:?} is not a country code"))?;
+        Ok(ExitConstraint::Country(country))
+    } else {
+        Ok(ExitConstraint::Hostname(exit.to_string()))
+    }
+}
+
+/// Registers a new account through the broker, showing progress on the proof of work it takes.
+fn register(config: Config) -> anyhow::Result<String> {
+    eprintln!("registering a new account, which takes a minute or two...");
+    let client = Client::start(config.inert());
+    let control = client.control_client();
+    smolscale::block_on(async move {
+        let idx = control
+            .start_registration()
+            .await?
+            .map_err(|e| anyhow::anyhow!(e))?;
+        loop {
+            let progress = control
+                .poll_registration(idx)
+                .await?
+                .map_err(|e| anyhow::anyhow!(e))?;
+            if let Some(secret) = progress.secret {
+                eprintln!("\rregistered! keep your account secret safe: {secret}");
+                return Ok(secret);
+            }
+            eprint!("\r{:.0}% done", progress.progress * 100.0);
+            smol::Timer::after(Duration::from_secs(1)).await;
+        }
+    })
+}
@@ -0,0 +1,367 @@
+use std::{
+    io::{self, stdin, stdout, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use bytes::Bytes;
+use check_config::check_config;
+use clap::{Parser, Subcommand};
+use generate_config::{generate_config, GenerateArgs};
+use geph5_client::{logging, resolve_secrets, Client, Config, ControlClient};
+use nanorpc::{JrpcRequest, RpcTransport};
+use nanorpc_sillad::DialerTransport;
+use overrides::apply_overrides;
+use sillad::tcp::TcpDialer;
+use smol::future::FutureExt as _;
+
+mod check_config;
+mod generate_config;
+mod overrides;
+#[cfg(windows)]
+mod service;
+mod systemd;
+
+/// Run the Geph5 client.
+#[derive(Parser)]
+struct CliArgs {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// path to a YAML-based config file, which is reloaded when it changes or on SIGHUP
+    #[arg(short, long)]
+    config: PathBuf,
+
+    /// Start with this profile, defined either in the config file or as a YAML file in the `profiles` directory next to it
+    #[arg(short, long)]
+    profile: Option<String>,
+
+    /// Override a config setting, like `--set socks5_listen=127.0.0.1:9909` or `--set kcp.nodelay=true`. These take precedence over GEPH5_-prefixed environment variables, which in turn take precedence over the config file
+    #[arg(long, value_name = "KEY=VALUE")]
+    set: Vec<String>,
+
+    #[arg(short, long)]
+    /// do RPC on the stdio
+    stdio_rpc: bool,
+
+    #[arg(long)]
+    /// Use stdin/stdout as a VPN interface with 16-bit big-endian length prefixes
+    stdio_vpn: bool,
+
+    #[arg(long)]
+    /// Write a debug bundle from the client already running with this config (through its control_listen) to the given path, then exit
+    debug_bundle: Option<PathBuf>,
+
+    #[arg(long)]
+    /// Run as a Windows service. The service control manager starts the client this way once it is installed with the `service install` subcommand
+    service: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Check the config for mistakes, printing each one with the line it is on, then exit
+    CheckConfig,
+    /// Write a starter config to the config path, asking for the account secret, exit, listeners, and VPN mode, then exit
+    GenerateConfig(GenerateArgs),
+    /// Manage the Windows service that runs the client at boot, without anyone logged in
+    Service {
+        #[command(subcommand)]
+        action: ServiceAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum ServiceAction {
+    /// Register the client as a service that starts at boot with this config, profile, and overrides
+    Install,
+    /// Stop and remove the service
+    Uninstall,
+    /// Start the service
+    Start,
+    /// Stop the service, tearing down VPN mode
+    Stop,
+}
+
+fn main() -> anyhow::Result<()> {
+    smolscale::permanently_single_threaded();
+    // Initialize logging with JSON support
+    logging::init_logging()?;
+
+    let args = CliArgs::parse();
+    match args.command {
+        Some(Command::CheckConfig) => {
+            return check_config(&args.config, args.profile.as_deref(), &args.set)
+        }
+        Some(Command::GenerateConfig(generate_args)) => {
+            return generate_config(&args.config, generate_args)
+        }
+        Some(Command::Service { action }) => {
+            return service_command(action, &args.config, args.profile.as_deref(), &args.set)
+        }
+        None => {}
+    }
+    if args.service {
+        #[cfg(windows)]
+        {
+            let (path, profile, sets) = (args.config, args.profile, args.set);
+            return service::run_as_service(move |stop| {
+                run_service(&path, profile.as_deref(), &sets, stop)
+            });
+        }
+        #[cfg(not(windows))]
+        anyhow::bail!("--service is only supported on Windows");
+    }
+    let config = read_config(&args.config, args.profile.as_deref(), &args.set)?;
+    config.with_profile(config.profile.as_deref())?;
+    for problem in config.check() {
+        tracing::warn!(
+            setting = problem.setting,
+            severity = debug(problem.severity),
+            "{}",
+            problem.message
+        );
+    }
+
+    if let Some(path) = args.debug_bundle {
+        let control_listen = config
+            .control_listen
+            .context("the config does not have a control_listen address")?;
+        let control = ControlClient(DialerTransport(TcpDialer {
+            dest_addr: control_listen,
+        }));
+        let bundle =
+            smolscale::block_on(control.debug_bundle())?.map_err(|e| anyhow::anyhow!(e))?;
+        std::fs::write(&path, bundle)?;
+        eprintln!("debug bundle written to {}", path.display());
+        return Ok(());
+    }
+
+    geph5_client::inherit_tcp_listeners(systemd::take_listen_fds());
+    let client = Client::start(config);
+
+    if args.stdio_rpc {
+        let rpc = Arc::new(client.control_client().0);
+        std::thread::spawn(move || {
+            let stdin = stdin();
+            for line in stdin.lines() {
+                let line = line.unwrap();
+                let line: JrpcRequest = serde_json::from_str(&line).unwrap();
+                let rpc = rpc.clone();
+                let resp = smolscale::block_on(async move { rpc.call_raw(line).await }).unwrap();
+                println!("{}", serde_json::to_string(&resp).unwrap());
+            }
+        });
+    } else if args.stdio_vpn {
+        // Run the stdio VPN interface
+        run_stdio_vpn(client.clone())?;
+    }
+
+    smolscale::block_on(
+        client
+            .clone()
+            .wait_until_dead()
+            .race(reload_loop(
+                &client,
+                &args.config,
+                args.profile.as_deref(),
+                &args.set,
+            ))
+            .race(systemd::notify_loop(&client)),
+    )?;
+    Ok(())
+}
+
+#[cfg(windows)]
+fn service_command(
+    action: ServiceAction,
+    path: &Path,
+    profile: Option<&str>,
+    sets: &[String],
+) -> anyhow::Result<()> {
+    match action {
+        ServiceAction::Install => service::install(path, profile, sets),
+        ServiceAction::Uninstall => service::uninstall(),
+        ServiceAction::Start => service::start(),
+        ServiceAction::Stop => service::stop(),
+    }
+}
+
+#[cfg(not(windows))]
+fn service_command(
+    _action: ServiceAction,
+    _path: &Path,
+    _profile: Option<&str>,
+    _sets: &[String],
+) -> anyhow::Result<()> {
+    anyhow::bail!(
+        "services are only supported on Windows; elsewhere, use the init system, such as systemd"
+    )
+}
+
+/// Runs the client as a Windows service until the service control manager asks it to stop, turning VPN mode off first so that its packet capture does not outlive the client.
+#[cfg(windows)]
+fn run_service(
+    path: &Path,
+    profile: Option<&str>,
+    sets: &[String],
+    stop: smol::channel::Receiver<()>,
+) -> anyhow::Result<()> {
+    let config = read_config(path, profile, sets)?;
+    let client = Client::start(config);
+    let stopped = async {
+        let _ = stop.recv().await;
+        tracing::info!("stopping the service");
+        client.control_client().set_vpn(false).await?;
+        // give the packet capture a moment to shut down before the client goes away
+        smol::Timer::after(Duration::from_secs(1)).await;
+        anyhow::Ok(())
+    };
+    smolscale::block_on(
+        client
+            .clone()
+            .wait_until_dead()
+            .race(reload_loop(&client, path, profile, sets))
+            .race(stopped),
+    )
+}
+
+/// How often to check whether the config file changed.
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+static SIGHUP_RECEIVED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn on_sighup(_: libc::c_int) {
+    SIGHUP_RECEIVED.store(true, Ordering::SeqCst);
+}
+
+/// Reads the config file, along with the profiles in the `profiles` directory next to it, each a YAML file named after the profile, then applies the overrides from the environment and the command line, and looks up the secrets it refers to.
+fn read_config(path: &Path, profile: Option<&str>, sets: &[String]) -> anyhow::Result<Config> {
+    let config: serde_json::Value = serde_yaml::from_slice(&std::fs::read(path)?)?;
+    let mut config: Config = serde_json::from_value(config)?;
+    let profiles_dir = path.parent().unwrap_or(Path::new(".")).join("profiles");
+    if let Ok(entries) = std::fs::read_dir(&profiles_dir) {
+        for entry in entries {
+            let path = entry?.path();
+            if !matches!(
+                path.extension().and_then(|ext| ext.to_str()),
+                Some("yaml" | "yml")
+            ) {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            let settings = serde_yaml::from_slice(&std::fs::read(&path)?)
+                .with_context(|| format!("could not parse profile {}", path.display()))?;
+            anyhow::ensure!(
+                config.profiles.insert(name.to_string(), settings).is_none(),
+                "profile {name:?} is defined both in the config file and in {}",
+                path.display()
+            );
+        }
+    }
+    let config = apply_overrides(config, sets)?;
+    let mut fields = serde_json::to_value(&config)?;
+    resolve_secrets(&mut fields)?;
+    let mut config: Config = serde_json::from_value(fields)?;
+    if let Some(profile) = profile {
+        config.profile = Some(profile.to_string());
+    }
+    Ok(config)
+}
+
+/// Reloads the config whenever the file changes, or on SIGHUP. A config that fails to parse is ignored until it is fixed. Changes to the files in the profiles directory are only picked up on SIGHUP.
+async fn reload_loop(
+    client: &Client,
+    path: &Path,
+    profile: Option<&str>,
+    sets: &[String],
+) -> anyhow::Result<()> {
+    #[cfg(unix)]
+    unsafe {
+        libc::signal(
+            libc::SIGHUP,
+            on_sighup as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+    let modified = || -> Option<SystemTime> { std::fs::metadata(path).ok()?.modified().ok() };
+    let mut last_modified = modified();
+    loop {
+        smol::Timer::after(RELOAD_CHECK_INTERVAL).await;
+        let now_modified = modified();
+        let hangup = SIGHUP_RECEIVED.swap(false, Ordering::SeqCst);
+        if now_modified == last_modified && !hangup {
+            continue;
+        }
+        last_modified = now_modified;
+        match read_config(path, profile, sets) {
+            Ok(config) => client.reload_config(config),
+            Err(err) => tracing::warn!(
+                err = debug(err),
+                "could not read the changed config, keeping the old one"
+            ),
+        }
+    }
+}
+
+/// Run the stdio VPN interface where packets are read from stdin and written to stdout,
+/// each prefixed with a 16-bit big-endian length.
+fn run_stdio_vpn(client: Client) -> anyhow::Result<()> {
+    // Spawn a thread for reading from stdin and sending to VPN
+    let client_clone = client.clone();
+    std::thread::spawn(move || -> anyhow::Result<()> {
+        let mut stdin = stdin().lock();
+        let mut length_buf = [0u8; 2];
+        loop {
+            // Read the 16-bit big-endian length prefix
+            match stdin.read_exact(&mut length_buf) {
+                Ok(_) => {
+                    // Convert big-endian length to usize
+                    let length = u16::from_be_bytes(length_buf) as usize;
+
+                    // Read the packet data
+                    let mut packet_buf = vec![0u8; length];
+                    stdin.read_exact(&mut packet_buf)?;
+
+                    // Send the packet to the VPN
+                    smol::future::block_on(client_clone.send_vpn_packet(Bytes::from(packet_buf)))?;
+                }
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    // End of input stream
+                    break;
+                }
+                Err(e) => {
+                    return Err(e).context("Error reading from stdin");
+                }
+            }
+        }
+        Ok(())
+    });
+
+    // Spawn a thread for receiving from VPN and writing to stdout
+    std::thread::spawn(move || -> anyhow::Result<()> {
+        let mut stdout = stdout().lock();
+        loop {
+            // Receive a packet from the VPN
+            let packet = smol::future::block_on(client.recv_vpn_packet())?;
+
+            // Get the length as a 16-bit value, capping at u16::MAX if larger
+            let length = std::cmp::min(packet.len(), u16::MAX as usize) as u16;
+
+            // Write the 16-bit big-endian length prefix
+            stdout.write_all(&length.to_be_bytes())?;
+
+            // Write the packet data
+            stdout.write_all(&packet)?;
+            stdout.flush()?;
+        }
+    });
+
+    Ok(())
+}
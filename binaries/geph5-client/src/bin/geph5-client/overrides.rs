@@ -0,0 +1,117 @@
+use anyhow::Context;
+use geph5_client::Config;
+use serde_json::Value;
+
+/// Environment variables starting with this override config settings, like `GEPH5_SOCKS5_LISTEN` for `socks5_listen`. Settings inside others are separated by double underscores, like `GEPH5_KCP__NODELAY`.
+const ENV_PREFIX: &str = "GEPH5_";
+
+/// Layers overrides from the environment, then from `--set key=value` flags, over the config file. Keys inside others are separated by dots, and list items are picked by index, like `listeners.0.listen`. Values are parsed as YAML, falling back to plain strings.
+///
+/// Profiles are applied on top of this, so a profile that sets something still replaces the overridden value while it is active.
+pub fn apply_overrides(config: Config, sets: &[String]) -> anyhow::Result<Config> {
+    let mut overrides = vec![];
+    let mut env: Vec<(String, String)> = std::env::vars()
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+        .collect();
+    env.sort();
+    for (name, value) in env {
+        let path: Vec<String> = name[ENV_PREFIX.len()..]
+            .split("__")
+            .map(|segment| segment.to_lowercase())
+            .collect();
+        overrides.push((name, path, value, true));
+    }
+    for set in sets {
+        let (key, value) = set
+            .split_once('=')
+            .with_context(|| format!("--set {set:?} is not of the form key=value"))?;
+        let path: Vec<String> = key.split('.').map(|segment| segment.to_string()).collect();
+        overrides.push((format!("--set {key}"), path, value.to_string(), false));
+    }
+
+    let mut fields = serde_json::to_value(&config)?;
+    for (source, path, raw, from_env) in overrides {
+        if fields.get(&path[0]).is_none() {
+            if from_env {
+                tracing::warn!(
+                    var = source,
+                    "ignoring variable that is not a config setting"
+                );
+                continue;
+            }
+            anyhow::bail!("{source}: there is no setting {:?}", path[0]);
+        }
+        let parsed = serde_yaml::from_str(&raw).unwrap_or_else(|_| Value::String(raw.clone()));
+        let mut last_err = None;
+        for candidate in [parsed, Value::String(raw.clone())] {
+            let mut attempt = fields.clone();
+            set_path(&mut attempt, &path, candidate).with_context(|| source.clone())?;
+            match serde_json::from_value::<Config>(attempt.clone()) {
+                Ok(_) => {
+                    fields = attempt;
+                    last_err = None;
+                    break;
+                }
+                Err(err) => last_err = Some(err),
+            }
+        }
+        if let Some(err) = last_err {
+            anyhow::bail!("{source}: {raw:?} is not a valid value: {err}");
+        }
+    }
+    Ok(serde_json::from_value(fields)?)
+}
+
+/// Sets the value at the given path of keys and list indices, creating maps where the path goes through unset settings.
+fn set_path(target: &mut Value, path: &[String], new: Value) -> anyhow::Result<()> {
+    let Some((first, rest)) = path.split_first() else {
+        *target = new;
+        return Ok(());
+    };
+    if target.is_null() {
+        *target = Value::Object(Default::default());
+    }
+    let next = match target {
+        Value::Object(map) => map.entry(first.clone()).or_insert(Value::Null),
+        Value::Array(items) => {
+            let index: usize = first
+                .parse()
+                .with_context(|| format!("{first:?} is not a list index"))?;
+            let len = items.len();
+            items
+                .get_mut(index)
+                .with_context(|| format!("index {index} is past the end of a list of {len}"))?
+        }
+        _ => anyhow::bail!("cannot set {first:?} inside a setting that is not a map or a list"),
+    };
+    set_path(next, rest, new)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn set_nested_paths() {
+        let mut value = json!({"listeners": [{"listen": "127.0.0.1:1"}], "upstream_proxy": null});
+        set_path(
+            &mut value,
+            &["listeners".into(), "0".into(), "listen".into()],
+            json!("127.0.0.1:2"),
+        )
+        .unwrap();
+        set_path(
+            &mut value,
+            &["upstream_proxy".into(), "socks5".into()],
+            json!("127.0.0.1:3"),
+        )
+        .unwrap();
+        assert_eq!(
+            value,
+            json!({"listeners": [{"listen": "127.0.0.1:2"}], "upstream_proxy": {"socks5": "127.0.0.1:3"}})
+        );
+        assert!(set_path(&mut value, &["listeners".into(), "1".into()], json!(null)).is_err());
+    }
+}
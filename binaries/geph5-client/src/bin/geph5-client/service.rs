@@ -0,0 +1,296 @@
+use std::{
+    ffi::OsStr,
+    os::windows::ffi::OsStrExt,
+    path::Path,
+    ptr::{null, null_mut},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use smol::channel::{Receiver, Sender};
+use winapi::{
+    shared::{
+        minwindef::{DWORD, LPVOID},
+        winerror::{ERROR_CALL_NOT_IMPLEMENTED, ERROR_SERVICE_SPECIFIC_ERROR, NO_ERROR},
+    },
+    um::{
+        winnt::{
+            DELETE, LPWSTR, SERVICE_AUTO_START, SERVICE_ERROR_NORMAL, SERVICE_WIN32_OWN_PROCESS,
+        },
+        winsvc::{
+            ChangeServiceConfig2W, CloseServiceHandle, ControlService, CreateServiceW,
+            DeleteService, OpenSCManagerW, OpenServiceW, QueryServiceStatus,
+            RegisterServiceCtrlHandlerExW, SetServiceStatus, StartServiceCtrlDispatcherW,
+            StartServiceW, SC_HANDLE, SC_MANAGER_CONNECT, SC_MANAGER_CREATE_SERVICE,
+            SERVICE_ACCEPT_SHUTDOWN, SERVICE_ACCEPT_STOP, SERVICE_ALL_ACCESS,
+            SERVICE_CONFIG_DESCRIPTION, SERVICE_CONTROL_INTERROGATE, SERVICE_CONTROL_SHUTDOWN,
+            SERVICE_CONTROL_STOP, SERVICE_DESCRIPTIONW, SERVICE_QUERY_STATUS, SERVICE_RUNNING,
+            SERVICE_START, SERVICE_START_PENDING, SERVICE_STATUS, SERVICE_STATUS_HANDLE,
+            SERVICE_STOP, SERVICE_STOPPED, SERVICE_STOP_PENDING, SERVICE_TABLE_ENTRYW,
+        },
+    },
+};
+
+const SERVICE_NAME: &str = "geph5-client";
+const DISPLAY_NAME: &str = "Geph5 Client";
+const DESCRIPTION: &str =
+    "Connects to the Geph network in the background, without anyone logged in.";
+
+/// How long to wait for the service to stop before giving up.
+const STOP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What the service runs once the service control manager starts it, given a channel that is signalled when it should stop.
+type ServiceBody = Box<dyn FnOnce(Receiver<()>) -> anyhow::Result<()> + Send>;
+
+static SERVICE_BODY: Mutex<Option<ServiceBody>> = Mutex::new(None);
+static STOP_SIGNAL: OnceLock<Sender<()>> = OnceLock::new();
+static STATUS_HANDLE: AtomicUsize = AtomicUsize::new(0);
+
+/// Registers the client as a service that starts at boot, running with the given config and command-line overrides.
+pub fn install(config: &Path, profile: Option<&str>, sets: &[String]) -> anyhow::Result<()> {
+    let exe = std::env::current_exe()?;
+    // services start in the system directory, so relative paths would not work
+    let config = std::fs::canonicalize(config)
+        .with_context(|| format!("could not find config {}", config.display()))?;
+    let mut command = format!(
+        "{} --service --config {}",
+        quote(&exe.to_string_lossy()),
+        quote(&config.to_string_lossy())
+    );
+    if let Some(profile) = profile {
+        command += &format!(" --profile {}", quote(profile));
+    }
+    for set in sets {
+        command += &format!(" --set {}", quote(set));
+    }
+
+    let manager = open_manager(SC_MANAGER_CREATE_SERVICE)?;
+    let service = ScHandle::new(unsafe {
+        CreateServiceW(
+            manager.0,
+            wide(SERVICE_NAME).as_ptr(),
+            wide(DISPLAY_NAME).as_ptr(),
+            SERVICE_ALL_ACCESS,
+            SERVICE_WIN32_OWN_PROCESS,
+            SERVICE_AUTO_START,
+            SERVICE_ERROR_NORMAL,
+            wide(&command).as_ptr(),
+            null(),
+            null_mut(),
+            null(),
+            null(),
+            null(),
+        )
+    })
+    .context("could not create the service")?;
+    let mut description = wide(DESCRIPTION);
+    let mut info = SERVICE_DESCRIPTIONW {
+        lpDescription: description.as_mut_ptr(),
+    };
+    unsafe {
+        ChangeServiceConfig2W(
+            service.0,
+            SERVICE_CONFIG_DESCRIPTION,
+            &mut info as *mut SERVICE_DESCRIPTIONW as LPVOID,
+        )
+    };
+    eprintln!("installed the {SERVICE_NAME} service, which runs: {command}");
+    Ok(())
+}
+
+/// Stops the service if it is running, then removes it.
+pub fn uninstall() -> anyhow::Result<()> {
+    let manager = open_manager(SC_MANAGER_CONNECT)?;
+    let service = open_service(&manager, SERVICE_STOP | SERVICE_QUERY_STATUS | DELETE)?;
+    if current_state(&service)? != SERVICE_STOPPED {
+        stop_and_wait(&service)?;
+    }
+    if unsafe { DeleteService(service.0) } == 0 {
+        return Err(std::io::Error::last_os_error()).context("could not delete the service");
+    }
+    eprintln!("uninstalled the {SERVICE_NAME} service");
+    Ok(())
+}
+
+/// Asks the service control manager to start the service.
+pub fn start() -> anyhow::Result<()> {
+    let manager = open_manager(SC_MANAGER_CONNECT)?;
+    let service = open_service(&manager, SERVICE_START)?;
+    if unsafe { StartServiceW(service.0, 0, null_mut()) } == 0 {
+        return Err(std::io::Error::last_os_error()).context("could not start the service");
+    }
+    eprintln!("started the {SERVICE_NAME} service");
+    Ok(())
+}
+
+/// Asks the service to stop, waiting until it has.
+pub fn stop() -> anyhow::Result<()> {
+    let manager = open_manager(SC_MANAGER_CONNECT)?;
+    let service = open_service(&manager, SERVICE_STOP | SERVICE_QUERY_STATUS)?;
+    stop_and_wait(&service)?;
+    eprintln!("stopped the {SERVICE_NAME} service");
+    Ok(())
+}
+
+/// Runs the given body as the service, reporting its status to the service control manager. This only works when the service control manager started the process, and blocks until the service stops.
+pub fn run_as_service(
+    body: impl FnOnce(Receiver<()>) -> anyhow::Result<()> + Send + 'static,
+) -> anyhow::Result<()> {
+    *SERVICE_BODY.lock().unwrap() = Some(Box::new(body));
+    let mut name = wide(SERVICE_NAME);
+    let table = [
+        SERVICE_TABLE_ENTRYW {
+            lpServiceName: name.as_mut_ptr(),
+            lpServiceProc: Some(service_main),
+        },
+        SERVICE_TABLE_ENTRYW {
+            lpServiceName: null_mut(),
+            lpServiceProc: None,
+        },
+    ];
+    if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+        return Err(std::io::Error::last_os_error())
+            .context("could not reach the service control manager; --service only works when Windows starts the client as a service");
+    }
+    Ok(())
+}
+
+unsafe extern "system" fn service_main(_argc: DWORD, _argv: *mut LPWSTR) {
+    let (send_stop, recv_stop) = smol::channel::bounded(1);
+    let _ = STOP_SIGNAL.set(send_stop);
+    let handle = RegisterServiceCtrlHandlerExW(
+        wide(SERVICE_NAME).as_ptr(),
+        Some(control_handler),
+        null_mut(),
+    );
+    if handle.is_null() {
+        tracing::error!(
+            err = debug(std::io::Error::last_os_error()),
+            "could not register the service control handler"
+        );
+        return;
+    }
+    STATUS_HANDLE.store(handle as usize, Ordering::SeqCst);
+    // the client starts in the background, so there is nothing to wait for before accepting controls
+    set_status(SERVICE_RUNNING, NO_ERROR);
+    let body = SERVICE_BODY.lock().unwrap().take();
+    match body.map(|body| body(recv_stop)) {
+        Some(Err(err)) => {
+            tracing::error!(err = debug(err), "service stopped with an error");
+            set_status(SERVICE_STOPPED, ERROR_SERVICE_SPECIFIC_ERROR);
+        }
+        _ => set_status(SERVICE_STOPPED, NO_ERROR),
+    }
+}
+
+unsafe extern "system" fn control_handler(
+    control: DWORD,
+    _event_type: DWORD,
+    _event_data: LPVOID,
+    _context: LPVOID,
+) -> DWORD {
+    match control {
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+            set_status(SERVICE_STOP_PENDING, NO_ERROR);
+            if let Some(stop) = STOP_SIGNAL.get() {
+                let _ = stop.try_send(());
+            }
+            NO_ERROR
+        }
+        SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+        _ => ERROR_CALL_NOT_IMPLEMENTED,
+    }
+}
+
+fn set_status(state: DWORD, exit_code: DWORD) {
+    let pending = state == SERVICE_START_PENDING || state == SERVICE_STOP_PENDING;
+    let mut status = SERVICE_STATUS {
+        dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+        dwCurrentState: state,
+        dwControlsAccepted: if state == SERVICE_RUNNING {
+            SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN
+        } else {
+            0
+        },
+        dwWin32ExitCode: exit_code,
+        dwServiceSpecificExitCode: if exit_code == ERROR_SERVICE_SPECIFIC_ERROR {
+            1
+        } else {
+            0
+        },
+        dwCheckPoint: 0,
+        dwWaitHint: if pending {
+            STOP_TIMEOUT.as_millis() as DWORD
+        } else {
+            0
+        },
+    };
+    let handle = STATUS_HANDLE.load(Ordering::SeqCst) as SERVICE_STATUS_HANDLE;
+    unsafe { SetServiceStatus(handle, &mut status) };
+}
+
+fn stop_and_wait(service: &ScHandle) -> anyhow::Result<()> {
+    let mut status: SERVICE_STATUS = unsafe { std::mem::zeroed() };
+    if unsafe { ControlService(service.0, SERVICE_CONTROL_STOP, &mut status) } == 0 {
+        return Err(std::io::Error::last_os_error()).context("could not stop the service");
+    }
+    let start = Instant::now();
+    while current_state(service)? != SERVICE_STOPPED {
+        anyhow::ensure!(
+            start.elapsed() < STOP_TIMEOUT,
+            "the service did not stop within {STOP_TIMEOUT:?}"
+        );
+        std::thread::sleep(Duration::from_millis(250));
+    }
+    Ok(())
+}
+
+fn current_state(service: &ScHandle) -> anyhow::Result<DWORD> {
+    let mut status: SERVICE_STATUS = unsafe { std::mem::zeroed() };
+    if unsafe { QueryServiceStatus(service.0, &mut status) } == 0 {
+        return Err(std::io::Error::last_os_error()).context("could not query the service status");
+    }
+    Ok(status.dwCurrentState)
+}
+
+/// A handle to the service control manager or a service, closed on drop.
+struct ScHandle(SC_HANDLE);
+
+impl ScHandle {
+    fn new(handle: SC_HANDLE) -> anyhow::Result<Self> {
+        if handle.is_null() {
+            Err(std::io::Error::last_os_error().into())
+        } else {
+            Ok(Self(handle))
+        }
+    }
+}
+
+impl Drop for ScHandle {
+    fn drop(&mut self) {
+        unsafe { CloseServiceHandle(self.0) };
+    }
+}
+
+fn open_manager(access: DWORD) -> anyhow::Result<ScHandle> {
+    ScHandle::new(unsafe { OpenSCManagerW(null(), null(), access) })
+        .context("could not open the service control manager, which needs an administrator")
+}
+
+fn open_service(manager: &ScHandle, access: DWORD) -> anyhow::Result<ScHandle> {
+    ScHandle::new(unsafe { OpenServiceW(manager.0, wide(SERVICE_NAME).as_ptr(), access) })
+        .with_context(|| format!("could not open the {SERVICE_NAME} service; is it installed?"))
+}
+
+fn wide(s: &str) -> Vec<u16> {
+    OsStr::new(s).encode_wide().chain(Some(0)).collect()
+}
+
+/// Quotes an argument for a Windows command line.
+fn quote(arg: &str) -> String {
+    format!("\"{}\"", arg.replace('"', "\\\""))
+}
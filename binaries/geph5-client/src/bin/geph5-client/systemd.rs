@@ -0,0 +1,125 @@
+use std::time::Duration;
+
+use geph5_client::{Client, ConnInfo};
+
+/// The first file descriptor that systemd passes sockets in.
+#[cfg(target_os = "linux")]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Takes the TCP listening sockets systemd passed us, if it started us through socket activation.
+#[cfg(target_os = "linux")]
+pub fn take_listen_fds() -> Vec<std::net::TcpListener> {
+    use std::os::fd::FromRawFd;
+
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(std::process::id());
+    let count: i32 = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(0);
+    // so that hook scripts don't think the sockets are meant for them
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    if !for_us {
+        return vec![];
+    }
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .filter_map(|fd| {
+            if !is_stream_socket(fd) {
+                tracing::warn!(fd, "ignoring inherited socket that is not a TCP listener");
+                return None;
+            }
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            // Unix sockets are stream sockets too, but have no IP address
+            listener.local_addr().is_ok().then_some(listener)
+        })
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn take_listen_fds() -> Vec<std::net::TcpListener> {
+    vec![]
+}
+
+#[cfg(target_os = "linux")]
+fn is_stream_socket(fd: i32) -> bool {
+    let mut kind: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut kind as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    ret == 0 && kind == libc::SOCK_STREAM
+}
+
+/// Sends a state change, like "READY=1", to systemd if it is supervising us as a Type=notify service.
+#[cfg(target_os = "linux")]
+fn notify(state: &str) {
+    use std::os::{
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram},
+    };
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let send = || -> std::io::Result<()> {
+        let socket = UnixDatagram::unbound()?;
+        let addr = match path.as_encoded_bytes().strip_prefix(b"@") {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(&path)?,
+        };
+        socket.send_to_addr(state.as_bytes(), &addr)?;
+        Ok(())
+    };
+    if let Err(err) = send() {
+        tracing::warn!(err = debug(err), state, "could not notify systemd");
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn notify(_state: &str) {}
+
+/// Tells systemd that we are ready once the first tunnel is up, and keeps the service status in step with the connection.
+pub async fn notify_loop(client: &Client) -> anyhow::Result<()> {
+    if std::env::var_os("NOTIFY_SOCKET").is_none() {
+        return smol::future::pending().await;
+    }
+    let control = client.control_client();
+    let mut ready = false;
+    let mut last_status = String::new();
+    loop {
+        let status = match control.conn_info().await? {
+            ConnInfo::Disconnected => "Disconnected".to_string(),
+            ConnInfo::Connecting => "Connecting".to_string(),
+            ConnInfo::Connected(info) => {
+                if !ready {
+                    notify("READY=1");
+                    ready = true;
+                }
+                format!(
+                    "Connected to {} in {}, {} over {} through {}",
+                    info.exit.c2e_listen.ip(),
+                    info.exit.city,
+                    info.exit.country.alpha2(),
+                    info.protocol,
+                    info.bridge
+                )
+            }
+        };
+        if status != last_status {
+            notify(&format!("STATUS={status}"));
+            last_status = status;
+        }
+        smol::Timer::after(Duration::from_secs(1)).await;
+    }
+}
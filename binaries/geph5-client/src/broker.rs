@@ -1,4 +1,5 @@
 mod fronted_http;
+mod mirror;
 mod priority_race;
 mod race;
 
@@ -10,16 +11,25 @@ use anyhow::Context;
 
 #[cfg(feature = "aws_lambda")]
 use aws_lambda::AwsLambdaTransport;
+use ed25519_dalek::VerifyingKey;
 use fronted_http::FrontedHttpTransport;
-use geph5_broker_protocol::BrokerClient;
+use geph5_broker_protocol::{
+    BrokerClient, Capabilities, BROKER_PROTOCOL_VERSION, FEATURE_IPV6_EXITS,
+};
 use itertools::Itertools;
+pub use mirror::{mirrored_exits, mirrored_routes};
 use nanorpc::DynRpcTransport;
 use priority_race::PriorityRaceTransport;
 use race::RaceTransport;
 
 use serde::{Deserialize, Serialize};
-use sillad::tcp::TcpDialer;
-use std::{collections::BTreeMap, net::SocketAddr};
+use sillad::{dialer::DialerExt, tcp::TcpDialer};
+use sillad_proxy::{ProxyDialer, UpstreamProxy};
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
 
 use crate::client::{Config, CtxField};
 
@@ -46,19 +56,35 @@ pub enum BrokerSource {
 impl BrokerSource {
     /// Converts to a RpcTransport.
     pub fn rpc_transport(&self) -> DynRpcTransport {
+        self.rpc_transport_via(None)
+    }
+
+    /// Converts to a RpcTransport that makes its connections through the given upstream proxy, if any.
+    pub fn rpc_transport_via(&self, proxy: Option<&UpstreamProxy>) -> DynRpcTransport {
         match self {
             BrokerSource::Direct(s) => DynRpcTransport::new(FrontedHttpTransport {
                 url: s.clone(),
                 host: None,
+                proxy: proxy.map(|p| p.to_url()),
             }),
             BrokerSource::DirectTcp(dest_addr) => {
-                DynRpcTransport::new(nanorpc_sillad::DialerTransport(TcpDialer {
-                    dest_addr: *dest_addr,
-                }))
+                let dialer = match proxy {
+                    Some(proxy) => ProxyDialer {
+                        proxy: proxy.clone(),
+                        dest_addr: *dest_addr,
+                    }
+                    .dynamic(),
+                    None => TcpDialer {
+                        dest_addr: *dest_addr,
+                    }
+                    .dynamic(),
+                };
+                DynRpcTransport::new(nanorpc_sillad::DialerTransport(dialer))
             }
             BrokerSource::Fronted { front, host } => DynRpcTransport::new(FrontedHttpTransport {
                 url: front.clone(),
                 host: Some(host.clone()),
+                proxy: proxy.map(|p| p.to_url()),
             }),
             #[cfg(feature = "aws_lambda")]
             BrokerSource::AwsLambda {
@@ -75,12 +101,15 @@ impl BrokerSource {
             BrokerSource::Race(race_between) => {
                 let transports = race_between
                     .iter()
-                    .map(|bs| bs.rpc_transport())
+                    .map(|bs| bs.rpc_transport_via(proxy))
                     .collect_vec();
                 DynRpcTransport::new(RaceTransport::new(transports))
             }
             BrokerSource::PriorityRace(inner) => {
-                let inner = inner.iter().map(|(k, v)| (*k, v.rpc_transport())).collect();
+                let inner = inner
+                    .iter()
+                    .map(|(k, v)| (*k, v.rpc_transport_via(proxy)))
+                    .collect();
                 DynRpcTransport::new(PriorityRaceTransport::new(inner))
             }
         }
@@ -93,9 +122,76 @@ pub fn broker_client(ctx: &AnyCtx<Config>) -> anyhow::Result<&BrokerClient> {
     )
 }
 
+/// Gets what the broker supports, asking it at most about once an hour. Brokers too old to answer are taken to support what [`Capabilities::legacy`] says, and so are brokers we can't reach right now, until we ask again a bit later.
+pub async fn broker_capabilities(ctx: &AnyCtx<Config>) -> Capabilities {
+    if let Some((caps, expiry)) = ctx.get(BROKER_CAPABILITIES).lock().clone() {
+        if expiry > Instant::now() {
+            return caps;
+        }
+    }
+    let fetched = async { anyhow::Ok(broker_client(ctx)?.get_capabilities().await?) };
+    let (caps, lifetime) = match fetched.await {
+        Ok(caps) => {
+            if !caps.protocol_versions.contains(&BROKER_PROTOCOL_VERSION) {
+                tracing::warn!(
+                    ours = BROKER_PROTOCOL_VERSION,
+                    theirs = debug(&caps.protocol_versions),
+                    "broker does not speak our protocol version, consider upgrading"
+                );
+            }
+            (caps, Duration::from_secs(3600))
+        }
+        Err(err) => {
+            tracing::debug!(
+                err = debug(err),
+                "could not get broker capabilities, assuming legacy broker"
+            );
+            (Capabilities::legacy(), Duration::from_secs(300))
+        }
+    };
+    *ctx.get(BROKER_CAPABILITIES).lock() = Some((caps.clone(), Instant::now() + lifetime));
+    caps
+}
+
+/// Gets which exits can reach IPv6 destinations, asking the broker at most about every ten minutes. Brokers that don't know about exit features are taken to know of no such exits.
+pub async fn ipv6_exits(ctx: &AnyCtx<Config>) -> HashSet<VerifyingKey> {
+    if let Some((exits, expiry)) = ctx.get(IPV6_EXITS).lock().clone() {
+        if expiry > Instant::now() {
+            return exits;
+        }
+    }
+    if !broker_capabilities(ctx)
+        .await
+        .has_feature(FEATURE_IPV6_EXITS)
+    {
+        return HashSet::new();
+    }
+    let fetched = async {
+        broker_client(ctx)?
+            .get_ipv6_exits()
+            .await?
+            .map_err(|e| anyhow::anyhow!("broker refused to list IPv6 exits: {e}"))
+    };
+    let exits: HashSet<VerifyingKey> = match fetched.await {
+        Ok(exits) => exits.into_iter().collect(),
+        Err(err) => {
+            tracing::debug!(err = debug(err), "could not get IPv6 exits");
+            HashSet::new()
+        }
+    };
+    *ctx.get(IPV6_EXITS).lock() = Some((exits.clone(), Instant::now() + Duration::from_secs(600)));
+    exits
+}
+
+static IPV6_EXITS: CtxField<parking_lot::Mutex<Option<(HashSet<VerifyingKey>, Instant)>>> =
+    |_| parking_lot::Mutex::new(None);
+
+static BROKER_CAPABILITIES: CtxField<parking_lot::Mutex<Option<(Capabilities, Instant)>>> =
+    |_| parking_lot::Mutex::new(None);
+
 static BROKER_CLIENT: CtxField<Option<BrokerClient>> = |ctx| {
     ctx.init()
         .broker
         .as_ref()
-        .map(|src| BrokerClient::from(src.rpc_transport()))
+        .map(|src| BrokerClient::from(src.rpc_transport_via(ctx.init().upstream_proxy.as_ref())))
 };
@@ -7,6 +7,8 @@ use nanorpc::{JrpcRequest, JrpcResponse, RpcTransport};
 pub struct FrontedHttpTransport {
     pub url: String,
     pub host: Option<String>,
+    /// The URL of an upstream proxy to send requests through.
+    pub proxy: Option<String>,
 }
 
 #[async_trait]
@@ -20,7 +22,12 @@ impl RpcTransport for FrontedHttpTransport {
             "calling broker through http"
         );
         let start = Instant::now();
-        let mut request_builder = reqwest::Client::new()
+        let mut client_builder = reqwest::Client::builder();
+        if let Some(proxy) = &self.proxy {
+            client_builder = client_builder.proxy(reqwest::Proxy::all(proxy)?);
+        }
+        let mut request_builder = client_builder
+            .build()?
             .post(&self.url)
             .header("content-type", "application/json");
 
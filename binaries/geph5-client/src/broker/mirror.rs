@@ -0,0 +1,81 @@
+use std::time::Duration;
+
+use anyctx::AnyCtx;
+use anyhow::Context;
+use ed25519_dalek::VerifyingKey;
+use geph5_broker_protocol::{
+    mirrored_exits_path, mirrored_routes_path, AccountLevel, ExitList, Mirrored, RouteDescriptor,
+    Signed, DOMAIN_MIRRORED_EXITS, DOMAIN_MIRRORED_ROUTES,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::client::Config;
+
+/// How long we wait for one mirror before moving on to the next.
+const MIRROR_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Fetches the exit list for the given account level from a broker mirror.
+pub async fn mirrored_exits(ctx: &AnyCtx<Config>, level: AccountLevel) -> anyhow::Result<ExitList> {
+    fetch_mirrored(ctx, mirrored_exits_path(level), DOMAIN_MIRRORED_EXITS).await
+}
+
+/// Fetches bridge routes to the given exit from a broker mirror.
+pub async fn mirrored_routes(
+    ctx: &AnyCtx<Config>,
+    exit: &VerifyingKey,
+) -> anyhow::Result<RouteDescriptor> {
+    fetch_mirrored(ctx, &mirrored_routes_path(exit), DOMAIN_MIRRORED_ROUTES).await
+}
+
+/// Tries every mirror in turn, returning the first list that is signed by the broker's master key and not yet expired. Without a master key to check against, mirrors are never trusted.
+async fn fetch_mirrored<T: Serialize + DeserializeOwned>(
+    ctx: &AnyCtx<Config>,
+    path: &str,
+    domain: &str,
+) -> anyhow::Result<T> {
+    let master = ctx
+        .init()
+        .broker_keys
+        .as_ref()
+        .context("cannot trust broker mirrors without the broker's master key")?;
+    let master = VerifyingKey::from_bytes(
+        hex::decode(&master.master)?
+            .as_slice()
+            .try_into()
+            .context("master key of the wrong length")?,
+    )?;
+
+    let mut client_builder = reqwest::Client::builder().timeout(MIRROR_TIMEOUT);
+    if let Some(proxy) = &ctx.init().upstream_proxy {
+        client_builder = client_builder.proxy(reqwest::Proxy::all(proxy.to_url())?);
+    }
+    let client = client_builder.build()?;
+
+    let mut last_err = anyhow::anyhow!("no broker mirrors configured");
+    for mirror in ctx.init().broker_mirrors.iter() {
+        let url = format!("{}{path}", mirror.trim_end_matches('/'));
+        let res = async {
+            let body = client
+                .get(&url)
+                .send()
+                .await?
+                .error_for_status()?
+                .bytes()
+                .await?;
+            let signed: Signed<Mirrored<T>> = serde_json::from_slice(&body)?;
+            signed.verify_mirrored(domain, &master)
+        }
+        .await;
+        match res {
+            Ok(inner) => {
+                tracing::debug!(url, "fetched a list from a broker mirror");
+                return Ok(inner);
+            }
+            Err(err) => {
+                tracing::warn!(url, err = debug(&err), "broker mirror failed");
+                last_err = err;
+            }
+        }
+    }
+    Err(last_err)
+}
@@ -0,0 +1,211 @@
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+use sillad_shadowsocks::ShadowsocksKey;
+
+use crate::{
+    get_dialer::{verify_bridge_bundle, PinnedBridge},
+    Config,
+};
+
+/// Something wrong with a config that parsing it does not catch.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ConfigProblem {
+    /// The top-level setting the problem is in, such as "listeners".
+    pub setting: String,
+    pub severity: Severity,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    /// Something that will fail once the client runs.
+    Error,
+    /// Something that probably does not do what was intended.
+    Warning,
+}
+
+impl Config {
+    /// Checks the config for problems that span several settings or only show up once the client runs, like two listeners on the same port.
+    pub fn check(&self) -> Vec<ConfigProblem> {
+        let mut problems = vec![];
+        let mut problem = |setting: &str, severity: Severity, message: String| {
+            problems.push(ConfigProblem {
+                setting: setting.to_string(),
+                severity,
+                message,
+            })
+        };
+
+        let mut tcp_listeners: Vec<(&str, SocketAddr)> = [
+            ("socks5_listen", self.socks5_listen),
+            ("http_proxy_listen", self.http_proxy_listen),
+            ("pac_listen", self.pac_listen),
+            ("metrics_listen", self.metrics_listen),
+            ("transparent_listen", self.transparent_listen),
+            ("control_listen", self.control_listen),
+        ]
+        .into_iter()
+        .filter_map(|(setting, listen)| Some((setting, listen?)))
+        .collect();
+        tcp_listeners.extend(self.listeners.iter().map(|l| ("listeners", l.listen)));
+        tcp_listeners.extend(self.forward.iter().map(|f| ("forward", f.listen)));
+        for (i, (setting, listen)) in tcp_listeners.iter().enumerate() {
+            if let Some((other_setting, other_listen)) = tcp_listeners[..i]
+                .iter()
+                .find(|(_, other)| listeners_overlap(*listen, *other))
+            {
+                problem(
+                    setting,
+                    Severity::Error,
+                    format!("{listen} overlaps with {other_listen} in {other_setting}, so one of them will fail to bind"),
+                );
+            }
+        }
+
+        for bridge in self.pinned_bridges.iter() {
+            match bridge {
+                PinnedBridge::Shadowsocks {
+                    server,
+                    method,
+                    password,
+                } => {
+                    let key = method
+                        .parse()
+                        .and_then(|method| ShadowsocksKey::new(method, password));
+                    if let Err(err) = key {
+                        problem(
+                            "pinned_bridges",
+                            Severity::Error,
+                            format!("bridge {server} has a bad method or password: {err:#}"),
+                        );
+                    }
+                }
+            }
+        }
+
+        if let Some(bundle) = &self.bridge_bundle {
+            if let Err(err) = verify_bridge_bundle(bundle, self.broker_keys.as_ref()) {
+                problem(
+                    "bridge_bundle",
+                    Severity::Error,
+                    format!("bridge bundle cannot be used: {err:#}"),
+                );
+            }
+        }
+
+        if let Some(shaping) = self.shaping {
+            if !shaping.is_sane() {
+                problem(
+                    "shaping",
+                    Severity::Error,
+                    format!("{shaping:?} is out of range, and exits refuse it"),
+                );
+            }
+        }
+
+        for (transport, command) in self.pluggable_transports.iter() {
+            if command.is_empty() {
+                problem(
+                    "pluggable_transports",
+                    Severity::Error,
+                    format!("transport {transport:?} has no command"),
+                );
+            }
+        }
+
+        for name in self.profiles.keys() {
+            if let Err(err) = self.with_profile(Some(name)) {
+                problem("profiles", Severity::Error, format!("{err:#}"));
+            }
+        }
+        if let Some(name) = &self.profile {
+            if !self.profiles.contains_key(name) {
+                problem(
+                    "profile",
+                    Severity::Error,
+                    format!("there is no profile named {name:?}"),
+                );
+            }
+        }
+
+        if self.broker.is_some() && self.broker_keys.is_none() {
+            problem(
+                "broker_keys",
+                Severity::Warning,
+                "without broker_keys, what the broker says is not verified".into(),
+            );
+        }
+
+        if self.transparent_listen.is_some() && !cfg!(target_os = "linux") {
+            problem(
+                "transparent_listen",
+                Severity::Error,
+                "transparent proxying is only supported on Linux".into(),
+            );
+        }
+        if self.bind_interface.is_some() && !cfg!(any(target_os = "linux", target_os = "android")) {
+            problem(
+                "bind_interface",
+                Severity::Error,
+                "binding to an interface is only supported on Linux and Android".into(),
+            );
+        }
+        if self.vpn_fd.is_some() && !cfg!(unix) {
+            problem(
+                "vpn_fd",
+                Severity::Error,
+                "passing in a TUN device is only supported on Unix".into(),
+            );
+        }
+        if self.vpn {
+            if cfg!(any(target_os = "android", target_os = "ios")) && self.vpn_fd.is_none() {
+                problem(
+                    "vpn",
+                    Severity::Warning,
+                    "on this platform, VPN mode needs a vpn_fd or packets passed in through the library".into(),
+                );
+            }
+            #[cfg(target_os = "linux")]
+            if self.vpn_fd.is_none() && unsafe { libc::geteuid() } != 0 {
+                problem(
+                    "vpn",
+                    Severity::Warning,
+                    "VPN mode needs to run as root to set up the TUN device and routes".into(),
+                );
+            }
+        }
+
+        problems
+    }
+}
+
+/// Whether two TCP listeners would conflict, since they share a port on the same address, or one of them binds every address of the same family.
+fn listeners_overlap(a: SocketAddr, b: SocketAddr) -> bool {
+    let wildcard =
+        (a.ip().is_unspecified() || b.ip().is_unspecified()) && a.is_ipv4() == b.is_ipv4();
+    a.port() == b.port() && a.port() != 0 && (a.ip() == b.ip() || wildcard)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn overlapping_listeners() {
+        let config: Config = serde_json::from_value(json!({
+            "exit_constraint": "auto",
+            "socks5_listen": "127.0.0.1:9909",
+            "http_proxy_listen": "127.0.0.1:9910",
+            "listeners": [{"protocol": "http", "listen": "0.0.0.0:9909"}],
+        }))
+        .unwrap();
+        let problems = config.check();
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].setting, "listeners");
+        assert_eq!(problems[0].severity, Severity::Error);
+    }
+}
@@ -22,6 +22,11 @@ static IP_ADDRS: Lazy<PrefixSet<ipnet::Ipv4Net>> = Lazy::new(|| {
     set
 });
 
+/// Iterates over all the known Chinese domains.
+pub fn chinese_domains() -> impl Iterator<Item = &'static str> {
+    DOMAINS.iter().map(|s| s.as_str())
+}
+
 /// Returns true if the given host is Chinese
 pub fn is_chinese_host(host: &str) -> bool {
     if let Ok(ipv4) = Ipv4Addr::from_str(host) {
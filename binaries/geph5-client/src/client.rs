@@ -9,22 +9,34 @@ use geph5_broker_protocol::{Credential, UserInfo};
 use nanorpc::DynRpcTransport;
 use sillad::Pipe;
 use smol::future::FutureExt as _;
-use std::{fs::File, net::SocketAddr, path::PathBuf, sync::Arc};
+use std::{collections::BTreeMap, fs::File, net::SocketAddr, path::PathBuf, sync::Arc};
 
 use serde::{Deserialize, Serialize};
 use smolscale::immortal::Immortal;
 
 use crate::{
+    announcements::announcements_loop,
     auth::{auth_loop, get_auth_token},
     broker::{broker_client, BrokerSource},
     client_inner::{client_inner, open_conn},
     control_prot::{
         ControlClient, ControlProtocolImpl, ControlService, DummyControlProtocolTransport,
     },
-    http_proxy::http_proxy_serve,
+    dial_stats::protocol_telemetry_loop,
+    dns::{blocklist_loop, dns_serve, BlocklistSource},
+    forward::{forward_loop, PortForward, ReverseForward},
+    get_dialer::{ExitConstraint, PinnedBridge},
+    hooks::{hooks_loop, Hooks},
+    listeners::{listeners_loop, ListenAcl, ProxyListener},
+    metrics::metrics_serve,
     pac::pac_serve,
-    get_dialer::ExitConstraint,
-    socks5::socks5_loop,
+    profiles::{reload_base_config, set_base_config},
+    proxy_auth::ProxyAuth,
+    quality::{quality_loop, QualityAlerts},
+    socket_activation::bind_tcp,
+    stream::{Protocol, TunnelStream},
+    transparent::transparent_loop,
+    usage::usage_loop,
     vpn::{recv_vpn_packet, send_vpn_packet, vpn_loop},
 };
 
@@ -33,6 +45,44 @@ pub struct Config {
     pub socks5_listen: Option<SocketAddr>,
     pub http_proxy_listen: Option<SocketAddr>,
     pub pac_listen: Option<SocketAddr>,
+    /// Listener for a Prometheus-compatible `/metrics` endpoint.
+    #[serde(default)]
+    pub metrics_listen: Option<SocketAddr>,
+    #[serde(default)]
+    pub dns_listen: Option<SocketAddr>,
+    /// Listener for connections redirected by iptables (REDIRECT or TPROXY). Linux only.
+    #[serde(default)]
+    pub transparent_listen: Option<SocketAddr>,
+    /// DNS-over-HTTPS endpoint, reached through the tunnel, that the local resolver forwards queries to.
+    #[serde(default)]
+    pub doh_upstream: Option<String>,
+    /// Per-domain overrides of the DoH endpoint. Keys match the domain and all its subdomains; the value "exit" forces plain resolution by the exit.
+    #[serde(default)]
+    pub doh_overrides: BTreeMap<String, String>,
+    /// Hosts-format or domain-list blocklists applied to DNS lookups and proxied connections.
+    #[serde(default)]
+    pub blocklists: Vec<BlocklistSource>,
+    /// Per-domain policy on whether hostnames are resolved by the exit or locally. Domains not listed are resolved by the exit.
+    #[serde(default)]
+    pub resolve_policy: BTreeMap<String, ResolvePolicy>,
+    /// Per-host rules, keyed on the requested hostname (or the TLS SNI of transparently proxied flows), that override the split-tunneling decision.
+    #[serde(default)]
+    pub host_rules: BTreeMap<String, HostAction>,
+    /// Credentials required from clients of the SOCKS5 and HTTP proxies.
+    #[serde(default)]
+    pub proxy_auth: Option<ProxyAuth>,
+    /// Source-IP ACLs for the listeners that have none of their own: the `socks5_listen` and `http_proxy_listen` proxies, the transparent proxy, port forwards, the DNS resolver, and the PAC server.
+    #[serde(default)]
+    pub listen_acl: ListenAcl,
+    /// Additional proxy listeners, each with its own bind address, credentials, and rule overrides.
+    #[serde(default)]
+    pub listeners: Vec<ProxyListener>,
+    /// Local ports whose connections are forwarded through the tunnel to fixed destinations.
+    #[serde(default)]
+    pub forward: Vec<PortForward>,
+    /// Ports on the exit whose inbound connections are forwarded back to local services.
+    #[serde(default)]
+    pub reverse_forward: Vec<ReverseForward>,
 
     pub control_listen: Option<SocketAddr>,
     pub exit_constraint: ExitConstraint,
@@ -42,6 +92,9 @@ pub struct Config {
 
     pub broker: Option<BrokerSource>,
     pub broker_keys: Option<BrokerKeys>,
+    /// Base URLs of CDN caches and static mirrors of the broker's exit and bridge lists, tried when the broker itself can't be reached. Their lists are only trusted if signed by the broker's master key.
+    #[serde(default)]
+    pub broker_mirrors: Vec<String>,
 
     #[serde(default)]
     pub vpn: bool,
@@ -51,6 +104,60 @@ pub struct Config {
     pub spoof_dns: bool,
     #[serde(default)]
     pub passthrough_china: bool,
+    /// Turns off counting tunnel traffic by destination host.
+    #[serde(default)]
+    pub disable_domain_accounting: bool,
+    /// Turns off sending the broker randomized counts of which protocols could connect, which it uses to tell clients in the same country which protocols to try first.
+    #[serde(default)]
+    pub disable_protocol_telemetry: bool,
+    /// Thresholds on the connection quality score that trigger degraded and recovered events.
+    #[serde(default)]
+    pub quality_alerts: QualityAlerts,
+    /// Webhooks or scripts to run when the connection goes up or down, or the exit changes.
+    #[serde(default)]
+    pub hooks: Hooks,
+    /// Self-hosted bridges to reach the exit through, besides the ones the broker provides.
+    #[serde(default)]
+    pub pinned_bridges: Vec<PinnedBridge>,
+    /// A bundle of bridges signed by the broker, as pasted from a side channel such as email, which gets us to an exit while the broker can't be reached.
+    #[serde(default)]
+    pub bridge_bundle: Option<String>,
+    /// Pluggable transport executables, with their arguments, keyed by the transport names they provide. Routes over transports not listed here are skipped.
+    #[serde(default)]
+    pub pluggable_transports: BTreeMap<String, Vec<String>>,
+    /// How KCP routes are tuned. More aggressive settings use more bandwidth but cope with more loss.
+    #[serde(default)]
+    pub kcp: sillad_kcp::KcpParams,
+    /// Experimental: how many ICMP echo requests per second ICMP tunnel routes may send. Those routes are slow and need unprivileged ping sockets or root, so they are skipped unless this is set.
+    #[serde(default)]
+    pub icmp_max_pps: Option<u32>,
+    /// The resolver that DNS tunnel routes send their queries through, which should be the one the network provides. Without one, the first nameserver in /etc/resolv.conf is used, and DNS tunnel routes are skipped where there is none.
+    #[serde(default)]
+    pub dns_tunnel_resolver: Option<SocketAddr>,
+    /// A SOCKS5 or HTTP proxy that connections to bridges, exits, and the broker go through, for networks that only allow traffic through a proxy. Routes over UDP are skipped when this is set.
+    #[serde(default)]
+    pub upstream_proxy: Option<sillad_proxy::UpstreamProxy>,
+    /// Cuts up the first packets of TCP connections, such as TLS ClientHellos, to get past DPI that blocks by SNI. Turn this on for networks that need it; it slows down connecting a little.
+    #[serde(default)]
+    pub fragment: Option<sillad_fragment::FragmentParams>,
+    /// Sends all traffic to the exit in fixed-size frames, with cover traffic when idle, to resist flow correlation by traffic analysis. This costs a lot of bandwidth, and exits that predate it refuse such connections.
+    #[serde(default)]
+    pub shaping: Option<sillad_shaping::ShapingParams>,
+    /// The network interface that direct TCP connections to bridges and exits go out of, such as "wlan0". In VPN mode, this keeps them from looping back into the tunnel on platforms where routes alone cannot. Only supported on Linux and Android.
+    #[serde(default)]
+    pub bind_interface: Option<String>,
+    /// Which address families connections outside the tunnel try first, or at all, and how quickly they move on to the next address.
+    #[serde(default)]
+    pub happy_eyeballs: sillad::tcp::HappyEyeballsPolicy,
+    /// Flow-control windows of the tunnel to the exit. Raising them helps single streams go faster over long, fast paths.
+    #[serde(default)]
+    pub mux_windows: picomux::WindowConfig,
+    /// Named profiles, each a set of the settings above that replace the configured ones while the profile is active.
+    #[serde(default)]
+    pub profiles: BTreeMap<String, serde_json::Map<String, serde_json::Value>>,
+    /// The profile to start with. Without one, the settings above apply as they are.
+    #[serde(default)]
+    pub profile: Option<String>,
     #[serde(default)]
     pub dry_run: bool,
     #[serde(default)]
@@ -76,7 +183,13 @@ impl Config {
         this.dry_run = true;
         this.socks5_listen = None;
         this.http_proxy_listen = None;
+        this.listeners.clear();
+        this.forward.clear();
+        this.reverse_forward.clear();
         this.pac_listen = None;
+        this.metrics_listen = None;
+        this.dns_listen = None;
+        this.transparent_listen = None;
         this.control_listen = None;
         this
     }
@@ -95,6 +208,29 @@ impl Default for BridgeMode {
     }
 }
 
+/// Where the hostname of a proxied connection gets resolved.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ResolvePolicy {
+    /// The exit resolves the hostname.
+    #[default]
+    Remote,
+    /// We resolve the hostname with the system resolver, then connect through the tunnel by IP.
+    Local,
+}
+
+/// What to do with a connection whose host matches a host rule.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum HostAction {
+    /// Connect directly, bypassing the tunnel.
+    Direct,
+    /// Always go through the tunnel, even if the host would otherwise be passed through.
+    Proxy,
+    /// Refuse the connection.
+    Block,
+}
+
 #[derive(Clone)]
 pub struct Client {
     task: Shared<smol::Task<Result<(), Arc<anyhow::Error>>>>,
@@ -108,7 +244,21 @@ impl Client {
         std::env::remove_var("https_proxy");
         std::env::remove_var("HTTP_PROXY");
         std::env::remove_var("HTTPS_PROXY");
+        let base = cfg;
+        let cfg = base
+            .with_profile(base.profile.as_deref())
+            .unwrap_or_else(|err| {
+                tracing::warn!(
+                    err = debug(err),
+                    "could not apply profile, starting without it"
+                );
+                Config {
+                    profile: None,
+                    ..base.clone()
+                }
+            });
         let ctx = AnyCtx::new(cfg.clone());
+        set_base_config(&ctx, base);
 
         #[cfg(unix)]
         if let Some(fd) = cfg.vpn_fd {
@@ -183,7 +333,14 @@ impl Client {
         }
     }
 
-    /// Opens a connection through the tunnel.
+    /// Opens a connection through the tunnel to a "host:port" destination, without going through any local proxy listener. The client does not need any listeners configured for this, so an app can tunnel just the connections it picks.
+    pub async fn open(&self, protocol: Protocol, dest: &str) -> anyhow::Result<TunnelStream> {
+        Ok(TunnelStream::new(
+            open_conn(&self.ctx, protocol.as_str(), dest).await?,
+        ))
+    }
+
+    /// Opens a TCP connection through the tunnel, as a [Pipe].
     pub async fn open_conn(&self, remote: &str) -> anyhow::Result<Box<dyn Pipe>> {
         open_conn(&self.ctx, "tcp", remote).await
     }
@@ -207,6 +364,11 @@ impl Client {
         Ok(())
     }
 
+    /// Applies a changed config without restarting, keeping the active profile unless the new config picks another. Settings that cannot change at runtime, like the credentials, keep their old values until restart.
+    pub fn reload_config(&self, cfg: Config) {
+        reload_base_config(&self.ctx, cfg)
+    }
+
     /// Get the control protocol client.
     pub fn control_client(&self) -> ControlClient {
         ControlClient(DynRpcTransport::new(DummyControlProtocolTransport(
@@ -245,7 +407,7 @@ async fn client_main(ctx: AnyCtx<Config>) -> anyhow::Result<()> {
     let rpc_serve = async {
         if let Some(control_listen) = ctx.init().control_listen {
             nanorpc_sillad::rpc_serve(
-                sillad::tcp::TcpListener::bind(control_listen).await?,
+                sillad::tcp::TcpListener::from_std(bind_tcp(control_listen)?)?,
                 ControlService(ControlProtocolImpl { ctx: ctx.clone() }),
             )
             .await?;
@@ -261,19 +423,55 @@ async fn client_main(ctx: AnyCtx<Config>) -> anyhow::Result<()> {
 
         let _client_loop = Immortal::spawn(client_inner(ctx.clone()));
 
-        socks5_loop(&ctx)
-            .inspect_err(|e| tracing::error!(err = debug(e), "socks5 loop stopped"))
+        listeners_loop(&ctx)
+            .inspect_err(|e| tracing::error!(err = debug(e), "proxy listeners stopped"))
             .race(vpn_loop.inspect_err(|e| tracing::error!(err = debug(e), "vpn loop stopped")))
-            .race(
-                http_proxy_serve(&ctx)
-                    .inspect_err(|e| tracing::error!(err = debug(e), "http proxy stopped")),
-            )
             .race(
                 auth_loop(&ctx)
                     .inspect_err(|e| tracing::error!(err = debug(e), "auth loop stopped")),
             )
             .race(rpc_serve)
             .race(pac_serve(&ctx))
+            .race(
+                metrics_serve(&ctx)
+                    .inspect_err(|e| tracing::error!(err = debug(e), "metrics server stopped")),
+            )
+            .race(
+                transparent_loop(&ctx)
+                    .inspect_err(|e| tracing::error!(err = debug(e), "transparent proxy stopped")),
+            )
+            .race(
+                blocklist_loop(&ctx)
+                    .inspect_err(|e| tracing::error!(err = debug(e), "blocklist loop stopped")),
+            )
+            .race(
+                dns_serve(&ctx)
+                    .inspect_err(|e| tracing::error!(err = debug(e), "dns server stopped")),
+            )
+            .race(
+                usage_loop(&ctx)
+                    .inspect_err(|e| tracing::error!(err = debug(e), "usage history stopped")),
+            )
+            .race(
+                forward_loop(&ctx)
+                    .inspect_err(|e| tracing::error!(err = debug(e), "port forwards stopped")),
+            )
+            .race(
+                announcements_loop(&ctx)
+                    .inspect_err(|e| tracing::error!(err = debug(e), "announcements stopped")),
+            )
+            .race(
+                hooks_loop(&ctx)
+                    .inspect_err(|e| tracing::error!(err = debug(e), "connection hooks stopped")),
+            )
+            .race(
+                quality_loop(&ctx)
+                    .inspect_err(|e| tracing::error!(err = debug(e), "quality probes stopped")),
+            )
+            .race(
+                protocol_telemetry_loop(&ctx)
+                    .inspect_err(|e| tracing::error!(err = debug(e), "protocol telemetry stopped")),
+            )
             .await
     }
 }
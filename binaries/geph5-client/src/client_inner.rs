@@ -1,11 +1,13 @@
 use anyctx::AnyCtx;
 use anyhow::Context;
 use bytes::Bytes;
-use clone_macro::clone;
 use ed25519_dalek::VerifyingKey;
 use futures_util::{future::join_all, AsyncReadExt as _};
 use geph5_misc_rpc::{
-    exit::{ClientCryptHello, ClientExitCryptPipe, ClientHello, ExitHello, ExitHelloInner},
+    exit::{
+        ClientCryptHello, ClientExitCryptPipe, ClientHello, ExitHello, ExitHelloInner,
+        SessionLimits,
+    },
     read_prepend_length, write_prepend_length,
 };
 use nursery_macro::nursery;
@@ -13,9 +15,11 @@ use nursery_macro::nursery;
 use picomux::{LivenessConfig, PicoMux};
 use rand::Rng;
 use sillad::{dialer::Dialer as _, EitherPipe, Pipe};
+use sillad_shaping::{ShapedPipe, ShapingParams};
 use smol::future::FutureExt as _;
 use smol_timeout2::TimeoutExt;
 use std::{
+    collections::HashSet,
     convert::Infallible,
     net::{IpAddr, SocketAddr},
     str::FromStr,
@@ -26,15 +30,28 @@ use std::{
 use stdcode::StdcodeSerializeExt;
 
 use crate::{
-    auth::get_connect_token, china::is_chinese_host, client::CtxField, control_prot::{ConnectedInfo, CURRENT_CONN_INFO}, get_dialer::get_dialer, spoof_dns::fake_dns_backtranslate, stats::{stat_incr_num, stat_set_num}, traffcount::TRAFF_COUNT, vpn::smart_vpn_whitelist, ConnInfo
+    auth::get_connect_token, broker::ipv6_exits, china::is_chinese_host, client::CtxField, conntrack::TrackedStream, control_prot::ConnectedInfo, dns::blocklist_check, quality::record_stream_open, reload::live_config, events::{push_event, set_conn_info, ConnEvent}, domain_rules::match_domain_rule, get_dialer::{exit_generation, get_dialer, wait_exit_change}, listeners::RuleOverrides, spoof_dns::fake_dns_backtranslate, stats::{stat_incr_num, stat_record_hist, stat_set_num}, vpn::smart_vpn_whitelist, ConnInfo
 };
 
-use super::Config;
+use super::{
+    client::{HostAction, ResolvePolicy},
+    Config,
+};
 
 pub async fn open_conn(
     ctx: &AnyCtx<Config>,
     protocol: &str,
     dest_addr: &str,
+) -> anyhow::Result<Box<dyn sillad::Pipe>> {
+    open_conn_with_rules(ctx, &RuleOverrides::default(), protocol, dest_addr).await
+}
+
+/// Like [open_conn], but applying the given listener-specific rule overrides.
+pub async fn open_conn_with_rules(
+    ctx: &AnyCtx<Config>,
+    rules: &RuleOverrides,
+    protocol: &str,
+    dest_addr: &str,
 ) -> anyhow::Result<Box<dyn sillad::Pipe>> {
     let dest_addr = if let Ok(sock_addr) = SocketAddr::from_str(dest_addr) {
         if let IpAddr::V4(v4) = sock_addr.ip() {
@@ -51,41 +68,127 @@ pub async fn open_conn(
     };
 
     if let Some((dest_host, _)) = dest_addr.rsplit_once(":") {
-        if whitelist_host(ctx, dest_host) {
-            let addrs = smol::net::resolve(&dest_addr).await?;
-            for addr in addrs.iter() {
-                smart_vpn_whitelist(ctx, addr.ip());
+        if let Some(list) = blocklist_check(ctx, dest_host) {
+            anyhow::bail!("{dest_host} is blocked by {list}");
+        }
+        match host_rule(ctx, dest_host) {
+            Some(HostAction::Block) => anyhow::bail!("{dest_host} is blocked by a host rule"),
+            Some(HostAction::Direct) => return dial_direct(ctx, &dest_addr).await,
+            Some(HostAction::Proxy) => {}
+            None => {
+                if whitelist_host(ctx, rules, dest_host) {
+                    return dial_direct(ctx, &dest_addr).await;
+                }
             }
-            tracing::debug!(
-                dest_addr = debug(dest_addr),
-                "passing through whitelisted address"
-            );
-            return Ok(sillad::tcp::HappyEyeballsTcpDialer(addrs).dial().await?);
         }
     }
 
+    let dest_addr = resolve_locally_if_needed(ctx, rules, dest_addr).await?;
+    Ok(Box::new(open_tunneled(ctx, rules, protocol, &dest_addr).await?))
+}
+
+/// Opens a stream through the tunnel, without looking at the host rules, blocklists, or split tunneling, for services of the exit itself and destinations already checked against them.
+pub async fn open_tunneled(
+    ctx: &AnyCtx<Config>,
+    rules: &RuleOverrides,
+    protocol: &str,
+    dest_addr: &str,
+) -> anyhow::Result<TrackedStream> {
     let (send, recv) = oneshot::channel();
     let elem = (format!("{protocol}${dest_addr}"), send);
     let _ = ctx.get(CONN_REQ_CHAN).0.send(elem).await;
-    let mut conn = recv.await?;
-    let ctx = ctx.clone();
-    conn.set_on_read(clone!([ctx], move |n| {
-        stat_incr_num(&ctx, "total_rx_bytes", n as _);
-        ctx.get(TRAFF_COUNT).write().unwrap().incr(n as _);
-    }));
-    conn.set_on_write(clone!([ctx], move |n| {
-        stat_incr_num(&ctx, "total_tx_bytes", n as _);
-        ctx.get(TRAFF_COUNT).write().unwrap().incr(n as _);
-    }));
-    Ok(Box::new(conn))
+    let conn = recv.await?;
+    if let Some(weight) = rules.priority {
+        conn.set_priority(weight);
+    }
+    if rules
+        .compress
+        .unwrap_or_else(|| protocol == "tcp" && is_text_heavy(dest_addr))
+    {
+        conn.set_compression(true);
+    }
+    Ok(TrackedStream::new(ctx, protocol, dest_addr, conn))
+}
+
+/// Ports of plaintext protocols that carry mostly text, which compresses well: HTTP, SMTP, POP3, IMAP, IRC, and Telnet.
+const TEXT_HEAVY_PORTS: &[u16] = &[23, 25, 80, 110, 143, 587, 6667, 8080];
+
+fn is_text_heavy(dest_addr: &str) -> bool {
+    dest_addr
+        .rsplit_once(':')
+        .and_then(|(_, port)| port.parse().ok())
+        .is_some_and(|port| TEXT_HEAVY_PORTS.contains(&port))
 }
 
+/// Finds the host rule that applies to the given hostname, if any.
+pub fn host_rule(ctx: &AnyCtx<Config>, host: &str) -> Option<HostAction> {
+    match_domain_rule(&live_config(ctx).host_rules, host).copied()
+}
 
-fn whitelist_host(ctx: &AnyCtx<Config>, host: &str) -> bool {
+/// Connects to the destination directly, without going through the tunnel.
+pub async fn dial_direct(
+    ctx: &AnyCtx<Config>,
+    dest_addr: &str,
+) -> anyhow::Result<Box<dyn sillad::Pipe>> {
+    let addrs = smol::net::resolve(dest_addr).await?;
+    for addr in addrs.iter() {
+        smart_vpn_whitelist(ctx, addr.ip());
+    }
+    tracing::debug!(dest_addr = debug(dest_addr), "passing through address");
+    Ok(sillad::tcp::HappyEyeballsTcpDialer {
+        addrs,
+        policy: live_config(ctx).happy_eyeballs,
+    }
+    .dial()
+    .await?)
+}
+
+/// Applies the per-domain resolve policy, turning the hostname into an IP address if it should be resolved locally.
+async fn resolve_locally_if_needed(
+    ctx: &AnyCtx<Config>,
+    rules: &RuleOverrides,
+    dest_addr: String,
+) -> anyhow::Result<String> {
+    let Some((dest_host, _)) = dest_addr.rsplit_once(':') else {
+        return Ok(dest_addr);
+    };
+    if dest_host.parse::<IpAddr>().is_ok() || dest_host.contains('[') {
+        return Ok(dest_addr);
+    }
+    let config = live_config(ctx);
+    let policy = rules
+        .resolve_policy
+        .as_ref()
+        .unwrap_or(&config.resolve_policy);
+    match match_domain_rule(policy, dest_host) {
+        Some(ResolvePolicy::Local) => {
+            let addrs = smol::net::resolve(&dest_addr)
+                .await
+                .context(format!("could not locally resolve {dest_addr}"))?;
+            let addr = addrs
+                .iter()
+                .find(|addr| addr.is_ipv4())
+                .or_else(|| addrs.first())
+                .context(format!("no addresses for {dest_addr}"))?;
+            tracing::debug!(
+                dest_addr = display(&dest_addr),
+                resolved = display(addr),
+                "resolved locally by policy"
+            );
+            Ok(addr.to_string())
+        }
+        _ => Ok(dest_addr),
+    }
+}
+
+fn whitelist_host(ctx: &AnyCtx<Config>, rules: &RuleOverrides, host: &str) -> bool {
     if host.is_empty() || host.contains("[") {
         return false;
     }
-    if ctx.init().passthrough_china && is_chinese_host(host) {
+    let passthrough_china = rules
+        .passthrough_china
+        .unwrap_or(live_config(ctx).passthrough_china);
+    if passthrough_china && is_chinese_host(host) {
         return true;
     }
     if let Ok(ip) = IpAddr::from_str(host) {
@@ -103,6 +206,9 @@ fn whitelist_host(ctx: &AnyCtx<Config>, host: &str) -> bool {
 
 type ChanElem = (String, oneshot::Sender<picomux::Stream>);
 
+/// How long we wait before handing a connection request to another tunnel after the exit refused it for having too many streams, so that we do not spin when every tunnel is full.
+const STREAM_LIMIT_BACKOFF: Duration = Duration::from_millis(100);
+
 static CONN_REQ_CHAN: CtxField<(
     smol::channel::Sender<ChanElem>,
     smol::channel::Receiver<ChanElem>,
@@ -113,10 +219,13 @@ static CONN_REQ_CHAN: CtxField<(
 
 pub static CONCURRENCY: usize = 3;
 
+/// The longest a tunnel to a previously selected exit lingers after an exit switch, so that streams already open through it can finish.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(600);
+
 #[tracing::instrument(skip_all)]
 pub async fn client_inner(ctx: AnyCtx<Config>) -> Infallible {
     tracing::info!("(re)starting main logic");
-    *ctx.get(CURRENT_CONN_INFO).lock() = ConnInfo::Connecting;
+    set_conn_info(&ctx, ConnInfo::Connecting);
 
     let start = Instant::now();
 
@@ -129,8 +238,9 @@ pub async fn client_inner(ctx: AnyCtx<Config>) -> Infallible {
         smolscale::spawn(async move {
             loop {
                 let once = async {
-                    *ctx.get(CURRENT_CONN_INFO).lock() = ConnInfo::Connecting;
-                    let (authed_pipe, exit) = async {
+                    set_conn_info(&ctx, ConnInfo::Connecting);
+                    let generation = exit_generation(&ctx);
+                    let (authed_pipe, limits, exit) = async {
                         let (pubkey, exit, raw_dialer) = get_dialer(&ctx).await?;
                         let start = Instant::now();
                         let raw_pipe = raw_dialer.dial().await.context("could not dial")?;
@@ -140,35 +250,48 @@ pub async fn client_inner(ctx: AnyCtx<Config>) -> Infallible {
                             "dial completed"
                         );
 
-                        let authed_pipe = client_auth(&ctx, raw_pipe, pubkey)
+                        let (authed_pipe, limits) = client_auth(&ctx, raw_pipe, pubkey)
                             .await
                             .context("could not client auth")?;
+                        *ctx.get(CURRENT_EXIT).lock() = Some(pubkey);
 
                         tracing::debug!(
                             elapsed = debug(start.elapsed()),
                             "authentication done, starting mux system"
                         );
-                        anyhow::Ok((authed_pipe, exit))
+                        anyhow::Ok((authed_pipe, limits, exit))
                     }
                     .timeout(Duration::from_secs(30))
                     .await
-                    .context("overall dial/mux/auth timeout")??;
-
-                    *ctx.get(CURRENT_CONN_INFO).lock() = ConnInfo::Connected(ConnectedInfo {
-                        protocol: authed_pipe.protocol().to_string(),
-                        bridge: authed_pipe
-                            .remote_addr()
-                            .map(|s| s.to_string())
-                            .unwrap_or_default(),
-                        exit: exit.clone(),
-                    });
+                    .context("overall dial/mux/auth timeout")
+                    .and_then(|res| res)
+                    .inspect_err(|_| stat_incr_num(&ctx, "dial_failures", 1.0))?;
+
+                    set_conn_info(
+                        &ctx,
+                        ConnInfo::Connected(ConnectedInfo {
+                            protocol: authed_pipe.protocol().to_string(),
+                            bridge: authed_pipe
+                                .remote_addr()
+                                .map(|s| s.to_string())
+                                .unwrap_or_default(),
+                            exit: exit.clone(),
+                            limits,
+                        }),
+                    );
                     let addr: SocketAddr = authed_pipe.remote_addr().unwrap_or("").parse()?;
-                    proxy_loop(ctx.clone(), authed_pipe, instance)
+                    proxy_loop(ctx.clone(), authed_pipe, instance, generation)
                         .await
                         .context(format!("inner connection to {addr} failed"))
 
                 };
                 if let Err(err) = once.await {
+                    push_event(
+                        &ctx,
+                        ConnEvent::Disconnected {
+                            reason: format!("{err:#}"),
+                        },
+                    );
                     let wait_time = Duration::from_secs_f64(rand::thread_rng().gen_range(1.0..10.0));
                     tracing::warn!(instance, err = debug(err), wait_time=debug(wait_time), "individual client thread failed");
                     smol::Timer::after(wait_time).await;
@@ -197,9 +320,10 @@ async fn proxy_loop(
     ctx: AnyCtx<Config>,
     authed_pipe: impl Pipe,
     instance: usize,
+    generation: u64,
 ) -> anyhow::Result<()> {
     let (read, write) = authed_pipe.split();
-    let mut mux = PicoMux::new(read, write);
+    let mut mux = PicoMux::with_windows(read, write, live_config(ctx).mux_windows);
     mux.set_liveness(LivenessConfig {
         ping_interval: Duration::from_secs(1800),
         timeout: Duration::from_secs(3),
@@ -220,12 +344,22 @@ async fn proxy_loop(
                 }
                 spawn!(async move {
                     tracing::debug!(remote_addr = display(&remote_addr), "opening tunnel");
+                    let start = Instant::now();
                     let stream = mux.open(remote_addr.as_bytes()).await;
                     match stream {
                         Ok(stream) => {
+                            stat_record_hist(&ctx, "stream_open_time", start.elapsed().as_secs_f64());
+                            record_stream_open(&ctx, true);
                             let _ = send_back.send(stream);
                         }
+                        Err(err) if err.kind() == std::io::ErrorKind::ConnectionRefused => {
+                            // the exit's stream limit is reached, which is no fault of the session
+                            tracing::debug!(remote_addr = display(&remote_addr), "stream limit reached, hot-potatoing the connection request to somebody else");
+                            smol::Timer::after(STREAM_LIMIT_BACKOFF).await;
+                            let _ = ctx.get(CONN_REQ_CHAN).0.try_send((remote_addr, send_back));
+                        }
                         Err(err) => {
+                            record_stream_open(&ctx, false);
                             tracing::warn!(remote_addr = display(&remote_addr), err = debug(&err), "session is dead, hot-potatoing the connection request to somebody else");
                             let _ = ctx.get(CONN_REQ_CHAN).0.try_send((remote_addr, send_back));
                         }
@@ -237,25 +371,93 @@ async fn proxy_loop(
             }
         })
     }.or(mux.wait_until_dead())
+    .or(async {
+        // once the exit changes, we stop taking new streams but let the old tunnel drain in the background
+        wait_exit_change(&ctx, generation).await;
+        tracing::info!(instance, "exit changed, draining old tunnel");
+        drain_in_background(mux.clone());
+        Ok(())
+    })
+    .or(async {
+        // same when the exit is about to restart, so that we reconnect while its streams finish
+        mux.wait_goaway().await;
+        tracing::info!(instance, "exit is going away, draining old tunnel");
+        drain_in_background(mux.clone());
+        Ok(())
+    })
     .await
 }
 
+/// Stops opening streams on a tunnel, telling the exit so, but lets the streams already on it finish in the background. The tunnel closes once they have, or after the drain timeout at the latest.
+fn drain_in_background(mux: Arc<PicoMux>) {
+    mux.go_away();
+    smolscale::spawn(async move {
+        let _ = mux
+            .wait_idle()
+            .or(async {
+                let _ = mux.wait_until_dead().await;
+            })
+            .timeout(DRAIN_TIMEOUT)
+            .await;
+    })
+    .detach();
+}
+
+/// The exit our tunnels go to, once we have authenticated with it.
+static CURRENT_EXIT: CtxField<parking_lot::Mutex<Option<VerifyingKey>>> =
+    |_| parking_lot::Mutex::new(None);
+
+/// Whether the exit our tunnels go to can reach IPv6 destinations, going by what the broker says.
+pub async fn current_exit_has_ipv6(ctx: &AnyCtx<Config>) -> bool {
+    let Some(pubkey) = *ctx.get(CURRENT_EXIT).lock() else {
+        return false;
+    };
+    ipv6_exits(ctx).await.contains(&pubkey)
+}
+
+/// Exits that did not understand our request for session limits, which we no longer ask.
+static EXITS_WITHOUT_LIMITS: CtxField<parking_lot::Mutex<HashSet<VerifyingKey>>> =
+    |_| parking_lot::Mutex::new(HashSet::new());
+
 #[tracing::instrument(skip_all, fields(pubkey = hex::encode(pubkey.as_bytes())))]
 async fn client_auth(
     ctx: &AnyCtx<Config>,
     mut pipe: impl Pipe,
     pubkey: VerifyingKey,
-) -> anyhow::Result<impl Pipe> {
+) -> anyhow::Result<(impl Pipe, Option<SessionLimits>)> {
     let server = pipe.remote_addr().unwrap_or("").to_string();
 
     let credentials = if ctx.init().broker.is_none() {
         Bytes::new()
     } else {
-        let (level, token, sig) = get_connect_token(ctx)
+        let credential = get_connect_token(ctx)
             .await
             .context("cannot get connect token")?;
-        tracing::info!(level=debug(level), "authentication with a connect token");
-        (level, token, sig).stdcode().into()
+        tracing::info!(level=debug(credential.level), "authentication with a connect token");
+        credential.stdcode().into()
+    };
+    let shaping = live_config(ctx).shaping;
+    if let Some(params) = shaping {
+        anyhow::ensure!(params.is_sane(), "unreasonable shaping parameters {params:?}");
+    }
+    // exits that predate session limits hang up on requests for them, after which we stop asking
+    let ask_limits = !ctx.get(EXITS_WITHOUT_LIMITS).lock().contains(&pubkey);
+    let shaped_hello = |crypt_hello: ClientCryptHello| {
+        let crypt_hello = match shaping {
+            Some(params) => ClientCryptHello::Shaped(params, Box::new(crypt_hello)),
+            None => crypt_hello,
+        };
+        if ask_limits {
+            ClientCryptHello::AskLimits(Box::new(crypt_hello))
+        } else {
+            crypt_hello
+        }
+    };
+    let read_exit_hello = |raw: std::io::Result<Vec<u8>>| {
+        if raw.is_err() && ask_limits {
+            ctx.get(EXITS_WITHOUT_LIMITS).lock().insert(pubkey);
+        }
+        anyhow::Ok(stdcode::deserialize::<ExitHello>(&raw?)?)
     };
     match pipe.shared_secret().map(|s| s.to_owned()) {
         Some(ss) => {
@@ -263,19 +465,19 @@ async fn client_auth(
             let challenge = rand::random();
             let client_hello = ClientHello {
                 credentials,
-                crypt_hello: ClientCryptHello::SharedSecretChallenge(challenge),
+                crypt_hello: shaped_hello(ClientCryptHello::SharedSecretChallenge(challenge)),
             };
             write_prepend_length(&client_hello.stdcode(), &mut pipe).await?;
 
             let mac = blake3::keyed_hash(&challenge, &ss);
-            let exit_response: ExitHello =
-                stdcode::deserialize(&read_prepend_length(&mut pipe).await?)
-                    .context("cannot deserialize exit hello")?;
-            match exit_response.inner {
+            let exit_response = read_exit_hello(read_prepend_length(&mut pipe).await)
+                .context("cannot deserialize exit hello")?;
+            let (inner, limits) = exit_response.inner.without_limits();
+            match inner {
                 ExitHelloInner::SharedSecretResponse(response_mac) => {
                     if mac == response_mac {
                         tracing::debug!(server, "authentication successful with shared secret");
-                        Ok(EitherPipe::Left(pipe))
+                        Ok((EitherPipe::Left(shaped_pipe(pipe, shaping)), limits))
                     } else {
                         anyhow::bail!("authentication failed with shared secret");
                     }
@@ -288,20 +490,27 @@ async fn client_auth(
             let my_esk = x25519_dalek::EphemeralSecret::random_from_rng(rand::thread_rng());
             let client_hello = ClientHello {
                 credentials,
-                crypt_hello: ClientCryptHello::X25519((&my_esk).into()),
+                crypt_hello: shaped_hello(ClientCryptHello::X25519((&my_esk).into())),
             };
             write_prepend_length(&client_hello.stdcode(), &mut pipe).await?;
             tracing::trace!(server, "wrote client hello");
-            let exit_hello: ExitHello =
-                stdcode::deserialize(&read_prepend_length(&mut pipe).await?)
-                    .context("could not deserialize exit hello")?;
+            let exit_hello = read_exit_hello(read_prepend_length(&mut pipe).await)
+                .context("could not deserialize exit hello")?;
             tracing::trace!(server, "received exit hello");
             // verify the exit hello
             let signed_value = (&client_hello, &exit_hello.inner).stdcode();
             pubkey
                 .verify_strict(&signed_value, &exit_hello.signature)
                 .context("exit hello failed validation")?;
-            match exit_hello.inner {
+            let (inner, limits) = exit_hello.inner.without_limits();
+            if let Some(limits) = limits {
+                tracing::debug!(
+                    server,
+                    limits = debug(limits),
+                    "exit told us our session limits"
+                );
+            }
+            match inner {
                 ExitHelloInner::Reject(reason) => {
                     anyhow::bail!("exit rejected our authentication attempt: {reason}")
                 }
@@ -314,11 +523,25 @@ async fn client_auth(
                     let shared_secret = my_esk.diffie_hellman(&their_epk);
                     let read_key = blake3::derive_key("e2c", shared_secret.as_bytes());
                     let write_key = blake3::derive_key("c2e", shared_secret.as_bytes());
-                    Ok(EitherPipe::Right(ClientExitCryptPipe::new(
-                        pipe, read_key, write_key,
-                    )))
+                    Ok((
+                        EitherPipe::Right(ClientExitCryptPipe::new(
+                            shaped_pipe(pipe, shaping),
+                            read_key,
+                            write_key,
+                        )),
+                        limits,
+                    ))
                 }
+                ExitHelloInner::Limited(..) => anyhow::bail!("exit sent session limits twice"),
             }
         }
     }
 }
+
+/// Wraps the pipe to the exit in the shaping layer, if shaping is on. Shaping goes beneath the exit's encryption, so that the transport only ever carries fixed-size frames, though how it turns them into packets is still up to the transport.
+fn shaped_pipe(pipe: impl Pipe, shaping: Option<ShapingParams>) -> Box<dyn Pipe> {
+    match shaping {
+        Some(params) => Box::new(ShapedPipe::new(pipe, params)),
+        None => Box::new(pipe),
+    }
+}
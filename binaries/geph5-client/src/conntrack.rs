@@ -0,0 +1,294 @@
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::SystemTime,
+};
+
+use anyctx::AnyCtx;
+use bytes::Bytes;
+use clone_macro::clone;
+use dashmap::DashMap;
+use futures_util::{task::AtomicWaker, AsyncRead, AsyncWrite};
+use geph5_misc_rpc::exit::StreamRejection;
+use serde::{Deserialize, Serialize};
+use sillad::Pipe;
+
+use crate::{
+    accounting::domain_counter,
+    client::CtxField,
+    stats::{stat_incr_num, stat_record_hist},
+    traffcount::TRAFF_COUNT,
+    Config,
+};
+
+/// Information about an open tunneled stream.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StreamInfo {
+    pub id: u64,
+    pub protocol: String,
+    pub dest: String,
+    pub start_time: SystemTime,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    /// How many times sending on the stream had to wait for the exit to raise its flow-control window.
+    #[serde(default)]
+    pub window_stalls: u64,
+    /// Round-trip time to the exit as seen by this stream, known once the stream has stalled at least once.
+    #[serde(default)]
+    pub rtt_ms: Option<f64>,
+}
+
+struct StreamEntry {
+    protocol: String,
+    dest: String,
+    start_time: SystemTime,
+    rx_bytes: AtomicU64,
+    tx_bytes: AtomicU64,
+    killed: AtomicBool,
+    read_waker: AtomicWaker,
+    write_waker: AtomicWaker,
+    mux_stats: Arc<picomux::StreamStats>,
+}
+
+static STREAM_TABLE: CtxField<DashMap<u64, Arc<StreamEntry>>> = |_| DashMap::new();
+
+static NEXT_STREAM_ID: CtxField<AtomicU64> = |_| AtomicU64::new(0);
+
+/// Counts bytes going one way through a tracked stream.
+type Counter = Arc<dyn Fn(usize) + Send + Sync>;
+
+/// A tunneled stream that is listed in the connection table for as long as it lives.
+pub struct TrackedStream {
+    ctx: AnyCtx<Config>,
+    id: u64,
+    entry: Arc<StreamEntry>,
+    inner: picomux::Stream,
+    count_rx: Counter,
+    count_tx: Counter,
+}
+
+impl TrackedStream {
+    /// Registers the stream in the connection table, and hooks it up to the traffic counters.
+    pub fn new(
+        ctx: &AnyCtx<Config>,
+        protocol: &str,
+        dest: &str,
+        mut inner: picomux::Stream,
+    ) -> Self {
+        let id = ctx.get(NEXT_STREAM_ID).fetch_add(1, Ordering::Relaxed);
+        let entry = Arc::new(StreamEntry {
+            protocol: protocol.to_string(),
+            dest: dest.to_string(),
+            start_time: SystemTime::now(),
+            rx_bytes: AtomicU64::new(0),
+            tx_bytes: AtomicU64::new(0),
+            killed: AtomicBool::new(false),
+            read_waker: AtomicWaker::new(),
+            write_waker: AtomicWaker::new(),
+            mux_stats: inner.stats(),
+        });
+        let domain_counter = domain_counter(ctx, dest);
+        let count_rx: Counter = Arc::new(clone!([ctx, entry, domain_counter], move |n| {
+            entry.rx_bytes.fetch_add(n as _, Ordering::Relaxed);
+            if let Some(counter) = &domain_counter {
+                counter.incr_rx(n);
+            }
+            stat_incr_num(&ctx, "total_rx_bytes", n as _);
+            ctx.get(TRAFF_COUNT).write().unwrap().incr(n as _);
+        }));
+        let count_tx: Counter = Arc::new(clone!([ctx, entry, domain_counter], move |n| {
+            entry.tx_bytes.fetch_add(n as _, Ordering::Relaxed);
+            if let Some(counter) = &domain_counter {
+                counter.incr_tx(n);
+            }
+            stat_incr_num(&ctx, "total_tx_bytes", n as _);
+            ctx.get(TRAFF_COUNT).write().unwrap().incr(n as _);
+        }));
+        inner.set_on_read(clone!([count_rx], move |n| count_rx(n)));
+        inner.set_on_write(clone!([count_tx], move |n| count_tx(n)));
+        inner.set_on_blocked(clone!([ctx], move |blocked| {
+            tracing::debug!(blocked = debug(blocked), "stream blocked on flow control");
+            stat_incr_num(&ctx, "blocked_streams", 1.0);
+        }));
+        ctx.get(STREAM_TABLE).insert(id, entry.clone());
+        stat_incr_num(ctx, "open_streams", 1.0);
+        Self {
+            ctx: ctx.clone(),
+            id,
+            entry,
+            inner,
+            count_rx,
+            count_tx,
+        }
+    }
+
+    /// The datagrams of the stream, counted like its data.
+    pub fn datagrams(&self) -> TrackedDatagrams {
+        TrackedDatagrams {
+            inner: self.inner.datagrams(),
+            count_rx: self.count_rx.clone(),
+            count_tx: self.count_tx.clone(),
+        }
+    }
+
+    fn check_killed(&self) -> std::io::Result<()> {
+        if self.entry.killed.load(Ordering::SeqCst) {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionAborted,
+                "stream was killed",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Fails with the exit's reason if the exit closed the stream because it refused to proxy it, which it tells TCP streams in a datagram right before closing them.
+    fn check_rejected(&self) -> std::io::Result<()> {
+        if self.entry.protocol != "tcp" {
+            return Ok(());
+        }
+        let Some(rejection) = self
+            .inner
+            .datagrams()
+            .try_recv()
+            .and_then(|dgram| stdcode::deserialize::<StreamRejection>(&dgram).ok())
+        else {
+            return Ok(());
+        };
+        tracing::warn!(dest = display(&self.entry.dest), "{rejection}");
+        Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            rejection,
+        ))
+    }
+}
+
+/// The datagrams of a [`TrackedStream`], which go through the same traffic counters as its data.
+#[derive(Clone)]
+pub struct TrackedDatagrams {
+    inner: picomux::Datagrams,
+    count_rx: Counter,
+    count_tx: Counter,
+}
+
+impl TrackedDatagrams {
+    /// Whether the exit understands datagrams.
+    pub fn is_supported(&self) -> bool {
+        self.inner.is_supported()
+    }
+
+    /// Sends a datagram, which may be silently dropped.
+    pub fn send(&self, body: &[u8]) -> std::io::Result<()> {
+        self.inner.send(body)?;
+        (self.count_tx)(body.len());
+        Ok(())
+    }
+
+    /// Receives the next datagram. Fails once the stream is gone.
+    pub async fn recv(&self) -> std::io::Result<Bytes> {
+        let body = self.inner.recv().await?;
+        (self.count_rx)(body.len());
+        Ok(body)
+    }
+}
+
+impl Drop for TrackedStream {
+    fn drop(&mut self) {
+        self.ctx.get(STREAM_TABLE).remove(&self.id);
+        stat_incr_num(&self.ctx, "open_streams", -1.0);
+        let mux_stats = &self.entry.mux_stats;
+        stat_incr_num(&self.ctx, "window_stalls", mux_stats.window_stalls() as _);
+        if let Some(rtt) = mux_stats.rtt() {
+            stat_record_hist(&self.ctx, "stream_rtt", rtt.as_secs_f64());
+        }
+    }
+}
+
+impl AsyncRead for TrackedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.entry.read_waker.register(cx.waker());
+        self.check_killed()?;
+        let n = futures_util::ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        if n == 0 && !buf.is_empty() {
+            self.check_rejected()?;
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for TrackedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.entry.write_waker.register(cx.waker());
+        self.check_killed()?;
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+impl Pipe for TrackedStream {
+    fn protocol(&self) -> &str {
+        self.inner.protocol()
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        self.inner.remote_addr()
+    }
+
+    fn poll_close_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close_write(cx)
+    }
+}
+
+/// Lists all the open tunneled streams, oldest first.
+pub fn list_streams(ctx: &AnyCtx<Config>) -> Vec<StreamInfo> {
+    let mut streams: Vec<StreamInfo> = ctx
+        .get(STREAM_TABLE)
+        .iter()
+        .map(|entry| StreamInfo {
+            id: *entry.key(),
+            protocol: entry.protocol.clone(),
+            dest: entry.dest.clone(),
+            start_time: entry.start_time,
+            rx_bytes: entry.rx_bytes.load(Ordering::Relaxed),
+            tx_bytes: entry.tx_bytes.load(Ordering::Relaxed),
+            window_stalls: entry.mux_stats.window_stalls(),
+            rtt_ms: entry.mux_stats.rtt().map(|rtt| rtt.as_secs_f64() * 1000.0),
+        })
+        .collect();
+    streams.sort_by_key(|info| info.id);
+    streams
+}
+
+/// Kills an open tunneled stream, making all further reads and writes on it fail. Returns whether the stream existed.
+pub fn kill_stream(ctx: &AnyCtx<Config>, id: u64) -> bool {
+    if let Some(entry) = ctx.get(STREAM_TABLE).get(&id) {
+        entry.killed.store(true, Ordering::SeqCst);
+        entry.read_waker.wake();
+        entry.write_waker.wake();
+        true
+    } else {
+        false
+    }
+}
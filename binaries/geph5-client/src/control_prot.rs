@@ -7,7 +7,10 @@ use std::{
 use anyctx::AnyCtx;
 use async_trait::async_trait;
 use chrono::{NaiveDate, NaiveDateTime};
-use geph5_broker_protocol::{puzzle::solve_puzzle, AccountLevel, ExitDescriptor, VoucherInfo};
+use geph5_broker_protocol::{
+    puzzle::solve_puzzle, AccountLevel, ExitDescriptor, TrustInfo, VoucherInfo,
+};
+use geph5_misc_rpc::exit::SessionLimits;
 
 use nanorpc::{nanorpc_derive, JrpcRequest, JrpcResponse, RpcService, RpcTransport};
 use parking_lot::Mutex;
@@ -16,8 +19,25 @@ use slab::Slab;
 use tap::Tap;
 
 use crate::{
-    broker_client, client::CtxField, logging::get_json_logs, stats::stat_get_num,
-    traffcount::TRAFF_COUNT, updates::get_update_manifest, Config,
+    accounting::{top_domains, DomainUsage},
+    announcements::{list_announcements, mark_announcement_read, AnnouncementInfo},
+    broker_client,
+    client::CtxField,
+    conntrack::{kill_stream, list_streams, StreamInfo},
+    debug_bundle::debug_bundle,
+    dial_stats::{dial_stats, DialStats},
+    events::{wait_events, TimedConnEvent},
+    get_dialer::set_exit_constraint,
+    logging::{get_json_logs, get_last_log_lines, set_log_filter},
+    profiles::{list_profiles, switch_profile},
+    reload::live_config,
+    speedtest::{speed_test, SpeedTestResult},
+    stats::{stat_get_hist, stat_get_num, HistogramSummary},
+    traffcount::TRAFF_COUNT,
+    updates::get_update_manifest,
+    usage::{usage_history, UsageGranularity, UsageRecord},
+    vpn::set_vpn_enabled,
+    Config, ExitConstraint,
 };
 
 #[nanorpc_derive]
@@ -25,10 +45,42 @@ use crate::{
 pub trait ControlProtocol {
     async fn conn_info(&self) -> ConnInfo;
     async fn stat_num(&self, stat: String) -> f64;
+    /// Summarizes a latency histogram, such as "tunnel_rtt" or "stream_open_time", with values in seconds.
+    async fn stat_hist(&self, stat: String) -> Option<HistogramSummary>;
     async fn start_time(&self) -> SystemTime;
     async fn stop(&self);
+    /// Switches to a different exit without restarting, keeping all the local listeners up.
+    async fn set_exit(&self, constraint: ExitConstraint);
+    /// Turns VPN mode on or off without restarting.
+    async fn set_vpn(&self, enabled: bool);
+    /// Lists the named config profiles.
+    async fn list_profiles(&self) -> Vec<String>;
+    /// The profile currently in effect, if any.
+    async fn current_profile(&self) -> Option<String>;
+    /// Switches to the named config profile without restarting, or back to the base config if None.
+    async fn set_profile(&self, name: Option<String>) -> Result<(), String>;
 
     async fn recent_logs(&self) -> Vec<String>;
+    /// Fetches the last `limit` lines from the in-memory log buffer.
+    async fn last_log_lines(&self, limit: usize) -> Vec<String>;
+    /// Produces a JSON debug bundle, with secrets redacted, for attaching to bug reports.
+    async fn debug_bundle(&self) -> Result<String, String>;
+    /// Changes the log filter at runtime, using the same syntax as RUST_LOG (e.g. "geph=trace").
+    async fn set_log_filter(&self, filter: String) -> Result<(), String>;
+
+    /// Long-polls for connection events with sequence numbers after `after`, or for new events if `after` is None. Returns an empty list if nothing happens within about 30 seconds.
+    async fn next_events(&self, after: Option<u64>) -> Vec<TimedConnEvent>;
+
+    /// Lists every open tunneled stream.
+    async fn open_streams(&self) -> Vec<StreamInfo>;
+    /// Kills a tunneled stream, returning false if there was no such stream.
+    async fn kill_stream(&self, id: u64) -> bool;
+    /// Lists the destination hosts that used the most tunnel traffic since startup.
+    async fn top_domains(&self, limit: usize) -> Vec<DomainUsage>;
+    /// Measures latency and throughput through the tunnel, against the exit's measurement endpoint. Takes about 10 seconds.
+    async fn speed_test(&self) -> Result<SpeedTestResult, String>;
+    /// Per-protocol dial attempts, successes, failure causes, and dial times since startup, for telling which transports the network blocks.
+    async fn dial_stats(&self) -> Vec<DialStats>;
 
     // broker-proxying stuff
 
@@ -42,9 +94,19 @@ pub trait ControlProtocol {
         password: String,
     ) -> Result<String, String>;
     async fn stat_history(&self, stat: String) -> Result<Vec<f64>, String>;
+    /// Reads the persistent tunnel usage history between two UNIX timestamps.
+    async fn usage_history(
+        &self,
+        start_unix: u64,
+        end_unix: u64,
+        granularity: UsageGranularity,
+    ) -> Result<Vec<UsageRecord>, String>;
     async fn exit_list(&self) -> Result<Vec<ExitDescriptor>, String>;
     async fn free_exit_list(&self) -> Result<Vec<ExitDescriptor>, String>;
     async fn latest_news(&self, lang: String) -> Result<Vec<NewsItem>, String>;
+    /// Lists the broker's current announcements, newest first, with their read state.
+    async fn announcements(&self) -> Result<Vec<AnnouncementInfo>, String>;
+    async fn mark_announcement_read(&self, id: String) -> Result<(), String>;
     async fn price_points(&self) -> Result<Vec<(u32, f64)>, String>;
     async fn payment_methods(&self) -> Result<Vec<String>, String>;
     async fn create_payment(
@@ -55,6 +117,9 @@ pub trait ControlProtocol {
     ) -> Result<String, String>;
     async fn get_free_voucher(&self, secret: String) -> Result<Option<VoucherInfo>, String>;
     async fn redeem_voucher(&self, secret: String, code: String) -> Result<i32, String>;
+    async fn trust_info(&self, secret: String) -> Result<Option<TrustInfo>, String>;
+    async fn issue_invite(&self, secret: String) -> Result<String, String>;
+    async fn redeem_invite(&self, secret: String, code: String) -> Result<TrustInfo, String>;
     async fn export_debug_pack(
         &self,
         email: Option<String>,
@@ -78,6 +143,9 @@ pub struct ConnectedInfo {
     pub bridge: String,
 
     pub exit: ExitDescriptor,
+    /// The limits the exit puts on our session, if it told us.
+    #[serde(default)]
+    pub limits: Option<SessionLimits>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -114,6 +182,10 @@ impl ControlProtocol for ControlProtocolImpl {
         stat_get_num(&self.ctx, &stat)
     }
 
+    async fn stat_hist(&self, stat: String) -> Option<HistogramSummary> {
+        stat_get_hist(&self.ctx, &stat)
+    }
+
     async fn start_time(&self) -> SystemTime {
         static START_TIME: CtxField<SystemTime> = |_| SystemTime::now();
         *self.ctx.get(START_TIME)
@@ -126,10 +198,66 @@ impl ControlProtocol for ControlProtocolImpl {
         });
     }
 
+    async fn set_exit(&self, constraint: ExitConstraint) {
+        set_exit_constraint(&self.ctx, constraint)
+    }
+
+    async fn set_vpn(&self, enabled: bool) {
+        set_vpn_enabled(&self.ctx, enabled)
+    }
+
+    async fn list_profiles(&self) -> Vec<String> {
+        list_profiles(&self.ctx)
+    }
+
+    async fn current_profile(&self) -> Option<String> {
+        live_config(&self.ctx).profile.clone()
+    }
+
+    async fn set_profile(&self, name: Option<String>) -> Result<(), String> {
+        switch_profile(&self.ctx, name).map_err(|e| format!("{:?}", e))
+    }
+
     async fn recent_logs(&self) -> Vec<String> {
         get_json_logs().split("\n").map(|s| s.to_string()).collect()
     }
 
+    async fn last_log_lines(&self, limit: usize) -> Vec<String> {
+        get_last_log_lines(limit)
+    }
+
+    async fn debug_bundle(&self) -> Result<String, String> {
+        debug_bundle(&self.ctx).map_err(|e| format!("{:?}", e))
+    }
+
+    async fn set_log_filter(&self, filter: String) -> Result<(), String> {
+        set_log_filter(&filter).map_err(|e| format!("{:?}", e))
+    }
+
+    async fn next_events(&self, after: Option<u64>) -> Vec<TimedConnEvent> {
+        wait_events(&self.ctx, after, Duration::from_secs(30)).await
+    }
+
+    async fn open_streams(&self) -> Vec<StreamInfo> {
+        list_streams(&self.ctx)
+    }
+
+    async fn kill_stream(&self, id: u64) -> bool {
+        kill_stream(&self.ctx, id)
+    }
+
+    async fn top_domains(&self, limit: usize) -> Vec<DomainUsage> {
+        top_domains(&self.ctx, limit)
+    }
+
+    async fn speed_test(&self) -> Result<SpeedTestResult, String> {
+        speed_test(&self.ctx).await.map_err(|e| format!("{:?}", e))
+    }
+
+    async fn dial_stats(&self) -> Vec<DialStats> {
+        dial_stats(&self.ctx)
+    }
+
     async fn check_secret(&self, secret: String) -> Result<bool, String> {
         let res = broker_client(&self.ctx)
             .map_err(|e| format!("{:?}", e))?
@@ -240,6 +368,17 @@ impl ControlProtocol for ControlProtocolImpl {
         Ok(self.ctx.get(TRAFF_COUNT).read().unwrap().speed_history())
     }
 
+    async fn usage_history(
+        &self,
+        start_unix: u64,
+        end_unix: u64,
+        granularity: UsageGranularity,
+    ) -> Result<Vec<UsageRecord>, String> {
+        usage_history(&self.ctx, start_unix, end_unix, granularity)
+            .await
+            .map_err(|e| format!("{:?}", e))
+    }
+
     async fn exit_list(&self) -> Result<Vec<ExitDescriptor>, String> {
         let resp = broker_client(&self.ctx)
             .map_err(|e| format!("{:?}", e))?
@@ -309,6 +448,18 @@ impl ControlProtocol for ControlProtocolImpl {
         Ok(out)
     }
 
+    async fn announcements(&self) -> Result<Vec<AnnouncementInfo>, String> {
+        list_announcements(&self.ctx)
+            .await
+            .map_err(|e| format!("{:?}", e))
+    }
+
+    async fn mark_announcement_read(&self, id: String) -> Result<(), String> {
+        mark_announcement_read(&self.ctx, id)
+            .await
+            .map_err(|e| format!("{:?}", e))
+    }
+
     async fn get_free_voucher(&self, secret: String) -> Result<Option<VoucherInfo>, String> {
         let client = broker_client(&self.ctx).map_err(|e| format!("{:?}", e))?;
         Ok(client
@@ -329,6 +480,33 @@ impl ControlProtocol for ControlProtocolImpl {
             .map_err(|s| s.to_string())
     }
 
+    async fn trust_info(&self, secret: String) -> Result<Option<TrustInfo>, String> {
+        let client = broker_client(&self.ctx).map_err(|e| format!("{:?}", e))?;
+        client
+            .get_trust_info(secret)
+            .await
+            .map_err(|s| s.to_string())?
+            .map_err(|s| s.to_string())
+    }
+
+    async fn issue_invite(&self, secret: String) -> Result<String, String> {
+        let client = broker_client(&self.ctx).map_err(|e| format!("{:?}", e))?;
+        client
+            .issue_invite(secret)
+            .await
+            .map_err(|s| s.to_string())?
+            .map_err(|s| s.to_string())
+    }
+
+    async fn redeem_invite(&self, secret: String, code: String) -> Result<TrustInfo, String> {
+        let client = broker_client(&self.ctx).map_err(|e| format!("{:?}", e))?;
+        client
+            .redeem_invite(secret, code)
+            .await
+            .map_err(|s| s.to_string())?
+            .map_err(|s| s.to_string())
+    }
+
     async fn price_points(&self) -> Result<Vec<(u32, f64)>, String> {
         let client = broker_client(&self.ctx).map_err(|e| format!("{:?}", e))?;
         Ok(client
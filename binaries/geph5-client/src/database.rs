@@ -7,7 +7,7 @@ use stdcode::StdcodeSerializeExt;
 
 use crate::client::{Config, CtxField};
 
-static DATABASE: CtxField<SqlitePool> = |ctx| {
+pub static DATABASE: CtxField<SqlitePool> = |ctx| {
     // TODO this somehow does not make all the connections share the same db?
     let db_path = ctx
         .init()
@@ -47,6 +47,19 @@ static DATABASE: CtxField<SqlitePool> = |ctx| {
         .await
         .unwrap();
 
+        for table in ["usage_hourly", "usage_daily"] {
+            sqlx::query(&format!(
+                "CREATE TABLE IF NOT EXISTS {table} (
+                    period INTEGER PRIMARY KEY,
+                    rx_bytes INTEGER NOT NULL,
+                    tx_bytes INTEGER NOT NULL
+                );"
+            ))
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
         pool
     })
 };
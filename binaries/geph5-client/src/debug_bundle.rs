@@ -0,0 +1,149 @@
+use std::{net::SocketAddr, time::SystemTime};
+
+use anyctx::AnyCtx;
+use serde_json::{json, Value};
+
+use crate::{
+    control_prot::CURRENT_CONN_INFO, get_dialer::LAST_ROUTES, logging::get_last_log_lines,
+    reload::live_config, secrets::is_secret_key, stats::stat_all_nums, Config,
+};
+
+/// How many log lines go into a debug bundle.
+const BUNDLE_LOG_LINES: usize = 5000;
+
+/// Builds a single JSON document with everything we usually need for a bug report: recent logs, the effective config with secrets redacted, the shape of the current bridge routes, the connection state, and all stats.
+///
+/// Bundles get shared, so they leave out bridge addresses and cookies, which are what a censor would need to block the bridges. Log lines that mention them are scrubbed as well.
+pub fn debug_bundle(ctx: &AnyCtx<Config>) -> anyhow::Result<String> {
+    bundle_with_logs(ctx, get_last_log_lines(BUNDLE_LOG_LINES))
+}
+
+fn bundle_with_logs(ctx: &AnyCtx<Config>, mut logs: Vec<String>) -> anyhow::Result<String> {
+    let mut config = serde_json::to_value(&*live_config(ctx))?;
+    redact(&mut config);
+    let mut conn_info = serde_json::to_value(&*ctx.get(CURRENT_CONN_INFO).lock())?;
+    let mut routes = serde_json::to_value(&*ctx.get(LAST_ROUTES).lock())?;
+    let mut bridge_secrets = vec![];
+    collect_bridge_secrets(&routes, &mut bridge_secrets);
+    if let Some(bridge) = conn_info.get_mut("bridge") {
+        collect_bridge_secrets(bridge, &mut bridge_secrets);
+        *bridge = json!("<redacted>");
+    }
+    redact_leaves(&mut routes);
+    for line in logs.iter_mut() {
+        for secret in bridge_secrets.iter() {
+            if line.contains(secret.as_str()) {
+                *line = line.replace(secret.as_str(), "<redacted>");
+            }
+        }
+    }
+    let stats: serde_json::Map<String, Value> = stat_all_nums(ctx)
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), json!(v)))
+        .collect();
+    let bundle = json!({
+        "generated_at": SystemTime::now(),
+        "version": env!("CARGO_PKG_VERSION"),
+        "conn_info": conn_info,
+        "config": config,
+        "routes": routes,
+        "stats": stats,
+        "logs": logs,
+    });
+    Ok(serde_json::to_string_pretty(&bundle)?)
+}
+
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if is_secret_key(key) {
+                    *value = json!("<redacted>");
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+/// Redacts every value, keeping only the structure, such as which transports a route ladder is made of.
+fn redact_leaves(value: &mut Value) {
+    match value {
+        Value::Object(map) => map.values_mut().for_each(redact_leaves),
+        Value::Array(values) => values.iter_mut().for_each(redact_leaves),
+        Value::Null => {}
+        _ => *value = json!("<redacted>"),
+    }
+}
+
+/// Collects the strings in routes that could give a bridge away, such as addresses and cookies, along with the bare IPs of any addresses. Very short strings are left out, since they are enum tags or the like, and scrubbing them would mangle unrelated log lines.
+fn collect_bridge_secrets(value: &Value, out: &mut Vec<String>) {
+    match value {
+        Value::Object(map) => map.values().for_each(|v| collect_bridge_secrets(v, out)),
+        Value::Array(values) => values.iter().for_each(|v| collect_bridge_secrets(v, out)),
+        Value::String(s) if s.len() >= 6 => {
+            // the full string goes first, so that an address is scrubbed whole rather than leaving its port behind
+            out.push(s.clone());
+            if let Ok(addr) = s.parse::<SocketAddr>() {
+                out.push(addr.ip().to_string());
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_keys_in_any_case_and_nested() {
+        let mut config = json!({
+            "Password": "hunter2",
+            "upstream": {"broker_auth_token": "abc", "host": "example.com"},
+        });
+        redact(&mut config);
+        assert_eq!(
+            config,
+            json!({
+                "Password": "<redacted>",
+                "upstream": {"broker_auth_token": "<redacted>", "host": "example.com"},
+            })
+        );
+    }
+
+    #[test]
+    fn bundle_scrubs_bridges_from_logs() {
+        let config: Config = serde_json::from_value(json!({"exit_constraint": "auto"})).unwrap();
+        let ctx = AnyCtx::new(config);
+        *ctx.get(LAST_ROUTES).lock() = Some(
+            serde_json::from_value(json!({"race": [
+                {"kcp": {"addr": "192.0.2.1:443", "cookie": "c00k1e-c00k1e"}},
+                {"icmp": {"addr": "198.51.100.7", "port": 8443}},
+            ]}))
+            .unwrap(),
+        );
+        let logs = vec![
+            r#"{"fields":{"message":"dialing 192.0.2.1:443 with c00k1e-c00k1e"}}"#.to_string(),
+            r#"{"fields":{"message":"pinging 198.51.100.7"}}"#.to_string(),
+        ];
+        let bundle = bundle_with_logs(&ctx, logs).unwrap();
+        for secret in ["192.0.2.1", "c00k1e-c00k1e", "198.51.100.7"] {
+            assert!(!bundle.contains(secret), "bundle leaks {secret}");
+        }
+        assert!(bundle.contains("dialing <redacted> with <redacted>"));
+    }
+
+    #[test]
+    fn routes_keep_only_their_shape() {
+        let mut routes = json!({"Race": [{"Sosistab3": {"cookie": "c00k1e", "lower": {"Tcp": "192.0.2.1:443"}}}]});
+        redact_leaves(&mut routes);
+        assert_eq!(
+            routes,
+            json!({"Race": [{"Sosistab3": {"cookie": "<redacted>", "lower": {"Tcp": "<redacted>"}}}]})
+        );
+    }
+}
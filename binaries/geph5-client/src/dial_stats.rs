@@ -0,0 +1,232 @@
+//! Per-protocol dial telemetry, so that which transports a network blocks shows up in the stats rather than only in packet captures.
+
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use anyctx::AnyCtx;
+use async_trait::async_trait;
+use geph5_broker_protocol::{randomize_outcome, ProtocolOutcomes, FEATURE_PROTOCOL_HINTS};
+use geph5_misc_rpc::exit::ConnectCredential;
+use serde::{Deserialize, Serialize};
+use sillad::{
+    dialer::{Dialer, DynDialer},
+    Pipe,
+};
+
+use crate::{
+    auth::get_broker_token,
+    broker::{broker_capabilities, broker_client},
+    client::CtxField,
+    stats::{stat_all_nums, stat_get_hist, stat_incr_num, stat_record_hist, HistogramSummary},
+    Config,
+};
+
+/// A dialer that records its attempts, successes, failures, and dial times under the given protocol name.
+pub struct RecordingDialer {
+    pub ctx: AnyCtx<Config>,
+    pub protocol: String,
+    pub inner: DynDialer,
+}
+
+#[async_trait]
+impl Dialer for RecordingDialer {
+    type P = Box<dyn Pipe>;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let protocol = &self.protocol;
+        stat_incr_num(&self.ctx, &format!("dial_attempts.{protocol}"), 1.0);
+        let start = Instant::now();
+        match self.inner.dial().await {
+            Ok(pipe) => {
+                stat_incr_num(&self.ctx, &format!("dial_successes.{protocol}"), 1.0);
+                stat_record_hist(
+                    &self.ctx,
+                    &format!("dial_time.{protocol}"),
+                    start.elapsed().as_secs_f64(),
+                );
+                Ok(pipe)
+            }
+            Err(err) => {
+                let cause = failure_cause(&err);
+                stat_incr_num(&self.ctx, &format!("dial_failures.{protocol}.{cause}"), 1.0);
+                Err(err)
+            }
+        }
+    }
+}
+
+/// A short, stable name for why a dial failed, such as "timed_out" or "connection_reset".
+fn failure_cause(err: &std::io::Error) -> String {
+    let mut cause = String::new();
+    for c in format!("{:?}", err.kind()).chars() {
+        if c.is_uppercase() && !cause.is_empty() {
+            cause.push('_');
+        }
+        cause.push(c.to_ascii_lowercase());
+    }
+    cause
+}
+
+/// How dialing over one protocol has gone since startup. Attempts that neither succeeded nor failed were cut short, usually because a faster route won a race.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct DialStats {
+    pub protocol: String,
+    pub attempts: u64,
+    pub successes: u64,
+    /// Failure counts by cause.
+    pub failures: BTreeMap<String, u64>,
+    /// How long successful dials took, in seconds.
+    pub dial_time: Option<HistogramSummary>,
+}
+
+/// Gathers the dial telemetry of every protocol tried so far.
+pub fn dial_stats(ctx: &AnyCtx<Config>) -> Vec<DialStats> {
+    let mut by_protocol: BTreeMap<String, DialStats> = BTreeMap::new();
+    for (name, value) in stat_all_nums(ctx) {
+        let value = value as u64;
+        if let Some(protocol) = name.strip_prefix("dial_attempts.") {
+            entry(&mut by_protocol, protocol).attempts = value;
+        } else if let Some(protocol) = name.strip_prefix("dial_successes.") {
+            entry(&mut by_protocol, protocol).successes = value;
+        } else if let Some((protocol, cause)) = name
+            .strip_prefix("dial_failures.")
+            .and_then(|rest| rest.split_once('.'))
+        {
+            entry(&mut by_protocol, protocol)
+                .failures
+                .insert(cause.to_string(), value);
+        }
+    }
+    by_protocol
+        .into_values()
+        .map(|mut stats| {
+            stats.dial_time = stat_get_hist(ctx, &format!("dial_time.{}", stats.protocol));
+            stats
+        })
+        .collect()
+}
+
+fn entry<'a>(
+    by_protocol: &'a mut BTreeMap<String, DialStats>,
+    protocol: &str,
+) -> &'a mut DialStats {
+    by_protocol
+        .entry(protocol.to_string())
+        .or_insert_with(|| DialStats {
+            protocol: protocol.to_string(),
+            ..Default::default()
+        })
+}
+
+/// How often we send the broker what happened to our dials since the last time.
+const TELEMETRY_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// How long we go by the broker's hints before asking again.
+const HINTS_LIFETIME: Duration = Duration::from_secs(3600);
+
+/// Periodically sends the broker randomized counts of how dialing over each protocol went, from which it works out which protocols work in which countries. Every outcome is randomized on its own before it is counted, so no single report says for sure what happened.
+pub async fn protocol_telemetry_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    if ctx.init().broker.is_none() || ctx.init().disable_protocol_telemetry {
+        return smol::future::pending().await;
+    }
+    let mut reported: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+    loop {
+        smol::Timer::after(TELEMETRY_INTERVAL).await;
+        if !broker_capabilities(ctx)
+            .await
+            .has_feature(FEATURE_PROTOCOL_HINTS)
+        {
+            continue;
+        }
+        let mut outcomes = vec![];
+        for stats in dial_stats(ctx) {
+            let failures = stats.failures.values().sum::<u64>();
+            let (old_successes, old_failures) = reported
+                .insert(stats.protocol.clone(), (stats.successes, failures))
+                .unwrap_or_default();
+            let mut randomized = ProtocolOutcomes {
+                protocol: stats.protocol,
+                successes: 0,
+                failures: 0,
+            };
+            let new_successes = stats.successes.saturating_sub(old_successes);
+            let new_failures = failures.saturating_sub(old_failures);
+            for success in std::iter::repeat(true)
+                .take(new_successes as usize)
+                .chain(std::iter::repeat(false).take(new_failures as usize))
+            {
+                if randomize_outcome(success, rand::random()) {
+                    randomized.successes += 1;
+                } else {
+                    randomized.failures += 1;
+                }
+            }
+            if randomized.successes + randomized.failures > 0 {
+                outcomes.push(randomized);
+            }
+        }
+        if outcomes.is_empty() {
+            continue;
+        }
+        tracing::debug!(protocols = outcomes.len(), "reporting protocol outcomes");
+        let sent = async {
+            let ConnectCredential { token, sig, .. } = get_broker_token(ctx).await?;
+            broker_client(ctx)?
+                .report_protocol_outcomes(token, sig, outcomes)
+                .await?
+                .map_err(|e| anyhow::anyhow!("broker refused the report: {e}"))?;
+            anyhow::Ok(())
+        };
+        if let Err(err) = sent.await {
+            tracing::warn!(err = debug(err), "failed to report protocol outcomes");
+        }
+    }
+}
+
+/// Gets the protocols that work best in our country, best first, as the broker last told us. This is empty if the broker has no hints for us, or can't give any.
+pub async fn protocol_hints(ctx: &AnyCtx<Config>) -> Vec<String> {
+    if let Some((hints, expiry)) = ctx.get(PROTOCOL_HINTS).lock().clone() {
+        if expiry > Instant::now() {
+            return hints;
+        }
+    }
+    if !broker_capabilities(ctx)
+        .await
+        .has_feature(FEATURE_PROTOCOL_HINTS)
+    {
+        return vec![];
+    }
+    let fetched = async {
+        broker_client(ctx)?
+            .get_protocol_hints()
+            .await?
+            .map_err(|e| anyhow::anyhow!("broker refused to give protocol hints: {e}"))
+    };
+    let hints = match fetched.await {
+        Ok(hints) => hints,
+        Err(err) => {
+            tracing::debug!(err = debug(err), "could not get protocol hints");
+            vec![]
+        }
+    };
+    *ctx.get(PROTOCOL_HINTS).lock() = Some((hints.clone(), Instant::now() + HINTS_LIFETIME));
+    hints
+}
+
+static PROTOCOL_HINTS: CtxField<parking_lot::Mutex<Option<(Vec<String>, Instant)>>> =
+    |_| parking_lot::Mutex::new(None);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failure_causes_are_snake_case() {
+        let err = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        assert_eq!(failure_cause(&err), "connection_reset");
+        let err = std::io::Error::from(std::io::ErrorKind::TimedOut);
+        assert_eq!(failure_cause(&err), "timed_out");
+    }
+}
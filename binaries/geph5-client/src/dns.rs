@@ -0,0 +1,290 @@
+mod blocklist;
+mod doh;
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use anyctx::AnyCtx;
+use anyhow::Context;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::{io::BufReader, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use geph5_misc_rpc::{
+    dns::{DnsRecord, ExitDnsClient, DNS_RPC_PROTOCOL},
+    udp::UdpNatFrame,
+};
+use moka::future::Cache;
+use nanorpc::{JrpcRequest, JrpcResponse, RpcTransport};
+use nursery_macro::nursery;
+use simple_dns::{
+    rdata::{RData, A, AAAA},
+    Packet, ResourceRecord, CLASS, QTYPE, RCODE, TYPE,
+};
+use smol::future::FutureExt as _;
+use smol_timeout2::TimeoutExt;
+
+use crate::{
+    client::CtxField, client_inner::open_conn, listeners::RuleOverrides, reload::live_config,
+    socket_activation::bind_tcp, udpnat::UdpNatTunnel, Config,
+};
+
+use self::doh::{doh_query, pick_upstream, DnsUpstream};
+
+pub use blocklist::{blocklist_check, blocklist_loop, BlocklistSource};
+
+/// Upstream resolver that the exit forwards our tunneled DNS queries to.
+const UPSTREAM_DNS: &str = "1.1.1.1:53";
+
+/// How long we cache negative or answerless responses.
+const NEGATIVE_TTL: u32 = 30;
+
+/// Upper bound on how long we cache anything, regardless of the advertised TTL.
+const MAX_TTL: u32 = 86400;
+
+/// Cache of raw DNS responses, keyed by the question section, together with when they expire.
+static DNS_CACHE: CtxField<Cache<String, (Bytes, Instant)>> = |_| {
+    Cache::builder()
+        .max_capacity(10000)
+        .time_to_live(Duration::from_secs(MAX_TTL as _))
+        .build()
+};
+
+/// Serves the local caching stub resolver on both UDP and TCP, if configured.
+#[tracing::instrument(skip_all)]
+pub async fn dns_serve(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    if let Some(listen) = ctx.init().dns_listen {
+        tracing::info!(listen = display(listen), "starting local DNS resolver");
+        dns_udp_loop(ctx, listen)
+            .race(dns_tcp_loop(ctx, listen))
+            .await
+    } else {
+        smol::future::pending().await
+    }
+}
+
+async fn dns_udp_loop(ctx: &AnyCtx<Config>, listen: SocketAddr) -> anyhow::Result<()> {
+    let socket = smol::net::UdpSocket::bind(listen).await?;
+    let mut buf = [0u8; 65536];
+    nursery!({
+        loop {
+            let (n, client_addr) = socket.recv_from(&mut buf).await?;
+            if !live_config(ctx)
+                .listen_acl
+                .admits(ctx, listen, Some(client_addr.ip()))
+            {
+                continue;
+            }
+            let req = Bytes::copy_from_slice(&buf[..n]);
+            let socket = socket.clone();
+            spawn!(async move {
+                let resp = dns_respond(ctx, &req).await?;
+                socket.send_to(&resp, client_addr).await?;
+                anyhow::Ok(())
+            })
+            .detach();
+        }
+    })
+}
+
+async fn dns_tcp_loop(ctx: &AnyCtx<Config>, listen: SocketAddr) -> anyhow::Result<()> {
+    let listener = smol::net::TcpListener::try_from(bind_tcp(listen)?)?;
+    nursery!({
+        loop {
+            let (conn, peer_addr) = listener.accept().await?;
+            if !live_config(ctx)
+                .listen_acl
+                .admits(ctx, listen, Some(peer_addr.ip()))
+            {
+                continue;
+            }
+            spawn!(dns_tcp_conn(ctx, conn)).detach();
+        }
+    })
+}
+
+async fn dns_tcp_conn(ctx: &AnyCtx<Config>, mut conn: smol::net::TcpStream) -> anyhow::Result<()> {
+    loop {
+        let mut len_buf = [0u8; 2];
+        conn.read_exact(&mut len_buf).await?;
+        let mut req = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+        conn.read_exact(&mut req).await?;
+        let resp = dns_respond(ctx, &req).await?;
+        conn.write_all(&(resp.len() as u16).to_be_bytes()).await?;
+        conn.write_all(&resp).await?;
+        conn.flush().await?;
+    }
+}
+
+/// Answers a raw DNS query, either from the cache or by resolving it through the tunnel.
+pub async fn dns_respond(ctx: &AnyCtx<Config>, req: &[u8]) -> anyhow::Result<Bytes> {
+    let packet = Packet::parse(req)?;
+    let cache_key = packet
+        .questions
+        .iter()
+        .map(|q| {
+            format!(
+                "{}/{:?}/{:?}",
+                q.qname.to_string().to_lowercase(),
+                q.qtype,
+                q.qclass
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(";");
+    if packet
+        .questions
+        .iter()
+        .any(|q| blocklist_check(ctx, &q.qname.to_string()).is_some())
+    {
+        let mut reply = packet.into_reply();
+        *reply.rcode_mut() = RCODE::NameError;
+        return Ok(reply.build_bytes_vec_compressed()?.into());
+    }
+
+    let cache = ctx.get(DNS_CACHE);
+    if let Some((resp, expiry)) = cache.get(&cache_key).await {
+        if expiry > Instant::now() {
+            tracing::trace!(cache_key, "DNS cache hit");
+            return Ok(with_id(&resp, req));
+        }
+        cache.invalidate(&cache_key).await;
+    }
+
+    let upstream = match packet.questions.first() {
+        Some(question) => pick_upstream(ctx, &question.qname.to_string()),
+        None => DnsUpstream::Exit,
+    };
+    let resp = match upstream {
+        DnsUpstream::Exit => match dns_respond_via_exit(ctx, &packet).await {
+            Ok(resp) => resp,
+            Err(err) => {
+                tracing::debug!(
+                    err = debug(err),
+                    "falling back to plain DNS through the exit"
+                );
+                dns_resolve_remote(ctx, req).await?
+            }
+        },
+        DnsUpstream::Doh(url) => doh_query(ctx, &url, req).await?,
+    };
+    if let Some(ttl) = cacheable_ttl(&resp) {
+        tracing::trace!(cache_key, ttl, "caching DNS response");
+        cache
+            .insert(
+                cache_key,
+                (resp.clone(), Instant::now() + Duration::from_secs(ttl as _)),
+            )
+            .await;
+    }
+    Ok(resp)
+}
+
+/// Answers a query for the A or AAAA records of a single name through the exit's DNS RPC service. Other queries, and exits that predate the service, fail.
+async fn dns_respond_via_exit(ctx: &AnyCtx<Config>, packet: &Packet<'_>) -> anyhow::Result<Bytes> {
+    let [question] = &packet.questions[..] else {
+        anyhow::bail!("the exit's resolver only answers single questions")
+    };
+    let want_v4 = match question.qtype {
+        QTYPE::TYPE(TYPE::A) => true,
+        QTYPE::TYPE(TYPE::AAAA) => false,
+        _ => anyhow::bail!("the exit's resolver only answers A and AAAA questions"),
+    };
+    let records = exit_resolve(ctx, &question.qname.to_string()).await?;
+    let mut reply = packet.clone().into_reply();
+    for record in records {
+        let rdata = match record.addr {
+            IpAddr::V4(ip) if want_v4 => RData::A(A { address: ip.into() }),
+            IpAddr::V6(ip) if !want_v4 => RData::AAAA(AAAA { address: ip.into() }),
+            _ => continue,
+        };
+        reply.answers.push(ResourceRecord::new(
+            question.qname.clone(),
+            CLASS::IN,
+            record.ttl,
+            rdata,
+        ));
+    }
+    Ok(reply.build_bytes_vec_compressed()?.into())
+}
+
+/// Resolves a hostname into all its address records through the exit's DNS RPC service.
+pub async fn exit_resolve(ctx: &AnyCtx<Config>, host: &str) -> anyhow::Result<Vec<DnsRecord>> {
+    ExitDnsClient(ExitDnsTransport(ctx.clone()))
+        .resolve(host.to_string())
+        .timeout(Duration::from_secs(10))
+        .await
+        .context("timed out resolving through the exit")??
+        .map_err(|e| anyhow::anyhow!(e))
+}
+
+/// Makes DNS RPC calls to the exit, each on a fresh `dnsrpc$` stream.
+struct ExitDnsTransport(AnyCtx<Config>);
+
+#[async_trait]
+impl RpcTransport for ExitDnsTransport {
+    type Error = anyhow::Error;
+
+    async fn call_raw(&self, req: JrpcRequest) -> Result<JrpcResponse, Self::Error> {
+        let tunneled = open_conn(&self.0, DNS_RPC_PROTOCOL, "").await?;
+        let (read_tunneled, mut write_tunneled) = tunneled.split();
+        write_tunneled
+            .write_all(format!("{}\n", serde_json::to_string(&req)?).as_bytes())
+            .await?;
+        write_tunneled.flush().await?;
+        let mut line = String::new();
+        BufReader::new(read_tunneled).read_line(&mut line).await?;
+        Ok(serde_json::from_str(&line)?)
+    }
+}
+
+/// Sends a single raw DNS query through the tunnel, and returns the raw response.
+pub async fn dns_resolve_remote(ctx: &AnyCtx<Config>, req: &[u8]) -> anyhow::Result<Bytes> {
+    async {
+        let upstream: SocketAddr = UPSTREAM_DNS.parse()?;
+        let tunnel = UdpNatTunnel::open(ctx, &RuleOverrides::default()).await?;
+        tunnel
+            .send(&UdpNatFrame {
+                peer: upstream,
+                payload: Bytes::copy_from_slice(req),
+            })
+            .await?;
+        loop {
+            let frame = tunnel.recv().await?;
+            if frame.peer == upstream {
+                return anyhow::Ok(frame.payload);
+            }
+        }
+    }
+    .timeout(Duration::from_secs(10))
+    .await
+    .context("timed out resolving DNS through the tunnel")?
+}
+
+/// Figures out how long a response may be cached, or None if it should not be cached at all.
+fn cacheable_ttl(resp: &[u8]) -> Option<u32> {
+    let packet = Packet::parse(resp).ok()?;
+    match packet.rcode() {
+        RCODE::NoError => Some(
+            packet
+                .answers
+                .iter()
+                .map(|answer| answer.ttl)
+                .min()
+                .unwrap_or(NEGATIVE_TTL)
+                .min(MAX_TTL),
+        ),
+        RCODE::NameError => Some(NEGATIVE_TTL),
+        _ => None,
+    }
+}
+
+/// Copies the transaction ID of the request onto a (possibly cached) response.
+fn with_id(resp: &[u8], req: &[u8]) -> Bytes {
+    let mut resp = resp.to_vec();
+    if resp.len() >= 2 && req.len() >= 2 {
+        resp[..2].copy_from_slice(&req[..2]);
+    }
+    resp.into()
+}
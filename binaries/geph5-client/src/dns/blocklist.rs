@@ -0,0 +1,156 @@
+use std::{collections::HashSet, path::PathBuf, sync::LazyLock, time::Duration};
+
+use anyctx::AnyCtx;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+use smol::future::FutureExt as _;
+
+use crate::{
+    client::CtxField,
+    reload::{live_config, wait_config_change},
+    stats::stat_incr_num,
+    Config,
+};
+
+/// How often blocklists get reloaded.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(86400);
+
+/// Where to load a hosts-format or plain domain-list blocklist from.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum BlocklistSource {
+    File(PathBuf),
+    Url(String),
+}
+
+impl BlocklistSource {
+    /// A human-readable name for the list, used to label its stats.
+    pub fn name(&self) -> String {
+        match self {
+            BlocklistSource::File(path) => path.display().to_string(),
+            BlocklistSource::Url(url) => url.clone(),
+        }
+    }
+
+    async fn load(&self) -> anyhow::Result<HashSet<String>> {
+        static CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
+            reqwest::ClientBuilder::new()
+                .timeout(Duration::from_secs(60))
+                .no_proxy()
+                .build()
+                .unwrap()
+        });
+        let raw = match self {
+            BlocklistSource::File(path) => smol::fs::read_to_string(path).await?,
+            BlocklistSource::Url(url) => CLIENT.get(url).send().await?.text().await?,
+        };
+        Ok(parse_blocklist(&raw))
+    }
+}
+
+static BLOCKLISTS: CtxField<RwLock<Vec<(String, HashSet<String>)>>> = |_| RwLock::new(vec![]);
+
+/// Periodically (re)loads all the configured blocklists, and again whenever the configured lists change.
+pub async fn blocklist_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    loop {
+        let mut loaded = vec![];
+        let config = live_config(ctx);
+        for source in config.blocklists.iter() {
+            match source.load().await {
+                Ok(domains) => {
+                    tracing::info!(
+                        list = source.name(),
+                        count = domains.len(),
+                        "loaded blocklist"
+                    );
+                    loaded.push((source.name(), domains));
+                }
+                Err(err) => {
+                    tracing::warn!(
+                        list = source.name(),
+                        err = debug(err),
+                        "failed to load blocklist"
+                    );
+                    // keep whatever we had before
+                    let old = ctx
+                        .get(BLOCKLISTS)
+                        .read()
+                        .iter()
+                        .find(|(name, _)| *name == source.name())
+                        .cloned();
+                    loaded.extend(old);
+                }
+            }
+        }
+        *ctx.get(BLOCKLISTS).write() = loaded;
+        let refresh = async {
+            if config.blocklists.is_empty() {
+                smol::future::pending().await
+            } else {
+                smol::Timer::after(REFRESH_INTERVAL).await;
+            }
+        };
+        refresh
+            .race(wait_config_change(ctx, |config| config.blocklists.clone()))
+            .await;
+    }
+}
+
+/// Checks whether the given name, or any of its parent domains, is on a blocklist. Returns the name of the matching list, counting the hit in stats.
+pub fn blocklist_check(ctx: &AnyCtx<Config>, name: &str) -> Option<String> {
+    let name = name.trim_end_matches('.').to_lowercase();
+    let lists = ctx.get(BLOCKLISTS).read();
+    let exploded: Vec<_> = name.split('.').collect();
+    for i in 0..exploded.len() {
+        let candidate = exploded[i..].join(".");
+        for (list_name, domains) in lists.iter() {
+            if domains.contains(&candidate) {
+                tracing::debug!(name, list = list_name, "blocked by blocklist");
+                stat_incr_num(ctx, &format!("blocked:{list_name}"), 1.0);
+                return Some(list_name.clone());
+            }
+        }
+    }
+    None
+}
+
+/// Parses either a hosts file ("0.0.0.0 example.com") or a plain list of domains, one per line.
+fn parse_blocklist(raw: &str) -> HashSet<String> {
+    raw.lines()
+        .filter_map(|line| {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            let mut words = line.split_ascii_whitespace();
+            let first = words.next()?;
+            let domain = if first.parse::<std::net::IpAddr>().is_ok() {
+                words.next()?
+            } else {
+                first
+            };
+            let domain = domain.trim_end_matches('.').to_lowercase();
+            if matches!(
+                domain.as_str(),
+                "localhost" | "localhost.localdomain" | "local" | "broadcasthost"
+            ) {
+                None
+            } else {
+                Some(domain)
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_hosts_and_domains() {
+        let parsed = parse_blocklist(
+            "# comment\n127.0.0.1 localhost\n0.0.0.0 ads.example.com # trailing\n\ntracker.example.org\n",
+        );
+        assert_eq!(parsed.len(), 2);
+        assert!(parsed.contains("ads.example.com"));
+        assert!(parsed.contains("tracker.example.org"));
+    }
+}
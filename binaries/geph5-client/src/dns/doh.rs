@@ -0,0 +1,80 @@
+use std::time::{Duration, Instant};
+
+use anyctx::AnyCtx;
+use anyhow::Context;
+use async_compat::CompatExt;
+use async_native_tls::TlsConnector;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::{Request, StatusCode, Uri};
+use hyper_util::rt::TokioIo;
+use smol_timeout2::TimeoutExt;
+
+use crate::{
+    client_inner::open_conn, domain_rules::match_domain_rule, reload::live_config, Config,
+};
+
+/// Which upstream a particular DNS name should be resolved through.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DnsUpstream {
+    /// Plain DNS, forwarded by the exit.
+    Exit,
+    /// A DNS-over-HTTPS endpoint, reached through the tunnel.
+    Doh(String),
+}
+
+/// Picks the upstream for a given name, using the most specific matching override before falling back to the default DoH upstream.
+pub fn pick_upstream(ctx: &AnyCtx<Config>, name: &str) -> DnsUpstream {
+    let config = live_config(ctx);
+    let upstream = match match_domain_rule(&config.doh_overrides, name) {
+        Some(upstream) => Some(upstream.clone()),
+        None => config.doh_upstream.clone(),
+    };
+    match upstream {
+        Some(url) if url != "exit" => DnsUpstream::Doh(url),
+        _ => DnsUpstream::Exit,
+    }
+}
+
+/// Sends a raw DNS query to a DoH endpoint (RFC 8484), with the HTTPS connection going through the tunnel.
+pub async fn doh_query(ctx: &AnyCtx<Config>, url: &str, req: &[u8]) -> anyhow::Result<Bytes> {
+    let uri: Uri = url.parse().context("invalid DoH URL")?;
+    let host = uri.host().context("DoH URL has no host")?.to_string();
+    let port = uri.port_u16().unwrap_or(443);
+
+    async {
+        let start = Instant::now();
+        let tunneled = open_conn(ctx, "tcp", &format!("{host}:{port}")).await?;
+        let tls = TlsConnector::new()
+            .connect(host.as_str(), tunneled)
+            .await
+            .context("TLS handshake with DoH server failed")?;
+        let (mut sender, conn) =
+            hyper::client::conn::http1::handshake(TokioIo::new(tls.compat())).await?;
+        let _conn = smolscale::spawn(async move {
+            if let Err(err) = conn.await {
+                tracing::debug!(err = debug(err), "DoH connection closed");
+            }
+        });
+
+        let request = Request::post(uri.clone())
+            .header("host", host.as_str())
+            .header("content-type", "application/dns-message")
+            .header("accept", "application/dns-message")
+            .body(Full::new(Bytes::copy_from_slice(req)))?;
+        let response = sender.send_request(request).await?;
+        if response.status() != StatusCode::OK {
+            anyhow::bail!("DoH server returned status {}", response.status());
+        }
+        let body = response.into_body().collect().await?.to_bytes();
+        tracing::trace!(
+            url,
+            elapsed = debug(start.elapsed()),
+            "dns-over-https completed"
+        );
+        anyhow::Ok(body)
+    }
+    .timeout(Duration::from_secs(10))
+    .await
+    .context("timed out resolving DNS over HTTPS")?
+}
@@ -0,0 +1,40 @@
+/// Returns whether a domain pattern matches the given name. A pattern matches the domain itself and all its subdomains, a leading "*." is ignored, and a bare "*" matches everything.
+pub fn domain_matches(pattern: &str, name: &str) -> bool {
+    let name = name.trim_end_matches('.').to_lowercase();
+    let pattern = pattern.trim_start_matches("*.").to_lowercase();
+    pattern == "*" || name == pattern || name.ends_with(&format!(".{pattern}"))
+}
+
+/// Finds the value of the most specific rule whose pattern matches the given name.
+pub fn match_domain_rule<'a, V>(
+    rules: impl IntoIterator<Item = (&'a String, &'a V)>,
+    name: &str,
+) -> Option<&'a V> {
+    rules
+        .into_iter()
+        .filter(|(pattern, _)| domain_matches(pattern, name))
+        .max_by_key(|(pattern, _)| if *pattern == "*" { 0 } else { pattern.len() })
+        .map(|(_, value)| value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[test]
+    fn most_specific_wins() {
+        let rules: BTreeMap<String, u32> = [
+            ("*".to_string(), 0),
+            ("example.com".to_string(), 1),
+            ("*.cdn.example.com".to_string(), 2),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(match_domain_rule(&rules, "foo.org"), Some(&0));
+        assert_eq!(match_domain_rule(&rules, "example.com."), Some(&1));
+        assert_eq!(match_domain_rule(&rules, "x.cdn.example.com"), Some(&2));
+        assert!(!domain_matches("example.com", "notexample.com"));
+    }
+}
@@ -0,0 +1,150 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, SystemTime},
+};
+
+use anyctx::AnyCtx;
+use geph5_broker_protocol::ExitDescriptor;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use smol_timeout2::TimeoutExt;
+
+use crate::{
+    client::CtxField,
+    control_prot::{ConnInfo, CURRENT_CONN_INFO},
+    Config,
+};
+
+/// How many past events we keep around for clients that fall behind.
+const EVENT_BACKLOG: usize = 1000;
+
+/// A change in the state of the connection.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "event")]
+pub enum ConnEvent {
+    Connecting,
+    Connected {
+        exit: ExitDescriptor,
+        bridge: String,
+        protocol: String,
+    },
+    Disconnected {
+        reason: String,
+    },
+    RouteChanged {
+        exit: ExitDescriptor,
+        bridge: String,
+    },
+    AuthError {
+        reason: String,
+    },
+    QualityDegraded {
+        score: f64,
+    },
+    QualityRecovered {
+        score: f64,
+    },
+}
+
+/// An event, along with its sequence number and when it happened.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TimedConnEvent {
+    pub seqno: u64,
+    pub time: SystemTime,
+    pub event: ConnEvent,
+}
+
+#[derive(Default)]
+struct EventLog {
+    next_seqno: u64,
+    events: VecDeque<TimedConnEvent>,
+}
+
+static EVENT_LOG: CtxField<Mutex<EventLog>> = |_| Mutex::new(EventLog::default());
+
+static NEW_EVENT: CtxField<async_event::Event> = |_| async_event::Event::new();
+
+/// Records an event and wakes up everybody waiting for events.
+pub fn push_event(ctx: &AnyCtx<Config>, event: ConnEvent) {
+    tracing::debug!(event = debug(&event), "connection event");
+    {
+        let mut log = ctx.get(EVENT_LOG).lock();
+        let seqno = log.next_seqno;
+        log.next_seqno += 1;
+        log.events.push_back(TimedConnEvent {
+            seqno,
+            time: SystemTime::now(),
+            event,
+        });
+        if log.events.len() > EVENT_BACKLOG {
+            log.events.pop_front();
+        }
+    }
+    ctx.get(NEW_EVENT).notify_all();
+}
+
+/// Updates the current connection info, emitting the corresponding events.
+pub fn set_conn_info(ctx: &AnyCtx<Config>, info: ConnInfo) {
+    let previous = std::mem::replace(&mut *ctx.get(CURRENT_CONN_INFO).lock(), info.clone());
+    match (previous, info) {
+        (ConnInfo::Connecting, ConnInfo::Connecting) => {}
+        (_, ConnInfo::Connecting) => push_event(ctx, ConnEvent::Connecting),
+        (previous, ConnInfo::Connected(info)) => {
+            if let ConnInfo::Connected(previous) = previous {
+                if previous.exit.b2e_listen == info.exit.b2e_listen
+                    && previous.bridge == info.bridge
+                {
+                    return;
+                }
+                push_event(
+                    ctx,
+                    ConnEvent::RouteChanged {
+                        exit: info.exit.clone(),
+                        bridge: info.bridge.clone(),
+                    },
+                );
+            }
+            push_event(
+                ctx,
+                ConnEvent::Connected {
+                    exit: info.exit,
+                    bridge: info.bridge,
+                    protocol: info.protocol,
+                },
+            );
+        }
+        // disconnections are reported with their reason by whoever noticed them
+        (_, ConnInfo::Disconnected) => {}
+    }
+}
+
+/// Returns all events after the given sequence number, waiting up to the given timeout for one to happen if there are none yet. Pass `None` to only wait for new events.
+pub async fn wait_events(
+    ctx: &AnyCtx<Config>,
+    after: Option<u64>,
+    timeout: Duration,
+) -> Vec<TimedConnEvent> {
+    let start = match after {
+        Some(after) => after + 1,
+        None => ctx.get(EVENT_LOG).lock().next_seqno,
+    };
+    let fetch = || {
+        let log = ctx.get(EVENT_LOG).lock();
+        let events: Vec<_> = log
+            .events
+            .iter()
+            .filter(|evt| evt.seqno >= start)
+            .cloned()
+            .collect();
+        if events.is_empty() {
+            None
+        } else {
+            Some(events)
+        }
+    };
+    ctx.get(NEW_EVENT)
+        .wait_until(fetch)
+        .timeout(timeout)
+        .await
+        .unwrap_or_default()
+}
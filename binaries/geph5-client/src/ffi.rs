@@ -0,0 +1,290 @@
+//! A stable C interface for embedding the client in native apps, such as an iOS NetworkExtension linking it as a static library.
+//!
+//! Strings going in and out are NUL-terminated UTF-8, and structured data, like configs and connection info, is JSON in the same format as the control protocol. Functions that wait on the network block the calling thread, so call them off the UI thread. Every function returns a negative `GEPH5_ERR_*` code on failure.
+
+use std::{
+    collections::HashMap,
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc, LazyLock,
+    },
+    time::Duration,
+};
+
+use futures_util::{
+    io::{ReadHalf, WriteHalf},
+    AsyncReadExt, AsyncWriteExt,
+};
+use parking_lot::{Mutex, RwLock};
+
+use crate::{Client, Config, ControlClient, Protocol, TunnelStream};
+
+pub const GEPH5_OK: c_int = 0;
+/// The client is not running.
+pub const GEPH5_ERR_NOT_RUNNING: c_int = -1;
+/// The client is already running.
+pub const GEPH5_ERR_ALREADY_RUNNING: c_int = -2;
+/// An argument is not valid, such as a config that does not parse.
+pub const GEPH5_ERR_INVALID_ARGUMENT: c_int = -3;
+/// The output buffer is too small for the result.
+pub const GEPH5_ERR_BUFFER_TOO_SMALL: c_int = -4;
+/// The stream handle does not refer to an open stream.
+pub const GEPH5_ERR_NO_SUCH_STREAM: c_int = -5;
+/// Connecting, reading, or writing failed.
+pub const GEPH5_ERR_IO: c_int = -6;
+
+/// Called with the connection info, as JSON, whenever it changes, on a thread belonging to the client. The string is only valid during the call.
+pub type Geph5EventCallback =
+    Option<unsafe extern "C" fn(conn_info_json: *const c_char, userdata: *mut c_void)>;
+
+/// How often to check whether the connection info changed, to tell the event callback.
+const CONN_INFO_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The client started through the C interface, if it is running.
+static CLIENT: RwLock<Option<Client>> = RwLock::new(None);
+
+/// Watches for changes in the connection info while the client runs.
+static WATCHER: Mutex<Option<smol::Task<()>>> = Mutex::new(None);
+
+static EVENT_CALLBACK: Mutex<Option<EventCallback>> = Mutex::new(None);
+
+static STREAMS: LazyLock<Mutex<HashMap<i64, Arc<FfiStream>>>> = LazyLock::new(Default::default);
+
+static NEXT_STREAM: AtomicI64 = AtomicI64::new(1);
+
+#[derive(Clone, Copy)]
+struct EventCallback {
+    callback: unsafe extern "C" fn(*const c_char, *mut c_void),
+    userdata: *mut c_void,
+}
+
+// the caller promises that the userdata can be used from any thread
+unsafe impl Send for EventCallback {}
+
+struct FfiStream {
+    read: smol::lock::Mutex<ReadHalf<TunnelStream>>,
+    write: smol::lock::Mutex<WriteHalf<TunnelStream>>,
+}
+
+/// The client started through the C interface, if it is running.
+pub(crate) fn running_client() -> Option<Client> {
+    CLIENT.read().clone()
+}
+
+/// Starts the client with the given config, as JSON.
+#[no_mangle]
+pub unsafe extern "C" fn geph5_start(config_json: *const c_char) -> c_int {
+    let Some(config) = parse_config(config_json) else {
+        return GEPH5_ERR_INVALID_ARGUMENT;
+    };
+    start(config)
+}
+
+/// Starts the client through the C interface, so that the other functions here reach it.
+pub(crate) fn start(config: Config) -> c_int {
+    let mut client = CLIENT.write();
+    if client.is_some() {
+        return GEPH5_ERR_ALREADY_RUNNING;
+    }
+    let started = Client::start(config);
+    *WATCHER.lock() = Some(smolscale::spawn(watch_conn_info(started.control_client())));
+    *client = Some(started);
+    GEPH5_OK
+}
+
+/// Stops the client, closing every stream opened through it and tearing down VPN mode.
+#[no_mangle]
+pub extern "C" fn geph5_stop() -> c_int {
+    let Some(client) = CLIENT.write().take() else {
+        return GEPH5_ERR_NOT_RUNNING;
+    };
+    WATCHER.lock().take();
+    STREAMS.lock().clear();
+    drop(client);
+    GEPH5_OK
+}
+
+/// Applies a changed config, as JSON, to the running client. Settings that cannot change at runtime keep their old values until the client restarts.
+#[no_mangle]
+pub unsafe extern "C" fn geph5_set_config(config_json: *const c_char) -> c_int {
+    let Some(config) = parse_config(config_json) else {
+        return GEPH5_ERR_INVALID_ARGUMENT;
+    };
+    let Some(client) = running_client() else {
+        return GEPH5_ERR_NOT_RUNNING;
+    };
+    client.reload_config(config);
+    GEPH5_OK
+}
+
+/// Writes the connection info, as JSON, into the buffer, returning its length without the NUL terminator.
+#[no_mangle]
+pub unsafe extern "C" fn geph5_get_conn_info(out_buf: *mut c_char, out_buflen: usize) -> c_int {
+    let Some(client) = running_client() else {
+        return GEPH5_ERR_NOT_RUNNING;
+    };
+    match smolscale::block_on(client.control_client().conn_info()) {
+        Ok(info) => write_c_string(&serde_json::to_string(&info).unwrap(), out_buf, out_buflen),
+        Err(_) => GEPH5_ERR_IO,
+    }
+}
+
+/// Sets the callback told about connection info changes, replacing any earlier one, or removes it if the callback is NULL. Once the client starts, the callback is called with the initial connection info, then on every change.
+#[no_mangle]
+pub extern "C" fn geph5_set_event_callback(
+    callback: Geph5EventCallback,
+    userdata: *mut c_void,
+) -> c_int {
+    *EVENT_CALLBACK.lock() = callback.map(|callback| EventCallback { callback, userdata });
+    GEPH5_OK
+}
+
+/// Opens a TCP stream through the tunnel to a "host:port" destination, returning a positive handle for it. This blocks until the stream is connected.
+#[no_mangle]
+pub unsafe extern "C" fn geph5_open_stream(dest: *const c_char) -> i64 {
+    let Some(dest) = c_str(dest) else {
+        return GEPH5_ERR_INVALID_ARGUMENT as i64;
+    };
+    let Some(client) = running_client() else {
+        return GEPH5_ERR_NOT_RUNNING as i64;
+    };
+    match smolscale::block_on(client.open(Protocol::Tcp, dest)) {
+        Ok(stream) => {
+            let (read, write) = stream.split();
+            let handle = NEXT_STREAM.fetch_add(1, Ordering::SeqCst);
+            STREAMS.lock().insert(
+                handle,
+                Arc::new(FfiStream {
+                    read: smol::lock::Mutex::new(read),
+                    write: smol::lock::Mutex::new(write),
+                }),
+            );
+            handle
+        }
+        Err(err) => {
+            tracing::debug!(err = debug(err), dest, "could not open stream for C caller");
+            GEPH5_ERR_IO as i64
+        }
+    }
+}
+
+/// Reads from a stream into the buffer, blocking until data is available, and returns how many bytes were read, which is 0 once the other end has closed the stream.
+#[no_mangle]
+pub unsafe extern "C" fn geph5_stream_read(handle: i64, buf: *mut u8, buflen: usize) -> i64 {
+    let Some(stream) = STREAMS.lock().get(&handle).cloned() else {
+        return GEPH5_ERR_NO_SUCH_STREAM as i64;
+    };
+    if buf.is_null() {
+        return GEPH5_ERR_INVALID_ARGUMENT as i64;
+    }
+    let buf = std::slice::from_raw_parts_mut(buf, buflen);
+    match smolscale::block_on(async { stream.read.lock().await.read(buf).await }) {
+        Ok(n) => n as i64,
+        Err(_) => GEPH5_ERR_IO as i64,
+    }
+}
+
+/// Writes the whole buffer to a stream, blocking until it is sent, and returns how many bytes were written.
+#[no_mangle]
+pub unsafe extern "C" fn geph5_stream_write(handle: i64, buf: *const u8, len: usize) -> i64 {
+    let Some(stream) = STREAMS.lock().get(&handle).cloned() else {
+        return GEPH5_ERR_NO_SUCH_STREAM as i64;
+    };
+    if buf.is_null() {
+        return GEPH5_ERR_INVALID_ARGUMENT as i64;
+    }
+    let buf = std::slice::from_raw_parts(buf, len);
+    let written = smolscale::block_on(async {
+        let mut write = stream.write.lock().await;
+        write.write_all(buf).await?;
+        write.flush().await
+    });
+    match written {
+        Ok(()) => len as i64,
+        Err(_) => GEPH5_ERR_IO as i64,
+    }
+}
+
+/// Closes a stream, after which its handle is no longer valid.
+#[no_mangle]
+pub extern "C" fn geph5_stream_close(handle: i64) -> c_int {
+    let Some(stream) = STREAMS.lock().remove(&handle) else {
+        return GEPH5_ERR_NO_SUCH_STREAM;
+    };
+    let _ = smolscale::block_on(async { stream.write.lock().await.close().await });
+    GEPH5_OK
+}
+
+async fn watch_conn_info(control: ControlClient) {
+    let mut last = String::new();
+    loop {
+        if let Ok(info) = control.conn_info().await {
+            let json = serde_json::to_string(&info).unwrap();
+            if json != last {
+                // copied out, so that the callback can replace itself
+                let callback = *EVENT_CALLBACK.lock();
+                if let Some(callback) = callback {
+                    let json = CString::new(json.clone()).unwrap();
+                    unsafe { (callback.callback)(json.as_ptr(), callback.userdata) };
+                }
+                last = json;
+            }
+        }
+        smol::Timer::after(CONN_INFO_POLL_INTERVAL).await;
+    }
+}
+
+unsafe fn c_str<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        return None;
+    }
+    CStr::from_ptr(s).to_str().ok()
+}
+
+unsafe fn parse_config(config_json: *const c_char) -> Option<Config> {
+    match serde_json::from_str(c_str(config_json)?) {
+        Ok(config) => Some(config),
+        Err(err) => {
+            tracing::warn!(err = debug(err), "C caller passed an invalid config");
+            None
+        }
+    }
+}
+
+/// Copies a string into a C buffer with a NUL terminator, returning its length without the terminator.
+unsafe fn write_c_string(s: &str, out_buf: *mut c_char, out_buflen: usize) -> c_int {
+    if out_buf.is_null() {
+        return GEPH5_ERR_INVALID_ARGUMENT;
+    }
+    if s.len() + 1 > out_buflen {
+        return GEPH5_ERR_BUFFER_TOO_SMALL;
+    }
+    std::ptr::copy_nonoverlapping(s.as_ptr(), out_buf as *mut u8, s.len());
+    *out_buf.add(s.len()) = 0;
+    s.len() as c_int
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_bad_arguments() {
+        let bad_config = CString::new("{\"not\": \"a config\"}").unwrap();
+        assert_eq!(
+            unsafe { geph5_start(bad_config.as_ptr()) },
+            GEPH5_ERR_INVALID_ARGUMENT
+        );
+        assert_eq!(
+            unsafe { geph5_start(std::ptr::null()) },
+            GEPH5_ERR_INVALID_ARGUMENT
+        );
+        let mut buf = [0u8; 16];
+        assert_eq!(
+            unsafe { geph5_stream_read(i64::MAX, buf.as_mut_ptr(), buf.len()) },
+            GEPH5_ERR_NO_SUCH_STREAM as i64
+        );
+        assert_eq!(geph5_stream_close(i64::MAX), GEPH5_ERR_NO_SUCH_STREAM);
+    }
+}
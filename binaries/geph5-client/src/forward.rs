@@ -0,0 +1,163 @@
+//! Port forwards. Local forwards work like `ssh -L`: every connection accepted on a local port is piped through the tunnel to a fixed destination. Reverse forwards work like `ssh -R`: the exit listens on a public port, and its inbound connections are piped back to a local service.
+
+use std::{net::SocketAddr, time::Duration};
+
+use anyctx::AnyCtx;
+use futures_util::{
+    future::{try_join, try_join_all},
+    AsyncReadExt as _, AsyncWriteExt as _,
+};
+use geph5_broker_protocol::{ReverseForwardGrant, Signed, FEATURE_REVERSE_FORWARD};
+use nursery_macro::nursery;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::get_auth_token,
+    broker::{broker_capabilities, broker_client},
+    client_inner::open_conn,
+    litecopy::litecopy,
+    reload::live_config,
+    socket_activation::bind_tcp,
+    taskpool::add_task,
+    Config,
+};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PortForward {
+    pub listen: SocketAddr,
+    /// The "host:port" that connections are forwarded to, resolved at the exit.
+    pub dest: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReverseForward {
+    /// The public port on the exit. The exit must allow it, and only paying users, who get grants for it from the broker, may use reverse forwards.
+    pub remote_port: u16,
+    /// The local service that inbound connections are piped to.
+    pub local: SocketAddr,
+}
+
+/// Runs all the configured port forwards.
+pub async fn forward_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    let forwards = &ctx.init().forward;
+    let reverse_forwards = &ctx.init().reverse_forward;
+    if forwards.is_empty() && reverse_forwards.is_empty() {
+        return smol::future::pending().await;
+    }
+    let local = try_join_all(
+        forwards
+            .iter()
+            .map(|forward| forward_once_loop(ctx, forward)),
+    );
+    let reverse = try_join_all(
+        reverse_forwards
+            .iter()
+            .map(|forward| reverse_forward_loop(ctx, forward)),
+    );
+    try_join(local, reverse).await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all, fields(listen = display(forward.listen), dest = forward.dest))]
+async fn forward_once_loop(ctx: &AnyCtx<Config>, forward: &PortForward) -> anyhow::Result<()> {
+    let listener = smol::net::TcpListener::try_from(bind_tcp(forward.listen)?)?;
+    tracing::info!("port forward listening");
+    nursery!({
+        loop {
+            let (client, peer_addr) = listener.accept().await?;
+            if !live_config(ctx)
+                .listen_acl
+                .admits(ctx, forward.listen, Some(peer_addr.ip()))
+            {
+                continue;
+            }
+            let task = spawn!(async move {
+                tracing::trace!(
+                    peer_addr = display(peer_addr),
+                    "forwarded connection accepted"
+                );
+                let stream = open_conn(ctx, "tcp", &forward.dest).await?;
+                let (read_client, write_client) = client.split();
+                let (read_stream, write_stream) = stream.split();
+                // each direction half-closes on its own, so the relay is done only once both are
+                try_join(
+                    litecopy(read_stream, write_client),
+                    litecopy(read_client, write_stream),
+                )
+                .await?;
+                anyhow::Ok(())
+            });
+            if let Some(task_limit) = live_config(ctx).task_limit {
+                add_task(task_limit, task);
+            } else {
+                task.detach();
+            }
+        }
+    })
+}
+
+#[tracing::instrument(skip_all, fields(remote_port = forward.remote_port, local = display(forward.local)))]
+async fn reverse_forward_loop(
+    ctx: &AnyCtx<Config>,
+    forward: &ReverseForward,
+) -> anyhow::Result<()> {
+    loop {
+        if let Err(err) = reverse_forward_once(ctx, forward).await {
+            tracing::warn!(err = debug(err), "reverse port forward failed, retrying");
+        }
+        smol::Timer::after(Duration::from_secs(5)).await;
+    }
+}
+
+async fn reverse_forward_once(
+    ctx: &AnyCtx<Config>,
+    forward: &ReverseForward,
+) -> anyhow::Result<()> {
+    let grant = stdcode::serialize(&reverse_forward_grant(ctx).await?)?;
+    let mut control = open_conn(ctx, "rlisten", &forward.remote_port.to_string()).await?;
+    control
+        .write_all(&(grant.len() as u16).to_be_bytes())
+        .await?;
+    control.write_all(&grant).await?;
+    control.flush().await?;
+    tracing::info!("reverse port forward requested");
+    nursery!({
+        loop {
+            let mut id_buf = [0u8; 8];
+            control.read_exact(&mut id_buf).await?;
+            let id = u64::from_be_bytes(id_buf);
+            spawn!(async move {
+                let stream = open_conn(ctx, "raccept", &id.to_string()).await?;
+                let service = smol::net::TcpStream::connect(forward.local).await?;
+                tracing::trace!(id, "inbound connection piped to local service");
+                let (read_service, write_service) = service.split();
+                let (read_stream, write_stream) = stream.split();
+                // each direction half-closes on its own, so the relay is done only once both are
+                try_join(
+                    litecopy(read_stream, write_service),
+                    litecopy(read_service, write_stream),
+                )
+                .await?;
+                anyhow::Ok(())
+            })
+            .detach();
+        }
+    })
+}
+
+/// Asks the broker for a grant to show the exit, which lets us have it listen on our behalf until the grant expires and the exit ends the forward.
+async fn reverse_forward_grant(
+    ctx: &AnyCtx<Config>,
+) -> anyhow::Result<Signed<ReverseForwardGrant>> {
+    if !broker_capabilities(ctx)
+        .await
+        .has_feature(FEATURE_REVERSE_FORWARD)
+    {
+        anyhow::bail!("the broker does not grant reverse port forwarding")
+    }
+    let auth_token = get_auth_token(ctx).await?;
+    broker_client(ctx)?
+        .get_reverse_forward_grant(auth_token)
+        .await?
+        .map_err(|e| anyhow::anyhow!("broker refused reverse port forwarding: {e}"))
+}
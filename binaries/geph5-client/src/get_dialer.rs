@@ -1,32 +1,57 @@
-use std::time::{Duration, SystemTime};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use anyctx::AnyCtx;
 use anyhow::Context;
+use async_trait::async_trait;
 
 use arrayref::array_ref;
 use async_native_tls::TlsConnector;
 use ed25519_dalek::VerifyingKey;
 
 use geph5_broker_protocol::{
-    AccountLevel, ExitDescriptor, ExitList, RouteDescriptor, DOMAIN_EXIT_DESCRIPTOR,
+    decode_bridge_bundle, puzzle::solve_puzzle, AccountLevel, BridgeBundle, Credential,
+    ExitDescriptor, ExitList, PortHopSchedule, PuzzleSolution, RouteDescriptor, RoutesOrChallenge,
+    DOMAIN_BRIDGE_BUNDLE, DOMAIN_EXIT_DESCRIPTOR, FEATURE_ROUTES_CHALLENGE, FEATURE_TRUST_GROUPS,
 };
+use geph5_misc_rpc::exit::ConnectCredential;
 use isocountry::CountryCode;
 use ordered_float::OrderedFloat;
 use rand::seq::SliceRandom;
 use serde::{Deserialize, Serialize};
 use sillad::{
-    dialer::{DialerExt, DynDialer, FailingDialer},
-    tcp::TcpDialer,
+    dialer::{Dialer, DialerExt, DynDialer, FailingDialer},
+    tcp::{TcpDialer, TcpOptions, TunedTcpDialer},
+    Pipe,
 };
+use sillad_browser_tls::{BrowserFingerprint, BrowserTlsDialer};
 use sillad_conntest::ConnTestDialer;
+use sillad_dns::dialer::DnsDialer;
+use sillad_fragment::FragmentDialer;
+use sillad_icmp::dialer::IcmpDialer;
+use sillad_kcp::dialer::KcpDialer;
+use sillad_meek::dialer::MeekDialer;
+use sillad_proxy::ProxyDialer;
+use sillad_pt::PtDialer;
+use sillad_quic::{
+    dialer::QuicDialer,
+    obfs::{FecParams, ObfsConfig},
+};
+use sillad_shadowsocks::{ShadowsocksDialer, ShadowsocksKey};
 use sillad_sosistab3::{dialer::SosistabDialer, Cookie};
+use sillad_websocket::WsDialer;
 
 use smol_timeout2::TimeoutExt as _;
 
 use crate::{
-    auth::get_connect_token,
-    broker::broker_client, // example: define/alias type that has .all_exits
-    client::{Config, CtxField},
+    auth::get_broker_token,
+    broker::{broker_capabilities, broker_client, mirrored_exits, mirrored_routes},
+    client::{BrokerKeys, Config, CtxField},
+    dial_stats::{protocol_hints, RecordingDialer},
+    reload::live_config,
     vpn::smart_vpn_whitelist,
 };
 
@@ -40,14 +65,84 @@ pub enum ExitConstraint {
     CountryCity(CountryCode, String),
 }
 
+/// A bridge that the user runs themselves, which we use to reach the exit alongside the bridges the broker hands out.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "protocol", rename_all = "snake_case")]
+pub enum PinnedBridge {
+    /// A Shadowsocks 2022 server, with a method such as "2022-blake3-aes-256-gcm" and its base64 key.
+    Shadowsocks {
+        server: SocketAddr,
+        method: String,
+        password: String,
+    },
+}
+
+/// The bridge routes most recently obtained from the broker, kept around for debugging.
+pub static LAST_ROUTES: CtxField<parking_lot::Mutex<Option<RouteDescriptor>>> =
+    |_| parking_lot::Mutex::new(None);
+
+/// How many puzzles in a row we solve for the broker before giving up on getting bridge routes.
+const MAX_ROUTES_PUZZLES: usize = 3;
+
+/// The exit constraint set at runtime through the control protocol, which overrides the configured one.
+static EXIT_OVERRIDE: CtxField<parking_lot::Mutex<Option<ExitConstraint>>> =
+    |_| parking_lot::Mutex::new(None);
+
+/// Bumped whenever the exit selection changes, so that tunnels to the old exit know to drain.
+static EXIT_GENERATION: CtxField<(AtomicU64, async_event::Event)> =
+    |_| (AtomicU64::new(0), async_event::Event::new());
+
+/// The exit constraint currently in effect.
+pub fn exit_constraint(ctx: &AnyCtx<Config>) -> ExitConstraint {
+    ctx.get(EXIT_OVERRIDE)
+        .lock()
+        .clone()
+        .unwrap_or_else(|| live_config(ctx).exit_constraint.clone())
+}
+
+/// Switches to a different exit at runtime. Existing tunnels stop taking new streams and get replaced by tunnels to the new exit.
+pub fn set_exit_constraint(ctx: &AnyCtx<Config>, constraint: ExitConstraint) {
+    tracing::info!(constraint = debug(&constraint), "switching exit");
+    *ctx.get(EXIT_OVERRIDE).lock() = Some(constraint);
+    redial(ctx);
+}
+
+/// Replaces the existing tunnels with freshly dialed ones, which drain the same way as when switching exits. Used when the settings for reaching the exit change.
+pub fn redial(ctx: &AnyCtx<Config>) {
+    let (generation, event) = ctx.get(EXIT_GENERATION);
+    generation.fetch_add(1, Ordering::SeqCst);
+    event.notify_all();
+}
+
+/// The current exit generation, to be compared against later with [wait_exit_change].
+pub fn exit_generation(ctx: &AnyCtx<Config>) -> u64 {
+    ctx.get(EXIT_GENERATION).0.load(Ordering::SeqCst)
+}
+
+/// Waits until the exit selection changes away from the given generation.
+pub async fn wait_exit_change(ctx: &AnyCtx<Config>, since: u64) {
+    let (generation, event) = ctx.get(EXIT_GENERATION);
+    event
+        .wait_until(|| (generation.load(Ordering::SeqCst) != since).then_some(()))
+        .await
+}
+
 /// Gets a sillad Dialer that produces a single, pre-authentication pipe, as well as the public key.
 pub async fn get_dialer(
     ctx: &AnyCtx<Config>,
 ) -> anyhow::Result<(VerifyingKey, ExitDescriptor, DynDialer)> {
     static SEMAPH: CtxField<
-        smol::lock::Mutex<Option<(VerifyingKey, ExitDescriptor, DynDialer, SystemTime)>>,
+        smol::lock::Mutex<Option<(VerifyingKey, ExitDescriptor, DynDialer, SystemTime, u64)>>,
     > = |_| smol::lock::Mutex::new(None);
     let mut cached_value = ctx.get(SEMAPH).lock().await;
+    let generation = exit_generation(ctx);
+    // a dialer cached for a previous exit selection is useless, even as a stale fallback
+    if cached_value
+        .as_ref()
+        .is_some_and(|inner| inner.4 != generation)
+    {
+        *cached_value = None;
+    }
 
     if let Some(inner) = cached_value.clone() {
         if inner.3.elapsed()? < Duration::from_secs(10) {
@@ -61,9 +156,26 @@ pub async fn get_dialer(
         .await
         .ok_or_else(|| anyhow::anyhow!("get_dialer_inner timed out"))
         .and_then(|x| x);
+    // a bridge bundle from a side channel is what we have to go on when the broker is blocked
+    let res = match res {
+        Err(err) if live_config(ctx).bridge_bundle.is_some() => {
+            tracing::warn!(
+                err = debug(err),
+                "could not get a dialer through the broker, using the bridge bundle"
+            );
+            bundled_dialer(ctx).await
+        }
+        res => res,
+    };
     match res {
         Ok(val) => {
-            *cached_value = Some((val.0, val.1.clone(), val.2.clone(), SystemTime::now()));
+            *cached_value = Some((
+                val.0,
+                val.1.clone(),
+                val.2.clone(),
+                SystemTime::now(),
+                generation,
+            ));
             Ok((val.0, val.1, val.2))
         }
         Err(err) => {
@@ -82,7 +194,8 @@ async fn get_dialer_inner(
     ctx: &AnyCtx<Config>,
 ) -> anyhow::Result<(VerifyingKey, ExitDescriptor, DynDialer)> {
     // If the user specified a direct constraint, handle that path immediately:
-    if let ExitConstraint::Direct(dir) = &ctx.init().exit_constraint {
+    let constraint = exit_constraint(ctx);
+    if let ExitConstraint::Direct(dir) = &constraint {
         let (dir, pubkey_hex) = dir
             .split_once('/')
             .context("did not find / in a direct constraint")?;
@@ -97,7 +210,6 @@ async fn get_dialer_inner(
             .await?
             .choose(&mut rand::thread_rng())
             .context("could not resolve destination for direct exit connection")?;
-        smart_vpn_whitelist(ctx, dest_addr.ip());
         return Ok((
             pubkey,
             ExitDescriptor {
@@ -110,39 +222,57 @@ async fn get_dialer_inner(
             },
             ConnTestDialer {
                 ping_count: 1,
-                inner: TcpDialer { dest_addr },
+                inner: tcp_dialer(ctx, dest_addr, TcpOptions::default()),
             }
             .dynamic(),
         ));
     }
 
     // Otherwise, we need to pick an exit from the broker based on user constraints.
-    let (level, conn_token, sig) = get_connect_token(ctx)
+    // the broker gets a token of its own, so that it can't recognize the one we show exits
+    let ConnectCredential {
+        level,
+        token: conn_token,
+        sig,
+    } = get_broker_token(ctx)
         .await
-        .context("could not get connect token")?;
+        .context("could not get broker token")?;
 
     let broker = broker_client(ctx).context("could not get broker client")?;
-    let exits_response = match level {
-        AccountLevel::Plus => broker.get_exits().await,
-        AccountLevel::Free => broker.get_free_exits().await,
-    }?
-    .map_err(|e| anyhow::anyhow!("broker refused to serve exits: {e}"))?;
-
-    // Verify the broker's signature over the exit list:
-    let exits_verified = exits_response
-        .verify(DOMAIN_EXIT_DESCRIPTOR, |their_pk| {
-            if let Some(broker_pk) = &ctx.init().broker_keys {
-                hex::encode(their_pk.as_bytes()) == broker_pk.master
-            } else {
-                true
-            }
-        })
-        .context("could not verify exits")?;
+    let caps = broker_capabilities(ctx).await;
+    let exits_from_broker = async {
+        let exits_response = match level {
+            AccountLevel::Plus => broker.get_exits().await,
+            AccountLevel::Free => broker.get_free_exits().await,
+        }?
+        .map_err(|e| anyhow::anyhow!("broker refused to serve exits: {e}"))?;
+
+        // Verify the broker's signature over the exit list:
+        exits_response
+            .verify(DOMAIN_EXIT_DESCRIPTOR, |their_pk| {
+                if let Some(broker_pk) = &ctx.init().broker_keys {
+                    hex::encode(their_pk.as_bytes()) == broker_pk.master
+                } else {
+                    true
+                }
+            })
+            .context("could not verify exits")
+    };
+    let exits_verified = match exits_from_broker.await {
+        Ok(exits) => exits,
+        Err(err) if !ctx.init().broker_mirrors.is_empty() => {
+            tracing::warn!(
+                err = debug(err),
+                "could not get exits from the broker, trying mirrors"
+            );
+            mirrored_exits(ctx, level).await?
+        }
+        Err(err) => return Err(err),
+    };
 
     // Use our new helper function to pick the best exit:
     let rendezvous_key = blake3::hash(serde_json::to_string(&ctx.init().credentials)?.as_bytes());
-    let (pubkey, exit) =
-        pick_exit_with_constraint(rendezvous_key, &ctx.init().exit_constraint, &exits_verified)?;
+    let (pubkey, exit) = pick_exit_with_constraint(rendezvous_key, &constraint, &exits_verified)?;
 
     tracing::debug!(exit = ?exit, "narrowed down choice of exit");
     smart_vpn_whitelist(ctx, exit.c2e_listen.ip());
@@ -150,26 +280,93 @@ async fn get_dialer_inner(
     let exit_c2e = exit.c2e_listen;
     let direct_dialer = ConnTestDialer {
         ping_count: 1,
-        inner: TcpDialer {
-            dest_addr: exit_c2e,
-        },
+        inner: tcp_dialer(ctx, exit_c2e, TcpOptions::default()),
     };
 
     tracing::debug!(token = %conn_token, "CONN TOKEN");
 
-    // Also get potential “bridge routes”:
-    let bridge_routes = broker
-        .get_routes(conn_token, sig, exit.b2e_listen)
-        .await?
-        .map_err(|e| anyhow::anyhow!("broker refused to serve bridge routes: {e}"))?;
-    tracing::debug!(
-        "bridge routes obtained: {}",
-        serde_json::to_string(&bridge_routes)?
-    );
+    // Also get potential “bridge routes”, solving whatever puzzles the broker asks for first:
+    let routes_from_broker = async {
+        if !caps.has_feature(FEATURE_ROUTES_CHALLENGE) {
+            return broker
+                .get_routes(conn_token, sig.clone(), exit.b2e_listen)
+                .await?
+                .map_err(|e| anyhow::anyhow!("broker refused to serve bridge routes: {e}"));
+        }
+        let mut solution = None;
+        let mut tries = 0;
+        loop {
+            match broker
+                .get_routes_challenged(conn_token, sig.clone(), exit.b2e_listen, solution.take())
+                .await?
+                .map_err(|e| anyhow::anyhow!("broker refused to serve bridge routes: {e}"))?
+            {
+                RoutesOrChallenge::Routes(routes) => break anyhow::Ok(routes),
+                RoutesOrChallenge::Challenge { puzzle, difficulty } => {
+                    tries += 1;
+                    if tries > MAX_ROUTES_PUZZLES {
+                        anyhow::bail!("broker kept asking for puzzles before serving bridge routes")
+                    }
+                    tracing::debug!(difficulty, "solving a puzzle to get bridge routes");
+                    let answer = {
+                        let puzzle = puzzle.clone();
+                        smol::unblock(move || solve_puzzle(&puzzle, difficulty, |_| {})).await
+                    };
+                    solution = Some(PuzzleSolution {
+                        puzzle,
+                        solution: answer,
+                    });
+                }
+            }
+        }
+    };
+    let bridge_routes = match routes_from_broker.await {
+        Ok(routes) => routes,
+        Err(err) if !ctx.init().broker_mirrors.is_empty() => {
+            tracing::warn!(
+                err = debug(err),
+                "could not get bridge routes from the broker, trying mirrors"
+            );
+            mirrored_routes(ctx, pubkey).await?
+        }
+        Err(err) => return Err(err),
+    };
+    // accounts in a trust group also get the bridges set aside for their group
+    let bridge_routes = match &ctx.init().credentials {
+        Credential::Secret(secret) if caps.has_feature(FEATURE_TRUST_GROUPS) => match broker
+            .get_group_routes(secret.clone(), exit.b2e_listen)
+            .await
+        {
+            Ok(Ok(group_routes)) => RouteDescriptor::Race(vec![bridge_routes, group_routes]),
+            Ok(Err(err)) => {
+                tracing::debug!(err = %err, "no trust group routes");
+                bridge_routes
+            }
+            Err(err) => {
+                tracing::warn!(err = debug(err), "could not get trust group routes");
+                bridge_routes
+            }
+        },
+        _ => bridge_routes,
+    };
+    let bridge_routes = order_by_hints(bridge_routes, &protocol_hints(ctx).await);
+    // the routes themselves carry bridge addresses and cookies, which have no place in logs that get shared
+    tracing::debug!("bridge routes obtained");
 
-    let bridge_dialer = route_to_dialer(ctx, &bridge_routes);
+    *ctx.get(LAST_ROUTES).lock() = Some(bridge_routes.clone());
+    let bridge_dialer = live_config(ctx)
+        .pinned_bridges
+        .iter()
+        .filter_map(|bridge| {
+            pinned_bridge_dialer(ctx, bridge, exit_c2e)
+                .inspect_err(|e| tracing::warn!(err = debug(e), "skipping bad pinned bridge"))
+                .ok()
+        })
+        .fold(route_to_dialer(ctx, &bridge_routes), |a, b| {
+            a.race(b).dynamic()
+        });
 
-    let final_dialer = match ctx.init().bridge_mode {
+    let final_dialer = match live_config(ctx).bridge_mode {
         crate::BridgeMode::Auto => direct_dialer
             .race(bridge_dialer.delay(Duration::from_millis(1000)))
             .dynamic(),
@@ -180,6 +377,56 @@ async fn get_dialer_inner(
     Ok((*pubkey, exit.clone(), final_dialer))
 }
 
+/// Builds a dialer over the routes of the configured bridge bundle, which all lead to the one exit the broker picked for the bundle.
+async fn bundled_dialer(
+    ctx: &AnyCtx<Config>,
+) -> anyhow::Result<(VerifyingKey, ExitDescriptor, DynDialer)> {
+    let config = live_config(ctx);
+    let text = config
+        .bridge_bundle
+        .as_deref()
+        .context("no bridge bundle")?;
+    let bundle = verify_bridge_bundle(text, ctx.init().broker_keys.as_ref())?;
+    let first = bundle
+        .routes
+        .first()
+        .context("bridge bundle has no routes")?;
+    let (exit_pubkey, exit) = (first.exit_pubkey, first.exit.clone());
+    let routes = RouteDescriptor::Race(
+        bundle
+            .routes
+            .into_iter()
+            .filter(|route| route.exit_pubkey == exit_pubkey)
+            .map(|route| route.route)
+            .collect(),
+    );
+    let routes = order_by_hints(routes, &protocol_hints(ctx).await);
+    *ctx.get(LAST_ROUTES).lock() = Some(routes.clone());
+    let dialer = config
+        .pinned_bridges
+        .iter()
+        .filter_map(|bridge| pinned_bridge_dialer(ctx, bridge, exit.c2e_listen).ok())
+        .fold(route_to_dialer(ctx, &routes), |a, b| a.race(b).dynamic());
+    Ok((exit_pubkey, exit, dialer))
+}
+
+/// Decodes a bridge bundle pasted from a side channel, checking that it is signed by the broker and not expired. Anyone can send a bundle, so without the broker's keys to check it against, none is trusted.
+pub fn verify_bridge_bundle(
+    text: &str,
+    broker_keys: Option<&BrokerKeys>,
+) -> anyhow::Result<BridgeBundle> {
+    let broker_keys =
+        broker_keys.context("cannot check a bridge bundle without the broker keys")?;
+    let bundle = decode_bridge_bundle(text)?
+        .verify(DOMAIN_BRIDGE_BUNDLE, |their_pk| {
+            hex::encode(their_pk.as_bytes()) == broker_keys.master
+        })
+        .context("bridge bundle is not signed by the broker")?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    anyhow::ensure!(bundle.expiry > now, "bridge bundle has expired");
+    Ok(bundle)
+}
+
 /// A helper that filters the verified exits by the user’s `ExitConstraint`,
 /// then picks the exit with the lowest load.
 fn pick_exit_with_constraint<'a>(
@@ -256,14 +503,342 @@ fn pick_exit_with_constraint<'a>(
     Ok((&first.0, &first.1))
 }
 
+/// Builds a dialer that reaches the exit's direct listener through a pinned bridge.
+fn pinned_bridge_dialer(
+    ctx: &AnyCtx<Config>,
+    bridge: &PinnedBridge,
+    exit_c2e: SocketAddr,
+) -> anyhow::Result<DynDialer> {
+    match bridge {
+        PinnedBridge::Shadowsocks {
+            server,
+            method,
+            password,
+        } => Ok(ConnTestDialer {
+            ping_count: 1,
+            inner: ShadowsocksDialer {
+                inner: tcp_dialer(ctx, *server, TcpOptions::default()),
+                key: ShadowsocksKey::new(method.parse()?, password)?,
+                dest_addr: exit_c2e,
+            },
+        }
+        .dynamic()),
+    }
+}
+
+/// Builds a dialer for a plain TCP connection, which goes through the upstream proxy if one is configured, and fragments its first packets if that is turned on. Socket options only apply to direct connections.
+fn tcp_dialer(ctx: &AnyCtx<Config>, dest_addr: SocketAddr, mut options: TcpOptions) -> DynDialer {
+    let dialer = match &live_config(ctx).upstream_proxy {
+        Some(proxy) => {
+            smart_vpn_whitelist(ctx, proxy.addr().ip());
+            ProxyDialer {
+                proxy: proxy.clone(),
+                dest_addr,
+            }
+            .dynamic()
+        }
+        None => {
+            smart_vpn_whitelist(ctx, dest_addr.ip());
+            if options.bind_interface.is_none() {
+                options.bind_interface = live_config(ctx).bind_interface.clone();
+            }
+            if options == TcpOptions::default() {
+                TcpDialer { dest_addr }.dynamic()
+            } else {
+                TunedTcpDialer { dest_addr, options }.dynamic()
+            }
+        }
+    };
+    match live_config(ctx).fragment {
+        Some(params) => FragmentDialer {
+            inner: dialer,
+            params,
+        }
+        .dynamic(),
+        None => dialer,
+    }
+}
+
+/// Dials whichever port a port-hopping bridge is on when dialing, rather than when the route was made.
+struct PortHopDialer {
+    ctx: AnyCtx<Config>,
+    addr: SocketAddr,
+    schedule: PortHopSchedule,
+}
+
+#[async_trait]
+impl Dialer for PortHopDialer {
+    type P = Box<dyn Pipe>;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let port = self
+            .schedule
+            .port(self.addr.port(), self.schedule.current_period());
+        tcp_dialer(
+            &self.ctx,
+            SocketAddr::new(self.addr.ip(), port),
+            TcpOptions::default(),
+        )
+        .dial()
+        .await
+    }
+}
+
 fn route_to_dialer(ctx: &AnyCtx<Config>, route: &RouteDescriptor) -> DynDialer {
+    let dialer = route_to_dialer_inner(ctx, route);
+    match route_protocol(route) {
+        Some(protocol) => RecordingDialer {
+            ctx: ctx.clone(),
+            protocol,
+            inner: dialer,
+        }
+        .dynamic(),
+        None => dialer,
+    }
+}
+
+/// How many queries per second DNS tunnel routes send at most, which keeps resolvers from rate-limiting us.
+const DNS_TUNNEL_QPS: u32 = 50;
+
+/// The first nameserver in /etc/resolv.conf, which on most Unix-like systems is the resolver the network provides.
+fn system_resolver() -> Option<SocketAddr> {
+    let conf = std::fs::read_to_string("/etc/resolv.conf").ok()?;
+    conf.lines().find_map(|line| {
+        let ip: IpAddr = line
+            .trim()
+            .strip_prefix("nameserver")?
+            .trim()
+            .parse()
+            .ok()?;
+        Some(SocketAddr::new(ip, 53))
+    })
+}
+
+/// The name that dial telemetry for a route goes under, or None for routes that only combine other routes.
+fn route_protocol(route: &RouteDescriptor) -> Option<String> {
+    let protocol = match route {
+        RouteDescriptor::Tcp(_) | RouteDescriptor::TunedTcp { .. } => "tcp",
+        RouteDescriptor::PortHop { .. } => "port_hop",
+        RouteDescriptor::Kcp { .. } => "kcp",
+        RouteDescriptor::Icmp { .. } => "icmp",
+        RouteDescriptor::Dns { .. } => "dns",
+        RouteDescriptor::Quic { .. } => "quic",
+        RouteDescriptor::ObfsUdp { .. } => "obfs_udp",
+        RouteDescriptor::Sosistab3 { .. } => "sosistab3",
+        RouteDescriptor::PlainTls { .. } => "tls",
+        RouteDescriptor::Websocket { .. } => "websocket",
+        RouteDescriptor::Meek { .. } => "meek",
+        RouteDescriptor::PluggableTransport { transport, .. } => {
+            return Some(format!("pt_{transport}"))
+        }
+        RouteDescriptor::Race(_)
+        | RouteDescriptor::Fallback(_)
+        | RouteDescriptor::Timeout { .. }
+        | RouteDescriptor::Delay { .. }
+        | RouteDescriptor::ConnTest { .. }
+        | RouteDescriptor::Other(_) => return None,
+    };
+    Some(protocol.to_string())
+}
+
+/// Reorders the rungs of fallback ladders so that those over the protocols that work best in our country, as the broker hints, are tried first. Without hints, the broker's own order stays.
+fn order_by_hints(route: RouteDescriptor, hints: &[String]) -> RouteDescriptor {
+    match route {
+        RouteDescriptor::Fallback(routes) => {
+            let mut routes: Vec<_> = routes
+                .into_iter()
+                .map(|route| order_by_hints(route, hints))
+                .collect();
+            routes.sort_by_key(|route| route_rank(route, hints));
+            RouteDescriptor::Fallback(routes)
+        }
+        RouteDescriptor::Race(routes) => RouteDescriptor::Race(
+            routes
+                .into_iter()
+                .map(|route| order_by_hints(route, hints))
+                .collect(),
+        ),
+        RouteDescriptor::Timeout {
+            milliseconds,
+            lower,
+        } => RouteDescriptor::Timeout {
+            milliseconds,
+            lower: order_by_hints(*lower, hints).into(),
+        },
+        RouteDescriptor::Delay {
+            milliseconds,
+            lower,
+        } => RouteDescriptor::Delay {
+            milliseconds,
+            lower: order_by_hints(*lower, hints).into(),
+        },
+        RouteDescriptor::ConnTest { ping_count, lower } => RouteDescriptor::ConnTest {
+            ping_count,
+            lower: order_by_hints(*lower, hints).into(),
+        },
+        route => route,
+    }
+}
+
+/// Where a route stands among the hinted protocols, lower being better. A layered route ranks as its outermost layer, since dialing that only succeeds once every layer below it has, while a combination of routes ranks as its best. Protocols without hints rank after all hinted ones.
+fn route_rank(route: &RouteDescriptor, hints: &[String]) -> usize {
+    let rank_of = |protocol: Option<String>| {
+        protocol
+            .and_then(|protocol| hints.iter().position(|hint| hint == &protocol))
+            .unwrap_or(hints.len())
+    };
+    match route {
+        RouteDescriptor::Race(routes) | RouteDescriptor::Fallback(routes) => routes
+            .iter()
+            .map(|route| route_rank(route, hints))
+            .min()
+            .unwrap_or(hints.len()),
+        RouteDescriptor::Timeout { lower, .. }
+        | RouteDescriptor::Delay { lower, .. }
+        | RouteDescriptor::ConnTest { lower, .. } => route_rank(lower, hints),
+        route => rank_of(route_protocol(route)),
+    }
+}
+
+fn route_to_dialer_inner(ctx: &AnyCtx<Config>, route: &RouteDescriptor) -> DynDialer {
     use sillad_native_tls::TlsDialer;
 
     match route {
-        RouteDescriptor::Tcp(addr) => {
+        RouteDescriptor::Tcp(addr) => tcp_dialer(ctx, *addr, TcpOptions::default()),
+        RouteDescriptor::PortHop { addr, schedule } => PortHopDialer {
+            ctx: ctx.clone(),
+            addr: *addr,
+            schedule: schedule.clone(),
+        }
+        .dynamic(),
+        RouteDescriptor::TunedTcp {
+            addr,
+            fast_open,
+            dscp,
+        } => tcp_dialer(
+            ctx,
+            *addr,
+            TcpOptions {
+                fast_open: *fast_open,
+                bind_interface: None,
+                dscp: *dscp,
+            },
+        ),
+        // these make their own connections, over UDP or from another process, which cannot go through the upstream proxy
+        RouteDescriptor::PluggableTransport { .. }
+        | RouteDescriptor::Kcp { .. }
+        | RouteDescriptor::Icmp { .. }
+        | RouteDescriptor::Dns { .. }
+        | RouteDescriptor::Quic { .. }
+        | RouteDescriptor::ObfsUdp { .. }
+            if live_config(ctx).upstream_proxy.is_some() =>
+        {
+            tracing::debug!(
+                route = debug(route),
+                "skipping route that cannot go through the upstream proxy"
+            );
+            FailingDialer.dynamic()
+        }
+        RouteDescriptor::PluggableTransport {
+            transport,
+            args,
+            addr,
+        } => {
+            let config = live_config(ctx);
+            let Some(command) = config.pluggable_transports.get(transport) else {
+                tracing::debug!(
+                    transport = display(transport),
+                    "skipping route over a pluggable transport we do not have"
+                );
+                return FailingDialer.dynamic();
+            };
+            smart_vpn_whitelist(ctx, addr.ip());
+            PtDialer {
+                command: command.clone(),
+                transport: transport.clone(),
+                args: args.clone().into_iter().collect(),
+                dest_addr: *addr,
+                state_dir: std::env::temp_dir().join("geph5-pt-state"),
+            }
+            .dynamic()
+        }
+        RouteDescriptor::Kcp { addr, cookie } => {
+            smart_vpn_whitelist(ctx, addr.ip());
+            KcpDialer {
+                dest_addr: *addr,
+                params: live_config(ctx).kcp,
+                cookie: cookie.clone(),
+            }
+            .dynamic()
+        }
+        RouteDescriptor::Icmp { addr, port } => {
+            let Some(max_pps) = live_config(ctx).icmp_max_pps else {
+                tracing::debug!("skipping experimental ICMP route, since it is not turned on");
+                return FailingDialer.dynamic();
+            };
+            smart_vpn_whitelist(ctx, (*addr).into());
+            IcmpDialer {
+                dest_addr: *addr,
+                port: *port,
+                params: live_config(ctx).kcp,
+                max_pps,
+            }
+            .dynamic()
+        }
+        RouteDescriptor::Dns { domain, port } => {
+            let Some(resolver) = live_config(ctx)
+                .dns_tunnel_resolver
+                .or_else(system_resolver)
+            else {
+                tracing::debug!("skipping DNS tunnel route, since we know of no resolver");
+                return FailingDialer.dynamic();
+            };
+            smart_vpn_whitelist(ctx, resolver.ip());
+            DnsDialer {
+                resolver,
+                domain: domain.clone(),
+                port: *port,
+                params: live_config(ctx).kcp,
+                max_qps: DNS_TUNNEL_QPS,
+            }
+            .dynamic()
+        }
+        RouteDescriptor::Quic {
+            addr,
+            congestion,
+            sni_domain,
+        } => {
+            smart_vpn_whitelist(ctx, addr.ip());
+            QuicDialer {
+                dest_addr: *addr,
+                server_name: sni_domain.clone(),
+                congestion: congestion.parse().unwrap_or_default(),
+                obfs: None,
+            }
+            .dynamic()
+        }
+        RouteDescriptor::ObfsUdp {
+            addr,
+            cookie,
+            fec_data_shards,
+            fec_parity_shards,
+            congestion,
+        } => {
             smart_vpn_whitelist(ctx, addr.ip());
-            let addr = *addr;
-            TcpDialer { dest_addr: addr }.dynamic()
+            QuicDialer {
+                dest_addr: *addr,
+                // the obfuscation hides the handshake, so the name makes no difference
+                server_name: None,
+                congestion: congestion.parse().unwrap_or_default(),
+                obfs: Some(ObfsConfig {
+                    cookie: cookie.clone(),
+                    fec: Some(FecParams {
+                        data_shards: *fec_data_shards,
+                        parity_shards: *fec_parity_shards,
+                    }),
+                }),
+            }
+            .dynamic()
         }
         RouteDescriptor::Sosistab3 { cookie, lower } => {
             let inner = route_to_dialer(ctx, lower);
@@ -305,21 +880,113 @@ fn route_to_dialer(ctx: &AnyCtx<Config>, route: &RouteDescriptor) -> DynDialer {
         }
 
         RouteDescriptor::Other(_) => FailingDialer.dynamic(),
-        RouteDescriptor::PlainTls { sni_domain, lower } => {
+        RouteDescriptor::Websocket { host, path, lower } => WsDialer {
+            inner: route_to_dialer(ctx, lower),
+            host: host.clone(),
+            path: path.clone(),
+        }
+        .dynamic(),
+        RouteDescriptor::Meek { host, path, lower } => MeekDialer {
+            inner: route_to_dialer(ctx, lower),
+            host: host.clone(),
+            path: path.clone(),
+        }
+        .dynamic(),
+        RouteDescriptor::PlainTls {
+            sni_domain,
+            fingerprint,
+            ech_config_list,
+            lower,
+        } => {
             let lower = route_to_dialer(ctx, lower);
-            TlsDialer::new(
-                lower,
-                TlsConnector::new()
-                    .use_sni(sni_domain.is_some())
-                    .danger_accept_invalid_certs(true)
-                    .danger_accept_invalid_hostnames(true)
-                    .min_protocol_version(None)
-                    .max_protocol_version(None),
-                sni_domain
-                    .clone()
-                    .unwrap_or_else(|| "example.com".to_string()),
-            )
-            .dynamic()
+            let domain = sni_domain
+                .clone()
+                .unwrap_or_else(|| "example.com".to_string());
+            let ech_config_list = ech_config_list.as_ref().and_then(|ech| {
+                hex::decode(ech)
+                    .inspect_err(|e| tracing::warn!(err = debug(e), "invalid ECH config list"))
+                    .ok()
+            });
+            // an unknown fingerprint, perhaps from a newer broker, falls back to plain TLS
+            let fingerprint: Option<BrowserFingerprint> =
+                fingerprint.as_ref().and_then(|f| f.parse().ok());
+            match (fingerprint, ech_config_list) {
+                (None, None) => TlsDialer::new(
+                    lower,
+                    TlsConnector::new()
+                        .use_sni(sni_domain.is_some())
+                        .danger_accept_invalid_certs(true)
+                        .danger_accept_invalid_hostnames(true)
+                        .min_protocol_version(None)
+                        .max_protocol_version(None),
+                    domain,
+                )
+                .dynamic(),
+                (fingerprint, ech_config_list) => BrowserTlsDialer {
+                    inner: lower,
+                    domain,
+                    fingerprint: fingerprint.unwrap_or_default(),
+                    ech_config_list,
+                }
+                .dynamic(),
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hinted_rungs_come_first() {
+        let addr: SocketAddr = "1.2.3.4:5".parse().unwrap();
+        let sosistab3 = RouteDescriptor::ConnTest {
+            ping_count: 1,
+            lower: RouteDescriptor::Sosistab3 {
+                cookie: "abc".into(),
+                lower: RouteDescriptor::Tcp(addr).into(),
+            }
+            .into(),
+        };
+        let meek = RouteDescriptor::Meek {
+            host: "example.com".into(),
+            path: "/meek".into(),
+            lower: RouteDescriptor::Tcp(addr).into(),
+        };
+        let ladder = RouteDescriptor::Fallback(vec![sosistab3, RouteDescriptor::Tcp(addr), meek]);
+        let hints = ["meek".to_string(), "tcp".to_string()];
+        let RouteDescriptor::Fallback(ordered) = order_by_hints(ladder, &hints) else {
+            panic!("not a fallback")
+        };
+        let ranks: Vec<_> = ordered.iter().map(|r| route_rank(r, &hints)).collect();
+        assert_eq!(ranks, vec![0, 1, 2]);
+        assert!(matches!(ordered[0], RouteDescriptor::Meek { .. }));
+    }
+
+    #[test]
+    fn bridge_bundles_need_the_broker_signature() {
+        let broker_sk = ed25519_dalek::SigningKey::from_bytes(&[7; 32]);
+        let keys = BrokerKeys {
+            master: hex::encode(broker_sk.verifying_key().as_bytes()),
+            mizaru_free: String::new(),
+            mizaru_plus: String::new(),
+        };
+        let bundle = |sk: &ed25519_dalek::SigningKey, expiry: u64| {
+            geph5_broker_protocol::encode_bridge_bundle(&geph5_broker_protocol::Signed::new(
+                BridgeBundle {
+                    routes: vec![],
+                    expiry,
+                },
+                DOMAIN_BRIDGE_BUNDLE,
+                sk,
+            ))
+        };
+        let far_future = u64::MAX;
+        assert!(verify_bridge_bundle(&bundle(&broker_sk, far_future), Some(&keys)).is_ok());
+        assert!(verify_bridge_bundle(&bundle(&broker_sk, far_future), None).is_err());
+        assert!(verify_bridge_bundle(&bundle(&broker_sk, 1), Some(&keys)).is_err());
+        let other_sk = ed25519_dalek::SigningKey::from_bytes(&[8; 32]);
+        assert!(verify_bridge_bundle(&bundle(&other_sk, far_future), Some(&keys)).is_err());
+    }
+}
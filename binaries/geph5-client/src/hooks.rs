@@ -0,0 +1,108 @@
+//! User-configured hooks that fire on connection events, either by POSTing the event to a URL or by running a script.
+
+use std::{sync::LazyLock, time::Duration};
+
+use anyctx::AnyCtx;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    events::{wait_events, ConnEvent, TimedConnEvent},
+    Config,
+};
+
+/// Hooks that fire on connection events. Each receives the event as JSON.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Hooks {
+    #[serde(default)]
+    pub on_connect: Option<Hook>,
+    #[serde(default)]
+    pub on_disconnect: Option<Hook>,
+    /// Fires when the exit we are connected through changes, but not when only the bridge does.
+    #[serde(default)]
+    pub on_exit_change: Option<Hook>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Hook {
+    /// POSTs the event as a JSON body.
+    Url(String),
+    /// Runs the script with the hook name as its argument, and the event as JSON in the `GEPH_EVENT` environment variable.
+    Script(String),
+}
+
+impl Hook {
+    async fn fire(&self, name: &str, event: &TimedConnEvent) -> anyhow::Result<()> {
+        static CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
+            reqwest::ClientBuilder::new()
+                .timeout(Duration::from_secs(30))
+                .no_proxy()
+                .build()
+                .unwrap()
+        });
+        match self {
+            Hook::Url(url) => {
+                CLIENT
+                    .post(url)
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .body(serde_json::to_vec(event)?)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+            Hook::Script(script) => {
+                let status = smol::process::Command::new(script)
+                    .arg(name)
+                    .env("GEPH_EVENT", serde_json::to_string(event)?)
+                    .status()
+                    .await?;
+                anyhow::ensure!(status.success(), "hook script exited with {status}");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Watches the connection events and fires the matching hooks.
+pub async fn hooks_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    let hooks = &ctx.init().hooks;
+    if hooks.on_connect.is_none() && hooks.on_disconnect.is_none() && hooks.on_exit_change.is_none()
+    {
+        return smol::future::pending().await;
+    }
+    let mut after = None;
+    let mut last_exit = None;
+    loop {
+        for event in wait_events(ctx, after, Duration::from_secs(30)).await {
+            after = Some(event.seqno);
+            let (name, hook) = match &event.event {
+                ConnEvent::Connected { exit, .. } => {
+                    let changed = last_exit
+                        .replace(exit.b2e_listen)
+                        .is_some_and(|last| last != exit.b2e_listen);
+                    if changed {
+                        spawn_hook(&hooks.on_exit_change, "on_exit_change", &event);
+                    }
+                    ("on_connect", &hooks.on_connect)
+                }
+                ConnEvent::Disconnected { .. } => ("on_disconnect", &hooks.on_disconnect),
+                _ => continue,
+            };
+            spawn_hook(hook, name, &event);
+        }
+    }
+}
+
+/// Fires a hook in the background, so that a slow webhook or script does not hold up later events.
+fn spawn_hook(hook: &Option<Hook>, name: &'static str, event: &TimedConnEvent) {
+    let Some(hook) = hook.clone() else {
+        return;
+    };
+    let event = event.clone();
+    smolscale::spawn(async move {
+        if let Err(err) = hook.fire(name, &event).await {
+            tracing::warn!(name, err = debug(err), "connection hook failed");
+        }
+    })
+    .detach();
+}
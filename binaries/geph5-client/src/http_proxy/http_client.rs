@@ -12,7 +12,7 @@ use std::future::Future;
 use std::pin::Pin;
 use std::task::{self, Poll};
 
-use crate::{client_inner::open_conn, Config};
+use crate::{client_inner::open_conn_with_rules, listeners::RuleOverrides, Config};
 
 use super::address::host_addr;
 use super::rt_compat::HyperRtCompat;
@@ -20,11 +20,12 @@ use super::rt_compat::HyperRtCompat;
 #[derive(Clone)]
 pub struct Connector {
     ctx: AnyCtx<Config>,
+    rules: RuleOverrides,
 }
 
 impl Connector {
-    pub fn new(ctx: AnyCtx<Config>) -> Connector {
-        Connector { ctx }
+    pub fn new(ctx: AnyCtx<Config>, rules: RuleOverrides) -> Connector {
+        Connector { ctx, rules }
     }
 }
 
@@ -39,6 +40,7 @@ impl tower_service::Service<Uri> for Connector {
 
     fn call(&mut self, dst: Uri) -> Self::Future {
         let ctx = self.ctx.clone();
+        let rules = self.rules.clone();
         SocksConnecting {
             fut: async move {
                 match host_addr(&dst) {
@@ -47,7 +49,7 @@ impl tower_service::Service<Uri> for Connector {
                         let err = Error::new(ErrorKind::Other, "URI must be a valid Address");
                         Err(err)
                     }
-                    Some(addr) => open_conn(&ctx, "tcp", &addr.to_string())
+                    Some(addr) => open_conn_with_rules(&ctx, &rules, "tcp", &addr.to_string())
                         .await
                         .map_err(|e| std::io::Error::new(std::io::ErrorKind::ConnectionRefused, e))
                         .map(|c| HyperRtCompat::new(PicomuxConnection(c.compat()))),
@@ -4,44 +4,44 @@ mod rt_compat;
 
 use std::{net::SocketAddr, str::FromStr as _};
 
-pub async fn http_proxy_serve(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
-    let shared_server: SharedProxyServer = ProxyServer::new_shared(ctx.clone());
-    let listen = ctx.init().http_proxy_listen;
-    if let Some(listen) = listen {
-        let tcp_listener = tokio::net::TcpListener::bind(&listen).await?;
-        let mut join_set = JoinSet::new();
-        loop {
-            let (stream, addr) = match tcp_listener.accept().await {
-                Ok(x) => x,
-                Err(e) => {
-                    tracing::info!(%e, "failed to accept inbound HTTP proxy connection");
-                    continue;
-                }
-            };
-            let ctx = ctx.clone();
-            let cloned_server = shared_server.clone();
-            join_set.spawn(async move {
-                tracing::trace!(%addr, "accepted a HTTP proxy connection");
-
-                let service = service_fn(move |req: Request<Incoming>| {
-                    server_dispatch(req, addr, cloned_server.clone(), ctx.clone())
-                });
-
-                let result = hyper::server::conn::http1::Builder::new()
-                    .preserve_header_case(true)
-                    .title_case_headers(true)
-                    .serve_connection(hyper_util::rt::TokioIo::new(stream), service)
-                    .with_upgrades()
-                    .await;
-                if let Err(e) = result {
-                    tracing::error!(%addr, %e, "error serving HTTP proxy conn: {addr}");
-                }
-            });
+#[tracing::instrument(skip_all, fields(listen = %listener.listen))]
+pub async fn http_proxy_serve(
+    ctx: &AnyCtx<Config>,
+    listener: &ProxyListener,
+) -> anyhow::Result<()> {
+    let shared_server: SharedProxyServer = ProxyServer::new_shared(ctx.clone(), listener.clone());
+    let tcp_listener = tokio::net::TcpListener::from_std(bind_tcp(listener.listen)?)?;
+    let mut join_set = JoinSet::new();
+    loop {
+        let (stream, addr) = match tcp_listener.accept().await {
+            Ok(x) => x,
+            Err(e) => {
+                tracing::info!(%e, "failed to accept inbound HTTP proxy connection");
+                continue;
+            }
+        };
+        if !listener.admits(ctx, Some(addr.ip())) {
+            continue;
         }
-        // while let Some(_) = join_set.join_next().await {}
-        // Ok(())
-    } else {
-        smol::future::pending().await
+        let ctx = ctx.clone();
+        let cloned_server = shared_server.clone();
+        join_set.spawn(async move {
+            tracing::trace!(%addr, "accepted a HTTP proxy connection");
+
+            let service = service_fn(move |req: Request<Incoming>| {
+                server_dispatch(req, addr, cloned_server.clone(), ctx.clone())
+            });
+
+            let result = hyper::server::conn::http1::Builder::new()
+                .preserve_header_case(true)
+                .title_case_headers(true)
+                .serve_connection(hyper_util::rt::TokioIo::new(stream), service)
+                .with_upgrades()
+                .await;
+            if let Err(e) = result {
+                tracing::error!(%addr, %e, "error serving HTTP proxy conn: {addr}");
+            }
+        });
     }
 }
 type SharedProxyServer = std::sync::Arc<ProxyServer>;
@@ -52,6 +52,24 @@ async fn server_dispatch(
     proxy_server: SharedProxyServer,
     ctx: AnyCtx<Config>,
 ) -> std::io::Result<Response<HttpEither<BoxBody<Bytes, hyper::Error>, Empty<Bytes>>>> {
+    if req.uri().authority().is_none() && req.uri().path() == "/proxy.pac" {
+        // not a proxy request, but a request for the PAC file
+        return Ok(pac_response::<Full<Bytes>>(&ctx)
+            .map(|body| HttpEither::Left(body.map_err(|_| unreachable!()).boxed())));
+    }
+    if let Some(auth) = &proxy_server.listener.auth {
+        let authorized = req
+            .headers()
+            .get("Proxy-Authorization")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| auth.check_basic(v))
+            .unwrap_or_default();
+        if !authorized {
+            stat_incr_num(&ctx, "auth_failures", 1.0);
+            tracing::debug!(client_addr = %client_addr, "HTTP proxy client failed authentication");
+            return Ok(make_proxy_auth_required());
+        }
+    }
     let host = match host_addr(req.uri()) {
         None => {
             if req.uri().authority().is_some() {
@@ -144,6 +162,26 @@ async fn server_dispatch(
             host = %host,
             "CONNECT relay connected"
         );
+        // dial before answering, so that the client sees a proper error if the destination is unreachable
+        let stream = match open_conn_with_rules(
+            &ctx,
+            &proxy_server.listener.rules,
+            "tcp",
+            &host.to_string(),
+        )
+        .await
+        {
+            Ok(stream) => stream,
+            Err(err) => {
+                tracing::debug!(
+                    client_addr = %client_addr,
+                    host = %host,
+                    error = ?err,
+                    "CONNECT dial failed"
+                );
+                return Ok(make_bad_gateway(&host.to_string()));
+            }
+        };
         tokio::spawn(async move {
             match hyper::upgrade::on(&mut req).await {
                 Ok(upgraded) => {
@@ -152,10 +190,7 @@ async fn server_dispatch(
                         host = %host,
                         "CONNECT tunnel upgrade success"
                     );
-                    let stream = open_conn(&ctx, "tcp", &host.to_string()).await;
-                    if let Ok(stream) = stream {
-                        establish_connect_tunnel(upgraded, stream, client_addr).await
-                    }
+                    establish_connect_tunnel(upgraded, stream, client_addr).await
                 }
                 Err(e) => {
                     tracing::info!(
@@ -190,7 +225,6 @@ async fn server_dispatch(
                     tracing::trace!(
                         method = %method,
                         client_addr = %client_addr,
-                        proxy_addr = "127.0.0.1:1080",
                         host = %host,
                         error = %err,
                         "HTTP relay failed"
@@ -277,6 +311,31 @@ async fn establish_connect_tunnel(
     );
 }
 
+fn make_proxy_auth_required() -> Response<HttpEither<BoxBody<Bytes, hyper::Error>, Empty<Bytes>>> {
+    let mut resp: Response<HttpEither<BoxBody<Bytes, hyper::Error>, Empty<Bytes>>> = Response::new(
+        HttpEither::Left(Empty::new().map_err(|_| unreachable!()).boxed()),
+    );
+    *resp.status_mut() = StatusCode::PROXY_AUTHENTICATION_REQUIRED;
+    resp.headers_mut().insert(
+        "Proxy-Authenticate",
+        HeaderValue::from_static("Basic realm=\"geph\""),
+    );
+    resp
+}
+
+fn make_bad_gateway(
+    host: &str,
+) -> Response<HttpEither<BoxBody<Bytes, hyper::Error>, Empty<Bytes>>> {
+    let mut resp: Response<HttpEither<BoxBody<Bytes, hyper::Error>, Empty<Bytes>>> =
+        Response::new(HttpEither::Left(
+            Full::new(Bytes::from(format!("Could not connect to {}", host)))
+                .map_err(|_| unreachable!())
+                .boxed(),
+        ));
+    *resp.status_mut() = StatusCode::BAD_GATEWAY;
+    resp
+}
+
 fn make_bad_request() -> Response<HttpEither<BoxBody<Bytes, hyper::Error>, Empty<Bytes>>> {
     let mut resp: Response<HttpEither<BoxBody<Bytes, hyper::Error>, Empty<Bytes>>> = Response::new(
         HttpEither::Left(Empty::new().map_err(|_| unreachable!()).boxed()),
@@ -287,7 +346,10 @@ fn make_bad_request() -> Response<HttpEither<BoxBody<Bytes, hyper::Error>, Empty
 
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
-use crate::{client_inner::open_conn, Config};
+use crate::{
+    client_inner::open_conn_with_rules, listeners::ProxyListener, pac::pac_response,
+    socket_activation::bind_tcp, stats::stat_incr_num, Config,
+};
 
 use self::address::{host_addr, Address};
 fn authority_addr(scheme_str: Option<&str>, authority: &Authority) -> Option<Address> {
@@ -459,11 +521,12 @@ fn set_conn_keep_alive(version: Version, headers: &mut HeaderMap<HeaderValue>, k
 #[derive(Clone)]
 pub struct ProxyServer {
     client: http_client::CtxClient,
+    listener: ProxyListener,
 }
 
 impl ProxyServer {
-    fn new(ctx: AnyCtx<Config>) -> ProxyServer {
-        let connector = http_client::Connector::new(ctx);
+    fn new(ctx: AnyCtx<Config>, listener: ProxyListener) -> ProxyServer {
+        let connector = http_client::Connector::new(ctx, listener.rules.clone());
         let proxy_client: http_client::CtxClient =
             hyper_util::client::legacy::Builder::new(hyper_util::rt::TokioExecutor::new())
                 .http1_preserve_header_case(true)
@@ -471,9 +534,10 @@ impl ProxyServer {
                 .build(connector);
         ProxyServer {
             client: proxy_client,
+            listener,
         }
     }
-    fn new_shared(ctx: AnyCtx<Config>) -> SharedProxyServer {
-        std::sync::Arc::new(ProxyServer::new(ctx))
+    fn new_shared(ctx: AnyCtx<Config>, listener: ProxyListener) -> SharedProxyServer {
+        std::sync::Arc::new(ProxyServer::new(ctx, listener))
     }
 }
@@ -1,52 +1,112 @@
+//! The Geph5 client, which runs either on its own or embedded in another program.
+//!
+//! Besides serving local proxies and VPN mode as configured, a [Client] can tunnel individual connections for the program embedding it:
+//!
+//! ```no_run
+//! # async fn example(config: geph5_client::Config) -> anyhow::Result<()> {
+//! use futures_util::{AsyncReadExt, AsyncWriteExt};
+//! use geph5_client::{Client, Protocol};
+//!
+//! let client = Client::start(config);
+//! let mut stream = client.open(Protocol::Tcp, "example.com:80").await?;
+//! stream
+//!     .write_all(b"GET / HTTP/1.0\r\nHost: example.com\r\n\r\n")
+//!     .await?;
+//! let mut response = vec![];
+//! stream.read_to_end(&mut response).await?;
+//! # Ok(())
+//! # }
+//! ```
+
 use std::ffi::c_char;
 use std::ffi::c_int;
 use std::ffi::CStr;
 use std::io::Write;
 
+pub use accounting::DomainUsage;
+pub use announcements::AnnouncementInfo;
 pub use broker::broker_client;
 pub use broker::BrokerSource;
+pub use check::{ConfigProblem, Severity};
 use bytes::Bytes;
 pub use client::Client;
-pub use client::{BridgeMode, BrokerKeys, Config};
+pub use client::{BridgeMode, BrokerKeys, Config, HostAction, ResolvePolicy};
+pub use conntrack::StreamInfo;
 pub use control_prot::{ConnInfo, ControlClient};
+pub use dial_stats::DialStats;
+pub use dns::BlocklistSource;
+pub use events::{ConnEvent, TimedConnEvent};
+pub use forward::{PortForward, ReverseForward};
 pub use get_dialer::ExitConstraint;
+pub use hooks::{Hook, Hooks};
+pub use listeners::{ListenerProtocol, ProxyListener, RuleOverrides};
 use nanorpc::JrpcRequest;
 use nanorpc::RpcTransport;
-use once_cell::sync::OnceCell;
+pub use proxy_auth::ProxyAuth;
+pub use quality::QualityAlerts;
+pub use secrets::resolve_secrets;
+pub use socket_activation::inherit_tcp_listeners;
+pub use speedtest::SpeedTestResult;
+pub use stream::{Protocol, TunnelStream};
+pub use usage::{UsageGranularity, UsageRecord};
 
+mod accounting;
+#[cfg(target_os = "android")]
+mod android;
+mod announcements;
 mod auth;
+mod check;
 mod broker;
 mod china;
 mod client;
 mod client_inner;
+mod conntrack;
 mod control_prot;
 mod database;
+mod debug_bundle;
+mod dial_stats;
+mod dns;
+mod domain_rules;
+mod events;
+pub mod ffi;
+mod forward;
+mod hooks;
 mod http_proxy;
+mod listeners;
 mod litecopy;
 pub mod logging;
+mod metrics;
 
 mod get_dialer;
 mod pac;
+mod profiles;
+mod proxy_auth;
+mod quality;
+mod secrets;
+mod reload;
+mod sni;
+mod socket_activation;
 mod socks5;
+mod speedtest;
 mod spoof_dns;
 mod stats;
+mod stream;
 mod taskpool;
 mod traffcount;
+mod transparent;
+mod udpnat;
 mod updates;
+mod usage;
 mod vpn;
 
 // C interface
 
-static CLIENT: OnceCell<Client> = OnceCell::new();
-
 #[no_mangle]
 pub unsafe extern "C" fn start_client(cfg: *const c_char) -> libc::c_int {
-    let cfg_str = unsafe { CStr::from_ptr(cfg) }.to_str().unwrap();
-    let cfg: Config = serde_json::from_str(cfg_str).unwrap();
-
-    CLIENT.get_or_init(|| Client::start(cfg));
-
-    0
+    match ffi::geph5_start(cfg) {
+        ffi::GEPH5_ERR_ALREADY_RUNNING => 0,
+        ret => ret,
+    }
 }
 
 #[no_mangle]
@@ -58,7 +118,7 @@ pub unsafe extern "C" fn daemon_rpc(
     let req_str = unsafe { CStr::from_ptr(jrpc_req) }.to_str().unwrap();
     let jrpc: JrpcRequest = serde_json::from_str(req_str).unwrap();
 
-    if let Some(client) = CLIENT.get() {
+    if let Some(client) = ffi::running_client() {
         let ctrl = client.control_client().0;
         if let Ok(response) = smolscale::block_on(async move { ctrl.call_raw(jrpc).await }) {
             let response_json = serde_json::to_string(&response).unwrap();
@@ -77,7 +137,7 @@ pub unsafe extern "C" fn daemon_rpc(
 #[no_mangle]
 pub unsafe extern "C" fn send_pkt(pkt: *const c_char, pkt_len: c_int) -> c_int {
     let slice: &'static [u8] = std::slice::from_raw_parts(pkt as *mut u8, pkt_len as usize);
-    if let Some(client) = CLIENT.get() {
+    if let Some(client) = ffi::running_client() {
         if let Ok(_) = smol::future::block_on(client.send_vpn_packet(Bytes::copy_from_slice(slice)))
         {
             return 0;
@@ -88,7 +148,7 @@ pub unsafe extern "C" fn send_pkt(pkt: *const c_char, pkt_len: c_int) -> c_int {
 
 #[no_mangle]
 pub unsafe extern "C" fn recv_pkt(out_buf: *mut c_char, out_buflen: c_int) -> c_int {
-    if let Some(client) = CLIENT.get() {
+    if let Some(client) = ffi::running_client() {
         if let Ok(pkt) = smol::future::block_on(client.recv_vpn_packet()) {
             return fill_buffer(out_buf, out_buflen, &pkt);
         }
@@ -161,15 +221,46 @@ mod tests {
                 mizaru_plus: "cf6f58868c6d9459b3a63bc2bd86165631b3e916bad7f62b578cd9614e0bcb3b"
                     .into(),
             }),
+            broker_mirrors: vec![],
             // Values that can be overridden by `args`:
             vpn: false,
             spoof_dns: false,
             passthrough_china: false,
+            disable_domain_accounting: false,
+            disable_protocol_telemetry: false,
+            quality_alerts: Default::default(),
+            hooks: Default::default(),
+            pinned_bridges: vec![],
+            bridge_bundle: None,
+            pluggable_transports: Default::default(),
+            kcp: Default::default(),
+            icmp_max_pps: None,
+            dns_tunnel_resolver: None,
+            upstream_proxy: None,
+            fragment: None,
+            shaping: None,
+            bind_interface: None,
+            happy_eyeballs: Default::default(),
+            mux_windows: Default::default(),
+            profiles: Default::default(),
+            profile: None,
             dry_run: false,
             credentials: geph5_broker_protocol::Credential::Secret(String::new()),
             sess_metadata: Default::default(),
             task_limit: None,
             pac_listen: Some(PAC_ADDR),
+            metrics_listen: None,
+            dns_listen: None,
+            transparent_listen: None,
+            doh_upstream: None,
+            doh_overrides: Default::default(),
+            blocklists: vec![],
+            resolve_policy: Default::default(),
+            host_rules: Default::default(),
+            proxy_auth: None,
+            listeners: vec![],
+            forward: vec![],
+            reverse_forward: vec![],
         };
         let cfg_str = CString::new(serde_json::to_string(&cfg).unwrap()).unwrap();
         let cfg_ptr = cfg_str.as_ptr();
@@ -0,0 +1,141 @@
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+};
+
+use anyctx::AnyCtx;
+use futures_util::{future::try_join_all, FutureExt as _};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    client::ResolvePolicy,
+    http_proxy::http_proxy_serve,
+    proxy_auth::ProxyAuth,
+    reload::{live_config, wait_config_change},
+    socks5::socks5_loop,
+    stats::stat_incr_num,
+    Config,
+};
+
+/// A single local proxy listener.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ProxyListener {
+    pub protocol: ListenerProtocol,
+    pub listen: SocketAddr,
+    /// Credentials that clients of this listener must present.
+    #[serde(default)]
+    pub auth: Option<ProxyAuth>,
+    #[serde(default, flatten)]
+    pub acl: ListenAcl,
+    /// Overrides of the global split-tunneling rules, for connections through this listener.
+    #[serde(default, flatten)]
+    pub rules: RuleOverrides,
+}
+
+impl ProxyListener {
+    /// Checks a newly accepted client against the source-IP ACLs, counting the rejection in stats if it is refused.
+    pub fn admits(&self, ctx: &AnyCtx<Config>, peer: Option<IpAddr>) -> bool {
+        self.acl.admits(ctx, self.listen, peer)
+    }
+}
+
+/// Source-IP ACLs for a local listener.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ListenAcl {
+    /// If non-empty, only clients from these networks may connect.
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+    /// Clients from these networks are always refused, even if they are also allowed.
+    #[serde(default)]
+    pub deny: Vec<IpNet>,
+}
+
+impl ListenAcl {
+    /// Checks a newly accepted client of the listener at `listen`, counting the rejection in stats if it is refused. A client whose address we cannot tell is refused, unless the ACLs are empty.
+    pub fn admits(&self, ctx: &AnyCtx<Config>, listen: SocketAddr, peer: Option<IpAddr>) -> bool {
+        let admitted = match peer {
+            Some(peer) => {
+                !self.deny.iter().any(|net| net.contains(&peer))
+                    && (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&peer)))
+            }
+            None => self.allow.is_empty() && self.deny.is_empty(),
+        };
+        if !admitted {
+            tracing::debug!(
+                listen = display(listen),
+                peer = debug(peer),
+                "refusing connection from client outside the ACL"
+            );
+            stat_incr_num(ctx, &format!("rejected:{listen}"), 1.0);
+        }
+        admitted
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ListenerProtocol {
+    Socks5,
+    Http,
+}
+
+/// Per-listener overrides of the global rules deciding how connections are handled. Unset fields fall back to the global config.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RuleOverrides {
+    #[serde(default)]
+    pub passthrough_china: Option<bool>,
+    #[serde(default)]
+    pub resolve_policy: Option<BTreeMap<String, ResolvePolicy>>,
+    /// Scheduling weight of this listener's streams, relative to the default of 16, in both directions of the tunnel.
+    #[serde(default)]
+    pub priority: Option<u8>,
+    /// Whether to compress this listener's streams. If unset, only plaintext, text-heavy protocols like HTTP are compressed.
+    #[serde(default)]
+    pub compress: Option<bool>,
+}
+
+impl Config {
+    /// All the proxy listeners, including those given by the single-listener `socks5_listen` and `http_proxy_listen` fields.
+    pub fn all_listeners(&self) -> Vec<ProxyListener> {
+        let legacy = [
+            (ListenerProtocol::Socks5, self.socks5_listen),
+            (ListenerProtocol::Http, self.http_proxy_listen),
+        ]
+        .into_iter()
+        .filter_map(|(protocol, listen)| {
+            Some(ProxyListener {
+                protocol,
+                listen: listen?,
+                auth: self.proxy_auth.clone(),
+                acl: self.listen_acl.clone(),
+                rules: RuleOverrides::default(),
+            })
+        });
+        legacy.chain(self.listeners.iter().cloned()).collect()
+    }
+}
+
+/// Runs all the configured proxy listeners, restarting them whenever a reload changes them. Connections accepted by the old listeners are closed on restart.
+pub async fn listeners_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    loop {
+        let listeners = live_config(ctx).all_listeners();
+        let serve = async {
+            if listeners.is_empty() {
+                return smol::future::pending().await;
+            }
+            try_join_all(listeners.iter().map(|listener| match listener.protocol {
+                ListenerProtocol::Socks5 => socks5_loop(ctx, listener).boxed(),
+                ListenerProtocol::Http => http_proxy_serve(ctx, listener).boxed(),
+            }))
+            .await?;
+            anyhow::Ok(())
+        };
+        let changed = async {
+            wait_config_change(ctx, |config| config.all_listeners()).await;
+            tracing::info!("proxy listeners changed, restarting them");
+            anyhow::Ok(())
+        };
+        smol::future::race(serve, changed).await?;
+    }
+}
@@ -1,5 +1,6 @@
 use futures_util::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
+/// Copies from the reader to the writer until EOF, then closes the writer, which half-closes it if it is a socket or a tunneled stream.
 pub async fn litecopy<R, W>(mut reader: R, mut writer: W) -> Result<u64, std::io::Error>
 where
     R: AsyncRead + Unpin,
@@ -9,6 +10,7 @@ where
     loop {
         let val = async_io_bufpool::pooled_read(&mut reader).await?;
         if val.is_empty() {
+            writer.close().await?;
             return Ok(n);
         }
         writer.write_all(&val).await?;
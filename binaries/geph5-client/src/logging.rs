@@ -1,37 +1,88 @@
-use arc_writer::ArcWriter;
-use once_cell::sync::Lazy;
-use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+use std::{collections::VecDeque, io::Write};
 
-/// In-memory buffer for JSON formatted logs
-static JSON_LOGS: Lazy<ArcWriter<Vec<u8>>> = Lazy::new(|| ArcWriter::new(Vec::new()));
+use once_cell::sync::{Lazy, OnceCell};
+use parking_lot::Mutex;
+use tracing_subscriber::{
+    fmt, layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Registry,
+};
+
+/// How many log lines we keep in memory.
+const MAX_LOG_LINES: usize = 10000;
+
+/// In-memory ring buffer of JSON formatted log lines, along with any incomplete trailing line.
+static JSON_LOGS: Lazy<Mutex<(VecDeque<String>, Vec<u8>)>> =
+    Lazy::new(|| Mutex::new((VecDeque::new(), Vec::new())));
+
+/// Handle for swapping out the log filter at runtime.
+static FILTER_HANDLE: OnceCell<reload::Handle<EnvFilter, Registry>> = OnceCell::new();
+
+/// Writes into the in-memory ring buffer, one line at a time.
+struct RingWriter;
+
+impl Write for RingWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut logs = JSON_LOGS.lock();
+        let (lines, partial) = &mut *logs;
+        partial.extend_from_slice(buf);
+        while let Some(newline) = partial.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = partial.drain(..=newline).collect();
+            lines.push_back(String::from_utf8_lossy(&line[..newline]).to_string());
+            if lines.len() > MAX_LOG_LINES {
+                lines.pop_front();
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
 
 /// Initialize the tracing subscribers for logging
 pub fn init_logging() -> anyhow::Result<()> {
-    // Create a JSON writer that writes to our in-memory buffer
-    let json_writer = JSON_LOGS.clone();
+    let (filter, handle) = reload::Layer::new(
+        EnvFilter::builder()
+            .with_default_directive("geph=debug".parse()?)
+            .from_env_lossy(),
+    );
+    let _ = FILTER_HANDLE.set(handle);
 
     tracing_subscriber::registry()
+        // Set filtering based on environment or defaults, changeable at runtime
+        .with(filter)
         // Standard logs to stderr (for console display)
         .with(fmt::layer().compact().with_writer(std::io::stderr))
-        // Text logs to the LOGS global buffer
-        .with(fmt::layer().json().with_writer(move || json_writer.clone()))
-        // Set filtering based on environment or defaults
-        .with(
-            EnvFilter::builder()
-                .with_default_directive("geph=debug".parse()?)
-                .from_env_lossy(),
-        )
+        // JSON logs to the in-memory ring buffer
+        .with(fmt::layer().json().with_writer(|| RingWriter))
         .init();
 
     Ok(())
 }
 
+/// Replaces the log filter with the given directives, in the same syntax as RUST_LOG.
+pub fn set_log_filter(directives: &str) -> anyhow::Result<()> {
+    let filter = EnvFilter::builder().parse(directives)?;
+    FILTER_HANDLE
+        .get()
+        .ok_or_else(|| anyhow::anyhow!("logging was not initialized"))?
+        .reload(filter)?;
+    tracing::info!(directives, "log filter changed");
+    Ok(())
+}
+
 /// Get the current JSON logs as a String
 pub fn get_json_logs() -> String {
     let logs = JSON_LOGS.lock();
+    logs.0.iter().map(|line| format!("{line}\n")).collect()
+}
 
-    // Convert the byte buffer to a string
-    let log_string = String::from_utf8_lossy(&logs);
-
-    log_string.to_string()
+/// Get the last `limit` JSON log lines, oldest first.
+pub fn get_last_log_lines(limit: usize) -> Vec<String> {
+    let logs = JSON_LOGS.lock();
+    logs.0
+        .iter()
+        .skip(logs.0.len().saturating_sub(limit))
+        .cloned()
+        .collect()
 }
@@ -0,0 +1,148 @@
+use anyctx::AnyCtx;
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt::Write as _;
+
+use crate::{
+    socket_activation::bind_tcp,
+    stats::{stat_all_hists, stat_all_nums},
+    Config,
+};
+
+/// Serves the client's numeric stats on `/metrics`, in the Prometheus text exposition format.
+pub async fn metrics_serve(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    let Some(listen) = ctx.init().metrics_listen else {
+        return smol::future::pending().await;
+    };
+    let listener = tokio::net::TcpListener::from_std(bind_tcp(listen)?)?;
+    tracing::info!(listen = display(listen), "serving Prometheus metrics");
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let io = TokioIo::new(stream);
+        let ctx = ctx.clone();
+
+        tokio::task::spawn(async move {
+            let service = service_fn(move |req| serve_metrics(req, ctx.clone()));
+            if let Err(err) = hyper::server::conn::http1::Builder::new()
+                .serve_connection(io, service)
+                .await
+            {
+                tracing::debug!(err = debug(err), "error serving metrics connection");
+            }
+        });
+    }
+}
+
+async fn serve_metrics(
+    req: Request<hyper::body::Incoming>,
+    ctx: AnyCtx<Config>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    if req.uri().path() != "/metrics" {
+        let mut resp = Response::new(Full::new(Bytes::new()));
+        *resp.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(resp);
+    }
+    let mut resp = Response::new(Full::new(render_metrics(&ctx).into()));
+    resp.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        http::HeaderValue::from_static("text/plain; version=0.0.4"),
+    );
+    Ok(resp)
+}
+
+/// Stats that go up and down, which are exported as gauges. The rest only ever count up, so they are exported as counters.
+const GAUGES: &[&str] = &["open_streams", "ping", "quality_score"];
+
+/// Renders all stats as Prometheus samples. Stats named like `blocked:ads` become the metric `geph5_blocked` with the label `key="ads"`. Histograms become Prometheus histograms, in seconds.
+fn render_metrics(ctx: &AnyCtx<Config>) -> String {
+    let mut out = String::new();
+    // the samples of a metric have to come together, right after its type
+    let mut families: BTreeMap<String, (&str, Vec<String>)> = BTreeMap::new();
+    for (name, value) in stat_all_nums(ctx) {
+        let (metric, key) = match name.split_once(':') {
+            Some((metric, key)) => (metric, Some(key)),
+            None => (name.as_str(), None),
+        };
+        let kind = if GAUGES.contains(&metric) {
+            "gauge"
+        } else {
+            "counter"
+        };
+        let metric = sanitize(metric);
+        let sample = match key {
+            Some(key) => {
+                let key = key.replace('\\', "\\\\").replace('"', "\\\"");
+                format!("geph5_{metric}{{key=\"{key}\"}} {value}")
+            }
+            None => format!("geph5_{metric} {value}"),
+        };
+        families
+            .entry(metric)
+            .or_insert_with(|| (kind, vec![]))
+            .1
+            .push(sample);
+    }
+    for (metric, (kind, samples)) in families {
+        let _ = writeln!(&mut out, "# TYPE geph5_{metric} {kind}");
+        for sample in samples {
+            let _ = writeln!(&mut out, "{sample}");
+        }
+    }
+    for (name, hist) in stat_all_hists(ctx) {
+        let metric = sanitize(&name);
+        let _ = writeln!(&mut out, "# TYPE geph5_{metric}_seconds histogram");
+        for (bound, cumulative) in &hist.buckets {
+            let _ = writeln!(
+                &mut out,
+                "geph5_{metric}_seconds_bucket{{le=\"{bound}\"}} {cumulative}"
+            );
+        }
+        let _ = writeln!(
+            &mut out,
+            "geph5_{metric}_seconds_bucket{{le=\"+Inf\"}} {}",
+            hist.count
+        );
+        let _ = writeln!(&mut out, "geph5_{metric}_seconds_sum {}", hist.sum);
+        let _ = writeln!(&mut out, "geph5_{metric}_seconds_count {}", hist.count);
+    }
+    out
+}
+
+fn sanitize(metric: &str) -> String {
+    metric
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::stats::{stat_incr_num, stat_set_num};
+
+    #[test]
+    fn each_family_gets_one_type_line() {
+        let config: Config = serde_json::from_value(json!({"exit_constraint": "auto"})).unwrap();
+        let ctx = AnyCtx::new(config);
+        stat_incr_num(&ctx, "blocked:ads", 2.0);
+        stat_incr_num(&ctx, "blocked:malware", 1.0);
+        stat_set_num(&ctx, "ping", 0.05);
+        let rendered = render_metrics(&ctx);
+        assert_eq!(
+            rendered,
+            "# TYPE geph5_blocked counter\n\
+             geph5_blocked{key=\"ads\"} 2\n\
+             geph5_blocked{key=\"malware\"} 1\n\
+             # TYPE geph5_ping gauge\n\
+             geph5_ping 0.05\n"
+        );
+    }
+}
@@ -5,22 +5,36 @@ use hyper::service::service_fn;
 use hyper::{Request, Response};
 use hyper_util::rt::TokioIo;
 use std::convert::Infallible;
+use std::fmt::Write as _;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
 
-use crate::Config;
+use crate::{
+    china::chinese_domains,
+    client::{CtxField, HostAction},
+    listeners::ListenerProtocol,
+    reload::live_config,
+    socket_activation::bind_tcp,
+    Config,
+};
 
 pub async fn pac_serve(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
-    let listener = tokio::net::TcpListener::bind(if let Some(listen) = ctx.init().pac_listen {
-        listen
-    } else {
-        smol::future::pending().await
-    })
-    .await?;
+    let Some(listen) = ctx.init().pac_listen else {
+        return smol::future::pending().await;
+    };
+    let listener = tokio::net::TcpListener::from_std(bind_tcp(listen)?)?;
 
     // Clone the context for use in the spawned tasks
     let ctx = ctx.clone();
 
     loop {
-        let (stream, _) = listener.accept().await?;
+        let (stream, peer_addr) = listener.accept().await?;
+        if !live_config(&ctx)
+            .listen_acl
+            .admits(&ctx, listen, Some(peer_addr.ip()))
+        {
+            continue;
+        }
         let io = TokioIo::new(stream);
         // Clone the context for this connection
         let ctx = ctx.clone();
@@ -42,9 +56,158 @@ async fn serve_pac(
     _req: Request<hyper::body::Incoming>,
     ctx: AnyCtx<Config>,
 ) -> Result<Response<Full<Bytes>>, Infallible> {
-    let pac_addr = ctx.init().http_proxy_listen.unwrap();
-    Ok(Response::new(Full::new(Bytes::from(format!(
-        "function FindProxyForURL(url, host){{return 'PROXY {}';}}",
-        pac_addr
-    )))))
+    Ok(pac_response(&ctx))
+}
+
+/// Builds an HTTP response containing the PAC file.
+pub fn pac_response<B: From<Bytes>>(ctx: &AnyCtx<Config>) -> Response<B> {
+    let mut resp = Response::new(B::from(pac_script(ctx)));
+    resp.headers_mut().insert(
+        "content-type",
+        http::HeaderValue::from_static("application/x-ns-proxy-autoconfig"),
+    );
+    resp
+}
+
+/// The last generated PAC file, along with the config it was generated from.
+static PAC_SCRIPT: CtxField<parking_lot::Mutex<Option<(Arc<Config>, Bytes)>>> =
+    |_| parking_lot::Mutex::new(None);
+
+/// Gets the PAC file for the current config, only generating it again after a reload.
+fn pac_script(ctx: &AnyCtx<Config>) -> Bytes {
+    let config = live_config(ctx);
+    let mut cached = ctx.get(PAC_SCRIPT).lock();
+    match cached.as_ref() {
+        Some((generated_from, script)) if Arc::ptr_eq(generated_from, &config) => script.clone(),
+        _ => {
+            let script = Bytes::from(generate_pac(&config));
+            *cached = Some((config, script.clone()));
+            script
+        }
+    }
+}
+
+/// Generates a PAC file that sends traffic through our proxy, except for local addresses and whatever the split-tunneling rules let through directly. Like in the proxy itself, host rules come before everything else, and the most specific one wins.
+fn generate_pac(cfg: &Config) -> String {
+    let loopback = |addr: SocketAddr| {
+        if addr.ip().is_unspecified() {
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port())
+        } else {
+            addr
+        }
+    };
+    let listeners = cfg.all_listeners();
+    let first_listen = |protocol| {
+        listeners
+            .iter()
+            .find(|listener| listener.protocol == protocol)
+            .map(|listener| listener.listen)
+    };
+    let proxy = match (
+        first_listen(ListenerProtocol::Http),
+        first_listen(ListenerProtocol::Socks5),
+    ) {
+        (Some(http), Some(socks5)) => {
+            format!("PROXY {}; SOCKS5 {}", loopback(http), loopback(socks5))
+        }
+        (Some(http), None) => format!("PROXY {}", loopback(http)),
+        (None, Some(socks5)) => format!("SOCKS5 {}", loopback(socks5)),
+        (None, None) => "DIRECT".to_string(),
+    };
+
+    // blocked hosts go to the proxy too, which refuses them
+    let mut host_rules = String::new();
+    let mut wildcard_rule = "null";
+    for (pattern, action) in cfg.host_rules.iter() {
+        let direct = *action == HostAction::Direct;
+        let pattern = pattern.trim_start_matches("*.").to_lowercase();
+        if pattern == "*" {
+            wildcard_rule = if direct { "true" } else { "false" };
+        } else {
+            let _ = write!(&mut host_rules, "{:?}:{direct},", pattern);
+        }
+    }
+
+    let mut direct_domains = String::new();
+    if cfg.passthrough_china {
+        for domain in chinese_domains() {
+            let _ = write!(&mut direct_domains, "{:?}:1,", domain);
+        }
+    }
+
+    format!(
+        r#"var proxy = "{proxy}";
+var direct = {{{direct_domains}}};
+var hostRules = {{{host_rules}}};
+var wildcardRule = {wildcard_rule};
+function hostRule(h) {{
+    while (true) {{
+        if (hostRules.hasOwnProperty(h)) {{
+            return hostRules[h];
+        }}
+        var i = h.indexOf(".");
+        if (i < 0) {{
+            return wildcardRule;
+        }}
+        h = h.substring(i + 1);
+    }}
+}}
+function FindProxyForURL(url, host) {{
+    if (isPlainHostName(host) || host === "localhost") {{
+        return "DIRECT";
+    }}
+    var rule = hostRule(host.toLowerCase().replace(/\.$/, ""));
+    if (rule !== null) {{
+        return rule ? "DIRECT" : proxy;
+    }}
+    if (/^\d+\.\d+\.\d+\.\d+$/.test(host)) {{
+        if (isInNet(host, "10.0.0.0", "255.0.0.0") ||
+            isInNet(host, "172.16.0.0", "255.240.0.0") ||
+            isInNet(host, "192.168.0.0", "255.255.0.0") ||
+            isInNet(host, "169.254.0.0", "255.255.0.0") ||
+            isInNet(host, "127.0.0.0", "255.0.0.0")) {{
+            return "DIRECT";
+        }}
+        return proxy;
+    }}
+    var h = host.toLowerCase();
+    while (true) {{
+        if (direct.hasOwnProperty(h)) {{
+            return "DIRECT";
+        }}
+        var i = h.indexOf(".");
+        if (i < 0) {{
+            break;
+        }}
+        h = h.substring(i + 1);
+    }}
+    return proxy;
+}}
+"#
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn host_rules_in_pac() {
+        let config: Config = serde_json::from_value(json!({
+            "exit_constraint": "auto",
+            "http_proxy_listen": "0.0.0.0:9910",
+            "host_rules": {
+                "*.Example.com": "direct",
+                "ads.example.com": "block",
+                "*": "proxy",
+            },
+        }))
+        .unwrap();
+        let pac = generate_pac(&config);
+        assert!(pac.contains(r#"var proxy = "PROXY 127.0.0.1:9910";"#));
+        assert!(pac.contains(r#"var hostRules = {"example.com":true,"ads.example.com":false,};"#));
+        assert!(pac.contains("var wildcardRule = false;"));
+    }
 }
@@ -0,0 +1,136 @@
+//! Named configuration profiles, like "home", "work", and "travel".
+//!
+//! A profile is a set of top-level config settings that replace the ones in the base config while it is active. For instance, a profile that sets `host_rules` replaces all of the base host rules, rather than adding to them. Switching profiles at runtime goes through the same path as reloading the config, so settings that only change on restart stay as they were.
+
+use std::sync::Arc;
+
+use anyctx::AnyCtx;
+use anyhow::Context;
+use parking_lot::RwLock;
+use serde_json::Value;
+
+use crate::{
+    client::CtxField,
+    reload::{live_config, reload_config},
+    Config,
+};
+
+/// The config as loaded, before any profile is applied.
+static BASE_CONFIG: CtxField<RwLock<Arc<Config>>> = |ctx| RwLock::new(Arc::new(ctx.init().clone()));
+
+impl Config {
+    /// Applies the named profile on top of this config, or gives just the base settings if no profile is given.
+    pub fn with_profile(&self, name: Option<&str>) -> anyhow::Result<Config> {
+        let Some(name) = name else {
+            let mut this = self.clone();
+            this.profile = None;
+            return Ok(this);
+        };
+        let overrides = self
+            .profiles
+            .get(name)
+            .with_context(|| format!("there is no profile named {name:?}"))?;
+        let Value::Object(mut fields) = serde_json::to_value(self)? else {
+            anyhow::bail!("config did not serialize to an object")
+        };
+        for (key, value) in overrides {
+            anyhow::ensure!(
+                key != "profile" && key != "profiles",
+                "profile {name:?} cannot set {key:?}"
+            );
+            anyhow::ensure!(
+                fields.contains_key(key),
+                "profile {name:?} sets {key:?}, which is not a setting"
+            );
+            fields.insert(key.clone(), value.clone());
+        }
+        let mut config: Config = serde_json::from_value(Value::Object(fields))
+            .with_context(|| format!("profile {name:?} is not valid"))?;
+        config.profile = Some(name.to_string());
+        Ok(config)
+    }
+}
+
+/// Remembers the config a client was started with, before its profile was applied.
+pub fn set_base_config(ctx: &AnyCtx<Config>, base: Config) {
+    *ctx.get(BASE_CONFIG).write() = Arc::new(base);
+}
+
+/// The names of all the profiles in the base config.
+pub fn list_profiles(ctx: &AnyCtx<Config>) -> Vec<String> {
+    ctx.get(BASE_CONFIG)
+        .read()
+        .profiles
+        .keys()
+        .cloned()
+        .collect()
+}
+
+/// Switches to the named profile at runtime, or back to the base config if no profile is given.
+pub fn switch_profile(ctx: &AnyCtx<Config>, name: Option<String>) -> anyhow::Result<()> {
+    let base = ctx.get(BASE_CONFIG).read().clone();
+    let config = base.with_profile(name.as_deref())?;
+    tracing::info!(profile = debug(&name), "switching profile");
+    reload_config(ctx, config);
+    Ok(())
+}
+
+/// Applies a reloaded base config, keeping the profile that is currently active unless the reloaded config picks a different one.
+pub fn reload_base_config(ctx: &AnyCtx<Config>, base: Config) {
+    let old_base = ctx.get(BASE_CONFIG).read().clone();
+    let active = if base.profile != old_base.profile {
+        base.profile.clone()
+    } else {
+        live_config(ctx).profile.clone()
+    };
+    let config = match base.with_profile(active.as_deref()) {
+        Ok(config) => config,
+        Err(err) => {
+            tracing::warn!(
+                err = debug(err),
+                "could not apply the profile to the reloaded config, not reloading"
+            );
+            return;
+        }
+    };
+    set_base_config(ctx, base);
+    reload_config(ctx, config);
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::BridgeMode;
+
+    fn config() -> Config {
+        serde_json::from_value(json!({
+            "exit_constraint": "auto",
+            "passthrough_china": true,
+            "profiles": {
+                "travel": {"bridge_mode": "ForceBridges"},
+                "broken": {"bridge_mod": "ForceBridges"},
+            },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn profile_replaces_settings() {
+        let travel = config().with_profile(Some("travel")).unwrap();
+        assert_eq!(travel.bridge_mode, BridgeMode::ForceBridges);
+        assert!(travel.passthrough_china);
+        assert_eq!(travel.profile.as_deref(), Some("travel"));
+
+        let base = config().with_profile(None).unwrap();
+        assert_eq!(base.bridge_mode, BridgeMode::Auto);
+        assert_eq!(base.profile, None);
+    }
+
+    #[test]
+    fn bad_profiles_rejected() {
+        assert!(config().with_profile(Some("nonexistent")).is_err());
+        assert!(config().with_profile(Some("broken")).is_err());
+    }
+}
@@ -0,0 +1,35 @@
+use base64::Engine as _;
+use serde::{Deserialize, Serialize};
+
+/// Username/password credentials that local proxy clients must present.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ProxyAuth {
+    pub username: String,
+    pub password: String,
+}
+
+impl ProxyAuth {
+    /// Checks a username/password pair, as sent in a SOCKS5 (RFC 1929) subnegotiation.
+    pub fn check(&self, username: &[u8], password: &[u8]) -> bool {
+        // compare hashes so that timing doesn't leak how much of the secret matched
+        blake3::hash(username) == blake3::hash(self.username.as_bytes())
+            && blake3::hash(password) == blake3::hash(self.password.as_bytes())
+    }
+
+    /// Checks the value of a `Proxy-Authorization` header using the Basic scheme.
+    pub fn check_basic(&self, header: &str) -> bool {
+        let Some((scheme, encoded)) = header.trim().split_once(' ') else {
+            return false;
+        };
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return false;
+        }
+        let Ok(decoded) = base64::engine::general_purpose::STANDARD.decode(encoded.trim()) else {
+            return false;
+        };
+        match decoded.iter().position(|b| *b == b':') {
+            Some(idx) => self.check(&decoded[..idx], &decoded[idx + 1..]),
+            None => false,
+        }
+    }
+}
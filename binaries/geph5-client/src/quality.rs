@@ -0,0 +1,203 @@
+//! A rolling connection quality score, computed from probe latency, probe loss, and stream-open failures.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use anyctx::AnyCtx;
+use futures_util::{AsyncReadExt, AsyncWriteExt};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use smol_timeout2::TimeoutExt;
+
+use crate::{
+    client::CtxField,
+    client_inner::open_conn,
+    control_prot::{ConnInfo, CURRENT_CONN_INFO},
+    events::{push_event, ConnEvent},
+    stats::{stat_record_hist, stat_set_num},
+    Config,
+};
+
+/// How often we probe the tunnel while connected.
+const PROBE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// A probe that fails, or takes longer than this, counts as lost.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many probes and stream opens the score looks back over.
+const PROBE_WINDOW: usize = 20;
+const OPEN_WINDOW: usize = 50;
+
+/// Latencies at or below this are perfect, and at or above `LATENCY_BAD_MS` are worthless.
+const LATENCY_GOOD_MS: f64 = 150.0;
+const LATENCY_BAD_MS: f64 = 1500.0;
+
+/// Thresholds on the quality score, which ranges from 0 to 100, for emitting degraded and recovered events.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct QualityAlerts {
+    /// A "degraded" event fires when the score drops below this.
+    pub degraded_below: f64,
+    /// A "recovered" event fires when a degraded score climbs back above this.
+    pub recovered_above: f64,
+}
+
+impl Default for QualityAlerts {
+    fn default() -> Self {
+        Self {
+            degraded_below: 50.0,
+            recovered_above: 70.0,
+        }
+    }
+}
+
+#[derive(Default)]
+struct QualityTracker {
+    /// Round-trip times of recent probes in milliseconds, or None for lost probes.
+    probes: VecDeque<Option<f64>>,
+    /// Whether recent stream opens succeeded.
+    opens: VecDeque<bool>,
+    degraded: bool,
+}
+
+impl QualityTracker {
+    fn score(&self) -> Option<f64> {
+        if self.probes.is_empty() && self.opens.is_empty() {
+            return None;
+        }
+        let mut latencies: Vec<f64> = self.probes.iter().flatten().copied().collect();
+        latencies.sort_by(|a, b| a.total_cmp(b));
+        let latency_factor = match latencies.get(latencies.len() / 2) {
+            Some(median) => {
+                1.0 - ((median - LATENCY_GOOD_MS) / (LATENCY_BAD_MS - LATENCY_GOOD_MS))
+                    .clamp(0.0, 1.0)
+            }
+            None if self.probes.is_empty() => 1.0,
+            None => 0.0,
+        };
+        let loss = ratio(
+            self.probes.iter().filter(|p| p.is_none()).count(),
+            self.probes.len(),
+        );
+        let failure = ratio(self.opens.iter().filter(|o| !**o).count(), self.opens.len());
+        Some(100.0 * (0.4 * latency_factor + 0.3 * (1.0 - loss) + 0.3 * (1.0 - failure)))
+    }
+}
+
+fn ratio(num: usize, denom: usize) -> f64 {
+    if denom == 0 {
+        0.0
+    } else {
+        num as f64 / denom as f64
+    }
+}
+
+static TRACKER: CtxField<Mutex<QualityTracker>> = |_| Mutex::new(QualityTracker::default());
+
+/// Records whether opening a stream through the tunnel succeeded.
+pub fn record_stream_open(ctx: &AnyCtx<Config>, success: bool) {
+    {
+        let mut tracker = ctx.get(TRACKER).lock();
+        tracker.opens.push_back(success);
+        if tracker.opens.len() > OPEN_WINDOW {
+            tracker.opens.pop_front();
+        }
+    }
+    update_score(ctx);
+}
+
+fn record_probe(ctx: &AnyCtx<Config>, rtt: Option<Duration>) {
+    {
+        let mut tracker = ctx.get(TRACKER).lock();
+        tracker
+            .probes
+            .push_back(rtt.map(|rtt| rtt.as_secs_f64() * 1000.0));
+        if tracker.probes.len() > PROBE_WINDOW {
+            tracker.probes.pop_front();
+        }
+    }
+    update_score(ctx);
+}
+
+/// Recomputes the score, publishing it as a stat and firing events when it crosses the configured thresholds.
+fn update_score(ctx: &AnyCtx<Config>) {
+    let alerts = &ctx.init().quality_alerts;
+    let mut tracker = ctx.get(TRACKER).lock();
+    let Some(score) = tracker.score() else {
+        return;
+    };
+    stat_set_num(ctx, "quality_score", score);
+    if !tracker.degraded && score < alerts.degraded_below {
+        tracker.degraded = true;
+        drop(tracker);
+        tracing::warn!(score, "connection quality degraded");
+        push_event(ctx, ConnEvent::QualityDegraded { score });
+    } else if tracker.degraded && score > alerts.recovered_above {
+        tracker.degraded = false;
+        drop(tracker);
+        tracing::info!(score, "connection quality recovered");
+        push_event(ctx, ConnEvent::QualityRecovered { score });
+    }
+}
+
+/// Periodically probes the round-trip time through the tunnel, using the exit's echo endpoint.
+pub async fn quality_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    loop {
+        smol::Timer::after(PROBE_INTERVAL).await;
+        if !matches!(*ctx.get(CURRENT_CONN_INFO).lock(), ConnInfo::Connected(_)) {
+            continue;
+        }
+        match probe_once(ctx).timeout(PROBE_TIMEOUT).await {
+            Some(Ok(rtt)) => {
+                stat_record_hist(ctx, "tunnel_rtt", rtt.as_secs_f64());
+                record_probe(ctx, Some(rtt))
+            }
+            // a probe that can't even get through the tunnel says as much about it as one that gets no answer
+            Some(Err(err)) => {
+                tracing::debug!(err = debug(err), "quality probe failed");
+                record_probe(ctx, None)
+            }
+            None => {
+                tracing::debug!("quality probe timed out");
+                record_probe(ctx, None)
+            }
+        }
+    }
+}
+
+async fn probe_once(ctx: &AnyCtx<Config>) -> anyhow::Result<Duration> {
+    let (mut read, mut write) = open_conn(ctx, "speedtest", "echo").await?.split();
+    let start = Instant::now();
+    write.write_all(&[0u8; 8]).await?;
+    write.flush().await?;
+    read.read_exact(&mut [0u8; 8]).await?;
+    Ok(start.elapsed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn perfect_and_terrible_scores() {
+        let mut tracker = QualityTracker::default();
+        assert_eq!(tracker.score(), None);
+        tracker.probes.extend([Some(50.0), Some(80.0), Some(100.0)]);
+        tracker.opens.extend([true, true]);
+        assert!((tracker.score().unwrap() - 100.0).abs() < 1e-9);
+
+        tracker.probes = [None, None, None].into();
+        tracker.opens = [false, false].into();
+        assert!(tracker.score().unwrap().abs() < 1e-9);
+    }
+
+    #[test]
+    fn latency_degrades_gradually() {
+        let mut tracker = QualityTracker::default();
+        tracker.probes.push_back(Some(825.0));
+        let score = tracker.score().unwrap();
+        assert!((score - 80.0).abs() < 1e-9, "{score}");
+    }
+}
@@ -0,0 +1,136 @@
+//! Reloading the config while the client is running.
+//!
+//! Routing rules, DNS settings, blocklists, the proxy listeners, the task limit, the exit, and VPN mode take effect as soon as they are reloaded. Changes to how we reach the exit, like the bridge mode or the upstream proxy, make the existing tunnels drain and get replaced by ones dialed with the new settings, the same way as when switching exits. Everything else, like credentials and the broker, only changes on restart.
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use anyctx::AnyCtx;
+use parking_lot::RwLock;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    client::CtxField,
+    get_dialer::{redial, set_exit_constraint},
+    vpn::set_vpn_enabled,
+    Config,
+};
+
+/// Settings that take effect as soon as they are reloaded.
+const LIVE_KEYS: &[&str] = &[
+    "socks5_listen",
+    "http_proxy_listen",
+    "proxy_auth",
+    "listeners",
+    "listen_acl",
+    "host_rules",
+    "resolve_policy",
+    "passthrough_china",
+    "doh_upstream",
+    "doh_overrides",
+    "blocklists",
+    "task_limit",
+    "exit_constraint",
+    "vpn",
+    "profile",
+    "profiles",
+];
+
+/// Settings for reaching the exit, which take effect once the existing tunnels are replaced.
+const REDIAL_KEYS: &[&str] = &[
+    "bridge_mode",
+    "pinned_bridges",
+    "bridge_bundle",
+    "upstream_proxy",
+    "fragment",
+    "shaping",
+    "bind_interface",
+    "happy_eyeballs",
+    "pluggable_transports",
+    "kcp",
+    "icmp_max_pps",
+    "dns_tunnel_resolver",
+    "mux_windows",
+];
+
+static LIVE_CONFIG: CtxField<RwLock<Arc<Config>>> = |ctx| RwLock::new(Arc::new(ctx.init().clone()));
+
+/// Bumped on every reload, so that whatever depends on reloadable settings knows to look again.
+static RELOAD_GENERATION: CtxField<(AtomicU64, async_event::Event)> =
+    |_| (AtomicU64::new(0), async_event::Event::new());
+
+/// The config as of the last reload. Reloadable settings should be read from here rather than from `ctx.init()`.
+pub fn live_config(ctx: &AnyCtx<Config>) -> Arc<Config> {
+    ctx.get(LIVE_CONFIG).read().clone()
+}
+
+/// Waits until a reload changes the part of the config picked out by the given function.
+pub async fn wait_config_change<T: Serialize>(ctx: &AnyCtx<Config>, pick: impl Fn(&Config) -> T) {
+    let picked = |config: &Config| serde_json::to_value(pick(config)).ok();
+    let before = picked(&live_config(ctx));
+    let (generation, event) = ctx.get(RELOAD_GENERATION);
+    loop {
+        let since = generation.load(Ordering::SeqCst);
+        if picked(&live_config(ctx)) != before {
+            return;
+        }
+        event
+            .wait_until(|| (generation.load(Ordering::SeqCst) != since).then_some(()))
+            .await;
+    }
+}
+
+/// Applies a new config to the running client.
+pub fn reload_config(ctx: &AnyCtx<Config>, new: Config) {
+    let old = live_config(ctx);
+    let (Ok(Value::Object(old_fields)), Ok(Value::Object(new_fields))) =
+        (serde_json::to_value(&*old), serde_json::to_value(&new))
+    else {
+        tracing::warn!("could not compare configs, not reloading");
+        return;
+    };
+    let changed: Vec<&str> = new_fields
+        .iter()
+        .filter(|(key, value)| old_fields.get(key.as_str()) != Some(*value))
+        .map(|(key, _)| key.as_str())
+        .collect();
+    if changed.is_empty() {
+        tracing::debug!("config unchanged");
+        return;
+    }
+    let restart_only: Vec<&str> = changed
+        .iter()
+        .copied()
+        .filter(|key| !LIVE_KEYS.contains(key) && !REDIAL_KEYS.contains(key))
+        .collect();
+    if !restart_only.is_empty() {
+        tracing::warn!(
+            settings = debug(&restart_only),
+            "some changed settings only change on restart"
+        );
+    }
+    tracing::info!(changed = debug(&changed), "reloading config");
+
+    let exit_changed = changed.contains(&"exit_constraint");
+    let vpn_changed = changed.contains(&"vpn");
+    let must_redial = changed.iter().any(|key| REDIAL_KEYS.contains(key));
+    let exit_constraint = new.exit_constraint.clone();
+    let vpn = new.vpn;
+
+    *ctx.get(LIVE_CONFIG).write() = Arc::new(new);
+    let (generation, event) = ctx.get(RELOAD_GENERATION);
+    generation.fetch_add(1, Ordering::SeqCst);
+    event.notify_all();
+
+    if exit_changed {
+        set_exit_constraint(ctx, exit_constraint);
+    } else if must_redial {
+        redial(ctx);
+    }
+    if vpn_changed {
+        set_vpn_enabled(ctx, vpn);
+    }
+}
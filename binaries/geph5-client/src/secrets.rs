@@ -0,0 +1,126 @@
+use anyhow::Context;
+use serde_json::Value;
+
+/// Config keys holding secrets, wherever they appear. Their string values may be references to secrets kept elsewhere, and they never go into debug bundles.
+const SECRET_KEYS: &[&str] = &[
+    "credentials",
+    "password",
+    "secret",
+    "auth_token",
+    "broker_keys",
+    "sess_metadata",
+    "bridge_bundle",
+];
+
+/// Whether a config key holds a secret. Keys match regardless of case, and also when they are part of a longer key, like "broker_auth_token".
+pub(crate) fn is_secret_key(key: &str) -> bool {
+    let key = key.to_lowercase();
+    SECRET_KEYS.iter().any(|secret| key.contains(secret))
+}
+
+/// Replaces references to secrets in a config, in JSON form, with the secrets themselves, so that the config can be shared without them. A reference is one of:
+/// - `file:/path/to/secret`, for the contents of a file, without trailing newlines
+/// - `env:VARIABLE`, for an environment variable
+/// - `keychain:service/account`, for a password in the macOS keychain, or in the Secret Service (such as GNOME Keyring) on Linux
+pub fn resolve_secrets(config: &mut Value) -> anyhow::Result<()> {
+    resolve_within(config, false)
+}
+
+fn resolve_within(value: &mut Value, in_secret: bool) -> anyhow::Result<()> {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                resolve_within(value, in_secret || is_secret_key(key))
+                    .with_context(|| format!("in {key:?}"))?;
+            }
+        }
+        Value::Array(values) => {
+            for value in values.iter_mut() {
+                resolve_within(value, in_secret)?;
+            }
+        }
+        Value::String(reference) if in_secret => {
+            if let Some(secret) = resolve_reference(reference)? {
+                *reference = secret;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Looks up the secret a reference points to, or returns None if the string is not a reference.
+fn resolve_reference(reference: &str) -> anyhow::Result<Option<String>> {
+    if let Some(path) = reference.strip_prefix("file:") {
+        let secret = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read secret from {path}"))?;
+        Ok(Some(secret.trim_end_matches(['\r', '\n']).to_string()))
+    } else if let Some(variable) = reference.strip_prefix("env:") {
+        let secret = std::env::var(variable)
+            .with_context(|| format!("could not read secret from ${variable}"))?;
+        Ok(Some(secret))
+    } else if let Some(entry) = reference.strip_prefix("keychain:") {
+        let (service, account) = entry
+            .split_once('/')
+            .context("keychain references must be of the form keychain:service/account")?;
+        let secret = keychain_lookup(service, account).with_context(|| {
+            format!("could not read secret for {account} in {service} from the keychain")
+        })?;
+        Ok(Some(secret))
+    } else {
+        Ok(None)
+    }
+}
+
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+fn keychain_lookup(service: &str, account: &str) -> anyhow::Result<String> {
+    #[cfg(target_os = "macos")]
+    let output = std::process::Command::new("security")
+        .args(["find-generic-password", "-s", service, "-a", account, "-w"])
+        .output()?;
+    #[cfg(target_os = "linux")]
+    let output = std::process::Command::new("secret-tool")
+        .args(["lookup", "service", service, "account", account])
+        .output()?;
+    anyhow::ensure!(
+        output.status.success(),
+        "keychain lookup failed: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(String::from_utf8(output.stdout)?
+        .trim_end_matches(['\r', '\n'])
+        .to_string())
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "linux")))]
+fn keychain_lookup(_service: &str, _account: &str) -> anyhow::Result<String> {
+    anyhow::bail!("keychain references are only supported on macOS and Linux")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn resolves_only_secret_keys() {
+        let path = std::env::temp_dir().join("geph5-secrets-test");
+        std::fs::write(&path, "hunter2\n").unwrap();
+        std::env::set_var("GEPH_SECRETS_TEST", "swordfish");
+        let mut config = json!({
+            "credentials": {"secret": format!("file:{}", path.display())},
+            "listeners": [{"auth": {"username": "env:GEPH_SECRETS_TEST", "password": "env:GEPH_SECRETS_TEST"}}],
+            "doh_upstream": "env:GEPH_SECRETS_TEST",
+        });
+        resolve_secrets(&mut config).unwrap();
+        assert_eq!(
+            config,
+            json!({
+                "credentials": {"secret": "hunter2"},
+                "listeners": [{"auth": {"username": "env:GEPH_SECRETS_TEST", "password": "swordfish"}}],
+                "doh_upstream": "env:GEPH_SECRETS_TEST",
+            })
+        );
+    }
+}
@@ -0,0 +1,105 @@
+/// Extracts the server name from the SNI extension of a TLS ClientHello at the start of the given buffer, if there is one.
+pub fn parse_sni(buf: &[u8]) -> Option<String> {
+    let mut reader = Reader(buf);
+    // TLS record header
+    if reader.u8()? != 0x16 {
+        return None;
+    }
+    reader.skip(4)?;
+    // handshake header, which must be a ClientHello
+    if reader.u8()? != 0x01 {
+        return None;
+    }
+    reader.skip(3)?;
+    // client version and random
+    reader.skip(2 + 32)?;
+    let session_id_len = reader.u8()? as usize;
+    reader.skip(session_id_len)?;
+    let cipher_suites_len = reader.u16()? as usize;
+    reader.skip(cipher_suites_len)?;
+    let compression_len = reader.u8()? as usize;
+    reader.skip(compression_len)?;
+    let extensions_len = reader.u16()? as usize;
+    let mut extensions = Reader(reader.take(extensions_len)?);
+    while !extensions.0.is_empty() {
+        let ext_type = extensions.u16()?;
+        let ext_len = extensions.u16()? as usize;
+        let mut ext = Reader(extensions.take(ext_len)?);
+        if ext_type != 0 {
+            continue;
+        }
+        let list_len = ext.u16()? as usize;
+        let mut list = Reader(ext.take(list_len)?);
+        while !list.0.is_empty() {
+            let name_type = list.u8()?;
+            let name_len = list.u16()? as usize;
+            let name = list.take(name_len)?;
+            if name_type == 0 {
+                return String::from_utf8(name.to_vec()).ok();
+            }
+        }
+    }
+    None
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn skip(&mut self, n: usize) -> Option<()> {
+        self.take(n).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_client_hello() {
+        let name = b"example.com";
+        let mut sni_ext = vec![];
+        sni_ext.extend_from_slice(&((name.len() + 3) as u16).to_be_bytes());
+        sni_ext.push(0);
+        sni_ext.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        sni_ext.extend_from_slice(name);
+
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0u8; 32]);
+        hello.push(0); // session ID
+        hello.extend_from_slice(&[0, 2, 0x13, 0x01]); // cipher suites
+        hello.extend_from_slice(&[1, 0]); // compression
+        let mut extensions = vec![0x00, 0x0b, 0x00, 0x00]; // an empty unrelated extension
+        extensions.extend_from_slice(&[0, 0]);
+        extensions.extend_from_slice(&(sni_ext.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(&sni_ext);
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&extensions);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&((hello.len() + 4) as u16).to_be_bytes());
+        record.push(0x01);
+        record.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+        record.extend_from_slice(&hello);
+
+        assert_eq!(parse_sni(&record).as_deref(), Some("example.com"));
+        assert_eq!(parse_sni(&record[..20]), None);
+        assert_eq!(parse_sni(b"GET / HTTP/1.1\r\n"), None);
+    }
+}
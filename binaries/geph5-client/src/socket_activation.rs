@@ -0,0 +1,37 @@
+use std::{
+    net::{SocketAddr, TcpListener},
+    sync::LazyLock,
+};
+
+use parking_lot::Mutex;
+
+/// Listening sockets that were handed to us already bound.
+static INHERITED: LazyLock<Mutex<Vec<TcpListener>>> = LazyLock::new(Default::default);
+
+/// Hands over TCP sockets that are already bound and listening, such as the ones systemd passes in with socket activation. Listeners on the same address use these instead of binding their own.
+pub fn inherit_tcp_listeners(listeners: impl IntoIterator<Item = TcpListener>) {
+    let mut inherited = INHERITED.lock();
+    for listener in listeners {
+        tracing::info!(
+            listen = debug(listener.local_addr().ok()),
+            "inherited a listening socket"
+        );
+        inherited.push(listener);
+    }
+}
+
+/// Binds a nonblocking TCP listener, reusing an inherited socket on the same address if there is one. Inherited sockets stay around, so that listeners restarted by a config reload get them again.
+pub fn bind_tcp(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let inherited = INHERITED
+        .lock()
+        .iter()
+        .find(|listener| listener.local_addr().ok() == Some(addr))
+        .map(|listener| listener.try_clone())
+        .transpose()?;
+    let listener = match inherited {
+        Some(listener) => listener,
+        None => TcpListener::bind(addr)?,
+    };
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
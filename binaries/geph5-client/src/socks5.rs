@@ -1,69 +1,162 @@
-use crate::{client_inner::open_conn, litecopy::litecopy, taskpool::add_task};
+mod socks4;
+mod udp;
+
+use crate::{
+    client_inner::{current_exit_has_ipv6, open_conn_with_rules},
+    listeners::ProxyListener,
+    litecopy::litecopy,
+    proxy_auth::ProxyAuth,
+    reload::live_config,
+    socket_activation::bind_tcp,
+    stats::stat_incr_num,
+    taskpool::add_task,
+};
 
 use anyctx::AnyCtx;
 
-use futures_util::AsyncReadExt as _;
+use futures_util::{io::Cursor, AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
 use nursery_macro::nursery;
-use sillad::listener::Listener as _;
-use smol::future::FutureExt as _;
+use sillad::{listener::Listener as _, Pipe};
 use socksv5::v5::{
     read_handshake, read_request, write_auth_method, write_request_status, SocksV5AuthMethod,
-    SocksV5Host, SocksV5RequestStatus,
+    SocksV5Command, SocksV5Host, SocksV5RequestStatus,
 };
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use self::{socks4::socks4_once, udp::socks5_udp_associate};
 
 use super::Config;
 
-#[tracing::instrument(skip_all)]
-pub async fn socks5_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
-    if let Some(listen_addr) = ctx.init().socks5_listen {
-        let mut listener = sillad::tcp::TcpListener::bind(listen_addr).await?;
-        nursery!({
-            loop {
-                let client = listener.accept().await?;
-                let task = spawn!(async {
-                    tracing::trace!("socks5 connection accepted");
-                    let (mut read_client, mut write_client) = client.split();
-                    let _handshake = read_handshake(&mut read_client).await?;
-                    write_auth_method(&mut write_client, SocksV5AuthMethod::Noauth).await?;
-                    let request = read_request(&mut read_client).await?;
-                    let port = request.port;
-                    let domain: String = match &request.host {
-                        SocksV5Host::Domain(dom) => String::from_utf8_lossy(dom).parse()?,
-                        SocksV5Host::Ipv4(v4) => {
-                            let v4addr = Ipv4Addr::new(v4[0], v4[1], v4[2], v4[3]);
-                            v4addr.to_string()
-                        }
-                        _ => anyhow::bail!("IPv6 not supported"),
-                    };
-                    let remote_addr = format!("{domain}:{port}");
-                    tracing::trace!(
-                        remote_addr = display(&remote_addr),
-                        "socks5 request received"
-                    );
-                    let stream = open_conn(ctx, "tcp", &remote_addr).await?;
-                    write_request_status(
-                        &mut write_client,
-                        SocksV5RequestStatus::Success,
-                        request.host,
-                        port,
-                    )
-                    .await?;
-                    tracing::trace!(remote_addr = display(&remote_addr), "connection opened");
-                    let (read_stream, write_stream) = stream.split();
-                    litecopy(read_stream, write_client)
-                        .race(litecopy(read_client, write_stream))
-                        .await?;
-                    anyhow::Ok(())
-                });
-                if let Some(task_limit) = ctx.init().task_limit {
-                    add_task(task_limit, task);
-                } else {
-                    task.detach();
-                }
+#[tracing::instrument(skip_all, fields(listen = display(listener.listen)))]
+pub async fn socks5_loop(ctx: &AnyCtx<Config>, listener: &ProxyListener) -> anyhow::Result<()> {
+    let mut tcp_listener = sillad::tcp::TcpListener::from_std(bind_tcp(listener.listen)?)?;
+    nursery!({
+        loop {
+            let client = tcp_listener.accept().await?;
+            let peer = client
+                .remote_addr()
+                .and_then(|addr| addr.parse::<SocketAddr>().ok());
+            if !listener.admits(ctx, peer.map(|peer| peer.ip())) {
+                continue;
+            }
+            let task = spawn!(socks5_once(ctx, listener, client));
+            if let Some(task_limit) = live_config(ctx).task_limit {
+                add_task(task_limit, task);
+            } else {
+                task.detach();
+            }
+        }
+    })
+}
+
+async fn socks5_once(
+    ctx: &AnyCtx<Config>,
+    listener: &ProxyListener,
+    client: impl Pipe,
+) -> anyhow::Result<()> {
+    tracing::trace!("socks connection accepted");
+    let (mut read_client, write_client) = client.split();
+    let mut version = [0u8; 1];
+    read_client.read_exact(&mut version).await?;
+    match version[0] {
+        4 => socks4_once(ctx, listener, read_client, write_client).await,
+        5 => {
+            // put the version byte back for the SOCKS5 handshake parser
+            let read_client = Cursor::new(version).chain(read_client);
+            socks5_serve(ctx, listener, read_client, write_client).await
+        }
+        other => anyhow::bail!("unsupported SOCKS version {other}"),
+    }
+}
+
+async fn socks5_serve(
+    ctx: &AnyCtx<Config>,
+    listener: &ProxyListener,
+    mut read_client: impl AsyncRead + Unpin,
+    mut write_client: impl AsyncWrite + Unpin,
+) -> anyhow::Result<()> {
+    let handshake = read_handshake(&mut read_client).await?;
+    if let Some(auth) = &listener.auth {
+        if !handshake
+            .methods
+            .contains(&SocksV5AuthMethod::UsernamePassword)
+        {
+            write_auth_method(&mut write_client, SocksV5AuthMethod::NoAcceptableMethod).await?;
+            anyhow::bail!("socks5 client does not support username/password auth");
+        }
+        write_auth_method(&mut write_client, SocksV5AuthMethod::UsernamePassword).await?;
+        socks5_password_auth(auth, &mut read_client, &mut write_client)
+            .await
+            .inspect_err(|_| stat_incr_num(ctx, "auth_failures", 1.0))?;
+    } else {
+        write_auth_method(&mut write_client, SocksV5AuthMethod::Noauth).await?;
+    }
+    let request = read_request(&mut read_client).await?;
+    if let SocksV5Command::UdpAssociate = request.command {
+        return socks5_udp_associate(ctx, listener, read_client, write_client).await;
+    }
+    let port = request.port;
+    let domain: String = match &request.host {
+        SocksV5Host::Domain(dom) => String::from_utf8_lossy(dom).parse()?,
+        SocksV5Host::Ipv4(v4) => {
+            let v4addr = Ipv4Addr::new(v4[0], v4[1], v4[2], v4[3]);
+            v4addr.to_string()
+        }
+        SocksV5Host::Ipv6(v6) => {
+            if !current_exit_has_ipv6(ctx).await {
+                anyhow::bail!("IPv6 destinations are not supported by the current exit");
             }
-        })
+            format!("[{}]", Ipv6Addr::from(*v6))
+        }
+    };
+    let remote_addr = format!("{domain}:{port}");
+    tracing::trace!(
+        remote_addr = display(&remote_addr),
+        "socks5 request received"
+    );
+    let stream = open_conn_with_rules(ctx, &listener.rules, "tcp", &remote_addr).await?;
+    write_request_status(
+        &mut write_client,
+        SocksV5RequestStatus::Success,
+        request.host,
+        port,
+    )
+    .await?;
+    tracing::trace!(remote_addr = display(&remote_addr), "connection opened");
+    let (read_stream, write_stream) = stream.split();
+    // each direction half-closes on its own, so the relay is done only once both are
+    futures_util::future::try_join(
+        litecopy(read_stream, write_client),
+        litecopy(read_client, write_stream),
+    )
+    .await?;
+    anyhow::Ok(())
+}
+
+/// Runs the RFC 1929 username/password subnegotiation.
+async fn socks5_password_auth(
+    auth: &ProxyAuth,
+    mut read_client: impl AsyncRead + Unpin,
+    mut write_client: impl AsyncWrite + Unpin,
+) -> anyhow::Result<()> {
+    let mut header = [0u8; 2];
+    read_client.read_exact(&mut header).await?;
+    if header[0] != 1 {
+        anyhow::bail!("bad socks5 auth version {}", header[0]);
+    }
+    let mut username = vec![0u8; header[1] as usize];
+    read_client.read_exact(&mut username).await?;
+    let mut password_len = [0u8; 1];
+    read_client.read_exact(&mut password_len).await?;
+    let mut password = vec![0u8; password_len[0] as usize];
+    read_client.read_exact(&mut password).await?;
+    if auth.check(&username, &password) {
+        write_client.write_all(&[1, 0]).await?;
+        write_client.flush().await?;
+        Ok(())
     } else {
-        smol::future::pending().await
+        write_client.write_all(&[1, 1]).await?;
+        write_client.flush().await?;
+        anyhow::bail!("socks5 client failed authentication")
     }
 }
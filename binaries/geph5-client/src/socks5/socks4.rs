@@ -0,0 +1,89 @@
+use std::net::Ipv4Addr;
+
+use anyctx::AnyCtx;
+use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    client_inner::open_conn_with_rules, listeners::ProxyListener, litecopy::litecopy, Config,
+};
+
+const SOCKS4_CONNECT: u8 = 1;
+const SOCKS4_GRANTED: u8 = 0x5a;
+const SOCKS4_REJECTED: u8 = 0x5b;
+
+/// Handles a SOCKS4 or SOCKS4a CONNECT request, after the version byte has already been read.
+pub async fn socks4_once(
+    ctx: &AnyCtx<Config>,
+    listener: &ProxyListener,
+    mut read_client: impl AsyncRead + Unpin,
+    mut write_client: impl AsyncWrite + Unpin,
+) -> anyhow::Result<()> {
+    let mut header = [0u8; 7];
+    read_client.read_exact(&mut header).await?;
+    let command = header[0];
+    let port = u16::from_be_bytes([header[1], header[2]]);
+    let ip = Ipv4Addr::new(header[3], header[4], header[5], header[6]);
+    // the user ID is ignored, since SOCKS4 has no way of carrying a password
+    read_null_terminated(&mut read_client).await?;
+    // SOCKS4a signals a hostname with a destination IP of 0.0.0.x, where x is nonzero
+    let host = if ip.octets()[..3] == [0, 0, 0] && ip.octets()[3] != 0 {
+        String::from_utf8(read_null_terminated(&mut read_client).await?)?
+    } else {
+        ip.to_string()
+    };
+
+    if listener.auth.is_some() {
+        write_status(&mut write_client, SOCKS4_REJECTED).await?;
+        anyhow::bail!("socks4 cannot be used on a listener that requires authentication");
+    }
+    if command != SOCKS4_CONNECT {
+        write_status(&mut write_client, SOCKS4_REJECTED).await?;
+        anyhow::bail!("unsupported socks4 command {command}");
+    }
+
+    let remote_addr = format!("{host}:{port}");
+    tracing::trace!(
+        remote_addr = display(&remote_addr),
+        "socks4 request received"
+    );
+    let stream = match open_conn_with_rules(ctx, &listener.rules, "tcp", &remote_addr).await {
+        Ok(stream) => stream,
+        Err(err) => {
+            write_status(&mut write_client, SOCKS4_REJECTED).await?;
+            return Err(err);
+        }
+    };
+    write_status(&mut write_client, SOCKS4_GRANTED).await?;
+    let (read_stream, write_stream) = stream.split();
+    // each direction half-closes on its own, so the relay is done only once both are
+    futures_util::future::try_join(
+        litecopy(read_stream, write_client),
+        litecopy(read_client, write_stream),
+    )
+    .await?;
+    Ok(())
+}
+
+async fn read_null_terminated(mut read_client: impl AsyncRead + Unpin) -> anyhow::Result<Vec<u8>> {
+    let mut buf = vec![];
+    loop {
+        let mut byte = [0u8; 1];
+        read_client.read_exact(&mut byte).await?;
+        if byte[0] == 0 {
+            return Ok(buf);
+        }
+        if buf.len() >= 255 {
+            anyhow::bail!("socks4 field too long");
+        }
+        buf.push(byte[0]);
+    }
+}
+
+async fn write_status(mut write_client: impl AsyncWrite + Unpin, status: u8) -> anyhow::Result<()> {
+    // the port and address in the reply are ignored by clients
+    write_client
+        .write_all(&[0, status, 0, 0, 0, 0, 0, 0])
+        .await?;
+    write_client.flush().await?;
+    Ok(())
+}
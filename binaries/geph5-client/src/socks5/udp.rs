@@ -0,0 +1,240 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use anyctx::AnyCtx;
+use anyhow::Context;
+use bytes::Bytes;
+use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite};
+use geph5_misc_rpc::udp::UdpNatFrame;
+use parking_lot::Mutex;
+use smol::{future::FutureExt as _, net::UdpSocket};
+use socksv5::v5::{write_request_status, SocksV5Host, SocksV5RequestStatus};
+
+use crate::{
+    listeners::ProxyListener,
+    udpnat::{resolve_udp_dest, UdpNatTunnel},
+    Config,
+};
+
+/// How many datagrams may wait for the association's tunneled stream before more are dropped.
+const UDP_UPSTREAM_QUEUE: usize = 64;
+
+/// The destinations of an association that were given by name, or by a fake DNS address, rather than by their real address.
+#[derive(Default)]
+struct AssocNames {
+    resolved: HashMap<String, SocketAddr>,
+    /// The destination each resolved address was given as, so that responses seem to come from where the client sent to.
+    given_as: HashMap<SocketAddr, String>,
+}
+
+/// Handles a SOCKS5 UDP ASSOCIATE request. Datagrams sent to the relay socket go through a single `udpnat$` stream, so that peers see them coming from one UDP socket, and the association lasts until the control connection closes.
+pub async fn socks5_udp_associate(
+    ctx: &AnyCtx<Config>,
+    listener: &ProxyListener,
+    mut read_client: impl AsyncRead + Unpin,
+    mut write_client: impl AsyncWrite + Unpin,
+) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind(SocketAddr::new(listener.listen.ip(), 0)).await?;
+    let relay_addr = socket.local_addr()?;
+    let relay_host = match relay_addr.ip() {
+        IpAddr::V4(v4) => SocksV5Host::Ipv4(v4.octets()),
+        IpAddr::V6(v6) => SocksV5Host::Ipv6(v6.octets()),
+    };
+    write_request_status(
+        &mut write_client,
+        SocksV5RequestStatus::Success,
+        relay_host,
+        relay_addr.port(),
+    )
+    .await?;
+    tracing::debug!(
+        relay_addr = display(relay_addr),
+        "socks5 UDP association started"
+    );
+
+    let names = Arc::new(Mutex::new(AssocNames::default()));
+    let client_addr: Mutex<Option<SocketAddr>> = Mutex::new(None);
+    let (send_up, recv_up) = smol::channel::bounded::<(String, Bytes)>(UDP_UPSTREAM_QUEUE);
+    let recv_loop = async {
+        let mut buf = [0u8; 65536];
+        loop {
+            let (n, from) = socket.recv_from(&mut buf).await?;
+            let (dest, payload) = match decode_udp_header(&buf[..n]) {
+                Ok(val) => val,
+                Err(err) => {
+                    tracing::debug!(err = debug(err), "dropping bad socks5 UDP packet");
+                    continue;
+                }
+            };
+            *client_addr.lock() = Some(from);
+            // like on a congested link, datagrams are dropped while the stream is opening or backed up
+            let _ = send_up.try_send((dest, Bytes::copy_from_slice(payload)));
+        }
+    };
+    let relay = async {
+        loop {
+            // the stream is opened with the first datagram, and again with the next one if it fails or dies
+            let first = recv_up.recv().await?;
+            let tunnel = match UdpNatTunnel::open(ctx, &listener.rules).await {
+                Ok(tunnel) => Arc::new(tunnel),
+                Err(err) => {
+                    tracing::debug!(err = debug(err), "could not open socks5 UDP relay");
+                    while recv_up.try_recv().is_ok() {}
+                    continue;
+                }
+            };
+            let up_loop = async {
+                let mut next = Some(first);
+                loop {
+                    let (dest, payload) = match next.take() {
+                        Some(datagram) => datagram,
+                        None => recv_up.recv().await?,
+                    };
+                    let mut send = Box::pin(send_to_dest(
+                        ctx.clone(),
+                        names.clone(),
+                        tunnel.clone(),
+                        dest,
+                        payload,
+                    ));
+                    // datagrams to addresses go out right away, while those to names wait for them to resolve without holding up the rest
+                    if smol::future::poll_once(&mut send).await.is_none() {
+                        smolscale::spawn(send).detach();
+                    }
+                }
+            };
+            let dn_loop = async {
+                loop {
+                    let frame = tunnel.recv().await?;
+                    let Some(client_addr) = *client_addr.lock() else {
+                        continue;
+                    };
+                    let source = names
+                        .lock()
+                        .given_as
+                        .get(&frame.peer)
+                        .cloned()
+                        .unwrap_or_else(|| frame.peer.to_string());
+                    let mut packet = encode_udp_header(&source)?;
+                    packet.extend_from_slice(&frame.payload);
+                    socket.send_to(&packet, client_addr).await?;
+                }
+            };
+            let result: anyhow::Result<()> = up_loop.race(dn_loop).await;
+            if let Err(err) = result {
+                tracing::debug!(err = debug(err), "socks5 UDP relay died");
+            }
+        }
+    };
+    let control = async {
+        // the association ends when the TCP control connection closes
+        let mut buf = [0u8; 1];
+        while read_client.read(&mut buf).await? > 0 {}
+        anyhow::Ok(())
+    };
+    recv_loop.race(relay).race(control).await
+}
+
+/// Sends a datagram to a "host:port" destination through the association's stream. If the destination is blocked or cannot be resolved, only this datagram is lost.
+async fn send_to_dest(
+    ctx: AnyCtx<Config>,
+    names: Arc<Mutex<AssocNames>>,
+    tunnel: Arc<UdpNatTunnel>,
+    dest: String,
+    payload: Bytes,
+) {
+    let result = async {
+        let cached = names.lock().resolved.get(&dest).copied();
+        let peer = match cached {
+            Some(peer) => peer,
+            None => {
+                let peer = resolve_udp_dest(&ctx, &dest).await?;
+                // plain addresses are not remembered, so that associations talking to many peers stay small
+                if dest.parse::<SocketAddr>().ok() != Some(peer) {
+                    let mut names = names.lock();
+                    names.resolved.insert(dest.clone(), peer);
+                    names.given_as.insert(peer, dest.clone());
+                }
+                peer
+            }
+        };
+        tunnel.send(&UdpNatFrame { peer, payload }).await
+    }
+    .await;
+    if let Err(err) = result {
+        tracing::debug!(dest, err = debug(err), "dropping socks5 UDP datagram");
+    }
+}
+
+/// Decodes the SOCKS5 UDP request header, returning the destination and the payload.
+fn decode_udp_header(pkt: &[u8]) -> anyhow::Result<(String, &[u8])> {
+    if pkt.len() < 4 {
+        anyhow::bail!("packet too short")
+    }
+    if pkt[2] != 0 {
+        anyhow::bail!("fragmented socks5 UDP packets are not supported")
+    }
+    let (host, rest) = match pkt[3] {
+        1 => {
+            let addr: [u8; 4] = pkt.get(4..8).context("truncated IPv4")?.try_into()?;
+            (Ipv4Addr::from(addr).to_string(), &pkt[8..])
+        }
+        3 => {
+            let len = *pkt.get(4).context("truncated domain")? as usize;
+            let domain = pkt.get(5..5 + len).context("truncated domain")?;
+            (String::from_utf8_lossy(domain).to_string(), &pkt[5 + len..])
+        }
+        4 => {
+            let addr: [u8; 16] = pkt.get(4..20).context("truncated IPv6")?.try_into()?;
+            (format!("[{}]", Ipv6Addr::from(addr)), &pkt[20..])
+        }
+        other => anyhow::bail!("unknown address type {other}"),
+    };
+    let port = u16::from_be_bytes(rest.get(..2).context("truncated port")?.try_into()?);
+    Ok((format!("{host}:{port}"), &rest[2..]))
+}
+
+/// Encodes the SOCKS5 UDP header that precedes datagrams coming from the given source.
+fn encode_udp_header(source: &str) -> anyhow::Result<Vec<u8>> {
+    let mut header = vec![0, 0, 0];
+    if let Ok(addr) = source.parse::<SocketAddr>() {
+        match addr.ip() {
+            IpAddr::V4(v4) => {
+                header.push(1);
+                header.extend_from_slice(&v4.octets());
+            }
+            IpAddr::V6(v6) => {
+                header.push(4);
+                header.extend_from_slice(&v6.octets());
+            }
+        }
+        header.extend_from_slice(&addr.port().to_be_bytes());
+    } else {
+        let (host, port) = source.rsplit_once(':').context("no port in source")?;
+        let port: u16 = port.parse()?;
+        header.push(3);
+        header.push(host.len().try_into().context("domain too long")?);
+        header.extend_from_slice(host.as_bytes());
+        header.extend_from_slice(&port.to_be_bytes());
+    }
+    Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn udp_header_roundtrip() {
+        for dest in ["1.2.3.4:53", "example.com:443", "[::1]:8080"] {
+            let mut pkt = encode_udp_header(dest).unwrap();
+            pkt.extend_from_slice(b"hello");
+            let (decoded, payload) = decode_udp_header(&pkt).unwrap();
+            assert_eq!(decoded, dest);
+            assert_eq!(payload, b"hello");
+        }
+    }
+}
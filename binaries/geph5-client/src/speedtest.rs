@@ -0,0 +1,125 @@
+use std::time::{Duration, Instant};
+
+use anyctx::AnyCtx;
+use anyhow::Context;
+use futures_util::{AsyncReadExt, AsyncWriteExt};
+use serde::{Deserialize, Serialize};
+use smol_timeout2::TimeoutExt;
+
+use crate::{client_inner::open_conn, Config};
+
+/// How many sequential round trips we time for the latency test.
+const LATENCY_PINGS: usize = 20;
+
+/// How long each of the download and upload bursts lasts.
+const BURST_DURATION: Duration = Duration::from_secs(5);
+
+/// How much we ask the exit to send us; the burst normally ends long before this runs out.
+const DOWNLOAD_REQUEST: u64 = 1 << 30;
+
+/// The result of a speed test through the tunnel, against the exit's measurement endpoint.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SpeedTestResult {
+    pub download_bps: f64,
+    pub upload_bps: f64,
+    pub latency_p50_ms: f64,
+    pub latency_p90_ms: f64,
+    pub latency_p99_ms: f64,
+}
+
+/// Runs a full speed test through the tunnel: latency first, then a download burst, then an upload burst.
+#[tracing::instrument(skip_all)]
+pub async fn speed_test(ctx: &AnyCtx<Config>) -> anyhow::Result<SpeedTestResult> {
+    let mut latencies = measure_latency(ctx).await.context("latency test failed")?;
+    latencies.sort_by(|a, b| a.total_cmp(b));
+    let download_bps = measure_download(ctx)
+        .await
+        .context("download test failed")?;
+    let upload_bps = measure_upload(ctx).await.context("upload test failed")?;
+    let result = SpeedTestResult {
+        download_bps,
+        upload_bps,
+        latency_p50_ms: percentile(&latencies, 0.5),
+        latency_p90_ms: percentile(&latencies, 0.9),
+        latency_p99_ms: percentile(&latencies, 0.99),
+    };
+    tracing::info!(result = debug(&result), "speed test finished");
+    Ok(result)
+}
+
+async fn measure_latency(ctx: &AnyCtx<Config>) -> anyhow::Result<Vec<f64>> {
+    let (mut read, mut write) = open_conn(ctx, "speedtest", "echo").await?.split();
+    let mut latencies = Vec::with_capacity(LATENCY_PINGS);
+    for i in 0..LATENCY_PINGS as u64 {
+        let start = Instant::now();
+        write.write_all(&i.to_be_bytes()).await?;
+        write.flush().await?;
+        let mut buf = [0u8; 8];
+        read.read_exact(&mut buf)
+            .timeout(Duration::from_secs(10))
+            .await
+            .context("timed out waiting for echo")??;
+        anyhow::ensure!(u64::from_be_bytes(buf) == i, "echo out of order");
+        latencies.push(start.elapsed().as_secs_f64() * 1000.0);
+    }
+    Ok(latencies)
+}
+
+async fn measure_download(ctx: &AnyCtx<Config>) -> anyhow::Result<f64> {
+    let mut conn = open_conn(ctx, "speedtest", &format!("download:{DOWNLOAD_REQUEST}")).await?;
+    let start = Instant::now();
+    let mut buf = vec![0u8; 65536];
+    let mut total = 0u64;
+    while let Some(remaining) = BURST_DURATION.checked_sub(start.elapsed()) {
+        match conn.read(&mut buf).timeout(remaining).await {
+            Some(Ok(0)) | None => break,
+            Some(Ok(n)) => total += n as u64,
+            Some(Err(err)) => return Err(err.into()),
+        }
+    }
+    Ok(total as f64 / start.elapsed().as_secs_f64())
+}
+
+async fn measure_upload(ctx: &AnyCtx<Config>) -> anyhow::Result<f64> {
+    let (mut read, mut write) = open_conn(ctx, "speedtest", "upload").await?.split();
+    let start = Instant::now();
+    let send = async {
+        let buf = vec![0u8; 65536];
+        while let Some(remaining) = BURST_DURATION.checked_sub(start.elapsed()) {
+            write.write_all(&buf).timeout(remaining).await.transpose()?;
+        }
+        anyhow::Ok(())
+    };
+    // the exit acknowledges how much it has received every so often, so we measure up to the last acknowledgement
+    let receive = async {
+        let mut last_ack = (0u64, Duration::ZERO);
+        let mut buf = [0u8; 8];
+        while let Some(remaining) =
+            (BURST_DURATION + Duration::from_secs(1)).checked_sub(start.elapsed())
+        {
+            if read
+                .read_exact(&mut buf)
+                .timeout(remaining)
+                .await
+                .transpose()?
+                .is_none()
+            {
+                break;
+            }
+            last_ack = (u64::from_be_bytes(buf), start.elapsed());
+        }
+        anyhow::Ok(last_ack)
+    };
+    let (sent, received) = smol::future::zip(send, receive).await;
+    sent?;
+    let (acked, elapsed) = received?;
+    Ok(acked as f64 / elapsed.as_secs_f64().max(0.001))
+}
+
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let idx = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[idx]
+}
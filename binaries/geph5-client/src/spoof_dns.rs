@@ -6,7 +6,7 @@ use dashmap::DashMap;
 use rand::Rng;
 use simple_dns::{Packet, QTYPE};
 
-use crate::{client::CtxField, Config};
+use crate::{client::CtxField, dns::blocklist_check, Config};
 
 static FAKE_DNS_FORWARD: CtxField<DashMap<String, Ipv4Addr>> = |_| DashMap::new();
 
@@ -45,13 +45,17 @@ pub fn fake_dns_respond(ctx: &AnyCtx<Config>, pkt: &[u8]) -> anyhow::Result<Byte
     let mut answers = vec![];
     for question in pkt.questions.iter() {
         if question.qtype == QTYPE::TYPE(simple_dns::TYPE::A) {
+            let qname = question.qname.to_string();
+            let addr = if blocklist_check(ctx, &qname).is_some() {
+                Ipv4Addr::UNSPECIFIED
+            } else {
+                fake_dns_allocate(ctx, &qname)
+            };
             answers.push(simple_dns::ResourceRecord::new(
                 question.qname.clone(),
                 simple_dns::CLASS::IN,
                 1,
-                simple_dns::rdata::RData::A(
-                    fake_dns_allocate(ctx, &question.qname.to_string()).into(),
-                ),
+                simple_dns::rdata::RData::A(addr.into()),
             ));
         }
     }
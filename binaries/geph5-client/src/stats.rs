@@ -1,10 +1,11 @@
-use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use anyctx::AnyCtx;
 use async_trait::async_trait;
 use atomic_float::AtomicF64;
 use dashmap::DashMap;
 use nanorpc::nanorpc_derive;
+use serde::{Deserialize, Serialize};
 
 use smol_str::SmolStr;
 
@@ -33,6 +34,140 @@ pub fn stat_get_num(ctx: &AnyCtx<Config>, stat: &str) -> f64 {
         .unwrap_or(0.0)
 }
 
+/// A snapshot of every numeric stat, sorted by name.
+pub fn stat_all_nums(ctx: &AnyCtx<Config>) -> Vec<(SmolStr, f64)> {
+    let mut stats: Vec<_> = ctx
+        .get(NUM_STATS)
+        .iter()
+        .map(|entry| (entry.key().clone(), entry.value().load(Ordering::Relaxed)))
+        .collect();
+    stats.sort_by(|a, b| a.0.cmp(&b.0));
+    stats
+}
+
+/// The smallest value a histogram distinguishes, in seconds.
+const HIST_MIN: f64 = 0.0001;
+
+/// Buckets per doubling of the value. Four gives about 19% precision.
+const HIST_SUBBUCKETS: f64 = 4.0;
+
+/// Total buckets, covering up to about 100 seconds. Bigger values land in the last bucket.
+const HIST_BUCKETS: usize = 80;
+
+/// A log-linear histogram, HDR-style, of durations in seconds.
+struct Histogram {
+    buckets: [AtomicU64; HIST_BUCKETS],
+    count: AtomicU64,
+    sum: AtomicF64,
+    max: AtomicF64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            count: AtomicU64::new(0),
+            sum: AtomicF64::new(0.0),
+            max: AtomicF64::new(0.0),
+        }
+    }
+}
+
+/// The upper bound of the given histogram bucket, in seconds.
+fn bucket_bound(idx: usize) -> f64 {
+    HIST_MIN * (idx as f64 / HIST_SUBBUCKETS).exp2()
+}
+
+fn bucket_index(value: f64) -> usize {
+    if value <= HIST_MIN {
+        return 0;
+    }
+    ((value / HIST_MIN).log2() * HIST_SUBBUCKETS)
+        .ceil()
+        .min((HIST_BUCKETS - 1) as f64) as usize
+}
+
+/// A summary of a histogram, with percentiles accurate to the bucket width.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HistogramSummary {
+    pub count: u64,
+    pub sum: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub p999: f64,
+    pub max: f64,
+    /// Cumulative counts at each bucket's upper bound, in seconds, as in a Prometheus histogram.
+    pub buckets: Vec<(f64, u64)>,
+}
+
+impl Histogram {
+    fn record(&self, value: f64) {
+        self.buckets[bucket_index(value)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.max.fetch_max(value, Ordering::Relaxed);
+    }
+
+    fn summary(&self) -> HistogramSummary {
+        let mut cumulative = 0;
+        let buckets: Vec<(f64, u64)> = self
+            .buckets
+            .iter()
+            .enumerate()
+            .map(|(idx, bucket)| {
+                cumulative += bucket.load(Ordering::Relaxed);
+                (bucket_bound(idx), cumulative)
+            })
+            .collect();
+        let count = cumulative;
+        let max = self.max.load(Ordering::Relaxed);
+        let percentile = |p: f64| {
+            let rank = (count as f64 * p).ceil().max(1.0) as u64;
+            buckets
+                .iter()
+                .find(|(_, cumulative)| *cumulative >= rank)
+                .map(|(bound, _)| bound.min(max))
+                .unwrap_or(0.0)
+        };
+        HistogramSummary {
+            count,
+            sum: self.sum.load(Ordering::Relaxed),
+            p50: percentile(0.5),
+            p90: percentile(0.9),
+            p99: percentile(0.99),
+            p999: percentile(0.999),
+            max,
+            buckets,
+        }
+    }
+}
+
+static HIST_STATS: CtxField<DashMap<SmolStr, Histogram>> = |_| DashMap::new();
+
+/// Records a duration, in seconds, into the named histogram.
+pub fn stat_record_hist(ctx: &AnyCtx<Config>, stat: &str, secs: f64) {
+    ctx.get(HIST_STATS)
+        .entry(stat.into())
+        .or_default()
+        .record(secs);
+}
+
+pub fn stat_get_hist(ctx: &AnyCtx<Config>, stat: &str) -> Option<HistogramSummary> {
+    ctx.get(HIST_STATS).get(stat).map(|hist| hist.summary())
+}
+
+/// A snapshot of every histogram, sorted by name.
+pub fn stat_all_hists(ctx: &AnyCtx<Config>) -> Vec<(SmolStr, HistogramSummary)> {
+    let mut stats: Vec<_> = ctx
+        .get(HIST_STATS)
+        .iter()
+        .map(|entry| (entry.key().clone(), entry.value().summary()))
+        .collect();
+    stats.sort_by(|a, b| a.0.cmp(&b.0));
+    stats
+}
+
 pub struct ClientControlImpl(pub AnyCtx<Config>);
 
 #[async_trait]
@@ -63,3 +198,27 @@ pub trait ClientControlProtocol {
 //         smol::future::pending().await
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_percentiles() {
+        let hist = Histogram::default();
+        for ms in 1..=1000 {
+            hist.record(ms as f64 / 1000.0);
+        }
+        let summary = hist.summary();
+        assert_eq!(summary.count, 1000);
+        assert_eq!(summary.max, 1.0);
+        // percentiles are rounded up to a bucket bound, which is at most 19% off
+        for (estimate, truth) in [(summary.p50, 0.5), (summary.p90, 0.9), (summary.p99, 0.99)] {
+            assert!(
+                estimate >= truth && estimate <= truth * 1.19,
+                "{estimate} vs {truth}"
+            );
+        }
+        assert_eq!(summary.buckets.last().unwrap().1, 1000);
+    }
+}
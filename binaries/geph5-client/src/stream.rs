@@ -0,0 +1,68 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_util::{AsyncRead, AsyncWrite};
+use serde::{Deserialize, Serialize};
+use sillad::Pipe;
+
+/// What kind of connection to open through the tunnel with [`Client::open`](crate::Client::open).
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    /// A TCP connection to a "host:port" destination.
+    Tcp,
+    /// UDP datagrams to and from a "host:port" destination. Each datagram, in either direction, is framed on the stream with its length as a little-endian u16.
+    Udp,
+}
+
+impl Protocol {
+    /// The name the exit knows the protocol by.
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        }
+    }
+}
+
+/// A connection through the tunnel, subject to the same host rules, blocklists, and accounting as connections through the proxy listeners.
+pub struct TunnelStream {
+    inner: Box<dyn Pipe>,
+}
+
+impl TunnelStream {
+    pub(crate) fn new(inner: Box<dyn Pipe>) -> Self {
+        Self { inner }
+    }
+}
+
+impl AsyncRead for TunnelStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TunnelStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
@@ -0,0 +1,170 @@
+//! A transparent proxy listener for Linux router/gateway deployments. Connections redirected to it by iptables (either REDIRECT or TPROXY) get piped through the tunnel to their original destination.
+//!
+//! Make sure the client's own traffic (e.g. with `-m owner --uid-owner`) is excluded from the redirection rules, or it will loop back into itself.
+
+use anyctx::AnyCtx;
+
+use crate::Config;
+
+#[cfg(target_os = "linux")]
+pub async fn transparent_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    use std::net::SocketAddr;
+
+    use futures_util::AsyncReadExt as _;
+    use nursery_macro::nursery;
+    use socket2::{Domain, Socket, Type};
+
+    use crate::{
+        client::HostAction,
+        client_inner::{dial_direct, open_conn, open_tunneled},
+        dns::blocklist_check,
+        listeners::RuleOverrides,
+        litecopy::litecopy,
+        reload::live_config,
+        taskpool::add_task,
+    };
+
+    let Some(listen) = ctx.init().transparent_listen else {
+        return smol::future::pending().await;
+    };
+    let socket = Socket::new(Domain::for_address(listen), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    if let Err(err) = socket.set_ip_transparent(true) {
+        tracing::warn!(
+            err = debug(err),
+            "could not set IP_TRANSPARENT, so only REDIRECT (not TPROXY) will work"
+        );
+    }
+    socket.bind(&listen.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    let listener = smol::net::TcpListener::try_from(std::net::TcpListener::from(socket))?;
+    tracing::info!(listen = display(listen), "transparent proxy listening");
+
+    nursery!({
+        loop {
+            let (client, peer_addr) = listener.accept().await?;
+            if !live_config(ctx)
+                .listen_acl
+                .admits(ctx, listen, Some(peer_addr.ip()))
+            {
+                continue;
+            }
+            let task = spawn!(async move {
+                let dest_addr: SocketAddr = match original_dst(&client) {
+                    Ok(addr) => addr,
+                    // with TPROXY, the original destination is simply our local address
+                    Err(_) => client.local_addr()?,
+                };
+                if dest_addr == listen {
+                    anyhow::bail!("connection from {peer_addr} was not redirected");
+                }
+                tracing::trace!(
+                    peer_addr = display(peer_addr),
+                    dest_addr = display(dest_addr),
+                    "transparent connection accepted"
+                );
+                let stream: Box<dyn sillad::Pipe> = match sniff_host_rule(ctx, &client).await {
+                    Some((sni, HostAction::Block)) => {
+                        anyhow::bail!("{sni} is blocked by a host rule")
+                    }
+                    Some((_, HostAction::Direct)) => {
+                        dial_direct(ctx, &dest_addr.to_string()).await?
+                    }
+                    // open_conn would only see the bare IP, which split tunneling could still send around the tunnel, so the rule for the sniffed name is applied here, as open_conn would for a name
+                    Some((sni, HostAction::Proxy)) => {
+                        if let Some(list) = blocklist_check(ctx, &sni) {
+                            anyhow::bail!("{sni} is blocked by {list}");
+                        }
+                        Box::new(
+                            open_tunneled(
+                                ctx,
+                                &RuleOverrides::default(),
+                                "tcp",
+                                &dest_addr.to_string(),
+                            )
+                            .await?,
+                        )
+                    }
+                    None => open_conn(ctx, "tcp", &dest_addr.to_string()).await?,
+                };
+                let (read_client, write_client) = client.split();
+                let (read_stream, write_stream) = stream.split();
+                // each direction half-closes on its own, so the relay is done only once both are
+                futures_util::future::try_join(
+                    litecopy(read_stream, write_client),
+                    litecopy(read_client, write_stream),
+                )
+                .await?;
+                anyhow::Ok(())
+            });
+            if let Some(task_limit) = live_config(ctx).task_limit {
+                add_task(task_limit, task);
+            } else {
+                task.detach();
+            }
+        }
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn transparent_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    if ctx.init().transparent_listen.is_some() {
+        anyhow::bail!("transparent proxying is only supported on Linux")
+    }
+    smol::future::pending().await
+}
+
+/// If host rules are configured, peeks at the TLS ClientHello the client sends and looks up the host rule for its SNI.
+#[cfg(target_os = "linux")]
+async fn sniff_host_rule(
+    ctx: &AnyCtx<Config>,
+    client: &smol::net::TcpStream,
+) -> Option<(String, crate::client::HostAction)> {
+    use smol_timeout2::TimeoutExt;
+
+    use crate::{client_inner::host_rule, reload::live_config, sni::parse_sni};
+
+    if live_config(ctx).host_rules.is_empty() {
+        return None;
+    }
+    let mut buf = [0u8; 2048];
+    // protocols where the server speaks first never send anything, so don't wait long
+    let n = client
+        .peek(&mut buf)
+        .timeout(std::time::Duration::from_millis(300))
+        .await?
+        .ok()?;
+    let sni = parse_sni(&buf[..n])?;
+    let action = host_rule(ctx, &sni)?;
+    tracing::debug!(sni, action = debug(action), "host rule matched sniffed SNI");
+    Some((sni, action))
+}
+
+/// Recovers the pre-NAT destination of a REDIRECTed IPv4 connection using SO_ORIGINAL_DST.
+#[cfg(target_os = "linux")]
+fn original_dst(stream: &smol::net::TcpStream) -> std::io::Result<std::net::SocketAddr> {
+    use std::{
+        net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+        os::fd::AsRawFd,
+    };
+
+    let mut addr: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_IP,
+            libc::SO_ORIGINAL_DST,
+            &mut addr as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(SocketAddr::V4(SocketAddrV4::new(
+        Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+        u16::from_be(addr.sin_port),
+    )))
+}
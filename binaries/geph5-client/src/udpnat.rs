@@ -0,0 +1,112 @@
+//! Relaying UDP through the exit's `udpnat$` service, which gives every relayed local socket a public UDP socket of its own, with full-cone NAT semantics. Packets go as datagrams of the stream when the exit understands them, so that losing one never holds up the others, and with a length prefix on the stream itself otherwise.
+
+use std::net::{IpAddr, SocketAddr};
+
+use anyctx::AnyCtx;
+use anyhow::Context;
+use bytes::Bytes;
+use futures_util::{io::WriteHalf, AsyncReadExt, AsyncWriteExt};
+use geph5_misc_rpc::udp::{UdpNatFrame, UDP_NAT_PROTOCOL};
+use smol::{channel::Receiver, future::FutureExt as _, lock::Mutex};
+
+use crate::{
+    client::HostAction,
+    client_inner::{host_rule, open_tunneled},
+    conntrack::{TrackedDatagrams, TrackedStream},
+    dns::{blocklist_check, exit_resolve},
+    listeners::RuleOverrides,
+    spoof_dns::fake_dns_backtranslate,
+    Config,
+};
+
+/// A `udpnat$` stream, relaying the packets of one local UDP socket to any number of peers.
+pub struct UdpNatTunnel {
+    datagrams: TrackedDatagrams,
+    write: Mutex<WriteHalf<TrackedStream>>,
+    recv_framed: Receiver<Bytes>,
+    _read_framed: smol::Task<()>,
+}
+
+impl UdpNatTunnel {
+    /// Opens a `udpnat$` stream through the tunnel.
+    pub async fn open(ctx: &AnyCtx<Config>, rules: &RuleOverrides) -> anyhow::Result<Self> {
+        let stream = open_tunneled(ctx, rules, UDP_NAT_PROTOCOL, "").await?;
+        let datagrams = stream.datagrams();
+        let (mut read, write) = stream.split();
+        // packets framed on the stream are read in the background, since a read cut off halfway would lose track of the framing
+        let (send_framed, recv_framed) = smol::channel::bounded(64);
+        let read_framed = smolscale::spawn(async move {
+            let read_loop = async {
+                let mut len_buf = [0u8; 2];
+                loop {
+                    read.read_exact(&mut len_buf).await?;
+                    let mut buf = vec![0u8; u16::from_le_bytes(len_buf) as usize];
+                    read.read_exact(&mut buf).await?;
+                    let _ = send_framed.try_send(Bytes::from(buf));
+                }
+            };
+            let result: anyhow::Result<()> = read_loop.await;
+            if let Err(err) = result {
+                tracing::trace!(err = debug(err), "udpnat stream closed");
+            }
+        });
+        Ok(Self {
+            datagrams,
+            write: Mutex::new(write),
+            recv_framed,
+            _read_framed: read_framed,
+        })
+    }
+
+    /// Sends a packet, which may be dropped on the way like any UDP packet.
+    pub async fn send(&self, frame: &UdpNatFrame) -> anyhow::Result<()> {
+        let encoded = frame.encode();
+        if self.datagrams.is_supported() {
+            self.datagrams.send(&encoded)?;
+        } else {
+            let mut write = self.write.lock().await;
+            write
+                .write_all(&(encoded.len() as u16).to_le_bytes())
+                .await?;
+            write.write_all(&encoded).await?;
+            write.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Receives the next packet, from whichever peer sent it. Fails once the stream is gone.
+    pub async fn recv(&self) -> anyhow::Result<UdpNatFrame> {
+        let packet = async { anyhow::Ok(self.datagrams.recv().await?) }
+            .race(async { anyhow::Ok(self.recv_framed.recv().await?) })
+            .await?;
+        UdpNatFrame::decode(&packet)
+    }
+}
+
+/// Figures out where UDP packets to a "host:port" destination go, applying the blocklists and host rules, and resolving names, including fake DNS ones, through the exit. UDP always goes through the tunnel, so host rules sending a destination direct do not apply to it.
+pub async fn resolve_udp_dest(ctx: &AnyCtx<Config>, dest: &str) -> anyhow::Result<SocketAddr> {
+    let (host, port) = dest.rsplit_once(':').context("no port in destination")?;
+    let port: u16 = port.parse().context("bad port in destination")?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let host = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(v4)) => fake_dns_backtranslate(ctx, v4).unwrap_or_else(|| host.to_string()),
+        _ => host.to_string(),
+    };
+    if let Some(list) = blocklist_check(ctx, &host) {
+        anyhow::bail!("{host} is blocked by {list}");
+    }
+    if matches!(host_rule(ctx, &host), Some(HostAction::Block)) {
+        anyhow::bail!("{host} is blocked by a host rule");
+    }
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, port));
+    }
+    // the exit only relays UDP over IPv4
+    let records = exit_resolve(ctx, &host).await?;
+    let record = records
+        .iter()
+        .find(|record| record.addr.is_ipv4())
+        .or_else(|| records.first())
+        .context(format!("no addresses for {host}"))?;
+    Ok(SocketAddr::new(record.addr, port))
+}
@@ -0,0 +1,117 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyctx::AnyCtx;
+use serde::{Deserialize, Serialize};
+use sqlx::Row;
+
+use crate::{database::DATABASE, stats::stat_get_num, Config};
+
+/// How often we write the traffic counters to disk.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long hourly records are kept. Daily records are kept forever.
+const HOURLY_RETENTION_SECS: u64 = 90 * 86400;
+
+/// Granularity of usage records.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum UsageGranularity {
+    Hourly,
+    Daily,
+}
+
+impl UsageGranularity {
+    fn table(&self) -> &'static str {
+        match self {
+            UsageGranularity::Hourly => "usage_hourly",
+            UsageGranularity::Daily => "usage_daily",
+        }
+    }
+
+    fn period_secs(&self) -> u64 {
+        match self {
+            UsageGranularity::Hourly => 3600,
+            UsageGranularity::Daily => 86400,
+        }
+    }
+}
+
+/// Traffic through the tunnel during one hour or (UTC) day.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UsageRecord {
+    pub start_unix: u64,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+/// Periodically adds the traffic since the last flush to the persistent usage history.
+pub async fn usage_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    let mut last_rx = 0.0;
+    let mut last_tx = 0.0;
+    loop {
+        smol::Timer::after(FLUSH_INTERVAL).await;
+        let rx = stat_get_num(ctx, "total_rx_bytes");
+        let tx = stat_get_num(ctx, "total_tx_bytes");
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        if let Err(err) = record_usage(ctx, now, (rx - last_rx) as u64, (tx - last_tx) as u64).await
+        {
+            tracing::warn!(err = debug(err), "could not record usage history");
+            continue;
+        }
+        last_rx = rx;
+        last_tx = tx;
+    }
+}
+
+async fn record_usage(
+    ctx: &AnyCtx<Config>,
+    now: u64,
+    rx_bytes: u64,
+    tx_bytes: u64,
+) -> anyhow::Result<()> {
+    let mut txn = ctx.get(DATABASE).begin().await?;
+    for granularity in [UsageGranularity::Hourly, UsageGranularity::Daily] {
+        sqlx::query(&format!(
+            "INSERT INTO {} (period, rx_bytes, tx_bytes) VALUES (?, ?, ?)
+            ON CONFLICT(period) DO UPDATE SET rx_bytes = rx_bytes + excluded.rx_bytes, tx_bytes = tx_bytes + excluded.tx_bytes",
+            granularity.table()
+        ))
+        .bind((now / granularity.period_secs()) as i64)
+        .bind(rx_bytes as i64)
+        .bind(tx_bytes as i64)
+        .execute(&mut *txn)
+        .await?;
+    }
+    sqlx::query("DELETE FROM usage_hourly WHERE period < ?")
+        .bind((now.saturating_sub(HOURLY_RETENTION_SECS) / 3600) as i64)
+        .execute(&mut *txn)
+        .await?;
+    txn.commit().await?;
+    Ok(())
+}
+
+/// Reads the usage history for the periods starting within the given range of UNIX timestamps.
+pub async fn usage_history(
+    ctx: &AnyCtx<Config>,
+    start_unix: u64,
+    end_unix: u64,
+    granularity: UsageGranularity,
+) -> anyhow::Result<Vec<UsageRecord>> {
+    let period_secs = granularity.period_secs();
+    let rows = sqlx::query(&format!(
+        "SELECT period, rx_bytes, tx_bytes FROM {} WHERE period >= ? AND period <= ? ORDER BY period",
+        granularity.table()
+    ))
+    .bind((start_unix / period_secs) as i64)
+    .bind((end_unix / period_secs) as i64)
+    .fetch_all(ctx.get(DATABASE))
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| UsageRecord {
+            start_unix: row.get::<i64, _>("period") as u64 * period_secs,
+            rx_bytes: row.get::<i64, _>("rx_bytes") as u64,
+            tx_bytes: row.get::<i64, _>("tx_bytes") as u64,
+        })
+        .collect())
+}
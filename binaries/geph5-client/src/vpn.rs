@@ -3,6 +3,7 @@
 mod linux;
 use bytes::Bytes;
 use crossbeam_queue::ArrayQueue;
+use dashmap::DashSet;
 
 use ipstack_geph::{IpStack, IpStackConfig};
 #[cfg(target_os = "linux")]
@@ -14,11 +15,16 @@ mod dummy;
 #[cfg(any(target_os = "android", target_os = "ios"))]
 use dummy::*;
 
-use std::{net::IpAddr, time::Instant};
+use std::{
+    net::IpAddr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Instant,
+};
 
 use anyctx::AnyCtx;
 use anyhow::Context;
-use futures_util::{AsyncReadExt, AsyncWriteExt, TryFutureExt as _};
+use futures_util::AsyncReadExt;
+use geph5_misc_rpc::udp::UdpNatFrame;
 
 #[cfg(target_os = "windows")]
 mod windows;
@@ -33,17 +39,54 @@ mod macos;
 pub use macos::*;
 
 use crate::{
-    client::CtxField, client_inner::open_conn, litecopy::litecopy, spoof_dns::fake_dns_respond,
-    taskpool::add_task, Config,
+    client::CtxField,
+    client_inner::open_conn,
+    dns::dns_respond,
+    listeners::RuleOverrides,
+    litecopy::litecopy,
+    reload::live_config,
+    spoof_dns::fake_dns_respond,
+    taskpool::add_task,
+    udpnat::{resolve_udp_dest, UdpNatTunnel},
+    Config,
 };
 
+/// Whether VPN mode is on, which starts out as configured but can be flipped at runtime.
+static VPN_ENABLED: CtxField<(AtomicBool, async_event::Event)> =
+    |ctx| (AtomicBool::new(ctx.init().vpn), async_event::Event::new());
+
+/// Every address that needs to bypass the VPN, remembered so that we can whitelist them if VPN mode is turned on later.
+static VPN_BYPASS: CtxField<DashSet<IpAddr>> = |_| DashSet::new();
+
 /// Whitelist a vpn address if needed
 pub fn smart_vpn_whitelist(ctx: &AnyCtx<Config>, addr: IpAddr) {
-    if ctx.init().vpn {
+    ctx.get(VPN_BYPASS).insert(addr);
+    if vpn_enabled(ctx) {
         vpn_whitelist(addr);
     }
 }
 
+/// Whether VPN mode is currently on.
+pub fn vpn_enabled(ctx: &AnyCtx<Config>) -> bool {
+    ctx.get(VPN_ENABLED).0.load(Ordering::SeqCst)
+}
+
+/// Turns VPN mode on or off at runtime, setting up or tearing down the system routes without touching the other listeners.
+pub fn set_vpn_enabled(ctx: &AnyCtx<Config>, enabled: bool) {
+    let (flag, event) = ctx.get(VPN_ENABLED);
+    if flag.swap(enabled, Ordering::SeqCst) != enabled {
+        tracing::info!(enabled, "toggling VPN mode");
+        event.notify_all();
+    }
+}
+
+async fn wait_vpn_change(ctx: &AnyCtx<Config>, enabled: bool) {
+    ctx.get(VPN_ENABLED)
+        .1
+        .wait_until(|| (vpn_enabled(ctx) != enabled).then_some(()))
+        .await
+}
+
 /// Force a particular packet to be sent through VPN mode, regardless of whether VPN mode is on.
 pub async fn send_vpn_packet(ctx: &AnyCtx<Config>, bts: Bytes) {
     tracing::trace!(
@@ -86,39 +129,29 @@ pub async fn vpn_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
         recv_captured,
         send_injected,
     );
-    let _shuffle = if ctx.init().vpn {
-        smolscale::spawn(
-            packet_shuffle(ctx.clone(), send_captured, recv_injected)
-                .inspect_err(|e| tracing::warn!(e = debug(e), "packet_shuffle stopped")),
-        )
-    } else {
+    let _shuffle = {
         let ctx = ctx.clone();
         smolscale::spawn(async move {
-            let up_loop = async {
-                loop {
-                    let (bts, time) = ctx
-                        .get(VPN_EVENT)
-                        .wait_until(|| ctx.get(VPN_CAPTURE).pop())
-                        .await;
-
-                    tracing::trace!(
-                        len = bts.len(),
-                        elapsed = debug(time.elapsed()),
-                        packet = display(hex::encode(&bts)),
-                        "vpn shuffling up"
-                    );
-                    send_captured.send(bts).await?;
-                }
-            };
-            let dn_loop = async {
-                loop {
-                    let bts = recv_injected.recv().await?;
-                    tracing::trace!(len = bts.len(), "vpn shuffling down");
-                    let _ = ctx.get(VPN_INJECT).push(bts);
-                    ctx.get(VPN_EVENT).notify_all();
-                }
-            };
-            up_loop.race(dn_loop).await
+            loop {
+                let enabled = vpn_enabled(&ctx);
+                let shuffle = async {
+                    let res = if enabled {
+                        for addr in ctx.get(VPN_BYPASS).iter() {
+                            vpn_whitelist(*addr);
+                        }
+                        packet_shuffle(ctx.clone(), send_captured.clone(), recv_injected.clone())
+                            .await
+                    } else {
+                        manual_shuffle(&ctx, &send_captured, &recv_injected).await
+                    };
+                    if let Err(err) = res {
+                        tracing::warn!(err = debug(err), enabled, "packet shuffle stopped");
+                    }
+                    smol::future::pending().await
+                };
+                // dropping the shuffle tears down whatever routing it set up
+                shuffle.race(wait_vpn_change(&ctx, enabled)).await;
+            }
         })
     };
     loop {
@@ -141,13 +174,16 @@ pub async fn vpn_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
                     tracing::trace!(peer_addr = display(peer_addr), "dialed through VPN");
                     let (read_tunneled, write_tunneled) = tunneled.split();
                     let (read_captured, write_captured) = captured.split();
-                    litecopy(read_tunneled, write_captured)
-                        .race(litecopy(read_captured, write_tunneled))
-                        .await?;
+                    // each direction half-closes on its own, so the relay is done only once both are
+                    futures_util::future::try_join(
+                        litecopy(read_tunneled, write_captured),
+                        litecopy(read_captured, write_tunneled),
+                    )
+                    .await?;
                     anyhow::Ok(())
                 });
 
-                if let Some(task_limit) = ctx.init().task_limit {
+                if let Some(task_limit) = live_config(ctx).task_limit {
                     add_task(task_limit, task);
                 } else {
                     task.detach();
@@ -173,33 +209,43 @@ pub async fn vpn_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
                             let pkt = captured.recv().await?;
                             captured.send(&fake_dns_respond(&ctx_clone, &pkt)?).await?;
                         }
+                    } else if peer_addr.port() == 53
+                        && (ctx_clone.init().dns_listen.is_some()
+                            || ctx_clone.init().doh_upstream.is_some())
+                    {
+                        // answer through the local caching resolver
+                        loop {
+                            let pkt = captured.recv().await?;
+                            captured.send(&dns_respond(&ctx_clone, &pkt).await?).await?;
+                        }
                     } else {
-                        let tunneled = open_conn(&ctx_clone, "udp", &peer_addr.to_string()).await?;
-                        let (mut read_tunneled, mut write_tunneled) = tunneled.split();
+                        let peer = resolve_udp_dest(&ctx_clone, &peer_addr.to_string()).await?;
+                        let tunnel =
+                            UdpNatTunnel::open(&ctx_clone, &RuleOverrides::default()).await?;
                         let up_loop = async {
                             loop {
                                 let to_up = captured.recv().await?;
-                                write_tunneled
-                                    .write_all(&(to_up.len() as u16).to_le_bytes())
+                                tunnel
+                                    .send(&UdpNatFrame {
+                                        peer,
+                                        payload: Bytes::copy_from_slice(&to_up),
+                                    })
                                     .await?;
-                                write_tunneled.write_all(&to_up).await?;
-                                write_tunneled.flush().await?;
                             }
                         };
                         let dn_loop = async {
                             loop {
-                                let mut len_buf = [0u8; 2];
-                                read_tunneled.read_exact(&mut len_buf).await?;
-                                let len = u16::from_le_bytes(len_buf) as usize;
-                                let mut buf = vec![0u8; len];
-                                read_tunneled.read_exact(&mut buf).await?;
-                                captured.send(&buf).await?;
+                                let frame = tunnel.recv().await?;
+                                // the captured flow only talks to one peer, so packets from others have nowhere to go
+                                if frame.peer == peer {
+                                    captured.send(&frame.payload).await?;
+                                }
                             }
                         };
                         up_loop.race(dn_loop).await
                     }
                 });
-                if let Some(task_limit) = ctx.init().task_limit {
+                if let Some(task_limit) = live_config(ctx).task_limit {
                     add_task(task_limit, task);
                 } else {
                     task.detach();
@@ -214,3 +260,36 @@ pub async fn vpn_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
         }
     }
 }
+
+/// Shuffles packets between the IP stack and the packets sent through [send_vpn_packet] and [recv_vpn_packet], for when the system VPN is off.
+async fn manual_shuffle(
+    ctx: &AnyCtx<Config>,
+    send_captured: &smol::channel::Sender<Bytes>,
+    recv_injected: &smol::channel::Receiver<Bytes>,
+) -> anyhow::Result<()> {
+    let up_loop = async {
+        loop {
+            let (bts, time) = ctx
+                .get(VPN_EVENT)
+                .wait_until(|| ctx.get(VPN_CAPTURE).pop())
+                .await;
+
+            tracing::trace!(
+                len = bts.len(),
+                elapsed = debug(time.elapsed()),
+                packet = display(hex::encode(&bts)),
+                "vpn shuffling up"
+            );
+            send_captured.send(bts).await?;
+        }
+    };
+    let dn_loop = async {
+        loop {
+            let bts = recv_injected.recv().await?;
+            tracing::trace!(len = bts.len(), "vpn shuffling down");
+            let _ = ctx.get(VPN_INJECT).push(bts);
+            ctx.get(VPN_EVENT).notify_all();
+        }
+    };
+    up_loop.race(dn_loop).await
+}
@@ -14,10 +14,15 @@ use smol::{
 use std::{
     net::{IpAddr, Ipv4Addr},
     process::Command,
-    sync::LazyLock,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        LazyLock, Once,
+    },
 };
 
-use crate::{client_inner::open_conn, spoof_dns::fake_dns_respond, Config};
+use crate::{
+    client_inner::open_conn, dns::dns_resolve_remote, spoof_dns::fake_dns_respond, Config,
+};
 
 const FAKE_LOCAL_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::new(100, 64, 89, 64));
 
@@ -28,23 +33,36 @@ pub(super) fn vpn_whitelist(addr: IpAddr) {
     });
 }
 
+/// Whether our routing rules are currently installed.
+static ROUTING_ACTIVE: AtomicBool = AtomicBool::new(false);
+
 #[allow(clippy::redundant_closure)]
 fn setup_routing() -> anyhow::Result<()> {
     let cmd = include_str!("linux_routing_setup.sh");
     let mut child = Command::new("sh").arg("-c").arg(cmd).spawn().unwrap();
     child.wait().context("iptables was not set up properly")?;
-
-    unsafe {
-        libc::atexit(teardown_routing);
-    }
-    ctrlc::set_handler(|| teardown_routing())?;
+    ROUTING_ACTIVE.store(true, Ordering::SeqCst);
+
+    // VPN mode can be toggled many times, but the exit handlers must only be installed once
+    static HANDLERS: Once = Once::new();
+    let mut res = Ok(());
+    HANDLERS.call_once(|| {
+        unsafe {
+            libc::atexit(teardown_routing_and_exit);
+        }
+        res = ctrlc::set_handler(|| teardown_routing_and_exit());
+    });
+    res?;
 
     anyhow::Ok(())
 }
 
 static GEPH_DNS: LazyLock<Mutex<String>> = LazyLock::new(|| Mutex::new(String::new()));
 
-extern "C" fn teardown_routing() {
+fn teardown_routing() {
+    if !ROUTING_ACTIVE.swap(false, Ordering::SeqCst) {
+        return;
+    }
     tracing::debug!(
         "!!!!!!!!!!!!!!!!!!!!!!! teardown_routing starting !!!!!!!!!!!!!!!!!!!!!!!!!!!!!"
     );
@@ -53,6 +71,10 @@ extern "C" fn teardown_routing() {
     let cmd = include_str!("linux_routing_teardown.sh");
     let mut child = Command::new("sh").arg("-c").arg(cmd).spawn().unwrap();
     child.wait().expect("iptables was not set up properly");
+}
+
+extern "C" fn teardown_routing_and_exit() {
+    teardown_routing();
     std::process::exit(0);
 }
 
@@ -82,16 +104,8 @@ pub(super) async fn packet_shuffle(
                 let dns_proxy = dns_proxy.clone();
                 let ctx = ctx.clone();
                 smolscale::spawn(async move {
-                    let buf = &buf[..n];
-                    let mut conn = open_conn(&ctx, "udp", "1.1.1.1:53").await?;
-                    conn.write_all(&(buf.len() as u16).to_le_bytes()).await?;
-                    conn.write_all(buf).await?;
-                    let mut len_buf = [0u8; 2];
-                    conn.read_exact(&mut len_buf).await?;
-                    let len = u16::from_le_bytes(len_buf) as usize;
-                    let mut buf = vec![0u8; len];
-                    conn.read_exact(&mut buf).await?;
-                    dns_proxy.send_to(&buf, src).await?;
+                    let resp = dns_resolve_remote(&ctx, &buf[..n]).await?;
+                    dns_proxy.send_to(&resp, src).await?;
                     anyhow::Ok(())
                 })
                 .detach();
@@ -99,9 +113,9 @@ pub(super) async fn packet_shuffle(
         }
     };
 
-    use std::os::fd::{AsRawFd, FromRawFd};
-    let tun_device = configure_tun_device();
-    let fd_num = tun_device.as_raw_fd();
+    use std::os::fd::{FromRawFd, IntoRawFd};
+    // the file takes sole ownership of the descriptor, so that the device is really closed when VPN mode is turned off
+    let fd_num = configure_tun_device().into_raw_fd();
     let up_file = smol::Async::new(unsafe { std::fs::File::from_raw_fd(fd_num) })
         .context("cannot init up_file")?;
 
@@ -21,16 +21,36 @@ pub(super) async fn packet_shuffle(
     recv_injected: Receiver<Bytes>,
 ) -> anyhow::Result<()> {
     #[cfg(feature = "windivert")]
-    std::thread::spawn({
-        let ctx = ctx.clone();
-        move || up_shuffle(ctx, send_captured)
-    });
-    #[cfg(feature = "windivert")]
-    std::thread::spawn({
-        let ctx = ctx.clone();
-        move || dn_shuffle(ctx, recv_injected)
-    });
-    smol::future::pending().await
+    {
+        use smol::future::FutureExt as _;
+        // the blocking threads talk through channels owned by this future, so that they notice and stop once VPN mode is turned off
+        let (send_up, recv_up) = smol::channel::bounded(100);
+        let (send_dn, recv_dn) = smol::channel::bounded(100);
+        std::thread::spawn({
+            let ctx = ctx.clone();
+            move || up_shuffle(ctx, send_up)
+        });
+        std::thread::spawn({
+            let ctx = ctx.clone();
+            move || dn_shuffle(ctx, recv_dn)
+        });
+        let up = async {
+            loop {
+                send_captured.send(recv_up.recv().await?).await?;
+            }
+        };
+        let dn = async {
+            loop {
+                send_dn.send(recv_injected.recv().await?).await?;
+            }
+        };
+        up.race(dn).await
+    }
+    #[cfg(not(feature = "windivert"))]
+    {
+        let _ = (ctx, send_captured, recv_injected);
+        smol::future::pending().await
+    }
 }
 
 #[cfg(feature = "windivert")]
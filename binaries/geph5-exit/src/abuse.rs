@@ -0,0 +1,151 @@
+//! Coarse counts of connections to particular destination ports, to help operators answer abuse complaints, which usually name a destination and an hour. We only ever count connections per destination address and hour, never anything about who made them, and only keep the counts for a bounded time. Nothing is counted unless ports are configured.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{LazyLock, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::CONFIG_FILE;
+
+/// The longest we ever keep counts for, whatever the config says.
+const MAX_RETENTION_HOURS: u64 = 24 * 7;
+
+/// Which connections are counted, and what happens to the counts.
+#[derive(Deserialize, Clone, Debug)]
+pub struct AbuseCountConfig {
+    /// Destination ports whose connections are counted. Empty, the default, turns counting off.
+    #[serde(default)]
+    pub ports: Vec<u16>,
+    /// How many hours of counts are kept, at most a week.
+    #[serde(default = "default_retention_hours")]
+    pub retention_hours: u64,
+    /// Where the counts are written every minute, as JSON, replacing what was there. Without a path they are only kept in memory.
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+}
+
+impl Default for AbuseCountConfig {
+    fn default() -> Self {
+        Self {
+            ports: vec![],
+            retention_hours: default_retention_hours(),
+            path: None,
+        }
+    }
+}
+
+fn default_retention_hours() -> u64 {
+    24
+}
+
+/// How many connections went to a destination within an hour.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+struct CountRow {
+    /// The start of the hour, in seconds since the Unix epoch.
+    hour: u64,
+    dest: SocketAddr,
+    count: u64,
+}
+
+/// Connection counts, bucketed by hour since the Unix epoch.
+#[derive(Default)]
+struct Counts {
+    buckets: BTreeMap<u64, HashMap<SocketAddr, u64>>,
+}
+
+impl Counts {
+    fn record(&mut self, hour: u64, dest: SocketAddr) {
+        *self
+            .buckets
+            .entry(hour)
+            .or_default()
+            .entry(dest)
+            .or_default() += 1;
+    }
+
+    /// Forgets every hour before the given one.
+    fn prune(&mut self, oldest_hour: u64) {
+        self.buckets = self.buckets.split_off(&oldest_hour);
+    }
+
+    fn rows(&self) -> Vec<CountRow> {
+        self.buckets
+            .iter()
+            .flat_map(|(hour, dests)| {
+                dests.iter().map(|(dest, count)| CountRow {
+                    hour: hour * 3600,
+                    dest: *dest,
+                    count: *count,
+                })
+            })
+            .collect()
+    }
+}
+
+static COUNTS: LazyLock<Mutex<Counts>> = LazyLock::new(Default::default);
+
+fn current_hour() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        / 3600
+}
+
+/// Counts a connection to the destination, if its port is one of the configured ones.
+pub fn count_connection(dest: SocketAddr) {
+    if CONFIG_FILE.wait().abuse_counts.ports.contains(&dest.port()) {
+        COUNTS.lock().unwrap().record(current_hour(), dest);
+    }
+}
+
+/// This loop forgets counts once they are too old, and writes what is left to the configured file.
+pub async fn abuse_count_loop() -> anyhow::Result<()> {
+    loop {
+        smol::Timer::after(Duration::from_secs(60)).await;
+        let cfg = &CONFIG_FILE.wait().abuse_counts;
+        let retention_hours = cfg.retention_hours.min(MAX_RETENTION_HOURS);
+        let rows = {
+            let mut counts = COUNTS.lock().unwrap();
+            counts.prune((current_hour() + 1).saturating_sub(retention_hours));
+            counts.rows()
+        };
+        if let Some(path) = &cfg.path {
+            if let Err(err) = std::fs::write(path, serde_json::to_vec(&rows)?) {
+                tracing::warn!(
+                    path = debug(path),
+                    err = debug(err),
+                    "could not write abuse counts"
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn old_hours_are_forgotten() {
+        let dest: SocketAddr = "192.0.2.1:25".parse().unwrap();
+        let mut counts = Counts::default();
+        counts.record(10, dest);
+        counts.record(11, dest);
+        counts.record(11, dest);
+        counts.prune(11);
+        assert_eq!(
+            counts.rows(),
+            vec![CountRow {
+                hour: 11 * 3600,
+                dest,
+                count: 2
+            }]
+        );
+    }
+}
@@ -1,17 +1,92 @@
 use std::net::{IpAddr, SocketAddr};
 
-use crate::CONFIG_FILE;
-
-pub fn proxy_allowed(addr: SocketAddr, is_free: bool) -> bool {
-    if is_free
-        && !CONFIG_FILE
-            .wait()
-            .free_port_whitelist
-            .contains(&addr.port())
+use geph5_misc_rpc::exit::StreamRejection;
+use ipnet::IpNet;
+use serde::Deserialize;
+
+use crate::{asn::ip_to_asn_country, CONFIG_FILE};
+
+/// Which destinations we refuse to proxy to, whatever the account level.
+#[derive(Deserialize, Clone, Debug)]
+pub struct EgressPolicy {
+    /// Inclusive ranges of destination ports that are blocked. By default just SMTP, which is mostly good for spam.
+    #[serde(default = "default_blocked_ports")]
+    pub blocked_ports: Vec<(u16, u16)>,
+    /// Whether private, loopback, and other addresses that aren't globally routable are blocked. Only worth turning off for exits that are meant to reach a private network.
+    #[serde(default = "default_block_private")]
+    pub block_private: bool,
+    /// Further ranges of addresses that are blocked.
+    #[serde(default)]
+    pub blocked_cidrs: Vec<IpNet>,
+    /// Countries, as two-letter codes, whose IPv4 addresses are blocked, going by where the addresses are registered.
+    #[serde(default)]
+    pub blocked_countries: Vec<String>,
+}
+
+impl Default for EgressPolicy {
+    fn default() -> Self {
+        Self {
+            blocked_ports: default_blocked_ports(),
+            block_private: default_block_private(),
+            blocked_cidrs: vec![],
+            blocked_countries: vec![],
+        }
+    }
+}
+
+fn default_blocked_ports() -> Vec<(u16, u16)> {
+    vec![(25, 25)]
+}
+
+fn default_block_private() -> bool {
+    true
+}
+
+/// Checks whether a user of the given level may be proxied to the address, returning why not if they may not.
+pub async fn check_destination(addr: SocketAddr, is_free: bool) -> Result<(), StreamRejection> {
+    let cfg = CONFIG_FILE.wait();
+    let policy = &cfg.egress_policy;
+    if policy
+        .blocked_ports
+        .iter()
+        .any(|(min, max)| (*min..=*max).contains(&addr.port()))
+    {
+        return Err(StreamRejection::BlockedPort(addr.port()));
+    }
+    if is_free && !cfg.free_port_whitelist.contains(&addr.port()) {
+        return Err(StreamRejection::PaidPortOnly(addr.port()));
+    }
+    if policy.block_private && !is_globally_routable(&addr.ip()) {
+        return Err(StreamRejection::PrivateAddress);
+    }
+    if policy
+        .blocked_cidrs
+        .iter()
+        .any(|net| net.contains(&addr.ip()))
     {
-        return false;
+        return Err(StreamRejection::BlockedRange);
+    }
+    if let (IpAddr::V4(ip), false) = (addr.ip(), policy.blocked_countries.is_empty()) {
+        match ip_to_asn_country(ip).await {
+            Ok((_, country)) => {
+                if policy
+                    .blocked_countries
+                    .iter()
+                    .any(|blocked| blocked.eq_ignore_ascii_case(&country))
+                {
+                    return Err(StreamRejection::BlockedCountry(country));
+                }
+            }
+            Err(err) => {
+                tracing::debug!(
+                    addr = display(addr),
+                    err = debug(err),
+                    "could not look up the country of a destination"
+                )
+            }
+        }
     }
-    is_globally_routable(&addr.ip())
+    Ok(())
 }
 
 fn is_globally_routable(ip: &IpAddr) -> bool {
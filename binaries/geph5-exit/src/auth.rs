@@ -1,9 +1,11 @@
 use std::{sync::LazyLock, thread::available_parallelism};
 
-use geph5_broker_protocol::AccountLevel;
-use mizaru2::{ClientToken, UnblindedSignature};
+use anyhow::Context;
+use geph5_misc_rpc::exit::ConnectCredential;
 use threadpool::ThreadPool;
 
+use crate::CONFIG_FILE;
+
 static POOL: LazyLock<ThreadPool> = LazyLock::new(|| {
     ThreadPool::with_name(
         "user-verifier".to_string(),
@@ -11,33 +13,54 @@ static POOL: LazyLock<ThreadPool> = LazyLock::new(|| {
     )
 });
 
-pub async fn verify_user(
-    level: AccountLevel,
-    token: ClientToken,
-    sig: UnblindedSignature,
-) -> anyhow::Result<()> {
-    if sig.epoch.abs_diff(mizaru2::current_epoch()) > 2 {
-        anyhow::bail!("signature from wrong epoch")
-    }
-    // TODO make this configurable, once we get to all the servers
-    let key = match level {
-        AccountLevel::Free => mizaru2::PublicKey::from_bytes(
-            hex::decode("0558216cbab7a9c46f298f4c26e171add9af87d0694988b8a8fe52ee932aa754")
-                .unwrap()
-                .try_into()
-                .unwrap(),
-        ),
-        AccountLevel::Plus => mizaru2::PublicKey::from_bytes(
-            hex::decode("cf6f58868c6d9459b3a63bc2bd86165631b3e916bad7f62b578cd9614e0bcb3b")
-                .unwrap()
+/// The broker's Mizaru public keys for Plus and Free, in that order, as currently configured. The keys from before a rotation come second, if they are still accepted.
+fn mizaru_keys() -> anyhow::Result<Vec<(mizaru2::PublicKey, mizaru2::PublicKey)>> {
+    let broker = CONFIG_FILE
+        .wait()
+        .broker
+        .as_ref()
+        .context("users are only verified with a broker")?;
+    let parse = |hex_key: &str| {
+        anyhow::Ok(mizaru2::PublicKey::from_bytes(
+            hex::decode(hex_key)
+                .context("Mizaru key must be hex")?
                 .try_into()
-                .unwrap(),
-        ),
+                .ok()
+                .context("Mizaru key must be 32 bytes")?,
+        ))
     };
+    let mut keys = vec![(parse(&broker.mizaru_plus)?, parse(&broker.mizaru_free)?)];
+    if broker.previous_mizaru_plus.is_some() || broker.previous_mizaru_free.is_some() {
+        keys.push((
+            parse(
+                broker
+                    .previous_mizaru_plus
+                    .as_deref()
+                    .unwrap_or(&broker.mizaru_plus),
+            )?,
+            parse(
+                broker
+                    .previous_mizaru_free
+                    .as_deref()
+                    .unwrap_or(&broker.mizaru_free),
+            )?,
+        ));
+    }
+    Ok(keys)
+}
 
+pub async fn verify_user(credential: ConnectCredential) -> anyhow::Result<()> {
+    let keys = mizaru_keys()?;
     let (send, recv) = oneshot::channel();
     POOL.execute(move || {
-        let _ = send.send(key.blind_verify(token, &sig));
+        let mut result = Ok(());
+        for (plus_key, free_key) in keys.iter() {
+            result = credential.verify(plus_key, free_key);
+            if result.is_ok() {
+                break;
+            }
+        }
+        let _ = send.send(result);
     });
     recv.await??;
     Ok(())
@@ -10,13 +10,19 @@ use std::{
 
 use async_trait::async_trait;
 use ed25519_dalek::VerifyingKey;
-use geph5_broker_protocol::{BrokerClient, ExitDescriptor, Mac, Signed, DOMAIN_EXIT_DESCRIPTOR};
+use geph5_broker_protocol::{
+    BrokerClient, DirectUdpListener, ExitDescriptor, ExitFeatures, ExitLoad, Mac, Signed,
+    DOMAIN_EXIT_DESCRIPTOR, DOMAIN_EXIT_FEATURES, DOMAIN_EXIT_LOAD,
+};
 use nanorpc::{JrpcRequest, JrpcResponse, RpcTransport};
 use reqwest::Method;
 use tap::Tap;
 
 use crate::{
-    ratelimit::{get_kbps, get_load},
+    drain::is_draining,
+    ipv6::ipv6_egress_enabled,
+    listen::get_session_count,
+    ratelimit::{get_cpu, get_kbps, get_load},
     schedlag::SCHEDULER_LAG_SECS,
     tasklimit::get_task_count,
     watchdog::kick_watchdog,
@@ -102,6 +108,8 @@ pub async fn broker_loop() -> anyhow::Result<()> {
             let client = BrokerClient(transport);
 
             loop {
+                // the auth token may have changed in a reload since the last upload
+                let broker = CONFIG_FILE.wait().broker.as_ref().unwrap_or(broker);
                 let upload = async {
                     let free_exits = client
                         .get_free_exits()
@@ -144,6 +152,12 @@ pub async fn broker_loop() -> anyhow::Result<()> {
                         )
                         .await?;
 
+                    // a draining exit turns new clients away, so the broker must stop handing it out, which it does once our last descriptor expires
+                    if is_draining() {
+                        tracing::debug!("draining, so no longer advertising ourselves");
+                        return anyhow::Ok(());
+                    }
+
                     let descriptor = ExitDescriptor {
                         c2e_listen: CONFIG_FILE
                             .wait()
@@ -170,6 +184,67 @@ pub async fn broker_loop() -> anyhow::Result<()> {
                         .insert_exit(to_upload)
                         .await?
                         .map_err(|e| anyhow::anyhow!(e.0))?;
+
+                    // the broker weighs exits by these, so that new users go where there is room
+                    let exit_load = ExitLoad {
+                        kbps: get_kbps(),
+                        kbps_capacity: CONFIG_FILE.wait().total_ratelimit as f32,
+                        sessions: get_session_count() as _,
+                        streams: task_count as _,
+                        stream_capacity: CONFIG_FILE.wait().task_limit as _,
+                        cpu: get_cpu(),
+                        timestamp: SystemTime::now()
+                            .duration_since(SystemTime::UNIX_EPOCH)
+                            .unwrap()
+                            .as_secs(),
+                    };
+                    client
+                        .set_stat(format!("{server_name}.sessions"), exit_load.sessions as _)
+                        .await?;
+                    let to_upload = Mac::new(
+                        Signed::new(exit_load, DOMAIN_EXIT_LOAD, &SIGNING_SECRET),
+                        blake3::hash(broker.auth_token.as_bytes()).as_bytes(),
+                    );
+                    client
+                        .report_exit_load(to_upload)
+                        .await?
+                        .map_err(|e| anyhow::anyhow!(e.0))?;
+
+                    // so that clients know whether to send IPv6 destinations our way, and the broker can hand out routes to our UDP listeners
+                    let features = ExitFeatures {
+                        ipv6: ipv6_egress_enabled(),
+                        obfs_udp: CONFIG_FILE.wait().c2e_obfs_udp_listen.map(|listen| {
+                            DirectUdpListener {
+                                addr: listen.tap_mut(|addr| addr.set_ip(my_ip)),
+                                cookie: CONFIG_FILE.wait().obfs_udp.cookie.clone(),
+                            }
+                        }),
+                        kcp: CONFIG_FILE
+                            .wait()
+                            .c2e_kcp_listen
+                            .map(|listen| DirectUdpListener {
+                                addr: listen.tap_mut(|addr| addr.set_ip(my_ip)),
+                                cookie: CONFIG_FILE.wait().kcp_cookie.clone(),
+                            }),
+                        timestamp: SystemTime::now()
+                            .duration_since(SystemTime::UNIX_EPOCH)
+                            .unwrap()
+                            .as_secs(),
+                    };
+                    let to_upload = Mac::new(
+                        Signed::new(features, DOMAIN_EXIT_FEATURES, &SIGNING_SECRET),
+                        blake3::hash(broker.auth_token.as_bytes()).as_bytes(),
+                    );
+                    let report_features = async {
+                        client
+                            .report_exit_features(to_upload)
+                            .await?
+                            .map_err(|e| anyhow::anyhow!(e.0))
+                    };
+                    if let Err(err) = report_features.await {
+                        // older brokers don't know about exit features
+                        tracing::debug!(err = debug(err), "could not report exit features");
+                    }
                     anyhow::Ok(())
                 };
                 if let Err(err) = upload.await {
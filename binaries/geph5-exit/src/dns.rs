@@ -7,13 +7,19 @@ use std::{
 };
 
 use anyhow::Context;
+use async_trait::async_trait;
 use bytes::Bytes;
 
+use futures_util::{io::BufReader, AsyncBufReadExt, AsyncWriteExt};
+use geph5_misc_rpc::dns::{DnsRecord, ExitDnsProtocol, ExitDnsService};
 use globset::{Glob, GlobSet};
 use moka::future::Cache;
+use nanorpc::{JrpcRequest, RpcService};
 use serde::{Deserialize, Serialize};
 use simple_dns::{rdata::RData, Name, Packet, PacketFlag, Question, CLASS, QCLASS, QTYPE, TYPE};
 
+use crate::ipv6::ipv6_egress_enabled;
+
 #[derive(Serialize, Deserialize, Clone, Debug, Copy, Default)]
 pub struct FilterOptions {
     #[serde(default)]
@@ -156,10 +162,12 @@ pub async fn dns_resolve(name: &str, filter: FilterOptions) -> anyhow::Result<Ve
             )
             .await;
 
-            // Merge the results
+            // Merge the results, IPv4 first since that is what most destinations serve best. IPv6 addresses are left out unless we can reach them, and are then mostly useful for IPv6-only destinations.
             let mut ips = vec![];
-            // ips.extend_from_slice(&res_aaaa?);
             ips.extend_from_slice(&res_a?);
+            if ipv6_egress_enabled() {
+                ips.extend(res_aaaa.unwrap_or_default());
+            }
 
             tracing::debug!(name, ?ips, "ips received!");
             anyhow::Ok(ips)
@@ -174,6 +182,62 @@ pub async fn dns_resolve(name: &str, filter: FilterOptions) -> anyhow::Result<Ve
     Ok(addrs)
 }
 
+/// Resolves a hostname into all its address records, IPv4 first, whether or not we can reach IPv6 destinations ourselves.
+pub async fn dns_resolve_records(
+    host: &str,
+    filter: FilterOptions,
+) -> anyhow::Result<Vec<DnsRecord>> {
+    filter.check_host(host).await?;
+    let (res_a, res_aaaa) = futures_util::future::join(
+        async {
+            let resp = raw_dns_respond(build_dns_query(host, false)?, filter).await?;
+            parse_dns_records(&resp)
+        },
+        async {
+            let resp = raw_dns_respond(build_dns_query(host, true)?, filter).await?;
+            parse_dns_records(&resp)
+        },
+    )
+    .await;
+    let mut records = res_a?;
+    records.extend(res_aaaa.unwrap_or_default());
+    if records.is_empty() {
+        anyhow::bail!("no records for {host}")
+    }
+    Ok(records)
+}
+
+struct ExitDnsImpl {
+    filter: FilterOptions,
+}
+
+#[async_trait]
+impl ExitDnsProtocol for ExitDnsImpl {
+    async fn resolve(&self, host: String) -> Result<Vec<DnsRecord>, String> {
+        dns_resolve_records(&host, self.filter)
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Serves DNS RPC calls on a `dnsrpc$` stream until the client closes it.
+pub async fn serve_dns_rpc(stream: picomux::Stream, filter: FilterOptions) -> anyhow::Result<()> {
+    let service = ExitDnsService(ExitDnsImpl { filter });
+    let (read, mut write) = stream.split();
+    let mut read = BufReader::new(read);
+    loop {
+        let mut line = String::new();
+        if read.read_line(&mut line).await? == 0 {
+            return Ok(());
+        }
+        let req: JrpcRequest = serde_json::from_str(&line)?;
+        let resp = service.respond_raw(req).await;
+        write
+            .write_all(format!("{}\n", serde_json::to_string(&resp)?).as_bytes())
+            .await?;
+    }
+}
+
 /// Build a simple DNS query packet for both A and AAAA:
 fn build_dns_query(host: &str, aaaa: bool) -> anyhow::Result<Bytes> {
     let mut packet = Packet::new_query(rand::random());
@@ -202,25 +266,31 @@ fn build_dns_query(host: &str, aaaa: bool) -> anyhow::Result<Bytes> {
 
 /// Parse a raw DNS response to gather all A/AAAA records.
 fn parse_dns_response(packet_data: &[u8], port: u16) -> anyhow::Result<Vec<SocketAddr>> {
+    Ok(parse_dns_records(packet_data)?
+        .into_iter()
+        .map(|record| SocketAddr::new(record.addr, port))
+        .collect())
+}
+
+/// Parse a raw DNS response to gather all A/AAAA records, along with their TTLs.
+fn parse_dns_records(packet_data: &[u8]) -> anyhow::Result<Vec<DnsRecord>> {
     let packet = Packet::parse(packet_data)?;
 
-    let mut addrs = Vec::new();
+    let mut records = Vec::new();
 
     for answer in packet.answers.iter() {
-        match &answer.rdata {
-            RData::A(ipv4) => addrs.push(SocketAddr::new(
-                IpAddr::V4(Ipv4Addr::from_bits(ipv4.address)),
-                port,
-            )),
-            RData::AAAA(ipv6) => addrs.push(SocketAddr::new(
-                IpAddr::V6(Ipv6Addr::from_bits(ipv6.address)),
-                port,
-            )),
-            _ => {}
-        }
+        let addr = match &answer.rdata {
+            RData::A(ipv4) => IpAddr::V4(Ipv4Addr::from_bits(ipv4.address)),
+            RData::AAAA(ipv6) => IpAddr::V6(Ipv6Addr::from_bits(ipv6.address)),
+            _ => continue,
+        };
+        records.push(DnsRecord {
+            addr,
+            ttl: answer.ttl,
+        });
     }
 
-    Ok(addrs)
+    Ok(records)
 }
 
 #[cfg(test)]
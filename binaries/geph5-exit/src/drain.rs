@@ -0,0 +1,40 @@
+//! Graceful shutdown for maintenance restarts. On SIGTERM or SIGINT, we tell every client session to go away, stop taking new connections, and stop advertising ourselves to the broker, then give the existing streams a grace period to finish before exiting.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        LazyLock,
+    },
+    time::Duration,
+};
+
+use crate::CONFIG_FILE;
+
+static DRAINING: AtomicBool = AtomicBool::new(false);
+static DRAIN_EVENT: LazyLock<async_event::Event> = LazyLock::new(async_event::Event::new);
+
+/// Whether we are shutting down.
+pub fn is_draining() -> bool {
+    DRAINING.load(Ordering::SeqCst)
+}
+
+/// Waits until we start shutting down.
+pub async fn wait_drain() {
+    DRAIN_EVENT.wait_until(|| is_draining().then_some(())).await
+}
+
+/// Starts draining when told to shut down, and exits once the grace period is over. A second signal exits right away.
+pub async fn drain_loop() -> anyhow::Result<()> {
+    ctrlc::set_handler(|| {
+        if DRAINING.swap(true, Ordering::SeqCst) {
+            std::process::exit(1);
+        }
+        DRAIN_EVENT.notify_all();
+    })?;
+    wait_drain().await;
+    let grace = Duration::from_secs(CONFIG_FILE.wait().drain_grace_secs);
+    tracing::warn!(grace = debug(grace), "draining before shutting down");
+    smol::Timer::after(grace).await;
+    tracing::warn!("drain finished, shutting down");
+    std::process::exit(0)
+}
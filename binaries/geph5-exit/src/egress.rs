@@ -0,0 +1,97 @@
+use std::net::IpAddr;
+
+use serde::Deserialize;
+
+/// A pool of addresses that outbound connections are sent from, so that a website blocklisting one of them only affects some of our users.
+#[derive(Deserialize, Clone, Debug)]
+pub struct EgressPool {
+    /// The addresses to send from, which must all be assigned to this machine.
+    pub addrs: Vec<IpAddr>,
+    /// Which connections keep to the same address.
+    #[serde(default)]
+    pub stickiness: Stickiness,
+}
+
+/// Which connections keep to the same source address.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Stickiness {
+    /// All connections of a session go out from the same address, so that websites see every user at a single address.
+    #[default]
+    Session,
+    /// All connections to a destination go out from the same address, whoever makes them, so that every website sees as few of our addresses as possible.
+    Destination,
+}
+
+impl EgressPool {
+    /// Picks the address to send from to the destination, for the session with the given random seed. Returns None if the pool has no address of the destination's family.
+    pub fn pick_source(&self, session_seed: u64, dest: IpAddr) -> Option<IpAddr> {
+        let candidates: Vec<IpAddr> = self
+            .addrs
+            .iter()
+            .copied()
+            .filter(|addr| addr.is_ipv4() == dest.is_ipv4())
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+        let key = match self.stickiness {
+            Stickiness::Session => session_seed,
+            Stickiness::Destination => {
+                let hash = match dest {
+                    IpAddr::V4(ip) => blake3::hash(&ip.octets()),
+                    IpAddr::V6(ip) => blake3::hash(&ip.octets()),
+                };
+                u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap())
+            }
+        };
+        Some(candidates[(key % candidates.len() as u64) as usize])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(stickiness: Stickiness) -> EgressPool {
+        EgressPool {
+            addrs: vec![
+                "192.0.2.1".parse().unwrap(),
+                "192.0.2.2".parse().unwrap(),
+                "2001:db8::1".parse().unwrap(),
+            ],
+            stickiness,
+        }
+    }
+
+    #[test]
+    fn sources_match_the_destination_family() {
+        let pool = pool(Stickiness::Session);
+        for seed in 0..10 {
+            assert!(pool
+                .pick_source(seed, "198.51.100.1".parse().unwrap())
+                .unwrap()
+                .is_ipv4());
+            assert_eq!(
+                pool.pick_source(seed, "2001:db8::99".parse().unwrap()),
+                Some("2001:db8::1".parse().unwrap())
+            );
+        }
+        let v4_only = EgressPool {
+            addrs: vec!["192.0.2.1".parse().unwrap()],
+            stickiness: Stickiness::Session,
+        };
+        assert_eq!(
+            v4_only.pick_source(0, "2001:db8::99".parse().unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    fn destination_stickiness_ignores_the_session() {
+        let pool = pool(Stickiness::Destination);
+        let dest: IpAddr = "198.51.100.1".parse().unwrap();
+        let first = pool.pick_source(0, dest);
+        assert!((1..100).all(|seed| pool.pick_source(seed, dest) == first));
+    }
+}
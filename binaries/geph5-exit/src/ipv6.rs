@@ -1,6 +1,6 @@
 use std::{
     io::ErrorKind,
-    net::{Ipv6Addr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     time::Duration,
 };
 
@@ -8,55 +8,97 @@ use anyhow::Context;
 use futures_concurrency::future::RaceOk;
 use ipnet::Ipv6Net;
 use rand::Rng;
-use smol::{net::TcpStream, process::Command, Async};
+use smol::{
+    net::{TcpStream, UdpSocket},
+    process::Command,
+    Async,
+};
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 
 use crate::CONFIG_FILE;
 
-/// Something that can be used for happy-eyeballs dialing, with its own IPv6 address.
+/// Whether we can reach IPv6 destinations, which takes a subnet of IPv6 addresses, or IPv6 addresses in the egress pool, to send from. Without a config, as in tests, we can't.
+pub fn ipv6_egress_enabled() -> bool {
+    CONFIG_FILE.get().is_some_and(|cfg| {
+        cfg.ipv6_subnet != Ipv6Net::default()
+            || cfg
+                .egress_pool
+                .as_ref()
+                .is_some_and(|pool| pool.addrs.iter().any(|addr| addr.is_ipv6()))
+    })
+}
+
+/// Something that can be used for happy-eyeballs dialing, with its own IPv6 address, and its own seed for picking addresses from the egress pool.
 #[derive(Clone, Debug)]
 pub struct EyeballDialer {
     inner: Option<Ipv6Addr>,
+    seed: u64,
 }
 
 impl EyeballDialer {
     /// Create a new eyeball dialer.
     pub fn new() -> Self {
         let subnet = CONFIG_FILE.wait().ipv6_subnet;
+        let seed = rand::random();
         if subnet == Ipv6Net::default() {
-            Self { inner: None }
+            Self { inner: None, seed }
         } else {
             Self {
                 inner: Some(random_ipv6_in_net(subnet)),
+                seed,
             }
         }
     }
 
+    /// The address to send from to the destination, or None for whatever the system picks.
+    fn source_for(&self, dest: IpAddr) -> Option<IpAddr> {
+        if let (IpAddr::V6(_), Some(my_addr)) = (dest, self.inner) {
+            return Some(my_addr.into());
+        }
+        CONFIG_FILE
+            .wait()
+            .egress_pool
+            .as_ref()?
+            .pick_source(self.seed, dest)
+    }
+
     /// Connect to a given remote.
     pub async fn connect(&self, addrs: Vec<SocketAddr>) -> anyhow::Result<TcpStream> {
-        let my_addr = self.inner;
-        if my_addr.is_none() {
+        if self.inner.is_none() && CONFIG_FILE.wait().egress_pool.is_none() {
             Ok(TcpStream::connect(&addrs[..]).await?)
         } else {
             let streams: Vec<_> = addrs
                 .into_iter()
                 .enumerate()
-                .map(|(idx, addr)| async move {
-                    if idx > 0 {
-                        smol::Timer::after(Duration::from_millis(500 * idx as u64)).await;
-                        tracing::debug!(idx, addr = display(addr), "eyeballed to non-ideal");
-                    }
-                    if addr.is_ipv6() {
-                        if let Some(my_addr) = my_addr {
-                            return connect_from(my_addr, addr).await;
+                .map(|(idx, addr)| {
+                    let source = self.source_for(addr.ip());
+                    async move {
+                        if idx > 0 {
+                            smol::Timer::after(Duration::from_millis(500 * idx as u64)).await;
+                            tracing::debug!(idx, addr = display(addr), "eyeballed to non-ideal");
+                        }
+                        match source {
+                            Some(source) => connect_from(source, addr).await,
+                            None => Ok(TcpStream::connect(addr).await?),
                         }
                     }
-                    Ok(TcpStream::connect(addr).await?)
                 })
                 .collect();
             streams.race_ok().await.map_err(|mut e| e.remove(0))
         }
     }
+
+    /// Binds a UDP socket for sending to the given destination, from the same address that TCP connections to it would come from.
+    pub async fn bind_udp(&self, dest: SocketAddr) -> anyhow::Result<UdpSocket> {
+        let source = match self.source_for(dest.ip()) {
+            Some(source) => source,
+            None if dest.is_ipv6() => anyhow::bail!("no IPv6 egress configured"),
+            None => Ipv4Addr::UNSPECIFIED.into(),
+        };
+        UdpSocket::bind(SocketAddr::new(source, 0))
+            .await
+            .context("UDP bind failed")
+    }
 }
 
 /// Given an `Ipv6Net`, generate a random IPv6 address within that subnet.
@@ -89,17 +131,21 @@ fn random_ipv6_in_net(net: Ipv6Net) -> Ipv6Addr {
     Ipv6Addr::from(addr_octets)
 }
 
-/// Connect to a remote IPv6 address using the given IPv6 address.
-async fn connect_from(from: Ipv6Addr, remote: SocketAddr) -> anyhow::Result<TcpStream> {
+/// Connect to a remote address from the given local address, which must be of the same family.
+async fn connect_from(from: IpAddr, remote: SocketAddr) -> anyhow::Result<TcpStream> {
     tracing::debug!(
         from = display(from),
         remote = display(remote),
-        "connecting from an ephemeral IPv6"
+        "connecting from a chosen address"
     );
-    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    let socket = Socket::new(
+        Domain::for_address(remote),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
     socket.set_reuse_address(true)?;
     socket.set_reuse_port(true)?;
-    let local_addr = SocketAddr::new(std::net::IpAddr::V6(from), 0);
+    let local_addr = SocketAddr::new(from, 0);
     socket
         .bind(&SockAddr::from(local_addr))
         .context("cannot bind")?;
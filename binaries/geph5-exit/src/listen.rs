@@ -1,19 +1,33 @@
 use anyhow::Context;
 use ed25519_dalek::Signer;
-use futures_util::{AsyncReadExt, TryFutureExt};
+use futures_util::{io::Cursor, AsyncRead, AsyncReadExt, AsyncWrite, TryFutureExt};
 use geph5_broker_protocol::AccountLevel;
 use geph5_misc_rpc::{
-    bridge::B2eMetadata,
-    exit::{ClientCryptHello, ClientExitCryptPipe, ClientHello, ExitHello, ExitHelloInner},
+    b2e_auth::{b2e_auth_exit, B2E_AUTH_MAGIC},
+    bridge::{B2eMetadata, B2eStreamMetadata},
+    exit::{
+        ClientCryptHello, ClientExitCryptPipe, ClientHello, ConnectCredential, ExitHello,
+        ExitHelloInner, SessionLimits, StreamRejection,
+    },
     read_prepend_length, write_prepend_length,
 };
-use mizaru2::{ClientToken, UnblindedSignature};
 use moka::future::Cache;
 use picomux::{LivenessConfig, PicoMux};
 
 use sillad::{listener::Listener, tcp::TcpListener, EitherPipe, Pipe};
+use sillad_kcp::listener::KcpListener;
+use sillad_quic::listener::QuicListener;
+use sillad_shaping::ShapedPipe;
 use smol::future::FutureExt as _;
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use smol_timeout2::TimeoutExt;
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 use stdcode::StdcodeSerializeExt;
 use tachyonix::Sender;
 
@@ -22,27 +36,85 @@ mod b2e_process;
 mod tls;
 
 use crate::{
+    abuse::abuse_count_loop,
     asn::ip_to_asn_country,
     auth::verify_user,
     broker::{broker_loop, ACCEPT_FREE},
+    drain::{drain_loop, is_draining, wait_drain},
     ipv6::{configure_ipv6_routing, EyeballDialer},
-    proxy::proxy_stream,
-    ratelimit::{get_ratelimiter, RateLimiter},
+    proxy::{proxy_stream, send_rejection},
+    ratelimit::{get_ratelimiter, session_limits, RateLimiter},
     tasklimit::new_task_until_death,
+    throttle::{throttle, ThrottleKind},
+    ticket::{issue_ticket, redeem_ticket},
+    udpnat::UdpNatPool,
+    userlimit::UserSession,
     CONFIG_FILE, SIGNING_SECRET,
 };
 
 pub async fn listen_main() -> anyhow::Result<()> {
     configure_ipv6_routing().await?;
     let c2e = c2e_loop();
+    let c2e_quic = c2e_quic_loop();
+    let c2e_obfs_udp = c2e_obfs_udp_loop();
+    let c2e_kcp = c2e_kcp_loop();
     let b2e = b2e_loop();
     let broker = broker_loop();
-    c2e.race(broker).race(b2e).await
+    c2e.race(c2e_quic)
+        .race(c2e_obfs_udp)
+        .race(c2e_kcp)
+        .race(broker)
+        .race(b2e)
+        .race(drain_loop())
+        .race(abuse_count_loop())
+        .await
 }
 
 async fn c2e_loop() -> anyhow::Result<()> {
     let listener = TcpListener::bind(CONFIG_FILE.wait().c2e_listen).await?;
-    let mut listener = sillad_conntest::ConnTestListener::new(listener);
+    c2e_accept_loop(sillad_conntest::ConnTestListener::new(listener)).await
+}
+
+async fn c2e_quic_loop() -> anyhow::Result<()> {
+    let Some(listen) = CONFIG_FILE.wait().c2e_quic_listen else {
+        return smol::future::pending().await;
+    };
+    let listener = QuicListener::bind(listen, CONFIG_FILE.wait().quic_congestion).await?;
+    c2e_accept_loop(sillad_conntest::ConnTestListener::new(listener)).await
+}
+
+async fn c2e_obfs_udp_loop() -> anyhow::Result<()> {
+    let Some(listen) = CONFIG_FILE.wait().c2e_obfs_udp_listen else {
+        return smol::future::pending().await;
+    };
+    let listener = QuicListener::bind_obfs(
+        listen,
+        CONFIG_FILE.wait().quic_congestion,
+        CONFIG_FILE.wait().obfs_udp.clone(),
+    )
+    .await?;
+    c2e_accept_loop(sillad_conntest::ConnTestListener::new(listener)).await
+}
+
+async fn c2e_kcp_loop() -> anyhow::Result<()> {
+    let Some(listen) = CONFIG_FILE.wait().c2e_kcp_listen else {
+        return smol::future::pending().await;
+    };
+    // without a cookie, the obfuscation would be no obfuscation at all
+    anyhow::ensure!(
+        !CONFIG_FILE.wait().kcp_cookie.is_empty(),
+        "c2e_kcp_listen needs a kcp_cookie"
+    );
+    let listener = KcpListener::bind(
+        listen,
+        CONFIG_FILE.wait().kcp,
+        &CONFIG_FILE.wait().kcp_cookie,
+    )
+    .await?;
+    c2e_accept_loop(sillad_conntest::ConnTestListener::new(listener)).await
+}
+
+async fn c2e_accept_loop(mut listener: impl Listener) -> anyhow::Result<()> {
     loop {
         let c2e_raw = match listener.accept().await {
             Ok(conn) => conn,
@@ -65,6 +137,24 @@ async fn c2e_loop() -> anyhow::Result<()> {
             }
             anyhow::Ok(())
         };
+        if is_draining() {
+            tracing::debug!("draining, so rejecting a direct connection");
+            continue;
+        }
+        if let Some(remote_addr) = c2e_raw
+            .remote_addr()
+            .and_then(|addr| addr.parse::<SocketAddr>().ok())
+        {
+            let key = remote_addr.ip().to_string();
+            if let Err(err) = throttle(ThrottleKind::DirectHandshake, key.as_bytes()).await {
+                tracing::debug!(
+                    err = debug(err),
+                    remote_addr = display(remote_addr),
+                    "throttled a direct connection"
+                );
+                continue;
+            }
+        }
         if let Err(err) = test_addr.await {
             tracing::warn!(err = debug(err), "rejected a direct connection");
             continue;
@@ -74,10 +164,32 @@ async fn c2e_loop() -> anyhow::Result<()> {
 }
 
 async fn b2e_loop() -> anyhow::Result<()> {
-    let mut listener = TcpListener::bind(CONFIG_FILE.wait().b2e_listen).await?;
     let b2e_table: Cache<B2eMetadata, Sender<picomux::Stream>> = Cache::builder()
         .time_to_idle(Duration::from_secs(1200))
         .build();
+    let tcp = b2e_accept_loop(
+        TcpListener::bind(CONFIG_FILE.wait().b2e_listen).await?,
+        b2e_table.clone(),
+    );
+    // bridges that reach us over QUIC use the UDP port with the same number
+    let quic = async {
+        if !CONFIG_FILE.wait().b2e_quic {
+            return smol::future::pending().await;
+        }
+        let listener = QuicListener::bind(
+            CONFIG_FILE.wait().b2e_listen,
+            CONFIG_FILE.wait().quic_congestion,
+        )
+        .await?;
+        b2e_accept_loop(listener, b2e_table).await
+    };
+    tcp.race(quic).await
+}
+
+async fn b2e_accept_loop(
+    mut listener: impl Listener,
+    b2e_table: Cache<B2eMetadata, Sender<picomux::Stream>>,
+) -> anyhow::Result<()> {
     loop {
         let b2e_raw = match listener.accept().await {
             Ok(conn) => conn,
@@ -90,17 +202,56 @@ async fn b2e_loop() -> anyhow::Result<()> {
             .remote_addr()
             .map(|s| s.to_string())
             .unwrap_or_default();
-        let (read, write) = b2e_raw.split();
-        let mut b2e_mux = PicoMux::new(read, write);
-        b2e_mux.set_liveness(LivenessConfig {
-            ping_interval: Duration::from_secs(3600),
-            timeout: Duration::from_secs(3600),
-        });
+        // bridges connect from many ports, so they are throttled by address alone
+        let bridge_ip = bridge_addr
+            .parse::<SocketAddr>()
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_else(|_| bridge_addr.clone());
         let b2e_table = b2e_table.clone();
         smolscale::spawn::<anyhow::Result<()>>(async move {
+            let (bridge_id, read, write) = b2e_authenticate(b2e_raw).await.inspect_err(|err| {
+                tracing::debug!(
+                    err = debug(err),
+                    bridge_addr = display(&bridge_addr),
+                    "rejected a connection from a bridge"
+                )
+            })?;
+            let mut b2e_mux = PicoMux::new(read, write);
+            b2e_mux.set_liveness(LivenessConfig {
+                ping_interval: Duration::from_secs(3600),
+                timeout: Duration::from_secs(3600),
+            });
             loop {
                 let lala = b2e_mux.accept().await?;
-                let b2e_metadata: B2eMetadata = stdcode::deserialize(lala.metadata())?;
+                // authenticated bridges say which of their clients each stream is for, so that each client is also throttled like one connecting directly
+                let (b2e_metadata, client_key) = match &bridge_id {
+                    Some(bridge_id) => {
+                        let inner: B2eStreamMetadata = stdcode::deserialize(lala.metadata())?;
+                        (
+                            inner.metadata,
+                            Some(format!("{bridge_id}/{}", inner.client_id)),
+                        )
+                    }
+                    None => (stdcode::deserialize(lala.metadata())?, None),
+                };
+                let throttled = match (
+                    throttle(ThrottleKind::BridgeHandshake, bridge_ip.as_bytes()).await,
+                    &client_key,
+                ) {
+                    (Ok(()), Some(client_key)) => {
+                        throttle(ThrottleKind::BridgeClientHandshake, client_key.as_bytes()).await
+                    }
+                    (res, _) => res,
+                };
+                if let Err(err) = throttled {
+                    tracing::debug!(
+                        err = debug(err),
+                        bridge_addr = display(&bridge_addr),
+                        client_key = debug(&client_key),
+                        "throttled a connection through a bridge"
+                    );
+                    continue;
+                }
                 tracing::trace!(
                     bridge_addr = display(&bridge_addr),
                     "accepting b2e with metadata"
@@ -125,52 +276,160 @@ async fn b2e_loop() -> anyhow::Result<()> {
     }
 }
 
+/// Runs the exit's side of the b2e handshake if the bridge opens with one, returning the bridge's ID along with the two halves of the connection, with whatever was read off it that wasn't part of a handshake put back. Bridges that don't authenticate are only let through if the config doesn't require them to.
+async fn b2e_authenticate(
+    mut b2e_raw: impl Pipe,
+) -> anyhow::Result<(
+    Option<String>,
+    impl AsyncRead + Send + Unpin + 'static,
+    impl AsyncWrite + Send + Unpin + 'static,
+)> {
+    let mut first = [0u8; 1];
+    b2e_raw.read_exact(&mut first).await?;
+    let (bridge_id, unread) = if first[0] == B2E_AUTH_MAGIC[0] {
+        let mut rest = [0u8; B2E_AUTH_MAGIC.len() - 1];
+        b2e_raw.read_exact(&mut rest).await?;
+        anyhow::ensure!(rest == B2E_AUTH_MAGIC[1..], "garbled b2e handshake");
+        let broker = CONFIG_FILE
+            .wait()
+            .broker
+            .as_ref()
+            .context("cannot authenticate bridges without a broker token")?;
+        let bridge_id = b2e_auth_exit(&mut b2e_raw, &broker.auth_token)
+            .timeout(Duration::from_secs(10))
+            .await
+            .context("b2e handshake timed out")??;
+        (Some(bridge_id), vec![])
+    } else {
+        anyhow::ensure!(
+            !CONFIG_FILE.wait().b2e_require_auth,
+            "bridge did not authenticate"
+        );
+        (None, first.to_vec())
+    };
+    let (read, write) = b2e_raw.split();
+    Ok((bridge_id, Cursor::new(unread).chain(read), write))
+}
+
+static SESSION_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Obtains the number of client sessions currently open.
+pub fn get_session_count() -> usize {
+    SESSION_COUNT.load(Ordering::Relaxed)
+}
+
 async fn handle_client(mut client: impl Pipe) -> anyhow::Result<()> {
+    SESSION_COUNT.fetch_add(1, Ordering::Relaxed);
+    scopeguard::defer!({
+        SESSION_COUNT.fetch_sub(1, Ordering::Relaxed);
+    });
+
     // execute the authentication
     let client_hello: ClientHello = stdcode::deserialize(&read_prepend_length(&mut client).await?)?;
 
+    let (crypt_hello, resumption) = client_hello.crypt_hello.without_resumption();
+    let (crypt_hello, asks_limits) = crypt_hello.without_limits_request();
+    let (crypt_hello, shaping) = crypt_hello.unshaped();
+    if let Some(params) = shaping {
+        anyhow::ensure!(
+            params.is_sane(),
+            "unreasonable shaping parameters {params:?}"
+        );
+    }
+
     let keys: Option<([u8; 32], [u8; 32])>;
-    let exit_hello_inner: ExitHelloInner = match client_hello.crypt_hello {
+    let exit_hello_inner: ExitHelloInner = match crypt_hello {
         ClientCryptHello::SharedSecretChallenge(key) => {
             let real_ss = client.shared_secret().context("no shared secret")?;
-            let mac = blake3::keyed_hash(&key, real_ss);
+            let mac = blake3::keyed_hash(key, real_ss);
             keys = None;
             ExitHelloInner::SharedSecretResponse(mac)
         }
         ClientCryptHello::X25519(their_epk) => {
             let my_esk = EphemeralSecret::random_from_rng(rand::thread_rng());
             let my_epk = PublicKey::from(&my_esk);
-            let shared_secret = my_esk.diffie_hellman(&their_epk);
+            let shared_secret = my_esk.diffie_hellman(their_epk);
             let read_key = blake3::derive_key("c2e", shared_secret.as_bytes());
             let write_key = blake3::derive_key("e2c", shared_secret.as_bytes());
             keys = Some((read_key, write_key));
             ExitHelloInner::X25519(my_epk)
         }
+        ClientCryptHello::Shaped(..) => anyhow::bail!("shaping requested twice"),
+        ClientCryptHello::AskLimits(..) => anyhow::bail!("limits requested in the wrong place"),
+        ClientCryptHello::Resume(..) => anyhow::bail!("resumption requested in the wrong place"),
     };
 
     let mut is_free = false;
+    let mut user_session = None;
+    let mut throttle_key = None;
+    let mut ticket = None;
+    let mut limits = SessionLimits {
+        kbps: None,
+        burst_kb: 0,
+    };
     let ratelimit = if CONFIG_FILE.wait().broker.is_some() {
-        let (level, token, sig): (AccountLevel, ClientToken, UnblindedSignature) =
-            stdcode::deserialize(&client_hello.credentials)
-                .context("cannot deserialize credentials")?;
+        let resumed = resumption.flatten().and_then(|ticket| {
+            redeem_ticket(ticket)
+                .inspect_err(|e| tracing::debug!(err = debug(e), "not resuming, bad ticket"))
+                .ok()
+        });
+        let (level, token, credential, expiry) = match resumed {
+            Some(state) => (state.level, state.token, None, Some(state.expiry)),
+            None => {
+                let credential: ConnectCredential = stdcode::deserialize(&client_hello.credentials)
+                    .context("cannot deserialize credentials")?;
+                (credential.level, credential.token, Some(credential), None)
+            }
+        };
         if level == AccountLevel::Free && !ACCEPT_FREE.load(std::sync::atomic::Ordering::Relaxed) {
             anyhow::bail!("free users rejected here")
         }
-        verify_user(level, token, sig).await.inspect_err(|e| {
-            tracing::warn!(err = debug(e), "**** BAD BAD bad token received ***")
-        })?;
+        // throttling comes before the expensive check of the credential, so that floods of handshakes stay cheap
+        let key = (level, token).stdcode();
+        throttle(ThrottleKind::TokenHandshake, &key).await?;
+        throttle_key = Some(key);
+        // a good ticket shows that the credential was checked when the ticket was issued
+        if let Some(credential) = credential {
+            verify_user(credential).await.inspect_err(|e| {
+                tracing::warn!(err = debug(e), "**** BAD BAD bad token received ***")
+            })?;
+        }
+        if resumption.is_some() {
+            ticket = issue_ticket(level, token, expiry);
+        }
         is_free = level == AccountLevel::Free;
+        user_session = Some(UserSession::start(level, token).inspect_err(|e| {
+            tracing::debug!(
+                err = debug(e),
+                "refusing a session over the account's limit"
+            )
+        })?);
+        limits = session_limits(level);
         get_ratelimiter(level, token).await
     } else {
         RateLimiter::unlimited()
     };
 
+    let exit_hello_inner = if asks_limits {
+        ExitHelloInner::Limited(limits, Box::new(exit_hello_inner))
+    } else {
+        exit_hello_inner
+    };
+    let exit_hello_inner = match ticket {
+        Some(ticket) => ExitHelloInner::Ticket(ticket, Box::new(exit_hello_inner)),
+        None => exit_hello_inner,
+    };
     let exit_hello = ExitHello {
         inner: exit_hello_inner.clone(),
         signature: SIGNING_SECRET.sign(&(client_hello, exit_hello_inner).stdcode()),
     };
     write_prepend_length(&exit_hello.stdcode(), &mut client).await?;
 
+    // shaping goes beneath our own encryption, so that the transport only ever carries fixed-size frames, though it may still split or coalesce them into packets as it pleases
+    let client: Box<dyn Pipe> = match shaping {
+        Some(params) => Box::new(ShapedPipe::new(client, params)),
+        None => Box::new(client),
+    };
     let client = if let Some((read_key, write_key)) = keys {
         EitherPipe::Left(ClientExitCryptPipe::new(client, read_key, write_key))
     } else {
@@ -178,30 +437,62 @@ async fn handle_client(mut client: impl Pipe) -> anyhow::Result<()> {
     };
 
     let (client_read, client_write) = client.split();
-    let mux = PicoMux::new(client_read, client_write);
+    let mux = PicoMux::with_windows(client_read, client_write, CONFIG_FILE.wait().mux_windows);
 
     let mut sess_metadata = Arc::new(serde_json::Value::Null);
     let dialer = EyeballDialer::new();
-    loop {
-        let stream = mux.accept().await?;
-        let metadata = String::from_utf8_lossy(stream.metadata()).to_string();
-        if let Ok(new_sess_metadata) = serde_json::from_str::<serde_json::Value>(&metadata) {
-            sess_metadata = Arc::new(new_sess_metadata);
-            continue;
-        }
-        let sess_metadata = sess_metadata.clone();
-        let dialer = dialer.clone();
-        smolscale::spawn(
-            proxy_stream(
+    let udp_pool = UdpNatPool::for_session();
+    let go_away = async {
+        wait_drain().await;
+        tracing::debug!("draining, so telling the client to go away");
+        mux.go_away();
+        smol::future::pending().await
+    };
+    let serve = async {
+        loop {
+            let stream = mux.accept().await?;
+            let metadata = String::from_utf8_lossy(stream.metadata()).to_string();
+            if let Ok(new_sess_metadata) = serde_json::from_str::<serde_json::Value>(&metadata) {
+                sess_metadata = Arc::new(new_sess_metadata);
+                continue;
+            }
+            if let Some(key) = &throttle_key {
+                if let Err(err) = throttle(ThrottleKind::TokenStream, key).await {
+                    tracing::debug!(err = debug(err), "throttled a stream");
+                    send_rejection(&stream, &StreamRejection::Throttled);
+                    continue;
+                }
+            }
+            let user_stream = match &user_session {
+                Some(session) => match session.start_stream() {
+                    Some(user_stream) => Some(user_stream),
+                    None => {
+                        tracing::debug!("refusing a stream over the account's limit");
+                        send_rejection(&stream, &StreamRejection::TooManyStreams);
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            let sess_metadata = sess_metadata.clone();
+            let dialer = dialer.clone();
+            let proxy = proxy_stream(
                 dialer,
                 sess_metadata.clone(),
                 ratelimit.clone(),
+                udp_pool.clone(),
                 stream,
                 is_free,
             )
             .race(new_task_until_death(Duration::from_secs(1)))
-            .map_err(|e| tracing::trace!(err = debug(e), "stream died with")),
-        )
-        .detach();
-    }
+            .map_err(|e| tracing::trace!(err = debug(e), "stream died with"));
+            smolscale::spawn(async move {
+                // the stream counts towards the account's limit until it is done
+                let _user_stream = user_stream;
+                proxy.await
+            })
+            .detach();
+        }
+    };
+    serve.race(go_away).await
 }
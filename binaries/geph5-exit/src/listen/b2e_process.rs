@@ -5,9 +5,13 @@ use futures_util::TryFutureExt;
 use geph5_misc_rpc::bridge::{B2eMetadata, ObfsProtocol};
 use sillad::listener::{DynListener, ListenerExt};
 use sillad_conntest::ConnTestListener;
+use sillad_meek::listener::MeekListener;
 use sillad_sosistab3::{listener::SosistabListener, Cookie};
+use sillad_websocket::WsListener;
 use tachyonix::Receiver;
 
+use crate::drain::is_draining;
+
 use super::{handle_client, tls::dummy_tls_config};
 
 pub async fn b2e_process(
@@ -38,12 +42,24 @@ fn create_listener(protocol: ObfsProtocol, bottom: ReceiverListener) -> DynListe
             let inner = create_listener(*obfs_protocol, bottom);
             SosistabListener::new(inner, Cookie::new(&cookie)).dynamic()
         }
+        ObfsProtocol::Websocket(_host, path, obfs_protocol) => {
+            let inner = create_listener(*obfs_protocol, bottom);
+            WsListener::new(inner, path).dynamic()
+        }
+        ObfsProtocol::Meek(_host, path, obfs_protocol) => {
+            let inner = create_listener(*obfs_protocol, bottom);
+            MeekListener::new(inner, path).dynamic()
+        }
     }
 }
 
 async fn b2e_inner(mut listener: impl sillad::listener::Listener) -> anyhow::Result<()> {
     loop {
         let client = listener.accept().await?;
+        if is_draining() {
+            tracing::debug!("draining, so rejecting a client through b2e");
+            continue;
+        }
         smolscale::spawn(
             handle_client(client)
                 .map_err(|e| tracing::trace!(err = debug(e), "client stopped through b2e")),
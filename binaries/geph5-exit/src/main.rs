@@ -10,7 +10,7 @@ use ipnet::Ipv6Net;
 
 use isocountry::CountryCode;
 use listen::listen_main;
-use once_cell::sync::{Lazy, OnceCell};
+use once_cell::sync::Lazy;
 use rand::Rng;
 use serde::Deserialize;
 use serde_with::{serde_as, DisplayFromStr};
@@ -20,22 +20,35 @@ use std::{
 };
 use tracing_subscriber::{layer::SubscriberExt as _, util::SubscriberInitExt as _};
 
+mod abuse;
 mod allow;
 mod auth;
 mod broker;
+mod drain;
+mod egress;
 mod listen;
 mod proxy;
 mod ratelimit;
+mod reload;
+mod reverse;
 mod schedlag;
+mod speedtest;
+mod throttle;
+mod ticket;
+mod udpnat;
+mod userlimit;
 
 #[cfg(target_env = "musl")]
 #[global_allocator]
 static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 
-use crate::ratelimit::update_load_loop;
+use crate::{
+    ratelimit::update_load_loop,
+    reload::{reload_loop, ConfigCell},
+};
 
-/// The global config file.
-static CONFIG_FILE: OnceCell<ConfigFile> = OnceCell::new();
+/// The global config file, which is reloaded whenever it changes.
+static CONFIG_FILE: ConfigCell<ConfigFile> = ConfigCell::new();
 
 /// This struct defines the structure of our configuration file
 #[serde_as]
@@ -46,6 +59,12 @@ struct ConfigFile {
 
     c2e_listen: SocketAddr,
     b2e_listen: SocketAddr,
+    /// Whether bridges must authenticate their connections to us, which they do by default. Turning it off lets in bridges that predate authentication, and anyone else who can reach the port. Authenticating needs the broker's token, so with no broker configured, no bridge gets in unless this is off.
+    #[serde(default = "default_b2e_require_auth")]
+    b2e_require_auth: bool,
+    /// Whether we also take connections from bridges over QUIC, on the UDP port with the same number as `b2e_listen`. Bridges only use it if they are set up to.
+    #[serde(default)]
+    b2e_quic: bool,
     ip_addr: Option<IpAddr>,
 
     country: CountryCode,
@@ -60,6 +79,12 @@ struct ConfigFile {
     #[serde(default = "default_plus_ratelimit")]
     plus_ratelimit: u32,
 
+    /// How far, in kilobytes, free and Plus sessions may go over their rate limits in a burst.
+    #[serde(default = "default_burst")]
+    free_burst: u32,
+    #[serde(default = "default_burst")]
+    plus_burst: u32,
+
     #[serde(default = "default_total_ratelimit")]
     total_ratelimit: u32,
 
@@ -72,6 +97,78 @@ struct ConfigFile {
     #[serde_as(as = "DisplayFromStr")]
     #[serde(default)]
     ipv6_subnet: Ipv6Net,
+
+    /// Inclusive range of public ports that paying users may ask us to listen on for reverse port forwarding, with a grant from the broker. Disabled if absent.
+    #[serde(default)]
+    reverse_port_range: Option<(u16, u16)>,
+
+    /// Which ports, how many sockets, and how long mappings last for full-cone UDP relaying.
+    #[serde(default)]
+    udp_nat: udpnat::UdpNatConfig,
+
+    /// Which ports, address ranges, and countries we refuse to proxy to. By default, SMTP and addresses that aren't globally routable.
+    #[serde(default)]
+    egress_policy: allow::EgressPolicy,
+
+    /// How many sessions and open streams a single account may have at once, so that one abusive client can't exhaust the exit.
+    #[serde(default)]
+    user_limits: userlimit::UserLimitConfig,
+
+    /// How fast handshakes and streams may be started per address, bridge, and account, and how long whoever goes faster is banned for.
+    #[serde(default)]
+    throttle: throttle::ThrottleConfig,
+
+    /// Destination ports whose connections are counted per destination and hour, without any client identity, to help answer abuse complaints. Off unless ports are given.
+    #[serde(default)]
+    abuse_counts: abuse::AbuseCountConfig,
+
+    /// Addresses of this machine that outbound connections are spread over, and whether they keep to one address per session or per destination. Without a pool, the system picks.
+    #[serde(default)]
+    egress_pool: Option<egress::EgressPool>,
+
+    /// Where we additionally listen for direct client connections over QUIC, if anywhere. Clients reach it through a `conn_test` route over a `quic` one, just like the TCP listener.
+    #[serde(default)]
+    c2e_quic_listen: Option<SocketAddr>,
+
+    /// The congestion controller of the QUIC listeners.
+    #[serde(default)]
+    quic_congestion: sillad_quic::Congestion,
+
+    /// Where we additionally listen for direct client connections over obfuscated UDP, if anywhere. The broker hands out a `conn_test` route over an `obfs_udp` one with the same cookie, which clients race against the bridges.
+    #[serde(default)]
+    c2e_obfs_udp_listen: Option<SocketAddr>,
+
+    /// The cookie of the obfuscated UDP listener, and the FEC we add to what it sends.
+    #[serde(default)]
+    obfs_udp: sillad_quic::obfs::ObfsConfig,
+
+    /// Where we additionally listen for direct client connections over KCP, if anywhere. The broker hands out a `conn_test` route over a `kcp` one with the same cookie, which clients race against the bridges.
+    #[serde(default)]
+    c2e_kcp_listen: Option<SocketAddr>,
+
+    /// The cookie that the KCP listener's packets are obfuscated with, which must be set for it to start.
+    #[serde(default)]
+    kcp_cookie: String,
+
+    /// How the KCP listener is tuned, which only affects what it sends.
+    #[serde(default)]
+    kcp: sillad_kcp::KcpParams,
+
+    /// How long resumption tickets let clients reconnect without their credentials being checked again, in seconds. Zero turns tickets off.
+    #[serde(default = "default_ticket_lifetime_secs")]
+    ticket_lifetime_secs: u64,
+
+    /// Flow-control windows and the stream limit of the multiplexed sessions with clients.
+    #[serde(default = "default_mux_windows")]
+    mux_windows: picomux::WindowConfig,
+
+    /// How long, on SIGTERM, we let existing streams finish before exiting.
+    #[serde(default = "default_drain_grace_secs")]
+    drain_grace_secs: u64,
+}
+
+fn default_b2e_require_auth() -> bool {
+    true
 }
 
 fn default_free_ratelimit() -> u32 {
@@ -82,6 +179,10 @@ fn default_plus_ratelimit() -> u32 {
     30000
 }
 
+fn default_burst() -> u32 {
+    100
+}
+
 fn default_total_ratelimit() -> u32 {
     125000
 }
@@ -90,6 +191,21 @@ fn default_task_limit() -> usize {
     1_000_000
 }
 
+fn default_drain_grace_secs() -> u64 {
+    120
+}
+
+fn default_ticket_lifetime_secs() -> u64 {
+    3600
+}
+
+fn default_mux_windows() -> picomux::WindowConfig {
+    picomux::WindowConfig {
+        max_streams: Some(2000),
+        ..Default::default()
+    }
+}
+
 fn default_free_port_whitelist() -> Vec<u16> {
     vec![80, 443, 8080, 8443, 22, 53]
 }
@@ -102,6 +218,30 @@ fn default_country_blacklist() -> Vec<String> {
 struct BrokerConfig {
     url: String,
     auth_token: String,
+
+    /// The broker's Mizaru public keys, in hex, which connect credentials for each account level must be signed with.
+    #[serde(default = "default_mizaru_free")]
+    mizaru_free: String,
+    #[serde(default = "default_mizaru_plus")]
+    mizaru_plus: String,
+
+    /// The keys from before a rotation, which are still accepted until they are removed, so that the broker's keys can be rotated without turning away clients that got their credentials just before.
+    #[serde(default)]
+    previous_mizaru_free: Option<String>,
+    #[serde(default)]
+    previous_mizaru_plus: Option<String>,
+
+    /// The broker's master public key, in hex, which grants for reverse port forwarding must be signed with. Without it, we refuse all reverse forwards.
+    #[serde(default)]
+    master_pk: Option<String>,
+}
+
+fn default_mizaru_free() -> String {
+    "0558216cbab7a9c46f298f4c26e171add9af87d0694988b8a8fe52ee932aa754".into()
+}
+
+fn default_mizaru_plus() -> String {
+    "cf6f58868c6d9459b3a63bc2bd86165631b3e916bad7f62b578cd9614e0bcb3b".into()
 }
 
 static SIGNING_SECRET: Lazy<SigningKey> = Lazy::new(|| {
@@ -142,9 +282,10 @@ fn main() -> anyhow::Result<()> {
         .init();
     tracing::info!("**** START GEPH EXIT ****");
     let args = CliArgs::parse();
-    let config: ConfigFile = serde_yaml::from_slice(&std::fs::read(args.config)?)?;
+    let config: ConfigFile = serde_yaml::from_slice(&std::fs::read(&args.config)?)?;
 
     CONFIG_FILE.set(config).ok().unwrap();
+    smolscale::spawn(reload_loop(args.config)).detach();
 
     smol::future::block_on(smolscale::spawn(listen_main()))
 }
@@ -1,19 +1,28 @@
 use std::{
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 
 use anyhow::Context;
+use geph5_misc_rpc::{dns::DNS_RPC_PROTOCOL, exit::StreamRejection, udp::UDP_NAT_PROTOCOL};
 
 use futures_util::{io::BufReader, AsyncReadExt, AsyncWriteExt};
 
 use smol::{future::FutureExt as _, net::UdpSocket};
+use stdcode::StdcodeSerializeExt;
 
 use crate::{
-    allow::proxy_allowed,
-    dns::{dns_resolve, raw_dns_respond, FilterOptions},
+    abuse::count_connection,
+    allow::check_destination,
+    dns::{dns_resolve, raw_dns_respond, serve_dns_rpc, FilterOptions},
     ipv6::EyeballDialer,
     ratelimit::RateLimiter,
+    reverse::{reverse_accept, reverse_listen},
+    speedtest::speedtest,
+    udpnat::{udp_nat, UdpNatPool},
 };
 
 use smol_timeout2::TimeoutExt;
@@ -23,22 +32,38 @@ pub async fn proxy_stream(
     dialer: EyeballDialer,
     sess_metadata: Arc<serde_json::Value>,
     ratelimit: RateLimiter,
+    udp_pool: UdpNatPool,
     stream: picomux::Stream,
     is_free: bool,
 ) -> anyhow::Result<()> {
-    let dest_host = String::from_utf8_lossy(stream.metadata());
+    let dest_host = String::from_utf8_lossy(stream.metadata()).to_string();
     let (protocol, dest_host): (&str, &str) = if dest_host.contains('$') {
         dest_host.split_once('$').unwrap()
     } else {
         ("tcp", &dest_host)
     };
+    match protocol {
+        "rlisten" => return reverse_listen(stream, dest_host, ratelimit, is_free).await,
+        "raccept" => return reverse_accept(stream, dest_host),
+        "speedtest" => return speedtest(stream, dest_host, ratelimit).await,
+        _ => {}
+    }
     let filter: FilterOptions =
         serde_json::from_value(sess_metadata["filter"].clone()).unwrap_or_default();
+    if protocol == UDP_NAT_PROTOCOL {
+        return udp_nat(stream, udp_pool, ratelimit, is_free, filter).await;
+    }
+    if protocol == DNS_RPC_PROTOCOL {
+        return serve_dns_rpc(stream, filter).await;
+    }
     let dest_addrs = dns_resolve(dest_host, filter)
         .await
         .context("failed to resolve DNS")?;
-    if !dest_addrs.iter().all(|addr| proxy_allowed(*addr, is_free)) {
-        anyhow::bail!("Proxying to {} is not allowed", dest_host);
+    for addr in dest_addrs.iter() {
+        if let Err(rejection) = check_destination(*addr, is_free).await {
+            send_rejection(&stream, &rejection);
+            anyhow::bail!("proxying to {dest_host} is not allowed: {rejection}");
+        }
     }
 
     match protocol {
@@ -49,6 +74,9 @@ pub async fn proxy_stream(
                 .timeout(Duration::from_secs(5))
                 .await
                 .context(format!("timeout in TCP dial to {:?}", dest_addrs))??;
+            if let Ok(peer_addr) = dest_tcp.peer_addr() {
+                count_connection(peer_addr);
+            }
             tracing::trace!(
                 protocol,
                 dest_host = display(dest_host),
@@ -57,38 +85,52 @@ pub async fn proxy_stream(
             );
             let (read_stream, mut write_stream) = stream.split();
             let (read_dest, mut write_dest) = dest_tcp.split();
-            smol::future::race(
-                ratelimit.io_copy(read_stream, &mut write_dest),
-                ratelimit.io_copy(read_dest, &mut write_stream),
+            // each direction half-closes on its own, so that protocols relying on half-closes work
+            smol::future::try_zip(
+                async {
+                    ratelimit.io_copy(read_stream, &mut write_dest).await?;
+                    write_dest.close().await
+                },
+                async {
+                    ratelimit.io_copy(read_dest, &mut write_stream).await?;
+                    write_stream.close().await
+                },
             )
             .await?;
             Ok(())
         }
         "udp" => {
+            // IPv6 addresses are only among the resolved ones if we can reach them
             let addr = *dest_addrs
                 .iter()
                 .find(|s| s.is_ipv4())
-                .context("UDP only supports ipv4 for now")?;
+                .or_else(|| dest_addrs.first())
+                .context("no address to send UDP to")?;
             if addr.port() == 53 {
                 return proxy_dns(stream, filter).await;
             }
             if addr.port() == 443 {
                 anyhow::bail!("special-case banning QUIC to improve traffic management")
             }
-            let udp_socket: UdpSocket = UdpSocket::bind("0.0.0.0:0")
-                .await
-                .context("UDP bind failed")?;
+            let udp_socket: UdpSocket = dialer.bind_udp(addr).await?;
             udp_socket.connect(addr).await?;
+            count_connection(addr);
+            // clients that send their packets as datagrams get their responses as datagrams too
+            let datagrams = stream.datagrams();
+            let use_datagrams = AtomicBool::new(false);
             let (read_stream, mut write_stream) = stream.split();
             let up_loop = async {
                 let mut read_stream = BufReader::new(read_stream);
                 let mut len_buf = [0; 2];
                 loop {
-                    read_stream
+                    let read = read_stream
                         .read_exact(&mut len_buf)
                         .timeout(Duration::from_secs(60))
-                        .await
-                        .context("timeout in udp up")??;
+                        .await;
+                    if read.is_none() && use_datagrams.load(Ordering::Relaxed) {
+                        return smol::future::pending().await;
+                    }
+                    read.context("timeout in udp up")??;
                     let mut packet_buf = vec![0; u16::from_le_bytes(len_buf) as usize];
                     read_stream
                         .read_exact(&mut packet_buf)
@@ -99,6 +141,22 @@ pub async fn proxy_stream(
                     udp_socket.send(&packet_buf).await?;
                 }
             };
+            let dgram_up_loop = async {
+                loop {
+                    let packet = if use_datagrams.load(Ordering::Relaxed) {
+                        datagrams
+                            .recv()
+                            .timeout(Duration::from_secs(60))
+                            .await
+                            .context("timeout in udp up")??
+                    } else {
+                        datagrams.recv().await?
+                    };
+                    use_datagrams.store(true, Ordering::Relaxed);
+                    ratelimit.wait(packet.len()).await;
+                    udp_socket.send(&packet).await?;
+                }
+            };
             let dn_loop = async {
                 let mut buf = [0u8; 8192];
                 loop {
@@ -109,6 +167,10 @@ pub async fn proxy_stream(
                         .await
                         .context("timeout in udp down")??;
                     ratelimit.wait(len).await;
+                    if use_datagrams.load(Ordering::Relaxed) {
+                        datagrams.send(&buf[2..len + 2])?;
+                        continue;
+                    }
 
                     // Store the length of the data in the first two bytes
                     let len_bytes = (len as u16).to_le_bytes();
@@ -119,7 +181,7 @@ pub async fn proxy_stream(
                     write_stream.write_all(&buf[..len + 2]).await?;
                 }
             };
-            up_loop.race(dn_loop).await
+            up_loop.race(dgram_up_loop).race(dn_loop).await
         }
         prot => {
             anyhow::bail!("unknown protocol {prot}")
@@ -127,6 +189,18 @@ pub async fn proxy_stream(
     }
 }
 
+/// Tells the client why a stream is about to be closed, if it is a TCP stream and the client understands datagrams. Other streams carry their own data in datagrams, so they are just closed.
+pub fn send_rejection(stream: &picomux::Stream, rejection: &StreamRejection) {
+    let metadata = String::from_utf8_lossy(stream.metadata());
+    let is_tcp = match metadata.split_once('$') {
+        Some((protocol, _)) => protocol.split('@').next() == Some("tcp"),
+        None => true,
+    };
+    if is_tcp {
+        let _ = stream.datagrams().send(&rejection.stdcode());
+    }
+}
+
 async fn proxy_dns(stream: picomux::Stream, filter: FilterOptions) -> anyhow::Result<()> {
     let (mut read_stream, write_stream) = stream.split();
     let write_stream = Arc::new(smol::lock::Mutex::new(write_stream));
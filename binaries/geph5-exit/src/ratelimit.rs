@@ -11,6 +11,7 @@ use async_io_bufpool::pooled_read;
 use atomic_float::AtomicF32;
 use futures_util::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use geph5_broker_protocol::AccountLevel;
+use geph5_misc_rpc::exit::SessionLimits;
 use governor::{DefaultDirectRateLimiter, Quota};
 use mizaru2::ClientToken;
 use moka::future::Cache;
@@ -44,6 +45,11 @@ pub fn get_load() -> f32 {
     cpu.max(speed)
 }
 
+/// Obtains the smoothed CPU usage, between 0 and 1.
+pub fn get_cpu() -> f32 {
+    CPU_USAGE.load(Ordering::Relaxed)
+}
+
 pub fn get_kbps() -> f32 {
     CURRENT_SPEED.load(Ordering::Relaxed) / 1000.0
 }
@@ -81,24 +87,35 @@ pub fn update_load_loop() {
 }
 
 pub async fn get_ratelimiter(level: AccountLevel, token: ClientToken) -> RateLimiter {
+    let limits = session_limits(level);
+    let new_limiter = async { RateLimiter::new(limits.kbps.unwrap_or_default(), limits.burst_kb) };
     match level {
         AccountLevel::Free => {
             FREE_RL_CACHE
-                .get_with(blake3::hash(&(level, token).stdcode()), async {
-                    RateLimiter::new(CONFIG_FILE.wait().free_ratelimit, 100)
-                })
+                .get_with(blake3::hash(&(level, token).stdcode()), new_limiter)
                 .await
         }
         AccountLevel::Plus => {
             PLUS_RL_CACHE
-                .get_with(blake3::hash(&(level, token).stdcode()), async {
-                    RateLimiter::new(CONFIG_FILE.wait().plus_ratelimit, 100)
-                })
+                .get_with(blake3::hash(&(level, token).stdcode()), new_limiter)
                 .await
         }
     }
 }
 
+/// The limits that sessions of the account level get, as configured.
+pub fn session_limits(level: AccountLevel) -> SessionLimits {
+    let cfg = CONFIG_FILE.wait();
+    let (kbps, burst_kb) = match level {
+        AccountLevel::Free => (cfg.free_ratelimit, cfg.free_burst),
+        AccountLevel::Plus => (cfg.plus_ratelimit, cfg.plus_burst),
+    };
+    SessionLimits {
+        kbps: Some(kbps),
+        burst_kb: burst_kb.max(1),
+    }
+}
+
 /// A generic rate limiter.
 #[derive(Clone)]
 pub struct RateLimiter {
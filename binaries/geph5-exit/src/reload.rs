@@ -0,0 +1,129 @@
+//! Live reloading of the config file, so that operators don't have to restart the exit, dropping every session, to change it. We check the file for changes every few seconds, and once it changes and still parses, everything that reads the config from then on sees the new version: rate limits, the egress policy, per-account limits, and the broker's auth token and Mizaru keys. Live sessions carry on as they are. Listening addresses, the signing secret, and the IPv6 subnet are only read at startup.
+
+use std::{
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    ptr::null_mut,
+    sync::atomic::{AtomicPtr, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use once_cell::sync::OnceCell;
+
+use crate::{ConfigFile, CONFIG_FILE};
+
+/// How often we check the config file for changes.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A config that can be replaced while it is being read. Every version is handed out by reference for the rest of the process, so replaced versions are leaked rather than freed, which is fine since reloads are rare and configs small.
+pub struct ConfigCell<T> {
+    loaded: OnceCell<()>,
+    current: AtomicPtr<T>,
+    _marker: PhantomData<Box<T>>,
+}
+
+impl<T> ConfigCell<T> {
+    pub const fn new() -> Self {
+        Self {
+            loaded: OnceCell::new(),
+            current: AtomicPtr::new(null_mut()),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Sets the initial config, handing it back if there already is one.
+    pub fn set(&self, value: T) -> Result<(), T> {
+        if self.loaded.get().is_some() {
+            return Err(value);
+        }
+        self.current
+            .store(Box::into_raw(Box::new(value)), Ordering::SeqCst);
+        let _ = self.loaded.set(());
+        Ok(())
+    }
+
+    /// Gets the current config, if the initial one has been set.
+    pub fn get(&self) -> Option<&T> {
+        self.loaded.get()?;
+        // SAFETY: the pointer is set before `loaded`, and what it points to is never freed
+        Some(unsafe { &*self.current.load(Ordering::SeqCst) })
+    }
+
+    /// Gets the current config, blocking until the initial one is set.
+    pub fn wait(&self) -> &T {
+        self.loaded.wait();
+        // SAFETY: the pointer is set before `loaded`, and what it points to is never freed
+        unsafe { &*self.current.load(Ordering::SeqCst) }
+    }
+
+    /// Replaces the config, for everybody who reads it from now on.
+    pub fn replace(&self, value: T) {
+        self.current
+            .swap(Box::into_raw(Box::new(value)), Ordering::SeqCst);
+        let _ = self.loaded.set(());
+    }
+}
+
+impl<T> Default for ConfigCell<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// This loop reloads the config file whenever it changes. A file that does not parse is ignored, with a warning, until it is fixed.
+pub async fn reload_loop(path: PathBuf) -> anyhow::Result<()> {
+    let mut last_modified = modified(&path);
+    loop {
+        smol::Timer::after(CHECK_INTERVAL).await;
+        let now_modified = modified(&path);
+        if now_modified == last_modified {
+            continue;
+        }
+        last_modified = now_modified;
+        let parsed = std::fs::read(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|bts| Ok(serde_yaml::from_slice::<ConfigFile>(&bts)?));
+        match parsed {
+            Ok(config) => {
+                let old = CONFIG_FILE.wait();
+                if config.c2e_listen != old.c2e_listen
+                    || config.b2e_listen != old.b2e_listen
+                    || config.signing_secret != old.signing_secret
+                    || config.ipv6_subnet != old.ipv6_subnet
+                {
+                    tracing::warn!("listening addresses, the signing secret, and the IPv6 subnet only change on restart");
+                }
+                CONFIG_FILE.replace(config);
+                tracing::info!(path = debug(&path), "reloaded the config file");
+            }
+            Err(err) => {
+                tracing::warn!(
+                    path = debug(&path),
+                    err = debug(err),
+                    "not reloading the config file, since it is broken"
+                )
+            }
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).ok()?.modified().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaced_configs_stay_readable() {
+        let cell = ConfigCell::new();
+        assert!(cell.get().is_none());
+        cell.set(1).unwrap();
+        assert_eq!(cell.set(2), Err(2));
+        let old = cell.wait();
+        cell.replace(3);
+        assert_eq!(*old, 1);
+        assert_eq!(*cell.wait(), 3);
+    }
+}
@@ -0,0 +1,178 @@
+//! Reverse port forwarding. A client opens a `rlisten$<port>` stream to have us listen on a public port, and first sends on it the broker's grant, as a 2-byte length and the stdcode-encoded [`Signed<ReverseForwardGrant>`]. Every inbound connection is then announced on that stream as a random 8-byte ID. The client claims the connection by opening a `raccept$<id>` stream, which gets piped to it.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use dashmap::DashMap;
+use ed25519_dalek::VerifyingKey;
+use futures_util::{AsyncReadExt, AsyncWriteExt};
+use geph5_broker_protocol::{ReverseForwardGrant, Signed, DOMAIN_REVERSE_FORWARD};
+use nursery_macro::nursery;
+use once_cell::sync::Lazy;
+use smol::future::FutureExt as _;
+use smol_timeout2::TimeoutExt;
+
+use crate::{ratelimit::RateLimiter, CONFIG_FILE};
+
+/// Inbound connections waiting for their client to claim them.
+static PENDING: Lazy<DashMap<u64, oneshot::Sender<picomux::Stream>>> = Lazy::new(DashMap::new);
+
+/// How many ports are being forwarded for each user, by the holder in their grants.
+static PORTS_IN_USE: Lazy<DashMap<[u8; 32], u16>> = Lazy::new(DashMap::new);
+
+/// Reads the grant at the start of a `rlisten` stream, and checks that the broker signed it and that it hasn't expired.
+async fn read_grant(stream: &mut picomux::Stream) -> anyhow::Result<ReverseForwardGrant> {
+    let master_pk = CONFIG_FILE
+        .wait()
+        .broker
+        .as_ref()
+        .and_then(|broker| broker.master_pk.as_deref())
+        .context("reverse port forwarding needs the broker's public key")?;
+    let master_pk = VerifyingKey::from_bytes(
+        &hex::decode(master_pk)
+            .context("broker public key must be hex")?
+            .try_into()
+            .ok()
+            .context("broker public key must be 32 bytes")?,
+    )
+    .ok()
+    .context("broker public key is invalid")?;
+    let read = async {
+        let mut len_buf = [0u8; 2];
+        stream.read_exact(&mut len_buf).await?;
+        let mut buf = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+        stream.read_exact(&mut buf).await?;
+        anyhow::Ok(buf)
+    };
+    let buf = read
+        .timeout(Duration::from_secs(10))
+        .await
+        .context("client did not send a grant in time")??;
+    let grant: Signed<ReverseForwardGrant> = stdcode::deserialize(&buf)?;
+    let grant = grant
+        .verify(DOMAIN_REVERSE_FORWARD, |pk| pk == &master_pk)
+        .context("grant not signed by the broker")?;
+    if grant.expiry <= unix_now() {
+        anyhow::bail!("grant expired")
+    }
+    Ok(grant)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Listens on a public port on behalf of a client, for as long as the client keeps the stream open and its grant is good.
+pub async fn reverse_listen(
+    mut stream: picomux::Stream,
+    port: &str,
+    ratelimit: RateLimiter,
+    is_free: bool,
+) -> anyhow::Result<()> {
+    if is_free {
+        anyhow::bail!("free users cannot use reverse port forwarding")
+    }
+    let port: u16 = port.parse().context("invalid port")?;
+    let (min_port, max_port) = CONFIG_FILE
+        .wait()
+        .reverse_port_range
+        .context("reverse port forwarding is disabled on this exit")?;
+    if !(min_port..=max_port).contains(&port) {
+        anyhow::bail!("port {port} is outside the range allowed for reverse forwarding")
+    }
+    let grant = read_grant(&mut stream).await?;
+    {
+        let mut in_use = PORTS_IN_USE.entry(grant.holder).or_default();
+        if *in_use >= grant.max_ports {
+            anyhow::bail!(
+                "grant holder already has {} reverse forwards at once",
+                grant.max_ports
+            )
+        }
+        *in_use += 1;
+    }
+    scopeguard::defer!({
+        PORTS_IN_USE.remove_if_mut(&grant.holder, |_, in_use| {
+            *in_use -= 1;
+            *in_use == 0
+        });
+    });
+    let listener =
+        smol::net::TcpListener::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port)).await?;
+    tracing::debug!(port, "started reverse port forward");
+
+    let (mut read_stream, mut write_stream) = stream.split();
+    let accept_loop = async {
+        nursery!({
+            loop {
+                let (inbound, peer_addr) = listener.accept().await?;
+                let id: u64 = rand::random();
+                let (send, recv) = oneshot::channel();
+                PENDING.insert(id, send);
+                write_stream.write_all(&id.to_be_bytes()).await?;
+                write_stream.flush().await?;
+                let ratelimit = ratelimit.clone();
+                spawn!(async move {
+                    scopeguard::defer!({
+                        PENDING.remove(&id);
+                    });
+                    let claimed = recv
+                        .timeout(Duration::from_secs(10))
+                        .await
+                        .context("client did not claim the inbound connection")??;
+                    tracing::trace!(
+                        port,
+                        peer_addr = display(peer_addr),
+                        "inbound connection claimed"
+                    );
+                    let (read_claimed, mut write_claimed) = claimed.split();
+                    let (read_inbound, mut write_inbound) = inbound.split();
+                    // each direction half-closes on its own, so that protocols relying on half-closes work
+                    smol::future::try_zip(
+                        async {
+                            ratelimit.io_copy(read_claimed, &mut write_inbound).await?;
+                            write_inbound.close().await
+                        },
+                        async {
+                            ratelimit.io_copy(read_inbound, &mut write_claimed).await?;
+                            write_claimed.close().await
+                        },
+                    )
+                    .await?;
+                    anyhow::Ok(())
+                })
+                .detach();
+            }
+        })
+    };
+    let control = async {
+        // the forward stops when the client closes the stream
+        let mut buf = [0u8; 1];
+        while read_stream.read(&mut buf).await? > 0 {}
+        anyhow::Ok(())
+    };
+    let expiry = async {
+        smol::Timer::after(Duration::from_secs(grant.expiry.saturating_sub(unix_now()))).await;
+        tracing::debug!(port, "reverse port forward grant expired");
+        anyhow::Ok(())
+    };
+    accept_loop.race(control).race(expiry).await
+}
+
+/// Hands a client's claiming stream to the inbound connection it asked for.
+pub fn reverse_accept(stream: picomux::Stream, id: &str) -> anyhow::Result<()> {
+    let id: u64 = id.parse().context("invalid connection ID")?;
+    let (_, send) = PENDING
+        .remove(&id)
+        .context("no pending inbound connection with this ID")?;
+    send.send(stream)
+        .ok()
+        .context("inbound connection already gone")?;
+    Ok(())
+}
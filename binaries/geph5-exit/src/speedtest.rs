@@ -0,0 +1,57 @@
+//! A measurement endpoint for the client's built-in speed test, reached by opening `speedtest$<mode>` streams:
+//!
+//! - `echo` echoes everything back, for measuring latency.
+//! - `download:<bytes>` sends that many bytes of filler.
+//! - `upload` swallows everything, acknowledging the running total as a big-endian u64 after every 64 KiB.
+
+use anyhow::Context;
+use futures_util::{AsyncReadExt, AsyncWriteExt};
+
+use crate::ratelimit::RateLimiter;
+
+/// Upper bound on how much a single download test may request.
+const MAX_DOWNLOAD: u64 = 1 << 30;
+
+const ACK_INTERVAL: u64 = 1 << 16;
+
+pub async fn speedtest(
+    stream: picomux::Stream,
+    mode: &str,
+    ratelimit: RateLimiter,
+) -> anyhow::Result<()> {
+    let (mut read_stream, mut write_stream) = stream.split();
+    if mode == "echo" {
+        ratelimit.io_copy(read_stream, write_stream).await?;
+    } else if let Some(bytes) = mode.strip_prefix("download:") {
+        let mut remaining: u64 = bytes.parse::<u64>().context("invalid byte count")?;
+        remaining = remaining.min(MAX_DOWNLOAD);
+        let filler = [0u8; 16384];
+        while remaining > 0 {
+            let n = remaining.min(filler.len() as u64) as usize;
+            ratelimit.wait(n).await;
+            write_stream.write_all(&filler[..n]).await?;
+            remaining -= n as u64;
+        }
+        write_stream.flush().await?;
+    } else if mode == "upload" {
+        let mut buf = [0u8; 16384];
+        let mut total = 0u64;
+        let mut next_ack = ACK_INTERVAL;
+        loop {
+            let n = read_stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            ratelimit.wait(n).await;
+            total += n as u64;
+            if total >= next_ack {
+                write_stream.write_all(&total.to_be_bytes()).await?;
+                write_stream.flush().await?;
+                next_ack = total + ACK_INTERVAL;
+            }
+        }
+    } else {
+        anyhow::bail!("unknown speed test mode {mode}")
+    }
+    Ok(())
+}
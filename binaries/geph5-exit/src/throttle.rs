@@ -0,0 +1,160 @@
+//! Limits on how fast handshakes and streams may be started, so that a single misbehaving or compromised client, or a flood coming through one bridge, can't tie up the exit for everyone. Whoever goes over a limit is banned for a while rather than just slowed down, since well-behaved clients never come close.
+
+use std::{
+    num::NonZeroU32,
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use governor::{DefaultDirectRateLimiter, Quota};
+use moka::future::Cache;
+use serde::Deserialize;
+
+use crate::CONFIG_FILE;
+
+/// How fast handshakes and streams may be started. Zero turns a limit off.
+#[derive(Deserialize, Clone, Debug)]
+pub struct ThrottleConfig {
+    /// How many handshakes a minute we accept from a single address connecting directly, or from a single client of a bridge that authenticated itself and so tells its clients apart.
+    #[serde(default = "default_direct_handshakes_per_minute")]
+    pub direct_handshakes_per_minute: u32,
+    /// How many handshakes a minute we accept through a single bridge, which carries many clients.
+    #[serde(default = "default_bridge_handshakes_per_minute")]
+    pub bridge_handshakes_per_minute: u32,
+    /// How many handshakes a minute a single account may make.
+    #[serde(default = "default_token_handshakes_per_minute")]
+    pub token_handshakes_per_minute: u32,
+    /// How many streams a second a single account may open, all its sessions together.
+    #[serde(default = "default_token_streams_per_second")]
+    pub token_streams_per_second: u32,
+    /// How long going over a limit bans the address, bridge, or account from what it went over, in seconds. Bans are forgotten early if nothing more is heard from the banned one for an hour.
+    #[serde(default = "default_ban_secs")]
+    pub ban_secs: u64,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        Self {
+            direct_handshakes_per_minute: default_direct_handshakes_per_minute(),
+            bridge_handshakes_per_minute: default_bridge_handshakes_per_minute(),
+            token_handshakes_per_minute: default_token_handshakes_per_minute(),
+            token_streams_per_second: default_token_streams_per_second(),
+            ban_secs: default_ban_secs(),
+        }
+    }
+}
+
+fn default_direct_handshakes_per_minute() -> u32 {
+    300
+}
+
+fn default_bridge_handshakes_per_minute() -> u32 {
+    30000
+}
+
+fn default_token_handshakes_per_minute() -> u32 {
+    120
+}
+
+fn default_token_streams_per_second() -> u32 {
+    500
+}
+
+fn default_ban_secs() -> u64 {
+    300
+}
+
+/// What is being started, and by whom.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ThrottleKind {
+    /// A handshake from an address connecting directly.
+    DirectHandshake,
+    /// A handshake coming through a bridge, told apart by the bridge's address.
+    BridgeHandshake,
+    /// A handshake by one client of an authenticated bridge, told apart by the bridge's ID and the bridge's number for the client.
+    BridgeClientHandshake,
+    /// A handshake by an account, told apart by its connect token.
+    TokenHandshake,
+    /// A stream opened by an account, told apart by its connect token.
+    TokenStream,
+}
+
+impl ThrottleKind {
+    fn quota(self, cfg: &ThrottleConfig) -> Option<Quota> {
+        let per_minute = |n| NonZeroU32::new(n).map(Quota::per_minute);
+        match self {
+            ThrottleKind::DirectHandshake | ThrottleKind::BridgeClientHandshake => {
+                per_minute(cfg.direct_handshakes_per_minute)
+            }
+            ThrottleKind::BridgeHandshake => per_minute(cfg.bridge_handshakes_per_minute),
+            ThrottleKind::TokenHandshake => per_minute(cfg.token_handshakes_per_minute),
+            ThrottleKind::TokenStream => {
+                NonZeroU32::new(cfg.token_streams_per_second).map(Quota::per_second)
+            }
+        }
+    }
+}
+
+struct Throttle {
+    limiter: DefaultDirectRateLimiter,
+    banned_until: Mutex<Option<Instant>>,
+}
+
+impl Throttle {
+    fn new(quota: Quota) -> Self {
+        Self {
+            limiter: governor::RateLimiter::direct(quota),
+            banned_until: Mutex::new(None),
+        }
+    }
+
+    /// Counts one more start, banning for the given time if that goes over the limit.
+    fn check(&self, ban: Duration) -> anyhow::Result<()> {
+        let mut banned_until = self.banned_until.lock().unwrap();
+        if banned_until.is_some_and(|until| until > Instant::now()) {
+            anyhow::bail!("banned for going over the limit")
+        }
+        if self.limiter.check().is_err() {
+            *banned_until = Some(Instant::now() + ban);
+            anyhow::bail!("went over the limit, so banned for {ban:?}")
+        }
+        Ok(())
+    }
+}
+
+static THROTTLES: LazyLock<Cache<(ThrottleKind, blake3::Hash), Arc<Throttle>>> =
+    LazyLock::new(|| {
+        Cache::builder()
+            .time_to_idle(Duration::from_secs(3600))
+            .build()
+    });
+
+/// Counts one more start of the given kind by whoever the key identifies, failing if that is over the configured limit or they are banned.
+pub async fn throttle(kind: ThrottleKind, key: &[u8]) -> anyhow::Result<()> {
+    let cfg = &CONFIG_FILE.wait().throttle;
+    let Some(quota) = kind.quota(cfg) else {
+        return Ok(());
+    };
+    let throttle = THROTTLES
+        .get_with((kind, blake3::hash(key)), async {
+            Arc::new(Throttle::new(quota))
+        })
+        .await;
+    throttle.check(Duration::from_secs(cfg.ban_secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn going_over_the_limit_bans() {
+        let throttle = Throttle::new(Quota::per_minute(NonZeroU32::new(2).unwrap()));
+        let ban = Duration::from_secs(60);
+        assert!(throttle.check(ban).is_ok());
+        assert!(throttle.check(ban).is_ok());
+        assert!(throttle.check(ban).is_err());
+        assert!(throttle.banned_until.lock().unwrap().is_some());
+        assert!(throttle.check(ban).is_err());
+    }
+}
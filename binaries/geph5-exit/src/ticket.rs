@@ -0,0 +1,71 @@
+//! Resumption tickets, which let clients that reconnect soon after a session skip the check of their credentials, the most expensive part of a handshake. A ticket holds the account level and connect token of the session it came from, encrypted with a key that is derived from our signing secret and changes every ticket lifetime. Tickets made with the key before are still accepted, so a ticket is good for its whole lifetime however close to a change it was made.
+
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use geph5_broker_protocol::AccountLevel;
+use geph5_misc_rpc::exit::TicketState;
+use mizaru2::ClientToken;
+
+use crate::{CONFIG_FILE, SIGNING_SECRET};
+
+fn ticket_key(secret: &[u8; 32], period: u64) -> [u8; 32] {
+    let mut material = secret.to_vec();
+    material.extend_from_slice(&period.to_be_bytes());
+    blake3::derive_key("geph5-exit resumption ticket key", &material)
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Makes a ticket for a session of the given account, or returns None if tickets are turned off. Sessions that were themselves resumed pass on the expiry of their ticket, so that credentials are checked again at least once a lifetime.
+pub fn issue_ticket(level: AccountLevel, token: ClientToken, expiry: Option<u64>) -> Option<Bytes> {
+    let lifetime = CONFIG_FILE.wait().ticket_lifetime_secs;
+    if lifetime == 0 {
+        return None;
+    }
+    let now = now_secs();
+    let state = TicketState {
+        level,
+        token,
+        expiry: expiry.unwrap_or(now + lifetime),
+    };
+    Some(state.seal(&ticket_key(&SIGNING_SECRET.to_bytes(), now / lifetime)))
+}
+
+/// Checks a ticket, returning what it remembers of the session it came from.
+pub fn redeem_ticket(ticket: &[u8]) -> anyhow::Result<TicketState> {
+    let lifetime = CONFIG_FILE.wait().ticket_lifetime_secs;
+    anyhow::ensure!(lifetime > 0, "tickets are turned off");
+    let now = now_secs();
+    let secret = SIGNING_SECRET.to_bytes();
+    let period = now / lifetime;
+    let state = TicketState::open(ticket, &ticket_key(&secret, period))
+        .or_else(|_| TicketState::open(ticket, &ticket_key(&secret, period.saturating_sub(1))))?;
+    anyhow::ensure!(state.expiry > now, "ticket expired");
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tickets_only_open_with_their_key() {
+        let secret = [7u8; 32];
+        let state = TicketState {
+            level: AccountLevel::Plus,
+            token: ClientToken::random(),
+            expiry: 1000,
+        };
+        let ticket = state.seal(&ticket_key(&secret, 5));
+        let opened = TicketState::open(&ticket, &ticket_key(&secret, 5)).unwrap();
+        assert_eq!(opened.expiry, 1000);
+        assert!(TicketState::open(&ticket, &ticket_key(&secret, 6)).is_err());
+        assert!(TicketState::open(&ticket, &ticket_key(&[8u8; 32], 5)).is_err());
+    }
+}
@@ -0,0 +1,193 @@
+//! The exit side of UDP relaying with full-cone NAT semantics, over `udpnat$` streams framed as in [`geph5_misc_rpc::udp`]. Every stream gets a public UDP socket of its own for as long as packets keep flowing, so peer-to-peer protocols that learn their public address from one peer and hand it to another work through us.
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use bytes::Bytes;
+use futures_util::{io::BufReader, AsyncReadExt, AsyncWriteExt};
+use geph5_misc_rpc::udp::UdpNatFrame;
+use serde::Deserialize;
+use smol::{future::FutureExt as _, lock::Semaphore, net::UdpSocket};
+
+use crate::{
+    allow::check_destination,
+    dns::{raw_dns_respond, FilterOptions},
+    ratelimit::RateLimiter,
+    CONFIG_FILE,
+};
+
+/// Configuration of full-cone UDP relaying.
+#[derive(Deserialize, Clone, Debug)]
+pub struct UdpNatConfig {
+    /// Inclusive ranges of destination ports that packets may be relayed to. By default, everything but 443, since QUIC is better off going over TCP through us.
+    #[serde(default = "default_allowed_ports")]
+    pub allowed_ports: Vec<(u16, u16)>,
+    /// How long a mapping lives without packets in either direction.
+    #[serde(default = "default_mapping_timeout_secs")]
+    pub mapping_timeout_secs: u64,
+    /// How many mappings, and so public UDP sockets, one session may have at once.
+    #[serde(default = "default_max_mappings_per_session")]
+    pub max_mappings_per_session: usize,
+}
+
+impl Default for UdpNatConfig {
+    fn default() -> Self {
+        Self {
+            allowed_ports: default_allowed_ports(),
+            mapping_timeout_secs: default_mapping_timeout_secs(),
+            max_mappings_per_session: default_max_mappings_per_session(),
+        }
+    }
+}
+
+fn default_allowed_ports() -> Vec<(u16, u16)> {
+    vec![(1, 442), (444, 65535)]
+}
+
+fn default_mapping_timeout_secs() -> u64 {
+    120
+}
+
+fn default_max_mappings_per_session() -> usize {
+    64
+}
+
+impl UdpNatConfig {
+    fn port_allowed(&self, port: u16) -> bool {
+        self.allowed_ports
+            .iter()
+            .any(|(min, max)| (*min..=*max).contains(&port))
+    }
+}
+
+/// The UDP mappings of one session, which may only have so many at once.
+#[derive(Clone)]
+pub struct UdpNatPool(Arc<Semaphore>);
+
+impl UdpNatPool {
+    pub fn for_session() -> Self {
+        Self(Arc::new(Semaphore::new(
+            CONFIG_FILE.wait().udp_nat.max_mappings_per_session,
+        )))
+    }
+}
+
+/// Relays the packets of a `udpnat$` stream through a public UDP socket of its own, until no packets go either way for the mapping timeout.
+pub async fn udp_nat(
+    stream: picomux::Stream,
+    pool: UdpNatPool,
+    ratelimit: RateLimiter,
+    is_free: bool,
+    filter: FilterOptions,
+) -> anyhow::Result<()> {
+    let cfg = &CONFIG_FILE.wait().udp_nat;
+    let _mapping = pool
+        .0
+        .try_acquire_arc()
+        .context("too many UDP mappings in this session")?;
+    let socket = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .context("UDP bind failed")?;
+    let timeout = Duration::from_secs(cfg.mapping_timeout_secs);
+    let last_activity = Mutex::new(Instant::now());
+    let touch = || *last_activity.lock().unwrap() = Instant::now();
+
+    // DNS queries are answered by us, with the session's filters, rather than relayed
+    let (dns_send, dns_recv) = smol::channel::bounded::<UdpNatFrame>(16);
+    let relay_up = |frame: UdpNatFrame| {
+        let dns_send = dns_send.clone();
+        let socket = &socket;
+        let ratelimit = &ratelimit;
+        async move {
+            touch();
+            if !frame.peer.is_ipv4() {
+                tracing::trace!(peer = display(frame.peer), "dropping UDP packet to IPv6");
+                return anyhow::Ok(());
+            }
+            if frame.peer.port() == 53 {
+                smolscale::spawn(async move {
+                    let response = raw_dns_respond(frame.payload, filter).await?;
+                    let _ = dns_send.try_send(UdpNatFrame {
+                        peer: frame.peer,
+                        payload: response,
+                    });
+                    anyhow::Ok(())
+                })
+                .detach();
+                return Ok(());
+            }
+            if !cfg.port_allowed(frame.peer.port())
+                || check_destination(frame.peer, is_free).await.is_err()
+            {
+                tracing::trace!(peer = display(frame.peer), "dropping disallowed UDP packet");
+                return Ok(());
+            }
+            ratelimit.wait(frame.payload.len()).await;
+            socket.send_to(&frame.payload, frame.peer).await?;
+            Ok(())
+        }
+    };
+
+    // clients that send their packets as datagrams get their responses as datagrams too
+    let datagrams = stream.datagrams();
+    let use_datagrams = AtomicBool::new(false);
+    let (read_stream, mut write_stream) = stream.split();
+    let up_loop = async {
+        let mut read_stream = BufReader::new(read_stream);
+        let mut len_buf = [0; 2];
+        loop {
+            read_stream.read_exact(&mut len_buf).await?;
+            let mut frame_buf = vec![0; u16::from_le_bytes(len_buf) as usize];
+            read_stream.read_exact(&mut frame_buf).await?;
+            relay_up(UdpNatFrame::decode(&frame_buf)?).await?;
+        }
+    };
+    let dgram_up_loop = async {
+        loop {
+            let frame = datagrams.recv().await?;
+            use_datagrams.store(true, Ordering::Relaxed);
+            relay_up(UdpNatFrame::decode(&frame)?).await?;
+        }
+    };
+    let dn_loop = async {
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let frame = async {
+                let (len, peer): (usize, SocketAddr) = socket.recv_from(&mut buf).await?;
+                anyhow::Ok(UdpNatFrame {
+                    peer,
+                    payload: Bytes::copy_from_slice(&buf[..len]),
+                })
+            }
+            .race(async { Ok(dns_recv.recv().await?) })
+            .await?;
+            touch();
+            ratelimit.wait(frame.payload.len()).await;
+            let encoded = frame.encode();
+            if use_datagrams.load(Ordering::Relaxed) {
+                datagrams.send(&encoded)?;
+            } else {
+                write_stream
+                    .write_all(&(encoded.len() as u16).to_le_bytes())
+                    .await?;
+                write_stream.write_all(&encoded).await?;
+            }
+        }
+    };
+    let expiry = async {
+        loop {
+            smol::Timer::after(timeout).await;
+            if last_activity.lock().unwrap().elapsed() >= timeout {
+                anyhow::bail!("UDP mapping timed out")
+            }
+        }
+    };
+    up_loop.race(dgram_up_loop).race(dn_loop).race(expiry).await
+}
@@ -0,0 +1,144 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, LazyLock,
+};
+
+use dashmap::DashMap;
+use geph5_broker_protocol::AccountLevel;
+use mizaru2::ClientToken;
+use serde::Deserialize;
+use stdcode::StdcodeSerializeExt;
+
+use crate::CONFIG_FILE;
+
+/// Ceilings on what a single account may have open at this exit at once, across all its sessions. Accounts are told apart by their connect tokens, which are the same for all of an account's sessions within an epoch.
+#[derive(Deserialize, Clone, Debug)]
+pub struct UserLimitConfig {
+    /// How many sessions an account may have at once.
+    #[serde(default = "default_max_sessions")]
+    pub max_sessions: usize,
+    /// How many streams an account may have open at once, all its sessions together.
+    #[serde(default = "default_max_streams")]
+    pub max_streams: usize,
+}
+
+impl Default for UserLimitConfig {
+    fn default() -> Self {
+        Self {
+            max_sessions: default_max_sessions(),
+            max_streams: default_max_streams(),
+        }
+    }
+}
+
+fn default_max_sessions() -> usize {
+    32
+}
+
+fn default_max_streams() -> usize {
+    8000
+}
+
+/// What one account has open at the moment.
+#[derive(Default)]
+struct UserUsage {
+    sessions: AtomicUsize,
+    streams: AtomicUsize,
+}
+
+impl UserUsage {
+    fn is_idle(&self) -> bool {
+        self.sessions.load(Ordering::SeqCst) == 0 && self.streams.load(Ordering::SeqCst) == 0
+    }
+}
+
+/// Increments the counter unless it is already at the ceiling.
+fn try_increment(counter: &AtomicUsize, max: usize) -> bool {
+    counter
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+            (n < max).then_some(n + 1)
+        })
+        .is_ok()
+}
+
+static USAGE: LazyLock<DashMap<blake3::Hash, Arc<UserUsage>>> = LazyLock::new(DashMap::new);
+
+/// A session of an authenticated account, counted towards the account's ceilings for as long as it lives.
+pub struct UserSession {
+    key: blake3::Hash,
+    usage: Arc<UserUsage>,
+}
+
+impl UserSession {
+    /// Starts counting a session of the account with the given token, failing if the account already has too many.
+    pub fn start(level: AccountLevel, token: ClientToken) -> anyhow::Result<Self> {
+        let key = blake3::hash(&(level, token).stdcode());
+        let max_sessions = CONFIG_FILE.wait().user_limits.max_sessions;
+        // counting while holding the entry keeps it from being forgotten under us
+        let entry = USAGE.entry(key).or_default();
+        if !try_increment(&entry.sessions, max_sessions) {
+            drop(entry);
+            forget_if_idle(key);
+            anyhow::bail!("account already has {max_sessions} sessions");
+        }
+        Ok(Self {
+            key,
+            usage: Arc::clone(&entry),
+        })
+    }
+
+    /// Starts counting a stream, or returns None if the account already has too many streams open.
+    pub fn start_stream(&self) -> Option<UserStream> {
+        if try_increment(
+            &self.usage.streams,
+            CONFIG_FILE.wait().user_limits.max_streams,
+        ) {
+            Some(UserStream {
+                key: self.key,
+                usage: self.usage.clone(),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for UserSession {
+    fn drop(&mut self) {
+        self.usage.sessions.fetch_sub(1, Ordering::SeqCst);
+        forget_if_idle(self.key);
+    }
+}
+
+/// A stream counted towards its account's ceiling for as long as it lives, which may be longer than its session.
+pub struct UserStream {
+    key: blake3::Hash,
+    usage: Arc<UserUsage>,
+}
+
+impl Drop for UserStream {
+    fn drop(&mut self) {
+        self.usage.streams.fetch_sub(1, Ordering::SeqCst);
+        forget_if_idle(self.key);
+    }
+}
+
+fn forget_if_idle(key: blake3::Hash) {
+    USAGE.remove_if(&key, |_, usage| usage.is_idle());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn increments_stop_at_the_ceiling() {
+        let counter = AtomicUsize::new(0);
+        assert!(try_increment(&counter, 2));
+        assert!(try_increment(&counter, 2));
+        assert!(!try_increment(&counter, 2));
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+        counter.fetch_sub(1, Ordering::SeqCst);
+        assert!(try_increment(&counter, 2));
+    }
+}
@@ -1,6 +1,12 @@
-use std::net::SocketAddr;
+use std::{collections::BTreeMap, net::SocketAddr};
 
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use ed25519_dalek::VerifyingKey;
 use serde::{Deserialize, Serialize};
+use stdcode::StdcodeSerializeExt;
+
+use crate::{ExitDescriptor, RouteDescriptor, Signed};
 
 #[derive(Serialize, Deserialize, Clone, Debug, Hash, PartialEq, Eq)]
 pub struct BridgeDescriptor {
@@ -9,3 +15,132 @@ pub struct BridgeDescriptor {
     pub pool: String,
     pub expiry: u64,
 }
+
+/// How many bytes a bridge relayed since its last report, which bridges send every few minutes.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BridgeUsage {
+    pub control_listen: SocketAddr,
+    pub pool: String,
+    /// Bytes relayed in both directions, keyed by the b2e address of the exit they went to or came from.
+    pub bytes_per_exit: BTreeMap<SocketAddr, u64>,
+    /// The limits the bridge puts on itself, which the broker takes into account when handing it out.
+    pub limits: BridgeLimits,
+    /// When the report was made, in seconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+/// How fast a bridge relays at most, in kilobytes per second counting both directions, where zero means no limit.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BridgeLimits {
+    /// The limit for each client address.
+    pub client_kbps: u32,
+    /// The limit for all clients together.
+    pub total_kbps: u32,
+}
+
+/// A request for how much the bridges of a pool relayed recently, made by whoever runs them.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BridgeUsageQuery {
+    pub pool: String,
+    /// The bridge asking, if it is a volunteer bridge, which only gets to see its own usage.
+    pub control_listen: Option<SocketAddr>,
+    /// When the request was made, in seconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+/// How many bytes one bridge relayed recently.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BridgeUsageSummary {
+    pub control_listen: SocketAddr,
+    /// Bytes relayed within the current and the previous hour.
+    pub bytes_last_hour: u64,
+    /// Bytes relayed within the last 24 hours.
+    pub bytes_last_day: u64,
+}
+
+/// What a volunteer bridge gets once it has proven its bandwidth: the pool it goes into, and a token of its own to authenticate to the broker with, in place of the token shared by the operators' bridges.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct VolunteerRegistration {
+    pub pool: String,
+    pub token: String,
+    /// When the token stops being accepted, in seconds since the Unix epoch, after which the bridge has to register again.
+    pub expiry: u64,
+}
+
+pub const DOMAIN_BRIDGE_BUNDLE: &str = "bridge-bundle";
+
+/// A handful of bridge routes for someone who can't reach the broker, handed out over side channels such as email. Bundles are signed by the broker's master key.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BridgeBundle {
+    pub routes: Vec<BundledRoute>,
+    /// When the routes stop working, since bridges forward to exits for only so long.
+    pub expiry: u64,
+}
+
+/// A route through a bridge to one exit, along with what a client needs to talk to that exit.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BundledRoute {
+    pub exit_pubkey: VerifyingKey,
+    pub exit: ExitDescriptor,
+    pub route: RouteDescriptor,
+}
+
+const BUNDLE_BEGIN: &str = "-----BEGIN GEPH BRIDGES-----";
+const BUNDLE_END: &str = "-----END GEPH BRIDGES-----";
+
+/// Encodes a signed bundle as a block of text that survives being pasted around, such as in an email.
+pub fn encode_bridge_bundle(bundle: &Signed<BridgeBundle>) -> String {
+    let encoded = STANDARD.encode(bundle.stdcode());
+    let mut out = String::from(BUNDLE_BEGIN);
+    for line in encoded.as_bytes().chunks(64) {
+        out.push('\n');
+        out.push_str(std::str::from_utf8(line).unwrap());
+    }
+    out.push('\n');
+    out.push_str(BUNDLE_END);
+    out
+}
+
+/// Finds and decodes a signed bundle within some text, such as a whole email. The signature still needs to be checked.
+pub fn decode_bridge_bundle(text: &str) -> anyhow::Result<Signed<BridgeBundle>> {
+    let start = text.find(BUNDLE_BEGIN).context("no bridge bundle found")? + BUNDLE_BEGIN.len();
+    let end = start
+        + text[start..]
+            .find(BUNDLE_END)
+            .context("bridge bundle cut off")?;
+    let encoded: String = text[start..end]
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '>')
+        .collect();
+    Ok(stdcode::deserialize(&STANDARD.decode(encoded)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::SigningKey;
+
+    use super::*;
+
+    #[test]
+    fn bundle_survives_quoting() {
+        let sk = SigningKey::from_bytes(&[7; 32]);
+        let bundle = Signed::new(
+            BridgeBundle {
+                routes: vec![],
+                expiry: 1234,
+            },
+            DOMAIN_BRIDGE_BUNDLE,
+            &sk,
+        );
+        let quoted = encode_bridge_bundle(&bundle)
+            .lines()
+            .map(|line| format!("> {line}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let decoded = decode_bridge_bundle(&format!("thanks!\n\n{quoted}\n"))
+            .unwrap()
+            .verify(DOMAIN_BRIDGE_BUNDLE, |pk| *pk == sk.verifying_key())
+            .unwrap();
+        assert_eq!(decoded.expiry, 1234);
+    }
+}
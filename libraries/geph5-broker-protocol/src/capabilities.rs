@@ -0,0 +1,100 @@
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::Credential;
+
+/// The version of the broker protocol spoken here. It goes up whenever a change needs both sides to know about it, rather than just being an optional feature.
+pub const BROKER_PROTOCOL_VERSION: u32 = 1;
+
+/// Bridge-list requests may be challenged with puzzles, through `get_routes_challenged`.
+pub const FEATURE_ROUTES_CHALLENGE: &str = "routes-challenge";
+
+/// Exits may report their load, through `report_exit_load`.
+pub const FEATURE_EXIT_LOAD: &str = "exit-load";
+
+/// Signed lists are served for CDN caches and static mirrors.
+pub const FEATURE_MIRRORS: &str = "mirrors";
+
+/// Accounts may invite others into trust groups with bridges of their own.
+pub const FEATURE_TRUST_GROUPS: &str = "trust-groups";
+
+/// Clients may report which transports work for them, through `report_protocol_outcomes`, and get back per-country hints through `get_protocol_hints`.
+pub const FEATURE_PROTOCOL_HINTS: &str = "protocol-hints";
+
+/// Exits may report what they can do through `report_exit_features`, and clients may ask which exits reach IPv6 destinations through `get_ipv6_exits`.
+pub const FEATURE_IPV6_EXITS: &str = "ipv6-exits";
+
+/// Plus users may get grants for reverse port forwarding at exits, through `get_reverse_forward_grant`.
+pub const FEATURE_REVERSE_FORWARD: &str = "reverse-forward";
+
+/// What a broker supports, so that clients can use new features where they are available and fall back where they are not, without upgrading in lockstep with the broker.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Versions of the broker protocol the broker speaks.
+    pub protocol_versions: BTreeSet<u32>,
+    /// Kinds of routes the broker may hand out, such as `sosistab3` or `meek`.
+    pub transports: BTreeSet<String>,
+    /// Kinds of credentials the broker accepts, such as `secret`.
+    pub auth_schemes: BTreeSet<String>,
+    /// Optional features, such as [`FEATURE_ROUTES_CHALLENGE`].
+    #[serde(default)]
+    pub features: BTreeSet<String>,
+}
+
+impl Capabilities {
+    /// What brokers from before capabilities were advertised support, for talking to those.
+    pub fn legacy() -> Self {
+        Self {
+            protocol_versions: [0].into(),
+            transports: ["tcp", "sosistab3", "tls", "meek"]
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            auth_schemes: ["secret", "legacy_username_password"]
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            features: BTreeSet::new(),
+        }
+    }
+
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.contains(feature)
+    }
+
+    /// Whether the broker accepts this kind of credential.
+    pub fn accepts(&self, credential: &Credential) -> bool {
+        self.auth_schemes.contains(auth_scheme(credential))
+    }
+}
+
+/// The name that a kind of credential goes under in [`Capabilities::auth_schemes`].
+pub fn auth_scheme(credential: &Credential) -> &'static str {
+    match credential {
+        Credential::TestDummy => "test_dummy",
+        Credential::LegacyUsernamePassword { .. } => "legacy_username_password",
+        Credential::Secret(_) => "secret",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_capabilities_are_ignored() {
+        let caps: Capabilities = serde_json::from_value(serde_json::json!({
+            "protocol_versions": [1, 2],
+            "transports": ["sosistab3", "some_future_transport"],
+            "auth_schemes": ["secret"],
+            "features": ["routes-challenge", "some-future-feature"],
+            "some_future_field": true,
+        }))
+        .unwrap();
+        assert!(caps.has_feature(FEATURE_ROUTES_CHALLENGE));
+        assert!(!caps.has_feature(FEATURE_TRUST_GROUPS));
+        assert!(caps.accepts(&Credential::Secret("123".into())));
+        assert!(!caps.accepts(&Credential::TestDummy));
+    }
+}
@@ -26,6 +26,70 @@ pub struct ExitDescriptor {
     pub expiry: u64,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+/// A live report of how busy an exit is, which the broker uses to steer new users towards exits with room to spare.
+pub struct ExitLoad {
+    /// Current throughput, in kilobytes per second
+    pub kbps: f32,
+    /// The throughput the exit is limited to, in kilobytes per second
+    pub kbps_capacity: f32,
+    /// How many client sessions are open
+    pub sessions: u32,
+    /// How many streams are open
+    pub streams: u32,
+    /// How many streams the exit allows
+    pub stream_capacity: u32,
+    /// CPU usage, between 0 and 1
+    pub cpu: f32,
+    /// When the report was made, in seconds since the epoch
+    pub timestamp: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+/// What an exit can do beyond proxying to IPv4 destinations, which clients ask the broker about to decide what to send through it.
+pub struct ExitFeatures {
+    /// Whether the exit can reach IPv6 destinations
+    pub ipv6: bool,
+    /// Where the exit takes direct connections over obfuscated UDP, if anywhere
+    pub obfs_udp: Option<DirectUdpListener>,
+    /// Where the exit takes direct connections over obfuscated KCP, if anywhere
+    pub kcp: Option<DirectUdpListener>,
+    /// When the report was made, in seconds since the epoch
+    pub timestamp: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+/// The broker's permission for whoever holds it to have exits listen on public ports for reverse port forwarding, which exits check before listening.
+pub struct ReverseForwardGrant {
+    /// How many ports may be forwarded at once under this grant, at each exit
+    pub max_ports: u16,
+    /// When the grant stops being good, in seconds since the epoch. Exits end forwards made under it then.
+    pub expiry: u64,
+    /// Stands for the user the grant was issued to, the same across all their grants, so that exits count ports per user rather than per grant. It is a keyed hash of the user's ID, so exits can't tell who the user is.
+    pub holder: [u8; 32],
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+/// A listener for direct connections to an exit over UDP packets that are obfuscated with the cookie, which the broker hands out as a route alongside the bridges.
+pub struct DirectUdpListener {
+    pub addr: SocketAddr,
+    pub cookie: String,
+}
+
+impl ExitLoad {
+    /// The fraction of the exit's capacity that is still free, going by whichever resource is closest to running out.
+    pub fn remaining_capacity(&self) -> f32 {
+        let used = [
+            self.kbps / self.kbps_capacity.max(1.0),
+            self.streams as f32 / self.stream_capacity.max(1) as f32,
+            self.cpu,
+        ]
+        .into_iter()
+        .fold(0.0f32, f32::max);
+        (1.0 - used).clamp(0.0, 1.0)
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 /// This fully describes all the available exits in the system.
 pub struct ExitList {
@@ -47,3 +111,24 @@ impl ExitList {
             )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scarcest_resource_decides_capacity() {
+        let load = ExitLoad {
+            kbps: 500.0,
+            kbps_capacity: 1000.0,
+            sessions: 100,
+            streams: 900,
+            stream_capacity: 1000,
+            cpu: 0.2,
+            timestamp: 0,
+        };
+        assert!((load.remaining_capacity() - 0.1).abs() < 1e-6);
+        let overloaded = ExitLoad { cpu: 1.5, ..load };
+        assert_eq!(overloaded.remaining_capacity(), 0.0);
+    }
+}
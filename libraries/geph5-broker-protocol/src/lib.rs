@@ -2,6 +2,7 @@ use std::{collections::BTreeMap, fmt::Display, net::SocketAddr};
 
 use async_trait::async_trait;
 use bytes::Bytes;
+use ed25519_dalek::VerifyingKey;
 use mizaru2::{BlindedClientToken, BlindedSignature, ClientToken, UnblindedSignature};
 use nanorpc::nanorpc_derive;
 mod route;
@@ -16,11 +17,20 @@ pub mod puzzle;
 pub use mac::*;
 mod bridge;
 pub use bridge::*;
+mod mirror;
+pub use mirror::*;
+mod capabilities;
+pub use capabilities::*;
+mod telemetry;
+pub use telemetry::*;
 use thiserror::Error;
 
 #[nanorpc_derive]
 #[async_trait]
 pub trait BrokerProtocol {
+    // What this broker supports. Brokers from before this method existed do not answer it, and support what Capabilities::legacy says.
+    async fn get_capabilities(&self) -> Capabilities;
+
     async fn get_mizaru_subkey(&self, level: AccountLevel, epoch: u16) -> Bytes;
     async fn get_auth_token(&self, credential: Credential) -> Result<String, AuthError>;
     async fn get_user_info(&self, auth_token: String) -> Result<Option<UserInfo>, AuthError>;
@@ -45,19 +55,78 @@ pub trait BrokerProtocol {
         exit_b2e: SocketAddr,
     ) -> Result<RouteDescriptor, GenericError>;
 
+    /// Like `get_routes`, but while the broker sees bridges being enumerated at scale, it first answers with a puzzle, whose solution must come with the next call.
+    async fn get_routes_challenged(
+        &self,
+        token: ClientToken,
+        sig: UnblindedSignature,
+        exit_b2e: SocketAddr,
+        solution: Option<PuzzleSolution>,
+    ) -> Result<RoutesOrChallenge, GenericError>;
+
     async fn insert_exit(
         &self,
         descriptor: Mac<Signed<ExitDescriptor>>,
     ) -> Result<(), GenericError>;
 
+    async fn report_exit_load(&self, report: Mac<Signed<ExitLoad>>) -> Result<(), GenericError>;
+
+    async fn report_exit_features(
+        &self,
+        report: Mac<Signed<ExitFeatures>>,
+    ) -> Result<(), GenericError>;
+
+    // The exits that can currently reach IPv6 destinations
+    async fn get_ipv6_exits(&self) -> Result<Vec<VerifyingKey>, GenericError>;
+
+    // Lets a Plus user have exits listen on public ports for a while, through a grant the exits check
+    async fn get_reverse_forward_grant(
+        &self,
+        auth_token: String,
+    ) -> Result<Signed<ReverseForwardGrant>, GenericError>;
+
     async fn insert_bridge(&self, descriptor: Mac<BridgeDescriptor>) -> Result<(), GenericError>;
 
+    // Registers a volunteer bridge after timing a transfer from its control port, assigning it a pool and a token of its own
+    async fn register_volunteer_bridge(
+        &self,
+        control_listen: SocketAddr,
+        control_cookie: String,
+    ) -> Result<VolunteerRegistration, GenericError>;
+
+    // Checks that the broker can reach a bridge's control port from outside, so that bridges behind NAT can tell whether their ports are forwarded
+    async fn probe_bridge(
+        &self,
+        control_listen: SocketAddr,
+        control_cookie: String,
+    ) -> Result<(), GenericError>;
+
+    // How many bytes a bridge relayed to each exit since its last report
+    async fn report_bridge_usage(&self, usage: Mac<BridgeUsage>) -> Result<(), GenericError>;
+
+    // How much each bridge of a pool relayed recently, for whoever runs the pool
+    async fn get_bridge_usage(
+        &self,
+        query: Mac<BridgeUsageQuery>,
+    ) -> Result<Vec<BridgeUsageSummary>, GenericError>;
+
     async fn incr_stat(&self, stat: String, value: i32);
 
     async fn set_stat(&self, stat: String, value: f64);
 
     async fn upload_available(&self, data: AvailabilityData);
 
+    // Randomized counts of how dialing over each transport went, which the broker only keeps per country, aggregated over everyone. Only clients with a valid connect token may report, and only a few times per token
+    async fn report_protocol_outcomes(
+        &self,
+        token: ClientToken,
+        sig: UnblindedSignature,
+        outcomes: Vec<ProtocolOutcomes>,
+    ) -> Result<(), GenericError>;
+
+    // Transports ordered from most to least likely to work in the requester's country, empty if too few reports are in
+    async fn get_protocol_hints(&self) -> Result<Vec<String>, GenericError>;
+
     async fn get_puzzle(&self) -> (String, u16);
 
     async fn register_user_secret(
@@ -69,6 +138,9 @@ pub trait BrokerProtocol {
 
     async fn get_news(&self, lang: String) -> Result<Vec<NewsItem>, GenericError>;
 
+    /// Gets the currently active announcements, signed by the master key.
+    async fn get_announcements(&self) -> Result<Signed<AnnouncementList>, GenericError>;
+
     async fn raw_price_points(&self) -> Result<Vec<(u32, u32)>, GenericError>;
     async fn payment_methods(&self) -> Result<Vec<String>, GenericError>;
     async fn create_payment(
@@ -83,6 +155,22 @@ pub trait BrokerProtocol {
     // Redeem a voucher/gift card code to add credit to the user's account
     async fn redeem_voucher(&self, secret: String, code: String) -> Result<i32, GenericError>;
 
+    // Where the account stands in invite-based bridge distribution, if it takes part
+    async fn get_trust_info(&self, secret: String) -> Result<Option<TrustInfo>, GenericError>;
+
+    // Issue an invite code that brings someone else into the account's trust group
+    async fn issue_invite(&self, secret: String) -> Result<String, GenericError>;
+
+    // Join the trust group of whoever issued the invite code
+    async fn redeem_invite(&self, secret: String, code: String) -> Result<TrustInfo, GenericError>;
+
+    // Bridge routes set aside for the account's trust group
+    async fn get_group_routes(
+        &self,
+        secret: String,
+        exit_b2e: SocketAddr,
+    ) -> Result<RouteDescriptor, GenericError>;
+
     // Upload debug information for troubleshooting purposes
     async fn upload_debug_pack(
         &self,
@@ -97,6 +185,35 @@ pub struct VoucherInfo {
     pub explanation: BTreeMap<String, String>,
 }
 
+/// Where an account stands in invite-based bridge distribution. Accounts gain trust over time, and trusted enough accounts can invite others into their group. Every group gets bridges of its own, so that when one is blocked, suspicion falls on a handful of accounts.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TrustInfo {
+    pub trust: u32,
+    pub max_trust: u32,
+    /// The trust level at which the account may issue invites.
+    pub invite_trust: u32,
+    /// How many more invites the account may issue right now.
+    pub invites_left: u32,
+    /// When the account next gains trust, in seconds since the epoch, unless it is at the top already.
+    pub next_promotion: Option<u64>,
+    pub group_size: u32,
+    /// How many times bridges of the group were found blocked.
+    pub strikes: u32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PuzzleSolution {
+    pub puzzle: String,
+    pub solution: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoutesOrChallenge {
+    Routes(RouteDescriptor),
+    Challenge { puzzle: String, difficulty: u16 },
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct AvailabilityData {
     pub listen: String,
@@ -146,6 +263,14 @@ impl Default for Credential {
 
 pub const DOMAIN_EXIT_DESCRIPTOR: &str = "exit-descriptor";
 
+pub const DOMAIN_EXIT_LOAD: &str = "exit-load";
+
+pub const DOMAIN_EXIT_FEATURES: &str = "exit-features";
+
+pub const DOMAIN_ANNOUNCEMENT: &str = "announcement";
+
+pub const DOMAIN_REVERSE_FORWARD: &str = "reverse-forward";
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(transparent)]
 pub struct GenericError(pub String);
@@ -168,3 +293,46 @@ pub struct NewsItem {
     pub date_unix: u64,
     pub contents: String,
 }
+
+/// A message from the operators, such as a maintenance notice, to be shown to users in the app.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Announcement {
+    /// A stable, unique ID, so that clients can remember which announcements were already read.
+    pub id: String,
+    pub kind: AnnouncementKind,
+    pub title: String,
+    pub contents: String,
+    /// ISO 3166 country codes of the regions this is relevant to. Empty means everywhere.
+    pub regions: Vec<String>,
+    pub created_unix: u64,
+    pub expiry_unix: u64,
+}
+
+impl Announcement {
+    /// Whether this is relevant to someone in the given country. Without a country, only announcements for everywhere are.
+    pub fn relevant_to(&self, country: Option<&str>) -> bool {
+        self.regions.is_empty()
+            || country.is_some_and(|country| {
+                self.regions
+                    .iter()
+                    .any(|region| region.eq_ignore_ascii_case(country))
+            })
+    }
+}
+
+/// The active announcements, along with the country that the broker saw the request come from, so that clients can tell which regional announcements are for them.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AnnouncementList {
+    pub announcements: Vec<Announcement>,
+    /// The ISO 3166 code of the requester's country, if the broker could tell.
+    pub requester_country: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AnnouncementKind {
+    Info,
+    Maintenance,
+    UpdateRequired,
+    RegionalAdvice,
+}
@@ -0,0 +1,91 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ed25519_dalek::VerifyingKey;
+use serde::{Deserialize, Serialize};
+
+use crate::{AccountLevel, Signed};
+
+pub const DOMAIN_MIRRORED_EXITS: &str = "mirrored-exits";
+
+pub const DOMAIN_MIRRORED_ROUTES: &str = "mirrored-routes";
+
+/// A list that the broker signs for serving through CDN caches and static mirrors. The same list is served to everyone for a while, so that caches can hold on to it, and it carries its own expiry, so that a mirror can't keep serving it for longer than intended.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Mirrored<T> {
+    pub inner: T,
+    /// When the list stops being valid, in seconds since the epoch.
+    pub expiry: u64,
+}
+
+impl<T: Serialize> Signed<Mirrored<T>> {
+    /// Verifies a list fetched from a mirror against the broker's master key, returning what's inside if it has not expired yet.
+    pub fn verify_mirrored(self, domain: &str, master: &VerifyingKey) -> anyhow::Result<T> {
+        let mirrored = self.verify(domain, |pk| pk == master)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        anyhow::ensure!(mirrored.expiry > now, "mirrored list expired");
+        Ok(mirrored.inner)
+    }
+}
+
+/// Where mirrors serve the list of exits for an account level.
+pub fn mirrored_exits_path(level: AccountLevel) -> &'static str {
+    match level {
+        AccountLevel::Free => "/mirror/exits/free",
+        AccountLevel::Plus => "/mirror/exits/plus",
+    }
+}
+
+/// Where mirrors serve the bridge routes to an exit.
+pub fn mirrored_routes_path(exit: &VerifyingKey) -> String {
+    format!("/mirror/routes/{}", hex_pubkey(exit))
+}
+
+fn hex_pubkey(pk: &VerifyingKey) -> String {
+    pk.as_bytes().iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::SigningKey;
+
+    use super::*;
+
+    #[test]
+    fn mirrored_lists_need_key_and_freshness() {
+        let sk = SigningKey::from_bytes(&[3; 32]);
+        let other = SigningKey::from_bytes(&[4; 32]).verifying_key();
+        let signed = |expiry| {
+            Signed::new(
+                Mirrored {
+                    inner: 42u32,
+                    expiry,
+                },
+                DOMAIN_MIRRORED_ROUTES,
+                &sk,
+            )
+        };
+        let later = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 600;
+        assert_eq!(
+            signed(later)
+                .verify_mirrored(DOMAIN_MIRRORED_ROUTES, &sk.verifying_key())
+                .unwrap(),
+            42
+        );
+        assert!(signed(later)
+            .verify_mirrored(DOMAIN_MIRRORED_EXITS, &sk.verifying_key())
+            .is_err());
+        assert!(signed(later)
+            .verify_mirrored(DOMAIN_MIRRORED_ROUTES, &other)
+            .is_err());
+        assert!(signed(1)
+            .verify_mirrored(DOMAIN_MIRRORED_ROUTES, &sk.verifying_key())
+            .is_err());
+    }
+}
@@ -1,4 +1,8 @@
-use std::net::SocketAddr;
+use std::{
+    collections::BTreeMap,
+    net::{Ipv4Addr, SocketAddr},
+    time::SystemTime,
+};
 
 use serde::{Deserialize, Serialize};
 
@@ -7,14 +11,86 @@ use serde::{Deserialize, Serialize};
 /// This fully describes a route to a particular exit.
 pub enum RouteDescriptor {
     Tcp(SocketAddr),
+    /// Plain TCP with socket options: TCP Fast Open, and the DSCP value to mark packets with.
+    TunedTcp {
+        addr: SocketAddr,
+        #[serde(default)]
+        fast_open: bool,
+        #[serde(default)]
+        dscp: Option<u8>,
+    },
+    /// TCP to a port that hops around on the schedule, which the bridge forwards to the given address, so that blocking a port only works until the next hop.
+    PortHop {
+        addr: SocketAddr,
+        schedule: PortHopSchedule,
+    },
+    /// KCP over UDP packets obfuscated with the cookie, tuned by the client's own settings, for very lossy networks.
+    Kcp {
+        addr: SocketAddr,
+        cookie: String,
+    },
+    /// Experimental: KCP inside ICMP echo packets, reaching the given TCP port on the host, as a last resort for networks that only let pings through. Clients skip it unless they opt in.
+    Icmp {
+        addr: Ipv4Addr,
+        port: u16,
+    },
+    /// KCP inside DNS queries and answers, sent through the network's own resolver to the bridge that is authoritative for the domain, reaching the given TCP port on it. This is the last resort for networks where only DNS gets through.
+    Dns {
+        domain: String,
+        port: u16,
+    },
+    Quic {
+        addr: SocketAddr,
+        /// The congestion controller we use when sending, such as "bbr", "cubic", or "new_reno". Unknown ones fall back to the default.
+        #[serde(default)]
+        congestion: String,
+        /// The server name sent in the handshake, which the listener makes its certificate out to. Without one, no name is sent, like browsers reaching a bare IP address.
+        #[serde(default)]
+        sni_domain: Option<String>,
+    },
+    /// QUIC over UDP packets that are obfuscated with the cookie, for lossy networks where TCP-based routes crawl. The FEC fields set how many parity packets we add after how many data packets, and either being zero turns FEC off.
+    ObfsUdp {
+        addr: SocketAddr,
+        cookie: String,
+        #[serde(default)]
+        fec_data_shards: u8,
+        #[serde(default)]
+        fec_parity_shards: u8,
+        #[serde(default)]
+        congestion: String,
+    },
     Sosistab3 {
         cookie: String,
         lower: Box<RouteDescriptor>,
     },
     PlainTls {
         sni_domain: Option<String>,
+        /// The browser whose handshake to imitate, such as "chrome", "firefox", or "safari". Without one, or with an unknown one, the platform's TLS library is used as is.
+        #[serde(default)]
+        fingerprint: Option<String>,
+        /// A hex-encoded ECHConfigList, which hides the SNI from the wire. Routes with one always imitate a browser, since the platform's TLS library cannot do ECH.
+        #[serde(default)]
+        ech_config_list: Option<String>,
         lower: Box<RouteDescriptor>,
     },
+    Websocket {
+        host: String,
+        path: String,
+        lower: Box<RouteDescriptor>,
+    },
+    /// Meek-style HTTP requests with the given Host header and path, which can be domain-fronted through a CDN by making the lower route a TLS connection to it.
+    Meek {
+        host: String,
+        path: String,
+        lower: Box<RouteDescriptor>,
+    },
+    /// A pluggable transport, such as obfs4, with its per-bridge arguments. Clients run it if they have an executable for it configured, and fail the route otherwise.
+    PluggableTransport {
+        transport: String,
+        #[serde(default)]
+        args: BTreeMap<String, String>,
+        addr: SocketAddr,
+    },
     Race(Vec<RouteDescriptor>),
     Fallback(Vec<RouteDescriptor>),
     Timeout {
@@ -33,3 +109,33 @@ pub enum RouteDescriptor {
     #[serde(untagged)]
     Other(serde_json::Value),
 }
+
+/// Which port a bridge listens on at any time, for each port it forwards. Time is split into periods of `period_secs` since the Unix epoch, and each gets a port between `min_port` and `max_port` that is picked by hashing the seed, the forwarded port, and the period.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PortHopSchedule {
+    pub seed: String,
+    pub period_secs: u64,
+    pub min_port: u16,
+    pub max_port: u16,
+}
+
+impl PortHopSchedule {
+    /// The period that it is now.
+    pub fn current_period(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            / self.period_secs.max(1)
+    }
+
+    /// The port that stands in for the forwarded port during the period.
+    pub fn port(&self, forwarded: u16, period: u64) -> u16 {
+        let mut hasher = blake3::Hasher::new_keyed(blake3::hash(self.seed.as_bytes()).as_bytes());
+        hasher.update(&forwarded.to_be_bytes());
+        hasher.update(&period.to_be_bytes());
+        let hash = u64::from_le_bytes(hasher.finalize().as_bytes()[..8].try_into().unwrap());
+        let span = self.max_port.saturating_sub(self.min_port) as u64 + 1;
+        self.min_port + (hash % span) as u16
+    }
+}
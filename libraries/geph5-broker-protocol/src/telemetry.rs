@@ -0,0 +1,47 @@
+use serde::{Deserialize, Serialize};
+
+/// How likely a client is to report the opposite of how a dial went. Any single report is deniable, while the broker can still estimate success rates over many of them.
+pub const FLIP_PROBABILITY: f64 = 0.25;
+
+/// How dialing over one transport went for a client, through randomized response: every outcome is counted as the opposite with probability [`FLIP_PROBABILITY`] before it is sent.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ProtocolOutcomes {
+    /// The transport's name, as in [`crate::Capabilities::transports`].
+    pub protocol: String,
+    pub successes: u32,
+    pub failures: u32,
+}
+
+/// Randomizes one outcome before it is counted, given a coin toss drawn uniformly from `[0, 1)`.
+pub fn randomize_outcome(success: bool, coin: f64) -> bool {
+    if coin < FLIP_PROBABILITY {
+        !success
+    } else {
+        success
+    }
+}
+
+/// Estimates the true success rate behind randomized reports, or None if there are none.
+pub fn debiased_success_rate(successes: f64, failures: f64) -> Option<f64> {
+    let total = successes + failures;
+    if total <= 0.0 {
+        return None;
+    }
+    let reported = successes / total;
+    Some(((reported - FLIP_PROBABILITY) / (1.0 - 2.0 * FLIP_PROBABILITY)).clamp(0.0, 1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debiasing_undoes_randomization() {
+        // 80% true successes, reported as expected under randomized response
+        let successes = 0.8 * (1.0 - FLIP_PROBABILITY) + 0.2 * FLIP_PROBABILITY;
+        let rate = debiased_success_rate(successes * 1000.0, (1.0 - successes) * 1000.0).unwrap();
+        assert!((rate - 0.8).abs() < 1e-9);
+        assert_eq!(debiased_success_rate(0.0, 0.0), None);
+        assert_eq!(debiased_success_rate(0.0, 10.0), Some(0.0));
+    }
+}
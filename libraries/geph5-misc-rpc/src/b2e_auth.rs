@@ -0,0 +1,110 @@
+//! The handshake that opens an authenticated b2e connection, before picomux takes over. The bridge starts with [`B2E_AUTH_MAGIC`], whose first byte no picomux connection starts with, so that exits still take connections from bridges that predate authentication on the same port. Both sides then prove that they know the bridge's key: the bridge because the broker issued it, and the exit because it derives it from the token it shares with the broker.
+
+use anyhow::Context;
+use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use serde::{Deserialize, Serialize};
+use stdcode::StdcodeSerializeExt;
+
+use crate::bridge::{b2e_key, B2eCredential};
+
+/// What an authenticated b2e connection starts with.
+pub const B2E_AUTH_MAGIC: [u8; 16] = *b"geph5-b2e-auth/1";
+
+/// The longest handshake message we read, which is far more than any real one.
+const MAX_MESSAGE_LEN: usize = 4096;
+
+#[derive(Serialize, Deserialize)]
+struct BridgeHello {
+    bridge_id: String,
+    nonce: [u8; 32],
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExitResponse {
+    nonce: [u8; 32],
+    proof: [u8; 32],
+}
+
+/// A proof of knowing the key, as a [`blake3::Hash`], whose equality is checked in constant time so that comparing proofs leaks nothing about the expected one.
+fn proof(key: &[u8; 32], role: &str, first: &[u8; 32], second: &[u8; 32]) -> blake3::Hash {
+    blake3::Hasher::new_keyed(key)
+        .update(role.as_bytes())
+        .update(first)
+        .update(second)
+        .finalize()
+}
+
+async fn write_message(conn: &mut (impl AsyncWrite + Unpin), msg: &[u8]) -> std::io::Result<()> {
+    conn.write_all(&(msg.len() as u16).to_be_bytes()).await?;
+    conn.write_all(msg).await?;
+    conn.flush().await
+}
+
+async fn read_message(conn: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<Vec<u8>> {
+    let mut len_buf = [0u8; 2];
+    conn.read_exact(&mut len_buf).await?;
+    let len = u16::from_be_bytes(len_buf) as usize;
+    anyhow::ensure!(len <= MAX_MESSAGE_LEN, "handshake message too long");
+    let mut msg = vec![0u8; len];
+    conn.read_exact(&mut msg).await?;
+    Ok(msg)
+}
+
+/// Authenticates a fresh connection to an exit with the bridge's credential, failing unless the exit proves that it knows the key too.
+pub async fn b2e_auth_bridge(
+    conn: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    credential: &B2eCredential,
+) -> anyhow::Result<()> {
+    let nonce: [u8; 32] = rand::random();
+    conn.write_all(&B2E_AUTH_MAGIC).await?;
+    write_message(
+        conn,
+        &BridgeHello {
+            bridge_id: credential.bridge_id.clone(),
+            nonce,
+        }
+        .stdcode(),
+    )
+    .await?;
+    let response: ExitResponse = stdcode::deserialize(&read_message(conn).await?)?;
+    anyhow::ensure!(
+        blake3::Hash::from(response.proof)
+            == proof(&credential.key, "exit", &nonce, &response.nonce),
+        "the exit does not know our key"
+    );
+    write_message(
+        conn,
+        proof(&credential.key, "bridge", &response.nonce, &nonce).as_bytes(),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Runs the exit's side of the handshake on a connection whose magic bytes were already read, returning the ID of the bridge once it has proven that it holds the key derived from the exit token.
+pub async fn b2e_auth_exit(
+    conn: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    exit_token: &str,
+) -> anyhow::Result<String> {
+    let hello: BridgeHello = stdcode::deserialize(&read_message(conn).await?)?;
+    let key = b2e_key(exit_token, &hello.bridge_id);
+    let nonce: [u8; 32] = rand::random();
+    write_message(
+        conn,
+        &ExitResponse {
+            nonce,
+            proof: proof(&key, "exit", &hello.nonce, &nonce).into(),
+        }
+        .stdcode(),
+    )
+    .await?;
+    let bridge_proof: [u8; 32] = read_message(conn)
+        .await?
+        .try_into()
+        .ok()
+        .context("garbled bridge proof")?;
+    anyhow::ensure!(
+        blake3::Hash::from(bridge_proof) == proof(&key, "bridge", &nonce, &hello.nonce),
+        "the bridge does not hold its key"
+    );
+    Ok(hello.bridge_id)
+}
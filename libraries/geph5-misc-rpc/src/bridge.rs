@@ -1,6 +1,7 @@
-use std::{net::SocketAddr, time::SystemTime};
+use std::{collections::BTreeMap, fmt::Write, net::SocketAddr, time::SystemTime};
 
 use async_trait::async_trait;
+use geph5_broker_protocol::PortHopSchedule;
 use nanorpc::nanorpc_derive;
 use serde::{Deserialize, Serialize};
 
@@ -19,6 +20,62 @@ pub enum ObfsProtocol {
     ConnTest(Box<Self>),
     PlainTls(Box<Self>),
     Sosistab3New(String, Box<Self>),
+    /// WebSocket with the given Host header and path, so that the bridge can sit behind a CDN.
+    Websocket(String, String, Box<Self>),
+    /// Meek-style HTTP requests with the given Host header and path, as a last resort where no long-lived connection survives.
+    Meek(String, String, Box<Self>),
+}
+
+/// What each stream carries on an authenticated b2e connection, in place of a bare [`B2eMetadata`].
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct B2eStreamMetadata {
+    pub metadata: B2eMetadata,
+    /// Which of the bridge's clients the stream is for. The bridge gives every client address its own number, which tells the exit nothing about the address itself.
+    pub client_id: u64,
+}
+
+/// What a bridge authenticates its connections to exits with, issued by the broker. The key is derived from the bridge's ID and the token that the broker shares with exits, so that exits can check it without asking the broker.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct B2eCredential {
+    pub bridge_id: String,
+    pub key: [u8; 32],
+}
+
+impl B2eCredential {
+    /// Issues the credential of the bridge with the given ID.
+    pub fn issue(exit_token: &str, bridge_id: String) -> Self {
+        Self {
+            key: b2e_key(exit_token, &bridge_id),
+            bridge_id,
+        }
+    }
+}
+
+/// The key of the bridge with the given ID.
+pub fn b2e_key(exit_token: &str, bridge_id: &str) -> [u8; 32] {
+    let master = blake3::derive_key("geph5 b2e credential", exit_token.as_bytes());
+    *blake3::keyed_hash(&master, bridge_id.as_bytes()).as_bytes()
+}
+
+/// A kind of listener that a bridge serves on every port that it forwards, each of which the broker hands out as a separate route, so that a single bridge serves users behind different kinds of filtering.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, Hash, PartialEq)]
+pub enum BridgeListener {
+    /// Sosistab3-obfuscated TCP.
+    Sosistab3,
+    /// WebSocket over TCP, with the given Host header and path.
+    Websocket(String, String),
+    /// QUIC, on the UDP port with the same number as each forwarded TCP port.
+    Quic,
+    /// A Tor-style pluggable transport with the given name, such as obfs4, which the bridge runs in front of a forwarded port when the broker asks for it with `pt_forward`.
+    PluggableTransport(String),
+}
+
+/// A pluggable transport that a bridge runs in front of a forwarded port, with what clients need to connect through it.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, Hash, PartialEq)]
+pub struct PtListener {
+    pub transport: String,
+    pub args: BTreeMap<String, String>,
+    pub addr: SocketAddr,
 }
 
 /// The RPC protocol that bridges expose, called by the broker.
@@ -26,4 +83,40 @@ pub enum ObfsProtocol {
 #[async_trait]
 pub trait BridgeControlProtocol {
     async fn tcp_forward(&self, b2e_dest: SocketAddr, metadata: B2eMetadata) -> SocketAddr;
+
+    /// Like `tcp_forward`, but with the given pluggable transport running in front of the forwarded port, or None if the bridge cannot run it. Bridges that predate pluggable transports do not have this method at all.
+    async fn pt_forward(
+        &self,
+        b2e_dest: SocketAddr,
+        metadata: B2eMetadata,
+        transport: String,
+    ) -> Option<PtListener>;
+
+    /// The domain that this bridge is the authoritative DNS server for, answering DNS tunnel queries, if it runs a DNS tunnel. Bridges that predate DNS tunnels do not have this method at all.
+    async fn dns_tunnel_domain(&self) -> Option<String>;
+
+    /// The listeners that this bridge serves. Bridges that predate multiple listeners do not have this method at all, and are treated like before.
+    async fn listeners(&self) -> Vec<BridgeListener>;
+
+    /// The schedule that this bridge hops ports on, if it does. Bridges that predate port hopping do not have this method at all.
+    async fn port_hop_schedule(&self) -> Option<PortHopSchedule>;
+
+    /// The answer to a bandwidth challenge, as made by [`bandwidth_proof`], which the broker times to measure the bridge's bandwidth when a volunteer bridge registers. Bridges that predate volunteer registration do not have this method at all.
+    async fn bandwidth_proof(&self, nonce: String, len: u32) -> String;
+
+    /// Gives the bridge the credential that it authenticates its connections to exits with, which the broker does before asking it to forward anything. Bridges that predate authenticated b2e connections do not have this method at all, and connect to exits like before.
+    async fn set_b2e_credential(&self, credential: B2eCredential);
+}
+
+/// The answer to a bandwidth challenge: `len` bytes of blake3 output keyed by the hash of the nonce, hex-encoded. Nobody can answer without the nonce, so the time from the challenge to the answer shows how fast the answer was sent.
+pub fn bandwidth_proof(nonce: &str, len: usize) -> String {
+    let mut raw = vec![0u8; len];
+    blake3::Hasher::new_keyed(blake3::hash(nonce.as_bytes()).as_bytes())
+        .finalize_xof()
+        .fill(&mut raw);
+    let mut out = String::with_capacity(len * 2);
+    for byte in raw {
+        write!(out, "{byte:02x}").unwrap();
+    }
+    out
 }
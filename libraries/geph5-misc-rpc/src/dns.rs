@@ -0,0 +1,26 @@
+//! The RPC protocol through which exits resolve hostnames for clients. A client opens a `dnsrpc$` stream and makes calls on it as newline-delimited JSON-RPC, so lookups go through the encrypted tunnel and the exit's own resolver, rather than as plaintext DNS to some resolver the exit forwards to.
+
+use std::net::IpAddr;
+
+use async_trait::async_trait;
+use nanorpc::nanorpc_derive;
+use serde::{Deserialize, Serialize};
+
+/// The protocol name of streams that carry [`ExitDnsProtocol`] calls.
+pub const DNS_RPC_PROTOCOL: &str = "dnsrpc";
+
+/// An address record of a hostname.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DnsRecord {
+    pub addr: IpAddr,
+    /// How many seconds the record may be cached for.
+    pub ttl: u32,
+}
+
+/// The RPC protocol that exits expose to clients over `dnsrpc$` streams.
+#[nanorpc_derive]
+#[async_trait]
+pub trait ExitDnsProtocol {
+    /// Resolves a hostname into all its A and AAAA records, with the IPv4 ones first. Fails if the name does not exist or is blocked by the session's filters.
+    async fn resolve(&self, host: String) -> Result<Vec<DnsRecord>, String>;
+}
@@ -7,11 +7,14 @@ use bipe::{BipeReader, BipeWriter};
 use bytes::Bytes;
 use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit};
 use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use geph5_broker_protocol::AccountLevel;
+use mizaru2::{ClientToken, UnblindedSignature};
 use pin_project::pin_project;
 use serde::{Deserialize, Serialize};
 use sillad::Pipe;
 
 use tap::Tap;
+use thiserror::Error;
 
 use crate::{read_prepend_length, write_prepend_length};
 
@@ -20,12 +23,43 @@ use crate::{read_prepend_length, write_prepend_length};
 /// to use.
 #[derive(Serialize, Deserialize)]
 pub struct ClientHello {
-    // The client's credentials
+    // The client's credentials, a stdcode-encoded ConnectCredential if the exit is run with a broker
     pub credentials: Bytes,
     // The initial cryptographic hello message
     pub crypt_hello: ClientCryptHello,
 }
 
+/// ConnectCredential is what clients put in the credentials of their ClientHello when the exit is run with a broker. The token is blindly signed by the broker with the key for one account level and one epoch, so an exit can check the level and freshness of a credential, but nobody, the broker included, can tell which account it belongs to.
+///
+/// It serializes exactly like the `(level, token, sig)` tuple that older clients send.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ConnectCredential {
+    pub level: AccountLevel,
+    pub token: ClientToken,
+    pub sig: UnblindedSignature,
+}
+
+impl ConnectCredential {
+    /// How many epochs away from the current one a credential may be, which allows for clock skew and for credentials fetched ahead of time.
+    pub const EPOCH_SLACK: u16 = 2;
+
+    /// Checks that the credential is fresh and was signed with the key for its level. This is expensive, so it is best done off the async executor.
+    pub fn verify(
+        &self,
+        plus_key: &mizaru2::PublicKey,
+        free_key: &mizaru2::PublicKey,
+    ) -> anyhow::Result<()> {
+        if self.sig.epoch.abs_diff(mizaru2::current_epoch()) > Self::EPOCH_SLACK {
+            anyhow::bail!("signature from wrong epoch")
+        }
+        let key = match self.level {
+            AccountLevel::Plus => plus_key,
+            AccountLevel::Free => free_key,
+        };
+        key.blind_verify(self.token, &self.sig)
+    }
+}
+
 /// ClientCryptHello is an enum representing the possible
 /// cryptographic methods available for authentication/encryption.
 #[derive(Serialize, Deserialize)]
@@ -34,6 +68,38 @@ pub enum ClientCryptHello {
     SharedSecretChallenge([u8; 32]),
     /// An X25519 public key to be used to add a layer of encryption
     X25519(x25519_dalek::PublicKey),
+    /// Another cryptographic hello, with a request to shape all traffic after the handshake with the given parameters
+    Shaped(sillad_shaping::ShapingParams, Box<ClientCryptHello>),
+    /// Another cryptographic hello, with a request to be told the limits of the session in the exit hello. Exits that predate it refuse such connections.
+    AskLimits(Box<ClientCryptHello>),
+    /// Another cryptographic hello, with a request for a resumption ticket in the exit hello, and the ticket from an earlier session with the same exit, if any. An exit that accepts the ticket does not check the credentials again. It goes outside of any request for limits, and exits that predate it refuse such connections.
+    Resume(Option<Bytes>, Box<ClientCryptHello>),
+}
+
+impl ClientCryptHello {
+    /// Separates the shaping request, if any, from the cryptographic hello within.
+    pub fn unshaped(&self) -> (&ClientCryptHello, Option<sillad_shaping::ShapingParams>) {
+        match self {
+            ClientCryptHello::Shaped(params, inner) => (inner, Some(*params)),
+            other => (other, None),
+        }
+    }
+
+    /// Separates the request for session limits, if any, from the cryptographic hello within. It goes outside of any shaping request.
+    pub fn without_limits_request(&self) -> (&ClientCryptHello, bool) {
+        match self {
+            ClientCryptHello::AskLimits(inner) => (inner, true),
+            other => (other, false),
+        }
+    }
+
+    /// Separates the request for a resumption ticket, if any, from the cryptographic hello within. The request may come with a ticket from an earlier session.
+    pub fn without_resumption(&self) -> (&ClientCryptHello, Option<Option<&Bytes>>) {
+        match self {
+            ClientCryptHello::Resume(ticket, inner) => (inner, Some(ticket.as_ref())),
+            other => (other, None),
+        }
+    }
 }
 
 /// ExitHello represents the response of the exit node to the initial
@@ -58,6 +124,95 @@ pub enum ExitHelloInner {
     SharedSecretResponse(blake3::Hash),
     /// An X25519 public key to be used in the key exchange process
     X25519(x25519_dalek::PublicKey),
+    /// Another response, along with the limits of the session, for clients that asked for them
+    Limited(SessionLimits, Box<ExitHelloInner>),
+    /// Another response, along with a fresh resumption ticket, for clients that asked for one. It goes outside of any limits.
+    Ticket(Bytes, Box<ExitHelloInner>),
+}
+
+impl ExitHelloInner {
+    /// Separates the session limits, if any, from the response within.
+    pub fn without_limits(self) -> (ExitHelloInner, Option<SessionLimits>) {
+        match self {
+            ExitHelloInner::Limited(limits, inner) => (*inner, Some(limits)),
+            other => (other, None),
+        }
+    }
+
+    /// Separates the resumption ticket, if any, from the response within.
+    pub fn without_ticket(self) -> (ExitHelloInner, Option<Bytes>) {
+        match self {
+            ExitHelloInner::Ticket(ticket, inner) => (*inner, Some(ticket)),
+            other => (other, None),
+        }
+    }
+}
+
+/// What an exit remembers about a session in a resumption ticket. Tickets are encrypted with a key that only the exit knows, so to clients they are opaque bytes.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TicketState {
+    pub level: AccountLevel,
+    pub token: ClientToken,
+    /// When the ticket stops being accepted, in seconds since the Unix epoch.
+    pub expiry: u64,
+}
+
+impl TicketState {
+    /// Encrypts the state into a ticket with the given key. The nonce is a keyed hash of the state, so that no randomness is needed and a nonce is only ever reused for the very same state.
+    pub fn seal(&self, key: &[u8; 32]) -> Bytes {
+        let plaintext = stdcode::serialize(self).unwrap();
+        let nonce: [u8; 12] = blake3::keyed_hash(key, &plaintext).as_bytes()[..12]
+            .try_into()
+            .unwrap();
+        let ciphertext = ChaCha20Poly1305::new_from_slice(key)
+            .unwrap()
+            .encrypt(&nonce.into(), plaintext.as_slice())
+            .unwrap();
+        let mut ticket = nonce.to_vec();
+        ticket.extend_from_slice(&ciphertext);
+        ticket.into()
+    }
+
+    /// Decrypts a ticket made by [`TicketState::seal`] with the same key. It is up to the caller to check the expiry.
+    pub fn open(ticket: &[u8], key: &[u8; 32]) -> anyhow::Result<Self> {
+        anyhow::ensure!(ticket.len() >= 12, "ticket too short");
+        let (nonce, ciphertext) = ticket.split_at(12);
+        let nonce: [u8; 12] = nonce.try_into().unwrap();
+        let plaintext = ChaCha20Poly1305::new_from_slice(key)
+            .unwrap()
+            .decrypt(&nonce.into(), ciphertext)
+            .ok()
+            .context("cannot decrypt ticket")?;
+        Ok(stdcode::deserialize(&plaintext)?)
+    }
+}
+
+/// The limits an exit enforces on a session, which depend on the account level, so that clients can show them.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SessionLimits {
+    /// How fast the session may go, in kilobytes per second, or None if it is not limited.
+    pub kbps: Option<u32>,
+    /// How much the session may go over that speed in a burst, in kilobytes.
+    pub burst_kb: u32,
+}
+
+/// Why an exit refused to proxy a TCP stream. The exit sends it as the only datagram of the stream right before closing it, so that clients can tell their users instead of just seeing the connection drop.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Error)]
+pub enum StreamRejection {
+    #[error("destination blocked by exit policy: port {0} is blocked")]
+    BlockedPort(u16),
+    #[error("destination blocked by exit policy: private address")]
+    PrivateAddress,
+    #[error("destination blocked by exit policy: blocked address range")]
+    BlockedRange,
+    #[error("destination blocked by exit policy: addresses in {0} are blocked")]
+    BlockedCountry(String),
+    #[error("destination blocked by exit policy: port {0} is only open to paying users")]
+    PaidPortOnly(u16),
+    #[error("too many connections open on this account at once")]
+    TooManyStreams,
+    #[error("connections opened too fast on this account, so refused for a while")]
+    Throttled,
 }
 
 /// ClientExitCryptPipe is a sillad::Pipe implementation representing an end-to-end encrypted connection between the client and the exit.
@@ -120,6 +275,11 @@ impl ClientExitCryptPipe {
             let fallible = async {
                 for read_nonce in 0u64.. {
                     let msg = read_prepend_length(&mut pipe_read).await?;
+                    if msg.is_empty() {
+                        // an empty message, which is never a valid ciphertext, means the other side half-closed
+                        write_incoming.close().await?;
+                        break;
+                    }
                     let read_nonce = [0; 12]
                         .tap_mut(|nonce| nonce[..8].copy_from_slice(&read_nonce.to_le_bytes()));
                     let plaintext = read_aead
@@ -143,6 +303,10 @@ impl ClientExitCryptPipe {
                     let write_nonce = [0; 12]
                         .tap_mut(|nonce| nonce[..8].copy_from_slice(&write_nonce.to_le_bytes()));
                     let n = read_outgoing.read(&mut buf).await?;
+                    if n == 0 {
+                        write_prepend_length(&[], &mut pipe_write).await?;
+                        break;
+                    }
                     let ciphertext = write_aead.encrypt(&write_nonce.into(), &buf[..n]).unwrap();
                     write_prepend_length(&ciphertext, &mut pipe_write).await?;
                 }
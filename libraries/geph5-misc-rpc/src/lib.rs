@@ -1,7 +1,10 @@
 use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+pub mod b2e_auth;
 pub mod bridge;
+pub mod dns;
 pub mod exit;
+pub mod udp;
 
 /// A helper function to write a length-prepended value into an AsyncWrite.
 pub async fn write_prepend_length<W: AsyncWrite + Unpin>(
@@ -0,0 +1,60 @@
+//! Framing for UDP relaying with full-cone NAT semantics. A client opens a `udpnat$` stream for every local UDP socket it relays, and the exit gives it one public UDP socket of its own: packets to any destination leave from the same address, and packets from anyone who learned that address come back. Every packet carries the address of the far end, as a datagram of the stream, or with a little-endian `u16` length prefix on the stream itself for clients that can't send datagrams.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::Context;
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// The protocol name of full-cone UDP relay streams.
+pub const UDP_NAT_PROTOCOL: &str = "udpnat";
+
+/// A relayed UDP packet, with the address it goes to on the way up, or came from on the way down.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UdpNatFrame {
+    pub peer: SocketAddr,
+    pub payload: Bytes,
+}
+
+impl UdpNatFrame {
+    /// Encodes the frame as the address family (4 or 6), the IP address, the port in big-endian, and then the payload.
+    pub fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(19 + self.payload.len());
+        match self.peer.ip() {
+            IpAddr::V4(ip) => {
+                buf.put_u8(4);
+                buf.put_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                buf.put_u8(6);
+                buf.put_slice(&ip.octets());
+            }
+        }
+        buf.put_u16(self.peer.port());
+        buf.put_slice(&self.payload);
+        buf.freeze()
+    }
+
+    /// Decodes a frame made by [`UdpNatFrame::encode`].
+    pub fn decode(bts: &[u8]) -> anyhow::Result<Self> {
+        let (&family, rest) = bts.split_first().context("empty UDP frame")?;
+        let (ip, rest): (IpAddr, &[u8]) = match family {
+            4 => {
+                anyhow::ensure!(rest.len() >= 4, "truncated IPv4 address");
+                let octets: [u8; 4] = rest[..4].try_into().unwrap();
+                (Ipv4Addr::from(octets).into(), &rest[4..])
+            }
+            6 => {
+                anyhow::ensure!(rest.len() >= 16, "truncated IPv6 address");
+                let octets: [u8; 16] = rest[..16].try_into().unwrap();
+                (Ipv6Addr::from(octets).into(), &rest[16..])
+            }
+            other => anyhow::bail!("unknown address family {other}"),
+        };
+        anyhow::ensure!(rest.len() >= 2, "truncated port");
+        let port = u16::from_be_bytes([rest[0], rest[1]]);
+        Ok(Self {
+            peer: SocketAddr::new(ip, port),
+            payload: Bytes::copy_from_slice(&rest[2..]),
+        })
+    }
+}
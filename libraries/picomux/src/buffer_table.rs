@@ -1,34 +1,49 @@
 use std::{
     hash::BuildHasherDefault,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 
 use ahash::AHasher;
+use bytes::Bytes;
 use dashmap::DashMap;
 use futures_intrusive::sync::SharedSemaphore;
 
-use crate::{frame::Frame, INIT_WINDOW, MAX_WINDOW};
+use crate::frame::Frame;
 
 #[allow(clippy::type_complexity)]
 type Inner = DashMap<
     u32,
-    (async_channel::Sender<(Frame, Instant)>, SharedSemaphore),
+    (
+        async_channel::Sender<(Frame, Instant)>,
+        SharedSemaphore,
+        async_channel::Sender<Bytes>,
+        Arc<AtomicBool>,
+    ),
     BuildHasherDefault<AHasher>,
 >;
 
+/// How many incoming datagrams a stream buffers before dropping new ones.
+const DATAGRAM_QUEUE: usize = 256;
+
 /// A table containing all the buffers for the streams within a mux.
 #[derive(Clone)]
 pub struct BufferTable {
     inner: Arc<Inner>,
+    max_queued: usize,
 }
 
 impl BufferTable {
-    pub fn new() -> Self {
+    /// Creates a table whose buffers each hold up to `max_queued` frames, beyond which the peer is sending past its window.
+    pub fn new(max_queued: usize) -> Self {
         Self {
             inner: Arc::new(DashMap::with_hasher(
                 BuildHasherDefault::<AHasher>::default(),
             )),
+            max_queued,
         }
     }
 
@@ -36,23 +51,36 @@ impl BufferTable {
         self.inner.contains_key(&id)
     }
 
-    pub fn create_entry(&self, stream_id: u32) -> BufferReceive {
+    /// Creates the buffer for a stream, which may initially send `send_window` frames, and compresses what it sends while `compress` is set. Also returns the receiver of the stream's datagrams.
+    pub fn create_entry(
+        &self,
+        stream_id: u32,
+        send_window: usize,
+        compress: Arc<AtomicBool>,
+    ) -> (BufferReceive, async_channel::Receiver<Bytes>) {
         let (send_incoming, recv_incoming) = async_channel::unbounded::<(Frame, Instant)>();
-        let send_more = SharedSemaphore::new(false, INIT_WINDOW);
-        self.inner.insert(stream_id, (send_incoming, send_more));
-        BufferReceive {
-            id: stream_id,
-            recv: recv_incoming,
-
-            inner: self.inner.clone(),
-
-            queue_delay: None,
-        }
+        let send_more = SharedSemaphore::new(false, send_window);
+        let (send_datagram, recv_datagram) = async_channel::bounded(DATAGRAM_QUEUE);
+        self.inner.insert(
+            stream_id,
+            (send_incoming, send_more, send_datagram, compress),
+        );
+        (
+            BufferReceive {
+                id: stream_id,
+                recv: recv_incoming,
+
+                inner: self.inner.clone(),
+
+                queue_delay: None,
+            },
+            recv_datagram,
+        )
     }
 
     pub fn send_to(&self, stream_id: u32, frame: Frame) {
         if let Some(inner) = self.inner.get(&stream_id) {
-            if inner.0.len() > MAX_WINDOW * 2 {
+            if inner.0.len() > self.max_queued {
                 tracing::warn!(
                     stream_id,
                     frame = debug(frame.header),
@@ -64,6 +92,29 @@ impl BufferTable {
         }
     }
 
+    /// Delivers a datagram to the given stream, dropping it if the stream is gone or is not keeping up.
+    pub fn send_datagram(&self, stream_id: u32, body: Bytes) {
+        if let Some(inner) = self.inner.get(&stream_id) {
+            if inner.2.try_send(body).is_err() {
+                tracing::trace!(stream_id, "datagram queue is full, so dropping datagram");
+            }
+        }
+    }
+
+    /// Turns compression of what the given stream sends on or off.
+    pub fn set_compression(&self, stream_id: u32, enabled: bool) {
+        if let Some(inner) = self.inner.get(&stream_id) {
+            inner.3.store(enabled, Ordering::Relaxed);
+        }
+    }
+
+    /// The current send window of the given stream.
+    pub fn send_window(&self, stream_id: u32) -> usize {
+        self.inner
+            .get(&stream_id)
+            .map_or(0, |inner| inner.1.permits())
+    }
+
     /// Waits until the send window for the given stream is at least 1, then decrement it by 1.
     pub async fn wait_send_window(&self, stream_id: u32) {
         let semaph = if let Some(inner) = self.inner.get(&stream_id) {
@@ -1,5 +1,5 @@
 use bytemuck::{Pod, Zeroable};
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use futures_util::{AsyncRead, AsyncReadExt};
 use serde::{Deserialize, Serialize};
 
@@ -36,27 +36,85 @@ impl Frame {
         }
     }
 
-    /// Read a frame from an async reader.
-    pub async fn read(mut rdr: impl AsyncRead + Unpin) -> std::io::Result<Self> {
-        let mut header_buf = [0; std::mem::size_of::<Header>()];
-        rdr.read_exact(&mut header_buf).await?;
+    /// The bytes representation of the frame's header.
+    pub fn header_bytes(&self) -> [u8; HEADER_LEN] {
+        bytemuck::cast(self.header)
+    }
+}
+
+pub const HEADER_LEN: usize = std::mem::size_of::<Header>();
+
+/// How much buffer space a frame reader sets aside at a time.
+const READ_POOL_SIZE: usize = 256 * 1024;
+
+/// Reads frames, carving their bodies out of a pooled buffer instead of allocating each one separately.
+pub struct FrameReader<R> {
+    inner: R,
+    pool: BytesMut,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            pool: BytesMut::new(),
+        }
+    }
+
+    /// Reads the next frame.
+    pub async fn read(&mut self) -> std::io::Result<Frame> {
+        let mut header_buf = [0; HEADER_LEN];
+        self.inner.read_exact(&mut header_buf).await?;
         let header: Header = bytemuck::cast(header_buf);
         let len = header.body_len as usize;
-        let mut body = vec![0; len];
-        rdr.read_exact(&mut body).await?;
-        Ok(Self {
+        if self.pool.capacity() < len {
+            // this reclaims the old allocation if every body carved out of it is gone
+            self.pool.reserve(READ_POOL_SIZE);
+        }
+        self.pool.resize(len, 0);
+        self.inner.read_exact(&mut self.pool).await?;
+        Ok(Frame {
             header,
-            body: body.into(),
+            body: self.pool.split().freeze(),
         })
     }
+}
+
+/// The zstd level that frames are compressed at, which is about as fast as LZ4 on the small bodies of frames while compressing better.
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compresses the body of a PSH frame with the best algorithm the peer understands, returning the command and body to send instead, unless the peer understands none or compressing doesn't make the body smaller.
+pub fn compress_body(peer: &Settings, body: &[u8]) -> Option<(u8, Bytes)> {
+    let (command, compressed) = if peer.zstd {
+        (CMD_PSHZSTD, zstd::bulk::compress(body, ZSTD_LEVEL).ok()?)
+    } else if peer.lz4 {
+        (CMD_PSHZ, lz4_flex::compress_prepend_size(body))
+    } else {
+        return None;
+    };
+    (compressed.len() < body.len()).then(|| (command, Bytes::from(compressed)))
+}
 
-    /// The bytes representation of the frame.
-    pub fn bytes(&self) -> Bytes {
-        let mut buf = vec![0; self.body.len() + std::mem::size_of::<Header>()];
-        buf[..std::mem::size_of::<Header>()].copy_from_slice(bytemuck::bytes_of(&self.header));
-        buf[std::mem::size_of::<Header>()..].copy_from_slice(&self.body);
-        buf.into()
+/// Decompresses the body of a PSHZ or PSHZSTD frame, refusing anything that would not fit in a frame once decompressed.
+pub fn decompress_body(command: u8, body: &[u8]) -> std::io::Result<Bytes> {
+    let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidData, msg);
+    if command == CMD_PSHZSTD {
+        return zstd::bulk::decompress(body, u16::MAX as usize)
+            .map(Bytes::from)
+            .map_err(|e| invalid(format!("corrupt compressed frame: {e}")));
+    }
+    let declared = body
+        .get(..4)
+        .map(|len| u32::from_le_bytes(len.try_into().unwrap()))
+        .ok_or_else(|| invalid("truncated compressed frame".into()))?;
+    if declared > u16::MAX as u32 {
+        return Err(invalid(format!(
+            "compressed frame expands to {declared} bytes"
+        )));
     }
+    lz4_flex::decompress_size_prepended(body)
+        .map(Bytes::from)
+        .map_err(|e| invalid(format!("corrupt compressed frame: {e}")))
 }
 
 #[derive(Clone, Copy, Pod, PartialEq, Zeroable, Debug)]
@@ -73,6 +131,18 @@ pub const CMD_FIN: u8 = 1;
 pub const CMD_PSH: u8 = 2;
 pub const CMD_NOP: u8 = 3;
 pub const CMD_MORE: u8 = 4;
+pub const CMD_DGRAM: u8 = 5;
+pub const CMD_GOAWAY: u8 = 6;
+/// Like PSH, but with an LZ4-compressed body that is prefixed with its uncompressed length.
+pub const CMD_PSHZ: u8 = 7;
+/// Asks the peer to turn compression of its side of a stream on (body `[1]`) or off (body `[0]`).
+pub const CMD_COMPRESS: u8 = 8;
+/// Like PSH, but with a zstd-compressed body.
+pub const CMD_PSHZSTD: u8 = 9;
+/// Asks the peer to weight its side of a stream, with the weight as the only byte of the body.
+pub const CMD_PRIORITY: u8 = 10;
+/// Refuses or aborts a stream. Unlike FIN, it always ends both directions, and the stream's slot is released at once.
+pub const CMD_RST: u8 = 11;
 
 pub const CMD_PING: u8 = 0xa0;
 pub const CMD_PONG: u8 = 0xa1;
@@ -81,3 +151,34 @@ pub const CMD_PONG: u8 = 0xa1;
 pub struct PingInfo {
     pub next_ping_in_ms: u32,
 }
+
+/// Settings each side announces when the session starts. They travel in the body of a NOP frame, which older peers ignore.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Settings {
+    /// How many frames the sender lets the peer send on a stream the sender opens, before the first window increase.
+    pub init_window: usize,
+    /// Whether the sender understands datagram frames.
+    #[serde(default)]
+    pub datagrams: bool,
+    /// Whether the sender understands GOAWAY frames.
+    #[serde(default)]
+    pub goaway: bool,
+    /// Whether the sender understands LZ4-compressed frames.
+    #[serde(default)]
+    pub lz4: bool,
+    /// Whether the sender understands zstd-compressed frames, which are preferred over LZ4 ones.
+    #[serde(default)]
+    pub zstd: bool,
+    /// Whether the sender understands PRIORITY frames.
+    #[serde(default)]
+    pub priority: bool,
+    /// The most streams the sender lets the peer have open at once.
+    #[serde(default)]
+    pub max_streams: Option<usize>,
+    /// Whether the sender treats FIN as closing only one direction of a stream, rather than the whole stream.
+    #[serde(default)]
+    pub half_close: bool,
+    /// Whether the sender understands RST frames.
+    #[serde(default)]
+    pub rst: bool,
+}
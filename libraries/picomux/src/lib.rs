@@ -2,6 +2,7 @@ mod bdp;
 mod buffer_table;
 mod frame;
 mod outgoing;
+mod stats;
 
 use std::{
     convert::Infallible,
@@ -10,8 +11,8 @@ use std::{
     ops::Deref,
     pin::Pin,
     sync::{
-        atomic::{AtomicU64, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        Arc, OnceLock,
     },
     task::Poll,
     time::{Duration, Instant},
@@ -25,28 +26,78 @@ use atomic_float::AtomicF64;
 use bdp::BwEstimate;
 use buffer_table::BufferTable;
 use bytes::Bytes;
-use frame::{Frame, CMD_FIN, CMD_MORE, CMD_NOP, CMD_PING, CMD_PONG, CMD_PSH, CMD_SYN};
+use frame::{
+    Frame, CMD_COMPRESS, CMD_DGRAM, CMD_FIN, CMD_GOAWAY, CMD_MORE, CMD_NOP, CMD_PING, CMD_PONG,
+    CMD_PRIORITY, CMD_PSH, CMD_PSHZ, CMD_PSHZSTD, CMD_RST, CMD_SYN,
+};
 use futures_lite::{Future, FutureExt as LiteExt};
 use futures_util::{
     future::Shared, io::BufReader, AsyncRead, AsyncWrite, AsyncWriteExt, FutureExt,
 };
 
 use async_io::Timer;
-use outgoing::Outgoing;
+use outgoing::{Outgoing, DEFAULT_WEIGHT};
 use parking_lot::Mutex;
 use pin_project::pin_project;
 use rand::Rng;
+use scopeguard::ScopeGuard;
+use serde::{Deserialize, Serialize};
 use smol_timeout2::TimeoutExt;
 use smolscale::reaper::TaskReaper;
 use tachyonix::{Receiver, Sender};
 use tap::Tap;
 
-use crate::frame::{Header, PingInfo};
+use crate::frame::{FrameReader, Header, PingInfo, Settings};
+
+pub use stats::StreamStats;
 
+/// The send window of a stream before the receiver says otherwise. Peers that predate window settings assume this for every stream.
 const INIT_WINDOW: usize = 10;
-const MAX_WINDOW: usize = 1500;
 const MSS: usize = 8192;
 
+/// How long a stream must wait for the peer's window before it counts as blocked.
+const BLOCKED_THRESHOLD: Duration = Duration::from_secs(1);
+
+type BlockedCallback = Arc<Mutex<Box<dyn Fn(Duration) + Send + Sync + 'static>>>;
+
+/// Flow-control limits on what the peer may send us: windows counted in frames of up to 8 KiB, and how many streams it may open. They are announced to the peer when the session starts.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WindowConfig {
+    /// How many frames the peer may send on a new stream before hearing back from us.
+    pub init_window: usize,
+    /// The most frames we let the peer have in flight on one stream, however fast the stream is going.
+    pub max_window: usize,
+    /// The most frames we let the peer have in flight across all streams together. Every stream can always send at least one frame, so that none of them starves.
+    pub max_conn_window: usize,
+    /// The most streams the peer may have open with us at once. Peers that know the limit refuse to open more; streams beyond it are closed right away.
+    pub max_streams: Option<usize>,
+}
+
+impl Default for WindowConfig {
+    fn default() -> Self {
+        Self {
+            init_window: INIT_WINDOW,
+            max_window: 1500,
+            max_conn_window: usize::MAX,
+            max_streams: None,
+        }
+    }
+}
+
+impl WindowConfig {
+    /// Clamps the windows into what the wire format can express and what makes sense together.
+    fn normalized(self) -> Self {
+        let max_window = self.max_window.clamp(1, u16::MAX as usize);
+        Self {
+            init_window: self.init_window.clamp(1, max_window),
+            max_window,
+            max_conn_window: self.max_conn_window.max(1),
+            max_streams: self.max_streams,
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct LivenessConfig {
     pub ping_interval: Duration,
@@ -71,6 +122,50 @@ pub struct PicoMux {
     liveness: LivenessConfig,
 
     last_ping: Arc<Mutex<Option<Duration>>>,
+
+    send_goaway: async_channel::Sender<()>,
+    state: Arc<SessionState>,
+    peer_settings: Arc<OnceLock<Settings>>,
+}
+
+/// State of a session shared between its handle and its background task.
+#[derive(Default)]
+struct SessionState {
+    /// Whether either side has said it is going away.
+    local_goaway: AtomicBool,
+    remote_goaway: AtomicBool,
+    remote_goaway_event: async_event::Event,
+    /// How many streams opened by each side are open.
+    local_streams: AtomicUsize,
+    remote_streams: AtomicUsize,
+    /// Fires whenever a stream gives its slot back.
+    stream_closed_event: async_event::Event,
+}
+
+impl SessionState {
+    /// Gives back the slot of a stream opened by us if `local`, or by the peer otherwise.
+    fn release(&self, local: bool) {
+        if local {
+            self.local_streams.fetch_sub(1, Ordering::SeqCst);
+        } else {
+            self.remote_streams.fetch_sub(1, Ordering::SeqCst);
+        }
+        self.stream_closed_event.notify_all();
+    }
+
+    fn stream_count(&self) -> usize {
+        self.local_streams.load(Ordering::SeqCst) + self.remote_streams.load(Ordering::SeqCst)
+    }
+}
+
+/// Counts one more open stream, unless that would go over the limit. The stream gives its slot back when it closes.
+fn try_reserve(stream_count: &AtomicUsize, max_streams: Option<usize>) -> bool {
+    let max_streams = max_streams.unwrap_or(usize::MAX);
+    stream_count
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+            (n < max_streams).then_some(n + 1)
+        })
+        .is_ok()
 }
 
 impl PicoMux {
@@ -78,6 +173,15 @@ impl PicoMux {
     pub fn new(
         read: impl AsyncRead + 'static + Send + Unpin,
         write: impl AsyncWrite + Send + Unpin + 'static,
+    ) -> Self {
+        Self::with_windows(read, write, WindowConfig::default())
+    }
+
+    /// Creates a new picomux wrapping the given underlying connection, with the given flow-control windows.
+    pub fn with_windows(
+        read: impl AsyncRead + 'static + Send + Unpin,
+        write: impl AsyncWrite + Send + Unpin + 'static,
+        windows: WindowConfig,
     ) -> Self {
         let (send_open_req, recv_open_req) = tachyonix::channel(1);
         let (send_accepted, recv_accepted) = async_channel::bounded(100);
@@ -85,6 +189,9 @@ impl PicoMux {
         let liveness = LivenessConfig::default();
         send_liveness.try_send(liveness).unwrap();
         let last_ping = Arc::new(Mutex::new(None));
+        let (send_goaway, recv_goaway) = async_channel::bounded(1);
+        let state = Arc::new(SessionState::default());
+        let peer_settings = Arc::new(OnceLock::new());
         let task = smolscale::spawn(
             picomux_inner(
                 read,
@@ -93,6 +200,10 @@ impl PicoMux {
                 recv_open_req,
                 recv_liveness,
                 last_ping.clone(),
+                windows.normalized(),
+                recv_goaway,
+                state.clone(),
+                peer_settings.clone(),
             )
             .map(Arc::new),
         )
@@ -106,6 +217,10 @@ impl PicoMux {
             liveness,
 
             last_ping,
+
+            send_goaway,
+            state,
+            peer_settings,
         }
     }
 
@@ -144,17 +259,72 @@ impl PicoMux {
         *self.last_ping.lock()
     }
 
+    /// Announces that we are going away: neither side may open new streams from now on, but existing streams carry on until they finish. The peer is told with a GOAWAY frame if it understands them.
+    pub fn go_away(&self) {
+        if !self.state.local_goaway.swap(true, Ordering::SeqCst) {
+            let _ = self.send_goaway.try_send(());
+        }
+    }
+
+    /// Waits until the peer announces that it is going away, after which no new streams can be opened.
+    pub async fn wait_goaway(&self) {
+        self.state
+            .remote_goaway_event
+            .wait_until(|| {
+                self.state
+                    .remote_goaway
+                    .load(Ordering::SeqCst)
+                    .then_some(())
+            })
+            .await
+    }
+
+    /// How many streams are open on the session, whichever side opened them.
+    pub fn stream_count(&self) -> usize {
+        self.state.stream_count()
+    }
+
+    /// Waits until no streams are open on the session, such as to close it once a GOAWAY has let them all finish.
+    pub async fn wait_idle(&self) {
+        self.state
+            .stream_closed_event
+            .wait_until(|| (self.state.stream_count() == 0).then_some(()))
+            .await
+    }
+
     /// Opens a new stream to the peer, putting the given metadata in the stream.
     pub async fn open(&self, metadata: &[u8]) -> std::io::Result<Stream> {
+        if self.state.local_goaway.load(Ordering::SeqCst)
+            || self.state.remote_goaway.load(Ordering::SeqCst)
+        {
+            return Err(std::io::Error::new(
+                ErrorKind::ConnectionAborted,
+                "session is going away",
+            ));
+        }
+        // the slot is taken in the same step as the limit is checked, so that concurrent opens can't all squeeze under it
+        let max_streams = self.peer_settings.get().and_then(|s| s.max_streams);
+        if !try_reserve(&self.state.local_streams, max_streams) {
+            return Err(std::io::Error::new(
+                ErrorKind::ConnectionRefused,
+                "peer's stream limit reached, retry later",
+            ));
+        }
+        let reservation = scopeguard::guard((), |_| self.state.release(true));
         {
             tracing::debug!("forcing a ping based on open");
             let _ = self.send_liveness.try_send(self.liveness);
         }
         let (send, recv) = oneshot::channel();
-        let _ = self
+        if self
             .send_open_req
             .send((Bytes::copy_from_slice(metadata), send))
-            .await;
+            .await
+            .is_ok()
+        {
+            // the stream, once created, gives the slot back when it closes
+            ScopeGuard::into_inner(reservation);
+        }
         async {
             if let Ok(val) = recv.await {
                 Ok(val)
@@ -180,6 +350,7 @@ impl PicoMux {
 
 static MUX_ID_CTR: AtomicU64 = AtomicU64::new(0);
 
+#[allow(clippy::too_many_arguments)]
 #[tracing::instrument(skip_all, fields(mux_id=MUX_ID_CTR.fetch_add(1, Ordering::Relaxed)))]
 async fn picomux_inner(
     read: impl AsyncRead + 'static + Send + Unpin,
@@ -188,24 +359,78 @@ async fn picomux_inner(
     mut recv_open_req: Receiver<(Bytes, oneshot::Sender<Stream>)>,
     recv_liveness: async_channel::Receiver<LivenessConfig>,
     last_ping: Arc<Mutex<Option<Duration>>>,
+    windows: WindowConfig,
+    recv_goaway: async_channel::Receiver<()>,
+    state: Arc<SessionState>,
+    peer_settings: Arc<OnceLock<Settings>>,
 ) -> Result<Infallible, std::io::Error> {
     let reaper = TaskReaper::new();
-    let mut inner_read = BufReader::with_capacity(MSS * 4, read);
+    let mut inner_read = FrameReader::new(BufReader::with_capacity(MSS * 4, read));
 
     let outgoing = Outgoing::new(write);
+    // announce our settings before anything else, so that they reach the peer before any of our SYNs
+    outgoing.enqueue(Frame::new(
+        0,
+        CMD_NOP,
+        &serde_json::to_vec(&Settings {
+            init_window: windows.init_window,
+            datagrams: true,
+            goaway: true,
+            lz4: true,
+            zstd: true,
+            priority: true,
+            max_streams: windows.max_streams,
+            half_close: true,
+            rst: true,
+        })
+        .unwrap(),
+    ));
     let (send_pong, recv_pong) = async_channel::unbounded();
-    let buffer_table = BufferTable::new();
+    let buffer_table = BufferTable::new(windows.max_window * 2);
 
     let last_bw_estimate = Arc::new(AtomicF64::new(1_000_000.0));
+    let conn_window = Arc::new(AtomicUsize::new(0));
 
-    let create_stream = |stream_id, metadata: Bytes| {
-        let mut buffer_recv = buffer_table.create_entry(stream_id);
+    // `local` is whether we opened the stream. The peer announces its initial window before sending any SYN, so it applies to streams it opens; for streams we open, the peer starts from the default until it raises the window. Our own settings likewise reach the peer before any of our SYNs, so on streams we open, the peer starts from our initial window even if its settings haven't reached us yet. The stream's slot under the stream limit must already be reserved.
+    let create_stream = |stream_id, metadata: Bytes, local: bool| {
+        let send_window = match (local, peer_settings.get()) {
+            (false, Some(settings)) => settings.init_window,
+            _ => INIT_WINDOW,
+        };
+        let assumed_remote_window = if local {
+            windows.init_window
+        } else {
+            INIT_WINDOW
+        };
+        let compress = Arc::new(AtomicBool::new(false));
+        let (mut buffer_recv, recv_datagram) =
+            buffer_table.create_entry(stream_id, send_window, compress.clone());
         let (mut write_incoming, read_incoming) = bipe::bipe(MSS * 2);
         let (write_outgoing, mut read_outgoing) = bipe::bipe(MSS * 2);
+        let weight = Arc::new(AtomicU8::new(DEFAULT_WEIGHT));
+        outgoing.register_weight(stream_id, weight.clone());
+        let stats = Arc::new(StreamStats::default());
+        let fin_sent = Arc::new(AtomicBool::new(false));
+        let reset = Arc::new(AtomicBool::new(false));
+        let on_blocked: BlockedCallback = Arc::new(Mutex::new(Box::new(|_| {})));
         let stream = Stream {
             write_outgoing,
             read_incoming,
             metadata,
+            weight,
+            reset: reset.clone(),
+            id: stream_id,
+            outgoing: outgoing.clone(),
+            peer_settings: peer_settings.clone(),
+            compress: compress.clone(),
+            datagrams: Datagrams {
+                stream_id,
+                outgoing: outgoing.clone(),
+                peer_settings: peer_settings.clone(),
+                recv: recv_datagram,
+            },
+            stats: stats.clone(),
+            on_blocked: on_blocked.clone(),
             on_write: Box::new(|_| {}),
             on_read: Box::new(|_| {}),
         };
@@ -214,44 +439,87 @@ async fn picomux_inner(
         let outgoing_task = {
             let outgoing = outgoing.clone();
             let last_bw_estimate = last_bw_estimate.clone();
+            let stats = stats.clone();
+            let peer_settings = peer_settings.clone();
+            let fin_sent = fin_sent.clone();
+            let mut remote_window = ConnCredit::new(
+                conn_window.clone(),
+                windows.max_conn_window,
+                assumed_remote_window,
+            );
             async move {
-                let mut remote_window = INIT_WINDOW;
-                let mut target_remote_window = MAX_WINDOW;
+                let mut target_remote_window = windows.max_window;
+
+                // bring the peer up to our initial window, in case it started from the default
+                let quantum = remote_window.quantum(windows.init_window);
+                if quantum > 0 {
+                    outgoing.enqueue(Frame::new(
+                        stream_id,
+                        CMD_MORE,
+                        &(quantum as u16).to_le_bytes(),
+                    ));
+                    remote_window.grant(quantum);
+                }
 
                 let mut bw_estimate = BwEstimate::new(last_bw_estimate.load(Ordering::Relaxed));
                 loop {
                     let min_quantum = (target_remote_window / 10).clamp(1, 500);
                     let frame = buffer_recv.recv().await;
+                    if frame.header.command == CMD_RST {
+                        // the peer has no stream left to tell, so we skip the FIN
+                        reset.store(true, Ordering::SeqCst);
+                        fin_sent.store(true, Ordering::SeqCst);
+                        anyhow::bail!("stream reset by peer");
+                    }
                     if frame.header.command == CMD_FIN {
-                        anyhow::bail!("received remote FIN");
+                        if !peer_settings.get().is_some_and(|s| s.half_close) {
+                            anyhow::bail!("received remote FIN");
+                        }
+                        // the peer is done sending, but may still be reading. we keep our entry in the buffer table around, since we still need its send window.
+                        tracing::debug!(stream_id, "remote half-closed");
+                        write_incoming
+                            .close()
+                            .await
+                            .context("could not close incoming")?;
+                        return Ok(buffer_recv);
                     }
                     let queue_delay = buffer_recv.queue_delay().unwrap();
                     tracing::trace!(
                         stream_id,
                         queue_delay = debug(queue_delay),
-                        remote_window,
+                        remote_window = remote_window.get(),
                         target_remote_window,
                         "queue delay measured"
                     );
-                    bw_estimate.sample(frame.body.len());
+                    // a frame that doesn't decompress takes down only its own stream
+                    let body = match frame.header.command {
+                        CMD_PSHZ | CMD_PSHZSTD => {
+                            frame::decompress_body(frame.header.command, &frame.body)?
+                        }
+                        _ => frame.body,
+                    };
+                    bw_estimate.sample(body.len());
+                    stats
+                        .bytes_received
+                        .fetch_add(body.len() as _, Ordering::Relaxed);
                     write_incoming
-                        .write_all(&frame.body)
+                        .write_all(&body)
                         .await
                         .context("could not write to incoming")?;
-                    remote_window -= 1;
+                    remote_window.consume();
 
                     // assume the delay is 500ms
                     let estimate = bw_estimate.read();
                     last_bw_estimate.store(estimate, Ordering::Relaxed);
-                    target_remote_window =
-                        ((estimate / MSS as f64 / 2.0) as usize).clamp(INIT_WINDOW, MAX_WINDOW);
+                    target_remote_window = ((estimate / MSS as f64 / 2.0) as usize)
+                        .clamp(windows.init_window, windows.max_window);
                     tracing::debug!(
                         target_remote_window,
                         "setting target remote send window based on bw"
                     );
 
-                    if remote_window + min_quantum <= target_remote_window {
-                        let quantum = target_remote_window - remote_window;
+                    let quantum = remote_window.quantum(target_remote_window);
+                    if quantum >= min_quantum || (remote_window.get() == 0 && quantum > 0) {
                         outgoing.enqueue(Frame::new(
                             stream_id,
                             CMD_MORE,
@@ -259,13 +527,13 @@ async fn picomux_inner(
                         ));
                         tracing::debug!(
                             stream_id,
-                            remote_window,
+                            remote_window = remote_window.get(),
                             target_remote_window,
                             quantum,
                             queue_delay = debug(queue_delay),
                             "sending MORE"
                         );
-                        remote_window += quantum;
+                        remote_window.grant(quantum);
                     }
                 }
             }
@@ -274,55 +542,95 @@ async fn picomux_inner(
         let incoming_task = {
             let buffer_table = buffer_table.clone();
             let outgoing = outgoing.clone();
+            let peer_settings = peer_settings.clone();
+            let fin_sent = fin_sent.clone();
             async move {
+                let mut last_sent: Option<Instant> = None;
                 loop {
-                    let body = async_io_bufpool::pooled_read(&mut read_outgoing, 8192)
+                    let Some(body) = async_io_bufpool::pooled_read(&mut read_outgoing, 8192)
                         .await
                         .context("could not read_outgoing")?
-                        .context("EOF on read_outgoing")?;
+                    else {
+                        if !peer_settings.get().is_some_and(|s| s.half_close) {
+                            anyhow::bail!("EOF on read_outgoing");
+                        }
+                        // only our direction is done, so we tell the peer and keep reading
+                        tracing::debug!(stream_id, "half-closing");
+                        fin_sent.store(true, Ordering::SeqCst);
+                        outgoing.enqueue(Frame::new_empty(stream_id, CMD_FIN));
+                        return Ok(());
+                    };
 
                     tracing::trace!(
                         stream_id,
                         n = body.len(),
                         "sending outgoing data into channel"
                     );
+                    let body_len = body.len();
+                    let (command, body) = compress
+                        .load(Ordering::Relaxed)
+                        .then(|| peer_settings.get())
+                        .flatten()
+                        .and_then(|peer| frame::compress_body(peer, &body))
+                        .unwrap_or((CMD_PSH, body));
                     let frame = Frame {
                         header: Header {
                             version: 1,
-                            command: CMD_PSH,
+                            command,
                             body_len: body.len() as _,
                             stream_id,
                         },
                         body,
                     };
-                    buffer_table.wait_send_window(stream_id).await;
+                    if buffer_table.send_window(stream_id) == 0 {
+                        let start = Instant::now();
+                        let mut wait = std::pin::pin!(buffer_table.wait_send_window(stream_id));
+                        if wait.as_mut().timeout(BLOCKED_THRESHOLD).await.is_none() {
+                            tracing::debug!(stream_id, "blocked on the peer's window");
+                            (on_blocked.lock())(start.elapsed());
+                            wait.await;
+                        }
+                        stats.record_stall(start.elapsed(), last_sent.map(|t| t.elapsed()));
+                    } else {
+                        buffer_table.wait_send_window(stream_id).await;
+                    }
                     outgoing.send(frame).await?;
+                    last_sent = Some(Instant::now());
+                    stats.bytes_sent.fetch_add(body_len as _, Ordering::Relaxed);
                 }
             }
         };
 
         {
             let outgoing = outgoing.clone();
+            let state = state.clone();
             reaper.attach(smolscale::spawn(async move {
                 scopeguard::defer!({
-                    tracing::debug!(stream_id, "enqueuing FIN to the other side");
-                    outgoing.enqueue(Frame {
-                        header: Header {
-                            version: 1,
-                            command: CMD_FIN,
-                            body_len: 0,
-                            stream_id,
-                        },
-                        body: Bytes::new(),
-                    });
+                    state.release(local);
+                    outgoing.unregister_weight(stream_id);
+                    if !fin_sent.load(Ordering::SeqCst) {
+                        tracing::debug!(stream_id, "enqueuing FIN to the other side");
+                        outgoing.enqueue(Frame {
+                            header: Header {
+                                version: 1,
+                                command: CMD_FIN,
+                                body_len: 0,
+                                stream_id,
+                            },
+                            body: Bytes::new(),
+                        });
+                    }
                 });
-                let _: anyhow::Result<()> =
-                    incoming_task.race(outgoing_task).await.inspect_err(|e| {
-                        tracing::debug!(
-                            e = debug(e),
-                            "incoming/outgoing task for individual stream stopped"
-                        )
-                    });
+                // with half-closes, the stream lives until both directions are done; otherwise, whichever direction stops first takes the other down with an error
+                let _: anyhow::Result<_> =
+                    futures_util::future::try_join(incoming_task, outgoing_task)
+                        .await
+                        .inspect_err(|e| {
+                            tracing::debug!(
+                                e = debug(e),
+                                "incoming/outgoing task for individual stream stopped"
+                            )
+                        });
             }));
         }
         stream
@@ -344,7 +652,7 @@ async fn picomux_inner(
                 f.body = metadata.clone();
                 f.header.body_len = metadata.len() as _;
             }));
-            let stream = create_stream(stream_id, metadata);
+            let stream = create_stream(stream_id, metadata, true);
 
             let _ = request.send(stream);
         }
@@ -394,11 +702,34 @@ async fn picomux_inner(
         }
     };
 
+    // tell the peer we are going away, if it would understand
+    let goaway_loop = async {
+        if recv_goaway.recv().await.is_ok() {
+            if peer_settings.get().is_some_and(|s| s.goaway) {
+                tracing::debug!("sending GOAWAY");
+                outgoing.enqueue(Frame::new_empty(0, CMD_GOAWAY));
+            } else {
+                tracing::debug!("peer does not understand GOAWAY, so not sending it");
+            }
+        }
+        futures_util::future::pending().await
+    };
+
+    // refuse a SYN with RST, so that the opener tears the stream down; a peer that doesn't understand RST gets the old FIN
+    let refusal = |stream_id| {
+        if peer_settings.get().is_some_and(|s| s.rst) {
+            Frame::new_empty(stream_id, CMD_RST)
+        } else {
+            Frame::new_empty(stream_id, CMD_FIN)
+        }
+    };
+
     open_req_loop
         .race(ping_loop)
+        .race(goaway_loop)
         .race(async {
             loop {
-                let frame = Frame::read(&mut inner_read).await?;
+                let frame = inner_read.read().await?;
                 let stream_id = frame.header.stream_id;
                 tracing::trace!(
                     command = frame.header.command,
@@ -414,7 +745,17 @@ async fn picomux_inner(
                                 "duplicate SYN",
                             ));
                         }
-                        let stream = create_stream(stream_id, frame.body.clone());
+                        if state.local_goaway.load(Ordering::SeqCst) {
+                            tracing::debug!(stream_id, "going away, so refusing SYN");
+                            outgoing.enqueue(refusal(stream_id));
+                            continue;
+                        }
+                        if !try_reserve(&state.remote_streams, windows.max_streams) {
+                            tracing::debug!(stream_id, "too many streams, so refusing SYN");
+                            outgoing.enqueue(refusal(stream_id));
+                            continue;
+                        }
+                        let stream = create_stream(stream_id, frame.body.clone(), false);
                         if let Err(err) = send_accepted.try_send(stream) {
                             match err {
                                 async_channel::TrySendError::Full(_) => {
@@ -440,14 +781,44 @@ async fn picomux_inner(
                         );
                         buffer_table.incr_send_window(stream_id, window_increase);
                     }
-                    CMD_PSH | CMD_FIN => {
+                    // compressed frames are decompressed by their streams, so that a corrupt one fails only its stream
+                    CMD_PSH | CMD_PSHZ | CMD_PSHZSTD | CMD_FIN | CMD_RST => {
                         if frame.header.command == CMD_FIN {
                             tracing::debug!(stream_id, "FIN received");
                         }
                         buffer_table.send_to(stream_id, frame);
                     }
+                    CMD_COMPRESS => {
+                        buffer_table.set_compression(stream_id, frame.body.first() == Some(&1))
+                    }
+                    CMD_PRIORITY => {
+                        if let Some(&weight) = frame.body.first() {
+                            outgoing.set_weight(stream_id, weight.max(1))
+                        }
+                    }
 
-                    CMD_NOP => {}
+                    CMD_DGRAM => buffer_table.send_datagram(stream_id, frame.body),
+                    CMD_GOAWAY => {
+                        tracing::debug!("peer is going away");
+                        state.remote_goaway.store(true, Ordering::SeqCst);
+                        state.remote_goaway_event.notify_all();
+                    }
+                    CMD_NOP => {
+                        if !frame.body.is_empty() {
+                            match serde_json::from_slice::<Settings>(&frame.body) {
+                                Ok(settings) => {
+                                    tracing::debug!(
+                                        init_window = settings.init_window,
+                                        "received peer settings"
+                                    );
+                                    let _ = peer_settings.set(settings);
+                                }
+                                Err(err) => {
+                                    tracing::debug!(err = debug(err), "ignoring unknown settings")
+                                }
+                            }
+                        }
+                    }
                     CMD_PING => {
                         let ping_info: PingInfo =
                             serde_json::from_slice(&frame.body).map_err(|e| {
@@ -478,6 +849,59 @@ async fn picomux_inner(
         .await
 }
 
+/// The window a stream has granted the peer, counted against a window shared by the whole connection. The stream's share is given back when it goes away.
+struct ConnCredit {
+    total: Arc<AtomicUsize>,
+    max_total: usize,
+    mine: usize,
+}
+
+impl ConnCredit {
+    fn new(total: Arc<AtomicUsize>, max_total: usize, initial: usize) -> Self {
+        total.fetch_add(initial, Ordering::Relaxed);
+        Self {
+            total,
+            max_total,
+            mine: initial,
+        }
+    }
+
+    fn get(&self) -> usize {
+        self.mine
+    }
+
+    /// How much more we may grant to bring the window up to the target. While the window is empty, this is at least one frame regardless of the connection window.
+    fn quantum(&self, target: usize) -> usize {
+        let budget = self
+            .max_total
+            .saturating_sub(self.total.load(Ordering::Relaxed));
+        let quantum = target.saturating_sub(self.mine).min(budget);
+        if self.mine == 0 {
+            quantum.max(1)
+        } else {
+            quantum
+        }
+    }
+
+    fn grant(&mut self, amount: usize) {
+        self.mine += amount;
+        self.total.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    fn consume(&mut self) {
+        if self.mine > 0 {
+            self.mine -= 1;
+            self.total.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for ConnCredit {
+    fn drop(&mut self) {
+        self.total.fetch_sub(self.mine, Ordering::Relaxed);
+    }
+}
+
 #[pin_project]
 pub struct Stream {
     #[pin]
@@ -485,6 +909,15 @@ pub struct Stream {
     #[pin]
     write_outgoing: bipe::BipeWriter,
     metadata: Bytes,
+    weight: Arc<AtomicU8>,
+    reset: Arc<AtomicBool>,
+    id: u32,
+    outgoing: Outgoing,
+    peer_settings: Arc<OnceLock<Settings>>,
+    compress: Arc<AtomicBool>,
+    datagrams: Datagrams,
+    stats: Arc<StreamStats>,
+    on_blocked: BlockedCallback,
     on_write: Box<dyn Fn(usize) + Send + Sync + 'static>,
     on_read: Box<dyn Fn(usize) + Send + Sync + 'static>,
 }
@@ -500,6 +933,41 @@ impl Stream {
         &self.metadata
     }
 
+    /// Sets the scheduling weight of the stream, on both sides if the peer understands priorities. Under contention, streams get a share of the pipe proportional to their weight. The default is 16.
+    pub fn set_priority(&self, weight: u8) {
+        // a stream with no weight would never be scheduled, so the lowest weight is 1
+        let weight = weight.max(1);
+        self.weight.store(weight, Ordering::Relaxed);
+        if self.peer_settings.get().is_some_and(|s| s.priority) {
+            self.outgoing
+                .enqueue(Frame::new(self.id, CMD_PRIORITY, &[weight]));
+        }
+    }
+
+    /// Turns compression of the stream's data on or off, on both sides if the peer understands compression, with zstd if it understands that and LZ4 otherwise. Worth it for text-heavy protocols on slow links, but a waste of CPU for data that is already compressed or encrypted.
+    pub fn set_compression(&self, enabled: bool) {
+        self.compress.store(enabled, Ordering::Relaxed);
+        if self.peer_settings.get().is_some_and(|s| s.lz4 || s.zstd) {
+            self.outgoing
+                .enqueue(Frame::new(self.id, CMD_COMPRESS, &[enabled as u8]));
+        }
+    }
+
+    /// Returns a handle for sending and receiving datagrams alongside the stream.
+    pub fn datagrams(&self) -> Datagrams {
+        self.datagrams.clone()
+    }
+
+    /// Returns the live statistics of the stream.
+    pub fn stats(&self) -> Arc<StreamStats> {
+        self.stats.clone()
+    }
+
+    /// Sets a callback for when sending is blocked on the peer's window for longer than a second, which is called with how long it has been blocked so far.
+    pub fn set_on_blocked(&self, on_blocked: impl Fn(Duration) + Send + Sync + 'static) {
+        *self.on_blocked.lock() = Box::new(on_blocked);
+    }
+
     pub fn set_on_write(&mut self, on_write: impl Fn(usize) + Send + Sync + 'static) {
         self.on_write = Box::new(on_write);
     }
@@ -509,6 +977,58 @@ impl Stream {
     }
 }
 
+/// The datagrams of a stream. Unlike the stream's data, datagrams are not flow-controlled, and are dropped rather than queued whenever either side falls behind, so that losing one never holds up the others.
+#[derive(Clone)]
+pub struct Datagrams {
+    stream_id: u32,
+    outgoing: Outgoing,
+    peer_settings: Arc<OnceLock<Settings>>,
+    recv: async_channel::Receiver<Bytes>,
+}
+
+impl Datagrams {
+    /// Whether the peer understands datagrams. This is false until the peer's settings arrive at the start of the session, and forever for peers that predate datagrams.
+    pub fn is_supported(&self) -> bool {
+        self.peer_settings.get().is_some_and(|s| s.datagrams)
+    }
+
+    /// Sends a datagram, which may be silently dropped. Fails if the peer does not understand datagrams or the datagram is too big to fit in a frame.
+    pub fn send(&self, body: &[u8]) -> std::io::Result<()> {
+        if !self.is_supported() {
+            return Err(std::io::Error::new(
+                ErrorKind::Unsupported,
+                "peer does not support datagrams",
+            ));
+        }
+        if body.len() > u16::MAX as usize {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "datagram too big",
+            ));
+        }
+        if !self
+            .outgoing
+            .enqueue_datagram(Frame::new(self.stream_id, CMD_DGRAM, body))
+        {
+            tracing::trace!(stream_id = self.stream_id, "dropping outgoing datagram");
+        }
+        Ok(())
+    }
+
+    /// Receives the next datagram. Fails once the stream is gone.
+    pub async fn recv(&self) -> std::io::Result<Bytes> {
+        self.recv
+            .recv()
+            .await
+            .map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, "stream is gone"))
+    }
+
+    /// Receives a datagram that has already arrived, if there is one, without waiting.
+    pub fn try_recv(&self) -> Option<Bytes> {
+        self.recv.try_recv().ok()
+    }
+}
+
 impl AsyncRead for Stream {
     fn poll_read(
         self: Pin<&mut Self>,
@@ -521,8 +1041,15 @@ impl AsyncRead for Stream {
         } else {
             let this = self.project();
             let r = this.read_incoming.poll_read(cx, buf);
-            if r.is_ready() {
-                (this.on_read)(buf.len());
+            if let Poll::Ready(Ok(n)) = &r {
+                // a reset stream ends in an error, not a clean EOF
+                if *n == 0 && !buf.is_empty() && this.reset.load(Ordering::SeqCst) {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        ErrorKind::ConnectionReset,
+                        "stream reset by peer",
+                    )));
+                }
+                (this.on_read)(*n);
             }
             r
         }
@@ -543,8 +1070,8 @@ impl AsyncWrite for Stream {
         // } else {
         let this = self.project();
         let r = this.write_outgoing.poll_write(cx, buf);
-        if r.is_ready() {
-            (this.on_write)(buf.len());
+        if let Poll::Ready(Ok(n)) = &r {
+            (this.on_write)(*n);
         }
         r
         // }
@@ -579,6 +1106,7 @@ impl sillad::Pipe for Stream {
 mod tests {
     use super::*;
     use futures_lite::{AsyncReadExt, AsyncWriteExt};
+    use sillad::Pipe as _;
     use tracing_test::traced_test;
 
     async fn setup_picomux_pair() -> (PicoMux, PicoMux) {
@@ -615,4 +1143,303 @@ mod tests {
             a_proc.race(b_proc).await
         })
     }
+
+    #[traced_test]
+    #[test]
+    fn test_picomux_datagrams() {
+        smolscale::block_on(async move {
+            let (picomux_a, picomux_b) = setup_picomux_pair().await;
+            let stream_a = picomux_a.open(b"").await.unwrap();
+            let stream_b = picomux_b.accept().await.unwrap();
+            // by now, each side has seen the other's settings
+            let (dgram_a, dgram_b) = (stream_a.datagrams(), stream_b.datagrams());
+            assert!(dgram_b.is_supported());
+            dgram_b.send(b"ping").unwrap();
+            assert_eq!(&dgram_a.recv().await.unwrap()[..], b"ping");
+            dgram_a.send(b"pong").unwrap();
+            assert_eq!(&dgram_b.recv().await.unwrap()[..], b"pong");
+        })
+    }
+
+    #[traced_test]
+    #[test]
+    fn test_picomux_goaway() {
+        smolscale::block_on(async move {
+            let (picomux_a, picomux_b) = setup_picomux_pair().await;
+            let mut stream_a = picomux_a.open(b"").await.unwrap();
+            let mut stream_b = picomux_b.accept().await.unwrap();
+            picomux_b.go_away();
+            picomux_a.wait_goaway().await;
+            assert!(picomux_a.open(b"").await.is_err());
+            assert!(picomux_b.open(b"").await.is_err());
+            // existing streams keep working
+            stream_a.write_all(b"still here").await.unwrap();
+            let mut buf = [0u8; 10];
+            stream_b.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"still here");
+        })
+    }
+
+    #[traced_test]
+    #[test]
+    fn test_picomux_compression() {
+        smolscale::block_on(async move {
+            let (picomux_a, picomux_b) = setup_picomux_pair().await;
+            let mut stream_a = picomux_a.open(b"").await.unwrap();
+            let mut stream_b = picomux_b.accept().await.unwrap();
+            stream_b.set_compression(true);
+            let text = b"the quick brown fox jumps over the lazy dog. ".repeat(2000);
+            // b's data is compressed, and a mirrors the setting for its own
+            stream_b.write_all(&text).await.unwrap();
+            let mut buf = vec![0u8; text.len()];
+            stream_a.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, text);
+            stream_a.write_all(&text).await.unwrap();
+            stream_b.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, text);
+            assert!(stream_a.compress.load(Ordering::Relaxed));
+        })
+    }
+
+    #[traced_test]
+    #[test]
+    fn test_picomux_priority() {
+        smolscale::block_on(async move {
+            let (picomux_a, picomux_b) = setup_picomux_pair().await;
+            let mut stream_a = picomux_a.open(b"").await.unwrap();
+            let mut stream_b = picomux_b.accept().await.unwrap();
+            let mut buf = [0u8; 1];
+            // by the time b's data arrives, so have b's settings
+            stream_b.write_all(b"x").await.unwrap();
+            stream_a.read_exact(&mut buf).await.unwrap();
+            stream_a.set_priority(40);
+            stream_a.write_all(b"x").await.unwrap();
+            stream_b.read_exact(&mut buf).await.unwrap();
+            assert_eq!(stream_b.weight.load(Ordering::Relaxed), 40);
+        })
+    }
+
+    #[traced_test]
+    #[test]
+    fn test_picomux_corrupt_compressed_frame() {
+        smolscale::block_on(async move {
+            let (a_write, mut raw_read) = bipe::bipe(65536);
+            let (mut raw_write, a_read) = bipe::bipe(65536);
+            let picomux_a = PicoMux::new(a_read, a_write);
+            smolscale::spawn(async move {
+                futures_util::io::copy(&mut raw_read, &mut futures_util::io::sink()).await
+            })
+            .detach();
+            let mut raw = vec![];
+            for frame in [
+                Frame::new_empty(1, CMD_SYN),
+                Frame::new(1, CMD_PSHZSTD, b"not zstd at all"),
+                Frame::new_empty(3, CMD_SYN),
+                Frame::new(3, CMD_PSH, b"intact"),
+            ] {
+                raw.extend_from_slice(&frame.header_bytes());
+                raw.extend_from_slice(&frame.body);
+            }
+            raw_write.write_all(&raw).await.unwrap();
+            raw_write.flush().await.unwrap();
+            // the corrupt frame ends its own stream, but not the session
+            let mut corrupted = picomux_a.accept().await.unwrap();
+            let mut intact = picomux_a.accept().await.unwrap();
+            let mut buf = vec![];
+            let _ = corrupted.read_to_end(&mut buf).await;
+            assert!(buf.is_empty());
+            let mut buf = [0u8; 6];
+            intact.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"intact");
+        })
+    }
+
+    #[traced_test]
+    #[test]
+    fn test_picomux_custom_windows() {
+        smolscale::block_on(async move {
+            let (a_write, b_read) = bipe::bipe(1);
+            let (b_write, a_read) = bipe::bipe(1);
+            let windows = WindowConfig {
+                init_window: 100,
+                max_window: 200,
+                max_conn_window: 150,
+                max_streams: None,
+            };
+            let picomux_a = PicoMux::with_windows(a_read, a_write, windows);
+            let picomux_b = PicoMux::with_windows(b_read, b_write, windows);
+
+            let a_proc = async move {
+                let mut stream_a = picomux_a.open(b"").await.unwrap();
+                stream_a.write_all(&vec![1u8; 1 << 22]).await.unwrap();
+                stream_a.flush().await.unwrap();
+                futures_util::future::pending().await
+            };
+            let b_proc = async move {
+                let mut stream_b = picomux_b.accept().await.unwrap();
+                let mut buf = vec![0u8; 1 << 22];
+                stream_b.read_exact(&mut buf).await.unwrap();
+                assert!(buf.iter().all(|b| *b == 1));
+            };
+            a_proc.race(b_proc).await
+        })
+    }
+
+    #[traced_test]
+    #[test]
+    fn test_picomux_half_close() {
+        smolscale::block_on(async move {
+            let (picomux_a, picomux_b) = setup_picomux_pair().await;
+            let mut stream_a = picomux_a.open(b"").await.unwrap();
+            stream_a.write_all(b"hello").await.unwrap();
+            let mut stream_b = picomux_b.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            stream_b.read_exact(&mut buf).await.unwrap();
+            // make sure a has b's settings before half-closing
+            stream_b.write_all(b"hello").await.unwrap();
+            stream_a.read_exact(&mut buf).await.unwrap();
+
+            stream_a.close_write().await.unwrap();
+            let mut rest = vec![];
+            stream_b.read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty());
+            stream_b.write_all(b"still here").await.unwrap();
+            stream_b.close_write().await.unwrap();
+            let mut reply = vec![];
+            stream_a.read_to_end(&mut reply).await.unwrap();
+            assert_eq!(reply, b"still here");
+        })
+    }
+
+    #[traced_test]
+    #[test]
+    fn test_picomux_stream_limit() {
+        smolscale::block_on(async move {
+            let (a_write, b_read) = bipe::bipe(32768);
+            let (b_write, a_read) = bipe::bipe(32768);
+            let picomux_a = PicoMux::new(a_read, a_write);
+            let picomux_b = PicoMux::with_windows(
+                b_read,
+                b_write,
+                WindowConfig {
+                    max_streams: Some(1),
+                    ..Default::default()
+                },
+            );
+
+            let mut stream_a = picomux_a.open(b"").await.unwrap();
+            stream_a.write_all(b"hello").await.unwrap();
+            let mut stream_b = picomux_b.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            stream_b.read_exact(&mut buf).await.unwrap();
+            // b's settings came before anything else it sent, so a knows the limit by now
+            stream_b.write_all(b"world").await.unwrap();
+            stream_a.read_exact(&mut buf).await.unwrap();
+
+            let err = picomux_a.open(b"").await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+            drop(stream_a);
+            drop(stream_b);
+            for _ in 0..100 {
+                if picomux_a.open(b"").await.is_ok() {
+                    return;
+                }
+                Timer::after(Duration::from_millis(10)).await;
+            }
+            panic!("stream limit never freed up");
+        })
+    }
+
+    #[traced_test]
+    #[test]
+    fn test_picomux_refused_stream_resets() {
+        smolscale::block_on(async move {
+            let (a_write, b_read) = bipe::bipe(32768);
+            let (b_write, a_read) = bipe::bipe(32768);
+            let picomux_a = PicoMux::new(a_read, a_write);
+            // a has not heard b's limit yet, so both opens go out as SYNs, and b refuses the second
+            let _stream_a = picomux_a.open(b"").await.unwrap();
+            let mut refused = picomux_a.open(b"").await.unwrap();
+            let picomux_b = PicoMux::with_windows(
+                b_read,
+                b_write,
+                WindowConfig {
+                    max_streams: Some(1),
+                    ..Default::default()
+                },
+            );
+            let _stream_b = picomux_b.accept().await.unwrap();
+
+            let mut buf = [0u8; 5];
+            let err = refused
+                .read(&mut buf)
+                .timeout(Duration::from_secs(5))
+                .await
+                .expect("refused stream hung")
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+            for _ in 0..100 {
+                if picomux_a.state.local_streams.load(Ordering::SeqCst) == 1 {
+                    return;
+                }
+                Timer::after(Duration::from_millis(10)).await;
+            }
+            panic!("refused stream never released its slot");
+        })
+    }
+
+    #[traced_test]
+    #[test]
+    fn test_picomux_wait_idle() {
+        smolscale::block_on(async move {
+            let (a_write, b_read) = bipe::bipe(32768);
+            let (b_write, a_read) = bipe::bipe(32768);
+            let picomux_a = PicoMux::new(a_read, a_write);
+            let picomux_b = PicoMux::new(b_read, b_write);
+
+            let stream_a = picomux_a.open(b"").await.unwrap();
+            let stream_b = picomux_b.accept().await.unwrap();
+            assert_eq!(picomux_a.stream_count(), 1);
+            assert!(picomux_a
+                .wait_idle()
+                .timeout(Duration::from_millis(100))
+                .await
+                .is_none());
+            drop(stream_a);
+            drop(stream_b);
+            picomux_a
+                .wait_idle()
+                .timeout(Duration::from_secs(5))
+                .await
+                .expect("session never went idle");
+            assert_eq!(picomux_a.stream_count(), 0);
+        })
+    }
+
+    #[traced_test]
+    #[test]
+    fn test_picomux_concurrent_opens_respect_limit() {
+        smolscale::block_on(async move {
+            let (a_write, b_read) = bipe::bipe(32768);
+            let (b_write, a_read) = bipe::bipe(32768);
+            let picomux_a = PicoMux::new(a_read, a_write);
+            let picomux_b = PicoMux::with_windows(
+                b_read,
+                b_write,
+                WindowConfig {
+                    max_streams: Some(3),
+                    ..Default::default()
+                },
+            );
+
+            let mut stream_a = picomux_a.open(b"").await.unwrap();
+            let mut stream_b = picomux_b.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            stream_b.write_all(b"world").await.unwrap();
+            stream_a.read_exact(&mut buf).await.unwrap();
+
+            let opened = futures_util::future::join_all((0..5).map(|_| picomux_a.open(b""))).await;
+            assert_eq!(opened.iter().filter(|res| res.is_ok()).count(), 2);
+        })
+    }
 }
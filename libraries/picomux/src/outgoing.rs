@@ -1,9 +1,28 @@
-use std::sync::{Arc, OnceLock};
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::BuildHasherDefault,
+    io::IoSlice,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc, OnceLock,
+    },
+};
 
-use crossbeam_queue::SegQueue;
+use ahash::AHasher;
 use futures_lite::{AsyncWrite, AsyncWriteExt};
+use parking_lot::Mutex;
 
-use crate::frame::Frame;
+use crate::frame::{Frame, CMD_DGRAM, CMD_FIN, CMD_PSH, CMD_PSHZ, CMD_PSHZSTD, HEADER_LEN};
+
+/// The weight streams get unless told otherwise.
+pub const DEFAULT_WEIGHT: u8 = 16;
+
+/// How many frames a stream may have queued before its datagrams get dropped.
+const MAX_QUEUED_DATAGRAMS: usize = 64;
+
+/// The most frames, and roughly the most bytes, written in one go.
+const MAX_BATCH_FRAMES: usize = 32;
+const MAX_BATCH_BYTES: usize = 64 * 1024;
 
 /// A writer for outgoing data.
 #[derive(Clone)]
@@ -39,7 +58,7 @@ impl Outgoing {
                 if let Some(err) = self.err.get() {
                     return Some(Err(anyhow::anyhow!("{:?}", err)));
                 }
-                if self.inner.queue.len() < 10 {
+                if self.inner.sched.lock().len < 10 {
                     Some(anyhow::Ok(()))
                 } else {
                     None
@@ -57,26 +76,281 @@ impl Outgoing {
             body_len = outgoing.header.body_len,
             "sending outgoing frame"
         );
-        self.inner.queue.push(outgoing);
+        self.inner.sched.lock().push(outgoing);
+        self.inner.grow_signal.notify_one();
+    }
+
+    /// Enqueues a datagram frame, unless its stream already has too much queued, in which case the datagram is dropped and false is returned.
+    pub fn enqueue_datagram(&self, outgoing: Frame) -> bool {
+        {
+            let mut sched = self.inner.sched.lock();
+            let queued = sched
+                .streams
+                .get(&outgoing.header.stream_id)
+                .map_or(0, |queue| queue.frames.len());
+            if queued >= MAX_QUEUED_DATAGRAMS {
+                return false;
+            }
+            sched.push(outgoing);
+        }
         self.inner.grow_signal.notify_one();
+        true
+    }
+
+    /// Registers the weight of a stream, which the scheduler reads every time it considers the stream.
+    pub fn register_weight(&self, stream_id: u32, weight: Arc<AtomicU8>) {
+        self.inner.sched.lock().weights.insert(stream_id, weight);
+    }
+
+    /// Changes the weight of a registered stream, as the peer asked us to.
+    pub fn set_weight(&self, stream_id: u32, weight: u8) {
+        if let Some(registered) = self.inner.sched.lock().weights.get(&stream_id) {
+            registered.store(weight.max(1), Ordering::Relaxed);
+        }
+    }
+
+    /// Forgets the weight of a stream, which then gets the default weight for whatever it still has queued.
+    pub fn unregister_weight(&self, stream_id: u32) {
+        self.inner.sched.lock().weights.remove(&stream_id);
     }
 }
 
 #[derive(Default)]
 struct Inner {
-    queue: SegQueue<Frame>,
+    sched: Mutex<Scheduler>,
     grow_signal: async_event::Event,
     shrink_signal: async_event::Event,
 }
 
+type FastMap<K, V> = HashMap<K, V, BuildHasherDefault<AHasher>>;
+
+/// A stride scheduler, which shares the pipe between streams in proportion to their weights.
+///
+/// Control frames (SYN, MORE, PING, etc.) skip ahead of all stream data. Data, datagram, and FIN frames are queued per stream, so that a FIN never overtakes the data before it.
+#[derive(Default)]
+struct Scheduler {
+    control: VecDeque<Frame>,
+    streams: FastMap<u32, StreamQueue>,
+    weights: FastMap<u32, Arc<AtomicU8>>,
+    /// The pass of the stream served last, which newly active streams start from so that they cannot hog the pipe by having been idle.
+    virtual_time: f64,
+    len: usize,
+}
+
+struct StreamQueue {
+    frames: VecDeque<Frame>,
+    pass: f64,
+}
+
+impl Scheduler {
+    fn push(&mut self, frame: Frame) {
+        self.len += 1;
+        if matches!(
+            frame.header.command,
+            CMD_PSH | CMD_PSHZ | CMD_PSHZSTD | CMD_FIN | CMD_DGRAM
+        ) {
+            let virtual_time = self.virtual_time;
+            self.streams
+                .entry(frame.header.stream_id)
+                .or_insert_with(|| StreamQueue {
+                    frames: VecDeque::new(),
+                    pass: virtual_time,
+                })
+                .frames
+                .push_back(frame);
+        } else {
+            self.control.push_back(frame);
+        }
+    }
+
+    fn pop(&mut self) -> Option<Frame> {
+        if let Some(frame) = self.control.pop_front() {
+            self.len -= 1;
+            return Some(frame);
+        }
+        let (&stream_id, queue) = self
+            .streams
+            .iter_mut()
+            .min_by(|a, b| a.1.pass.total_cmp(&b.1.pass))?;
+        let frame = queue.frames.pop_front()?;
+        let weight = self
+            .weights
+            .get(&stream_id)
+            .map(|w| w.load(Ordering::Relaxed))
+            .unwrap_or(DEFAULT_WEIGHT)
+            .max(1);
+        self.virtual_time = queue.pass;
+        queue.pass += (frame.body.len() + 8) as f64 / weight as f64;
+        if queue.frames.is_empty() {
+            self.streams.remove(&stream_id);
+        }
+        self.len -= 1;
+        Some(frame)
+    }
+}
+
+/// Writes out frames in batches. Batches go out as a single vectored write referencing the frame bodies directly, falling back to copying into a reused buffer for the rest of any batch the writer doesn't take as a vectored write.
 async fn outgoing_loop(
     mut write: impl AsyncWrite + Send + Unpin + 'static,
     inner: Arc<Inner>,
 ) -> anyhow::Result<()> {
     scopeguard::defer!(inner.shrink_signal.notify_all());
+    let mut batch: Vec<Frame> = Vec::with_capacity(MAX_BATCH_FRAMES);
+    let mut headers: Vec<[u8; HEADER_LEN]> = Vec::with_capacity(MAX_BATCH_FRAMES);
+    let mut coalesced: Vec<u8> = Vec::with_capacity(MAX_BATCH_BYTES + 65536);
     loop {
-        let next = inner.grow_signal.wait_until(|| inner.queue.pop()).await;
+        let first = inner
+            .grow_signal
+            .wait_until(|| inner.sched.lock().pop())
+            .await;
+        let mut batch_bytes = first.body.len();
+        batch.push(first);
+        {
+            let mut sched = inner.sched.lock();
+            while batch.len() < MAX_BATCH_FRAMES && batch_bytes < MAX_BATCH_BYTES {
+                let Some(next) = sched.pop() else {
+                    break;
+                };
+                batch_bytes += next.body.len();
+                batch.push(next);
+            }
+        }
         inner.shrink_signal.notify_all();
-        write.write_all(&next.bytes()).await?;
+
+        headers.extend(batch.iter().map(|frame| frame.header_bytes()));
+        let mut slices: Vec<IoSlice> = headers
+            .iter()
+            .zip(batch.iter())
+            .flat_map(|(header, frame)| [IoSlice::new(header), IoSlice::new(&frame.body)])
+            .collect();
+        write_all_vectored(&mut write, &mut slices, &mut coalesced).await?;
+        headers.clear();
+        batch.clear();
+    }
+}
+
+/// Writes all the slices with vectored writes, for as long as the writer takes more than the first slice at a time. Once a write takes no more than that, as it always does with writers that don't really support vectored writes, the rest of the slices are copied into `coalesced` and written in one go. Only that one write falls back; the next one tries vectored writes again.
+async fn write_all_vectored(
+    write: &mut (impl AsyncWrite + Unpin),
+    mut slices: &mut [IoSlice<'_>],
+    coalesced: &mut Vec<u8>,
+) -> std::io::Result<()> {
+    IoSlice::advance_slices(&mut slices, 0);
+    while !slices.is_empty() {
+        let n = write.write_vectored(slices).await?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        let only_first = slices.len() > 1 && n <= slices[0].len();
+        IoSlice::advance_slices(&mut slices, n);
+        if only_first {
+            for slice in slices.iter() {
+                coalesced.extend_from_slice(slice);
+            }
+            let res = write.write_all(coalesced).await;
+            coalesced.clear();
+            return res;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use super::*;
+
+    /// Takes a single byte of the first vectored write, and everything after that.
+    #[derive(Default)]
+    struct ShortOnce {
+        written: Vec<u8>,
+        vectored_writes: usize,
+    }
+
+    impl AsyncWrite for ShortOnce {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.written.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_write_vectored(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            bufs: &[IoSlice<'_>],
+        ) -> Poll<std::io::Result<usize>> {
+            self.vectored_writes += 1;
+            if self.vectored_writes == 1 {
+                self.written.push(bufs[0][0]);
+                return Poll::Ready(Ok(1));
+            }
+            let mut n = 0;
+            for buf in bufs {
+                self.written.extend_from_slice(buf);
+                n += buf.len();
+            }
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn short_vectored_write_falls_back_once() {
+        futures_lite::future::block_on(async {
+            let mut write = ShortOnce::default();
+            let mut coalesced = vec![];
+            for _ in 0..2 {
+                let mut slices = [IoSlice::new(b"hello "), IoSlice::new(b"world")];
+                write_all_vectored(&mut write, &mut slices, &mut coalesced)
+                    .await
+                    .unwrap();
+            }
+            assert_eq!(write.written, b"hello worldhello world");
+            // the second batch went out as a vectored write again
+            assert_eq!(write.vectored_writes, 2);
+        })
+    }
+
+    #[test]
+    fn weighted_sharing() {
+        let mut sched = Scheduler::default();
+        sched.weights.insert(1, Arc::new(AtomicU8::new(30)));
+        sched.weights.insert(2, Arc::new(AtomicU8::new(10)));
+        for _ in 0..100 {
+            sched.push(Frame::new(1, CMD_PSH, &[0; 1000]));
+            sched.push(Frame::new(2, CMD_PSH, &[0; 1000]));
+        }
+        let first_40: Vec<u32> = (0..40)
+            .map(|_| sched.pop().unwrap().header.stream_id)
+            .collect();
+        let ones = first_40.iter().filter(|id| **id == 1).count();
+        assert!((29..=31).contains(&ones), "{ones}");
+    }
+
+    #[test]
+    fn control_skips_ahead_but_fin_does_not() {
+        let mut sched = Scheduler::default();
+        sched.push(Frame::new(1, CMD_PSH, b"data"));
+        sched.push(Frame::new_empty(1, CMD_FIN));
+        sched.push(Frame::new_empty(2, crate::frame::CMD_SYN));
+        let order: Vec<u8> = std::iter::from_fn(|| sched.pop())
+            .map(|f| f.header.command)
+            .collect();
+        assert_eq!(order, vec![crate::frame::CMD_SYN, CMD_PSH, CMD_FIN]);
+        assert_eq!(sched.len, 0);
     }
 }
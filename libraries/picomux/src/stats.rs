@@ -0,0 +1,60 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Live statistics of a stream, which stay readable after the stream is gone.
+#[derive(Default, Debug)]
+pub struct StreamStats {
+    pub(crate) bytes_sent: AtomicU64,
+    pub(crate) bytes_received: AtomicU64,
+    pub(crate) window_stalls: AtomicU64,
+    pub(crate) stalled_micros: AtomicU64,
+    pub(crate) rtt_micros: AtomicU64,
+}
+
+impl StreamStats {
+    /// Bytes of data sent to the peer.
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of data received from the peer.
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+
+    /// How many times sending stopped because the peer's window ran out.
+    pub fn window_stalls(&self) -> u64 {
+        self.window_stalls.load(Ordering::Relaxed)
+    }
+
+    /// The total time spent waiting for the peer's window.
+    pub fn stalled_time(&self) -> Duration {
+        Duration::from_micros(self.stalled_micros.load(Ordering::Relaxed))
+    }
+
+    /// A smoothed estimate of the round-trip time, sampled whenever the window runs out, as the time from sending the last frame it allowed to the peer raising it again. None if the window never ran out.
+    pub fn rtt(&self) -> Option<Duration> {
+        match self.rtt_micros.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros)),
+        }
+    }
+
+    pub(crate) fn record_stall(&self, stalled: Duration, rtt_sample: Option<Duration>) {
+        self.window_stalls.fetch_add(1, Ordering::Relaxed);
+        self.stalled_micros
+            .fetch_add(stalled.as_micros() as u64, Ordering::Relaxed);
+        if let Some(sample) = rtt_sample {
+            let sample = (sample.as_micros() as u64).max(1);
+            let old = self.rtt_micros.load(Ordering::Relaxed);
+            let new = if old == 0 {
+                sample
+            } else {
+                (old * 7 + sample) / 8
+            };
+            self.rtt_micros.store(new, Ordering::Relaxed);
+        }
+    }
+}
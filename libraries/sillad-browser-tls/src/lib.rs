@@ -0,0 +1,224 @@
+use std::{pin::Pin, str::FromStr};
+
+use async_trait::async_trait;
+use boring::ssl::{SslConnector, SslMethod, SslVerifyMode, SslVersion};
+use futures_util::{AsyncRead, AsyncWrite};
+use serde::{Deserialize, Serialize};
+use sillad::{dialer::Dialer, Pipe};
+use tokio_boring::SslStream;
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+
+/// A browser whose TLS ClientHello we imitate: the cipher suites, groups, signature algorithms, ALPN, and extensions it offers, and whether it uses GREASE values and shuffles its extensions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BrowserFingerprint {
+    #[default]
+    Chrome,
+    Firefox,
+    Safari,
+}
+
+impl FromStr for BrowserFingerprint {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "chrome" => Ok(BrowserFingerprint::Chrome),
+            "firefox" => Ok(BrowserFingerprint::Firefox),
+            "safari" => Ok(BrowserFingerprint::Safari),
+            other => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("unknown browser fingerprint {other}"),
+            )),
+        }
+    }
+}
+
+const ALPN_H2_HTTP11: &[u8] = b"\x02h2\x08http/1.1";
+
+impl BrowserFingerprint {
+    fn connector(self) -> Result<SslConnector, boring::error::ErrorStack> {
+        let mut builder = SslConnector::builder(SslMethod::tls())?;
+        // the other end is authenticated by the protocols running over the pipe, not by its certificate
+        builder.set_verify(SslVerifyMode::NONE);
+        builder.set_min_proto_version(Some(SslVersion::TLS1_2))?;
+        builder.set_max_proto_version(Some(SslVersion::TLS1_3))?;
+        builder.set_alpn_protos(ALPN_H2_HTTP11)?;
+        builder.enable_ocsp_stapling();
+        match self {
+            BrowserFingerprint::Chrome => {
+                builder.set_grease_enabled(true);
+                builder.set_permute_extensions(true);
+                builder.enable_signed_cert_timestamps();
+                builder.set_cipher_list(
+                    "ECDHE-ECDSA-AES128-GCM-SHA256:ECDHE-RSA-AES128-GCM-SHA256:\
+                     ECDHE-ECDSA-AES256-GCM-SHA384:ECDHE-RSA-AES256-GCM-SHA384:\
+                     ECDHE-ECDSA-CHACHA20-POLY1305:ECDHE-RSA-CHACHA20-POLY1305:\
+                     ECDHE-RSA-AES128-SHA:ECDHE-RSA-AES256-SHA:\
+                     AES128-GCM-SHA256:AES256-GCM-SHA384:AES128-SHA:AES256-SHA",
+                )?;
+                builder.set_curves_list("X25519:P-256:P-384")?;
+                builder.set_sigalgs_list(
+                    "ecdsa_secp256r1_sha256:rsa_pss_rsae_sha256:rsa_pkcs1_sha256:\
+                     ecdsa_secp384r1_sha384:rsa_pss_rsae_sha384:rsa_pkcs1_sha384:\
+                     rsa_pss_rsae_sha512:rsa_pkcs1_sha512",
+                )?;
+            }
+            BrowserFingerprint::Firefox => {
+                builder.set_cipher_list(
+                    "ECDHE-ECDSA-AES128-GCM-SHA256:ECDHE-RSA-AES128-GCM-SHA256:\
+                     ECDHE-ECDSA-CHACHA20-POLY1305:ECDHE-RSA-CHACHA20-POLY1305:\
+                     ECDHE-ECDSA-AES256-GCM-SHA384:ECDHE-RSA-AES256-GCM-SHA384:\
+                     ECDHE-ECDSA-AES256-SHA:ECDHE-ECDSA-AES128-SHA:\
+                     ECDHE-RSA-AES128-SHA:ECDHE-RSA-AES256-SHA:\
+                     AES128-GCM-SHA256:AES256-GCM-SHA384:AES128-SHA:AES256-SHA",
+                )?;
+                builder.set_curves_list("X25519:P-256:P-384:P-521")?;
+                builder.set_sigalgs_list(
+                    "ecdsa_secp256r1_sha256:ecdsa_secp384r1_sha384:ecdsa_secp521r1_sha512:\
+                     rsa_pss_rsae_sha256:rsa_pss_rsae_sha384:rsa_pss_rsae_sha512:\
+                     rsa_pkcs1_sha256:rsa_pkcs1_sha384:rsa_pkcs1_sha512:\
+                     ecdsa_sha1:rsa_pkcs1_sha1",
+                )?;
+            }
+            BrowserFingerprint::Safari => {
+                builder.set_grease_enabled(true);
+                builder.enable_signed_cert_timestamps();
+                builder.set_cipher_list(
+                    "ECDHE-ECDSA-AES256-GCM-SHA384:ECDHE-ECDSA-AES128-GCM-SHA256:\
+                     ECDHE-ECDSA-CHACHA20-POLY1305:ECDHE-RSA-AES256-GCM-SHA384:\
+                     ECDHE-RSA-AES128-GCM-SHA256:ECDHE-RSA-CHACHA20-POLY1305:\
+                     ECDHE-ECDSA-AES256-SHA:ECDHE-ECDSA-AES128-SHA:\
+                     ECDHE-RSA-AES256-SHA:ECDHE-RSA-AES128-SHA:\
+                     AES256-GCM-SHA384:AES128-GCM-SHA256:AES256-SHA:AES128-SHA",
+                )?;
+                builder.set_curves_list("X25519:P-256:P-384:P-521")?;
+                builder.set_sigalgs_list(
+                    "ecdsa_secp256r1_sha256:rsa_pss_rsae_sha256:rsa_pkcs1_sha256:\
+                     ecdsa_secp384r1_sha384:ecdsa_sha1:rsa_pss_rsae_sha384:\
+                     rsa_pkcs1_sha384:rsa_pss_rsae_sha512:\
+                     rsa_pkcs1_sha512:rsa_pkcs1_sha1",
+                )?;
+            }
+        }
+        Ok(builder.build())
+    }
+}
+
+/// BrowserTlsPipe is a TLS stream whose handshake imitated a browser.
+pub struct BrowserTlsPipe<P: Pipe> {
+    inner: Compat<SslStream<Compat<P>>>,
+    remote_addr: Option<String>,
+}
+
+impl<P: Pipe> AsyncRead for BrowserTlsPipe<P> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl<P: Pipe> AsyncWrite for BrowserTlsPipe<P> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+impl<P: Pipe> Pipe for BrowserTlsPipe<P> {
+    fn protocol(&self) -> &str {
+        "browser-tls"
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        self.remote_addr.as_deref()
+    }
+}
+
+/// BrowserTlsDialer wraps a Dialer to establish TLS connections that look like they come from a browser. Any TLS listener can accept them.
+pub struct BrowserTlsDialer<D: Dialer> {
+    pub inner: D,
+    pub domain: String,
+    pub fingerprint: BrowserFingerprint,
+    /// An ECHConfigList, as published by the server's DNS. With one, the real domain is encrypted and the wire only shows the public name in the config. If an ECH handshake fails, for example because a middlebox strips the extension, we try again without ECH.
+    pub ech_config_list: Option<Vec<u8>>,
+}
+
+#[async_trait]
+impl<D: Dialer> Dialer for BrowserTlsDialer<D> {
+    type P = BrowserTlsPipe<D::P>;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        if let Some(ech_config_list) = &self.ech_config_list {
+            match self.dial_once(Some(ech_config_list)).await {
+                Ok(pipe) => return Ok(pipe),
+                Err(err) => tracing::warn!(
+                    err = debug(err),
+                    domain = display(&self.domain),
+                    "ECH handshake failed, falling back to a visible SNI"
+                ),
+            }
+        }
+        self.dial_once(None).await
+    }
+}
+
+impl<D: Dialer> BrowserTlsDialer<D> {
+    async fn dial_once(
+        &self,
+        ech_config_list: Option<&[u8]>,
+    ) -> std::io::Result<BrowserTlsPipe<D::P>> {
+        let to_io =
+            |e: boring::error::ErrorStack| std::io::Error::new(std::io::ErrorKind::Other, e);
+        let mut config = self
+            .fingerprint
+            .connector()
+            .map_err(to_io)?
+            .configure()
+            .map_err(to_io)?;
+        config.set_verify_hostname(false);
+        if let Some(ech_config_list) = ech_config_list {
+            config.set_ech_config_list(ech_config_list).map_err(to_io)?;
+        }
+        let stream = self.inner.dial().await?;
+        let remote_addr = stream.remote_addr().map(|s| s.to_string());
+        let tls_stream = tokio_boring::connect(config, &self.domain, stream.compat())
+            .await
+            .map_err(|e| {
+                let err = e
+                    .as_ssl_error_stack()
+                    .map(|stack| stack.to_string())
+                    .unwrap_or_else(|| "TLS handshake failed".into());
+                tracing::warn!(
+                    err = display(&err),
+                    addr = debug(&remote_addr),
+                    "browser TLS connection failed"
+                );
+                std::io::Error::new(std::io::ErrorKind::Other, err)
+            })?;
+        Ok(BrowserTlsPipe {
+            inner: tls_stream.compat(),
+            remote_addr,
+        })
+    }
+}
@@ -0,0 +1,112 @@
+use std::{
+    io::ErrorKind,
+    net::{SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+use async_io::Async;
+use async_trait::async_trait;
+use sillad::dialer::Dialer;
+use sillad_kcp::{start_polling_session, KcpParams, KcpPipe, PollCarrier, PollTiming};
+
+use crate::{encode_query, parse_response, unpack_down, up_capacity, Up, UP_HEADER};
+
+/// How often we poll while data is flowing, and how long after the last data we keep polling that often.
+const FAST_POLL: Duration = Duration::from_millis(20);
+const ACTIVE_WINDOW: Duration = Duration::from_secs(2);
+
+/// How often we poll an idle session.
+const SLOW_POLL: Duration = Duration::from_secs(1);
+
+/// KCP needs room for its own header and a little data in every packet.
+const MIN_MTU: usize = 64;
+
+/// A dialer for DNS tunnel listeners, which sends its queries through the given resolver to the listener that is authoritative for the domain, and reaches the given port on the listener's host. There is no handshake, so dialing always succeeds, and an unreachable listener only shows up as a pipe that never hears back.
+pub struct DnsDialer {
+    pub resolver: SocketAddr,
+    pub domain: String,
+    pub port: u16,
+    pub params: KcpParams,
+    /// The most queries we send per second, counting polls. Since every answer answers a query, this caps traffic both ways.
+    pub max_qps: u32,
+}
+
+#[async_trait]
+impl Dialer for DnsDialer {
+    type P = KcpPipe;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let mtu = up_capacity(&self.domain).saturating_sub(UP_HEADER);
+        if mtu < MIN_MTU {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "domain too long for a DNS tunnel",
+            ));
+        }
+        let bind_addr: SocketAddr = if self.resolver.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let socket = Async::<UdpSocket>::bind(bind_addr)?;
+        socket.get_ref().connect(self.resolver)?;
+        let conv: u32 = rand::random();
+        Ok(start_polling_session(
+            conv,
+            self.params,
+            mtu,
+            PollTiming {
+                fast: FAST_POLL,
+                active_window: ACTIVE_WINDOW,
+                slow: SLOW_POLL,
+                max_pps: self.max_qps,
+            },
+            QueryCarrier {
+                socket,
+                domain: self.domain.clone(),
+                conv,
+                port: self.port,
+            },
+            "dns",
+            self.resolver.to_string(),
+        ))
+    }
+}
+
+/// Carries one session's packets up in the names of queries and down in the answers to them.
+struct QueryCarrier {
+    socket: Async<UdpSocket>,
+    domain: String,
+    conv: u32,
+    port: u16,
+}
+
+#[async_trait]
+impl PollCarrier for QueryCarrier {
+    async fn send_poll(&self, kcp: &[u8]) -> std::io::Result<()> {
+        let name = Up {
+            conv: self.conv,
+            port: self.port,
+            nonce: rand::random(),
+            kcp,
+        }
+        .to_name(&self.domain);
+        self.socket
+            .send(&encode_query(rand::random(), &name))
+            .await?;
+        Ok(())
+    }
+
+    async fn recv_answer(&self) -> std::io::Result<Vec<Vec<u8>>> {
+        let mut buf = [0u8; 65536];
+        loop {
+            let n = self.socket.recv(&mut buf).await?;
+            if let Some(data) = parse_response(&buf[..n]) {
+                return Ok(unpack_down(&data)
+                    .into_iter()
+                    .map(|pkt| pkt.to_vec())
+                    .collect());
+            }
+        }
+    }
+}
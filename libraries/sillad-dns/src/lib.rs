@@ -0,0 +1,329 @@
+//! A transport of last resort for networks where only DNS gets through, such as behind captive portals. KCP packets go up in the names of TXT queries under a domain whose authoritative server is the listener, and come down in the TXT answers, by way of whatever resolver the network provides. Since the listener can only answer queries, the client keeps polling so that data can flow back.
+//!
+//! Everything stays within plain DNS over UDP: names of at most 253 characters, and responses of at most 512 bytes, which any resolver passes along.
+
+
+pub mod dialer;
+pub mod listener;
+
+const MAX_NAME: usize = 253;
+const MAX_LABEL: usize = 63;
+const MAX_RESPONSE: usize = 512;
+
+const TYPE_TXT: u16 = 16;
+const CLASS_IN: u16 = 1;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+
+const RCODE_NAME_ERROR: u16 = 3;
+const RCODE_REFUSED: u16 = 5;
+
+/// The conversation ID, the port on the listener's host to reach, and a nonce that keeps resolvers from answering from their caches.
+const UP_HEADER: usize = 8;
+
+/// The MTU of the listener's KCP packets, which leaves room to answer even the longest query within a response.
+const DOWN_MTU: usize = 200;
+
+/// DNS names are case-insensitive, and resolvers may well randomize their case, so we encode in lowercase base32.
+const BASE32: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+fn base32_encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len().div_ceil(5) * 8);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for &b in data {
+        acc = (acc << 8) | b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32[((acc >> bits) & 31) as usize]);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32[((acc << (5 - bits)) & 31) as usize]);
+    }
+    out
+}
+
+fn base32_decode(s: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 5 / 8);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for &c in s {
+        let val = BASE32.iter().position(|&x| x == c.to_ascii_lowercase())? as u32;
+        acc = (acc << 5) | val;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+fn domain_labels(domain: &str) -> Vec<&[u8]> {
+    domain
+        .split('.')
+        .filter(|l| !l.is_empty())
+        .map(|l| l.as_bytes())
+        .collect()
+}
+
+/// How many bytes one query carries up, header included, in names under the given domain.
+fn up_capacity(domain: &str) -> usize {
+    let avail = MAX_NAME.saturating_sub(domain.trim_end_matches('.').len());
+    // every label of data is followed by a dot
+    let chars = avail - avail.div_ceil(MAX_LABEL + 1);
+    chars * 5 / 8
+}
+
+/// What a query carries up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Up<'a> {
+    conv: u32,
+    port: u16,
+    nonce: u16,
+    kcp: &'a [u8],
+}
+
+impl Up<'_> {
+    /// Encodes this as a name under the domain, in wire format.
+    fn to_name(self, domain: &str) -> Vec<u8> {
+        let mut data = self.conv.to_be_bytes().to_vec();
+        data.extend_from_slice(&self.port.to_be_bytes());
+        data.extend_from_slice(&self.nonce.to_be_bytes());
+        data.extend_from_slice(self.kcp);
+        let encoded = base32_encode(&data);
+        let mut name = vec![];
+        for label in encoded.chunks(MAX_LABEL).chain(domain_labels(domain)) {
+            name.push(label.len() as u8);
+            name.extend_from_slice(label);
+        }
+        name.push(0);
+        name
+    }
+
+    fn decode(data: &[u8]) -> Option<Up<'_>> {
+        Some(Up {
+            conv: u32::from_be_bytes(data.get(..4)?.try_into().ok()?),
+            port: u16::from_be_bytes(data.get(4..6)?.try_into().ok()?),
+            nonce: u16::from_be_bytes(data.get(6..8)?.try_into().ok()?),
+            kcp: &data[UP_HEADER..],
+        })
+    }
+}
+
+fn read_u16(buf: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(buf.get(pos..pos + 2)?.try_into().ok()?))
+}
+
+fn encode_query(id: u16, name: &[u8]) -> Vec<u8> {
+    let mut msg = id.to_be_bytes().to_vec();
+    msg.extend_from_slice(&FLAG_RECURSION_DESIRED.to_be_bytes());
+    msg.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    msg.extend_from_slice(name);
+    msg.extend_from_slice(&TYPE_TXT.to_be_bytes());
+    msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+    msg
+}
+
+#[derive(Debug)]
+struct Query<'a> {
+    id: u16,
+    flags: u16,
+    /// The question as it came, so that the response repeats it byte for byte, letter case included.
+    question: &'a [u8],
+    labels: Vec<&'a [u8]>,
+    qtype: u16,
+}
+
+fn parse_query(buf: &[u8]) -> Option<Query<'_>> {
+    let flags = read_u16(buf, 2)?;
+    if flags & FLAG_RESPONSE != 0 || read_u16(buf, 4)? != 1 {
+        return None;
+    }
+    let mut pos = 12;
+    let mut labels = vec![];
+    loop {
+        let len = *buf.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        // this also rules out compression, which has no place in a question
+        if len > MAX_LABEL {
+            return None;
+        }
+        labels.push(buf.get(pos..pos + len)?);
+        pos += len;
+    }
+    let qtype = read_u16(buf, pos)?;
+    read_u16(buf, pos + 2)?;
+    Some(Query {
+        id: read_u16(buf, 0)?,
+        flags,
+        question: &buf[12..pos + 4],
+        labels,
+        qtype,
+    })
+}
+
+impl Query<'_> {
+    /// The base32 data in the labels before the domain, if the name is under the domain.
+    fn data_under(&self, domain: &str) -> Option<Vec<u8>> {
+        let domain = domain_labels(domain);
+        let split = self.labels.len().checked_sub(domain.len())?;
+        let (data, suffix) = self.labels.split_at(split);
+        suffix
+            .iter()
+            .zip(domain)
+            .all(|(a, b)| a.eq_ignore_ascii_case(b))
+            .then(|| data.concat())
+    }
+
+    /// How many bytes of TXT data a response to this query can carry.
+    fn txt_budget(&self) -> usize {
+        // the header, the question, and the answer up to its data, whose name points back at the question
+        let rdata = MAX_RESPONSE.saturating_sub(12 + self.question.len() + 12);
+        // every 255 bytes of data need a length byte
+        rdata.saturating_sub(rdata.div_ceil(256))
+    }
+
+    fn respond(&self, rcode: u16, txt: Option<&[u8]>) -> Vec<u8> {
+        let flags =
+            FLAG_RESPONSE | FLAG_AUTHORITATIVE | (self.flags & FLAG_RECURSION_DESIRED) | rcode;
+        let mut msg = self.id.to_be_bytes().to_vec();
+        msg.extend_from_slice(&flags.to_be_bytes());
+        msg.extend_from_slice(&[0, 1, 0, txt.is_some() as u8, 0, 0, 0, 0]);
+        msg.extend_from_slice(self.question);
+        if let Some(txt) = txt {
+            let mut rdata = vec![];
+            for chunk in txt.chunks(255) {
+                rdata.push(chunk.len() as u8);
+                rdata.extend_from_slice(chunk);
+            }
+            if rdata.is_empty() {
+                rdata.push(0);
+            }
+            msg.extend_from_slice(&[0xc0, 12]);
+            msg.extend_from_slice(&TYPE_TXT.to_be_bytes());
+            msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+            // a TTL of zero, so that nothing gets cached
+            msg.extend_from_slice(&[0, 0, 0, 0]);
+            msg.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            msg.extend_from_slice(&rdata);
+        }
+        msg
+    }
+}
+
+fn skip_name(buf: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *buf.get(pos)?;
+        if len == 0 {
+            return Some(pos + 1);
+        }
+        if len & 0xc0 == 0xc0 {
+            return Some(pos + 2);
+        }
+        pos += 1 + len as usize;
+    }
+}
+
+/// Collects the data of all the TXT records in a successful response.
+fn parse_response(buf: &[u8]) -> Option<Vec<u8>> {
+    let flags = read_u16(buf, 2)?;
+    if flags & FLAG_RESPONSE == 0 || flags & 0x000f != 0 {
+        return None;
+    }
+    let mut pos = 12;
+    for _ in 0..read_u16(buf, 4)? {
+        pos = skip_name(buf, pos)? + 4;
+    }
+    let mut data = vec![];
+    for _ in 0..read_u16(buf, 6)? {
+        pos = skip_name(buf, pos)?;
+        let rtype = read_u16(buf, pos)?;
+        let rdlen = read_u16(buf, pos + 8)? as usize;
+        let rdata = buf.get(pos + 10..pos + 10 + rdlen)?;
+        pos += 10 + rdlen;
+        if rtype != TYPE_TXT {
+            continue;
+        }
+        let mut i = 0;
+        while i < rdata.len() {
+            let len = rdata[i] as usize;
+            data.extend_from_slice(rdata.get(i + 1..i + 1 + len)?);
+            i += 1 + len;
+        }
+    }
+    Some(data)
+}
+
+/// Splits the data of a response into the KCP packets it carries, each prefixed by its length.
+fn unpack_down(mut data: &[u8]) -> Vec<&[u8]> {
+    let mut packets = vec![];
+    while let Some(len) = read_u16(data, 0) {
+        let Some(pkt) = data.get(2..2 + len as usize) else {
+            break;
+        };
+        packets.push(pkt);
+        data = &data[2 + len as usize..];
+    }
+    packets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base32_roundtrip() {
+        for len in 0..20 {
+            let data: Vec<u8> = (0..len).map(|i| (i * 37 + 11) as u8).collect();
+            let encoded = base32_encode(&data);
+            assert_eq!(base32_decode(&encoded), Some(data.clone()));
+            assert_eq!(base32_decode(&encoded.to_ascii_uppercase()), Some(data));
+        }
+        assert_eq!(base32_decode(b"not-base32"), None);
+    }
+
+    #[test]
+    fn query_and_response_roundtrip() {
+        let domain = "t.example.com";
+        let kcp = vec![42u8; up_capacity(domain) - UP_HEADER];
+        let up = Up {
+            conv: 0xdeadbeef,
+            port: 4433,
+            nonce: 7,
+            kcp: &kcp,
+        };
+        let name = up.to_name(domain);
+        // the wire format adds a length byte before the first label and a zero at the end
+        assert!(name.len() <= MAX_NAME + 2);
+        let msg = encode_query(1234, &name);
+
+        let query = parse_query(&msg).unwrap();
+        assert_eq!(query.id, 1234);
+        assert_eq!(query.qtype, TYPE_TXT);
+        assert!(query.data_under("example.org").is_none());
+        let data = base32_decode(&query.data_under("T.Example.Com.").unwrap()).unwrap();
+        assert_eq!(Up::decode(&data), Some(up));
+
+        // even the longest query leaves room for a packet of the listener's, with its length
+        assert!(query.txt_budget() >= DOWN_MTU + 2);
+        let mut down = vec![];
+        for pkt in [&b"hello"[..], &[1u8; DOWN_MTU]] {
+            down.extend_from_slice(&(pkt.len() as u16).to_be_bytes());
+            down.extend_from_slice(pkt);
+        }
+        let response = query.respond(0, Some(&down));
+        assert!(response.len() <= MAX_RESPONSE);
+        let data = parse_response(&response).unwrap();
+        assert_eq!(unpack_down(&data), vec![&b"hello"[..], &[1u8; DOWN_MTU]]);
+
+        assert_eq!(parse_response(&query.respond(RCODE_REFUSED, None)), None);
+    }
+}
@@ -0,0 +1,117 @@
+use std::{
+    io::ErrorKind,
+    net::{SocketAddr, UdpSocket},
+    sync::Arc,
+};
+
+use async_io::Async;
+use async_task::Task;
+use sillad_kcp::{KcpParams, KcpPipe, PolledSession, PolledSessions};
+use tachyonix::{Receiver, Sender};
+
+use crate::{base32_decode, parse_query, Up, DOWN_MTU, RCODE_NAME_ERROR, RCODE_REFUSED, TYPE_TXT};
+
+/// How many sessions one listener serves at most.
+const MAX_SESSIONS: usize = 1000;
+
+/// A listener for DNS tunnels, which acts as the authoritative server for its domain. The domain must be delegated to this host with an NS record, and the listener usually binds to port 53.
+///
+/// Sessions are told apart by their conversation IDs alone, since one client's queries may reach us from any of its resolver's addresses.
+pub struct DnsListener {
+    recv_pipe: Receiver<(KcpPipe, u16)>,
+    local_addr: SocketAddr,
+    _task: Task<()>,
+}
+
+impl DnsListener {
+    /// Creates a new DnsListener by listening to a particular UDP address, answering queries under the given domain.
+    pub async fn bind(addr: SocketAddr, domain: &str, params: KcpParams) -> std::io::Result<Self> {
+        let socket = Arc::new(Async::<UdpSocket>::bind(addr)?);
+        let local_addr = socket.get_ref().local_addr()?;
+        let (send_pipe, recv_pipe) = tachyonix::channel(1);
+        let _task = smolscale::spawn(listen_loop(socket, domain.to_string(), params, send_pipe));
+        Ok(Self {
+            recv_pipe,
+            local_addr,
+            _task,
+        })
+    }
+
+    /// Get the local listening address.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Accepts a new session, along with the port on this host that the client wants to reach. Which ports may be reached is up to the caller.
+    pub async fn accept(&mut self) -> std::io::Result<(KcpPipe, u16)> {
+        self.recv_pipe
+            .recv()
+            .await
+            .map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, "DNS listener died"))
+    }
+}
+
+async fn listen_loop(
+    socket: Arc<Async<UdpSocket>>,
+    domain: String,
+    params: KcpParams,
+    send_pipe: Sender<(KcpPipe, u16)>,
+) {
+    let mut sessions = PolledSessions::new(params, DOWN_MTU, MAX_SESSIONS, "dns");
+    let mut buf = [0u8; 65536];
+    loop {
+        let (n, addr) = match socket.recv_from(&mut buf).await {
+            Ok(res) => res,
+            Err(err) => {
+                tracing::debug!(err = debug(err), "DNS listener could not receive");
+                continue;
+            }
+        };
+        let Some(query) = parse_query(&buf[..n]) else {
+            continue;
+        };
+        let response = match query.data_under(&domain) {
+            None => query.respond(RCODE_REFUSED, None),
+            // resolvers also ask about other types of records, such as when they minimize names, and those must not look like the name does not exist
+            Some(_) if query.qtype != TYPE_TXT => query.respond(0, None),
+            Some(data) => match base32_decode(&data).as_deref().and_then(Up::decode) {
+                None => query.respond(RCODE_NAME_ERROR, None),
+                Some(up) => {
+                    if let Some(pipe) =
+                        sessions.input(up.conv, up.conv, up.kcp, || addr.to_string())
+                    {
+                        if send_pipe.send((pipe, up.port)).await.is_err() {
+                            return;
+                        }
+                    }
+                    let txt = sessions
+                        .get_mut(&up.conv)
+                        .map(|session| fill_answer(session, query.txt_budget()))
+                        .unwrap_or_default();
+                    // every query gets an answer, even an empty one, lest the resolver retry it
+                    query.respond(0, Some(&txt))
+                }
+            },
+        };
+        // like on the wire, a response that does not fit in the socket is lost
+        if let Err(err) = socket.get_ref().send_to(&response, addr) {
+            if err.kind() != ErrorKind::WouldBlock {
+                tracing::debug!(err = debug(err), "DNS listener could not send");
+            }
+        }
+    }
+}
+
+/// Packs as much of what the session has queued as fits in the answer to one query, each packet prefixed by its length.
+fn fill_answer(session: &mut PolledSession, budget: usize) -> Vec<u8> {
+    let mut txt = vec![];
+    while let Some(pkt) = session.next_packet() {
+        if txt.len() + 2 + pkt.len() > budget {
+            session.put_back(pkt);
+            break;
+        }
+        txt.extend_from_slice(&(pkt.len() as u16).to_be_bytes());
+        txt.extend_from_slice(&pkt);
+    }
+    txt
+}
@@ -0,0 +1,298 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    io::ErrorKind,
+    ops::Range,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use async_io::Timer;
+use async_trait::async_trait;
+use futures_util::{ready, AsyncRead, AsyncWrite};
+use pin_project::pin_project;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sillad::{dialer::Dialer, Pipe};
+
+/// How the first write on a pipe gets cut up.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct FragmentParams {
+    /// How many pieces the first write is cut into. A TLS ClientHello is always cut in the middle of its server name, and at random places besides.
+    pub pieces: usize,
+    /// How long to wait between sending pieces, so that they go out in separate packets and arrive after DPI has given up on reassembly.
+    pub delay_ms: u64,
+}
+
+impl Default for FragmentParams {
+    fn default() -> Self {
+        Self {
+            pieces: 4,
+            delay_ms: 20,
+        }
+    }
+}
+
+/// FragmentDialer wraps a dialer of a TCP-like transport, so that the first thing written on every pipe goes out in small, delayed pieces. This defeats DPI boxes that look for the SNI of a TLS ClientHello in the first packet, or that only reassemble a few packets.
+///
+/// The inner pipes must send writes right away, as a `TcpPipe` with Nagle's algorithm disabled does.
+pub struct FragmentDialer<D: Dialer> {
+    pub inner: D,
+    pub params: FragmentParams,
+}
+
+#[async_trait]
+impl<D: Dialer> Dialer for FragmentDialer<D> {
+    type P = FragmentPipe<D::P>;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        Ok(FragmentPipe::new(self.inner.dial().await?, self.params))
+    }
+}
+
+enum WriteState {
+    First,
+    Sending {
+        pieces: VecDeque<Vec<u8>>,
+        timer: Option<Timer>,
+        consumed: usize,
+    },
+    Done,
+}
+
+/// A pipe that cuts up its first write, and passes everything else through untouched.
+#[pin_project]
+pub struct FragmentPipe<P: Pipe> {
+    #[pin]
+    inner: P,
+    params: FragmentParams,
+    state: WriteState,
+}
+
+impl<P: Pipe> FragmentPipe<P> {
+    pub fn new(inner: P, params: FragmentParams) -> Self {
+        Self {
+            inner,
+            params,
+            state: WriteState::First,
+        }
+    }
+}
+
+impl<P: Pipe> AsyncRead for FragmentPipe<P> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.project().inner.poll_read(cx, buf)
+    }
+}
+
+impl<P: Pipe> AsyncWrite for FragmentPipe<P> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let mut this = self.project();
+        loop {
+            match this.state {
+                WriteState::Done => return this.inner.poll_write(cx, buf),
+                WriteState::First => {
+                    if buf.is_empty() {
+                        return Poll::Ready(Ok(0));
+                    }
+                    *this.state = WriteState::Sending {
+                        pieces: fragment(buf, this.params).into(),
+                        timer: None,
+                        consumed: buf.len(),
+                    };
+                }
+                // the first write is copied into the pieces, so it counts as written once they all are, even if the caller retries with a different buffer in between
+                WriteState::Sending {
+                    pieces,
+                    timer,
+                    consumed,
+                } => {
+                    if let Some(t) = timer {
+                        ready!(Pin::new(t).poll(cx));
+                        *timer = None;
+                    }
+                    if pieces.is_empty() {
+                        let consumed = *consumed;
+                        *this.state = WriteState::Done;
+                        return Poll::Ready(Ok(consumed));
+                    }
+                    let piece = &mut pieces[0];
+                    let n = ready!(this.inner.as_mut().poll_write(cx, piece))?;
+                    if n == 0 {
+                        return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+                    }
+                    piece.drain(..n);
+                    if piece.is_empty() {
+                        pieces.pop_front();
+                        if !pieces.is_empty() {
+                            *timer =
+                                Some(Timer::after(Duration::from_millis(this.params.delay_ms)));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+impl<P: Pipe> Pipe for FragmentPipe<P> {
+    fn shared_secret(&self) -> Option<&[u8]> {
+        self.inner.shared_secret()
+    }
+
+    fn protocol(&self) -> &str {
+        self.inner.protocol()
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        self.inner.remote_addr()
+    }
+
+    fn poll_close_write(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_close_write(cx)
+    }
+}
+
+/// Cuts up the first write. A TLS handshake record is split into several smaller records, which servers must reassemble but many DPI boxes do not; anything else is simply split in two.
+fn fragment(buf: &[u8], params: &FragmentParams) -> Vec<Vec<u8>> {
+    let mut rng = rand::thread_rng();
+    let record_len = read_len(buf, 3, 2).unwrap_or_default();
+    let is_handshake = buf.len() >= 5 + record_len && buf[0] == 0x16 && buf[1] == 0x03;
+    if !is_handshake || record_len < 2 {
+        if buf.len() < 2 {
+            return vec![buf.to_vec()];
+        }
+        let split = rng.gen_range(1..buf.len());
+        return vec![buf[..split].to_vec(), buf[split..].to_vec()];
+    }
+
+    let payload = &buf[5..5 + record_len];
+    let mut splits = vec![];
+    if let Some(name) = server_name_range(payload) {
+        splits.push(name.start + name.len() / 2);
+    }
+    while splits.len() + 1 < params.pieces.min(payload.len()) {
+        let split = rng.gen_range(1..payload.len());
+        if !splits.contains(&split) {
+            splits.push(split);
+        }
+    }
+    splits.retain(|&split| split > 0 && split < payload.len());
+    splits.sort_unstable();
+
+    let mut pieces = vec![];
+    let mut start = 0;
+    for end in splits.into_iter().chain(std::iter::once(payload.len())) {
+        let piece = &payload[start..end];
+        let mut record = vec![buf[0], buf[1], buf[2]];
+        record.extend_from_slice(&(piece.len() as u16).to_be_bytes());
+        record.extend_from_slice(piece);
+        pieces.push(record);
+        start = end;
+    }
+    if buf.len() > 5 + record_len {
+        pieces.push(buf[5 + record_len..].to_vec());
+    }
+    pieces
+}
+
+/// Finds where the server name is within a ClientHello handshake message.
+fn server_name_range(hs: &[u8]) -> Option<Range<usize>> {
+    if *hs.first()? != 1 {
+        return None;
+    }
+    // skip the handshake header, the version, and the random
+    let mut pos = 4 + 2 + 32;
+    // then the session ID, the cipher suites, and the compression methods
+    for len_bytes in [1, 2, 1] {
+        pos += len_bytes + read_len(hs, pos, len_bytes)?;
+    }
+    let extensions_end = pos + 2 + read_len(hs, pos, 2)?;
+    pos += 2;
+    while pos + 4 <= extensions_end {
+        let ext_type = read_len(hs, pos, 2)?;
+        let ext_len = read_len(hs, pos + 2, 2)?;
+        if ext_type == 0 {
+            // the list length and the name type come before the length of the name itself
+            let name_len = read_len(hs, pos + 4 + 3, 2)?;
+            let start = pos + 4 + 5;
+            return (start + name_len <= hs.len()).then_some(start..start + name_len);
+        }
+        pos += 4 + ext_len;
+    }
+    None
+}
+
+fn read_len(buf: &[u8], pos: usize, len_bytes: usize) -> Option<usize> {
+    buf.get(pos..pos + len_bytes)
+        .map(|b| b.iter().fold(0, |acc, &x| (acc << 8) | x as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_hello(server_name: &[u8]) -> Vec<u8> {
+        let mut sni = vec![];
+        sni.extend_from_slice(&(server_name.len() as u16 + 3).to_be_bytes());
+        sni.push(0);
+        sni.extend_from_slice(&(server_name.len() as u16).to_be_bytes());
+        sni.extend_from_slice(server_name);
+        let mut extensions = vec![0, 0];
+        extensions.extend_from_slice(&(sni.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(&sni);
+
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[0xaa; 32]);
+        body.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut hs = vec![1, 0];
+        hs.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        hs.extend_from_slice(&body);
+        let mut record = vec![0x16, 3, 1];
+        record.extend_from_slice(&(hs.len() as u16).to_be_bytes());
+        record.extend_from_slice(&hs);
+        record
+    }
+
+    #[test]
+    fn client_hello_is_split_within_server_name() {
+        let server_name = b"blocked.example.com";
+        let hello = client_hello(server_name);
+        assert_eq!(
+            server_name_range(&hello[5..]).map(|r| hello[5..][r].to_vec()),
+            Some(server_name.to_vec())
+        );
+
+        let pieces = fragment(&hello, &FragmentParams::default());
+        assert_eq!(pieces.len(), FragmentParams::default().pieces);
+        let mut reassembled = vec![];
+        for piece in &pieces {
+            assert_eq!(&piece[..3], &hello[..3]);
+            assert_eq!(read_len(piece, 3, 2), Some(piece.len() - 5));
+            assert!(!piece.windows(server_name.len()).any(|w| w == server_name));
+            reassembled.extend_from_slice(&piece[5..]);
+        }
+        assert_eq!(reassembled, &hello[5..]);
+    }
+}
@@ -0,0 +1,111 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    sync::atomic::{AtomicU16, Ordering},
+    time::Duration,
+};
+
+use async_io::Async;
+use async_trait::async_trait;
+use sillad::dialer::Dialer;
+use sillad_kcp::{start_polling_session, KcpParams, KcpPipe, PollCarrier, PollTiming, MTU};
+
+use crate::{
+    encode_echo, icmp_socket, parse_echo, Echo, Tunneled, DIR_DOWN, DIR_UP, ICMP_ECHO_REPLY,
+    ICMP_ECHO_REQUEST,
+};
+
+/// How often we poll while data is flowing, and how long after the last data we keep polling that often.
+const FAST_POLL: Duration = Duration::from_millis(10);
+const ACTIVE_WINDOW: Duration = Duration::from_secs(2);
+
+/// How often we poll an idle session.
+const SLOW_POLL: Duration = Duration::from_secs(1);
+
+/// A dialer for ICMP tunnel listeners, which reach the given port on the listener's host. Like KCP, there is no handshake, so dialing succeeds as long as an ICMP socket can be opened.
+pub struct IcmpDialer {
+    pub dest_addr: Ipv4Addr,
+    pub port: u16,
+    pub params: KcpParams,
+    /// The most echo requests we send per second, counting polls. Since every reply answers a request, this caps traffic both ways.
+    pub max_pps: u32,
+}
+
+#[async_trait]
+impl Dialer for IcmpDialer {
+    type P = KcpPipe;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let socket = icmp_socket(false)?;
+        socket
+            .get_ref()
+            .connect(SocketAddr::new(self.dest_addr.into(), 0))?;
+        let conv: u32 = rand::random();
+        Ok(start_polling_session(
+            conv,
+            self.params,
+            MTU,
+            PollTiming {
+                fast: FAST_POLL,
+                active_window: ACTIVE_WINDOW,
+                slow: SLOW_POLL,
+                max_pps: self.max_pps,
+            },
+            EchoCarrier {
+                socket,
+                conv,
+                ident: rand::random(),
+                port: self.port,
+                seq: AtomicU16::new(0),
+            },
+            "icmp",
+            SocketAddr::new(self.dest_addr.into(), self.port).to_string(),
+        ))
+    }
+}
+
+/// Carries one session's packets up in echo requests and down in the replies to them.
+struct EchoCarrier {
+    socket: Async<UdpSocket>,
+    conv: u32,
+    ident: u16,
+    port: u16,
+    seq: AtomicU16,
+}
+
+#[async_trait]
+impl PollCarrier for EchoCarrier {
+    async fn send_poll(&self, kcp: &[u8]) -> std::io::Result<()> {
+        let pkt = encode_echo(&Echo {
+            kind: ICMP_ECHO_REQUEST,
+            ident: self.ident,
+            seq: self.seq.fetch_add(1, Ordering::Relaxed),
+            tunneled: Tunneled {
+                dir: DIR_UP,
+                conv: self.conv,
+                port: self.port,
+                kcp,
+            },
+        });
+        self.socket.send(&pkt).await?;
+        Ok(())
+    }
+
+    async fn recv_answer(&self) -> std::io::Result<Vec<Vec<u8>>> {
+        let mut buf = [0u8; 65536];
+        loop {
+            let n = self.socket.recv(&mut buf).await?;
+            let Some(echo) = parse_echo(&buf[..n]) else {
+                continue;
+            };
+            let tunneled = echo.tunneled;
+            if echo.kind != ICMP_ECHO_REPLY
+                || tunneled.dir != DIR_DOWN
+                || tunneled.conv != self.conv
+                || tunneled.kcp.is_empty()
+            {
+                continue;
+            }
+            return Ok(vec![tunneled.kcp.to_vec()]);
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! An experimental transport of last resort, for networks that block everything but ping. KCP packets ride in the payloads of ICMP echo requests and replies; since the other end can only answer requests, the client keeps polling with empty requests so that data can flow back.
+//!
+//! This is slow and easy to spot, and needs the right privileges on both ends: the client needs unprivileged ping sockets (or a raw socket), and the listener needs a raw socket.
+
+use std::net::UdpSocket;
+
+use async_io::Async;
+use socket2::{Domain, Protocol, Socket, Type};
+
+pub mod dialer;
+pub mod listener;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+
+/// Every tunneled payload starts with this, so that ordinary pings are ignored.
+const MAGIC: [u8; 4] = *b"g5ic";
+
+/// Which way a payload goes. The kernel's own replies to our requests echo the request back unchanged, so telling the directions apart lets the client ignore them.
+const DIR_UP: u8 = 0;
+const DIR_DOWN: u8 = 1;
+
+/// The tunnel's header within an echo payload, followed by a KCP packet, or nothing for a poll.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Tunneled<'a> {
+    dir: u8,
+    conv: u32,
+    /// The port on the listener's host that the client wants to reach.
+    port: u16,
+    kcp: &'a [u8],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Echo<'a> {
+    kind: u8,
+    ident: u16,
+    seq: u16,
+    tunneled: Tunneled<'a>,
+}
+
+fn encode_echo(echo: &Echo) -> Vec<u8> {
+    let mut pkt = vec![echo.kind, 0, 0, 0];
+    pkt.extend_from_slice(&echo.ident.to_be_bytes());
+    pkt.extend_from_slice(&echo.seq.to_be_bytes());
+    pkt.extend_from_slice(&MAGIC);
+    pkt.push(echo.tunneled.dir);
+    pkt.extend_from_slice(&echo.tunneled.conv.to_be_bytes());
+    pkt.extend_from_slice(&echo.tunneled.port.to_be_bytes());
+    pkt.extend_from_slice(echo.tunneled.kcp);
+    let checksum = checksum(&pkt);
+    pkt[2..4].copy_from_slice(&checksum.to_be_bytes());
+    pkt
+}
+
+/// Parses a tunneled echo packet. Raw sockets, and ping sockets on some platforms, hand us the IPv4 header too, which we skip.
+fn parse_echo(buf: &[u8]) -> Option<Echo<'_>> {
+    let buf = if buf.first()? >> 4 == 4 {
+        buf.get(((buf[0] & 0x0f) as usize * 4)..)?
+    } else {
+        buf
+    };
+    let payload = buf.get(8..)?;
+    if payload.get(..4)? != MAGIC {
+        return None;
+    }
+    Some(Echo {
+        kind: buf[0],
+        ident: u16::from_be_bytes([buf[4], buf[5]]),
+        seq: u16::from_be_bytes([buf[6], buf[7]]),
+        tunneled: Tunneled {
+            dir: *payload.get(4)?,
+            conv: u32::from_be_bytes(payload.get(5..9)?.try_into().ok()?),
+            port: u16::from_be_bytes(payload.get(9..11)?.try_into().ok()?),
+            kcp: &payload[11..],
+        },
+    })
+}
+
+/// The Internet checksum over the whole ICMP packet.
+fn checksum(buf: &[u8]) -> u16 {
+    let mut sum: u32 = buf
+        .chunks(2)
+        .map(|c| u16::from_be_bytes([c[0], c.get(1).copied().unwrap_or(0)]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Opens an ICMPv4 socket, preferring an unprivileged ping socket unless a raw one is needed to see other hosts' requests.
+fn icmp_socket(raw: bool) -> std::io::Result<Async<UdpSocket>> {
+    let open = |ty| Socket::new(Domain::IPV4, ty, Some(Protocol::ICMPV4));
+    let socket = if raw {
+        open(Type::RAW)?
+    } else {
+        open(Type::DGRAM).or_else(|_| open(Type::RAW))?
+    };
+    Async::new(UdpSocket::from(socket))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn echo_roundtrip() {
+        let echo = Echo {
+            kind: ICMP_ECHO_REQUEST,
+            ident: 1234,
+            seq: 5,
+            tunneled: Tunneled {
+                dir: DIR_UP,
+                conv: 0xdeadbeef,
+                port: 4433,
+                kcp: b"hello world",
+            },
+        };
+        let pkt = encode_echo(&echo);
+        assert_eq!(checksum(&pkt), 0);
+        assert_eq!(parse_echo(&pkt), Some(echo));
+
+        // as a raw socket would see it, behind an IPv4 header
+        let mut with_ip = vec![0x45];
+        with_ip.extend_from_slice(&[0; 19]);
+        with_ip.extend_from_slice(&pkt);
+        assert_eq!(parse_echo(&with_ip), Some(echo));
+
+        // an ordinary ping is not ours
+        assert_eq!(
+            parse_echo(&[8, 0, 0, 0, 0, 1, 0, 1, b'a', b'b', b'c', b'd']),
+            None
+        );
+    }
+}
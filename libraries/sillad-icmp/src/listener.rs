@@ -0,0 +1,139 @@
+use std::{
+    io::ErrorKind,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    sync::Arc,
+    time::Instant,
+};
+
+use async_io::Async;
+use async_task::Task;
+use sillad_kcp::{KcpParams, KcpPipe, PolledSessions, MTU};
+use tachyonix::{Receiver, Sender};
+
+use crate::{
+    encode_echo, icmp_socket, parse_echo, Echo, Tunneled, DIR_DOWN, DIR_UP, ICMP_ECHO_REPLY,
+    ICMP_ECHO_REQUEST,
+};
+
+/// How many sessions one listener serves at most.
+const MAX_SESSIONS: usize = 1000;
+
+/// A listener for ICMP tunnels, which answers tunneled echo requests with echo replies carrying its side of each session. It needs a raw socket, and thus root or `CAP_NET_RAW`.
+///
+/// The kernel keeps answering pings on its own, which clients ignore; setting `net.ipv4.icmp_echo_ignore_all` avoids the duplicate replies without affecting the listener.
+pub struct IcmpListener {
+    recv_pipe: Receiver<(KcpPipe, u16)>,
+    _task: Task<()>,
+}
+
+impl IcmpListener {
+    /// Starts listening for tunneled echo requests, answering at most `max_pps` of them per second overall.
+    pub fn bind(params: KcpParams, max_pps: u32) -> std::io::Result<Self> {
+        let socket = Arc::new(icmp_socket(true)?);
+        let (send_pipe, recv_pipe) = tachyonix::channel(1);
+        let _task = smolscale::spawn(listen_loop(socket, params, max_pps, send_pipe));
+        Ok(Self { recv_pipe, _task })
+    }
+
+    /// Accepts a new session, along with the port on this host that the client wants to reach. Which ports may be reached is up to the caller.
+    pub async fn accept(&mut self) -> std::io::Result<(KcpPipe, u16)> {
+        self.recv_pipe
+            .recv()
+            .await
+            .map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, "ICMP listener died"))
+    }
+}
+
+/// A token bucket that allows short bursts of up to a second's worth of packets.
+struct RateLimit {
+    per_sec: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimit {
+    fn new(per_sec: u32) -> Self {
+        Self {
+            per_sec: per_sec as f64,
+            tokens: per_sec as f64,
+            last: Instant::now(),
+        }
+    }
+
+    fn try_take(&mut self) -> bool {
+        let now = Instant::now();
+        self.tokens =
+            (self.tokens + (now - self.last).as_secs_f64() * self.per_sec).min(self.per_sec);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+async fn listen_loop(
+    socket: Arc<Async<UdpSocket>>,
+    params: KcpParams,
+    max_pps: u32,
+    send_pipe: Sender<(KcpPipe, u16)>,
+) {
+    let mut sessions = PolledSessions::new(params, MTU, MAX_SESSIONS, "icmp");
+    let mut limit = RateLimit::new(max_pps);
+    let mut buf = [0u8; 65536];
+    loop {
+        let (n, addr) = match socket.recv_from(&mut buf).await {
+            Ok(res) => res,
+            Err(err) => {
+                tracing::debug!(err = debug(err), "ICMP listener could not receive");
+                continue;
+            }
+        };
+        let IpAddr::V4(src) = addr.ip() else {
+            continue;
+        };
+        let Some(echo) = parse_echo(&buf[..n]) else {
+            continue;
+        };
+        let tunneled = echo.tunneled;
+        if echo.kind != ICMP_ECHO_REQUEST || tunneled.dir != DIR_UP {
+            continue;
+        }
+        let key = (src, tunneled.conv);
+        if let Some(pipe) = sessions.input(key, tunneled.conv, tunneled.kcp, || {
+            SocketAddr::new(src.into(), 0).to_string()
+        }) {
+            if send_pipe.send((pipe, tunneled.port)).await.is_err() {
+                return;
+            }
+        }
+        let Some(session) = sessions.get_mut(&key) else {
+            continue;
+        };
+
+        // every request gets at most one reply, carrying whatever the session has queued
+        if !limit.try_take() {
+            continue;
+        }
+        let kcp = session.next_packet().unwrap_or_default();
+        let reply = encode_echo(&Echo {
+            kind: ICMP_ECHO_REPLY,
+            ident: echo.ident,
+            seq: echo.seq,
+            tunneled: Tunneled {
+                dir: DIR_DOWN,
+                conv: tunneled.conv,
+                port: tunneled.port,
+                kcp: &kcp,
+            },
+        });
+        // like on the wire, a reply that does not fit in the socket is lost
+        if let Err(err) = socket.get_ref().send_to(&reply, addr) {
+            if err.kind() != ErrorKind::WouldBlock {
+                tracing::debug!(err = debug(err), "ICMP listener could not send");
+            }
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use async_io::Async;
+use async_trait::async_trait;
+use sillad::dialer::Dialer;
+
+use crate::{
+    obfs::{PacketObfs, OVERHEAD},
+    session::{start_session, UdpOutput, MTU},
+    KcpParams, KcpPipe,
+};
+
+/// A dialer for KCP listeners with the same cookie. There is no handshake, so dialing always succeeds, and an unreachable listener, or one with another cookie, only shows up as a pipe that never hears back.
+pub struct KcpDialer {
+    pub dest_addr: SocketAddr,
+    pub params: KcpParams,
+    pub cookie: String,
+}
+
+#[async_trait]
+impl Dialer for KcpDialer {
+    type P = KcpPipe;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let bind_addr: SocketAddr = if self.dest_addr.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let socket = Arc::new(Async::<std::net::UdpSocket>::bind(bind_addr)?);
+        socket.get_ref().connect(self.dest_addr)?;
+        let conv: u32 = rand::random();
+        let obfs = Arc::new(PacketObfs::new(&self.cookie));
+        let (send_pkt, recv_pkt) = tachyonix::channel(1000);
+        let recv_socket = socket.clone();
+        let recv_obfs = obfs.clone();
+        let recv_task = smolscale::spawn(async move {
+            let mut buf = [0u8; 65536];
+            loop {
+                let n = match recv_socket.recv(&mut buf).await {
+                    Ok(n) => n,
+                    Err(err) => {
+                        tracing::debug!(err = debug(err), "KCP socket stopped");
+                        return;
+                    }
+                };
+                let Some(pkt) = recv_obfs.open(&buf[..n]) else {
+                    continue;
+                };
+                if pkt.len() < kcp::KCP_OVERHEAD || kcp::get_conv(&pkt) != conv {
+                    continue;
+                }
+                // like any UDP packet, one that does not fit in the queue is dropped
+                if let Err(tachyonix::TrySendError::Closed(_)) = send_pkt.try_send(pkt) {
+                    return;
+                }
+            }
+        });
+        Ok(start_session(
+            conv,
+            self.params,
+            MTU - OVERHEAD,
+            UdpOutput {
+                socket,
+                dest: None,
+                obfs,
+            },
+            recv_pkt,
+            vec![recv_task],
+            "kcp",
+            self.dest_addr.to_string(),
+        ))
+    }
+}
@@ -0,0 +1,153 @@
+use std::pin::Pin;
+
+use async_task::Task;
+use bipe::{BipeReader, BipeWriter};
+use futures_util::{AsyncRead, AsyncWrite};
+use pin_project::pin_project;
+use serde::{Deserialize, Serialize};
+use sillad::Pipe;
+
+pub mod dialer;
+pub mod listener;
+mod obfs;
+mod poll;
+mod session;
+
+pub use poll::{start_polling_session, PollCarrier, PollTiming, PolledSession, PolledSessions};
+pub use session::{start_session, KCP_CMD_PUSH, MTU};
+
+/// The tuning knobs of KCP. The defaults are KCP's "turbo" mode, which trades bandwidth for latency and keeps going under heavy loss.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KcpParams {
+    /// Whether to acknowledge right away and not back off retransmission timeouts exponentially.
+    pub nodelay: bool,
+    /// How often, in milliseconds, KCP runs its timers and sends out what is queued.
+    pub interval_ms: u32,
+    /// After how many later packets get acknowledged a packet is resent without waiting for its timeout, with 0 meaning never.
+    pub resend: u32,
+    /// Whether to send as fast as the windows allow, without congestion control.
+    pub no_congestion_control: bool,
+    /// The send and receive windows, in packets.
+    pub send_window: u16,
+    pub recv_window: u16,
+}
+
+impl Default for KcpParams {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            interval_ms: 20,
+            resend: 2,
+            no_congestion_control: true,
+            send_window: 512,
+            recv_window: 512,
+        }
+    }
+}
+
+/// A pipe carried by a KCP session, usually over UDP. KCP has no notion of closing, so we mark the end of each direction within the stream, and a session that hears nothing from the other end for a while ends.
+#[pin_project]
+pub struct KcpPipe {
+    #[pin]
+    read_incoming: BipeReader,
+    #[pin]
+    write_outgoing: BipeWriter,
+    _tasks: Vec<Task<()>>,
+
+    protocol: &'static str,
+    remote_addr: String,
+}
+
+impl AsyncRead for KcpPipe {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        self.project().read_incoming.poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for KcpPipe {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        self.project().write_outgoing.poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.project().write_outgoing.poll_flush(cx)
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.project().write_outgoing.poll_close(cx)
+    }
+}
+
+impl Pipe for KcpPipe {
+    fn protocol(&self) -> &str {
+        self.protocol
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        Some(&self.remote_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{AsyncReadExt, AsyncWriteExt};
+    use sillad::{dialer::Dialer, listener::Listener};
+
+    use crate::{dialer::KcpDialer, listener::KcpListener, KcpParams};
+
+    #[test]
+    fn kcp_roundtrip() {
+        smol::future::block_on(async {
+            let mut listener = KcpListener::bind(
+                "127.0.0.1:0".parse().unwrap(),
+                KcpParams::default(),
+                "cookie",
+            )
+            .await
+            .unwrap();
+            // a dialer with the wrong cookie is never heard
+            let mut stranger = KcpDialer {
+                dest_addr: listener.local_addr(),
+                params: KcpParams::default(),
+                cookie: "wrong".into(),
+            }
+            .dial()
+            .await
+            .unwrap();
+            stranger.write_all(b"knock knock").await.unwrap();
+            let dialer = KcpDialer {
+                dest_addr: listener.local_addr(),
+                params: KcpParams::default(),
+                cookie: "cookie".into(),
+            };
+            let mut client = dialer.dial().await.unwrap();
+            client.write_all(b"hello").await.unwrap();
+            let mut server = listener.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+
+            let msg = vec![42u8; 100_000];
+            server.write_all(&msg).await.unwrap();
+            server.close().await.unwrap();
+            let mut buf = vec![];
+            client.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, msg);
+        })
+    }
+}
@@ -0,0 +1,120 @@
+use std::{collections::HashMap, io::ErrorKind, net::SocketAddr, sync::Arc};
+
+use async_io::Async;
+use async_task::Task;
+use async_trait::async_trait;
+use sillad::listener::Listener;
+use tachyonix::{Receiver, Sender, TrySendError};
+
+use crate::{
+    obfs::{PacketObfs, OVERHEAD},
+    session::{start_session, UdpOutput, KCP_CMD_PUSH, MTU},
+    KcpParams, KcpPipe,
+};
+
+/// How many sessions one listener serves at most.
+const MAX_SESSIONS: usize = 10000;
+
+/// A KCP listener, which tells sessions apart by their source address and conversation ID, and ignores packets not sealed with its cookie.
+pub struct KcpListener {
+    recv_pipe: Receiver<KcpPipe>,
+    local_addr: SocketAddr,
+    _task: Task<()>,
+}
+
+impl KcpListener {
+    /// Creates a new KcpListener by listening to a particular UDP address.
+    pub async fn bind(addr: SocketAddr, params: KcpParams, cookie: &str) -> std::io::Result<Self> {
+        let socket = Arc::new(Async::<std::net::UdpSocket>::bind(addr)?);
+        let local_addr = socket.get_ref().local_addr()?;
+        let (send_pipe, recv_pipe) = tachyonix::channel(1);
+        let obfs = Arc::new(PacketObfs::new(cookie));
+        let _task = smolscale::spawn(listen_loop(socket, params, obfs, send_pipe));
+        Ok(Self {
+            recv_pipe,
+            local_addr,
+            _task,
+        })
+    }
+
+    /// Get the local listening address.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+#[async_trait]
+impl Listener for KcpListener {
+    type P = KcpPipe;
+
+    async fn accept(&mut self) -> std::io::Result<Self::P> {
+        self.recv_pipe
+            .recv()
+            .await
+            .map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, "KCP listener died"))
+    }
+}
+
+async fn listen_loop(
+    socket: Arc<Async<std::net::UdpSocket>>,
+    params: KcpParams,
+    obfs: Arc<PacketObfs>,
+    send_pipe: Sender<KcpPipe>,
+) {
+    let mut sessions: HashMap<(SocketAddr, u32), Sender<Vec<u8>>> = HashMap::new();
+    let mut buf = [0u8; 65536];
+    loop {
+        let (n, addr) = match socket.recv_from(&mut buf).await {
+            Ok(res) => res,
+            Err(err) => {
+                tracing::debug!(err = debug(err), "KCP listener could not receive");
+                continue;
+            }
+        };
+        let Some(pkt) = obfs.open(&buf[..n]) else {
+            continue;
+        };
+        if pkt.len() < kcp::KCP_OVERHEAD {
+            continue;
+        }
+        let key = (addr, kcp::get_conv(&pkt));
+        if let Some(send_pkt) = sessions.get(&key) {
+            // like any UDP packet, one that does not fit in the queue is dropped
+            if let Err(TrySendError::Closed(_)) = send_pkt.try_send(pkt) {
+                sessions.remove(&key);
+            }
+            continue;
+        }
+        // stray acknowledgements for sessions that already ended must not start new ones
+        if pkt[4] != KCP_CMD_PUSH {
+            continue;
+        }
+        if sessions.len() >= MAX_SESSIONS {
+            sessions.retain(|_, send_pkt| !send_pkt.is_closed());
+            if sessions.len() >= MAX_SESSIONS {
+                tracing::warn!("too many KCP sessions, dropping a new one");
+                continue;
+            }
+        }
+        let (send_pkt, recv_pkt) = tachyonix::channel(1000);
+        let _ = send_pkt.try_send(pkt);
+        sessions.insert(key, send_pkt);
+        let pipe = start_session(
+            key.1,
+            params,
+            MTU - OVERHEAD,
+            UdpOutput {
+                socket: socket.clone(),
+                dest: Some(addr),
+                obfs: obfs.clone(),
+            },
+            recv_pkt,
+            vec![],
+            "kcp",
+            addr.to_string(),
+        );
+        if send_pipe.send(pipe).await.is_err() {
+            return;
+        }
+    }
+}
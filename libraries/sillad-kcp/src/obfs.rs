@@ -0,0 +1,44 @@
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit, Nonce};
+use rand::RngCore;
+
+const NONCE_LEN: usize = 12;
+
+/// How many bytes sealing adds to every packet: the nonce and the authentication tag.
+pub(crate) const OVERHEAD: usize = NONCE_LEN + 16;
+
+/// Encrypts every KCP packet under a key derived from the cookie, so that nothing on the wire looks like KCP, and so that packets from anyone without the cookie are dropped before KCP sees them.
+pub(crate) struct PacketObfs {
+    cipher: ChaCha20Poly1305,
+}
+
+impl PacketObfs {
+    pub fn new(cookie: &str) -> Self {
+        let key = blake3::derive_key("sillad-kcp obfs", cookie.as_bytes());
+        Self {
+            cipher: ChaCha20Poly1305::new(&key.into()),
+        }
+    }
+
+    pub fn seal(&self, pkt: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let mut sealed = nonce.to_vec();
+        sealed.extend(
+            self.cipher
+                .encrypt(Nonce::from_slice(&nonce), pkt)
+                .expect("encryption cannot fail"),
+        );
+        sealed
+    }
+
+    /// Opens a sealed packet, or returns None if it was not sealed with our cookie.
+    pub fn open(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < OVERHEAD {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .ok()
+    }
+}
@@ -0,0 +1,316 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    io::{ErrorKind, Write},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_io::Timer;
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use smol_timeout2::TimeoutExt;
+use tachyonix::{Receiver, Sender, TrySendError};
+
+use crate::{start_session, KcpParams, KcpPipe, KCP_CMD_PUSH};
+
+/// How many KCP packets may wait to be sent, beyond which they are dropped like on the wire.
+const QUEUE_SIZE: usize = 1000;
+
+/// Where a KCP session's packets go: a queue that a task drains into whatever carries them. Writing never blocks; if the queue is full, the packet is lost.
+struct QueueOutput(Sender<Vec<u8>>);
+
+impl Write for QueueOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.0.try_send(buf.to_vec()) {
+            Err(TrySendError::Closed(_)) => Err(ErrorKind::BrokenPipe.into()),
+            _ => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A carrier of KCP packets where the listener can only answer the dialer's polls, such as DNS queries or ICMP echo requests. The carrier does the encoding; [`start_polling_session`] does the polling.
+#[async_trait]
+pub trait PollCarrier: Send + Sync + 'static {
+    /// Sends one poll, carrying a KCP packet, or nothing if the packet is empty.
+    async fn send_poll(&self, kcp: &[u8]) -> std::io::Result<()>;
+
+    /// Receives the next answer to our polls, returning the KCP packets it carries, which may be none.
+    async fn recv_answer(&self) -> std::io::Result<Vec<Vec<u8>>>;
+}
+
+/// How the dialing side of a polled session paces its polls.
+#[derive(Clone, Copy, Debug)]
+pub struct PollTiming {
+    /// How often we poll while data is flowing.
+    pub fast: Duration,
+    /// How long after the last data we keep polling fast.
+    pub active_window: Duration,
+    /// How often we poll an idle session.
+    pub slow: Duration,
+    /// The most polls we send per second, counting those carrying data. Since every answer answers a poll, this caps traffic both ways.
+    pub max_pps: u32,
+}
+
+/// Starts the dialing side of a KCP session over a polled carrier, which keeps polling so that the listener has something to answer with its data.
+pub fn start_polling_session(
+    conv: u32,
+    params: KcpParams,
+    mtu: usize,
+    timing: PollTiming,
+    carrier: impl PollCarrier,
+    protocol: &'static str,
+    remote_addr: String,
+) -> KcpPipe {
+    let carrier = Arc::new(carrier);
+    let last_active = Arc::new(Mutex::new(Instant::now()));
+    let gap = Duration::from_secs_f64(1.0 / timing.max_pps.max(1) as f64);
+
+    let (send_out, mut recv_out) = tachyonix::channel(QUEUE_SIZE);
+    let send_carrier = carrier.clone();
+    let send_active = last_active.clone();
+    let send_task = smolscale::spawn(async move {
+        let fallible = async {
+            loop {
+                let poll = if send_active.lock().elapsed() < timing.active_window {
+                    timing.fast
+                } else {
+                    timing.slow
+                };
+                // with nothing to send, we send an empty poll, so that the listener has something to answer with its data
+                let kcp = match recv_out.recv().timeout(poll).await {
+                    Some(Ok(pkt)) => {
+                        *send_active.lock() = Instant::now();
+                        pkt
+                    }
+                    Some(Err(_)) => return Ok(()),
+                    None => vec![],
+                };
+                send_carrier.send_poll(&kcp).await?;
+                Timer::after(gap).await;
+            }
+        };
+        let res: std::io::Result<()> = fallible.await;
+        if let Err(err) = res {
+            tracing::debug!(err = debug(err), protocol, "polling send side stopped");
+        }
+    });
+
+    let (send_in, recv_in) = tachyonix::channel(QUEUE_SIZE);
+    let recv_task = smolscale::spawn(async move {
+        loop {
+            let packets = match carrier.recv_answer().await {
+                Ok(packets) => packets,
+                Err(err) => {
+                    tracing::debug!(err = debug(err), protocol, "polling receive side stopped");
+                    return;
+                }
+            };
+            for pkt in packets {
+                *last_active.lock() = Instant::now();
+                if let Err(TrySendError::Closed(_)) = send_in.try_send(pkt) {
+                    return;
+                }
+            }
+        }
+    });
+
+    start_session(
+        conv,
+        params,
+        mtu,
+        QueueOutput(send_out),
+        recv_in,
+        vec![send_task, recv_task],
+        protocol,
+        remote_addr,
+    )
+}
+
+/// The listening side of the KCP sessions over a polled carrier, keyed however the carrier tells sessions apart. The carrier feeds in what each poll carries, then answers it with what the session has queued.
+pub struct PolledSessions<K> {
+    sessions: HashMap<K, PolledSession>,
+    params: KcpParams,
+    mtu: usize,
+    max_sessions: usize,
+    protocol: &'static str,
+}
+
+impl<K: Hash + Eq + Clone> PolledSessions<K> {
+    /// Creates an empty set of sessions, whose KCP packets are at most `mtu` bytes long.
+    pub fn new(params: KcpParams, mtu: usize, max_sessions: usize, protocol: &'static str) -> Self {
+        Self {
+            sessions: HashMap::new(),
+            params,
+            mtu,
+            max_sessions,
+            protocol,
+        }
+    }
+
+    /// Feeds the KCP packet a poll carried, which may be empty, to the session with the given key, and returns the pipe of a new session if this is the first data packet of one. A session whose pipe is gone is forgotten.
+    pub fn input(
+        &mut self,
+        key: K,
+        conv: u32,
+        kcp: &[u8],
+        remote_addr: impl FnOnce() -> String,
+    ) -> Option<KcpPipe> {
+        let mut new_pipe = None;
+        // polls and stray acknowledgements for sessions that already ended must not start new ones
+        if !self.sessions.contains_key(&key) && kcp.get(4) == Some(&KCP_CMD_PUSH) {
+            if self.sessions.len() >= self.max_sessions {
+                self.sessions
+                    .retain(|_, session| !session.send_in.is_closed());
+            }
+            if self.sessions.len() < self.max_sessions {
+                let (send_in, recv_in) = tachyonix::channel(QUEUE_SIZE);
+                let (send_out, recv_out) = tachyonix::channel(QUEUE_SIZE);
+                new_pipe = Some(start_session(
+                    conv,
+                    self.params,
+                    self.mtu,
+                    QueueOutput(send_out),
+                    recv_in,
+                    vec![],
+                    self.protocol,
+                    remote_addr(),
+                ));
+                self.sessions.insert(
+                    key.clone(),
+                    PolledSession {
+                        send_in,
+                        recv_out,
+                        pending: None,
+                    },
+                );
+            } else {
+                tracing::warn!(
+                    protocol = self.protocol,
+                    "too many polled sessions, dropping a new one"
+                );
+            }
+        }
+        if let Some(session) = self.sessions.get(&key) {
+            if !kcp.is_empty() {
+                if let Err(TrySendError::Closed(_)) = session.send_in.try_send(kcp.to_vec()) {
+                    self.sessions.remove(&key);
+                }
+            }
+        }
+        new_pipe
+    }
+
+    /// The session with the given key, whose queued packets answer the poll.
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut PolledSession> {
+        self.sessions.get_mut(key)
+    }
+}
+
+/// One session on the listening side of a polled carrier.
+pub struct PolledSession {
+    send_in: Sender<Vec<u8>>,
+    recv_out: Receiver<Vec<u8>>,
+    /// A packet that did not fit in the last answer.
+    pending: Option<Vec<u8>>,
+}
+
+impl PolledSession {
+    /// Takes the next packet the session has queued, if any.
+    pub fn next_packet(&mut self) -> Option<Vec<u8>> {
+        self.pending
+            .take()
+            .or_else(|| self.recv_out.try_recv().ok())
+    }
+
+    /// Puts back a packet that did not fit in an answer, to go first in the next one.
+    pub fn put_back(&mut self, pkt: Vec<u8>) {
+        self.pending = Some(pkt);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::MTU;
+
+    /// Answers every poll right away, with at most one packet, like ICMP does.
+    struct Loopback {
+        sessions: Mutex<PolledSessions<u32>>,
+        send_accepted: smol::channel::Sender<KcpPipe>,
+        send_answer: smol::channel::Sender<Vec<Vec<u8>>>,
+        recv_answer: smol::channel::Receiver<Vec<Vec<u8>>>,
+    }
+
+    #[async_trait]
+    impl PollCarrier for Loopback {
+        async fn send_poll(&self, kcp: &[u8]) -> std::io::Result<()> {
+            let mut sessions = self.sessions.lock();
+            if let Some(pipe) = sessions.input(1, 1, kcp, || "loopback".into()) {
+                let _ = self.send_accepted.try_send(pipe);
+            }
+            let answer = sessions
+                .get_mut(&1)
+                .and_then(|session| session.next_packet())
+                .into_iter()
+                .collect();
+            let _ = self.send_answer.try_send(answer);
+            Ok(())
+        }
+
+        async fn recv_answer(&self) -> std::io::Result<Vec<Vec<u8>>> {
+            self.recv_answer
+                .recv()
+                .await
+                .map_err(|_| ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    #[test]
+    fn polled_roundtrip() {
+        smol::future::block_on(async {
+            let (send_accepted, recv_accepted) = smol::channel::unbounded();
+            let (send_answer, recv_answer) = smol::channel::unbounded();
+            let carrier = Loopback {
+                sessions: Mutex::new(PolledSessions::new(KcpParams::default(), MTU, 1, "test")),
+                send_accepted,
+                send_answer,
+                recv_answer,
+            };
+            let timing = PollTiming {
+                fast: Duration::from_millis(1),
+                active_window: Duration::from_secs(2),
+                slow: Duration::from_millis(50),
+                max_pps: 10000,
+            };
+            let mut client = start_polling_session(
+                1,
+                KcpParams::default(),
+                MTU,
+                timing,
+                carrier,
+                "test",
+                "loopback".into(),
+            );
+            client.write_all(b"hello").await.unwrap();
+            let mut server = recv_accepted.recv().await.unwrap();
+            let mut buf = [0u8; 5];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+
+            // everything the listener sends rides on the answers to polls
+            let msg = vec![42u8; 20_000];
+            server.write_all(&msg).await.unwrap();
+            server.close().await.unwrap();
+            let mut buf = vec![];
+            client.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, msg);
+        })
+    }
+}
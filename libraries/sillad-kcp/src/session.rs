@@ -0,0 +1,197 @@
+use std::{
+    io::{ErrorKind, Write},
+    net::{SocketAddr, UdpSocket},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_io::{Async, Timer};
+use async_task::Task;
+use futures_util::{AsyncReadExt, AsyncWriteExt};
+use kcp::Kcp;
+use parking_lot::Mutex;
+use smol_timeout2::TimeoutExt;
+
+use crate::{obfs::PacketObfs, KcpParams, KcpPipe};
+
+/// The largest message we hand to KCP at once, which it splits into packets.
+const MAX_MESSAGE: usize = 8192;
+
+/// The MTU of KCP's packets over UDP, which leaves room for the IP and UDP headers, and for tunnels underneath.
+pub const MTU: usize = 1350;
+
+/// How often we send something on an otherwise idle session, so that NAT mappings stay open and the other end knows we are alive.
+const KEEP_ALIVE: Duration = Duration::from_secs(10);
+
+/// How long a session may go without hearing from the other end before it ends.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Every message starts with one of these.
+const MSG_DATA: u8 = 0;
+const MSG_FIN: u8 = 1;
+const MSG_KEEP_ALIVE: u8 = 2;
+
+/// The command of the KCP packets that carry data, which are the only ones that may start a session.
+pub const KCP_CMD_PUSH: u8 = 81;
+
+/// Where KCP's packets go, sealed: either a connected socket, or a particular peer of a listening one. Sending never blocks; if the socket is full, the packet is lost, just like on the wire.
+pub(crate) struct UdpOutput {
+    pub socket: Arc<Async<UdpSocket>>,
+    pub dest: Option<SocketAddr>,
+    pub obfs: Arc<PacketObfs>,
+}
+
+impl Write for UdpOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let sealed = self.obfs.seal(buf);
+        let res = match self.dest {
+            Some(dest) => self.socket.get_ref().send_to(&sealed, dest),
+            None => self.socket.get_ref().send(&sealed),
+        };
+        match res {
+            Err(err) if err.kind() != ErrorKind::WouldBlock => Err(err),
+            _ => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn to_io_error(err: kcp::Error) -> std::io::Error {
+    std::io::Error::new(ErrorKind::Other, err)
+}
+
+/// Starts a KCP session, fed by the packets that arrive for it, and returns the pipe it carries. KCP's packets, at most `mtu` bytes long, are written to the output, which must never block.
+///
+/// This is public so that other packet carriers than UDP can reuse KCP; the protocol is what the pipe reports. The MTU must leave room for KCP's own header, and then some.
+#[allow(clippy::too_many_arguments)]
+pub fn start_session(
+    conv: u32,
+    params: KcpParams,
+    mtu: usize,
+    output: impl Write + Send + 'static,
+    mut incoming: tachyonix::Receiver<Vec<u8>>,
+    mut tasks: Vec<Task<()>>,
+    protocol: &'static str,
+    remote_addr: String,
+) -> KcpPipe {
+    let mut kcp = Kcp::new(conv, output);
+    kcp.set_nodelay(
+        params.nodelay,
+        params.interval_ms as i32,
+        params.resend as i32,
+        params.no_congestion_control,
+    );
+    kcp.set_wndsize(params.send_window, params.recv_window);
+    kcp.set_mtu(mtu).expect("MTU is valid");
+    let kcp = Arc::new(Mutex::new(kcp));
+    let start = Instant::now();
+    let now_ms = move || start.elapsed().as_millis() as u32;
+    let interval = Duration::from_millis(params.interval_ms.max(1).into());
+
+    let (mut write_incoming, read_incoming) = bipe::bipe(MAX_MESSAGE * 4);
+    let (write_outgoing, mut read_outgoing) = bipe::bipe(MAX_MESSAGE * 4);
+
+    let input_kcp = kcp.clone();
+    tasks.push(smolscale::spawn(async move {
+        let fallible = async {
+            let mut fin_received = false;
+            loop {
+                let pkt = incoming
+                    .recv()
+                    .timeout(IDLE_TIMEOUT)
+                    .await
+                    .ok_or_else(|| std::io::Error::new(ErrorKind::TimedOut, "KCP session idle"))?
+                    .map_err(|_| std::io::Error::from(ErrorKind::BrokenPipe))?;
+                let mut messages = vec![];
+                {
+                    let mut kcp = input_kcp.lock();
+                    if let Err(err) = kcp.input(&pkt) {
+                        tracing::trace!(err = debug(err), "bad KCP packet");
+                        continue;
+                    }
+                    // acknowledge right away, rather than at the next tick
+                    kcp.flush().map_err(to_io_error)?;
+                    while let Ok(size) = kcp.peeksize() {
+                        let mut msg = vec![0u8; size];
+                        kcp.recv(&mut msg).map_err(to_io_error)?;
+                        messages.push(msg);
+                    }
+                }
+                for msg in messages {
+                    match msg.first() {
+                        Some(&MSG_DATA) if !fin_received => {
+                            write_incoming.write_all(&msg[1..]).await?
+                        }
+                        Some(&MSG_FIN) if !fin_received => {
+                            fin_received = true;
+                            write_incoming.close().await?;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        };
+        let res: std::io::Result<()> = fallible.await;
+        if let Err(err) = res {
+            tracing::debug!(err = debug(err), "KCP session stopped");
+        }
+    }));
+
+    let output_kcp = kcp.clone();
+    tasks.push(smolscale::spawn(async move {
+        let fallible = async {
+            let mut buf = vec![0u8; MAX_MESSAGE];
+            loop {
+                // we stop reading when KCP has plenty queued, so that backpressure reaches the writer
+                while output_kcp.lock().wait_snd() >= params.send_window as usize * 2 {
+                    Timer::after(interval).await;
+                }
+                let n = read_outgoing.read(&mut buf[1..]).await?;
+                let mut kcp = output_kcp.lock();
+                if n == 0 {
+                    kcp.send(&[MSG_FIN]).map_err(to_io_error)?;
+                    kcp.flush().map_err(to_io_error)?;
+                    return std::io::Result::Ok(());
+                }
+                buf[0] = MSG_DATA;
+                kcp.send(&buf[..n + 1]).map_err(to_io_error)?;
+                kcp.flush().map_err(to_io_error)?;
+            }
+        };
+        if let Err(err) = fallible.await {
+            tracing::debug!(err = debug(err), "KCP send side stopped");
+        }
+    }));
+
+    tasks.push(smolscale::spawn(async move {
+        let fallible = async {
+            let mut last_keep_alive = Instant::now();
+            loop {
+                {
+                    let mut kcp = kcp.lock();
+                    if last_keep_alive.elapsed() > KEEP_ALIVE {
+                        kcp.send(&[MSG_KEEP_ALIVE]).map_err(to_io_error)?;
+                        last_keep_alive = Instant::now();
+                    }
+                    kcp.update(now_ms()).map_err(to_io_error)?;
+                }
+                Timer::after(interval).await;
+            }
+        };
+        let res: std::io::Result<()> = fallible.await;
+        if let Err(err) = res {
+            tracing::debug!(err = debug(err), "KCP timer stopped");
+        }
+    }));
+
+    KcpPipe {
+        read_incoming,
+        write_outgoing,
+        _tasks: tasks,
+        protocol,
+        remote_addr,
+    }
+}
@@ -0,0 +1,192 @@
+use std::{io::ErrorKind, time::Duration};
+
+use async_trait::async_trait;
+use futures_util::{io::BufReader, AsyncReadExt, AsyncWriteExt};
+use rand::RngCore;
+use sillad::{dialer::Dialer, Pipe};
+use smol_timeout2::TimeoutExt;
+
+use crate::{
+    http::{read_response, write_request, Head},
+    MeekPipe, HEADER_DOWN_OFFSET, HEADER_FIN, HEADER_SESSION, HEADER_UP_OFFSET, LONG_POLL,
+    MAX_BODY,
+};
+
+/// How long a request may take, on top of however long the listener holds polls.
+const REQUEST_SLACK: Duration = Duration::from_secs(10);
+
+/// How many times in a row a request may fail before the pipe is given up on.
+const MAX_FAILURES: u32 = 5;
+
+/// MeekDialer wraps a Dialer to carry pipes in HTTP POSTs, which can go through anything that forwards HTTP requests, such as a CDN.
+///
+/// Uploads and polls each get their own connection, and a failed request is simply retried on a new one, so pipes outlive connections that get reset. For domain fronting, the inner dialer should produce TLS pipes to the CDN with an innocuous SNI, while `host` names the domain that leads to the listener.
+pub struct MeekDialer<D: Dialer + Clone> {
+    pub inner: D,
+    /// The Host header, which is what CDNs route on.
+    pub host: String,
+    /// The path of every request, such as "/meek".
+    pub path: String,
+}
+
+#[async_trait]
+impl<D: Dialer + Clone> Dialer for MeekDialer<D> {
+    type P = MeekPipe;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let mut session_id = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut session_id);
+        let mut up_lane = Lane {
+            dialer: self.inner.clone(),
+            host: self.host.clone(),
+            path: self.path.clone(),
+            session_id: hex::encode(session_id),
+            conn: None,
+            remote_addr: None,
+        };
+        // an empty upload creates the session, and tells us whether the listener is reachable at all
+        up_lane
+            .request(&[(HEADER_UP_OFFSET, "0".into())], &[])
+            .await
+            .inspect_err(|e| {
+                tracing::warn!(
+                    err = debug(e),
+                    host = display(&self.host),
+                    "meek session could not be established"
+                )
+            })?;
+        let remote_addr = up_lane.remote_addr.clone();
+        let mut down_lane = Lane {
+            dialer: self.inner.clone(),
+            host: self.host.clone(),
+            path: self.path.clone(),
+            session_id: up_lane.session_id.clone(),
+            conn: None,
+            remote_addr: None,
+        };
+
+        let (mut write_incoming, read_incoming) = bipe::bipe(MAX_BODY);
+        let (write_outgoing, mut read_outgoing) = bipe::bipe(MAX_BODY);
+        let up_task = smolscale::spawn(async move {
+            let fallible = async {
+                let mut offset = 0u64;
+                let mut buf = vec![0u8; MAX_BODY];
+                loop {
+                    let n = read_outgoing.read(&mut buf).await?;
+                    let mut headers = vec![(HEADER_UP_OFFSET, offset.to_string())];
+                    if n == 0 {
+                        headers.push((HEADER_FIN, "1".into()));
+                    }
+                    up_lane.request_retrying(&headers, &buf[..n]).await?;
+                    if n == 0 {
+                        return std::io::Result::Ok(());
+                    }
+                    offset += n as u64;
+                }
+            };
+            if let Err(err) = fallible.await {
+                tracing::debug!(err = debug(err), "meek upload side stopped");
+            }
+        });
+        let down_task = smolscale::spawn(async move {
+            let fallible = async {
+                let mut offset = 0u64;
+                loop {
+                    let (head, body) = down_lane
+                        .request_retrying(&[(HEADER_DOWN_OFFSET, offset.to_string())], &[])
+                        .await?;
+                    write_incoming.write_all(&body).await?;
+                    offset += body.len() as u64;
+                    if head.header(HEADER_FIN).is_some() {
+                        write_incoming.close().await?;
+                        return std::io::Result::Ok(());
+                    }
+                }
+            };
+            if let Err(err) = fallible.await {
+                tracing::debug!(err = debug(err), "meek download side stopped");
+            }
+        });
+        Ok(MeekPipe {
+            read_incoming,
+            write_outgoing,
+            _tasks: vec![up_task, down_task],
+            remote_addr,
+        })
+    }
+}
+
+/// A sequence of requests in one direction, over one connection at a time.
+struct Lane<D: Dialer> {
+    dialer: D,
+    host: String,
+    path: String,
+    session_id: String,
+    conn: Option<BufReader<D::P>>,
+    remote_addr: Option<String>,
+}
+
+impl<D: Dialer> Lane<D> {
+    async fn request(
+        &mut self,
+        headers: &[(&str, String)],
+        body: &[u8],
+    ) -> std::io::Result<(Head, Vec<u8>)> {
+        if self.conn.is_none() {
+            let pipe = self.dialer.dial().await?;
+            self.remote_addr = pipe.remote_addr().map(|s| s.to_string());
+            self.conn = Some(BufReader::new(pipe));
+        }
+        let conn = self.conn.as_mut().unwrap();
+        let host = &self.host;
+        let path = &self.path;
+        let mut all_headers = vec![(HEADER_SESSION, self.session_id.clone())];
+        all_headers.extend_from_slice(headers);
+        let result = async {
+            write_request(conn.get_mut(), host, path, &all_headers, body).await?;
+            let (head, body) = read_response(conn).await?;
+            if head.start != "200" {
+                return Err(std::io::Error::new(
+                    ErrorKind::Other,
+                    format!("meek request failed with status {}", head.start),
+                ));
+            }
+            Ok((head, body))
+        }
+        .timeout(LONG_POLL + REQUEST_SLACK)
+        .await
+        .unwrap_or_else(|| {
+            Err(std::io::Error::new(
+                ErrorKind::TimedOut,
+                "meek request timed out",
+            ))
+        });
+        if result.is_err() {
+            // the connection may be in the middle of a message, so it cannot be reused
+            self.conn = None;
+        }
+        result
+    }
+
+    /// Retries a request over fresh connections, backing off in between, which is safe because the listener ignores data it has already seen.
+    async fn request_retrying(
+        &mut self,
+        headers: &[(&str, String)],
+        body: &[u8],
+    ) -> std::io::Result<(Head, Vec<u8>)> {
+        let mut failures = 0;
+        loop {
+            match self.request(headers, body).await {
+                Ok(res) => return Ok(res),
+                Err(err) => {
+                    failures += 1;
+                    if failures >= MAX_FAILURES {
+                        return Err(err);
+                    }
+                    tracing::debug!(err = debug(err), failures, "meek request failed, retrying");
+                    async_io::Timer::after(Duration::from_millis(200) * failures).await;
+                }
+            }
+        }
+    }
+}
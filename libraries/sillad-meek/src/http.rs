@@ -0,0 +1,172 @@
+use std::io::ErrorKind;
+
+use futures_util::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::MAX_BODY;
+
+const MAX_HEAD: usize = 8192;
+const MAX_HEADERS: usize = 32;
+
+/// The parts of an HTTP/1.1 request or response head that we care about.
+pub(crate) struct Head {
+    /// The path of a request, or the status code of a response.
+    pub start: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Head {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn content_length(&self) -> std::io::Result<usize> {
+        let len: usize = self
+            .header("Content-Length")
+            .unwrap_or("0")
+            .parse()
+            .map_err(|_| invalid("bad Content-Length"))?;
+        if len > MAX_BODY {
+            return Err(invalid("HTTP body too long"));
+        }
+        Ok(len)
+    }
+}
+
+pub(crate) fn invalid(msg: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Reads a head up to and including the empty line. Returns None on a clean EOF before anything arrives, which is how keep-alive connections end.
+async fn read_raw_head(
+    reader: &mut (impl AsyncBufRead + Unpin),
+) -> std::io::Result<Option<Vec<u8>>> {
+    let mut raw = vec![];
+    loop {
+        let n = reader.read_until(b'\n', &mut raw).await?;
+        if n == 0 {
+            if raw.is_empty() {
+                return Ok(None);
+            }
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        if raw.ends_with(b"\r\n\r\n") {
+            return Ok(Some(raw));
+        }
+        if raw.len() > MAX_HEAD {
+            return Err(invalid("HTTP head too long"));
+        }
+    }
+}
+
+fn collect_headers(headers: &[httparse::Header]) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|h| {
+            (
+                h.name.to_string(),
+                String::from_utf8_lossy(h.value).into_owned(),
+            )
+        })
+        .collect()
+}
+
+pub(crate) async fn read_request(
+    reader: &mut (impl AsyncBufRead + Unpin),
+) -> std::io::Result<Option<(Head, Vec<u8>)>> {
+    let Some(raw) = read_raw_head(reader).await? else {
+        return Ok(None);
+    };
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut req = httparse::Request::new(&mut headers);
+    if !req
+        .parse(&raw)
+        .map_err(|_| invalid("malformed HTTP request"))?
+        .is_complete()
+    {
+        return Err(invalid("incomplete HTTP request"));
+    }
+    let head = Head {
+        start: req.path.unwrap_or_default().to_string(),
+        headers: collect_headers(req.headers),
+    };
+    let body = read_body(reader, &head).await?;
+    Ok(Some((head, body)))
+}
+
+pub(crate) async fn read_response(
+    reader: &mut (impl AsyncBufRead + Unpin),
+) -> std::io::Result<(Head, Vec<u8>)> {
+    let raw = read_raw_head(reader)
+        .await?
+        .ok_or(std::io::Error::from(ErrorKind::UnexpectedEof))?;
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut resp = httparse::Response::new(&mut headers);
+    if !resp
+        .parse(&raw)
+        .map_err(|_| invalid("malformed HTTP response"))?
+        .is_complete()
+    {
+        return Err(invalid("incomplete HTTP response"));
+    }
+    let head = Head {
+        start: resp.code.unwrap_or_default().to_string(),
+        headers: collect_headers(resp.headers),
+    };
+    let body = read_body(reader, &head).await?;
+    Ok((head, body))
+}
+
+async fn read_body(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    head: &Head,
+) -> std::io::Result<Vec<u8>> {
+    let mut body = vec![0u8; head.content_length()?];
+    reader.read_exact(&mut body).await?;
+    Ok(body)
+}
+
+pub(crate) async fn write_request(
+    writer: &mut (impl AsyncWrite + Unpin),
+    host: &str,
+    path: &str,
+    headers: &[(&str, String)],
+    body: &[u8],
+) -> std::io::Result<()> {
+    let msg = format!(
+        "POST {path} HTTP/1.1\r\nHost: {host}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\n",
+        body.len()
+    );
+    write_message(writer, msg, headers, body).await
+}
+
+pub(crate) async fn write_response(
+    writer: &mut (impl AsyncWrite + Unpin),
+    status: &str,
+    headers: &[(&str, String)],
+    body: &[u8],
+) -> std::io::Result<()> {
+    let msg = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/octet-stream\r\nCache-Control: no-store\r\nContent-Length: {}\r\n",
+        body.len()
+    );
+    write_message(writer, msg, headers, body).await
+}
+
+async fn write_message(
+    writer: &mut (impl AsyncWrite + Unpin),
+    mut msg: String,
+    headers: &[(&str, String)],
+    body: &[u8],
+) -> std::io::Result<()> {
+    for (k, v) in headers {
+        msg.push_str(&format!("{k}: {v}\r\n"));
+    }
+    msg.push_str("\r\n");
+    let mut buf = msg.into_bytes();
+    buf.extend_from_slice(body);
+    writer.write_all(&buf).await?;
+    writer.flush().await
+}
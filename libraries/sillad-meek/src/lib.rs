@@ -0,0 +1,125 @@
+use std::{pin::Pin, time::Duration};
+
+use async_task::Task;
+use bipe::{BipeReader, BipeWriter};
+use futures_util::{AsyncRead, AsyncWrite};
+use pin_project::pin_project;
+use sillad::Pipe;
+
+pub mod dialer;
+mod http;
+pub mod listener;
+
+/// The largest body of any request or response.
+const MAX_BODY: usize = 65536;
+
+/// How long the listener holds a poll that has nothing to return. This must stay well below the request timeouts of common CDNs.
+const LONG_POLL: Duration = Duration::from_secs(10);
+
+/// Identifies which session a request belongs to.
+const HEADER_SESSION: &str = "X-Session-Id";
+/// On an upload, the offset in the upstream of the first byte of the body.
+const HEADER_UP_OFFSET: &str = "X-Up-Offset";
+/// On a poll, how much of the downstream the client already has.
+const HEADER_DOWN_OFFSET: &str = "X-Down-Offset";
+/// Marks the end of the upstream, or, on a poll response, of the downstream.
+const HEADER_FIN: &str = "X-Fin";
+
+/// MeekPipe is a pipe carried by a sequence of HTTP requests, each of which may travel over a different connection.
+#[pin_project]
+pub struct MeekPipe {
+    #[pin]
+    read_incoming: BipeReader,
+    #[pin]
+    write_outgoing: BipeWriter,
+    _tasks: Vec<Task<()>>,
+
+    remote_addr: Option<String>,
+}
+
+impl AsyncRead for MeekPipe {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        self.project().read_incoming.poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for MeekPipe {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        self.project().write_outgoing.poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.project().write_outgoing.poll_flush(cx)
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.project().write_outgoing.poll_close(cx)
+    }
+}
+
+impl Pipe for MeekPipe {
+    fn protocol(&self) -> &str {
+        "meek"
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        self.remote_addr.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{AsyncReadExt, AsyncWriteExt};
+    use sillad::{
+        dialer::{Dialer, DialerExt},
+        listener::Listener,
+        tcp::{TcpDialer, TcpListener},
+    };
+
+    use crate::{dialer::MeekDialer, listener::MeekListener};
+
+    #[test]
+    fn meek_roundtrip() {
+        smol::future::block_on(async {
+            let tcp = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let dest_addr = tcp.local_addr().await;
+            let mut listener = MeekListener::new(tcp, "/meek".into());
+            let dialer = MeekDialer {
+                inner: TcpDialer { dest_addr }.dynamic(),
+                host: "example.com".into(),
+                path: "/meek".into(),
+            };
+            let mut client = dialer.dial().await.unwrap();
+            let mut server = listener.accept().await.unwrap();
+
+            // more than one body's worth, so that it takes several requests each way
+            let msg = vec![42u8; 200_000];
+            client.write_all(&msg).await.unwrap();
+            let mut buf = vec![0u8; msg.len()];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, msg);
+
+            server.write_all(b"pong").await.unwrap();
+            server.close().await.unwrap();
+            let mut buf = vec![];
+            client.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, b"pong");
+        })
+    }
+}
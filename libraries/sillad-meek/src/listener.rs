@@ -0,0 +1,251 @@
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use bipe::{BipeReader, BipeWriter};
+use futures_util::{io::BufReader, AsyncReadExt, AsyncWriteExt};
+use sillad::{listener::Listener, Pipe};
+use smol_timeout2::TimeoutExt;
+
+use crate::{
+    http::{invalid, read_request, write_response},
+    MeekPipe, HEADER_DOWN_OFFSET, HEADER_FIN, HEADER_SESSION, HEADER_UP_OFFSET, LONG_POLL,
+    MAX_BODY,
+};
+
+/// How long a session may go without any requests before it is dropped.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// MeekListener wraps a Listener to accept pipes carried by MeekDialer's HTTP requests. Requests for other paths get a 404, like they would from any web server.
+pub struct MeekListener {
+    incoming: tachyonix::Receiver<MeekPipe>,
+    _accept_task: async_task::Task<()>,
+}
+
+struct Session {
+    up: async_lock::Mutex<Upstream>,
+    down: async_lock::Mutex<Downstream>,
+    last_seen: parking_lot::Mutex<Instant>,
+}
+
+struct Upstream {
+    /// How much of the upstream we have received.
+    offset: u64,
+    writer: BipeWriter,
+}
+
+struct Downstream {
+    /// The offset of the first byte in `buf`, which the client has not yet confirmed receiving.
+    base: u64,
+    buf: Vec<u8>,
+    reader: BipeReader,
+    fin: bool,
+}
+
+type SessionTable = Arc<parking_lot::Mutex<HashMap<String, Arc<Session>>>>;
+
+impl MeekListener {
+    pub fn new(mut inner: impl Listener, path: String) -> Self {
+        let (tx, rx) = tachyonix::channel(1);
+        let sessions = SessionTable::default();
+        let accept_task = smolscale::spawn(async move {
+            let _reap_task = smolscale::spawn(reap_loop(sessions.clone()));
+            loop {
+                let raw_conn = match inner.accept().await {
+                    Ok(conn) => conn,
+                    Err(err) => {
+                        tracing::warn!(err = debug(err), "underlying listener died");
+                        break;
+                    }
+                };
+                // a CDN may send requests for many sessions over one connection, so each connection is served on its own
+                smolscale::spawn(serve_conn(
+                    raw_conn,
+                    path.clone(),
+                    sessions.clone(),
+                    tx.clone(),
+                ))
+                .detach();
+            }
+        });
+
+        MeekListener {
+            incoming: rx,
+            _accept_task: accept_task,
+        }
+    }
+}
+
+#[async_trait]
+impl Listener for MeekListener {
+    type P = MeekPipe;
+
+    async fn accept(&mut self) -> std::io::Result<Self::P> {
+        self.incoming
+            .recv()
+            .await
+            .map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, "meek listener died"))
+    }
+}
+
+async fn reap_loop(sessions: SessionTable) {
+    loop {
+        async_io::Timer::after(SESSION_TIMEOUT / 4).await;
+        sessions
+            .lock()
+            .retain(|_, session| session.last_seen.lock().elapsed() < SESSION_TIMEOUT);
+    }
+}
+
+async fn serve_conn(
+    conn: impl Pipe,
+    path: String,
+    sessions: SessionTable,
+    tx: tachyonix::Sender<MeekPipe>,
+) {
+    let remote_addr = conn.remote_addr().map(|s| s.to_string());
+    let mut conn = BufReader::new(conn);
+    let fallible = async {
+        while let Some((head, body)) = read_request(&mut conn).await? {
+            if head.start != path {
+                write_response(conn.get_mut(), "404 Not Found", &[], &[]).await?;
+                return Ok(());
+            }
+            let Some(session_id) = head.header(HEADER_SESSION) else {
+                write_response(conn.get_mut(), "400 Bad Request", &[], &[]).await?;
+                return Ok(());
+            };
+            let up_offset = head.header(HEADER_UP_OFFSET);
+            // only the first upload, which is empty and at offset zero, may create a session
+            let (session, new_pipe) =
+                get_session(&sessions, session_id, up_offset == Some("0"), &remote_addr);
+            let Some(session) = session else {
+                write_response(conn.get_mut(), "404 Not Found", &[], &[]).await?;
+                return Ok(());
+            };
+            if let Some(pipe) = new_pipe {
+                if tx.send(pipe).await.is_err() {
+                    return Ok(());
+                }
+            }
+            *session.last_seen.lock() = Instant::now();
+
+            let result = match (up_offset, head.header(HEADER_DOWN_OFFSET)) {
+                (Some(offset), _) => {
+                    handle_upload(&session, offset, head.header(HEADER_FIN).is_some(), &body)
+                        .await
+                        .map(|_| (vec![], vec![]))
+                }
+                (None, Some(offset)) => handle_poll(&session, offset).await,
+                (None, None) => Err(invalid("meek request has no offset")),
+            };
+            match result {
+                Ok((headers, body)) => {
+                    write_response(conn.get_mut(), "200 OK", &headers, &body).await?
+                }
+                Err(err) => {
+                    tracing::debug!(err = debug(&err), "bad meek request");
+                    write_response(conn.get_mut(), "409 Conflict", &[], &[]).await?;
+                    return Ok(());
+                }
+            }
+        }
+        std::io::Result::Ok(())
+    };
+    if let Err(err) = fallible.await {
+        tracing::debug!(err = debug(err), "meek connection stopped");
+    }
+}
+
+fn get_session(
+    sessions: &SessionTable,
+    session_id: &str,
+    may_create: bool,
+    remote_addr: &Option<String>,
+) -> (Option<Arc<Session>>, Option<MeekPipe>) {
+    let mut sessions = sessions.lock();
+    if let Some(session) = sessions.get(session_id) {
+        return (Some(session.clone()), None);
+    }
+    if !may_create {
+        return (None, None);
+    }
+    let (up_writer, up_reader) = bipe::bipe(MAX_BODY);
+    let (down_writer, down_reader) = bipe::bipe(MAX_BODY);
+    let session = Arc::new(Session {
+        up: async_lock::Mutex::new(Upstream {
+            offset: 0,
+            writer: up_writer,
+        }),
+        down: async_lock::Mutex::new(Downstream {
+            base: 0,
+            buf: vec![],
+            reader: down_reader,
+            fin: false,
+        }),
+        last_seen: parking_lot::Mutex::new(Instant::now()),
+    });
+    sessions.insert(session_id.to_string(), session.clone());
+    let pipe = MeekPipe {
+        read_incoming: up_reader,
+        write_outgoing: down_writer,
+        _tasks: vec![],
+        remote_addr: remote_addr.clone(),
+    };
+    (Some(session), Some(pipe))
+}
+
+async fn handle_upload(
+    session: &Session,
+    offset: &str,
+    fin: bool,
+    body: &[u8],
+) -> std::io::Result<()> {
+    let offset: u64 = offset.parse().map_err(|_| invalid("bad upload offset"))?;
+    let mut up = session.up.lock().await;
+    if offset > up.offset {
+        return Err(invalid("upload skips ahead"));
+    }
+    // a retried upload may carry data we already have
+    let skip = ((up.offset - offset) as usize).min(body.len());
+    up.writer.write_all(&body[skip..]).await?;
+    up.offset += (body.len() - skip) as u64;
+    if fin && offset + body.len() as u64 == up.offset {
+        up.writer.close().await?;
+    }
+    Ok(())
+}
+
+async fn handle_poll(
+    session: &Session,
+    offset: &str,
+) -> std::io::Result<(Vec<(&'static str, String)>, Vec<u8>)> {
+    let offset: u64 = offset.parse().map_err(|_| invalid("bad poll offset"))?;
+    let mut down = session.down.lock().await;
+    let down = &mut *down;
+    if offset < down.base || offset > down.base + down.buf.len() as u64 {
+        return Err(invalid("poll offset out of range"));
+    }
+    // everything before the offset has arrived, so we no longer need to keep it for retries
+    down.buf.drain(..(offset - down.base) as usize);
+    down.base = offset;
+    if down.buf.is_empty() && !down.fin {
+        let mut chunk = vec![0u8; MAX_BODY];
+        match down.reader.read(&mut chunk).timeout(LONG_POLL).await {
+            None => {}
+            Some(Ok(0)) => down.fin = true,
+            Some(Ok(n)) => down.buf.extend_from_slice(&chunk[..n]),
+            Some(Err(err)) => return Err(err),
+        }
+    }
+    let body = down.buf[..down.buf.len().min(MAX_BODY)].to_vec();
+    let mut headers = vec![];
+    if down.fin && body.len() == down.buf.len() {
+        headers.push((HEADER_FIN, "1".to_string()));
+    }
+    Ok((headers, body))
+}
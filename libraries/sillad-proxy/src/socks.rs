@@ -0,0 +1,79 @@
+use std::{io::ErrorKind, net::SocketAddr};
+
+use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+fn socks_error(msg: impl Into<String>) -> std::io::Error {
+    std::io::Error::new(ErrorKind::ConnectionRefused, msg.into())
+}
+
+/// Asks the SOCKS5 server at the other end of the pipe to connect to the destination, authenticating with a username and password if given.
+pub async fn socks5_connect(
+    pipe: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    dest: SocketAddr,
+    credentials: Option<(&[u8], &[u8])>,
+) -> std::io::Result<()> {
+    let method = if credentials.is_some() { 0x02 } else { 0x00 };
+    pipe.write_all(&[5, 1, method]).await?;
+    let mut reply = [0u8; 2];
+    pipe.read_exact(&mut reply).await?;
+    if reply != [5, method] {
+        return Err(socks_error(
+            "SOCKS5 server refused our authentication method",
+        ));
+    }
+
+    if let Some((username, password)) = credentials {
+        if username.len() > 255 || password.len() > 255 {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "SOCKS5 credentials too long",
+            ));
+        }
+        let mut auth = vec![1, username.len() as u8];
+        auth.extend_from_slice(username);
+        auth.push(password.len() as u8);
+        auth.extend_from_slice(password);
+        pipe.write_all(&auth).await?;
+        pipe.read_exact(&mut reply).await?;
+        if reply[1] != 0 {
+            return Err(socks_error("SOCKS5 server rejected our credentials"));
+        }
+    }
+
+    let mut request = vec![5, 1, 0];
+    match dest {
+        SocketAddr::V4(v4) => {
+            request.push(1);
+            request.extend_from_slice(&v4.ip().octets());
+        }
+        SocketAddr::V6(v6) => {
+            request.push(4);
+            request.extend_from_slice(&v6.ip().octets());
+        }
+    }
+    request.extend_from_slice(&dest.port().to_be_bytes());
+    pipe.write_all(&request).await?;
+
+    let mut head = [0u8; 4];
+    pipe.read_exact(&mut head).await?;
+    if head[1] != 0 {
+        return Err(socks_error(format!(
+            "SOCKS5 server could not connect, reply code {}",
+            head[1]
+        )));
+    }
+    // skip the bound address, which we have no use for
+    let bound_len = match head[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut len = [0u8];
+            pipe.read_exact(&mut len).await?;
+            len[0] as usize
+        }
+        _ => return Err(socks_error("bad SOCKS5 address type")),
+    };
+    let mut bound = vec![0u8; bound_len + 2];
+    pipe.read_exact(&mut bound).await?;
+    Ok(())
+}
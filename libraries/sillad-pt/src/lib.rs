@@ -0,0 +1,199 @@
+use std::{
+    collections::HashMap, io::ErrorKind, net::SocketAddr, path::PathBuf, sync::LazyLock,
+    time::Duration,
+};
+
+use async_process::{Child, ChildStdin, Command, Stdio};
+use async_trait::async_trait;
+use futures_util::{io::BufReader, AsyncBufReadExt, StreamExt};
+use sillad::{
+    dialer::Dialer,
+    tcp::{TcpDialer, TcpPipe},
+};
+use smol_timeout2::TimeoutExt;
+
+pub mod server;
+
+/// How long a pluggable transport may take to start up and report its SOCKS listener.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// PtDialer dials a destination through a pluggable transport that follows Tor's PT 1.0 spec, so that obfuscation protocols can be added by dropping in an executable.
+///
+/// The executable is launched the first time it is needed, in managed client mode, and is shared by every PtDialer with the same command and transport. If it dies, it is launched again on the next dial.
+pub struct PtDialer {
+    /// The executable and its arguments.
+    pub command: Vec<String>,
+    /// The name of the transport, such as "obfs4".
+    pub transport: String,
+    /// Per-bridge arguments of the transport, such as obfs4's `cert` and `iat-mode`.
+    pub args: Vec<(String, String)>,
+    pub dest_addr: SocketAddr,
+    /// Where the transport may keep state across runs.
+    pub state_dir: PathBuf,
+}
+
+#[async_trait]
+impl Dialer for PtDialer {
+    type P = TcpPipe;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let socks_addr = self.socks_addr().await?;
+        let mut pipe = TcpDialer {
+            dest_addr: socks_addr,
+        }
+        .dial()
+        .await?;
+        // non-empty arguments go in the SOCKS5 username, spilling over into the password, which is a single NUL if they are short
+        let args = encode_args(&self.args);
+        let credentials = match args.len() {
+            0 => None,
+            1..=255 => Some((&args[..], &[0u8][..])),
+            _ => Some(args.split_at(255)),
+        };
+        sillad_proxy::socks5_connect(&mut pipe, self.dest_addr, credentials)
+            .await
+            .inspect_err(|e| {
+                tracing::warn!(
+                    err = debug(e),
+                    transport = display(&self.transport),
+                    dest_addr = display(self.dest_addr),
+                    "pluggable transport could not connect"
+                )
+            })?;
+        Ok(pipe)
+    }
+}
+
+/// A running pluggable transport. Dropping it closes its stdin, which tells it to exit, and kills it in case it does not.
+struct ManagedPt {
+    child: Child,
+    socks_addr: SocketAddr,
+    _stdin: ChildStdin,
+}
+
+static RUNNING: LazyLock<async_lock::Mutex<HashMap<(Vec<String>, String), ManagedPt>>> =
+    LazyLock::new(Default::default);
+
+impl PtDialer {
+    async fn socks_addr(&self) -> std::io::Result<SocketAddr> {
+        let mut running = RUNNING.lock().await;
+        let key = (self.command.clone(), self.transport.clone());
+        if let Some(pt) = running.get_mut(&key) {
+            if pt.child.try_status()?.is_none() {
+                return Ok(pt.socks_addr);
+            }
+            tracing::warn!(
+                transport = display(&self.transport),
+                "pluggable transport exited, starting it again"
+            );
+        }
+        let pt = self
+            .launch()
+            .timeout(STARTUP_TIMEOUT)
+            .await
+            .ok_or_else(|| {
+                std::io::Error::new(ErrorKind::TimedOut, "pluggable transport did not start")
+            })??;
+        let socks_addr = pt.socks_addr;
+        running.insert(key, pt);
+        Ok(socks_addr)
+    }
+
+    async fn launch(&self) -> std::io::Result<ManagedPt> {
+        let (program, program_args) = self.command.split_first().ok_or_else(|| {
+            std::io::Error::new(ErrorKind::InvalidInput, "empty pluggable transport command")
+        })?;
+        std::fs::create_dir_all(&self.state_dir)?;
+        let mut child = Command::new(program)
+            .args(program_args)
+            .env("TOR_PT_MANAGED_TRANSPORT_VER", "1")
+            .env("TOR_PT_CLIENT_TRANSPORTS", &self.transport)
+            .env("TOR_PT_STATE_LOCATION", &self.state_dir)
+            .env("TOR_PT_EXIT_ON_STDIN_CLOSE", "1")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let mut lines = BufReader::new(child.stdout.take().expect("stdout is piped")).lines();
+
+        // the transport tells us how it is doing, line by line, until it is ready
+        let mut socks_addr = None;
+        while let Some(line) = lines.next().await {
+            let line = line?;
+            tracing::debug!(line = display(&line), "pluggable transport says");
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                ["VERSION", _] => {}
+                ["CMETHOD", name, "socks5", addr] if *name == self.transport => {
+                    socks_addr = Some(addr.parse().map_err(|_| {
+                        std::io::Error::new(ErrorKind::InvalidData, "bad CMETHOD address")
+                    })?);
+                }
+                ["CMETHODS", "DONE"] => break,
+                [kind, ..] if kind.ends_with("-ERROR") => {
+                    return Err(std::io::Error::new(
+                        ErrorKind::Other,
+                        format!("pluggable transport failed: {line}"),
+                    ));
+                }
+                _ => {}
+            }
+        }
+        let socks_addr = socks_addr.ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::Other,
+                format!(
+                    "pluggable transport does not offer {} over SOCKS5",
+                    self.transport
+                ),
+            )
+        })?;
+
+        // later status lines, such as LOG, are only of interest to us when debugging
+        smolscale::spawn(async move {
+            while let Some(Ok(line)) = lines.next().await {
+                tracing::debug!(line = display(&line), "pluggable transport says");
+            }
+        })
+        .detach();
+
+        Ok(ManagedPt {
+            child,
+            socks_addr,
+            _stdin: stdin,
+        })
+    }
+}
+
+/// Encodes per-bridge arguments the way the PT spec passes them through SOCKS5 authentication: `k=v` pairs joined by semicolons, with backslashes escaping special characters.
+fn encode_args(args: &[(String, String)]) -> Vec<u8> {
+    let escape = |s: &str| {
+        s.chars()
+            .flat_map(|c| {
+                let escaped = matches!(c, '\\' | '=' | ';').then_some('\\');
+                escaped.into_iter().chain(std::iter::once(c))
+            })
+            .collect::<String>()
+    };
+    args.iter()
+        .map(|(k, v)| format!("{}={}", escape(k), escape(v)))
+        .collect::<Vec<_>>()
+        .join(";")
+        .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn args_are_escaped() {
+        let args = vec![
+            ("cert".to_string(), "a=b;c".to_string()),
+            ("iat-mode".to_string(), "0".to_string()),
+        ];
+        assert_eq!(encode_args(&args), b"cert=a\\=b\\;c;iat-mode=0");
+    }
+}
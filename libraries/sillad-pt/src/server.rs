@@ -0,0 +1,162 @@
+use std::{io::ErrorKind, net::SocketAddr, path::Path};
+
+use async_process::{Child, ChildStdin, Command, Stdio};
+use futures_util::{io::BufReader, AsyncBufReadExt, StreamExt};
+use smol_timeout2::TimeoutExt;
+
+use crate::STARTUP_TIMEOUT;
+
+/// A pluggable transport running in managed server mode, which takes obfuscated connections on its own listener and hands what they carry, as plain TCP, to the "OR port" it was started with. Dropping it closes its stdin, which tells it to exit, and kills it in case it does not.
+pub struct PtServer {
+    _child: Child,
+    _stdin: ChildStdin,
+    addr: SocketAddr,
+    args: Vec<(String, String)>,
+}
+
+impl PtServer {
+    /// Launches the executable, which is the first element of the command, to serve one transport on the bind address, forwarding to the OR port.
+    pub async fn launch(
+        command: &[String],
+        transport: &str,
+        bind_addr: SocketAddr,
+        orport: SocketAddr,
+        state_dir: &Path,
+    ) -> std::io::Result<Self> {
+        launch(command, transport, bind_addr, orport, state_dir)
+            .timeout(STARTUP_TIMEOUT)
+            .await
+            .ok_or_else(|| {
+                std::io::Error::new(ErrorKind::TimedOut, "pluggable transport did not start")
+            })?
+    }
+
+    /// Where the transport listens, as it reported.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The arguments that clients need to connect, such as obfs4's `cert` and `iat-mode`.
+    pub fn args(&self) -> &[(String, String)] {
+        &self.args
+    }
+}
+
+async fn launch(
+    command: &[String],
+    transport: &str,
+    bind_addr: SocketAddr,
+    orport: SocketAddr,
+    state_dir: &Path,
+) -> std::io::Result<PtServer> {
+    let (program, program_args) = command.split_first().ok_or_else(|| {
+        std::io::Error::new(ErrorKind::InvalidInput, "empty pluggable transport command")
+    })?;
+    std::fs::create_dir_all(state_dir)?;
+    let mut child = Command::new(program)
+        .args(program_args)
+        .env("TOR_PT_MANAGED_TRANSPORT_VER", "1")
+        .env("TOR_PT_SERVER_TRANSPORTS", transport)
+        .env("TOR_PT_SERVER_BINDADDR", format!("{transport}-{bind_addr}"))
+        .env("TOR_PT_ORPORT", orport.to_string())
+        .env("TOR_PT_STATE_LOCATION", state_dir)
+        .env("TOR_PT_EXIT_ON_STDIN_CLOSE", "1")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+    let stdin = child.stdin.take().expect("stdin is piped");
+    let mut lines = BufReader::new(child.stdout.take().expect("stdout is piped")).lines();
+
+    let mut method = None;
+    while let Some(line) = lines.next().await {
+        let line = line?;
+        tracing::debug!(line = display(&line), "pluggable transport server says");
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["VERSION", _] => {}
+            ["SMETHOD", name, addr, options @ ..] if *name == transport => {
+                let addr: SocketAddr = addr.parse().map_err(|_| {
+                    std::io::Error::new(ErrorKind::InvalidData, "bad SMETHOD address")
+                })?;
+                let args = options
+                    .iter()
+                    .find_map(|option| option.strip_prefix("ARGS:"))
+                    .map(decode_server_args)
+                    .unwrap_or_default();
+                method = Some((addr, args));
+            }
+            ["SMETHODS", "DONE"] => break,
+            [kind, ..] if kind.ends_with("-ERROR") => {
+                return Err(std::io::Error::new(
+                    ErrorKind::Other,
+                    format!("pluggable transport failed: {line}"),
+                ));
+            }
+            _ => {}
+        }
+    }
+    let (addr, args) = method.ok_or_else(|| {
+        std::io::Error::new(
+            ErrorKind::Other,
+            format!("pluggable transport does not serve {transport}"),
+        )
+    })?;
+
+    smolscale::spawn(async move {
+        while let Some(Ok(line)) = lines.next().await {
+            tracing::debug!(line = display(&line), "pluggable transport server says");
+        }
+    })
+    .detach();
+
+    Ok(PtServer {
+        _child: child,
+        _stdin: stdin,
+        addr,
+        args,
+    })
+}
+
+/// Decodes the `ARGS:` option of an SMETHOD line: `k=v` pairs joined by commas, with backslashes escaping special characters.
+fn decode_server_args(args: &str) -> Vec<(String, String)> {
+    let mut pairs = vec![];
+    // the key, once its unescaped equals sign has gone by, and what comes after it
+    let mut key = None;
+    let mut current = String::new();
+    let mut chars = args.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => current.extend(chars.next()),
+            '=' if key.is_none() => key = Some(std::mem::take(&mut current)),
+            ',' => {
+                if let Some(key) = key.take() {
+                    pairs.push((key, std::mem::take(&mut current)));
+                }
+                current.clear();
+            }
+            c => current.push(c),
+        }
+    }
+    if let Some(key) = key {
+        pairs.push((key, current));
+    }
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_args_are_unescaped() {
+        assert_eq!(
+            decode_server_args("cert=a\\,b=c,iat-mode=0"),
+            vec![
+                ("cert".to_string(), "a,b=c".to_string()),
+                ("iat-mode".to_string(), "0".to_string()),
+            ]
+        );
+    }
+}
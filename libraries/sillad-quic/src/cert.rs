@@ -0,0 +1,123 @@
+use std::{collections::HashMap, fmt::Debug, sync::Arc};
+
+use parking_lot::Mutex;
+use rand::Rng;
+use rcgen::{
+    BasicConstraints, CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair, KeyUsagePurpose,
+};
+use rustls::{
+    crypto::ring::sign::any_ecdsa_type,
+    pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use time::{Duration, OffsetDateTime};
+
+/// How many names we keep certificates for, beyond which we start over.
+const MAX_CACHED: usize = 1000;
+
+/// Makes out a certificate to whatever name the client asks for, issued by a throwaway CA, so that the handshake looks like that of a server with an ordinary, publicly issued certificate for the name. Clients never check it; authenticating the other end is left to the protocols running over the pipe.
+pub(crate) struct CertResolver {
+    issuer: rcgen::Certificate,
+    issuer_key: KeyPair,
+    /// The name for clients that ask for none, which is what happens when they reach us by IP address.
+    default_name: String,
+    cached: Mutex<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl CertResolver {
+    pub fn new() -> Result<Self, rcgen::Error> {
+        let issuer_key = KeyPair::generate()?;
+        let mut params = CertificateParams::default();
+        params.distinguished_name = distinguished_name(&[
+            (DnType::CountryName, "US"),
+            (DnType::OrganizationName, "Let's Encrypt"),
+            (DnType::CommonName, "E6"),
+        ]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+        params.key_usages = vec![
+            KeyUsagePurpose::DigitalSignature,
+            KeyUsagePurpose::KeyCertSign,
+            KeyUsagePurpose::CrlSign,
+        ];
+        let now = OffsetDateTime::now_utc();
+        params.not_before = now - Duration::days(300);
+        params.not_after = now + Duration::days(800);
+        let issuer = params.self_signed(&issuer_key)?;
+        let mut rng = rand::thread_rng();
+        let label: String = (0..rng.gen_range(6..12))
+            .map(|_| rng.gen_range(b'a'..=b'z') as char)
+            .collect();
+        Ok(Self {
+            issuer,
+            issuer_key,
+            default_name: format!("{label}.com"),
+            cached: Default::default(),
+        })
+    }
+
+    fn issue(&self, name: &str) -> Result<CertifiedKey, Box<dyn std::error::Error>> {
+        let key = KeyPair::generate()?;
+        let mut params = CertificateParams::new(vec![name.to_string()])?;
+        params.distinguished_name = distinguished_name(&[(DnType::CommonName, name)]);
+        params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+        params.extended_key_usages = vec![
+            ExtendedKeyUsagePurpose::ServerAuth,
+            ExtendedKeyUsagePurpose::ClientAuth,
+        ];
+        params.use_authority_key_identifier_extension = true;
+        params.serial_number = Some(rand::random::<[u8; 16]>().to_vec().into());
+        // like an ACME certificate, valid for 90 days from some time in the past few weeks
+        let not_before =
+            OffsetDateTime::now_utc() - Duration::hours(rand::thread_rng().gen_range(1..24 * 60));
+        params.not_before = not_before;
+        params.not_after = not_before + Duration::days(90);
+        let cert = params.signed_by(&key, &self.issuer, &self.issuer_key)?;
+        let signing_key = any_ecdsa_type(&PrivateKeyDer::from(PrivatePkcs8KeyDer::from(
+            key.serialize_der(),
+        )))?;
+        Ok(CertifiedKey::new(
+            vec![cert.der().clone(), self.issuer.der().clone()],
+            signing_key,
+        ))
+    }
+}
+
+impl Debug for CertResolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CertResolver")
+            .field("default_name", &self.default_name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let name = client_hello.server_name().unwrap_or(&self.default_name);
+        if let Some(certified) = self.cached.lock().get(name) {
+            return Some(certified.clone());
+        }
+        let certified = match self.issue(name) {
+            Ok(certified) => Arc::new(certified),
+            Err(err) => {
+                tracing::warn!(err = debug(err), name, "could not issue a QUIC certificate");
+                return None;
+            }
+        };
+        let mut cached = self.cached.lock();
+        if cached.len() >= MAX_CACHED {
+            cached.clear();
+        }
+        cached.insert(name.to_string(), certified.clone());
+        Some(certified)
+    }
+}
+
+fn distinguished_name(entries: &[(DnType, &str)]) -> DistinguishedName {
+    let mut dn = DistinguishedName::new();
+    for (ty, value) in entries {
+        dn.push(ty.clone(), *value);
+    }
+    dn
+}
@@ -0,0 +1,143 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use async_trait::async_trait;
+use futures_util::AsyncWriteExt;
+use quinn::{crypto::rustls::QuicClientConfig, ClientConfig, Endpoint, EndpointConfig};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::CryptoProvider,
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    DigitallySignedStruct, SignatureScheme,
+};
+use sillad::dialer::Dialer;
+
+use crate::{
+    obfs::{obfs_endpoint, ObfsConfig},
+    spawn_control_stream, to_io_error, transport_config, Congestion, QuicPipe, ALPN,
+};
+
+/// A dialer for QUIC listeners.
+///
+/// The server's certificate is not checked, since the listener makes one up for whatever name we ask for. Like with TCP, authenticating the other end is left to the protocols running over the pipe.
+pub struct QuicDialer {
+    pub dest_addr: SocketAddr,
+    /// The server name sent in the handshake, or none at all, like browsers reaching a bare IP address.
+    pub server_name: Option<String>,
+    pub congestion: Congestion,
+    /// Obfuscation of the packets, which the listener must be set up for too.
+    pub obfs: Option<ObfsConfig>,
+}
+
+#[async_trait]
+impl Dialer for QuicDialer {
+    type P = QuicPipe;
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let bind_addr: SocketAddr = if self.dest_addr.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let socket = std::net::UdpSocket::bind(bind_addr)?;
+        let endpoint = match &self.obfs {
+            Some(obfs) => obfs_endpoint(socket, obfs, None)?,
+            None => Endpoint::new(
+                EndpointConfig::default(),
+                None,
+                socket,
+                Arc::new(quinn::SmolRuntime),
+            )?,
+        };
+        let conn = endpoint
+            .connect_with(
+                client_config(
+                    self.congestion,
+                    self.obfs.is_some(),
+                    self.server_name.is_some(),
+                )?,
+                self.dest_addr,
+                // without SNI, the name only needs to parse
+                self.server_name.as_deref().unwrap_or("localhost"),
+            )
+            .map_err(to_io_error)?
+            .await
+            .inspect_err(|e| tracing::warn!("QUIC handshake failed: {:?}", e))?;
+        let _control = spawn_control_stream(&conn);
+        let (mut send, recv) = conn.open_bi().await?;
+        // the listener only learns about the stream once something is sent on it
+        send.write_all(&[0]).await?;
+        Ok(QuicPipe {
+            send,
+            recv,
+            _control,
+            _conn: conn,
+            _endpoint: Some(endpoint),
+            remote_addr: self.dest_addr.to_string(),
+        })
+    }
+}
+
+fn client_config(congestion: Congestion, obfs: bool, sni: bool) -> std::io::Result<ClientConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut crypto = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(to_io_error)?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(SkipVerification(provider)))
+        .with_no_client_auth();
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    crypto.enable_sni = sni;
+    let mut config = ClientConfig::new(Arc::new(
+        QuicClientConfig::try_from(crypto).map_err(to_io_error)?,
+    ));
+    config.transport_config(transport_config(congestion, obfs, false));
+    Ok(config)
+}
+
+/// Accepts any certificate, while still checking that the handshake is signed by it.
+#[derive(Debug)]
+struct SkipVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for SkipVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
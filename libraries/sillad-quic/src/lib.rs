@@ -0,0 +1,160 @@
+use std::{pin::Pin, str::FromStr, sync::Arc, time::Duration};
+
+use async_task::Task;
+use futures_util::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use quinn::{
+    congestion::{BbrConfig, ControllerFactory, CubicConfig, NewRenoConfig},
+    Endpoint, RecvStream, SendStream, TransportConfig,
+};
+use serde::{Deserialize, Serialize};
+use sillad::Pipe;
+
+mod cert;
+pub mod dialer;
+pub mod listener;
+pub mod obfs;
+
+/// The ALPN we present, which is what HTTP/3 uses so that the handshake blends in. Connections then go on like HTTP/3 ones, with a control stream from each end and the actual data on a request stream.
+const ALPN: &[u8] = b"h3";
+
+/// What starts each end's HTTP/3 control stream: the stream type, then a SETTINGS frame turning off QPACK's dynamic table, like HTTP/3 implementations send before anything else.
+const H3_CONTROL_PREAMBLE: &[u8] = &[0x00, 0x04, 0x04, 0x01, 0x00, 0x07, 0x00];
+
+/// How many request streams the listener lets each connection open, which is what HTTP/3 servers usually allow, even though only the first one is used.
+const MAX_REQUEST_STREAMS: u8 = 100;
+
+/// How many unidirectional streams each end lets the other open, enough for HTTP/3's control and QPACK streams.
+const MAX_UNI_STREAMS: u8 = 3;
+
+/// How long a connection may go without hearing from the peer before it is considered dead.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often we ping an otherwise idle connection, so that NAT mappings stay open.
+const KEEP_ALIVE: Duration = Duration::from_secs(10);
+
+/// The congestion controller of a QUIC pipe. Only the sending side's choice matters, so each end picks its own.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Congestion {
+    /// BBR, which keeps its throughput under random loss, unlike loss-based controllers.
+    #[default]
+    Bbr,
+    Cubic,
+    NewReno,
+}
+
+impl Congestion {
+    fn factory(self) -> Arc<dyn ControllerFactory + Send + Sync> {
+        match self {
+            Congestion::Bbr => Arc::new(BbrConfig::default()),
+            Congestion::Cubic => Arc::new(CubicConfig::default()),
+            Congestion::NewReno => Arc::new(NewRenoConfig::default()),
+        }
+    }
+}
+
+impl FromStr for Congestion {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bbr" => Ok(Congestion::Bbr),
+            "cubic" => Ok(Congestion::Cubic),
+            "new_reno" => Ok(Congestion::NewReno),
+            other => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("unknown congestion controller {other}"),
+            )),
+        }
+    }
+}
+
+/// With `obfs`, packets stay at QUIC's minimum size, so that the obfuscation and FEC overhead still fits in a typical MTU. Like with HTTP/3, only the dialer opens request streams.
+fn transport_config(congestion: Congestion, obfs: bool, server: bool) -> Arc<TransportConfig> {
+    let mut transport = TransportConfig::default();
+    if obfs {
+        transport.mtu_discovery_config(None);
+    }
+    transport.congestion_controller_factory(congestion.factory());
+    transport.keep_alive_interval(Some(KEEP_ALIVE));
+    transport.max_idle_timeout(Some(IDLE_TIMEOUT.try_into().unwrap()));
+    transport.max_concurrent_uni_streams(MAX_UNI_STREAMS.into());
+    let max_request_streams = if server { MAX_REQUEST_STREAMS } else { 0 };
+    transport.max_concurrent_bidi_streams(max_request_streams.into());
+    Arc::new(transport)
+}
+
+/// Opens our HTTP/3 control stream in the background, keeping it open as long as the connection. Peers that predate it never let us open it, and then it simply never opens.
+fn spawn_control_stream(conn: &quinn::Connection) -> Task<()> {
+    let conn = conn.clone();
+    smolscale::spawn(async move {
+        let fallible = async {
+            let mut control = conn.open_uni().await?;
+            control.write_all(H3_CONTROL_PREAMBLE).await?;
+            conn.closed().await;
+            std::io::Result::Ok(())
+        };
+        if let Err(err) = fallible.await {
+            tracing::debug!(err = debug(err), "QUIC control stream failed");
+        }
+    })
+}
+
+fn to_io_error(err: impl std::error::Error + Send + Sync + 'static) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, err)
+}
+
+/// A pipe carried by a single bidirectional stream of its own QUIC connection. Closing the pipe finishes our side of the stream, which the other end reads as EOF.
+pub struct QuicPipe {
+    send: SendStream,
+    recv: RecvStream,
+    _control: Task<()>,
+    _conn: quinn::Connection,
+    // dialers own their endpoint, which must outlive the connection
+    _endpoint: Option<Endpoint>,
+    remote_addr: String,
+}
+
+impl AsyncRead for QuicPipe {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicPipe {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().send).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().send).poll_flush(cx)
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().send).poll_close(cx)
+    }
+}
+
+impl Pipe for QuicPipe {
+    fn protocol(&self) -> &str {
+        "quic"
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        Some(&self.remote_addr)
+    }
+}
@@ -0,0 +1,176 @@
+use std::{io::ErrorKind, net::SocketAddr, sync::Arc};
+
+use async_executor::Executor;
+use async_task::Task;
+use async_trait::async_trait;
+use futures_util::AsyncReadExt;
+use quinn::{crypto::rustls::QuicServerConfig, Endpoint, EndpointConfig, ServerConfig};
+use sillad::listener::Listener;
+use tachyonix::{Receiver, Sender};
+
+use crate::{
+    cert::CertResolver,
+    obfs::{obfs_endpoint, ObfsConfig},
+    spawn_control_stream, to_io_error, transport_config, Congestion, QuicPipe, ALPN,
+};
+
+/// A QUIC listener, which presents a certificate made out to whatever name the client asks for.
+pub struct QuicListener {
+    recv_pipe: Receiver<QuicPipe>,
+    local_addr: SocketAddr,
+    _task: Task<std::io::Result<()>>,
+}
+
+impl QuicListener {
+    /// Creates a new QuicListener by listening to a particular UDP address.
+    pub async fn bind(addr: SocketAddr, congestion: Congestion) -> std::io::Result<Self> {
+        let endpoint = Endpoint::new(
+            EndpointConfig::default(),
+            Some(server_config(congestion, false)?),
+            std::net::UdpSocket::bind(addr)?,
+            Arc::new(quinn::SmolRuntime),
+        )?;
+        Self::from_endpoint(endpoint)
+    }
+
+    /// Creates a new QuicListener whose packets are obfuscated, for QuicDialers with the same cookie.
+    pub async fn bind_obfs(
+        addr: SocketAddr,
+        congestion: Congestion,
+        obfs: ObfsConfig,
+    ) -> std::io::Result<Self> {
+        let endpoint = obfs_endpoint(
+            std::net::UdpSocket::bind(addr)?,
+            &obfs,
+            Some(server_config(congestion, true)?),
+        )?;
+        Self::from_endpoint(endpoint)
+    }
+
+    fn from_endpoint(endpoint: Endpoint) -> std::io::Result<Self> {
+        let local_addr = endpoint.local_addr()?;
+        let (send_pipe, recv_pipe) = tachyonix::channel(1);
+        let _task = smolscale::spawn(listen_loop(endpoint, send_pipe));
+        Ok(Self {
+            recv_pipe,
+            local_addr,
+            _task,
+        })
+    }
+
+    /// Get the local listening address.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+#[async_trait]
+impl Listener for QuicListener {
+    type P = QuicPipe;
+    async fn accept(&mut self) -> std::io::Result<Self::P> {
+        self.recv_pipe
+            .recv()
+            .await
+            .map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, "QUIC listener died"))
+    }
+}
+
+async fn listen_loop(endpoint: Endpoint, send_pipe: Sender<QuicPipe>) -> std::io::Result<()> {
+    let lexec = Executor::new();
+    lexec
+        .run(async {
+            // handshakes run concurrently, so that slow or malicious clients cannot hold up the rest
+            while let Some(incoming) = endpoint.accept().await {
+                let send_pipe = send_pipe.clone();
+                lexec
+                    .spawn(async move {
+                        let fallible = async {
+                            let remote_addr = incoming.remote_address().to_string();
+                            let conn = incoming.await?;
+                            let _control = spawn_control_stream(&conn);
+                            let (send, mut recv) = conn.accept_bi().await?;
+                            // skip the byte the dialer sends to open the stream
+                            recv.read_exact(&mut [0u8]).await?;
+                            std::io::Result::Ok(QuicPipe {
+                                send,
+                                recv,
+                                _control,
+                                _conn: conn,
+                                _endpoint: None,
+                                remote_addr,
+                            })
+                        };
+                        match fallible.await {
+                            Ok(pipe) => {
+                                let _ = send_pipe.send(pipe).await;
+                            }
+                            Err(err) => {
+                                tracing::debug!(err = debug(err), "QUIC accept failed")
+                            }
+                        }
+                    })
+                    .detach();
+            }
+            Err(std::io::Error::new(
+                ErrorKind::BrokenPipe,
+                "QUIC endpoint closed",
+            ))
+        })
+        .await
+}
+
+fn server_config(congestion: Congestion, obfs: bool) -> std::io::Result<ServerConfig> {
+    let mut crypto = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_protocol_versions(&[&rustls::version::TLS13])
+    .map_err(to_io_error)?
+    .with_no_client_auth()
+    .with_cert_resolver(Arc::new(CertResolver::new().map_err(to_io_error)?));
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    let mut config = ServerConfig::with_crypto(Arc::new(
+        QuicServerConfig::try_from(crypto).map_err(to_io_error)?,
+    ));
+    config.transport_config(transport_config(congestion, obfs, true));
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{AsyncReadExt, AsyncWriteExt};
+    use sillad::dialer::Dialer;
+
+    use super::*;
+    use crate::dialer::QuicDialer;
+
+    #[test]
+    fn quic_roundtrip() {
+        smolscale::block_on(async {
+            for server_name in [Some("www.example.com".to_string()), None] {
+                let mut listener =
+                    QuicListener::bind("127.0.0.1:0".parse().unwrap(), Congestion::default())
+                        .await
+                        .unwrap();
+                let dialer = QuicDialer {
+                    dest_addr: listener.local_addr(),
+                    server_name,
+                    congestion: Congestion::default(),
+                    obfs: None,
+                };
+                let mut client = dialer.dial().await.unwrap();
+                client.write_all(b"hello").await.unwrap();
+                let mut server = listener.accept().await.unwrap();
+                let mut buf = [0u8; 5];
+                server.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"hello");
+
+                let msg = vec![42u8; 100_000];
+                server.write_all(&msg).await.unwrap();
+                server.close().await.unwrap();
+                let mut buf = vec![];
+                client.read_to_end(&mut buf).await.unwrap();
+                assert_eq!(buf, msg);
+            }
+        })
+    }
+}
@@ -0,0 +1,468 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt::Debug,
+    io::{ErrorKind, IoSliceMut},
+    net::{SocketAddr, UdpSocket},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use async_io::Async;
+use async_task::Task;
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit, Nonce};
+use parking_lot::Mutex;
+use quinn::{
+    udp::{RecvMeta, Transmit},
+    AsyncUdpSocket, Endpoint, EndpointConfig, ServerConfig, UdpPoller,
+};
+use rand::RngCore;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use serde::{Deserialize, Serialize};
+
+/// Forward error correction for what one end sends: after every `data_shards` packets come `parity_shards` parity packets, so that up to `parity_shards` losses in each group are recovered without waiting for a retransmission.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct FecParams {
+    pub data_shards: u8,
+    pub parity_shards: u8,
+}
+
+/// Obfuscation of the UDP packets under a QUIC endpoint. Both ends must share the cookie, while each end picks its own FEC parameters, which only affect what it sends.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ObfsConfig {
+    pub cookie: String,
+    #[serde(default)]
+    pub fec: Option<FecParams>,
+}
+
+const NONCE_LEN: usize = 12;
+/// The group number, the index within the group, and the group's shape.
+const HEADER_LEN: usize = 4 + 1 + 1 + 1;
+
+/// Parity packets are sent this far apart, rather than in a burst right after the group they protect, since bursts are exactly what gets dropped on lossy links.
+const PARITY_SPACING: Duration = Duration::from_millis(1);
+
+/// How many groups per peer we keep around for reconstruction.
+const MAX_GROUPS: usize = 32;
+
+/// How many peers we keep FEC state for, which bounds the memory that a listener spends on spoofed sources.
+const MAX_PEERS: usize = 4096;
+
+/// Creates a QUIC endpoint whose packets go through an obfuscating socket.
+pub(crate) fn obfs_endpoint(
+    socket: UdpSocket,
+    obfs: &ObfsConfig,
+    server_config: Option<ServerConfig>,
+) -> std::io::Result<Endpoint> {
+    Endpoint::new_with_abstract_socket(
+        EndpointConfig::default(),
+        server_config,
+        Arc::new(ObfsSocket::new(socket, obfs)?),
+        Arc::new(quinn::SmolRuntime),
+    )
+}
+
+/// A UDP socket that encrypts every packet under a key derived from the cookie, so that nothing on the wire looks like QUIC, and optionally adds Reed-Solomon parity packets.
+struct ObfsSocket {
+    io: Arc<Async<UdpSocket>>,
+    cipher: ChaCha20Poly1305,
+    fec: Option<FecParams>,
+    encoders: Mutex<HashMap<SocketAddr, FecEncoder>>,
+    decoders: Mutex<HashMap<SocketAddr, FecDecoder>>,
+    recovered: Mutex<VecDeque<(SocketAddr, Vec<u8>)>>,
+    send_parity: tachyonix::Sender<(SocketAddr, Vec<u8>)>,
+    _pace_task: Task<()>,
+}
+
+impl Debug for ObfsSocket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ObfsSocket")
+            .field("io", &self.io)
+            .field("fec", &self.fec)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ObfsSocket {
+    fn new(socket: UdpSocket, obfs: &ObfsConfig) -> std::io::Result<Self> {
+        let io = Arc::new(Async::new(socket)?);
+        let key = blake3::derive_key("sillad-quic obfs", obfs.cookie.as_bytes());
+        let (send_parity, recv_parity) = tachyonix::channel(256);
+        let _pace_task = smolscale::spawn(pace_loop(io.clone(), recv_parity));
+        Ok(Self {
+            io,
+            cipher: ChaCha20Poly1305::new(&key.into()),
+            fec: obfs
+                .fec
+                .filter(|fec| fec.data_shards > 0 && fec.parity_shards > 0),
+            encoders: Default::default(),
+            decoders: Default::default(),
+            recovered: Default::default(),
+            send_parity,
+            _pace_task,
+        })
+    }
+
+    fn seal(&self, header: [u8; HEADER_LEN], body: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let mut plain = header.to_vec();
+        plain.extend_from_slice(body);
+        let mut sealed = nonce.to_vec();
+        sealed.extend(
+            self.cipher
+                .encrypt(Nonce::from_slice(&nonce), plain.as_slice())
+                .expect("encryption cannot fail"),
+        );
+        sealed
+    }
+
+    /// Decrypts an incoming packet, feeding it to FEC, and returns the QUIC packet it carries, if any. Anything that does not decrypt is silently dropped.
+    fn open(&self, addr: SocketAddr, raw: &[u8]) -> Option<Vec<u8>> {
+        if raw.len() < NONCE_LEN {
+            return None;
+        }
+        let plain = self
+            .cipher
+            .decrypt(Nonce::from_slice(&raw[..NONCE_LEN]), &raw[NONCE_LEN..])
+            .ok()?;
+        if plain.len() < HEADER_LEN {
+            return None;
+        }
+        let group = u32::from_be_bytes(plain[..4].try_into().unwrap());
+        let index = plain[4];
+        let data_shards = plain[5];
+        let parity_shards = plain[6];
+        let body = &plain[HEADER_LEN..];
+        if data_shards == 0 || parity_shards == 0 {
+            return Some(body.to_vec());
+        }
+        let mut decoders = self.decoders.lock();
+        if decoders.len() >= MAX_PEERS && !decoders.contains_key(&addr) {
+            decoders.clear();
+        }
+        let recovered = decoders.entry(addr).or_default().insert(
+            group,
+            index,
+            FecParams {
+                data_shards,
+                parity_shards,
+            },
+            body,
+        );
+        if !recovered.is_empty() {
+            self.recovered
+                .lock()
+                .extend(recovered.into_iter().map(|pkt| (addr, pkt)));
+        }
+        (index < data_shards).then(|| body.to_vec())
+    }
+}
+
+async fn pace_loop(
+    io: Arc<Async<UdpSocket>>,
+    mut recv_parity: tachyonix::Receiver<(SocketAddr, Vec<u8>)>,
+) {
+    while let Ok((dest, pkt)) = recv_parity.recv().await {
+        if let Err(err) = io.send_to(&pkt, dest).await {
+            tracing::debug!(err = debug(err), "could not send parity packet");
+        }
+        async_io::Timer::after(PARITY_SPACING).await;
+    }
+}
+
+impl AsyncUdpSocket for ObfsSocket {
+    fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn UdpPoller>> {
+        Box::pin(ObfsPoller(self.io.clone()))
+    }
+
+    fn try_send(&self, transmit: &Transmit) -> std::io::Result<()> {
+        let Some(fec) = self.fec else {
+            let pkt = self.seal([0; HEADER_LEN], transmit.contents);
+            self.io.get_ref().send_to(&pkt, transmit.destination)?;
+            return Ok(());
+        };
+        let mut encoders = self.encoders.lock();
+        if encoders.len() >= MAX_PEERS && !encoders.contains_key(&transmit.destination) {
+            encoders.clear();
+        }
+        let encoder = encoders.entry(transmit.destination).or_default();
+        let pkt = self.seal(encoder.next_header(fec), transmit.contents);
+        // a packet that was not sent will be retried, so only what actually went out joins the group
+        self.io.get_ref().send_to(&pkt, transmit.destination)?;
+        for (header, parity) in encoder.push(fec, transmit.contents) {
+            // parity is best effort, so if the pacer falls behind, we just send less of it
+            let _ = self
+                .send_parity
+                .try_send((transmit.destination, self.seal(header, &parity)));
+        }
+        Ok(())
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<std::io::Result<usize>> {
+        let mut raw = [0u8; 2048];
+        loop {
+            let next = self.recovered.lock().pop_front();
+            if let Some((addr, pkt)) = next {
+                if let Some(n) = deliver(addr, &pkt, bufs, meta) {
+                    return Poll::Ready(Ok(n));
+                }
+                continue;
+            }
+            futures_util::ready!(self.io.poll_readable(cx))?;
+            let (n, addr) = match self.io.get_ref().recv_from(&mut raw) {
+                Ok(res) => res,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+                Err(err) => return Poll::Ready(Err(err)),
+            };
+            if let Some(pkt) = self.open(addr, &raw[..n]) {
+                if let Some(n) = deliver(addr, &pkt, bufs, meta) {
+                    return Poll::Ready(Ok(n));
+                }
+            }
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.io.get_ref().local_addr()
+    }
+}
+
+fn deliver(
+    addr: SocketAddr,
+    pkt: &[u8],
+    bufs: &mut [IoSliceMut<'_>],
+    meta: &mut [RecvMeta],
+) -> Option<usize> {
+    let buf = bufs.first_mut()?;
+    if pkt.len() > buf.len() {
+        return None;
+    }
+    buf[..pkt.len()].copy_from_slice(pkt);
+    let meta = meta.first_mut()?;
+    meta.addr = addr;
+    meta.len = pkt.len();
+    meta.stride = pkt.len();
+    meta.ecn = None;
+    meta.dst_ip = None;
+    Some(1)
+}
+
+#[derive(Debug)]
+struct ObfsPoller(Arc<Async<UdpSocket>>);
+
+impl UdpPoller for ObfsPoller {
+    fn poll_writable(self: Pin<&mut Self>, cx: &mut Context) -> Poll<std::io::Result<()>> {
+        self.0.poll_writable(cx)
+    }
+}
+
+/// Turns a packet into a shard: its length, followed by the packet itself. Shards are padded to a common length before encoding.
+fn to_shard(pkt: &[u8]) -> Vec<u8> {
+    let mut shard = (pkt.len() as u16).to_be_bytes().to_vec();
+    shard.extend_from_slice(pkt);
+    shard
+}
+
+fn from_shard(shard: &[u8]) -> Option<Vec<u8>> {
+    let len = u16::from_be_bytes(shard.get(..2)?.try_into().unwrap()) as usize;
+    Some(shard.get(2..2 + len)?.to_vec())
+}
+
+#[derive(Default)]
+struct FecEncoder {
+    group: u32,
+    shards: Vec<Vec<u8>>,
+}
+
+impl FecEncoder {
+    fn next_header(&self, fec: FecParams) -> [u8; HEADER_LEN] {
+        header(
+            self.group,
+            self.shards.len() as u8,
+            fec.data_shards,
+            fec.parity_shards,
+        )
+    }
+
+    /// Adds a sent packet to the current group, returning the group's parity packets if that completes it.
+    fn push(&mut self, fec: FecParams, pkt: &[u8]) -> Vec<([u8; HEADER_LEN], Vec<u8>)> {
+        self.shards.push(to_shard(pkt));
+        if self.shards.len() < fec.data_shards as usize {
+            return vec![];
+        }
+        let shard_len = self
+            .shards
+            .iter()
+            .map(|s| s.len())
+            .max()
+            .unwrap_or_default();
+        let mut shards = std::mem::take(&mut self.shards);
+        for shard in shards.iter_mut() {
+            shard.resize(shard_len, 0);
+        }
+        shards.extend((0..fec.parity_shards).map(|_| vec![0u8; shard_len]));
+        let group = self.group;
+        self.group = self.group.wrapping_add(1);
+        let rs = match ReedSolomon::new(fec.data_shards.into(), fec.parity_shards.into()) {
+            Ok(rs) => rs,
+            Err(err) => {
+                tracing::warn!(err = debug(err), "bad FEC parameters");
+                return vec![];
+            }
+        };
+        if let Err(err) = rs.encode(&mut shards) {
+            tracing::warn!(err = debug(err), "FEC encoding failed");
+            return vec![];
+        }
+        shards
+            .into_iter()
+            .enumerate()
+            .skip(fec.data_shards.into())
+            .map(|(index, parity)| {
+                (
+                    header(group, index as u8, fec.data_shards, fec.parity_shards),
+                    parity,
+                )
+            })
+            .collect()
+    }
+}
+
+fn header(group: u32, index: u8, data_shards: u8, parity_shards: u8) -> [u8; HEADER_LEN] {
+    let mut header = [0u8; HEADER_LEN];
+    header[..4].copy_from_slice(&group.to_be_bytes());
+    header[4] = index;
+    header[5] = data_shards;
+    header[6] = parity_shards;
+    header
+}
+
+#[derive(Default)]
+struct FecDecoder {
+    groups: BTreeMap<u32, FecGroup>,
+}
+
+struct FecGroup {
+    fec: FecParams,
+    shards: Vec<Option<Vec<u8>>>,
+    done: bool,
+}
+
+impl FecDecoder {
+    /// Stores a shard, returning any data packets that it allowed us to recover.
+    fn insert(&mut self, group: u32, index: u8, fec: FecParams, body: &[u8]) -> Vec<Vec<u8>> {
+        let total = fec.data_shards as usize + fec.parity_shards as usize;
+        if index as usize >= total {
+            return vec![];
+        }
+        let entry = self.groups.entry(group).or_insert_with(|| FecGroup {
+            fec,
+            shards: vec![None; total],
+            done: false,
+        });
+        if entry.fec != fec {
+            // the peer restarted with different parameters, so whatever we had is stale
+            *entry = FecGroup {
+                fec,
+                shards: vec![None; total],
+                done: false,
+            };
+        }
+        let shard = if index < fec.data_shards {
+            to_shard(body)
+        } else {
+            body.to_vec()
+        };
+        entry.shards[index as usize] = Some(shard);
+        let recovered = if entry.done {
+            vec![]
+        } else {
+            entry.try_recover()
+        };
+        while self.groups.len() > MAX_GROUPS {
+            self.groups.pop_first();
+        }
+        recovered
+    }
+}
+
+impl FecGroup {
+    fn try_recover(&mut self) -> Vec<Vec<u8>> {
+        let data_shards = self.fec.data_shards as usize;
+        let missing: Vec<usize> = (0..data_shards)
+            .filter(|&i| self.shards[i].is_none())
+            .collect();
+        if missing.is_empty() {
+            self.done = true;
+            return vec![];
+        }
+        if self.shards.iter().flatten().count() < data_shards {
+            return vec![];
+        }
+        // parity shards have the padded length, and data shards must be padded to match
+        let Some(shard_len) = self.shards[data_shards..]
+            .iter()
+            .flatten()
+            .map(|s| s.len())
+            .next()
+        else {
+            return vec![];
+        };
+        let mut shards = self.shards.clone();
+        for shard in shards.iter_mut().flatten() {
+            if shard.len() > shard_len {
+                return vec![];
+            }
+            shard.resize(shard_len, 0);
+        }
+        self.done = true;
+        let Ok(rs) = ReedSolomon::new(data_shards, self.fec.parity_shards.into()) else {
+            return vec![];
+        };
+        if rs.reconstruct_data(&mut shards).is_err() {
+            return vec![];
+        }
+        missing
+            .into_iter()
+            .filter_map(|i| from_shard(shards[i].as_ref()?))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fec_recovers_lost_packets() {
+        let fec = FecParams {
+            data_shards: 4,
+            parity_shards: 2,
+        };
+        let pkts: Vec<Vec<u8>> = (0..4u8).map(|i| vec![i; 10 + i as usize * 100]).collect();
+        let mut encoder = FecEncoder::default();
+        let mut parity = vec![];
+        for pkt in pkts.iter() {
+            parity.extend(encoder.push(fec, pkt));
+        }
+        assert_eq!(parity.len(), 2);
+
+        // lose two of the four data packets
+        let mut decoder = FecDecoder::default();
+        let mut recovered = vec![];
+        for (i, pkt) in pkts.iter().enumerate().filter(|(i, _)| i % 2 == 0) {
+            recovered.extend(decoder.insert(0, i as u8, fec, pkt));
+        }
+        for (header, body) in parity {
+            recovered.extend(decoder.insert(0, header[4], fec, &body));
+        }
+        assert_eq!(recovered, vec![pkts[1].clone(), pkts[3].clone()]);
+    }
+}
@@ -0,0 +1,353 @@
+//! A dialer for the TCP half of Shadowsocks 2022 (SIP022), so that Shadowsocks servers can carry pipes to a fixed destination.
+
+use std::{
+    net::SocketAddr,
+    pin::Pin,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use aes_gcm::{Aes128Gcm, Aes256Gcm};
+use anyhow::Context;
+use async_task::Task;
+use async_trait::async_trait;
+use base64::Engine;
+use bipe::{BipeReader, BipeWriter};
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit};
+use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use pin_project::pin_project;
+use rand::{Rng, RngCore};
+use sillad::{dialer::Dialer, Pipe};
+
+const TAG_LEN: usize = 16;
+
+/// How far the server's clock may be from ours.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(30);
+
+/// The most plaintext a single chunk may carry.
+const MAX_CHUNK: usize = 0xffff;
+
+const HEADER_TYPE_CLIENT: u8 = 0;
+const HEADER_TYPE_SERVER: u8 = 1;
+
+/// A Shadowsocks 2022 cipher.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Method {
+    Aes128Gcm,
+    Aes256Gcm,
+    Chacha20Poly1305,
+}
+
+impl Method {
+    fn key_len(self) -> usize {
+        match self {
+            Method::Aes128Gcm => 16,
+            Method::Aes256Gcm | Method::Chacha20Poly1305 => 32,
+        }
+    }
+}
+
+impl FromStr for Method {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "2022-blake3-aes-128-gcm" => Ok(Method::Aes128Gcm),
+            "2022-blake3-aes-256-gcm" => Ok(Method::Aes256Gcm),
+            "2022-blake3-chacha20-poly1305" => Ok(Method::Chacha20Poly1305),
+            other => anyhow::bail!("unsupported Shadowsocks method {other}"),
+        }
+    }
+}
+
+/// The pre-shared key and cipher of a Shadowsocks 2022 server.
+#[derive(Clone)]
+pub struct ShadowsocksKey {
+    method: Method,
+    key: Vec<u8>,
+}
+
+impl ShadowsocksKey {
+    /// Parses the key from its usual base64 form, checking that its length suits the method.
+    pub fn new(method: Method, password: &str) -> anyhow::Result<Self> {
+        let key = base64::engine::general_purpose::STANDARD
+            .decode(password)
+            .context("Shadowsocks 2022 keys must be base64")?;
+        if key.len() != method.key_len() {
+            anyhow::bail!(
+                "{method:?} needs a {}-byte key, got {} bytes",
+                method.key_len(),
+                key.len()
+            )
+        }
+        Ok(Self { method, key })
+    }
+
+    fn session_cipher(&self, salt: &[u8]) -> SessionCipher {
+        let mut material = self.key.clone();
+        material.extend_from_slice(salt);
+        let subkey = blake3::derive_key("shadowsocks 2022 session subkey", &material);
+        let subkey = &subkey[..self.method.key_len()];
+        let aead = match self.method {
+            Method::Aes128Gcm => Aead3::Aes128(Aes128Gcm::new_from_slice(subkey).unwrap()),
+            Method::Aes256Gcm => Aead3::Aes256(Aes256Gcm::new_from_slice(subkey).unwrap()),
+            Method::Chacha20Poly1305 => {
+                Aead3::Chacha(ChaCha20Poly1305::new_from_slice(subkey).unwrap())
+            }
+        };
+        SessionCipher { aead, counter: 0 }
+    }
+}
+
+enum Aead3 {
+    Aes128(Aes128Gcm),
+    Aes256(Aes256Gcm),
+    Chacha(ChaCha20Poly1305),
+}
+
+/// An AEAD keyed with a session subkey, whose nonce is a little-endian counter bumped after every use.
+struct SessionCipher {
+    aead: Aead3,
+    counter: u64,
+}
+
+impl SessionCipher {
+    fn next_nonce(&mut self) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[..8].copy_from_slice(&self.counter.to_le_bytes());
+        self.counter += 1;
+        nonce
+    }
+
+    fn encrypt(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = self.next_nonce();
+        match &self.aead {
+            Aead3::Aes128(a) => a.encrypt(&nonce.into(), plaintext),
+            Aead3::Aes256(a) => a.encrypt(&nonce.into(), plaintext),
+            Aead3::Chacha(a) => a.encrypt(&nonce.into(), plaintext),
+        }
+        .unwrap()
+    }
+
+    fn decrypt(&mut self, ciphertext: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let nonce = self.next_nonce();
+        match &self.aead {
+            Aead3::Aes128(a) => a.decrypt(&nonce.into(), ciphertext),
+            Aead3::Aes256(a) => a.decrypt(&nonce.into(), ciphertext),
+            Aead3::Chacha(a) => a.decrypt(&nonce.into(), ciphertext),
+        }
+        .ok()
+        .context("cannot decrypt Shadowsocks data")
+    }
+}
+
+/// Encodes an address the way SOCKS5 does.
+fn encode_addr(addr: SocketAddr) -> Vec<u8> {
+    let mut out = vec![];
+    match addr {
+        SocketAddr::V4(v4) => {
+            out.push(1);
+            out.extend_from_slice(&v4.ip().octets());
+        }
+        SocketAddr::V6(v6) => {
+            out.push(4);
+            out.extend_from_slice(&v6.ip().octets());
+        }
+    }
+    out.extend_from_slice(&addr.port().to_be_bytes());
+    out
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// ShadowsocksDialer reaches a fixed destination through a Shadowsocks 2022 server, which the inner dialer connects to.
+pub struct ShadowsocksDialer<D: Dialer> {
+    pub inner: D,
+    pub key: ShadowsocksKey,
+    pub dest_addr: SocketAddr,
+}
+
+#[async_trait]
+impl<D: Dialer> Dialer for ShadowsocksDialer<D> {
+    type P = ShadowsocksPipe;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let lower = self.inner.dial().await?;
+        let remote_addr = lower.remote_addr().map(|s| s.to_string());
+        let (mut lower_read, mut lower_write) = lower.split();
+
+        // the request header, which goes out right away. without initial data, SIP022 requires padding.
+        let mut salt = vec![0u8; self.key.method.key_len()];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let mut write_cipher = self.key.session_cipher(&salt);
+        let mut variable_header = encode_addr(self.dest_addr);
+        let padding_len: u16 = rand::thread_rng().gen_range(1..=900);
+        variable_header.extend_from_slice(&padding_len.to_be_bytes());
+        variable_header.extend(std::iter::repeat(0u8).take(padding_len as usize));
+        let mut fixed_header = vec![HEADER_TYPE_CLIENT];
+        fixed_header.extend_from_slice(&unix_now().to_be_bytes());
+        fixed_header.extend_from_slice(&(variable_header.len() as u16).to_be_bytes());
+        let mut handshake = salt.clone();
+        handshake.extend_from_slice(&write_cipher.encrypt(&fixed_header));
+        handshake.extend_from_slice(&write_cipher.encrypt(&variable_header));
+        lower_write.write_all(&handshake).await?;
+
+        let (mut write_incoming, read_incoming) = bipe::bipe(32768);
+        let (write_outgoing, mut read_outgoing) = bipe::bipe(32768);
+        let key = self.key.clone();
+        let _read_task = smolscale::spawn(async move {
+            let fallible = async {
+                // the response header comes once the server has something to say
+                let mut server_salt = vec![0u8; key.method.key_len()];
+                lower_read.read_exact(&mut server_salt).await?;
+                let mut read_cipher = key.session_cipher(&server_salt);
+                let mut fixed_header = vec![0u8; 1 + 8 + salt.len() + 2 + TAG_LEN];
+                lower_read.read_exact(&mut fixed_header).await?;
+                let fixed_header = read_cipher.decrypt(&fixed_header)?;
+                if fixed_header[0] != HEADER_TYPE_SERVER {
+                    anyhow::bail!("wrong Shadowsocks response type")
+                }
+                let timestamp = u64::from_be_bytes(fixed_header[1..9].try_into().unwrap());
+                if timestamp.abs_diff(unix_now()) > MAX_CLOCK_SKEW.as_secs() {
+                    anyhow::bail!("Shadowsocks response has a bad timestamp")
+                }
+                if fixed_header[9..9 + salt.len()] != salt[..] {
+                    anyhow::bail!("Shadowsocks response is not for our request")
+                }
+                let mut length =
+                    u16::from_be_bytes(fixed_header[9 + salt.len()..].try_into().unwrap()) as usize;
+                loop {
+                    let mut chunk = vec![0u8; length + TAG_LEN];
+                    lower_read.read_exact(&mut chunk).await?;
+                    write_incoming
+                        .write_all(&read_cipher.decrypt(&chunk)?)
+                        .await?;
+                    let mut length_buf = [0u8; 2 + TAG_LEN];
+                    if let Err(err) = lower_read.read_exact(&mut length_buf).await {
+                        if err.kind() == std::io::ErrorKind::UnexpectedEof {
+                            write_incoming.close().await?;
+                            return Ok(());
+                        }
+                        return Err(err.into());
+                    }
+                    let length_buf = read_cipher.decrypt(&length_buf)?;
+                    length = u16::from_be_bytes([length_buf[0], length_buf[1]]) as usize;
+                }
+            };
+            if let Err(err) = fallible.await {
+                tracing::debug!(err = debug(err), "Shadowsocks read side stopped");
+            }
+        });
+        let _write_task = smolscale::spawn(async move {
+            let fallible = async {
+                let mut buf = vec![0u8; MAX_CHUNK];
+                loop {
+                    let n = read_outgoing.read(&mut buf).await?;
+                    if n == 0 {
+                        lower_write.close().await?;
+                        return anyhow::Ok(());
+                    }
+                    let mut to_send = write_cipher.encrypt(&(n as u16).to_be_bytes());
+                    to_send.extend_from_slice(&write_cipher.encrypt(&buf[..n]));
+                    lower_write.write_all(&to_send).await?;
+                }
+            };
+            if let Err(err) = fallible.await {
+                tracing::debug!(err = debug(err), "Shadowsocks write side stopped");
+            }
+        });
+        Ok(ShadowsocksPipe {
+            read_incoming,
+            _read_task,
+            write_outgoing,
+            _write_task,
+            remote_addr,
+        })
+    }
+}
+
+/// A pipe through a Shadowsocks 2022 server.
+#[pin_project]
+pub struct ShadowsocksPipe {
+    #[pin]
+    read_incoming: BipeReader,
+    _read_task: Task<()>,
+    #[pin]
+    write_outgoing: BipeWriter,
+    _write_task: Task<()>,
+
+    remote_addr: Option<String>,
+}
+
+impl AsyncRead for ShadowsocksPipe {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        self.project().read_incoming.poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ShadowsocksPipe {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        self.project().write_outgoing.poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.project().write_outgoing.poll_flush(cx)
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.project().write_outgoing.poll_close(cx)
+    }
+}
+
+impl Pipe for ShadowsocksPipe {
+    fn protocol(&self) -> &str {
+        "shadowsocks"
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        self.remote_addr.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_length_checked() {
+        let key16 = base64::engine::general_purpose::STANDARD.encode([7u8; 16]);
+        assert!(ShadowsocksKey::new(Method::Aes128Gcm, &key16).is_ok());
+        assert!(ShadowsocksKey::new(Method::Aes256Gcm, &key16).is_err());
+    }
+
+    #[test]
+    fn session_ciphers_agree() {
+        let key = base64::engine::general_purpose::STANDARD.encode([7u8; 32]);
+        let key =
+            ShadowsocksKey::new("2022-blake3-chacha20-poly1305".parse().unwrap(), &key).unwrap();
+        let mut sender = key.session_cipher(b"salt");
+        let mut receiver = key.session_cipher(b"salt");
+        for msg in [&b"hello"[..], b"world"] {
+            assert_eq!(receiver.decrypt(&sender.encrypt(msg)).unwrap(), msg);
+        }
+    }
+}
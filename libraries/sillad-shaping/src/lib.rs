@@ -0,0 +1,329 @@
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use async_io::Timer;
+use async_task::Task;
+use bipe::{BipeReader, BipeWriter};
+use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use pin_project::pin_project;
+use serde::{Deserialize, Serialize};
+use sillad::Pipe;
+use smol_timeout2::TimeoutExt;
+
+/// The data length that marks the last frame before our side closes. Frames are never this long.
+const LEN_FIN: u16 = u16::MAX;
+
+/// How traffic gets shaped. Both ends of a pipe must use the same parameters.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ShapingParams {
+    /// The size of every frame sent, in bytes, including the two-byte length of the data it carries.
+    pub frame_size: u16,
+    /// How many frames per second are sent while cover traffic is on, whether or not there is data to send. Data beyond this goes out as fast as it comes, still in whole frames.
+    pub frames_per_sec: u32,
+    /// The length of the duty cycle, in seconds.
+    pub cycle_secs: f64,
+    /// The fraction of every cycle during which cover traffic is on. Outside of it, frames are only sent when there is data.
+    pub duty: f64,
+}
+
+impl Default for ShapingParams {
+    fn default() -> Self {
+        Self {
+            frame_size: 1024,
+            frames_per_sec: 50,
+            cycle_secs: 60.0,
+            duty: 1.0,
+        }
+    }
+}
+
+impl ShapingParams {
+    /// Whether these parameters are within reason, so that the other end can refuse ones that would have it send an absurd amount of cover traffic.
+    pub fn is_sane(&self) -> bool {
+        (64..=16384).contains(&self.frame_size)
+            && (1..=1000).contains(&self.frames_per_sec)
+            && self.cycle_secs >= 1.0
+            && (0.0..=1.0).contains(&self.duty)
+    }
+
+    /// How long until the next frame of cover traffic is due, given how long the pipe has been up.
+    fn until_cover(&self, elapsed: Duration) -> Duration {
+        let interval = Duration::from_secs_f64(1.0 / self.frames_per_sec as f64);
+        let into_cycle = elapsed.as_secs_f64() % self.cycle_secs;
+        if into_cycle < self.cycle_secs * self.duty {
+            interval
+        } else {
+            Duration::from_secs_f64(self.cycle_secs - into_cycle).max(interval)
+        }
+    }
+}
+
+/// Whether the other side has closed its direction of a shaped pipe, which is when our cover frames can stop once our side is closed too.
+#[derive(Default)]
+struct RemoteClosed {
+    closed: AtomicBool,
+    event: async_event::Event,
+}
+
+impl RemoteClosed {
+    fn set(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.event.notify_all();
+    }
+
+    fn get(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    async fn wait(&self) {
+        self.event.wait_until(|| self.get().then_some(())).await
+    }
+}
+
+/// A pipe that sends everything in fixed-size frames over another pipe, and keeps up a steady stream of cover frames when idle, so that the sizes and timing of what it writes to the other pipe say little about the traffic within. How that becomes packets on the wire is up to the other pipe, which may split or coalesce the frames. Both ends must wrap their pipes in a ShapedPipe, and cover frames stop once both have closed.
+#[pin_project]
+pub struct ShapedPipe {
+    #[pin]
+    read_incoming: BipeReader,
+    #[pin]
+    write_outgoing: BipeWriter,
+    _tasks: Vec<Task<()>>,
+
+    protocol: String,
+    remote_addr: Option<String>,
+    shared_secret: Option<Vec<u8>>,
+}
+
+impl ShapedPipe {
+    pub fn new(inner: impl Pipe, params: ShapingParams) -> Self {
+        let protocol = inner.protocol().to_string();
+        let remote_addr = inner.remote_addr().map(|s| s.to_string());
+        let shared_secret = inner.shared_secret().map(|s| s.to_vec());
+        let frame_size = params.frame_size as usize;
+        let (mut inner_read, mut inner_write) = inner.split();
+        let (mut write_incoming, read_incoming) = bipe::bipe(frame_size * 32);
+        let (write_outgoing, mut read_outgoing) = bipe::bipe(frame_size * 32);
+        let remote_closed = Arc::new(RemoteClosed::default());
+
+        let send_remote_closed = remote_closed.clone();
+        let send_task = smolscale::spawn(async move {
+            let fallible = async {
+                let start = Instant::now();
+                let mut frame = vec![0u8; frame_size];
+                let mut closed = false;
+                loop {
+                    if closed && send_remote_closed.get() {
+                        break;
+                    }
+                    let until_cover = params.until_cover(start.elapsed());
+                    // once our side is closed, only cover frames go out, until the other side is closed too
+                    let len = if closed {
+                        if send_remote_closed
+                            .wait()
+                            .timeout(until_cover)
+                            .await
+                            .is_some()
+                        {
+                            break;
+                        }
+                        0
+                    } else {
+                        match read_outgoing
+                            .read(&mut frame[2..])
+                            .timeout(until_cover)
+                            .await
+                        {
+                            Some(Ok(0)) => {
+                                closed = true;
+                                LEN_FIN
+                            }
+                            Some(n) => n? as u16,
+                            None => 0,
+                        }
+                    };
+                    frame[..2].copy_from_slice(&len.to_be_bytes());
+                    let data_end = if len == LEN_FIN { 2 } else { 2 + len as usize };
+                    frame[data_end..].fill(0);
+                    inner_write.write_all(&frame).await?;
+                    inner_write.flush().await?;
+                }
+                inner_write.close().await
+            };
+            let res: std::io::Result<()> = fallible.await;
+            if let Err(err) = res {
+                tracing::debug!(err = debug(err), "shaped send side stopped");
+            }
+        });
+
+        let recv_task = smolscale::spawn(async move {
+            let fallible = async {
+                let mut frame = vec![0u8; frame_size];
+                loop {
+                    inner_read.read_exact(&mut frame).await?;
+                    let len = u16::from_be_bytes([frame[0], frame[1]]);
+                    if len == LEN_FIN {
+                        write_incoming.close().await?;
+                        remote_closed.set();
+                        continue;
+                    }
+                    let len = len as usize;
+                    if len > frame_size - 2 {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            "shaped frame too long",
+                        ));
+                    }
+                    write_incoming.write_all(&frame[2..2 + len]).await?;
+                }
+            };
+            let res: std::io::Result<()> = fallible.await;
+            if let Err(err) = res {
+                tracing::debug!(err = debug(err), "shaped receive side stopped");
+            }
+        });
+
+        Self {
+            read_incoming,
+            write_outgoing,
+            _tasks: vec![send_task, recv_task],
+            protocol,
+            remote_addr,
+            shared_secret,
+        }
+    }
+}
+
+impl AsyncRead for ShapedPipe {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        self.project().read_incoming.poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ShapedPipe {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        self.project().write_outgoing.poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.project().write_outgoing.poll_flush(cx)
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.project().write_outgoing.poll_close(cx)
+    }
+}
+
+impl Pipe for ShapedPipe {
+    fn shared_secret(&self) -> Option<&[u8]> {
+        self.shared_secret.as_deref()
+    }
+
+    fn protocol(&self) -> &str {
+        &self.protocol
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        self.remote_addr.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sillad::{
+        dialer::Dialer,
+        listener::Listener,
+        tcp::{TcpDialer, TcpListener},
+    };
+
+    use super::*;
+
+    #[test]
+    fn shaped_roundtrip() {
+        smol::future::block_on(async {
+            let params = ShapingParams {
+                frame_size: 256,
+                frames_per_sec: 100,
+                cycle_secs: 1.0,
+                duty: 0.5,
+            };
+            assert!(params.is_sane());
+            let mut listener = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let dialer = TcpDialer {
+                dest_addr: listener.local_addr().await,
+            };
+            let mut client = ShapedPipe::new(dialer.dial().await.unwrap(), params);
+            let mut server = ShapedPipe::new(listener.accept().await.unwrap(), params);
+
+            client.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+
+            let msg = vec![42u8; 100_000];
+            server.write_all(&msg).await.unwrap();
+            server.close().await.unwrap();
+            let mut buf = vec![];
+            client.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, msg);
+        })
+    }
+
+    #[test]
+    fn cover_stops_once_both_sides_close() {
+        smol::future::block_on(async {
+            let params = ShapingParams {
+                frame_size: 64,
+                frames_per_sec: 100,
+                cycle_secs: 1.0,
+                duty: 1.0,
+            };
+            let mut listener = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let dialer = TcpDialer {
+                dest_addr: listener.local_addr().await,
+            };
+            let mut shaped = ShapedPipe::new(dialer.dial().await.unwrap(), params);
+            let mut raw = listener.accept().await.unwrap();
+
+            // the unshaped side closes its direction right away
+            let mut fin = vec![0u8; 64];
+            fin[..2].copy_from_slice(&LEN_FIN.to_be_bytes());
+            raw.write_all(&fin).await.unwrap();
+            shaped.read_to_end(&mut vec![]).await.unwrap();
+            shaped.close().await.unwrap();
+
+            // so once the shaped side closes too, its cover frames stop and the connection ends
+            let mut frames = vec![];
+            raw.read_to_end(&mut frames)
+                .timeout(Duration::from_secs(5))
+                .await
+                .expect("cover frames never stopped")
+                .unwrap();
+            assert_eq!(frames.len() % 64, 0);
+        })
+    }
+}
@@ -113,6 +113,24 @@ mod tests {
         assert_eq!(handshake.padding_hash, decrypted_handshake.padding_hash);
     }
 
+    #[test]
+    fn test_client_handshake_check() {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let handshake = Handshake {
+            eph_pk: x25519_dalek::PublicKey::from(&secret),
+            timestamp: 123456789,
+            padding_len: 0,
+            padding_hash: blake3::hash(b""),
+            responding_to: blake3::hash(b""),
+        };
+        let cookie = Cookie::random();
+
+        assert!(cookie.is_client_handshake(&handshake.encrypt(cookie, false)));
+        assert!(!cookie.is_client_handshake(&handshake.encrypt(cookie, true)));
+        assert!(!Cookie::random().is_client_handshake(&handshake.encrypt(cookie, false)));
+        assert!(!cookie.is_client_handshake(&[0u8; 140]));
+    }
+
     #[test]
     fn test_handshake_bytes_round_trip() {
         let secret = EphemeralSecret::random_from_rng(OsRng);
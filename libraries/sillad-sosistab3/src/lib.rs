@@ -8,6 +8,7 @@ use std::{
 use futures_util::{AsyncRead, AsyncWrite};
 use pin_project::pin_project;
 
+use handshake::Handshake;
 use serde::{Deserialize, Serialize};
 use sillad::Pipe;
 use state::State;
@@ -78,8 +79,16 @@ impl Cookie {
     pub fn derive_key(&self, is_server: bool) -> [u8; 32] {
         blake3::derive_key(if is_server { "server" } else { "client" }, &self.key)
     }
+
+    /// Checks whether the bytes that open a connection are a client handshake made with this cookie, so that a relay in front of a listener can tell clients from probes without terminating the connection.
+    pub fn is_client_handshake(&self, opening: &[u8; CLIENT_HANDSHAKE_LEN]) -> bool {
+        Handshake::decrypt(*opening, *self, false).is_ok()
+    }
 }
 
+/// How many bytes the handshake that opens a connection from a client takes.
+pub const CLIENT_HANDSHAKE_LEN: usize = 140;
+
 /// An established sosistab3 connection.
 #[pin_project]
 pub struct SosistabPipe<P: Pipe> {
@@ -0,0 +1,210 @@
+use std::{io::ErrorKind, pin::Pin, task::Poll};
+
+use async_trait::async_trait;
+use async_tungstenite::{
+    tungstenite::{
+        client::IntoClientRequest,
+        handshake::server::{ErrorResponse, Request, Response},
+        http::StatusCode,
+        Message,
+    },
+    WebSocketStream,
+};
+use futures_util::{AsyncRead, AsyncWrite, SinkExt, StreamExt};
+use sillad::{dialer::Dialer, listener::Listener, Pipe};
+
+/// WsPipe carries a byte stream in binary WebSocket messages.
+pub struct WsPipe<P: Pipe> {
+    inner: WebSocketStream<P>,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    remote_addr: Option<String>,
+}
+
+impl<P: Pipe> WsPipe<P> {
+    fn new(inner: WebSocketStream<P>, remote_addr: Option<String>) -> Self {
+        Self {
+            inner,
+            read_buf: vec![],
+            read_pos: 0,
+            remote_addr,
+        }
+    }
+}
+
+fn to_io_error(err: async_tungstenite::tungstenite::Error) -> std::io::Error {
+    match err {
+        async_tungstenite::tungstenite::Error::Io(err) => err,
+        err => std::io::Error::new(ErrorKind::Other, err),
+    }
+}
+
+impl<P: Pipe> AsyncRead for WsPipe<P> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            if this.read_pos < this.read_buf.len() {
+                let n = buf.len().min(this.read_buf.len() - this.read_pos);
+                buf[..n].copy_from_slice(&this.read_buf[this.read_pos..][..n]);
+                this.read_pos += n;
+                return Poll::Ready(Ok(n));
+            }
+            match futures_util::ready!(this.inner.poll_next_unpin(cx)) {
+                None | Some(Ok(Message::Close(_))) => return Poll::Ready(Ok(0)),
+                Some(Err(err)) => return Poll::Ready(Err(to_io_error(err))),
+                Some(Ok(Message::Binary(data))) => {
+                    this.read_buf = data.into();
+                    this.read_pos = 0;
+                }
+                // pings are answered by the WebSocket implementation itself
+                Some(Ok(_)) => {}
+            }
+        }
+    }
+}
+
+impl<P: Pipe> AsyncWrite for WsPipe<P> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        futures_util::ready!(this.inner.poll_ready_unpin(cx)).map_err(to_io_error)?;
+        this.inner
+            .start_send_unpin(Message::binary(buf.to_vec()))
+            .map_err(to_io_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        self.get_mut()
+            .inner
+            .poll_flush_unpin(cx)
+            .map_err(to_io_error)
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        self.get_mut()
+            .inner
+            .poll_close_unpin(cx)
+            .map_err(to_io_error)
+    }
+}
+
+impl<P: Pipe> Pipe for WsPipe<P> {
+    fn protocol(&self) -> &str {
+        "websocket"
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        self.remote_addr.as_deref()
+    }
+}
+
+/// WsDialer wraps a Dialer to upgrade its pipes to WebSocket. For wss://, the inner dialer should produce TLS pipes.
+pub struct WsDialer<D: Dialer> {
+    pub inner: D,
+    /// The Host header, which is what CDNs route on.
+    pub host: String,
+    /// The path of the upgrade request, such as "/ws".
+    pub path: String,
+}
+
+#[async_trait]
+impl<D: Dialer> Dialer for WsDialer<D> {
+    type P = WsPipe<D::P>;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let lower = self.inner.dial().await?;
+        let remote_addr = lower.remote_addr().map(|s| s.to_string());
+        let request = format!("ws://{}{}", self.host, self.path)
+            .into_client_request()
+            .map_err(to_io_error)?;
+        let (stream, _) = async_tungstenite::client_async(request, lower)
+            .await
+            .inspect_err(|e| {
+                tracing::warn!(
+                    err = display(e),
+                    addr = debug(&remote_addr),
+                    "WebSocket upgrade failed"
+                )
+            })
+            .map_err(to_io_error)?;
+        Ok(WsPipe::new(stream, remote_addr))
+    }
+}
+
+/// WsListener wraps a Listener to accept WebSocket upgrades on one path. Requests for other paths get a 404, like they would from any web server.
+pub struct WsListener<L: Listener> {
+    incoming: tachyonix::Receiver<WsPipe<L::P>>,
+    _accept_task: async_task::Task<()>,
+}
+
+impl<L: Listener> WsListener<L> {
+    pub fn new(mut inner: L, path: String) -> Self {
+        let (tx, rx) = tachyonix::channel(1);
+        let accept_task = smolscale::spawn(async move {
+            loop {
+                let raw_conn = match inner.accept().await {
+                    Ok(conn) => conn,
+                    Err(err) => {
+                        tracing::warn!(err = debug(err), "underlying listener died");
+                        break;
+                    }
+                };
+                // upgrades happen in the background, so that slow clients do not hold up others
+                let tx = tx.clone();
+                let path = path.clone();
+                let remote_addr = raw_conn.remote_addr().map(|s| s.to_string());
+                smolscale::spawn(async move {
+                    let check_path = |req: &Request, resp: Response| {
+                        if req.uri().path() == path {
+                            Ok(resp)
+                        } else {
+                            let mut err = ErrorResponse::new(None);
+                            *err.status_mut() = StatusCode::NOT_FOUND;
+                            Err(err)
+                        }
+                    };
+                    match async_tungstenite::accept_hdr_async(raw_conn, check_path).await {
+                        Ok(stream) => {
+                            let _ = tx.send(WsPipe::new(stream, remote_addr)).await;
+                        }
+                        Err(err) => {
+                            tracing::debug!(err = debug(err), "WebSocket upgrade failed")
+                        }
+                    }
+                })
+                .detach();
+            }
+        });
+
+        WsListener {
+            incoming: rx,
+            _accept_task: accept_task,
+        }
+    }
+}
+
+#[async_trait]
+impl<L: Listener> Listener for WsListener<L> {
+    type P = WsPipe<L::P>;
+
+    async fn accept(&mut self) -> std::io::Result<Self::P> {
+        self.incoming
+            .recv()
+            .await
+            .map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, "WebSocket listener died"))
+    }
+}
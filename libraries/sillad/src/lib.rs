@@ -1,4 +1,8 @@
-use std::pin::{pin, Pin};
+use std::{
+    future::Future,
+    pin::{pin, Pin},
+    task::{Context, Poll},
+};
 
 use futures_util::{AsyncRead, AsyncWrite};
 use pin_project::pin_project;
@@ -19,6 +23,19 @@ pub trait Pipe: AsyncRead + AsyncWrite + Send + Unpin + 'static {
 
     /// This might return a string that is some sort of human-readable identifier of the remote address.
     fn remote_addr(&self) -> Option<&str>;
+
+    /// Closes only the writing half of the pipe, so that the other end reads EOF while still being able to send us data, like a TCP shutdown. Pipes that cannot half-close fall back to closing entirely.
+    fn poll_close_write(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.poll_close(cx)
+    }
+
+    /// Half-closes the pipe. See [`Pipe::poll_close_write`].
+    fn close_write(&mut self) -> impl Future<Output = std::io::Result<()>> + Send + '_
+    where
+        Self: Sized,
+    {
+        futures_util::future::poll_fn(move |cx| Pin::new(&mut *self).poll_close_write(cx))
+    }
 }
 
 impl Pipe for Box<dyn Pipe> {
@@ -33,6 +50,10 @@ impl Pipe for Box<dyn Pipe> {
     fn remote_addr(&self) -> Option<&str> {
         (**self).remote_addr()
     }
+
+    fn poll_close_write(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut **self.get_mut()).poll_close_write(cx)
+    }
 }
 
 /// EitherPipe is a pipe that is either left or right.
@@ -109,4 +130,11 @@ impl<L: Pipe, R: Pipe> Pipe for EitherPipe<L, R> {
             EitherPipe::Right(r) => r.remote_addr(),
         }
     }
+
+    fn poll_close_write(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.project() {
+            EitherPipeProj::Left(l) => l.poll_close_write(cx),
+            EitherPipeProj::Right(r) => r.poll_close_write(cx),
+        }
+    }
 }
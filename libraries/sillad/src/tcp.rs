@@ -9,6 +9,8 @@ use async_trait::async_trait;
 use futures_lite::{AsyncRead, AsyncWrite};
 use pin_project::pin_project;
 use rand::Rng as _;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
 
 use crate::{
     dialer::{Dialer, DialerExt},
@@ -28,6 +30,13 @@ impl TcpListener {
         Ok(Self { inner: new })
     }
 
+    /// Wraps a listener that is already bound, such as one inherited from the parent process.
+    pub fn from_std(listener: std::net::TcpListener) -> std::io::Result<Self> {
+        Ok(Self {
+            inner: Async::new(listener)?,
+        })
+    }
+
     /// Get the local listening address.
     pub async fn local_addr(&self) -> SocketAddr {
         self.inner.as_ref().local_addr().unwrap()
@@ -94,26 +103,106 @@ fn set_tcp_options(conn: &Async<TcpStream>) -> std::io::Result<()> {
     Ok(())
 }
 
-/// A HappyEyeballsTcpDialer is a dialer for TCP endpoints which tries the given addresses in sequence intelligently.
-pub struct HappyEyeballsTcpDialer(pub Vec<SocketAddr>);
+/// Which address families a HappyEyeballsTcpDialer tries, and which one first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FamilyPreference {
+    #[default]
+    PreferV6,
+    PreferV4,
+    V6Only,
+    V4Only,
+}
+
+/// How a HappyEyeballsTcpDialer schedules its attempts. The defaults follow RFC 8305, but some censored networks throttle or break one address family, so that preferring or avoiding it helps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HappyEyeballsPolicy {
+    pub family: FamilyPreference,
+    /// How long the preferred family gets to itself before attempts over the other family start.
+    pub head_start_ms: u64,
+    /// How long to wait between starting one attempt and the next.
+    pub attempt_delay_ms: u64,
+    /// How long each attempt may take before it is given up on, if at all.
+    pub attempt_timeout_ms: Option<u64>,
+}
+
+impl Default for HappyEyeballsPolicy {
+    fn default() -> Self {
+        Self {
+            family: FamilyPreference::PreferV6,
+            head_start_ms: 0,
+            attempt_delay_ms: 250,
+            attempt_timeout_ms: None,
+        }
+    }
+}
+
+impl HappyEyeballsPolicy {
+    /// Orders the addresses the policy allows, alternating between families starting with the preferred one, and works out when to start dialing each.
+    fn schedule(&self, addrs: &[SocketAddr]) -> Vec<(SocketAddr, Duration)> {
+        let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) =
+            addrs.iter().partition(|addr| addr.is_ipv6());
+        let (preferred, other) = match self.family {
+            FamilyPreference::PreferV6 => (v6, v4),
+            FamilyPreference::PreferV4 => (v4, v6),
+            FamilyPreference::V6Only => (v6, vec![]),
+            FamilyPreference::V4Only => (v4, vec![]),
+        };
+        let mut preferred = preferred.into_iter();
+        let mut other = other.into_iter();
+        let mut ordered = vec![];
+        loop {
+            let (p, o) = (preferred.next(), other.next());
+            if p.is_none() && o.is_none() {
+                break;
+            }
+            ordered.extend(p.map(|addr| (addr, false)));
+            ordered.extend(o.map(|addr| (addr, true)));
+        }
+        ordered
+            .into_iter()
+            .enumerate()
+            .map(|(idx, (addr, is_other))| {
+                let mut delay = self.attempt_delay_ms * idx as u64;
+                if is_other {
+                    delay += self.head_start_ms;
+                }
+                (addr, Duration::from_millis(delay))
+            })
+            .collect()
+    }
+}
+
+/// A HappyEyeballsTcpDialer is a dialer for TCP endpoints which tries the given addresses in sequence intelligently, following its policy.
+pub struct HappyEyeballsTcpDialer {
+    pub addrs: Vec<SocketAddr>,
+    pub policy: HappyEyeballsPolicy,
+}
 
 #[async_trait]
 impl Dialer for HappyEyeballsTcpDialer {
     type P = Box<dyn Pipe>;
     async fn dial(&self) -> std::io::Result<Self::P> {
         let res = self
-            .0
-            .iter()
-            .enumerate()
-            .map(|(idx, addr)| {
-                let delay = Duration::from_millis(250 * idx as u64);
-                TcpDialer { dest_addr: *addr }.delay(delay).dynamic()
+            .policy
+            .schedule(&self.addrs)
+            .into_iter()
+            .map(|(addr, delay)| {
+                let dialer = TcpDialer { dest_addr: addr };
+                match self.policy.attempt_timeout_ms {
+                    Some(ms) => dialer
+                        .timeout(Duration::from_millis(ms))
+                        .delay(delay)
+                        .dynamic(),
+                    None => dialer.delay(delay).dynamic(),
+                }
             })
             .reduce(|a, b| a.race(b).dynamic());
         match res {
             None => Err(std::io::Error::new(
                 std::io::ErrorKind::Other,
-                "no addresses given",
+                "no addresses of an allowed family given",
             )),
             Some(dialer) => dialer.dial().await,
         }
@@ -138,6 +227,115 @@ impl Dialer for TcpDialer {
     }
 }
 
+/// Socket options for outgoing TCP connections, beyond the ones every connection gets.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct TcpOptions {
+    /// Whether to use TCP Fast Open, which sends the first write along with the SYN on reconnections to a server that has given us a cookie. Only supported on Linux and Android; ignored elsewhere.
+    pub fast_open: bool,
+    /// The network interface to send through, regardless of the routing table. This keeps a VPN's own connections from looping back into its tunnel. Only supported on Linux and Android.
+    pub bind_interface: Option<String>,
+    /// The DSCP value to mark packets with, for networks that prioritize traffic by it. Only supported on Linux, Android, and macOS.
+    pub dscp: Option<u8>,
+}
+
+/// A TunedTcpDialer is a TcpDialer that sets extra socket options before connecting.
+pub struct TunedTcpDialer {
+    pub dest_addr: SocketAddr,
+    pub options: TcpOptions,
+}
+
+#[async_trait]
+impl Dialer for TunedTcpDialer {
+    type P = TcpPipe;
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let inner = connect_with_options(self.dest_addr, &self.options)
+            .await
+            .inspect_err(|e| tracing::warn!("inner dial failed: {:?}", e))?;
+        let _ =
+            set_tcp_options(&inner).inspect_err(|e| tracing::warn!("tcp option set fail: {:?}", e));
+        Ok(TcpPipe(inner, self.dest_addr.to_string()))
+    }
+}
+
+async fn connect_with_options(
+    dest_addr: SocketAddr,
+    options: &TcpOptions,
+) -> std::io::Result<Async<TcpStream>> {
+    let socket = Socket::new(
+        Domain::for_address(dest_addr),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    socket.set_nonblocking(true)?;
+
+    if let Some(interface) = &options.bind_interface {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        socket.bind_device(Some(interface.as_bytes()))?;
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!("cannot bind to interface {interface} on this platform"),
+        ));
+    }
+
+    if let Some(dscp) = options.dscp {
+        // the DSCP is the upper six bits of the old TOS byte
+        let tos = (dscp as u32) << 2;
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+        if dest_addr.is_ipv4() {
+            socket.set_tos(tos)?;
+        } else {
+            socket.set_tclass_v6(tos)?;
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!("cannot mark packets with DSCP {tos} on this platform"),
+        ));
+    }
+
+    if options.fast_open {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        unsafe {
+            use std::os::fd::AsRawFd;
+            let enable: libc::c_int = 1;
+            let ret = libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_FASTOPEN_CONNECT,
+                &enable as *const _ as *const libc::c_void,
+                std::mem::size_of_val(&enable) as libc::socklen_t,
+            );
+            if ret != 0 {
+                tracing::debug!(
+                    err = debug(std::io::Error::last_os_error()),
+                    "cannot turn on TCP fast open"
+                );
+            }
+        }
+    }
+
+    match socket.connect(&dest_addr.into()) {
+        Ok(()) => {}
+        Err(e) if connect_in_progress(&e) => {}
+        Err(e) => return Err(e),
+    }
+    let stream = Async::new(TcpStream::from(socket))?;
+    stream.writable().await?;
+    if let Some(err) = stream.get_ref().take_error()? {
+        return Err(err);
+    }
+    Ok(stream)
+}
+
+fn connect_in_progress(err: &std::io::Error) -> bool {
+    #[cfg(unix)]
+    if err.raw_os_error() == Some(libc::EINPROGRESS) {
+        return true;
+    }
+    err.kind() == std::io::ErrorKind::WouldBlock
+}
+
 #[pin_project]
 pub struct TcpPipe(#[pin] Async<TcpStream>, String);
 
@@ -184,3 +382,42 @@ impl Pipe for TcpPipe {
         Some(&self.1)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn happy_eyeballs_schedule() {
+        let addrs: Vec<SocketAddr> = ["1.1.1.1:443", "1.0.0.1:443", "[2606:4700::1111]:443"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        let policy = HappyEyeballsPolicy {
+            head_start_ms: 100,
+            ..Default::default()
+        };
+        let schedule = policy.schedule(&addrs);
+        assert_eq!(
+            schedule,
+            vec![
+                (addrs[2], Duration::from_millis(0)),
+                (addrs[0], Duration::from_millis(350)),
+                (addrs[1], Duration::from_millis(600)),
+            ]
+        );
+
+        let policy = HappyEyeballsPolicy {
+            family: FamilyPreference::V4Only,
+            ..Default::default()
+        };
+        let schedule = policy.schedule(&addrs);
+        assert_eq!(
+            schedule,
+            vec![
+                (addrs[0], Duration::from_millis(0)),
+                (addrs[1], Duration::from_millis(250)),
+            ]
+        );
+    }
+}